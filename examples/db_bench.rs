@@ -0,0 +1,81 @@
+use draft_kv::bench::{self, BenchOptions, Workload};
+use draft_kv::lsm::{Config, LsmDb};
+
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "usage: db_bench [--benchmarks=fillseq,readrandom,...] [--num=N] [--value_size=N] [--threads=N]
+                [--write_buffer_size=N] [--cache_size=N] [--cache_shard_bits=N] [--compression=none]
+                [--seed=N] [--db=DIR] [--use_existing_db]
+benchmarks: fillseq, fillrandom, readrandom, readseq, overwrite, mixed";
+
+//runs the workloads one after another on the same database, e.g.
+//cargo run --release --example db_bench -- --benchmarks=fillrandom,readrandom --num=100000 --threads=4
+fn main() {
+    let mut workloads = vec![Workload::FillSeq, Workload::ReadRandom, Workload::ReadSeq];
+    let mut options = BenchOptions::default();
+    let mut config = Config::new();
+    let mut dir = std::env::temp_dir().join("draft_kv_db_bench");
+    let mut use_existing = false;
+    for arg in std::env::args().skip(1) {
+        let (flag, value) = match arg.find('=') {
+            Some(pos) => (&arg[..pos], &arg[pos + 1..]),
+            None => (arg.as_str(), ""),
+        };
+        match flag {
+            "--benchmarks" => {
+                workloads = value.split(',')
+                    .filter(|name| !name.is_empty())
+                    .map(|name| Workload::parse(name).unwrap_or_else(|e| fail(&e.to_string())))
+                    .collect();
+            },
+            "--num" => options.num = number(flag, value),
+            "--value_size" => options.value_size = number(flag, value) as usize,
+            "--threads" => options.threads = number(flag, value) as usize,
+            "--write_buffer_size" => config.write_buffer_size = number(flag, value) as usize,
+            "--cache_size" => config.block_cache_size = number(flag, value) as usize,
+            "--cache_shard_bits" => config.block_cache_shard_bits = number(flag, value) as u32,
+            "--seed" => options.seed = number(flag, value),
+            //tables are not compressed yet
+            "--compression" if value != "none" => fail(&format!("unsupported compression {}", value)),
+            "--compression" => {},
+            "--db" => dir = PathBuf::from(value),
+            "--use_existing_db" => use_existing = true,
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ => fail(&format!("unknown flag {}", arg)),
+        }
+    }
+
+    if !use_existing {
+        let _ = fs::remove_dir_all(&dir);
+    }
+    println!("keys: {}, values: {} bytes, threads: {}, write buffer: {} bytes, seed: {}",
+        options.num, options.value_size, options.threads, config.write_buffer_size, options.seed);
+    let db = LsmDb::with_config(dir.clone(), config).unwrap_or_else(|e| fail(&e.to_string()));
+    for workload in workloads {
+        match bench::run(&db, workload, &options) {
+            Ok(report) => {
+                let cache = db.stats().block_cache;
+                println!("{}; block cache {} hits, {} misses, {} lock waits", report, cache.hits, cache.misses, cache.lock_waits);
+            },
+            Err(e) => fail(&format!("{} failed: {}", workload.name(), e)),
+        }
+    }
+    drop(db);
+    if !use_existing {
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+fn number(flag: &str, value: &str) -> u64 {
+    value.parse().unwrap_or_else(|_| fail(&format!("{} needs a number, got {:?}", flag, value)))
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(1)
+}
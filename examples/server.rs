@@ -0,0 +1,52 @@
+use draft_kv::codec::{decode_u64, encode_u64};
+use draft_kv::lsm::LsmDb;
+use draft_kv::server::{KvClient, KvServer};
+
+use std::fs;
+use std::sync::Arc;
+
+//starts a server on a free port and goes through every request, run with
+//cargo run --features server --example server
+fn main() {
+    let dir = std::env::temp_dir().join("draft_kv_server_example");
+    let _ = fs::remove_dir_all(&dir);
+    let db = Arc::new(LsmDb::new(dir.clone()).unwrap());
+    let server = KvServer::serve("127.0.0.1:0", db.clone()).unwrap();
+    println!("listening on {}", server.local_addr());
+
+    let mut client = KvClient::connect(server.local_addr()).unwrap();
+    client.put(b"account:alice", &encode_u64(100)).unwrap();
+    client.put(b"account:bob", &encode_u64(20)).unwrap();
+    client.put(b"note", b"to be deleted").unwrap();
+    client.delete(b"note").unwrap();
+    println!("note after delete: {:?}", client.get(b"note").unwrap());
+
+    //a transfer either moves the whole amount or nothing
+    transfer(&mut client, b"account:alice", b"account:bob", 30);
+    client.begin().unwrap();
+    client.put(b"account:bob", &encode_u64(0)).unwrap();
+    client.abort().unwrap();
+    for (key, value) in client.scan(b"account:", 10).unwrap() {
+        println!("{} = {}", String::from_utf8_lossy(&key), decode_u64(&value).unwrap());
+    }
+
+    //stops the server, then flushes
+    db.close().unwrap();
+    println!("after close: {:?}", client.get(b"account:alice").map_err(|e| e.to_string()));
+    drop(server);
+    drop(db);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+fn transfer(client: &mut KvClient, from: &[u8], to: &[u8], amount: u64) {
+    client.begin().unwrap();
+    let balance = |client: &mut KvClient, key| decode_u64(&client.get(key).unwrap().unwrap()).unwrap();
+    let (from_balance, to_balance) = (balance(client, from), balance(client, to));
+    if from_balance < amount {
+        client.abort().unwrap();
+        return;
+    }
+    client.put(from, &encode_u64(from_balance - amount)).unwrap();
+    client.put(to, &encode_u64(to_balance + amount)).unwrap();
+    client.commit().unwrap();
+}
@@ -0,0 +1,68 @@
+use draft_kv::env::{Env, StdEnv};
+use draft_kv::lsm::{Config, LsmDb};
+use draft_kv::uring::UringEnv;
+
+use std::fs::{self, File};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const NUM_KEYS: u64 = 200_000;
+const BATCH: usize = 256;
+const ROUNDS: usize = 9;
+
+//multi_get of cold keys with pread against io_uring, run with
+//cargo run --release --features uring --example uring_multi_get
+fn main() {
+    let dir = std::env::temp_dir().join("draft_kv_uring_multi_get");
+    let _ = fs::remove_dir_all(&dir);
+    {
+        let lsm = LsmDb::new(dir.clone()).unwrap();
+        for i in 0..NUM_KEYS {
+            lsm.insert(key(i).as_bytes(), &[b'v'; 100]).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.compact_level(0).unwrap();
+    }
+    println!("io_uring supported: {}", UringEnv::kernel_support());
+
+    let envs: [(&str, Arc<dyn Env>); 2] = [("pread", Arc::new(StdEnv)), ("io_uring", Arc::new(UringEnv::new()))];
+    for (name, env) in envs.iter() {
+        let mut config = Config::new();
+        config.env = env.clone();
+        let lsm = LsmDb::with_config(dir.clone(), config).unwrap();
+        let mut latencies = Vec::new();
+        for round in 0..ROUNDS {
+            //spread over the whole key space, so nearly every key needs a block of its own
+            let keys = (0..BATCH as u64)
+                .map(|i| key((i * NUM_KEYS / BATCH as u64 + round as u64 * 7) % NUM_KEYS))
+                .collect::<Vec<_>>();
+            let keys = keys.iter().map(|k| k.as_bytes()).collect::<Vec<_>>();
+            drop_page_cache(&dir);
+            let now = Instant::now();
+            let values = lsm.multi_get(&keys).unwrap();
+            latencies.push(now.elapsed());
+            assert!(values.iter().all(|v| v.is_some()));
+        }
+        latencies.sort();
+        println!("{:>8}: median {:?}, min {:?} for {} cold keys", name, latencies[ROUNDS / 2], latencies[0], BATCH);
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+fn key(i: u64) -> String {
+    format!("key{:010}", i)
+}
+
+//asks the kernel to forget the cached pages of the tables, clean pages are dropped right away
+fn drop_page_cache(dir: &Path) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "sst") {
+            let file = File::open(&path).unwrap();
+            unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        }
+    }
+    std::thread::sleep(Duration::from_millis(10));
+}
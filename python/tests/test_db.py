@@ -0,0 +1,95 @@
+"""Run by hand after `maturin develop`: pytest python/tests"""
+
+import threading
+
+import pytest
+
+import draft_kv
+
+
+@pytest.fixture
+def db(tmp_path):
+    db = draft_kv.Db(str(tmp_path / "db"))
+    yield db
+    db.close()
+
+
+def test_put_get_delete(db):
+    db.put(b"k\x00", b"v\xff")
+    assert db.get(b"k\x00") == b"v\xff"
+    assert isinstance(db.get(b"k\x00"), bytes)
+    db.delete(b"k\x00")
+    assert db.get(b"k\x00") is None
+    with pytest.raises(ValueError):
+        db.put(b"", b"v")
+    with pytest.raises(TypeError):
+        db.put("text", b"v")
+
+
+def test_scan(db):
+    for i in range(5):
+        db.put(b"user:%d" % i, b"%d" % i)
+    db.put(b"other", b"x")
+    assert db.scan(prefix=b"user:", limit=2) == [(b"user:0", b"0"), (b"user:1", b"1")]
+    assert len(db.scan()) == 6
+    assert db.scan(prefix=b"none") == []
+
+
+def test_write_batch(db):
+    db.put(b"gone", b"1")
+    db.write_batch([("put", b"a", b"1"), ("put", b"b", b"2"), ("delete", b"gone")])
+    assert db.scan() == [(b"a", b"1"), (b"b", b"2")]
+    with pytest.raises(ValueError):
+        db.write_batch([("put", b"a")])
+    assert db.get(b"a") == b"1"
+
+
+def test_transaction_commits_or_aborts(db):
+    db.put(b"balance", b"10")
+    with db.transaction() as tx:
+        assert tx.get(b"balance") == b"10"
+        tx.put(b"balance", b"9")
+        tx.put(b"log", b"spent 1")
+    assert db.get(b"balance") == b"9"
+
+    with pytest.raises(RuntimeError):
+        with db.transaction() as tx:
+            tx.put(b"balance", b"0")
+            raise RuntimeError("changed my mind")
+    assert db.get(b"balance") == b"9"
+
+    tx = db.transaction()
+    tx.delete(b"log")
+    tx.abort()
+    assert db.get(b"log") == b"spent 1"
+
+
+def test_writers_in_other_threads_are_not_blocked_by_a_transaction(db):
+    tx = db.transaction()
+    tx.put(b"a", b"1")
+    # the second transaction waits for the first with the GIL released, so this thread can commit it
+    waiter = threading.Thread(target=lambda: db.transaction().__enter__().put(b"b", b"2"))
+    waiter.start()
+    waiter.join(0.2)
+    assert waiter.is_alive()
+    tx.commit()
+    waiter.join()
+    assert db.get(b"a") == b"1"
+
+
+def test_errors(tmp_path):
+    path = str(tmp_path / "db")
+    db = draft_kv.Db(path)
+    db.put(b"k", b"v")
+    with pytest.raises(draft_kv.DbLockedError):
+        draft_kv.Db(path)
+    reader = draft_kv.Db(path, read_only=True)
+    assert reader.get(b"k") == b"v"
+    with pytest.raises(draft_kv.ReadOnlyError) as err:
+        reader.put(b"k", b"w")
+    assert isinstance(err.value, draft_kv.DraftKvError)
+    db.close()
+    with pytest.raises(draft_kv.ClosedError):
+        db.get(b"k")
+    with pytest.raises(ValueError):
+        draft_kv.Db(str(tmp_path / "missing"), read_only=True)
@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use parking_lot::Mutex;
+
+use crate::clock::unix_millis;
+use crate::env::{self, Env};
+use crate::error::{Error, Result};
+use crate::fault;
+use crate::lsm::LsmDb;
+use crate::utils::{crc32c, file_num};
+
+//A backup directory holds any number of backups of one database, numbered from 1:
+//  meta/<id>                      what the backup is made of, one name=value per line, written last
+//  shared/<num>_<size>_<crc>.sst   the tables, shared by every backup that has them
+//  shared/<num>_<size>_<crc>.vlog  the value logs, shared the same way
+//  private/<id>-<name>             the logs, MANIFEST and IDENTITY of a single backup
+//Tables and value logs never change once written, so a backup only copies the ones no earlier backup has.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: u64,
+    pub created_millis: u64,
+    pub seq_num: u64, //restoring gives the database as it was at this sequence number
+    pub tables: Vec<(String, String)>, //the name in shared/, the name in the database, value logs included
+    pub files: Vec<String>, //the names in the database of the private files
+}
+
+impl BackupInfo {
+    fn encode_to(&self) -> Vec<u8> {
+        let mut text = format!("created_millis={}\nseq_num={}\n", self.created_millis, self.seq_num);
+        for (shared, name) in self.tables.iter() {
+            text += &format!("table={} {}\n", shared, name);
+        }
+        for name in self.files.iter() {
+            text += &format!("file={}\n", name);
+        }
+        text.into_bytes()
+    }
+
+    fn decode_from(id: u64, bytes: &[u8]) -> Result<Self> {
+        let corrupt = |what: &str| Error::Corruption(format!("{} in the meta file of backup {}", what, id));
+        let text = std::str::from_utf8(bytes).map_err(|_| corrupt("invalid utf-8"))?;
+        let mut info = BackupInfo { id, created_millis: 0, seq_num: 0, tables: Vec::new(), files: Vec::new() };
+        for line in text.lines() {
+            let invalid = || corrupt(&format!("invalid line {:?}", line));
+            match line.split_once('=').ok_or_else(invalid)? {
+                ("created_millis", millis) => info.created_millis = millis.parse().map_err(|_| invalid())?,
+                ("seq_num", seq_num) => info.seq_num = seq_num.parse().map_err(|_| invalid())?,
+                ("table", table) => {
+                    let (shared, name) = table.split_once(' ').ok_or_else(invalid)?;
+                    info.tables.push((shared.to_owned(), name.to_owned()));
+                },
+                ("file", name) => info.files.push(name.to_owned()),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(info)
+    }
+}
+
+pub struct BackupEngine {
+    dir: PathBuf,
+    env: Arc<dyn Env>,
+    lock: Mutex<()>, //one backup or purge at a time
+}
+
+impl BackupEngine {
+    pub fn create(backup_dir: PathBuf) -> Result<Self> {
+        Self::with_env(backup_dir, env::default_env())
+    }
+
+    //the backups and the restored databases are written through `env`
+    pub fn with_env(backup_dir: PathBuf, env: Arc<dyn Env>) -> Result<Self> {
+        for sub_dir in ["meta", "shared", "private"].iter() {
+            env.create_dir_all(&backup_dir.join(sub_dir))?;
+        }
+        Ok(BackupEngine { dir: backup_dir, env, lock: Mutex::new(()) })
+    }
+
+    //copies what `db` needs to be restored and returns the id of the new backup. File deletions of the database
+    //are disabled meanwhile, flushes and compactions go on.
+    pub fn backup(&self, db: &LsmDb) -> Result<u64> {
+        let _lock = self.lock.lock();
+        db.disable_file_deletions();
+        let res = self.copy_live_files(db);
+        db.enable_file_deletions()?;
+        res
+    }
+
+    fn copy_live_files(&self, db: &LsmDb) -> Result<u64> {
+        let live = db.live_files()?;
+        let db_env = &**db.env();
+        let id = self.next_id()?;
+        let shared = self.shared_files()?;
+        let mut info = BackupInfo {
+            id,
+            created_millis: unix_millis(SystemTime::now()),
+            seq_num: live.seq_num,
+            tables: Vec::new(),
+            files: Vec::new(),
+        };
+        let value_logs = live.value_logs.iter().map(|path| (path, "vlog"));
+        for (path, extension) in live.tables.iter().map(|table| (&table.path, "sst")).chain(value_logs) {
+            let bytes = db_env.read(path)?;
+            let num = file_num(path).ok_or_else(|| Error::InvalidArgument(format!("unnumbered file {:?}", path)))?;
+            let shared_name = format!("{}_{}_{:08x}.{}", num, bytes.len(), crc32c(&bytes), extension);
+            if !shared.contains(&shared_name) {
+                write_file(&*self.env, &self.dir.join("shared").join(&shared_name), &bytes)?;
+            }
+            info.tables.push((shared_name, file_name(path)));
+        }
+        //the logs only up to what the listed sequence number covers
+        for log in live.logs.iter() {
+            let mut bytes = db_env.read(&log.path)?;
+            bytes.truncate(log.valid_bytes as usize);
+            self.write_private(&mut info, &file_name(&log.path), &bytes)?;
+        }
+        self.write_private(&mut info, &file_name(&live.manifest), &live.manifest_contents)?;
+        if let Some(identity) = live.identity.as_ref() {
+            self.write_private(&mut info, &file_name(identity), &db_env.read(identity)?)?;
+        }
+        //the backup exists once its meta file does
+        write_file(&*self.env, &self.meta_path(id), &info.encode_to())?;
+        Ok(id)
+    }
+
+    fn write_private(&self, info: &mut BackupInfo, name: &str, bytes: &[u8]) -> Result<()> {
+        write_file(&*self.env, &self.private_path(info.id, name), bytes)?;
+        info.files.push(name.to_owned());
+        Ok(())
+    }
+
+    //oldest first
+    pub fn backups(&self) -> Result<Vec<BackupInfo>> {
+        self.backup_ids()?.into_iter().map(|id| self.backup_info(id)).collect()
+    }
+
+    pub fn backup_info(&self, id: u64) -> Result<BackupInfo> {
+        let bytes = match self.env.read(&self.meta_path(id)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::InvalidArgument(format!("no backup {} in {:?}", id, self.dir)));
+            },
+            Err(e) => return Err(e.into()),
+        };
+        BackupInfo::decode_from(id, &bytes)
+    }
+
+    //writes the database of backup `id` to `target_dir`, which must be empty or not exist yet
+    pub fn restore(&self, id: u64, target_dir: &Path) -> Result<()> {
+        let info = self.backup_info(id)?;
+        self.env.create_dir_all(target_dir)?;
+        if !self.env.list_dir(target_dir)?.is_empty() {
+            return Err(Error::InvalidArgument(format!("{:?} is not empty", target_dir)));
+        }
+        for (shared_name, name) in info.tables.iter() {
+            let bytes = self.env.read(&self.dir.join("shared").join(shared_name))?;
+            write_file(&*self.env, &target_dir.join(name), &bytes)?;
+        }
+        for name in info.files.iter() {
+            let bytes = self.env.read(&self.private_path(id, name))?;
+            write_file(&*self.env, &target_dir.join(name), &bytes)?;
+        }
+        Ok(())
+    }
+
+    //deletes all but the newest `keep` backups and the tables no backup left refers to, returns how many went
+    pub fn purge_old_backups(&self, keep: usize) -> Result<usize> {
+        let _lock = self.lock.lock();
+        let ids = self.backup_ids()?;
+        let purged = ids.len().saturating_sub(keep);
+        for &id in ids[..purged].iter() {
+            fault::remove_file(&*self.env, &self.meta_path(id))?;
+        }
+        //also what a backup that failed halfway left behind
+        let kept = ids[purged..].iter().map(|&id| self.backup_info(id)).collect::<Result<Vec<_>>>()?;
+        let kept_ids = kept.iter().map(|info| info.id).collect::<HashSet<_>>();
+        for path in self.env.list_dir(&self.dir.join("private"))? {
+            let id = file_name(&path).split_once('-').and_then(|(id, _)| id.parse::<u64>().ok());
+            if !matches!(id, Some(id) if kept_ids.contains(&id)) {
+                fault::remove_file(&*self.env, &path)?;
+            }
+        }
+        let referenced = kept.iter().flat_map(|info| info.tables.iter().map(|(shared, _)| shared.clone())).collect::<HashSet<_>>();
+        for path in self.env.list_dir(&self.dir.join("shared"))? {
+            if !referenced.contains(&file_name(&path)) {
+                fault::remove_file(&*self.env, &path)?;
+            }
+        }
+        Ok(purged)
+    }
+
+    fn backup_ids(&self) -> Result<Vec<u64>> {
+        let mut ids = self.env.list_dir(&self.dir.join("meta"))?.iter()
+            .filter_map(|path| file_name(path).parse().ok())
+            .collect::<Vec<u64>>();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    //past the files of a backup that failed halfway as well
+    fn next_id(&self) -> Result<u64> {
+        let private = self.env.list_dir(&self.dir.join("private"))?.iter()
+            .filter_map(|path| file_name(path).split_once('-').and_then(|(id, _)| id.parse::<u64>().ok()))
+            .max();
+        Ok(std::cmp::max(self.backup_ids()?.last().copied(), private).unwrap_or(0) + 1)
+    }
+
+    fn shared_files(&self) -> Result<HashSet<String>> {
+        Ok(self.env.list_dir(&self.dir.join("shared"))?.iter()
+            .map(|path| file_name(path))
+            .filter(|name| name.ends_with(".sst") || name.ends_with(".vlog"))
+            .collect())
+    }
+
+    fn meta_path(&self, id: u64) -> PathBuf {
+        self.dir.join("meta").join(id.to_string())
+    }
+
+    fn private_path(&self, id: u64, name: &str) -> PathBuf {
+        self.dir.join("private").join(format!("{}-{}", id, name))
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+}
+
+//written to a temporary file first, so a file under its final name is always whole
+fn write_file(env: &dyn Env, path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_file = path.with_extension("tmp");
+    let mut file = env.create(&tmp_file)?;
+    fault::append(&mut *file, bytes, &tmp_file)?;
+    fault::sync(&mut *file, &tmp_file)?;
+    drop(file);
+    fault::rename(env, &tmp_file, path)?;
+    match path.parent() {
+        Some(dir) => fault::sync_dir(env, dir),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::MemEnv;
+    use crate::lsm::Config;
+
+    fn config(env: &MemEnv) -> Config {
+        let mut config = Config::new();
+        config.env = Arc::new(env.clone());
+        config.write_buffer_size = 1 << 20;
+        config.l0_compaction_threshold = 100;
+        config
+    }
+
+    fn insert_and_flush(db: &LsmDb, range: std::ops::Range<usize>) {
+        for i in range {
+            db.insert(format!("key{:04}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+    }
+
+    fn assert_restores(engine: &BackupEngine, env: &MemEnv, id: u64, target: &str, keys: usize) {
+        engine.restore(id, Path::new(target)).unwrap();
+        let db = LsmDb::with_config(PathBuf::from(target), config(env)).unwrap();
+        for i in 0..keys + 10 {
+            let expected = (i < keys).then(|| format!("value{}", i).into_bytes());
+            assert_eq!(db.search(format!("key{:04}", i).as_bytes(), None).unwrap(), expected, "key {} of backup {}", i, id);
+        }
+    }
+
+    #[test]
+    fn a_second_backup_copies_only_the_new_tables() {
+        let env = MemEnv::new();
+        let db = LsmDb::with_config(PathBuf::from("/mem/db"), config(&env)).unwrap();
+        let engine = BackupEngine::with_env(PathBuf::from("/mem/backups"), Arc::new(env.clone())).unwrap();
+        insert_and_flush(&db, 0..100);
+        insert_and_flush(&db, 100..200);
+        assert_eq!(engine.backup(&db).unwrap(), 1);
+
+        insert_and_flush(&db, 200..210);
+        //unflushed writes go along in the log
+        db.insert(b"key0210", b"value210").unwrap();
+        env.take_appends();
+        assert_eq!(engine.backup(&db).unwrap(), 2);
+        let shared_dir = Path::new("/mem/backups/shared");
+        let copied = env.take_appends().into_iter()
+            .filter(|(path, _)| path.parent() == Some(shared_dir))
+            .collect::<Vec<_>>();
+        let (first, second) = (engine.backup_info(1).unwrap(), engine.backup_info(2).unwrap());
+        assert_eq!((first.tables.len(), second.tables.len()), (2, 3));
+        assert_eq!(copied.len(), 1);
+        let copied = file_name(&copied[0].0.with_extension("sst"));
+        assert!(first.tables.iter().all(|(shared, _)| *shared != copied));
+        assert!(second.tables.iter().any(|(shared, _)| *shared == copied));
+        assert!(second.seq_num > first.seq_num);
+
+        assert_restores(&engine, &env, 1, "/mem/restored1", 200);
+        assert_restores(&engine, &env, 2, "/mem/restored2", 211);
+        assert!(matches!(engine.restore(2, Path::new("/mem/restored2")), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn purging_a_backup_keeps_the_tables_of_the_others() {
+        let env = MemEnv::new();
+        let db = LsmDb::with_config(PathBuf::from("/mem/db"), config(&env)).unwrap();
+        let engine = BackupEngine::with_env(PathBuf::from("/mem/backups"), Arc::new(env.clone())).unwrap();
+        insert_and_flush(&db, 0..100);
+        engine.backup(&db).unwrap();
+        //the table of the first backup is compacted away, only that backup refers to it
+        db.compact_level(0).unwrap();
+        insert_and_flush(&db, 100..150);
+        engine.backup(&db).unwrap();
+        engine.backup(&db).unwrap();
+        assert_eq!(engine.backups().unwrap().iter().map(|b| b.id).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        assert_eq!(engine.purge_old_backups(2).unwrap(), 1);
+        assert!(matches!(engine.restore(1, Path::new("/mem/restored1")), Err(Error::InvalidArgument(_))));
+        assert_eq!(env.list_dir(Path::new("/mem/backups/shared")).unwrap().len(), 2);
+        assert_restores(&engine, &env, 2, "/mem/restored2", 150);
+
+        assert_eq!(engine.purge_old_backups(1).unwrap(), 1);
+        assert_restores(&engine, &env, 3, "/mem/restored3", 150);
+        assert_eq!(engine.purge_old_backups(0).unwrap(), 1);
+        assert!(env.list_dir(Path::new("/mem/backups/shared")).unwrap().is_empty());
+        assert!(env.list_dir(Path::new("/mem/backups/private")).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::lsm::LsmDb;
+use crate::utils::Rng;
+
+//The standard workloads of examples/db_bench.rs. Keys are the decimal numbers below `num`, zero padded
+//so they sort by number, and every thread draws them from its own generator seeded from `seed`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Workload {
+    FillSeq,    //every key once in ascending order, split into one range per thread
+    FillRandom, //`num` writes of random keys
+    ReadRandom, //`num` searches of random keys
+    ReadSeq,    //everything in key order, by every thread
+    Overwrite,  //fillrandom over keys that are meant to be there already
+    Mixed,      //random keys, one write to every four searches
+}
+
+impl Workload {
+    pub const ALL: [Workload; 6] = [
+        Workload::FillSeq, Workload::FillRandom, Workload::ReadRandom,
+        Workload::ReadSeq, Workload::Overwrite, Workload::Mixed,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Workload::FillSeq => "fillseq",
+            Workload::FillRandom => "fillrandom",
+            Workload::ReadRandom => "readrandom",
+            Workload::ReadSeq => "readseq",
+            Workload::Overwrite => "overwrite",
+            Workload::Mixed => "mixed",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self> {
+        Self::ALL.iter()
+            .copied()
+            .find(|w| w.name() == name)
+            .ok_or_else(|| Error::InvalidArgument(format!("unknown workload {}", name)))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchOptions {
+    pub num: u64,
+    pub value_size: usize,
+    pub threads: usize,
+    pub seed: u64,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            num: 1_000_000,
+            value_size: 100,
+            threads: 1,
+            seed: 301,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchReport {
+    pub workload: Workload,
+    pub ops: u64,
+    pub found: u64, //searches that found their key, and the entries readseq went over
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub wal_bytes_written: u64,
+    pub sst_bytes_written: u64, //by flushes and compactions
+    pub files_per_level: Vec<usize>, //once the workload is done
+}
+
+impl BenchReport {
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<11}: {:>10.0} ops/sec, p50 {:?}, p99 {:?}, {} of {} found; wrote {} bytes to the log, {} to tables; files per level {:?}",
+            self.workload.name(), self.ops_per_sec(), self.p50, self.p99, self.found, self.ops,
+            self.wal_bytes_written, self.sst_bytes_written, self.files_per_level)
+    }
+}
+
+pub fn key(i: u64) -> String {
+    format!("{:016}", i)
+}
+
+//runs the workload on `threads` threads and waits for them
+pub fn run(db: &LsmDb, workload: Workload, options: &BenchOptions) -> Result<BenchReport> {
+    if options.threads == 0 || options.num == 0 {
+        return Err(Error::InvalidArgument("a benchmark needs at least one thread and one key".to_owned()));
+    }
+    let sst_bytes = |db: &LsmDb| {
+        let stats = db.stats().compaction;
+        stats.bytes_written + stats.flush_bytes_written
+    };
+    let (wal_before, sst_before) = (db.stats().wal_bytes_written, sst_bytes(db));
+    let now = Instant::now();
+    let results = crossbeam_utils::thread::scope(|s| {
+        let handles = (0..options.threads)
+            .map(|thread| s.spawn(move |_| run_thread(db, workload, options, thread)))
+            .collect::<Vec<_>>();
+        handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>()
+    }).unwrap();
+    let elapsed = now.elapsed();
+
+    let mut latencies = Vec::new();
+    let (mut ops, mut found) = (0, 0);
+    for res in results {
+        let (thread_ops, thread_found, thread_latencies) = res?;
+        ops += thread_ops;
+        found += thread_found;
+        latencies.extend(thread_latencies);
+    }
+    latencies.sort_unstable();
+    let percentile = |p: usize| latencies.get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)))
+        .copied()
+        .unwrap_or_default();
+    let stats = db.stats();
+    Ok(BenchReport {
+        workload,
+        ops,
+        found,
+        elapsed,
+        p50: percentile(50),
+        p99: percentile(99),
+        wal_bytes_written: stats.wal_bytes_written - wal_before,
+        sst_bytes_written: sst_bytes(db) - sst_before,
+        files_per_level: stats.levels.iter().map(|l| l.num_files).collect(),
+    })
+}
+
+//the first values of xorshift from nearby seeds are alike, so the seed and thread are mixed
+//with splitmix64 first; the state must not be 0
+fn thread_rng(seed: u64, thread: usize) -> Rng {
+    let mut z = seed.wrapping_add((thread as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    Rng(std::cmp::max(z ^ (z >> 31), 1))
+}
+
+//the ops, hits and latencies of one thread
+fn run_thread(db: &LsmDb, workload: Workload, options: &BenchOptions, thread: usize) -> Result<(u64, u64, Vec<Duration>)> {
+    let mut rng = thread_rng(options.seed, thread);
+    let threads = options.threads as u64;
+    let (start, end) = (options.num * thread as u64 / threads, options.num * (thread as u64 + 1) / threads);
+    //the values are slices of one random buffer, so generating them costs nothing
+    let buf = (0..options.value_size * 2 + 1).map(|_| rng.next() as u8).collect::<Vec<_>>();
+    let value = |rng: &mut Rng| {
+        let offset = (rng.next() % (options.value_size as u64 + 1)) as usize;
+        buf[offset..offset + options.value_size].to_vec()
+    };
+    let mut latencies = Vec::with_capacity((end - start) as usize);
+    let mut found = 0;
+    if workload == Workload::ReadSeq {
+        //the whole database is read in one call, the latency is the time per entry
+        let now = Instant::now();
+        let mut iter = db.iter()?;
+        let mut entries = 0;
+        iter.seek_to_first();
+        while iter.valid() {
+            entries += 1;
+            iter.next();
+        }
+        iter.status()?;
+        latencies.push(now.elapsed() / std::cmp::max(entries, 1) as u32);
+        return Ok((entries, entries, latencies));
+    }
+    for i in start..end {
+        let now = Instant::now();
+        match workload {
+            Workload::FillSeq => db.insert(key(i).as_bytes(), &value(&mut rng))?,
+            Workload::FillRandom | Workload::Overwrite => {
+                let k = key(rng.next() % options.num);
+                db.insert(k.as_bytes(), &value(&mut rng))?;
+            },
+            Workload::ReadRandom => {
+                found += db.search(key(rng.next() % options.num).as_bytes(), None)?.is_some() as u64;
+            },
+            Workload::Mixed => {
+                let k = key(rng.next() % options.num);
+                if rng.next().is_multiple_of(5) {
+                    db.insert(k.as_bytes(), &value(&mut rng))?;
+                } else {
+                    found += db.search(k.as_bytes(), None)?.is_some() as u64;
+                }
+            },
+            Workload::ReadSeq => unreachable!(),
+        }
+        latencies.push(now.elapsed());
+    }
+    Ok((end - start, found, latencies))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::Config;
+    use crate::utils::temp_dir;
+
+    fn tiny_db(name: &str) -> LsmDb {
+        let mut config = Config::new();
+        config.write_buffer_size = 4 * 1024;
+        config.block_size = 1024;
+        LsmDb::with_config(temp_dir(name), config).unwrap()
+    }
+
+    #[test]
+    fn every_workload_runs() {
+        let db = tiny_db("bench_workloads");
+        let options = BenchOptions { num: 300, value_size: 20, threads: 2, seed: 7 };
+        for workload in Workload::ALL.iter().copied() {
+            let report = run(&db, workload, &options).unwrap();
+            assert_eq!(Workload::parse(workload.name()).unwrap(), workload);
+            match workload {
+                //every thread reads all of it
+                Workload::ReadSeq => assert_eq!((report.ops, report.found), (600, 600)),
+                _ => assert_eq!(report.ops, 300),
+            }
+            if workload == Workload::ReadRandom {
+                assert_eq!(report.found, 300);
+            }
+            if workload == Workload::FillSeq {
+                assert!(report.wal_bytes_written > 300 * 20);
+            }
+            assert!(report.p50 <= report.p99);
+            assert!(report.to_string().starts_with(workload.name()));
+        }
+        db.flush().unwrap();
+        assert!(db.stats().compaction.flush_bytes_written > 0);
+        assert!(matches!(Workload::parse("fillsome"), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn the_same_seed_writes_the_same_data() {
+        let options = BenchOptions { num: 200, value_size: 16, threads: 1, seed: 42 };
+        let contents = |name: &str, options: &BenchOptions| {
+            let db = tiny_db(name);
+            run(&db, Workload::FillRandom, options).unwrap();
+            db.scan_prefix(b"").unwrap()
+        };
+        let first = contents("bench_seed_a", &options);
+        assert!(first.len() > 100 && first.len() < 200);
+        assert_eq!(contents("bench_seed_b", &options), first);
+        let other_seed = BenchOptions { seed: 43, ..options };
+        assert_ne!(contents("bench_seed_c", &other_seed), first);
+    }
+}
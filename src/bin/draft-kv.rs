@@ -0,0 +1,33 @@
+use draft_kv::cli::{self, USAGE};
+use draft_kv::lsm::Config;
+
+use std::io::{self, Write};
+use std::process;
+
+//e.g. draft-kv --db /var/lib/app/db scan --prefix user: --limit 10
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    let invocation = match cli::parse_args(args) {
+        Ok(invocation) => invocation,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        },
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let res = cli::run(&invocation, Config::new(), &mut out);
+    let _ = out.flush();
+    match res {
+        Ok(true) => {},
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        },
+    }
+}
@@ -0,0 +1,118 @@
+//Bloom filters as in LevelDB: k probes derived from a single 32 bit hash by double hashing.
+//The filter is the bit array followed by one byte holding k.
+
+pub const BITS_PER_KEY: f64 = 10.0;
+
+//LevelDB's murmur-like hash, with murmur3's finalizer: without it keys that differ only
+//in their last bytes share the low bits, and with them every probe of a small filter
+pub fn hash(data: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f_1d34;
+    const M: u32 = 0xc6a4_a793;
+    let mut h = SEED ^ (data.len() as u32).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let w = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        h = h.wrapping_add(w).wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, b) in rest.iter().enumerate() {
+            h = h.wrapping_add((*b as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ h >> 16
+}
+
+//ln 2 * bits per key minimizes the false positive rate
+pub fn num_probes(bits_per_key: f64) -> usize {
+    ((bits_per_key * std::f64::consts::LN_2).round() as usize).clamp(1, 30)
+}
+
+pub fn build(hashes: &[u32], bits_per_key: f64) -> Vec<u8> {
+    let k = num_probes(bits_per_key);
+    //tiny filters have a high false positive rate, so use at least 64 bits
+    let bits = ((hashes.len() as f64 * bits_per_key).ceil() as usize).max(64);
+    let bytes = bits.div_ceil(8);
+    let bits = bytes * 8;
+    let mut filter = vec![0; bytes + 1];
+    for h in hashes {
+        let delta = h.rotate_right(17);
+        let mut h = *h;
+        for _ in 0..k {
+            let pos = h as usize % bits;
+            filter[pos / 8] |= 1 << (pos % 8);
+            h = h.wrapping_add(delta);
+        }
+    }
+    filter[bytes] = k as u8;
+    filter
+}
+
+//false positives are possible, false negatives are not
+pub fn may_contain(filter: &[u8], key: &[u8]) -> bool {
+    let (k, bits) = match filter.split_last() {
+        Some((k, bits)) if !bits.is_empty() => (*k, bits),
+        //a malformed filter can not rule anything out
+        _ => return true,
+    };
+    if k > 30 {
+        return true;
+    }
+    let num_bits = bits.len() * 8;
+    let mut h = hash(key);
+    let delta = h.rotate_right(17);
+    for _ in 0..k {
+        let pos = h as usize % num_bits;
+        if bits[pos / 8] & (1 << (pos % 8)) == 0 {
+            return false;
+        }
+        h = h.wrapping_add(delta);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives_and_few_false_positives() {
+        let keys = (0..10000u32).map(|i| i.to_le_bytes()).collect::<Vec<_>>();
+        let hashes = keys.iter().map(|k| hash(k)).collect::<Vec<_>>();
+        let filter = build(&hashes, BITS_PER_KEY);
+        assert!(keys.iter().all(|k| may_contain(&filter, k)));
+        let false_positives = (10000..20000u32)
+            .filter(|i| may_contain(&filter, &i.to_le_bytes()))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+        assert!(!may_contain(&build(&[], BITS_PER_KEY), b"missing"));
+        //big-endian ids only differ in their last byte
+        let ids = [1u64, 2, 8, 9].iter().map(|i| hash(&i.to_be_bytes())).collect::<Vec<_>>();
+        let filter = build(&ids, BITS_PER_KEY);
+        let false_positives = (10..100u64)
+            .filter(|i| may_contain(&filter, &i.to_be_bytes()))
+            .count();
+        assert!(false_positives < 10, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn more_bits_per_key_mean_more_probes_and_fewer_false_positives() {
+        assert_eq!([0.5, 2.0, 10.0, 16.0, 100.0].map(num_probes), [1, 1, 7, 11, 30]);
+        let hashes = (0..10000u32).map(|i| hash(&i.to_le_bytes())).collect::<Vec<_>>();
+        let false_positives = |bits_per_key: f64| {
+            let filter = build(&hashes, bits_per_key);
+            (10000..30000u32).filter(|i| may_contain(&filter, &i.to_le_bytes())).count()
+        };
+        let (few, many) = (false_positives(4.0), false_positives(16.0));
+        assert!(many * 10 < few, "{} false positives with 16 bits per key, {} with 4", many, few);
+        //a fraction of a bit per key still counts
+        assert!(build(&hashes, 4.5).len() > build(&hashes, 4.0).len());
+    }
+}
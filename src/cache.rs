@@ -0,0 +1,333 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::stats::CacheStats;
+
+use bytes::Bytes;
+use parking_lot::{Mutex, MutexGuard};
+
+//a data block is named by the number of its table and its offset in the file
+type BlockKey = (u64, u64);
+
+//the index and filter blocks of a table are charged under this offset, they are kept by the table itself
+const META_OFFSET: u64 = u64::MAX;
+
+/// LRU cache of data blocks read by lookups, split into 2^shard_bits shards so parallel reads
+/// rarely wait on the same lock. Each shard holds its share of the capacity and evicts on its own.
+/// Pinned blocks are never evicted, only dropped with their table.
+#[derive(Debug)]
+pub struct BlockCache {
+    shards: Vec<Mutex<Shard>>,
+    shard_bits: u32,
+    shard_capacity: usize, //bytes of blocks
+    lock_waits: AtomicU64, //lookups and inserts that found their shard locked
+    warned_overflow: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    blocks: HashMap<BlockKey, (Bytes, usize, Option<u64>)>, //with its charge and the tick of its last use, None if pinned
+    lru: BTreeMap<u64, BlockKey>, //least recently used first, pinned blocks are not in it
+    next_tick: u64,
+    usage: usize,
+    pinned_usage: usize,
+    pinned_meta_usage: usize, //part of the pinned usage charged for index and filter blocks
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl Shard {
+    fn touch(&mut self, key: BlockKey) -> Option<Bytes> {
+        let tick = self.next_tick;
+        let (block, _, last_used) = self.blocks.get_mut(&key)?;
+        if let Some(last_used) = last_used {
+            self.lru.remove(last_used);
+            self.lru.insert(tick, key);
+            *last_used = tick;
+            self.next_tick += 1;
+        }
+        Some(block.clone())
+    }
+
+    //evicts unpinned blocks until `charge` more bytes fit, false if even evicting all of them is not enough
+    fn make_room(&mut self, charge: usize, capacity: usize) -> bool {
+        if self.pinned_usage + charge > capacity {
+            return false;
+        }
+        while self.usage + charge > capacity {
+            let (_, evicted) = self.lru.pop_first().unwrap();
+            let (_, evicted, _) = self.blocks.remove(&evicted).unwrap();
+            self.usage -= evicted;
+            self.evictions += 1;
+        }
+        true
+    }
+
+    fn add(&mut self, key: BlockKey, block: Bytes, charge: usize, pinned: bool) {
+        let last_used = match pinned {
+            true => {
+                self.pinned_usage += charge;
+                if key.1 == META_OFFSET {
+                    self.pinned_meta_usage += charge;
+                }
+                None
+            },
+            false => {
+                self.lru.insert(self.next_tick, key);
+                self.next_tick += 1;
+                Some(self.next_tick - 1)
+            },
+        };
+        self.usage += charge;
+        self.blocks.insert(key, (block, charge, last_used));
+    }
+
+    fn remove(&mut self, key: BlockKey) {
+        if let Some((_, charge, last_used)) = self.blocks.remove(&key) {
+            self.usage -= charge;
+            match last_used {
+                Some(tick) => { self.lru.remove(&tick); },
+                None if key.1 == META_OFFSET => {
+                    self.pinned_usage -= charge;
+                    self.pinned_meta_usage -= charge;
+                },
+                None => self.pinned_usage -= charge,
+            }
+        }
+    }
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize, shard_bits: u32) -> Self {
+        BlockCache {
+            shards: (0..1 << shard_bits).map(|_| Mutex::new(Shard::default())).collect(),
+            shard_bits,
+            shard_capacity: capacity >> shard_bits,
+            lock_waits: AtomicU64::new(0),
+            warned_overflow: AtomicBool::new(false),
+        }
+    }
+
+    //murmur3's finalizer, so tables and offsets that differ in few bits still land in other shards
+    fn shard_idx(&self, key: BlockKey) -> usize {
+        let mut h = key.0.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ key.1;
+        h = (h ^ (h >> 33)).wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h = (h ^ (h >> 33)).wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^= h >> 33;
+        (h & ((1 << self.shard_bits) - 1)) as usize
+    }
+
+    fn shard(&self, key: BlockKey) -> MutexGuard<'_, Shard> {
+        let shard = &self.shards[self.shard_idx(key)];
+        shard.try_lock().unwrap_or_else(|| {
+            self.lock_waits.fetch_add(1, Ordering::Relaxed);
+            shard.lock()
+        })
+    }
+
+    pub fn get(&self, file_num: u64, offset: u64) -> Option<Bytes> {
+        let mut shard = self.shard((file_num, offset));
+        let block = shard.touch((file_num, offset));
+        match block {
+            Some(_) => shard.hits += 1,
+            None => shard.misses += 1,
+        }
+        block
+    }
+
+    //evicts the least recently used blocks of the shard until it fits, a block larger than the shard is not kept
+    pub fn insert(&self, file_num: u64, offset: u64, block: Bytes) {
+        let key = (file_num, offset);
+        let mut shard = self.shard(key);
+        if shard.touch(key).is_some() {
+            return;
+        }
+        let charge = block.len();
+        if shard.make_room(charge, self.shard_capacity) {
+            shard.add(key, block, charge, false);
+        }
+    }
+
+    //a pinned block that would leave no room in its shard is kept unpinned instead, false then.
+    //A charge for index and filter blocks is dropped instead, the table holds them either way.
+    pub fn insert_pinned(&self, file_num: u64, offset: u64, block: Bytes) -> bool {
+        let charge = block.len();
+        self.pin((file_num, offset), block, charge)
+    }
+
+    //charges the index and filter blocks of a table as pinned, the table holds them either way
+    pub fn pin_meta(&self, file_num: u64, charge: usize) -> bool {
+        self.pin((file_num, META_OFFSET), Bytes::new(), charge)
+    }
+
+    fn pin(&self, key: BlockKey, block: Bytes, charge: usize) -> bool {
+        let mut shard = self.shard(key);
+        if let Some((_, _, None)) = shard.blocks.get(&key) {
+            return true;
+        }
+        shard.remove(key);
+        if shard.pinned_usage + charge <= self.shard_capacity && shard.make_room(charge, self.shard_capacity) {
+            shard.add(key, block, charge, true);
+            return true;
+        }
+        if !self.warned_overflow.swap(true, Ordering::Relaxed) {
+            log::warn!("pinned blocks exceed the block cache of {} bytes, further ones are cached unpinned",
+                self.shard_capacity << self.shard_bits);
+        }
+        if key.1 != META_OFFSET && shard.make_room(charge, self.shard_capacity) {
+            shard.add(key, block, charge, false);
+        }
+        false
+    }
+
+    //the pinned blocks of the table become ordinary ones, its index and filter charge stays pinned
+    pub fn unpin_file(&self, file_num: u64) {
+        self.retain_file(file_num, true);
+    }
+
+    //drops every block of a deleted table, pinned or not
+    pub fn erase_file(&self, file_num: u64) {
+        self.retain_file(file_num, false);
+    }
+
+    fn retain_file(&self, file_num: u64, unpin: bool) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock();
+            let keys = shard.blocks.iter()
+                .filter(|(key, (_, _, last_used))| key.0 == file_num && !(unpin && (last_used.is_some() || key.1 == META_OFFSET)))
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>();
+            for key in keys {
+                let block = shard.blocks.get(&key).map(|(block, _, _)| block.clone()).unwrap();
+                shard.remove(key);
+                if unpin {
+                    let charge = block.len();
+                    shard.add(key, block, charge, false);
+                }
+            }
+        }
+    }
+
+    //summed over the shards
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            capacity: self.shard_capacity << self.shard_bits,
+            shards: self.shards.len(),
+            lock_waits: self.lock_waits.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        };
+        for shard in self.shards.iter() {
+            let shard = shard.lock();
+            stats.hits += shard.hits;
+            stats.misses += shard.misses;
+            stats.evictions += shard.evictions;
+            stats.usage += shard.usage;
+            stats.pinned_usage += shard.pinned_usage;
+            stats.pinned_meta_usage += shard.pinned_meta_usage;
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn block(len: usize) -> Bytes {
+        Bytes::from(vec![7; len])
+    }
+
+    #[test]
+    fn least_recently_used_blocks_are_evicted() {
+        let cache = BlockCache::new(300, 0);
+        cache.insert(1, 0, block(100));
+        cache.insert(1, 100, block(100));
+        cache.insert(2, 0, block(100));
+        assert!(cache.get(1, 0).is_some());
+        cache.insert(2, 100, block(100));
+        assert!(cache.get(1, 100).is_none());
+        assert!(cache.get(1, 0).is_some() && cache.get(2, 0).is_some() && cache.get(2, 100).is_some());
+        cache.insert(3, 0, block(301));
+        assert!(cache.get(3, 0).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions, stats.usage), (4, 2, 1, 300));
+    }
+
+    #[test]
+    fn capacity_and_counters_are_split_over_the_shards() {
+        let cache = BlockCache::new(64 * 1000, 6);
+        for offset in 0..200 {
+            cache.insert(offset % 3, offset * 100, block(100));
+        }
+        let used = cache.shards.iter().filter(|s| s.lock().usage > 0).count();
+        assert!(used > 32, "only {} of 64 shards used", used);
+        assert!(cache.shards.iter().all(|s| s.lock().usage <= 1000));
+        for offset in 0..200 {
+            cache.get(offset % 3, offset * 100);
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.capacity, stats.shards), (64 * 1000, 64));
+        assert_eq!(stats.hits + stats.misses, 200);
+        assert_eq!(stats.hits as usize * 100, stats.usage);
+        assert_eq!(stats.evictions, 200 - stats.hits);
+    }
+
+    #[test]
+    fn only_readers_of_the_same_shard_wait() {
+        let cache = Arc::new(BlockCache::new(1 << 20, 6));
+        let other = (1..).map(|offset| (2, offset)).find(|&k| cache.shard_idx(k) != cache.shard_idx((1, 0))).unwrap();
+        let before = cache.stats().lock_waits;
+
+        let held = cache.shard((1, 0));
+        let reader = {
+            let cache = cache.clone();
+            std::thread::spawn(move || cache.get(other.0, other.1))
+        };
+        assert!(reader.join().unwrap().is_none());
+        assert_eq!(cache.lock_waits.load(Ordering::Relaxed), before);
+
+        let reader = {
+            let cache = cache.clone();
+            std::thread::spawn(move || cache.get(1, 0))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        drop(held);
+        assert!(reader.join().unwrap().is_none());
+        assert_eq!(cache.lock_waits.load(Ordering::Relaxed), before + 1);
+    }
+
+    #[test]
+    fn pinned_blocks_are_not_evicted_until_their_table_is_gone() {
+        let cache = BlockCache::new(400, 0);
+        assert!(cache.insert_pinned(1, 0, block(100)));
+        assert!(cache.pin_meta(1, 50));
+        for offset in 0..10 {
+            cache.insert(2, offset * 100, block(100));
+        }
+        assert!(cache.get(1, 0).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.usage, stats.pinned_usage, stats.pinned_meta_usage, stats.evictions), (350, 150, 50, 8));
+
+        //past the capacity pinned blocks overflow into ordinary ones
+        assert!(cache.insert_pinned(3, 0, block(200)));
+        assert!(!cache.insert_pinned(3, 200, block(100)));
+        assert_eq!(cache.stats().pinned_usage, 350);
+        cache.insert(2, 5000, block(50));
+        assert!(cache.get(3, 200).is_none());
+
+        cache.unpin_file(3);
+        assert_eq!(cache.stats().pinned_usage, 150);
+        assert!(cache.get(3, 0).is_some());
+        cache.erase_file(1);
+        let stats = cache.stats();
+        assert_eq!((stats.pinned_usage, stats.pinned_meta_usage, stats.usage), (0, 0, 250));
+        //the charge of a table that does not fit is not kept at all
+        assert!(!cache.pin_meta(4, 500));
+        assert_eq!(cache.stats().usage, 250);
+        assert!(cache.get(1, 0).is_none());
+    }
+}
@@ -0,0 +1,259 @@
+//! The commands of the `draft-kv` binary, parsed and run here so they can be tested without a process.
+//!
+//! Reads open the database read-only, so a directory still locked by a service or left by a stopped one
+//! can be inspected. Writes, flushes and compactions take the lock like any other open.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::error::{Error, Result};
+use crate::lsm::{Config, LsmDb};
+
+pub const USAGE: &str = "usage: draft-kv --db PATH [--hex | --raw] COMMAND
+commands:
+    get KEY                          print the value of KEY, exits with 1 if there is none
+    put KEY VALUE
+    del KEY
+    scan [--prefix P] [--limit N]    print KEY<tab>VALUE lines in key order
+    stats
+    compact                          flush, then compact every level into the next
+    flush
+    verify                           read back every table and check it
+keys and values are printed with non-printable bytes escaped as \\xNN, and arguments take the same escapes.
+--hex reads and prints them as hex, --raw takes arguments verbatim and prints bytes as they are.";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Escaped, //printable ascii as it is, the rest as \xNN
+    Hex,
+    Raw,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Get { key: Vec<u8> },
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Del { key: Vec<u8> },
+    Scan { prefix: Vec<u8>, limit: Option<usize> },
+    Stats,
+    Compact,
+    Flush,
+    Verify,
+}
+
+impl Command {
+    //these open the database with its lock, the rest read-only
+    pub fn mutates(&self) -> bool {
+        matches!(self, Command::Put { .. } | Command::Del { .. } | Command::Compact | Command::Flush)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invocation {
+    pub db: PathBuf,
+    pub format: Format,
+    pub command: Command,
+}
+
+//flags may come before or after the command, their values either as --flag=value or as the next argument
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Invocation> {
+    let invalid = |msg: String| Error::InvalidArgument(msg);
+    let mut args = args.into_iter();
+    let mut db = None;
+    let mut format = Format::Escaped;
+    let mut prefix = None;
+    let mut limit = None;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+        let (flag, inline) = match arg.find('=') {
+            Some(pos) => (arg[..pos].to_owned(), Some(arg[pos + 1..].to_owned())),
+            None => (arg.clone(), None),
+        };
+        let mut value = || inline.clone().or_else(|| args.next()).ok_or_else(|| invalid(format!("{} needs a value", flag)));
+        match flag.as_str() {
+            "--db" => db = Some(PathBuf::from(value()?)),
+            "--prefix" => prefix = Some(value()?),
+            "--limit" => {
+                let value = value()?;
+                limit = Some(value.parse().map_err(|_| invalid(format!("--limit needs a number, got {:?}", value)))?);
+            },
+            "--hex" | "--raw" if format != Format::Escaped => return Err(invalid("--hex and --raw exclude each other".to_owned())),
+            "--hex" => format = Format::Hex,
+            "--raw" => format = Format::Raw,
+            _ => return Err(invalid(format!("unknown flag {}", arg))),
+        }
+    }
+    let db = db.ok_or_else(|| invalid("--db is required".to_owned()))?;
+    let mut positional = positional.into_iter();
+    let name = positional.next().ok_or_else(|| invalid("no command given".to_owned()))?;
+    let mut operand = |what: &str| positional.next()
+        .ok_or_else(|| invalid(format!("{} needs {}", name, what)))
+        .and_then(|arg| decode(&arg, format));
+    let command = match name.as_str() {
+        "get" => Command::Get { key: operand("a key")? },
+        "put" => Command::Put { key: operand("a key")?, value: operand("a value")? },
+        "del" => Command::Del { key: operand("a key")? },
+        "scan" => Command::Scan {
+            prefix: prefix.take().map_or(Ok(Vec::new()), |p| decode(&p, format))?,
+            limit: limit.take(),
+        },
+        "stats" => Command::Stats,
+        "compact" => Command::Compact,
+        "flush" => Command::Flush,
+        "verify" => Command::Verify,
+        _ => return Err(invalid(format!("unknown command {}", name))),
+    };
+    if let Some(extra) = positional.next() {
+        return Err(invalid(format!("unexpected argument {:?} after {}", extra, name)));
+    }
+    if prefix.is_some() || limit.is_some() {
+        return Err(invalid("--prefix and --limit only go with scan".to_owned()));
+    }
+    Ok(Invocation { db, format, command })
+}
+
+//runs the command on a database opened with `config`, false if get found nothing
+pub fn run(invocation: &Invocation, config: Config, out: &mut dyn Write) -> Result<bool> {
+    let max_levels = config.max_levels;
+    let db = match invocation.command.mutates() {
+        true => LsmDb::with_config(invocation.db.clone(), config)?,
+        false => LsmDb::open_read_only(invocation.db.clone(), config)?,
+    };
+    let format = invocation.format;
+    match &invocation.command {
+        Command::Get { key } => match db.search(key, None)? {
+            Some(value) => writeln_bytes(out, &[&encode(&value, format)])?,
+            None => return Ok(false),
+        },
+        Command::Put { key, value } => db.insert(key, value)?,
+        Command::Del { key } => db.delete(key)?,
+        Command::Scan { prefix, limit } => {
+            let entries = db.scan_prefix(prefix)?;
+            for (key, value) in entries.iter().take(limit.unwrap_or(usize::MAX)) {
+                writeln_bytes(out, &[&encode(key, format), b"\t", &encode(value, format)])?;
+            }
+        },
+        Command::Stats => writeln!(out, "{:#?}\n{:#?}", db.stats(), db.memory_usage())?,
+        Command::Compact => {
+            db.flush()?;
+            let (mut input_files, mut output_files) = (0, 0);
+            for level in 0..max_levels - 1 {
+                let summary = db.compact_level(level)?;
+                input_files += summary.input_files;
+                output_files += summary.output_files;
+            }
+            writeln!(out, "compacted {} tables into {}", input_files, output_files)?;
+        },
+        Command::Flush => db.flush()?,
+        Command::Verify => writeln!(out, "verified {} tables", db.verify()?)?,
+    }
+    Ok(true)
+}
+
+fn writeln_bytes(out: &mut dyn Write, parts: &[&[u8]]) -> Result<()> {
+    for part in parts {
+        out.write_all(part)?;
+    }
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+pub fn encode(bytes: &[u8], format: Format) -> Vec<u8> {
+    match format {
+        Format::Escaped => bytes.escape_ascii().to_string().into_bytes(),
+        Format::Hex => bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>().into_bytes(),
+        Format::Raw => bytes.to_vec(),
+    }
+}
+
+//the inverse of encode, escaped arguments take the escapes escape_ascii writes
+pub fn decode(arg: &str, format: Format) -> Result<Vec<u8>> {
+    let invalid = || Error::InvalidArgument(format!("{:?} is not valid {}", arg, match format {
+        Format::Hex => "hex",
+        _ => "escaped text",
+    }));
+    let hex_byte = |digits: &[u8]| match digits.len() == 2 && digits.iter().all(u8::is_ascii_hexdigit) {
+        true => std::str::from_utf8(digits).ok().and_then(|d| u8::from_str_radix(d, 16).ok()),
+        false => None,
+    };
+    match format {
+        Format::Raw => Ok(arg.as_bytes().to_vec()),
+        Format::Hex => arg.as_bytes().chunks(2).map(|digits| hex_byte(digits).ok_or_else(invalid)).collect(),
+        Format::Escaped => {
+            let bytes = arg.as_bytes();
+            let mut decoded = Vec::with_capacity(bytes.len());
+            let mut i = 0;
+            while i < bytes.len() {
+                if bytes[i] != b'\\' {
+                    decoded.push(bytes[i]);
+                    i += 1;
+                    continue;
+                }
+                let (byte, len) = match bytes.get(i + 1).ok_or_else(invalid)? {
+                    b'n' => (b'\n', 2),
+                    b'r' => (b'\r', 2),
+                    b't' => (b'\t', 2),
+                    b'0' => (0, 2),
+                    c @ (b'\\' | b'\'' | b'"') => (*c, 2),
+                    b'x' => (bytes.get(i + 2..i + 4).and_then(hex_byte).ok_or_else(invalid)?, 4),
+                    _ => return Err(invalid()),
+                };
+                decoded.push(byte);
+                i += len;
+            }
+            Ok(decoded)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn commands_and_flags_parse_in_any_order() {
+        let invocation = parse_args(args("--db /tmp/db get user:1")).unwrap();
+        assert_eq!(invocation, Invocation {
+            db: PathBuf::from("/tmp/db"),
+            format: Format::Escaped,
+            command: Command::Get { key: b"user:1".to_vec() },
+        });
+        let invocation = parse_args(args("scan --limit=10 --db=/tmp/db --hex --prefix 00ff")).unwrap();
+        assert_eq!(invocation.format, Format::Hex);
+        assert_eq!(invocation.command, Command::Scan { prefix: vec![0, 0xff], limit: Some(10) });
+        assert_eq!(parse_args(args("--db d --raw put k v")).unwrap().command, Command::Put { key: b"k".to_vec(), value: b"v".to_vec() });
+        assert!(!parse_args(args("--db d verify")).unwrap().command.mutates());
+        assert!(parse_args(args("--db d compact")).unwrap().command.mutates());
+
+        for bad in ["get k", "--db d", "--db d get", "--db d put k", "--db d get k extra", "--db d frobnicate",
+            "--db d --hex --raw stats", "--db d get --limit 1 k", "--db d scan --limit ten", "--db d --hex get abc", "--db"] {
+            assert!(matches!(parse_args(args(bad)), Err(Error::InvalidArgument(_))), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip_through_every_format() {
+        let bytes = b"a\x00\xff\t\\'\" z".to_vec();
+        for format in [Format::Escaped, Format::Hex] {
+            let encoded = String::from_utf8(encode(&bytes, format)).unwrap();
+            assert_eq!(decode(&encoded, format).unwrap(), bytes, "{}", encoded);
+        }
+        assert_eq!(encode(&bytes, Format::Escaped), b"a\\x00\\xff\\t\\\\\\'\\\" z".to_vec());
+        assert_eq!(encode(b"\x01\xab", Format::Hex), b"01ab".to_vec());
+        assert_eq!(encode(&bytes, Format::Raw), bytes);
+        assert_eq!(decode("\\x", Format::Raw).unwrap(), b"\\x".to_vec());
+        for bad in ["\\", "\\x4", "\\xzz", "\\q"] {
+            assert!(decode(bad, Format::Escaped).is_err(), "{:?} decoded", bad);
+        }
+        assert!(decode("0g", Format::Hex).is_err());
+        assert!(decode("+1", Format::Hex).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! Order-preserving encodings for building keys: comparing the encoded bytes gives the same order
+//! as comparing the values, so range scans over encoded keys visit the values in order.
+//!
+//! Integers are big-endian, with the sign bit flipped for signed ones. Floats follow `f64::total_cmp`:
+//! -NaN < -inf < ... < -0.0 < 0.0 < ... < inf < NaN, so every NaN has a place and -0.0 sorts before 0.0.
+//! Byte strings are escaped instead of prefixed with their length, a length prefix would sort by length first:
+//! every 0 becomes 0 0xff and the string ends with 0 0, so no encoded string is a prefix of another.
+
+use crate::error::{Error, Result};
+
+pub fn encode_u64(v: u64) -> [u8; 8] {
+    v.to_be_bytes()
+}
+
+pub fn decode_u64(bytes: &[u8]) -> Result<u64> {
+    let mut reader = TupleReader::new(bytes);
+    let v = reader.read_u64()?;
+    reader.finish().map(|_| v)
+}
+
+pub fn encode_i64(v: i64) -> [u8; 8] {
+    (v as u64 ^ 1 << 63).to_be_bytes()
+}
+
+pub fn decode_i64(bytes: &[u8]) -> Result<i64> {
+    let mut reader = TupleReader::new(bytes);
+    let v = reader.read_i64()?;
+    reader.finish().map(|_| v)
+}
+
+pub fn encode_f64(v: f64) -> [u8; 8] {
+    let bits = v.to_bits();
+    //negative numbers grow with their magnitude, so all their bits are flipped
+    let bits = if bits >> 63 == 1 { !bits } else { bits | 1 << 63 };
+    bits.to_be_bytes()
+}
+
+pub fn decode_f64(bytes: &[u8]) -> Result<f64> {
+    let mut reader = TupleReader::new(bytes);
+    let v = reader.read_f64()?;
+    reader.finish().map(|_| v)
+}
+
+pub fn encode_bytes(v: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(v.len() + 2);
+    put_bytes(&mut buf, v);
+    buf
+}
+
+pub fn decode_bytes(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = TupleReader::new(bytes);
+    let v = reader.read_bytes()?;
+    reader.finish().map(|_| v)
+}
+
+fn put_bytes(buf: &mut Vec<u8>, v: &[u8]) {
+    for &b in v {
+        buf.push(b);
+        if b == 0 {
+            buf.push(0xff);
+        }
+    }
+    buf.extend_from_slice(&[0, 0]);
+}
+
+/// Builds a composite key out of encoded fields. Tuples compare field by field,
+/// and a tuple sorts right before every longer tuple it is a prefix of.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tuple {
+    buf: Vec<u8>,
+}
+
+impl Tuple {
+    pub fn new() -> Self {
+        Tuple {
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn push_u64(mut self, v: u64) -> Self {
+        self.buf.extend_from_slice(&encode_u64(v));
+        self
+    }
+
+    pub fn push_i64(mut self, v: i64) -> Self {
+        self.buf.extend_from_slice(&encode_i64(v));
+        self
+    }
+
+    pub fn push_f64(mut self, v: f64) -> Self {
+        self.buf.extend_from_slice(&encode_f64(v));
+        self
+    }
+
+    pub fn push_bytes(mut self, v: &[u8]) -> Self {
+        put_bytes(&mut self.buf, v);
+        self
+    }
+
+    pub fn push_str(self, v: &str) -> Self {
+        self.push_bytes(v.as_bytes())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads the fields of an encoded tuple back, in the order they were pushed.
+pub struct TupleReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> TupleReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        TupleReader {
+            bytes,
+            pos: 0,
+        }
+    }
+
+    fn read_fixed(&mut self) -> Result<[u8; 8]> {
+        let mut buf = [0; 8];
+        let field = self.bytes.get(self.pos..self.pos + 8)
+            .ok_or_else(|| Error::InvalidArgument(format!("no 8 byte field at offset {}", self.pos)))?;
+        buf.copy_from_slice(field);
+        self.pos += 8;
+        Ok(buf)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        self.read_fixed().map(u64::from_be_bytes)
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64> {
+        self.read_fixed().map(|buf| (u64::from_be_bytes(buf) ^ 1 << 63) as i64)
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64> {
+        let bits = u64::from_be_bytes(self.read_fixed()?);
+        let bits = if bits >> 63 == 1 { bits & !(1 << 63) } else { !bits };
+        Ok(f64::from_bits(bits))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>> {
+        let start = self.pos;
+        let mut res = Vec::new();
+        loop {
+            match self.bytes.get(self.pos..self.pos + 2) {
+                Some([0, 0]) => break,
+                Some([0, 0xff]) => {
+                    res.push(0);
+                    self.pos += 2;
+                },
+                Some([0, _]) => return Err(Error::InvalidArgument(format!("invalid escape at offset {}", self.pos))),
+                Some([b, _]) => {
+                    res.push(*b);
+                    self.pos += 1;
+                },
+                _ => return Err(Error::InvalidArgument(format!("unterminated byte string at offset {}", start))),
+            }
+        }
+        self.pos += 2;
+        Ok(res)
+    }
+
+    pub fn read_str(&mut self) -> Result<String> {
+        let pos = self.pos;
+        String::from_utf8(self.read_bytes()?)
+            .map_err(|_| Error::InvalidArgument(format!("invalid utf-8 string at offset {}", pos)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    //fails if anything is left after the fields read so far
+    pub fn finish(self) -> Result<()> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(Error::InvalidArgument(format!("{} trailing bytes", self.bytes.len() - self.pos))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Rng;
+    use std::cmp::Ordering;
+
+    fn short_bytes(rng: &mut Rng) -> Vec<u8> {
+        //few distinct bytes, so escapes, shared prefixes and equal strings are common
+        let len = rng.next() % 5;
+        (0..len).map(|_| [0, 1, 0xff, b'a'][(rng.next() % 4) as usize]).collect()
+    }
+
+    fn assert_order_preserved<T, F, C>(values: &[T], encode: F, cmp: C)
+    where
+        T: std::fmt::Debug,
+        F: Fn(&T) -> Vec<u8>,
+        C: Fn(&T, &T) -> Ordering,
+    {
+        for a in values {
+            for b in values {
+                assert_eq!(encode(a).cmp(&encode(b)), cmp(a, b), "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn integers_keep_their_order() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut unsigned = vec![0, 1, 255, 256, u64::MAX - 1, u64::MAX, 1 << 63];
+        unsigned.extend((0..100).map(|_| rng.next()));
+        assert_order_preserved(&unsigned, |v| encode_u64(*v).to_vec(), |a, b| a.cmp(b));
+        let mut signed = vec![i64::MIN, i64::MIN + 1, -256, -1, 0, 1, 255, i64::MAX - 1, i64::MAX];
+        signed.extend((0..100).map(|_| rng.next() as i64));
+        assert_order_preserved(&signed, |v| encode_i64(*v).to_vec(), |a, b| a.cmp(b));
+        for v in unsigned {
+            assert_eq!(decode_u64(&encode_u64(v)).unwrap(), v);
+        }
+        for v in signed {
+            assert_eq!(decode_i64(&encode_i64(v)).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn floats_follow_total_order() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut values = vec![
+            f64::NEG_INFINITY, f64::MIN, -1.0, -f64::MIN_POSITIVE, -0.0, 0.0,
+            f64::MIN_POSITIVE, 1.0, f64::MAX, f64::INFINITY, f64::NAN, -f64::NAN,
+            f64::from_bits(1), -f64::from_bits(1),
+        ];
+        values.extend((0..100).map(|_| f64::from_bits(rng.next())));
+        assert_order_preserved(&values, |v| encode_f64(*v).to_vec(), |a, b| a.total_cmp(b));
+        for v in values {
+            assert_eq!(decode_f64(&encode_f64(v)).unwrap().to_bits(), v.to_bits());
+        }
+    }
+
+    #[test]
+    fn byte_strings_keep_their_order_and_boundaries() {
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+        let mut values = vec![vec![], vec![0], vec![0, 0], vec![0xff], vec![0, 0xff], vec![1], vec![0xff, 0]];
+        values.extend((0..200).map(|_| short_bytes(&mut rng)));
+        assert_order_preserved(&values, |v| encode_bytes(v), |a, b| a.cmp(b));
+        for v in values.iter() {
+            assert_eq!(&decode_bytes(&encode_bytes(v)).unwrap(), v);
+        }
+        //a string followed by more fields still compares as the string alone
+        let pairs = values.iter()
+            .map(|v| (v.clone(), short_bytes(&mut rng)))
+            .collect::<Vec<_>>();
+        assert_order_preserved(&pairs, |(a, b)| Tuple::new().push_bytes(a).push_bytes(b).into_bytes(), |x, y| x.cmp(y));
+    }
+
+    #[test]
+    fn tuples_compare_field_by_field() {
+        let mut rng = Rng(0x1234_5678_9abc_def1);
+        let mut values = (0..100)
+            .map(|_| (rng.next() as i64 % 3, String::from_utf8(short_bytes(&mut rng)).unwrap_or_default(), rng.next() % 3))
+            .collect::<Vec<_>>();
+        values.push((i64::MIN, String::new(), 0));
+        values.push((i64::MAX, "\u{0}".to_owned(), u64::MAX));
+        let encode = |(a, b, c): &(i64, String, u64)| Tuple::new().push_i64(*a).push_str(b).push_u64(*c).into_bytes();
+        assert_order_preserved(&values, encode, |x, y| x.cmp(y));
+        for v in values.iter() {
+            let bytes = encode(v);
+            let mut reader = TupleReader::new(&bytes);
+            let decoded = (reader.read_i64().unwrap(), reader.read_str().unwrap(), reader.read_u64().unwrap());
+            reader.finish().unwrap();
+            assert_eq!(&decoded, v);
+        }
+        //a prefix sorts first
+        assert!(Tuple::new().push_u64(7) < Tuple::new().push_u64(7).push_bytes(b""));
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        assert!(matches!(decode_u64(&[1, 2, 3]), Err(Error::InvalidArgument(_))));
+        assert!(matches!(decode_u64(&[0; 9]), Err(Error::InvalidArgument(_))));
+        assert!(matches!(decode_bytes(b"abc"), Err(Error::InvalidArgument(_))));
+        assert!(matches!(decode_bytes(&[b'a', 0, 7, 0, 0]), Err(Error::InvalidArgument(_))));
+        assert!(matches!(decode_bytes(&[0, 0, 0]), Err(Error::InvalidArgument(_))));
+        let bytes = Tuple::new().push_bytes(&[0xff, 0xfe]).into_bytes();
+        assert!(matches!(TupleReader::new(&bytes).read_str(), Err(Error::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,24 @@
+use std::panic::{self, AssertUnwindSafe};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    Remove,
+    Change(Vec<u8>), //keep the key with this value instead
+}
+
+/// Decides what happens to the newest version of each key while it is rewritten by a compaction.
+/// Tombstones are not passed to the filter, and neither is anything still in a mem table.
+pub trait CompactionFilter: Send + Sync {
+    //`level` is the level the entry is written to
+    fn filter(&self, level: usize, key: &[u8], value: &[u8]) -> FilterDecision;
+}
+
+//a panicking filter keeps the entry rather than failing the whole compaction
+pub fn filter_or_keep(filter: &dyn CompactionFilter, level: usize, key: &[u8], value: &[u8]) -> FilterDecision {
+    panic::catch_unwind(AssertUnwindSafe(|| filter.filter(level, key, value)))
+        .unwrap_or_else(|_| {
+            log::error!("compaction filter panicked on key {:?}, keeping it", key);
+            FilterDecision::Keep
+        })
+}
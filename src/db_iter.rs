@@ -0,0 +1,512 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::error::{Error, Result};
+use crate::events::TableFile;
+use crate::key::{verify_value_checksum, InternalKey, LookUpKey, ValueType, MAX_SEQ_NUM};
+use crate::value_log::ValueLogs;
+
+//A position in one sorted source of stored entries, all versions of every key included.
+//Once it steps past either end it is no longer valid, only a seek brings it back.
+pub(crate) trait Cursor: Send {
+    fn valid(&self) -> bool;
+    fn seek(&mut self, key: &LookUpKey); //the first entry not less than `key`
+    fn seek_to_first(&mut self);
+    fn seek_to_last(&mut self);
+    fn next(&mut self);
+    fn prev(&mut self);
+    fn key(&self) -> &LookUpKey;
+    fn value(&self) -> &Bytes;
+    //why the cursor stopped early, a read past its deadline
+    fn status(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+//over entries copied out of a mem table
+pub(crate) struct VecCursor {
+    entries: Vec<(LookUpKey, Bytes)>,
+    pos: usize, //entries.len() when not valid
+}
+
+impl VecCursor {
+    pub fn new(entries: Vec<(LookUpKey, Bytes)>) -> Self {
+        let pos = entries.len();
+        VecCursor { entries, pos }
+    }
+}
+
+impl Cursor for VecCursor {
+    fn valid(&self) -> bool {
+        self.pos < self.entries.len()
+    }
+
+    fn seek(&mut self, key: &LookUpKey) {
+        self.pos = self.entries.partition_point(|(k, _)| k < key);
+    }
+
+    fn seek_to_first(&mut self) {
+        self.pos = 0;
+    }
+
+    fn seek_to_last(&mut self) {
+        self.pos = self.entries.len().saturating_sub(1);
+    }
+
+    fn next(&mut self) {
+        self.pos += 1;
+    }
+
+    fn prev(&mut self) {
+        self.pos = match self.pos {
+            0 => self.entries.len(),
+            pos => pos - 1,
+        };
+    }
+
+    fn key(&self) -> &LookUpKey {
+        &self.entries[self.pos].0
+    }
+
+    fn value(&self) -> &Bytes {
+        &self.entries[self.pos].1
+    }
+}
+
+//a child of the merging cursor by its current key, the heap pops the one to go on with first
+struct HeapEntry {
+    key: LookUpKey,
+    child: usize,
+    reverse: bool,
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let by_key = match self.reverse {
+            true => self.key.cmp(&other.key),
+            false => other.key.cmp(&self.key),
+        };
+        //equal keys come from the newer source first, which is the earlier child
+        by_key.then_with(|| other.child.cmp(&self.child))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+//All children merged into one cursor. The current child is out of the heap, the others are in it
+//by their keys. Changing direction positions every other child on the other side of the current key.
+struct MergingCursor {
+    children: Vec<Box<dyn Cursor>>, //newer sources first
+    heap: BinaryHeap<HeapEntry>,
+    current: Option<usize>,
+    reverse: bool,
+}
+
+impl MergingCursor {
+    fn new(children: Vec<Box<dyn Cursor>>) -> Self {
+        MergingCursor { children, heap: BinaryHeap::new(), current: None, reverse: false }
+    }
+
+    fn push(&mut self, child: usize) {
+        if self.children[child].valid() {
+            let key = self.children[child].key().clone();
+            self.heap.push(HeapEntry { key, child, reverse: self.reverse });
+        }
+    }
+
+    fn rebuild(&mut self) {
+        self.heap.clear();
+        for child in 0..self.children.len() {
+            self.push(child);
+        }
+        self.current = self.heap.pop().map(|e| e.child);
+    }
+
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn seek(&mut self, key: &LookUpKey) {
+        self.reverse = false;
+        self.children.iter_mut().for_each(|c| c.seek(key));
+        self.rebuild();
+    }
+
+    fn seek_to_first(&mut self) {
+        self.reverse = false;
+        self.children.iter_mut().for_each(|c| c.seek_to_first());
+        self.rebuild();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.reverse = true;
+        self.children.iter_mut().for_each(|c| c.seek_to_last());
+        self.rebuild();
+    }
+
+    fn next(&mut self) {
+        let current = self.current.unwrap();
+        if self.reverse {
+            //the others are before the current key, move each to its first entry after it
+            let key = self.key().clone();
+            for (_, child) in self.children.iter_mut().enumerate().filter(|(i, _)| *i != current) {
+                child.seek(&key);
+                if child.valid() && *child.key() == key {
+                    child.next();
+                }
+            }
+            self.reverse = false;
+            self.children[current].next();
+            self.rebuild();
+            return;
+        }
+        self.children[current].next();
+        self.push(current);
+        self.current = self.heap.pop().map(|e| e.child);
+    }
+
+    fn prev(&mut self) {
+        let current = self.current.unwrap();
+        if !self.reverse {
+            //the others are after the current key, move each to its last entry before it
+            let key = self.key().clone();
+            for (_, child) in self.children.iter_mut().enumerate().filter(|(i, _)| *i != current) {
+                child.seek(&key);
+                match child.valid() {
+                    true => child.prev(),
+                    false => child.seek_to_last(),
+                }
+            }
+            self.reverse = true;
+            self.children[current].prev();
+            self.rebuild();
+            return;
+        }
+        self.children[current].prev();
+        self.push(current);
+        self.current = self.heap.pop().map(|e| e.child);
+    }
+
+    fn key(&self) -> &LookUpKey {
+        self.children[self.current.unwrap()].key()
+    }
+
+    fn value(&self) -> &Bytes {
+        self.children[self.current.unwrap()].value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.children.iter().try_for_each(|c| c.status())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    Forward, //the merging cursor is at the entry the iterator is at
+    Reverse, //the merging cursor is before all entries of the key the iterator is at
+}
+
+/// A cursor over the live keys of the database as of one sequence number, it can seek and step
+/// both ways. Built by `LsmDb::iter`, it keeps the tables it reads alive and copies the mem tables,
+/// so writes and compactions after that do not change what it sees. The files of the tables it keeps
+/// are deleted once it is dropped, when a compaction replaced them in the meantime.
+/// Once a step runs past either end it is not valid, a seek brings it back.
+pub struct DbIterator {
+    cursor: MergingCursor,
+    seq_num: u64,
+    pinned_files: Vec<TableFile>, //the tables the cursors hold
+    value_logs: Option<Arc<ValueLogs>>, //where the values of pointer entries are read from, None for keys only
+    verify_checksums: bool, //the values of checked puts are compared against their checksums
+    value_error: Option<String>, //a value that could not be read stopped the iterator
+    direction: Direction,
+    valid: bool,
+    key: Vec<u8>,
+    value: Bytes,
+}
+
+impl DbIterator {
+    pub(crate) fn new(children: Vec<Box<dyn Cursor>>, seq_num: u64, pinned_files: Vec<TableFile>, value_logs: Option<Arc<ValueLogs>>,
+        verify_checksums: bool) -> Self {
+        DbIterator {
+            cursor: MergingCursor::new(children),
+            seq_num,
+            pinned_files,
+            value_logs,
+            verify_checksums,
+            value_error: None,
+            direction: Direction::Forward,
+            valid: false,
+            key: Vec::new(),
+            value: Bytes::new(),
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        self.valid
+    }
+
+    //to the first key not less than `key`
+    pub fn seek(&mut self, key: &[u8]) {
+        self.direction = Direction::Forward;
+        let seq_num = std::cmp::min(self.seq_num, MAX_SEQ_NUM);
+        self.cursor.seek(&LookUpKey::new(InternalKey::new(key, seq_num, ValueType::Delete)));
+        self.find_next_user_entry(None);
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.direction = Direction::Forward;
+        self.cursor.seek_to_first();
+        self.find_next_user_entry(None);
+    }
+
+    pub fn seek_to_last(&mut self) {
+        self.direction = Direction::Reverse;
+        self.cursor.seek_to_last();
+        self.find_prev_user_entry();
+    }
+
+    pub fn next(&mut self) {
+        assert!(self.valid, "next on an iterator that is not valid");
+        if self.direction == Direction::Reverse {
+            self.direction = Direction::Forward;
+            //on to the first entry of the current key
+            match self.cursor.valid() {
+                true => self.cursor.next(),
+                false => self.cursor.seek_to_first(),
+            }
+        }
+        let skip = std::mem::take(&mut self.key);
+        self.find_next_user_entry(Some(skip));
+    }
+
+    pub fn prev(&mut self) {
+        assert!(self.valid, "prev on an iterator that is not valid");
+        if self.direction == Direction::Forward {
+            //back past every version of the current key
+            loop {
+                self.cursor.prev();
+                if !self.cursor.valid() {
+                    self.valid = false;
+                    return;
+                }
+                if self.cursor.key().get_user_key() < &self.key[..] {
+                    break;
+                }
+            }
+            self.direction = Direction::Reverse;
+        }
+        self.find_prev_user_entry();
+    }
+
+    pub fn key(&self) -> &[u8] {
+        assert!(self.valid, "key of an iterator that is not valid");
+        &self.key
+    }
+
+    pub fn value(&self) -> &[u8] {
+        assert!(self.valid, "value of an iterator that is not valid");
+        &self.value
+    }
+
+    //Err(TimedOut) once a block read would have gone past the deadline of the ReadOptions,
+    //Err(Corruption) once a value could not be read or did not match its checksum. The iterator stopped there.
+    pub fn status(&self) -> Result<()> {
+        if let Some(e) = &self.value_error {
+            return Err(Error::Corruption(e.clone()));
+        }
+        self.cursor.status()
+    }
+
+    //the sequence number it reads at
+    pub fn seq_num(&self) -> u64 {
+        self.seq_num
+    }
+
+    //the tables it reads, as they were when it was created
+    pub fn pinned_files(&self) -> &[TableFile] {
+        &self.pinned_files
+    }
+
+    //forward to the newest visible version of the next key that is not deleted, skipping `skip`
+    fn find_next_user_entry(&mut self, mut skip: Option<Vec<u8>>) {
+        while self.cursor.valid() {
+            let k = self.cursor.key();
+            if k.get_seq_num() <= self.seq_num && skip.as_deref() != Some(k.get_user_key()) {
+                if !k.is_deletion() {
+                    let k = k.clone();
+                    self.key = k.get_user_key().to_vec();
+                    self.value = self.cursor.value().clone();
+                    self.valid = true;
+                    self.resolve_value(&k);
+                    return;
+                }
+                //the older versions are hidden by the tombstone
+                skip = Some(k.get_user_key().to_vec());
+            }
+            self.cursor.next();
+        }
+        self.valid = false;
+    }
+
+    //Backwards the versions of a key come oldest first, the last visible one is the newest.
+    //Stops on the entry before the first key whose newest visible version is not deleted.
+    fn find_prev_user_entry(&mut self) {
+        let mut found = None;
+        while self.cursor.valid() {
+            let k = self.cursor.key();
+            if k.get_seq_num() <= self.seq_num {
+                if found.is_some() && k.get_user_key() < &self.key[..] {
+                    break;
+                }
+                found = (!k.is_deletion()).then(|| k.clone());
+                if found.is_some() {
+                    self.key = k.get_user_key().to_vec();
+                    self.value = self.cursor.value().clone();
+                }
+            }
+            self.cursor.prev();
+        }
+        self.valid = found.is_some();
+        if let Some(k) = found {
+            self.resolve_value(&k);
+        }
+    }
+
+    //strips the checksum off the value of `key` and reads the value of a pointer entry from its value log,
+    //once the iterator settled on the entry. A value that can not be read, or does not match its checksum
+    //when checksums are verified, stops the iterator, status() tells why.
+    fn resolve_value(&mut self, key: &LookUpKey) {
+        let value_logs = match &self.value_logs {
+            Some(value_logs) => value_logs,
+            None => return,
+        };
+        let verify = self.verify_checksums;
+        let res = key.internal_key.split_value(std::mem::take(&mut self.value))
+            .and_then(|(value, checksum)| {
+                let value = value_logs.resolve(key.is_value_pointer(), value)?;
+                if verify {
+                    verify_value_checksum(key.get_user_key(), &value, checksum)?;
+                }
+                Ok(value)
+            });
+        match res {
+            Ok(value) => self.value = value,
+            Err(e) => {
+                self.value_error = Some(match e {
+                    Error::Corruption(msg) => msg,
+                    e => e.to_string(),
+                });
+                self.valid = false;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, seq_num: u64, value: Option<&str>) -> (LookUpKey, Bytes) {
+        let value_type = if value.is_some() { ValueType::Put } else { ValueType::Delete };
+        let internal_key = InternalKey::new(key.as_bytes(), seq_num, value_type);
+        (LookUpKey::new(internal_key), Bytes::from(value.unwrap_or("").to_owned()))
+    }
+
+    fn sorted(mut entries: Vec<(LookUpKey, Bytes)>) -> Box<dyn Cursor> {
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Box::new(VecCursor::new(entries))
+    }
+
+    //"b" is deleted at 6, "c" written again at 9, after the iterator's sequence number
+    fn iterator() -> DbIterator {
+        let newer = sorted(vec![entry("b", 6, None), entry("c", 9, Some("c9")), entry("d", 7, Some("d7"))]);
+        let older = sorted(vec![entry("a", 1, Some("a1")), entry("b", 2, Some("b2")), entry("c", 3, Some("c3")), entry("e", 4, Some("e4"))]);
+        DbIterator::new(vec![newer, older], 8, Vec::new(), None, false)
+    }
+
+    fn at(iter: &DbIterator) -> Option<(String, String)> {
+        iter.valid().then(|| (String::from_utf8(iter.key().to_vec()).unwrap(), String::from_utf8(iter.value().to_vec()).unwrap()))
+    }
+
+    fn pair(key: &str, value: &str) -> Option<(String, String)> {
+        Some((key.to_owned(), value.to_owned()))
+    }
+
+    #[test]
+    fn steps_over_the_visible_keys_both_ways() {
+        let mut iter = iterator();
+        iter.seek_to_first();
+        let mut forward = Vec::new();
+        while iter.valid() {
+            forward.push(at(&iter).unwrap());
+            iter.next();
+        }
+        let expected = [("a", "a1"), ("c", "c3"), ("d", "d7"), ("e", "e4")].iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(forward, expected);
+        iter.seek_to_last();
+        let mut backward = Vec::new();
+        while iter.valid() {
+            backward.push(at(&iter).unwrap());
+            iter.prev();
+        }
+        backward.reverse();
+        assert_eq!(backward, forward);
+    }
+
+    #[test]
+    fn seek_lands_on_the_next_greater_key() {
+        let mut iter = iterator();
+        iter.seek(b"bb");
+        assert_eq!(at(&iter), pair("c", "c3"));
+        //the deleted key is skipped as well
+        iter.seek(b"b");
+        assert_eq!(at(&iter), pair("c", "c3"));
+        iter.seek(b"");
+        assert_eq!(at(&iter), pair("a", "a1"));
+        iter.seek(b"f");
+        assert_eq!(at(&iter), None);
+        iter.seek(b"e");
+        iter.next();
+        assert_eq!(at(&iter), None);
+    }
+
+    #[test]
+    fn changes_direction_around_a_tombstone() {
+        let mut iter = iterator();
+        iter.seek(b"a");
+        iter.next();
+        assert_eq!(at(&iter), pair("c", "c3"));
+        iter.prev();
+        assert_eq!(at(&iter), pair("a", "a1"));
+        iter.next();
+        assert_eq!(at(&iter), pair("c", "c3"));
+        iter.next();
+        assert_eq!(at(&iter), pair("d", "d7"));
+        iter.prev();
+        iter.prev();
+        assert_eq!(at(&iter), pair("a", "a1"));
+        iter.prev();
+        assert_eq!(at(&iter), None);
+        iter.seek_to_last();
+        iter.prev();
+        iter.next();
+        assert_eq!(at(&iter), pair("e", "e4"));
+    }
+}
@@ -0,0 +1,587 @@
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::utils;
+
+use parking_lot::{Mutex, RwLock};
+
+/// Where the database keeps its files. `StdEnv` is the local file system, `MemEnv` keeps
+/// everything in memory. Paths are the ones the database builds from its directory.
+pub trait Env: Debug + Send + Sync {
+    //for reading, fails if the file does not exist
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>>;
+    //an empty file, an existing one is truncated
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+    //appends to the end of the file, which is created if it does not exist
+    fn open_appendable(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+    fn delete(&self, path: &Path) -> io::Result<()>;
+    //replaces `to` if it exists
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    //the paths of the files and subdirectories in `dir`
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    fn is_dir(&self, path: &Path) -> bool;
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+    //makes the creation, renaming and removal of the files in `dir` durable
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+    //None while someone else holds the lock, it is released when the returned guard is dropped
+    fn try_lock(&self, path: &Path) -> io::Result<Option<Box<dyn Any + Send + Sync>>>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.is_dir(path) || self.open(path).is_ok()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let file = self.open(path)?;
+        let mut buf = vec![0; file.size()? as usize];
+        file.read_at(&mut buf, 0)?;
+        Ok(buf)
+    }
+
+    //the bytes still free for writing on the file system of `dir`, None where that is not known
+    fn available_space(&self, _dir: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+//how the bytes of a file are going to be read, so the OS can read ahead or drop them from its page cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Advice {
+    Sequential, //read in order, the OS may read further ahead
+    DontNeed,   //not read again soon, the cached pages can go
+}
+
+pub trait RandomAccessFile: Debug + Send + Sync {
+    //fills `buf` from `offset`, failing if the file ends before
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    fn size(&self) -> io::Result<u64>;
+
+    //a hint for `len` bytes from `offset`, 0 means to the end of the file. Ignored where there is no fadvise.
+    fn advise(&self, _advice: Advice, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    //several (offset, len) reads as one request, an implementation may have them in flight together
+    fn read_many(&self, reads: &[(u64, usize)]) -> io::Result<Vec<Vec<u8>>> {
+        reads.iter()
+            .map(|&(offset, len)| {
+                let mut buf = vec![0; len];
+                self.read_at(&mut buf, offset)?;
+                Ok(buf)
+            })
+            .collect()
+    }
+}
+
+pub trait WritableFile: Debug + Send + Sync {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()>;
+    fn sync(&mut self) -> io::Result<()>;
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+
+    //reserves room for `len` bytes before they are appended, so they end up close together on disk.
+    //Space past what gets appended may stay reserved until the file is truncated to its length.
+    fn allocate(&mut self, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+//the Env of Config::new(), reads go through io_uring when it is built in and the kernel supports it
+pub fn default_env() -> Arc<dyn Env> {
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    return Arc::new(crate::uring::UringEnv::new());
+    #[cfg(not(all(target_os = "linux", feature = "uring")))]
+    return Arc::new(StdEnv);
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdEnv;
+
+impl Env for StdEnv {
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>> {
+        Ok(Arc::new(StdFile(File::open(path)?)))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(path)?;
+        Ok(Box::new(StdFile(file)))
+    }
+
+    fn open_appendable(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Box::new(StdFile(file)))
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        utils::sync_dir(dir)
+    }
+
+    //the lock goes with the open file, so the OS releases it when the process dies
+    fn try_lock(&self, path: &Path) -> io::Result<Option<Box<dyn Any + Send + Sync>>> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Box::new(file))),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    #[cfg(unix)]
+    fn available_space(&self, dir: &Path) -> io::Result<Option<u64>> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        let path = CString::new(dir.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        match unsafe { libc::statvfs(path.as_ptr(), &mut stat) } {
+            //the blocks an unprivileged user may still take
+            0 => Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64)),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StdFile(File);
+
+impl RandomAccessFile for StdFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_exact_at(&self.0, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn advise(&self, advice: Advice, offset: u64, len: u64) -> io::Result<()> {
+        fadvise(&self.0, advice, offset, len)
+    }
+}
+
+//fills `buf` from `offset` of the file, failing with UnexpectedEof if the file ends before.
+//Concurrent reads of the same file do not get in each other's way.
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+//seek_read moves the cursor of the file as well, nothing reading a file of ours goes by the cursor
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn read_exact_at(_file: &File, _buf: &mut [u8], _offset: u64) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "no positioned reads on this platform"))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub(crate) fn fadvise(file: &File, advice: Advice, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let advice = match advice {
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+    //returns the error instead of setting errno
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), offset as libc::off_t, len as libc::off_t, advice) } {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub(crate) fn fadvise(_file: &File, _advice: Advice, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+impl WritableFile for StdFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)
+    }
+
+    //the size stays as it is, so appending still starts at the end of what was written
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn allocate(&mut self, len: u64) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        match unsafe { libc::fallocate(self.0.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len as libc::off_t) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+/// Files in memory, for tests that should not touch the disk. Like on a file system an open file
+/// keeps its contents after it is deleted, and a renamed one can still be read.
+#[derive(Clone, Debug, Default)]
+pub struct MemEnv {
+    state: Arc<Mutex<MemState>>,
+    reads: Arc<MemReads>,
+}
+
+//reads can be slowed down like a disk, to see how many are outstanding at once
+#[derive(Debug, Default)]
+struct MemReads {
+    delay_micros: AtomicU64,
+    count: AtomicU64,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+    advice: Mutex<Vec<(PathBuf, Advice, u64, u64)>>, //the hints given, they change nothing in memory
+    allocations: Mutex<Vec<(PathBuf, u64)>>,
+    appends: Mutex<Vec<(PathBuf, u64)>>,
+}
+
+#[derive(Debug, Default)]
+struct MemState {
+    files: HashMap<PathBuf, Arc<RwLock<Vec<u8>>>>,
+    dirs: HashSet<PathBuf>,
+    locked: HashSet<PathBuf>,
+    available_space: Option<u64>, //what available_space reports, nothing is enforced
+}
+
+impl MemEnv {
+    pub fn new() -> Self {
+        MemEnv::default()
+    }
+
+    //every read_at takes at least this long
+    pub fn set_read_delay(&self, delay: Duration) {
+        self.reads.delay_micros.store(delay.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    //the number of read_at calls since the last call
+    pub fn take_read_count(&self) -> u64 {
+        self.reads.count.swap(0, Ordering::SeqCst)
+    }
+
+    //the most reads that were in flight at the same time since the last call
+    pub fn take_max_concurrent_reads(&self) -> usize {
+        self.reads.max_in_flight.swap(0, Ordering::SeqCst)
+    }
+
+    //the path, advice, offset and length of every advise call since the last call, in order
+    pub fn take_advice(&self) -> Vec<(PathBuf, Advice, u64, u64)> {
+        std::mem::take(&mut *self.reads.advice.lock())
+    }
+
+    //the path and length of every allocate call since the last call, in order
+    pub fn take_allocations(&self) -> Vec<(PathBuf, u64)> {
+        std::mem::take(&mut *self.reads.allocations.lock())
+    }
+
+    //what available_space reports from now on, None for not known
+    pub fn set_available_space(&self, bytes: Option<u64>) {
+        self.state.lock().available_space = bytes;
+    }
+
+    //the path and length of every append since the last call, in order
+    pub fn take_appends(&self) -> Vec<(PathBuf, u64)> {
+        std::mem::take(&mut *self.reads.appends.lock())
+    }
+
+    fn mem_file(&self, path: &Path, data: Arc<RwLock<Vec<u8>>>) -> MemFile {
+        MemFile {
+            path: path.to_path_buf(),
+            data,
+            reads: self.reads.clone(),
+        }
+    }
+
+    fn not_found(path: &Path) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, format!("{:?} does not exist", path))
+    }
+
+    //the contents of a file, created empty if `create` is set
+    fn file(&self, path: &Path, create: bool) -> io::Result<Arc<RwLock<Vec<u8>>>> {
+        let mut state = self.state.lock();
+        if let Some(data) = state.files.get(path) {
+            return Ok(data.clone());
+        }
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        if !create || !state.dirs.contains(parent) {
+            return Err(Self::not_found(path));
+        }
+        let data = Arc::new(RwLock::new(Vec::new()));
+        state.files.insert(path.to_path_buf(), data.clone());
+        Ok(data)
+    }
+}
+
+impl Env for MemEnv {
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>> {
+        Ok(Arc::new(self.mem_file(path, self.file(path, false)?)))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let data = self.file(path, true)?;
+        data.write().clear();
+        Ok(Box::new(self.mem_file(path, data)))
+    }
+
+    fn open_appendable(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        Ok(Box::new(self.mem_file(path, self.file(path, true)?)))
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        match self.state.lock().files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(Self::not_found(path)),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        let data = state.files.remove(from).ok_or_else(|| Self::not_found(from))?;
+        state.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock();
+        if !state.dirs.contains(dir) {
+            return Err(Self::not_found(dir));
+        }
+        Ok(state.files.keys()
+            .chain(state.dirs.iter())
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.state.lock().dirs.contains(path)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        for ancestor in dir.ancestors() {
+            state.dirs.insert(ancestor.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_lock(&self, path: &Path) -> io::Result<Option<Box<dyn Any + Send + Sync>>> {
+        self.file(path, true)?;
+        if !self.state.lock().locked.insert(path.to_path_buf()) {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(MemLock {
+            state: self.state.clone(),
+            path: path.to_path_buf(),
+        })))
+    }
+
+    fn available_space(&self, _dir: &Path) -> io::Result<Option<u64>> {
+        Ok(self.state.lock().available_space)
+    }
+}
+
+#[derive(Debug)]
+struct MemFile {
+    path: PathBuf, //it was opened by, to record the advice
+    data: Arc<RwLock<Vec<u8>>>,
+    reads: Arc<MemReads>,
+}
+
+impl RandomAccessFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.reads.count.fetch_add(1, Ordering::SeqCst);
+        let in_flight = self.reads.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.reads.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        thread::sleep(Duration::from_micros(self.reads.delay_micros.load(Ordering::Relaxed)));
+        let res = self.read_data(buf, offset);
+        self.reads.in_flight.fetch_sub(1, Ordering::SeqCst);
+        res
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.read().len() as u64)
+    }
+
+    fn advise(&self, advice: Advice, offset: u64, len: u64) -> io::Result<()> {
+        self.reads.advice.lock().push((self.path.clone(), advice, offset, len));
+        Ok(())
+    }
+}
+
+impl MemFile {
+    fn read_data(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = self.data.read();
+        let start = std::cmp::min(offset, data.len() as u64) as usize;
+        match data[start..].get(..buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(())
+            },
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "read past the end of the file")),
+        }
+    }
+}
+
+impl WritableFile for MemFile {
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.reads.appends.lock().push((self.path.clone(), buf.len() as u64));
+        self.data.write().extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.data.write().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn allocate(&mut self, len: u64) -> io::Result<()> {
+        self.reads.allocations.lock().push((self.path.clone(), len));
+        let mut data = self.data.write();
+        let additional = (len as usize).saturating_sub(data.len());
+        data.reserve(additional);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct MemLock {
+    state: Arc<Mutex<MemState>>,
+    path: PathBuf,
+}
+
+impl Drop for MemLock {
+    fn drop(&mut self) {
+        self.state.lock().locked.remove(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::temp_dir;
+
+    #[test]
+    fn positioned_reads_fill_the_buffer_from_the_offset() {
+        let path = temp_dir("read_exact_at").join("file");
+        let data = (0..=255).cycle().take(64 * 1024).collect::<Vec<u8>>();
+        std::fs::write(&path, &data).unwrap();
+        let file = File::open(&path).unwrap();
+        let mut buf = vec![0; 1000];
+        read_exact_at(&file, &mut buf, 40_000).unwrap();
+        assert_eq!(buf, &data[40_000..41_000]);
+        //up to the last byte, but not past it
+        read_exact_at(&file, &mut buf, data.len() as u64 - 1000).unwrap();
+        assert_eq!(buf, &data[data.len() - 1000..]);
+        let err = read_exact_at(&file, &mut buf, data.len() as u64 - 999).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        //readers at other offsets of the same file see their own bytes
+        let file = Arc::new(file);
+        let readers = (0..8u64)
+            .map(|i| {
+                let file = file.clone();
+                thread::spawn(move || (0..100).all(|_| {
+                    let offset = i * 4096 + 7;
+                    let mut buf = [0; 512];
+                    read_exact_at(&file, &mut buf, offset).is_ok()
+                        && buf.iter().enumerate().all(|(j, b)| *b == ((offset + j as u64) % 256) as u8)
+                }))
+            })
+            .collect::<Vec<_>>();
+        assert!(readers.into_iter().all(|r| r.join().unwrap()));
+    }
+
+    #[test]
+    fn mem_env_behaves_like_a_file_system() {
+        let env = MemEnv::new();
+        let dir = Path::new("/db");
+        assert!(env.create(&dir.join("a")).is_err());
+        env.create_dir_all(dir).unwrap();
+        let mut file = env.create(&dir.join("a")).unwrap();
+        file.append(b"hello").unwrap();
+        let reader = env.open(&dir.join("a")).unwrap();
+        let mut buf = [0; 3];
+        reader.read_at(&mut buf, 2).unwrap();
+        assert_eq!(&buf, b"llo");
+        assert!(reader.read_at(&mut buf, 3).is_err());
+
+        //an open file survives the rename and the removal of its name
+        env.rename(&dir.join("a"), &dir.join("b")).unwrap();
+        env.create_dir_all(&dir.join("sub")).unwrap();
+        let mut listed = env.list_dir(dir).unwrap();
+        listed.sort();
+        assert_eq!(listed, vec![dir.join("b"), dir.join("sub")]);
+        env.delete(&dir.join("b")).unwrap();
+        assert_eq!(reader.size().unwrap(), 5);
+        assert!(!env.exists(&dir.join("b")));
+        assert!(env.delete(&dir.join("b")).is_err());
+
+        let mut log = env.open_appendable(&dir.join("log")).unwrap();
+        log.append(b"12345").unwrap();
+        log.truncate(2).unwrap();
+        env.open_appendable(&dir.join("log")).unwrap().append(b"3").unwrap();
+        assert_eq!(env.read(&dir.join("log")).unwrap(), b"123");
+
+        let lock = env.try_lock(&dir.join("LOCK")).unwrap();
+        assert!(lock.is_some());
+        assert!(env.try_lock(&dir.join("LOCK")).unwrap().is_none());
+        drop(lock);
+        assert!(env.try_lock(&dir.join("LOCK")).unwrap().is_some());
+    }
+}
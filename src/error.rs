@@ -0,0 +1,49 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    InvalidArgument(String),
+    Corruption(String), //malformed data read back from a file
+    Background(String), //flush or compaction failed, the message is kept since it can be reported many times
+    SequenceExhausted, //no sequence numbers left for writes, the database stays readable
+    DbLocked { pid: Option<u32> }, //the directory is open elsewhere, pid is the holder's as written in its LOCK file
+    ReadOnly, //a write, flush or compaction on a database opened read-only
+    Closed, //a write after LsmDb::close
+    TimedOut, //a read passed the deadline of its ReadOptions
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            Error::Corruption(msg) => write!(f, "corruption: {}", msg),
+            Error::Background(msg) => write!(f, "background error: {}", msg),
+            Error::SequenceExhausted => write!(f, "sequence numbers exhausted"),
+            Error::DbLocked { pid: Some(pid) } => write!(f, "database is locked by process {}", pid),
+            Error::DbLocked { pid: None } => write!(f, "database is locked by another process"),
+            Error::ReadOnly => write!(f, "database is open read-only"),
+            Error::Closed => write!(f, "database is closed"),
+            Error::TimedOut => write!(f, "read timed out"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
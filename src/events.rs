@@ -0,0 +1,234 @@
+//! What the database does in the background, as a stream of events any number of receivers pull from.
+//!
+//! Every receiver has a queue of its own. A receiver that falls behind loses the oldest events of its
+//! queue; the first event it then gets is a `Dropped` counting them.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::stats::{StatsDump, WriteStallState};
+
+pub const DEFAULT_EVENT_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableFile {
+    pub level: usize,
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DbEvent {
+    FlushStarted { at: SystemTime, mem_table_bytes: usize },
+    //None if the mem table had nothing to write
+    FlushFinished { at: SystemTime, table: Option<TableFile>, duration: Duration },
+    CompactionStarted { at: SystemTime, inputs: Vec<TableFile>, output_level: usize },
+    //a compaction that lost its inputs to another one has no Finished event, its outputs are deleted
+    CompactionFinished { at: SystemTime, inputs: Vec<TableFile>, outputs: Vec<TableFile>, duration: Duration },
+    //a flush holds the writers up while it waits for the flush thread
+    WriteStallStarted { at: SystemTime },
+    WriteStallEnded { at: SystemTime, duration: Duration },
+    //the number of level 0 tables crossed Config::level0_slowdown_writes_trigger or level0_stop_writes_trigger
+    WriteStallStateChanged { at: SystemTime, from: WriteStallState, to: WriteStallState, level0_files: usize },
+    //background work gave up, it waits for LsmDb::resume
+    BackgroundError { at: SystemTime, error: String },
+    //writes go to a new log, the old one is deleted once its mem table is flushed
+    WalRotated { at: SystemTime, log_num: u64 },
+    SstDeleted { at: SystemTime, table: TableFile },
+    //every Config::stats_dump_period
+    StatsDump { at: SystemTime, stats: StatsDump },
+    //this many older events were dropped because the receiver fell behind
+    Dropped { at: SystemTime, count: u64 },
+}
+
+impl DbEvent {
+    pub fn at(&self) -> SystemTime {
+        match self {
+            DbEvent::FlushStarted { at, .. }
+            | DbEvent::FlushFinished { at, .. }
+            | DbEvent::CompactionStarted { at, .. }
+            | DbEvent::CompactionFinished { at, .. }
+            | DbEvent::WriteStallStarted { at }
+            | DbEvent::WriteStallEnded { at, .. }
+            | DbEvent::WriteStallStateChanged { at, .. }
+            | DbEvent::BackgroundError { at, .. }
+            | DbEvent::WalRotated { at, .. }
+            | DbEvent::SstDeleted { at, .. }
+            | DbEvent::StatsDump { at, .. }
+            | DbEvent::Dropped { at, .. } => *at,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    events: VecDeque<DbEvent>, //a Dropped in front does not count against the capacity
+    receiver_gone: bool,
+    senders_gone: bool,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+    capacity: usize,
+}
+
+impl Queue {
+    fn push(&self, event: DbEvent) {
+        let mut state = self.state.lock();
+        let marked = matches!(state.events.front(), Some(DbEvent::Dropped { .. }));
+        if state.events.len() - marked as usize >= self.capacity {
+            match marked {
+                true => {
+                    state.events.remove(1);
+                    if let Some(DbEvent::Dropped { count, .. }) = state.events.front_mut() {
+                        *count += 1;
+                    }
+                },
+                false => {
+                    state.events.pop_front();
+                    state.events.push_front(DbEvent::Dropped { at: SystemTime::now(), count: 1 });
+                },
+            }
+        }
+        state.events.push_back(event);
+        self.ready.notify_one();
+    }
+}
+
+/// Where the database sends its events, shared by the levels and their tables.
+#[derive(Debug, Default)]
+pub struct EventBus {
+    queues: Mutex<Vec<Arc<Queue>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self, capacity: usize) -> Receiver {
+        let queue = Arc::new(Queue { capacity: std::cmp::max(capacity, 1), ..Queue::default() });
+        self.queues.lock().push(queue.clone());
+        Receiver { queue }
+    }
+
+    //`event` is only built if someone is listening
+    pub fn emit<F: FnOnce() -> DbEvent>(&self, event: F) {
+        let mut queues = self.queues.lock();
+        queues.retain(|q| !q.state.lock().receiver_gone);
+        if queues.is_empty() {
+            return;
+        }
+        let event = event();
+        for queue in queues.iter() {
+            queue.push(event.clone());
+        }
+    }
+}
+
+impl Drop for EventBus {
+    fn drop(&mut self) {
+        for queue in self.queues.get_mut().iter() {
+            queue.state.lock().senders_gone = true;
+            queue.ready.notify_all();
+        }
+    }
+}
+
+/// Returned by `LsmDb::events`. Once the database is dropped, the events left are still received and
+/// then `recv` returns None.
+#[derive(Debug)]
+pub struct Receiver {
+    queue: Arc<Queue>,
+}
+
+impl Receiver {
+    //waits for the next event
+    pub fn recv(&self) -> Option<DbEvent> {
+        let mut state = self.queue.state.lock();
+        loop {
+            if let Some(event) = state.events.pop_front() {
+                return Some(event);
+            }
+            if state.senders_gone {
+                return None;
+            }
+            self.queue.ready.wait(&mut state);
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<DbEvent> {
+        self.queue.state.lock().events.pop_front()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<DbEvent> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.queue.state.lock();
+        loop {
+            if let Some(event) = state.events.pop_front() {
+                return Some(event);
+            }
+            if state.senders_gone || self.queue.ready.wait_until(&mut state, deadline).timed_out() {
+                return state.events.pop_front();
+            }
+        }
+    }
+}
+
+impl Iterator for Receiver {
+    type Item = DbEvent;
+
+    fn next(&mut self) -> Option<DbEvent> {
+        self.recv()
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.queue.state.lock().receiver_gone = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotated(log_num: u64) -> DbEvent {
+        DbEvent::WalRotated { at: SystemTime::UNIX_EPOCH, log_num }
+    }
+
+    #[test]
+    fn a_slow_receiver_loses_the_oldest_events_and_is_told_how_many() {
+        let bus = EventBus::default();
+        let slow = bus.subscribe(2);
+        let fast = bus.subscribe(100);
+        for log_num in 0..5 {
+            bus.emit(|| rotated(log_num));
+        }
+        assert_eq!((0..5).map(|_| fast.try_recv().unwrap()).collect::<Vec<_>>(), (0..5).map(rotated).collect::<Vec<_>>());
+        assert!(matches!(slow.try_recv(), Some(DbEvent::Dropped { count: 3, .. })));
+        assert_eq!(slow.try_recv(), Some(rotated(3)));
+        assert_eq!(slow.try_recv(), Some(rotated(4)));
+        assert_eq!(slow.try_recv(), None);
+
+        //a receiver that is gone is not sent to, and none at all builds no event
+        drop(slow);
+        drop(fast);
+        bus.emit(|| unreachable!("nobody listens"));
+        assert!(bus.queues.lock().is_empty());
+    }
+
+    #[test]
+    fn receivers_wait_for_events_until_the_bus_is_dropped() {
+        let bus = EventBus::default();
+        let receiver = bus.subscribe(DEFAULT_EVENT_CAPACITY);
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), None);
+        let waiter = std::thread::spawn(move || receiver.collect::<Vec<_>>());
+        bus.emit(|| rotated(1));
+        bus.emit(|| rotated(2));
+        drop(bus);
+        assert_eq!(waiter.join().unwrap(), vec![rotated(1), rotated(2)]);
+    }
+}
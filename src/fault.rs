@@ -0,0 +1,379 @@
+use std::io;
+use std::path::Path;
+
+use crate::env::{Env, WritableFile};
+
+//Every write, sync, rename and removal of a database file goes through here, whatever its Env. With the `testing` feature
+//a fault can be armed for a directory: the k-th matching operation fails, and from then on every
+//operation in the directory fails too, as if the process had been killed at that point.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileOp {
+    Append,
+    Sync,
+    Rename, //matched against the new name
+    Remove,
+    SyncDir,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(any(test, feature = "testing")), allow(dead_code))]
+pub enum FaultAction {
+    Fail,
+    Truncate(usize), //an append writes this many bytes of its buffer before it fails
+}
+
+pub(crate) fn append(file: &mut dyn WritableFile, buf: &[u8], path: &Path) -> io::Result<()> {
+    match intercept(FileOp::Append, path) {
+        None => file.append(buf),
+        Some(FaultAction::Truncate(len)) => {
+            file.append(&buf[..std::cmp::min(len, buf.len())])?;
+            Err(injected())
+        },
+        Some(FaultAction::Fail) => Err(injected()),
+    }
+}
+
+pub(crate) fn sync(file: &mut dyn WritableFile, path: &Path) -> io::Result<()> {
+    match intercept(FileOp::Sync, path) {
+        None => file.sync(),
+        Some(_) => Err(injected()),
+    }
+}
+
+pub(crate) fn rename(env: &dyn Env, from: &Path, to: &Path) -> io::Result<()> {
+    match intercept(FileOp::Rename, to) {
+        None => env.rename(from, to),
+        Some(_) => Err(injected()),
+    }
+}
+
+pub(crate) fn remove_file(env: &dyn Env, path: &Path) -> io::Result<()> {
+    match intercept(FileOp::Remove, path) {
+        None => env.delete(path),
+        Some(_) => Err(injected()),
+    }
+}
+
+pub(crate) fn sync_dir(env: &dyn Env, dir: &Path) -> io::Result<()> {
+    match intercept(FileOp::SyncDir, dir) {
+        None => env.sync_dir(dir),
+        Some(_) => Err(injected()),
+    }
+}
+
+fn injected() -> io::Error {
+    io::Error::other("injected fault")
+}
+
+#[cfg(not(any(test, feature = "testing")))]
+#[inline(always)]
+fn intercept(_op: FileOp, _path: &Path) -> Option<FaultAction> {
+    None
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub use self::testing::*;
+
+#[cfg(any(test, feature = "testing"))]
+use self::testing::intercept;
+
+#[cfg(any(test, feature = "testing"))]
+mod testing {
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    use super::{FaultAction, FileOp};
+    use crate::lsm::{Config, LsmDb};
+
+    #[derive(Clone, Debug)]
+    pub struct Fault {
+        pub op: FileOp,
+        pub file_pattern: &'static str, //a part of the file name, "" matches every file
+        pub nth: usize, //counting from 1
+        pub action: FaultAction,
+    }
+
+    impl Fault {
+        pub fn new(op: FileOp, file_pattern: &'static str, nth: usize) -> Self {
+            Fault {
+                op,
+                file_pattern,
+                nth,
+                action: FaultAction::Fail,
+            }
+        }
+
+        pub fn truncate(self, len: usize) -> Self {
+            Fault {
+                action: FaultAction::Truncate(len),
+                ..self
+            }
+        }
+    }
+
+    struct Armed {
+        dir: PathBuf,
+        fault: Fault,
+        seen: usize,
+        fired: bool,
+    }
+
+    //faults are kept per directory, so tests running side by side do not see each other's
+    static ARMED: Mutex<Vec<Armed>> = Mutex::new(Vec::new());
+
+    pub fn arm(dir: &Path, fault: Fault) {
+        let mut armed = ARMED.lock().unwrap();
+        armed.retain(|a| a.dir != dir);
+        armed.push(Armed {
+            dir: dir.to_path_buf(),
+            fault,
+            seen: 0,
+            fired: false,
+        });
+    }
+
+    //the files of the directory work again, returns whether the fault fired
+    pub fn disarm(dir: &Path) -> bool {
+        let mut armed = ARMED.lock().unwrap();
+        let fired = armed.iter().any(|a| a.dir == dir && a.fired);
+        armed.retain(|a| a.dir != dir);
+        fired
+    }
+
+    pub(super) fn intercept(op: FileOp, path: &Path) -> Option<FaultAction> {
+        let mut armed = ARMED.lock().unwrap();
+        let armed = armed.iter_mut().find(|a| path.starts_with(&a.dir))?;
+        if armed.fired {
+            return Some(FaultAction::Fail);
+        }
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if op != armed.fault.op || !name.contains(armed.fault.file_pattern) {
+            return None;
+        }
+        armed.seen += 1;
+        if armed.seen < armed.fault.nth {
+            return None;
+        }
+        armed.fired = true;
+        Some(armed.fault.action)
+    }
+
+    #[derive(Clone, Debug)]
+    pub enum WorkloadOp {
+        Insert(Vec<u8>, Vec<u8>),
+        Delete(Vec<u8>),
+        Tx(Vec<(Vec<u8>, Option<Vec<u8>>)>), //None deletes
+        Flush,
+    }
+
+    impl WorkloadOp {
+        fn writes(&self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+            match self {
+                WorkloadOp::Insert(key, value) => vec![(key.clone(), Some(value.clone()))],
+                WorkloadOp::Delete(key) => vec![(key.clone(), None)],
+                WorkloadOp::Tx(writes) => writes.clone(),
+                WorkloadOp::Flush => Vec::new(),
+            }
+        }
+
+        fn apply(&self, db: &LsmDb) -> crate::error::Result<()> {
+            match self {
+                WorkloadOp::Insert(key, value) => db.insert(key, value),
+                WorkloadOp::Delete(key) => db.delete(key),
+                WorkloadOp::Tx(writes) => {
+                    let (tx_id, seq_num) = db.tx_begin();
+                    for (key, value) in writes {
+                        match value {
+                            Some(value) => db.tx_insert(tx_id, seq_num, key, value)?,
+                            None => db.tx_delete(tx_id, seq_num, key),
+                        }
+                    }
+                    db.tx_commit(tx_id)
+                },
+                WorkloadOp::Flush => db.flush(),
+            }
+        }
+    }
+
+    //inserts, deletes and transactions over a few dozen keys, with a flush every 25 operations
+    pub fn workload(seed: u64, len: usize) -> Vec<WorkloadOp> {
+        let mut state = seed | 1;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let key = |next: &mut dyn FnMut() -> u64| format!("key{:02}", next() % 40).into_bytes();
+        (0..len)
+            .map(|i| {
+                let value = format!("value{}", i).into_bytes();
+                match next() % 10 {
+                    _ if i % 25 == 24 => WorkloadOp::Flush,
+                    0..=5 => WorkloadOp::Insert(key(&mut next), value),
+                    6 | 7 => WorkloadOp::Delete(key(&mut next)),
+                    _ => WorkloadOp::Tx(vec![
+                        (key(&mut next), Some(value.clone())),
+                        (key(&mut next), Some(value)),
+                        (key(&mut next), None),
+                    ]),
+                }
+            })
+            .collect()
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct CrashOutcome {
+        pub fired: bool,
+        pub acknowledged: usize, //operations that returned Ok before the crash
+    }
+
+    //Runs `ops` with `fault` armed until one fails, drops the database while its files still fail,
+    //reopens it and checks that it opens, that every acknowledged operation is there and that
+    //the operation cut short by the crash was applied whole or not at all.
+    pub fn run_crash_test(dir: &Path, config: impl Fn() -> Config, ops: &[WorkloadOp], fault: Fault) -> Result<CrashOutcome, String> {
+        let db = LsmDb::with_config(dir.to_path_buf(), config()).map_err(|e| format!("first open failed: {}", e))?;
+        super::arm(dir, fault);
+        let mut expected = BTreeMap::new();
+        let mut in_flight = None;
+        let mut acknowledged = 0;
+        for op in ops {
+            match op.apply(&db) {
+                Ok(()) => {
+                    expected.extend(op.writes());
+                    acknowledged += 1;
+                },
+                Err(_) => {
+                    in_flight = Some(op.writes());
+                    break;
+                },
+            }
+        }
+        //nothing is cleaned up, the files of the directory still fail
+        drop(db);
+        let fired = disarm(dir);
+
+        let db = LsmDb::with_config(dir.to_path_buf(), config()).map_err(|e| format!("reopen failed: {}", e))?;
+        let matches = |state: &BTreeMap<Vec<u8>, Option<Vec<u8>>>| {
+            state.iter().find_map(|(key, value)| match db.search(key, None) {
+                Ok(found) if found == *value => None,
+                found => Some(format!("{:?} should be {:?}, found {:?}",
+                    String::from_utf8_lossy(key), value.as_ref().map(|v| v.len()), found.map(|v| v.map(|v| v.len())))),
+            })
+        };
+        let mut with_in_flight = expected.clone();
+        for (key, _) in in_flight.iter().flatten() {
+            expected.entry(key.clone()).or_insert(None);
+        }
+        with_in_flight.extend(in_flight.into_iter().flatten());
+        match (matches(&expected), matches(&with_in_flight)) {
+            (Some(without), Some(with)) => Err(format!("lost or partial write: {}; or with the cut short operation {}", without, with)),
+            _ => Ok(CrashOutcome {
+                fired,
+                acknowledged,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::StdEnv;
+    use crate::error::Error;
+    use crate::lsm::{Config, LsmDb};
+    use crate::utils::temp_dir;
+
+    fn crash_config() -> Config {
+        let mut config = Config::new();
+        config.block_size = 1024;
+        config.write_buffer_size = 2048;
+        config.l0_compaction_threshold = 2;
+        config.max_background_retries = 1;
+        config
+    }
+
+    fn check_faults(name: &str, faults: Vec<Fault>) {
+        let ops = workload(0x5eed_0000_0000_0001, 400);
+        for fault in faults {
+            let dir = temp_dir(name);
+            let outcome = run_crash_test(&dir, crash_config, &ops, fault.clone())
+                .unwrap_or_else(|e| panic!("{:?}: {}", fault, e));
+            assert!(outcome.fired, "{:?} never fired", fault);
+        }
+    }
+
+    #[test]
+    fn crashes_while_logging() {
+        check_faults("crash_log", vec![
+            Fault::new(FileOp::Append, ".LOG", 1),
+            Fault::new(FileOp::Append, ".LOG", 60),
+            Fault::new(FileOp::Append, ".LOG", 35).truncate(5),
+            Fault::new(FileOp::Append, ".LOG", 200).truncate(1),
+            Fault::new(FileOp::Remove, ".LOG", 1),
+            Fault::new(FileOp::Remove, ".LOG", 3),
+        ]);
+    }
+
+    #[test]
+    fn crashes_while_writing_tables() {
+        check_faults("crash_table", vec![
+            Fault::new(FileOp::Append, ".sst.tmp", 1),
+            Fault::new(FileOp::Append, ".sst.tmp", 7).truncate(100),
+            Fault::new(FileOp::Sync, ".sst.tmp", 1),
+            Fault::new(FileOp::Sync, ".sst.tmp", 4),
+            Fault::new(FileOp::Rename, ".sst", 2),
+            Fault::new(FileOp::Remove, ".sst", 1),
+        ]);
+    }
+
+    #[test]
+    fn crashes_while_saving_the_manifest() {
+        check_faults("crash_manifest", vec![
+            Fault::new(FileOp::Append, "MANIFEST", 2).truncate(3),
+            Fault::new(FileOp::Sync, "MANIFEST", 3),
+            Fault::new(FileOp::Rename, "MANIFEST", 3),
+            Fault::new(FileOp::SyncDir, "", 5),
+        ]);
+    }
+
+    #[test]
+    fn failed_log_writes_are_errors_and_leave_nothing_behind() {
+        let dir = temp_dir("log_errors");
+        let db = LsmDb::with_config(dir.clone(), crash_config()).unwrap();
+        db.insert(b"before", b"1").unwrap();
+        arm(&dir, Fault::new(FileOp::Append, ".LOG", 1));
+        assert!(matches!(db.insert(b"put", b"2"), Err(Error::Io(_))));
+        assert!(matches!(db.delete(b"before"), Err(Error::Io(_))));
+        let (tx_id, seq_num) = db.tx_begin();
+        db.tx_insert(tx_id, seq_num, b"tx", b"3").unwrap();
+        assert!(matches!(db.tx_commit(tx_id), Err(Error::Io(_))));
+        assert!(disarm(&dir));
+        assert_eq!(db.search(b"put", None).unwrap(), None);
+        assert_eq!(db.search(b"tx", None).unwrap(), None);
+        assert_eq!(db.search(b"before", None).unwrap(), Some(b"1".to_vec()));
+        //the log goes on after the failed records
+        db.insert(b"after", b"4").unwrap();
+        drop(db);
+        let db = LsmDb::with_config(dir, crash_config()).unwrap();
+        assert_eq!(db.search(b"before", None).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.search(b"after", None).unwrap(), Some(b"4".to_vec()));
+        assert_eq!(db.search(b"put", None).unwrap(), None);
+    }
+
+    #[test]
+    fn faults_only_hit_their_directory() {
+        let dir = temp_dir("fault_scope");
+        let other = temp_dir("fault_scope_other");
+        arm(&dir, Fault::new(FileOp::Remove, "", 2));
+        for (i, path) in [dir.join("a"), other.join("a"), dir.join("b"), dir.join("c")].iter().enumerate() {
+            std::fs::write(path, b"x").unwrap();
+            //the first removal in `dir` passes, the second fails and so does everything after it
+            assert_eq!(remove_file(&StdEnv, path).is_ok(), i < 2, "{:?}", path);
+        }
+        assert!(disarm(&dir));
+        assert!(remove_file(&StdEnv, &dir.join("c")).is_ok());
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+//Written, read back and deleted again by LsmDb::health_check, applications should not use it.
+pub const HEALTH_CHECK_KEY: &[u8] = b"\xff\xffdraft_kv/health_check";
+
+//how often an idle background thread wakes up to show it is still there
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+//which checks LsmDb::health_check runs, and how long it may take
+#[derive(Clone, Debug)]
+pub struct HealthCheckOptions {
+    pub write_probe: bool, //put, get and delete HEALTH_CHECK_KEY, skipped by a read-only database
+    pub read_probe: bool,  //read a block of a random table of each level
+    pub background_threads: bool,
+    pub background_error: bool,
+    pub min_free_bytes: Option<u64>, //on the file system of the database, None skips the check
+    pub max_heartbeat_age: Duration, //a background thread quiet for longer is taken as stuck, a long compaction too
+    pub deadline: Duration, //checks not started by then fail without running
+}
+
+impl Default for HealthCheckOptions {
+    fn default() -> Self {
+        HealthCheckOptions {
+            write_probe: true,
+            read_probe: true,
+            background_threads: true,
+            background_error: true,
+            min_free_bytes: Some(64 << 20),
+            max_heartbeat_age: Duration::from_secs(60),
+            deadline: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HealthCheckKind {
+    WriteProbe,
+    ReadProbe { level: usize },
+    BackgroundThread { name: String },
+    BackgroundError,
+    FreeSpace,
+}
+
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    pub kind: HealthCheckKind,
+    pub passed: bool,
+    pub latency: Duration,
+    pub error: Option<String>, //why it failed
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HealthReport {
+    pub checks: Vec<HealthCheck>,
+    pub elapsed: Duration,
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(|c| c.passed)
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &HealthCheck> {
+        self.checks.iter().filter(|c| !c.passed)
+    }
+}
+
+//when a background thread last went round its loop
+#[derive(Debug)]
+pub(crate) struct Heartbeat {
+    pub name: String,
+    started: Instant,
+    last_millis: AtomicU64, //since started
+    #[cfg(test)]
+    pub exit: std::sync::atomic::AtomicBool, //makes the thread return as if it died
+}
+
+impl Heartbeat {
+    pub fn new(name: String) -> Self {
+        Heartbeat {
+            name,
+            started: Instant::now(),
+            last_millis: AtomicU64::new(0),
+            #[cfg(test)]
+            exit: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    pub fn beat(&self) {
+        self.last_millis.store(self.started.elapsed().as_millis() as u64, Ordering::Release);
+    }
+
+    pub fn age(&self) -> Duration {
+        self.started.elapsed().saturating_sub(Duration::from_millis(self.last_millis.load(Ordering::Acquire)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn heartbeat_ages_until_the_next_beat() {
+        let heartbeat = Heartbeat::new("flush".to_owned());
+        thread::sleep(Duration::from_millis(20));
+        let age = heartbeat.age();
+        assert!(age >= Duration::from_millis(20));
+        heartbeat.beat();
+        assert!(heartbeat.age() < age);
+    }
+}
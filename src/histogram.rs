@@ -0,0 +1,180 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::stats::{LatencyHistograms, LatencySummary};
+
+//each power of two of nanoseconds is split into 4 buckets, so a percentile is off by at most a quarter
+const SUB_BUCKET_BITS: u32 = 2;
+const NUM_BUCKETS: usize = (64 << SUB_BUCKET_BITS) as usize;
+
+/// Latencies counted into logarithmic buckets, fixed in size and recorded without a lock.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    max_nanos: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: (0..NUM_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max_nanos: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+//the bucket is the position of the highest bit and the bits right below it
+fn bucket_of(nanos: u64) -> usize {
+    if nanos < 1 << SUB_BUCKET_BITS {
+        return nanos as usize;
+    }
+    let high_bit = 63 - nanos.leading_zeros();
+    let sub_bucket = (nanos >> (high_bit - SUB_BUCKET_BITS)) & ((1 << SUB_BUCKET_BITS) - 1);
+    (((high_bit - SUB_BUCKET_BITS + 1) << SUB_BUCKET_BITS) as u64 + sub_bucket) as usize
+}
+
+//the largest latency that falls into the bucket
+fn bucket_limit(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < 1 << SUB_BUCKET_BITS {
+        return bucket;
+    }
+    let shift = (bucket >> SUB_BUCKET_BITS) - 1;
+    let sub_bucket = bucket & ((1 << SUB_BUCKET_BITS) - 1);
+    let limit = ((((1 << SUB_BUCKET_BITS) + sub_bucket + 1) as u128) << shift) - 1;
+    std::cmp::min(limit, u64::MAX as u128) as u64
+}
+
+impl Histogram {
+    pub fn record(&self, latency: Duration) {
+        let nanos = std::cmp::min(latency.as_nanos(), u64::MAX as u128) as u64;
+        self.buckets[bucket_of(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    //for each of the ascending `bounds` the latencies known to be at most it, then the count and the sum.
+    //A bucket reaching past a bound is left to the next one, so the counts are exact for bounds on bucket limits
+    #[cfg(any(test, feature = "prometheus"))]
+    pub fn cumulative_counts(&self, bounds: &[Duration]) -> (Vec<u64>, u64, Duration) {
+        let counts = self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect::<Vec<_>>();
+        let mut cumulative = Vec::with_capacity(bounds.len());
+        let (mut bucket, mut seen) = (0, 0);
+        for bound in bounds {
+            let bound = std::cmp::min(bound.as_nanos(), u64::MAX as u128) as u64;
+            while bucket < NUM_BUCKETS && bucket_limit(bucket) <= bound {
+                seen += counts[bucket];
+                bucket += 1;
+            }
+            cumulative.push(seen);
+        }
+        (cumulative, counts.iter().sum(), Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)))
+    }
+
+    //percentiles are the upper bound of their bucket, but never past the max
+    pub fn summary(&self) -> LatencySummary {
+        let counts = self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect::<Vec<_>>();
+        let count = counts.iter().sum::<u64>();
+        let max = self.max_nanos.load(Ordering::Relaxed);
+        let percentile = |p: u64| {
+            let rank = std::cmp::max((count * p).div_ceil(100), 1);
+            let mut seen = 0;
+            for (bucket, n) in counts.iter().enumerate() {
+                seen += n;
+                if seen >= rank {
+                    return Duration::from_nanos(std::cmp::min(bucket_limit(bucket), max));
+                }
+            }
+            Duration::from_nanos(max)
+        };
+        if count == 0 {
+            return LatencySummary::default();
+        }
+        LatencySummary {
+            count,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: Duration::from_nanos(max),
+        }
+    }
+}
+
+//one histogram per kind of operation, shared with the background threads
+#[derive(Debug, Default)]
+pub struct OpHistograms {
+    pub get: Histogram,
+    pub put: Histogram,
+    pub delete: Histogram,
+    pub write_batch: Histogram,
+    pub tx_commit: Histogram,
+    pub flush: Histogram,
+    pub compaction: Histogram,
+}
+
+impl OpHistograms {
+    pub fn summary(&self) -> LatencyHistograms {
+        LatencyHistograms {
+            get: self.get.summary(),
+            put: self.put.summary(),
+            delete: self.delete.summary(),
+            write_batch: self.write_batch.summary(),
+            tx_commit: self.tx_commit.summary(),
+            flush: self.flush.summary(),
+            compaction: self.compaction.summary(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_every_latency_in_order() {
+        for nanos in (0..10_000).chain((0..64).map(|shift| 1u64 << shift)).chain([u64::MAX]) {
+            let bucket = bucket_of(nanos);
+            assert!(bucket < NUM_BUCKETS);
+            assert!(nanos <= bucket_limit(bucket), "{} is past the limit of bucket {}", nanos, bucket);
+            assert!(bucket == 0 || nanos > bucket_limit(bucket - 1));
+        }
+        assert!((1..10_000).all(|nanos| bucket_of(nanos) >= bucket_of(nanos - 1)));
+    }
+
+    #[test]
+    fn percentiles_are_within_a_bucket() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.summary(), LatencySummary::default());
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        let summary = histogram.summary();
+        assert_eq!((summary.count, summary.max), (1000, Duration::from_millis(1)));
+        for (percentile, expected) in [(summary.p50, 500), (summary.p90, 900), (summary.p99, 990)] {
+            let expected = Duration::from_micros(expected);
+            assert!(percentile >= expected && percentile <= expected * 5 / 4, "{:?} for {:?}", percentile, expected);
+        }
+    }
+
+    #[test]
+    fn cumulative_counts_never_count_a_latency_past_its_bound() {
+        let histogram = Histogram::default();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        let bounds = [Duration::from_micros(100), Duration::from_micros(500), Duration::from_secs(1)];
+        let (cumulative, count, sum) = histogram.cumulative_counts(&bounds);
+        assert_eq!((count, sum), (1000, Duration::from_micros(500 * 1001)));
+        assert_eq!(cumulative[2], 1000);
+        assert!(cumulative[0] <= 100 && cumulative[0] >= 100 * 3 / 4, "{:?}", cumulative);
+        assert!(cumulative[1] <= 500 && cumulative[1] >= 500 * 3 / 4, "{:?}", cumulative);
+        //a bound on a bucket limit is exact
+        assert_eq!(histogram.cumulative_counts(&[Duration::from_nanos(bucket_limit(bucket_of(1_000_000)))]).0, vec![1000]);
+    }
+}
@@ -0,0 +1,113 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::env::Env;
+use crate::error::{Error, Result};
+use crate::fault;
+use crate::utils::random_u64;
+
+//The IDENTITY names the database wherever its directory is moved or copied.
+//Three lines of text: the id, the unix millis it was created at and the table format of the writer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub id: String, //a random (version 4) uuid
+    pub created_millis: u64,
+    pub format_version: u32,
+}
+
+impl Identity {
+    pub fn new(created_millis: u64, format_version: u32) -> Self {
+        Identity {
+            id: random_uuid(),
+            created_millis,
+            format_version,
+        }
+    }
+
+    pub fn file_name(db_path: &Path) -> PathBuf {
+        db_path.join("IDENTITY")
+    }
+
+    //None if the database does not have one yet
+    pub fn load(env: &dyn Env, db_path: &Path) -> Result<Option<Self>> {
+        let bytes = match env.read(&Self::file_name(db_path)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Self::decode_from(&bytes).map(Some)
+    }
+
+    pub fn save(&self, env: &dyn Env, db_path: &Path) -> io::Result<()> {
+        let identity_file = Self::file_name(db_path);
+        let tmp_file = identity_file.with_extension("tmp");
+        let mut file = env.create(&tmp_file)?;
+        fault::append(&mut *file, &self.encode_to(), &tmp_file)?;
+        fault::sync(&mut *file, &tmp_file)?;
+        drop(file);
+        fault::rename(env, &tmp_file, &identity_file)?;
+        fault::sync_dir(env, db_path)
+    }
+
+    pub fn encode_to(&self) -> Vec<u8> {
+        format!("{}\n{}\n{}\n", self.id, self.created_millis, self.format_version).into_bytes()
+    }
+
+    pub fn decode_from(bytes: &[u8]) -> Result<Self> {
+        let corrupted = || Error::Corruption(format!("invalid identity file of {} bytes", bytes.len()));
+        let text = std::str::from_utf8(bytes).map_err(|_| corrupted())?;
+        let lines = text.strip_suffix('\n').ok_or_else(corrupted)?.split('\n').collect::<Vec<_>>();
+        match lines.as_slice() {
+            [id, created_millis, format_version] if is_uuid(id) => Ok(Identity {
+                id: id.to_string(),
+                created_millis: created_millis.parse().map_err(|_| corrupted())?,
+                format_version: format_version.parse().map_err(|_| corrupted())?,
+            }),
+            _ => Err(corrupted()),
+        }
+    }
+}
+
+fn random_uuid() -> String {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&random_u64().to_le_bytes());
+    bytes[8..].copy_from_slice(&random_u64().to_le_bytes());
+    bytes[6] = bytes[6] & 0x0f | 0x40; //version 4
+    bytes[8] = bytes[8] & 0x3f | 0x80; //variant 1
+    let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+fn is_uuid(id: &str) -> bool {
+    id.len() == 36 && id.char_indices().all(|(i, c)| match i {
+        8 | 13 | 18 | 23 => c == '-',
+        _ => c.is_ascii_hexdigit(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::StdEnv;
+    use crate::utils::temp_dir;
+
+    #[test]
+    fn identity_roundtrip_and_validation() {
+        let dir = temp_dir("identity");
+        assert_eq!(Identity::load(&StdEnv, &dir).unwrap(), None);
+        let identity = Identity::new(1_600_000_000_000, 2);
+        assert!(is_uuid(&identity.id));
+        assert_ne!(identity.id, Identity::new(0, 2).id);
+        identity.save(&StdEnv, &dir).unwrap();
+        assert_eq!(Identity::load(&StdEnv, &dir).unwrap(), Some(identity.clone()));
+
+        let valid = identity.encode_to();
+        assert!(matches!(Identity::decode_from(&valid[..valid.len() - 1]), Err(Error::Corruption(_))));
+        assert!(matches!(Identity::decode_from(&valid[1..]), Err(Error::Corruption(_))));
+        assert!(matches!(Identity::decode_from(b"not-a-uuid\n1\n2\n"), Err(Error::Corruption(_))));
+        let extra_line = [&valid[..], b"more\n"].concat();
+        assert!(matches!(Identity::decode_from(&extra_line), Err(Error::Corruption(_))));
+        let bad_time = format!("{}\nlater\n2\n", identity.id);
+        assert!(matches!(Identity::decode_from(bad_time.as_bytes()), Err(Error::Corruption(_))));
+    }
+}
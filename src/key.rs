@@ -1,47 +1,199 @@
 use std::cmp::Ordering;
-use std::fs::File;
-use std::os::unix::fs::FileExt;
+use std::convert::TryFrom;
 
+use crate::codec;
+use crate::error::{Error, Result};
 use crate::utils::*;
+
+use bytes::Bytes;
+
+//With user timestamps the stored user key is the key as encoded by the codec followed by the
+//inverted timestamp, so plain byte order sorts the versions of a key by timestamp descending.
+//The codec escapes the key and terminates it, which keeps a key from sorting between the versions of a longer one.
+pub fn key_with_timestamp(key: &[u8], ts: &[u8]) -> Vec<u8> {
+    let mut res = timestamp_prefix(key);
+    res.extend(ts.iter().map(|b| !b));
+    res
+}
+
+//the bytes every stored version of `key` starts with
+pub fn timestamp_prefix(key: &[u8]) -> Vec<u8> {
+    codec::encode_bytes(key)
+}
+
+//splits a stored user key into the prefix shared by all its versions and the timestamp
+pub fn split_timestamp(stored: &[u8], ts_size: usize) -> (&[u8], Vec<u8>) {
+    let (prefix, ts) = stored.split_at(stored.len() - ts_size);
+    (prefix, ts.iter().map(|b| !b).collect())
+}
+
+//the key as the user wrote it
+pub fn strip_timestamp(stored: &[u8], ts_size: usize) -> Vec<u8> {
+    codec::decode_bytes(&stored[..stored.len() - ts_size]).expect("malformed timestamped key")
+}
+
+//the sequence number shares the tail with the type byte
+pub const MAX_SEQ_NUM: u64 = (1 << 56) - 1;
+
+//the type byte in the tail of an internal key, written to disk as is
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ValueType {
+    #[default]
+    Put = 0,
+    Delete = 1,
+    TxPut = 2, //written by a committed transaction
+    TxDelete = 3,
+    //a put whose value is in a value log, the entry holds a ValuePointer
+    PutPointer = 4,
+    TxPutPointer = 6,
+    //the puts above with a checksum of the key and value after the value or pointer
+    PutChecked = 8,
+    TxPutChecked = 10,
+    PutPointerChecked = 12,
+    TxPutPointerChecked = 14,
+}
+
+//set in the types of the entries that point into a value log
+pub const VALUE_POINTER_BIT: u8 = 4;
+//set in the types of the puts that carry a value checksum
+pub const VALUE_CHECKSUM_BIT: u8 = 8;
+pub const VALUE_CHECKSUM_LEN: usize = 4;
+
+//the checksum of the value the user wrote, a pointer entry carries the one of the value in the value log
+pub fn value_checksum(user_key: &[u8], value: &[u8]) -> u32 {
+    xxhash32(value, xxhash32(user_key, 0))
+}
+
+//the value followed by its checksum, as a checked put holds it
+pub fn checked_value(user_key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(value.len() + VALUE_CHECKSUM_LEN);
+    res.extend_from_slice(value);
+    res.extend_from_slice(&value_checksum(user_key, value).to_le_bytes());
+    res
+}
+
+//the value read for `user_key` has to match the checksum written with it
+pub fn verify_value_checksum(user_key: &[u8], value: &[u8], checksum: Option<u32>) -> Result<()> {
+    match checksum {
+        Some(checksum) if value_checksum(user_key, value) != checksum => {
+            Err(Error::Corruption(format!("value checksum mismatch for key {:?}", String::from_utf8_lossy(user_key))))
+        },
+        _ => Ok(()),
+    }
+}
+
+impl ValueType {
+    pub fn is_deletion(self) -> bool {
+        self == ValueType::Delete || self == ValueType::TxDelete
+    }
+
+    pub fn is_value_pointer(self) -> bool {
+        self as u8 & VALUE_POINTER_BIT != 0
+    }
+
+    //the type of the same put once its value moved to a value log
+    pub fn to_value_pointer(self) -> Self {
+        debug_assert!(!self.is_deletion(), "a tombstone has no value to move");
+        ValueType::try_from(self as u8 | VALUE_POINTER_BIT).expect("every put has a pointer type")
+    }
+
+    //the type of the same put with its value back in the entry
+    pub fn to_inline_value(self) -> Self {
+        ValueType::try_from(self as u8 & !VALUE_POINTER_BIT).expect("clearing the pointer bit gives a put")
+    }
+
+    pub fn has_value_checksum(self) -> bool {
+        self as u8 & VALUE_CHECKSUM_BIT != 0
+    }
+}
+
+impl TryFrom<u8> for ValueType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(ValueType::Put),
+            1 => Ok(ValueType::Delete),
+            2 => Ok(ValueType::TxPut),
+            3 => Ok(ValueType::TxDelete),
+            4 => Ok(ValueType::PutPointer),
+            6 => Ok(ValueType::TxPutPointer),
+            8 => Ok(ValueType::PutChecked),
+            10 => Ok(ValueType::TxPutChecked),
+            12 => Ok(ValueType::PutPointerChecked),
+            14 => Ok(ValueType::TxPutPointerChecked),
+            _ => Err(Error::Corruption(format!("invalid value type {}", value))),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct InternalKey {
-    pub user_key: Vec<u8>,
-    tail: u64, //sequence number (7 bytes) + type (1 byte)   
+    pub user_key: Bytes, //cloning shares the bytes
+    seq_num: u64, //7 bytes in the tail on disk, the type is the 8th
+    value_type: ValueType, //checked when the key was decoded
 }
 
 impl InternalKey {
-    pub fn new(user_key: &[u8], seq_num: u64, op_type: u8) -> Self {
+    pub fn new(user_key: &[u8], seq_num: u64, op_type: ValueType) -> Self {
+        Self::from_bytes(Bytes::copy_from_slice(user_key), seq_num, op_type)
+    }
+
+    pub fn from_bytes(user_key: Bytes, seq_num: u64, op_type: ValueType) -> Self {
+        debug_assert!(seq_num <= MAX_SEQ_NUM, "sequence number {} does not fit in 56 bits", seq_num);
         InternalKey {
-            user_key: user_key.to_vec(),
-            tail: seq_num << 8 | (op_type as u64),
+            user_key,
+            seq_num,
+            value_type: op_type,
         }
     }
 
-    pub fn get_type(&self) -> u8 {
-        (self.tail & 0xff) as u8
+    pub fn get_type(&self) -> ValueType {
+        self.value_type
     }
 
-    pub fn encode_to(&self) -> Vec<u8> {
-        let mut res = self.user_key.clone();
-        res.extend_from_slice(&self.tail.to_le_bytes());
-        res
+    pub fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.user_key);
+        buf.extend_from_slice(&(self.seq_num << 8 | self.value_type as u64).to_le_bytes());
     }
 
-    pub fn decode_from(bytes: &[u8]) -> Self {
+    //the user key is a view into `bytes`, nothing is copied
+    pub fn decode_from(bytes: Bytes) -> Result<Self> {
         let len = bytes.len();
-        let user_key = bytes[0..len-8].to_vec();
-        let tail = to_u64(&bytes[len-8..]);
-        InternalKey {
+        let tail = read_u64_exact(&bytes[len-8..])?;
+        let value_type = ValueType::try_from(tail as u8)?;
+        let user_key = bytes.slice(0..len-8);
+        Ok(InternalKey {
             user_key,
-            tail,
+            seq_num: tail >> 8,
+            value_type,
+        })
+    }
+
+    //the value or pointer of the entry and the checksum a checked put carries after it
+    pub fn split_value(&self, value: Bytes) -> Result<(Bytes, Option<u32>)> {
+        let (len, checksum) = self.value_len(&value)?;
+        Ok((value.slice(..len), checksum))
+    }
+
+    //the length of the value or pointer in front of the checksum, and the checksum
+    pub fn value_len(&self, value: &[u8]) -> Result<(usize, Option<u32>)> {
+        if !self.get_type().has_value_checksum() {
+            return Ok((value.len(), None));
         }
+        if value.len() < VALUE_CHECKSUM_LEN {
+            return Err(Error::Corruption(format!("value of {:?} has no room for its checksum", String::from_utf8_lossy(&self.user_key))));
+        }
+        let end = value.len() - VALUE_CHECKSUM_LEN;
+        let checksum = u32::from_le_bytes([value[end], value[end + 1], value[end + 2], value[end + 3]]);
+        Ok((end, Some(checksum)))
     }
 
 }
 
 impl PartialEq for InternalKey {
     fn eq(&self, other: &Self) -> bool {
-        self.user_key == other.user_key && (self.tail >> 8 == other.tail >> 8)
+        self.user_key == other.user_key && self.seq_num == other.seq_num
     }
 }
 
@@ -49,21 +201,7 @@ impl Eq for InternalKey {}
 
 impl PartialOrd for InternalKey {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(match self.user_key.cmp(&other.user_key) {
-            Ordering::Greater => Ordering::Greater,
-            Ordering::Less => Ordering::Less,
-            Ordering::Equal => {
-                let sa = self.tail >> 8;
-                let sb = other.tail >> 8;
-                if sa > sb {
-                    Ordering::Less
-                } else if sa == sb {
-                    Ordering::Equal
-                } else {
-                    Ordering::Greater
-                }
-            }
-        })
+        Some(self.cmp(other))
     }
 }
 
@@ -73,8 +211,8 @@ impl Ord for InternalKey {
             Ordering::Greater => Ordering::Greater,
             Ordering::Less => Ordering::Less,
             Ordering::Equal => {
-                let sa = self.tail >> 8;
-                let sb = other.tail >> 8;
+                let sa = self.seq_num;
+                let sb = other.seq_num;
                 if sa > sb {
                     Ordering::Less
                 } else if sa == sb {
@@ -101,47 +239,36 @@ impl LookUpKey {
         }
     }
 
-    pub fn encode_to(&self) -> Vec<u8> {
-        let mut res = Vec::new();
-        res.extend_from_slice(&self.key_len.to_le_bytes());
-        res.append(&mut self.internal_key.encode_to());
-        res
+    //the length is a varint in newer formats, older ones use 8 bytes
+    pub fn encode_to(&self, buf: &mut Vec<u8>, varint: bool) {
+        if varint {
+            assert!(self.key_len <= u32::MAX as u64, "key of {} bytes is too long", self.key_len);
+            put_varint32(buf, self.key_len as u32);
+        } else {
+            buf.extend_from_slice(&self.key_len.to_le_bytes());
+        }
+        self.internal_key.encode_to(buf);
     }
 
-    pub fn decode_from_bytes(bytes: &[u8], offset: &mut u64) -> Self {
+    pub fn decode_from_bytes(bytes: &Bytes, offset: &mut u64, varint: bool) -> Result<Self> {
         let mut cur = *offset as usize;
-        let mut next = (*offset + 8) as usize;
-        let key_len = to_u64(&bytes[cur..next]);
-        *offset += 8;
-        cur = *offset as usize;
-        next = (*offset + key_len) as usize;
-        let internal_key = InternalKey::decode_from(&bytes[cur..next]);
-        *offset += key_len as u64;
-        LookUpKey {
-            key_len,
-            internal_key,
+        let key_len = if varint {
+            get_varint32(bytes, &mut cur)? as u64
+        } else {
+            get_fixed64(bytes, &mut cur)?
+        };
+        if key_len < 8 {
+            return Err(Error::Corruption(format!("key of {} bytes at offset {} has no tail", key_len, cur)));
         }
-    }
-
-    pub fn decode_from_file(file: &File, offset: &mut u64) -> Self {
-        let mut key_len = vec![0; 8];
-        file.read_exact_at(
-            key_len.as_mut_slice(),
-            *offset,
-        ).unwrap();
-        *offset += 8;
-        let key_len = to_u64(&key_len);
-        let mut internal_key = vec![0; key_len as usize];
-        file.read_exact_at(
-            internal_key.as_mut_slice(),
-            *offset,
-        ).unwrap();
-        *offset += key_len;
-        let internal_key = InternalKey::decode_from(&internal_key);
-        LookUpKey {
+        let end = checked_end(cur, key_len, bytes.len())
+            .ok_or_else(|| Error::Corruption(format!("key truncated at offset {}", cur)))?;
+        let internal_key = InternalKey::decode_from(bytes.slice(cur..end))
+            .map_err(|_| Error::Corruption(format!("invalid value type in the key at offset {}", cur)))?;
+        *offset = (cur as u64) + key_len;
+        Ok(LookUpKey {
             key_len,
             internal_key,
-        }
+        })
     }
 
     pub fn get_user_key(&self) -> &[u8] {
@@ -149,13 +276,25 @@ impl LookUpKey {
     }
 
     pub fn get_seq_num(&self) -> u64 {
-        self.internal_key.tail >> 8
+        self.internal_key.seq_num
     }
 
-    pub fn get_type(&self) -> u8 {
+    pub fn get_type(&self) -> ValueType {
         self.internal_key.get_type()
     }
 
+    pub fn is_deletion(&self) -> bool {
+        self.get_type().is_deletion()
+    }
+
+    pub fn is_value_pointer(&self) -> bool {
+        self.get_type().is_value_pointer()
+    }
+
+    pub fn has_value_checksum(&self) -> bool {
+        self.get_type().has_value_checksum()
+    }
+
 }
 
 impl PartialEq for LookUpKey {
@@ -168,7 +307,7 @@ impl Eq for LookUpKey {}
 
 impl PartialOrd for LookUpKey {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.internal_key.partial_cmp(&other.internal_key)
+        Some(self.cmp(other))
     }
 }
 
@@ -178,3 +317,57 @@ impl Ord for LookUpKey {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamped_keys_sort_by_key_then_newest_timestamp() {
+        let ts = |t: u64| t.to_be_bytes();
+        let mut keys = [
+            (b"a".to_vec(), 1), (b"a".to_vec(), 9), (b"a\x00".to_vec(), 5),
+            (b"ab".to_vec(), 0), (b"ab".to_vec(), u64::MAX), (b"".to_vec(), 3),
+        ];
+        let mut stored = keys.iter().map(|(k, t)| key_with_timestamp(k, &ts(*t))).collect::<Vec<_>>();
+        stored.sort();
+        keys.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+        for ((key, t), stored) in keys.iter().zip(stored.iter()) {
+            assert_eq!(&strip_timestamp(stored, 8), key);
+            assert_eq!(split_timestamp(stored, 8), (&timestamp_prefix(key)[..], ts(*t).to_vec()));
+        }
+    }
+
+    #[test]
+    fn checked_values_split_off_their_checksum() {
+        let key = InternalKey::new(b"k", 1, ValueType::PutChecked);
+        assert_eq!(ValueType::TxPutChecked.to_value_pointer(), ValueType::TxPutPointerChecked);
+        assert_eq!(ValueType::PutPointerChecked.to_inline_value(), ValueType::PutChecked);
+        assert!(ValueType::TxPutPointerChecked.has_value_checksum() && !ValueType::TxPutPointer.has_value_checksum());
+        let (value, checksum) = key.split_value(Bytes::from(checked_value(b"k", b"value"))).unwrap();
+        assert_eq!(&value[..], b"value");
+        assert!(verify_value_checksum(b"k", &value, checksum).is_ok());
+        assert!(matches!(verify_value_checksum(b"k", b"valuf", checksum), Err(Error::Corruption(_))));
+        assert!(matches!(verify_value_checksum(b"j", &value, checksum), Err(Error::Corruption(_))));
+        assert!(matches!(key.split_value(Bytes::from_static(b"abc")), Err(Error::Corruption(_))));
+        let plain = InternalKey::new(b"k", 1, ValueType::Put);
+        assert_eq!(plain.split_value(Bytes::from_static(b"abc")).unwrap(), (Bytes::from_static(b"abc"), None));
+    }
+
+    #[test]
+    fn random_bytes_never_panic_the_decoder() {
+        let mut rng = crate::utils::Rng(0x5eed_1234_abcd_0002);
+        for _ in 0..10000 {
+            let bytes = Bytes::from(rng.bytes(40));
+            for varint in [false, true].iter() {
+                let mut offset = 0;
+                while (offset as usize) < bytes.len() {
+                    match LookUpKey::decode_from_bytes(&bytes, &mut offset, *varint) {
+                        Ok(key) => assert!(key.key_len >= 8 && offset as usize <= bytes.len()),
+                        Err(_) => break,
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,21 +1,57 @@
-#![feature(btree_drain_filter)]
-#![feature(map_first_last)]
-
+pub mod backup;
+pub mod bench;
+mod bloom;
+mod cache;
+pub mod cli;
+pub mod clock;
+pub mod codec;
+pub mod compaction_filter;
+pub mod db_iter;
+pub mod env;
+pub mod error;
+pub mod events;
+#[cfg(any(test, feature = "testing"))]
+pub mod fault;
+#[cfg(not(any(test, feature = "testing")))]
+mod fault;
+pub mod health;
+mod histogram;
+mod identity;
 mod key;
 pub mod lsm;
+mod manifest;
 mod memtable;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+mod options;
+pub mod perf_context;
+pub mod prefix_extractor;
+#[cfg(feature = "python")]
+mod python;
+mod rate_limiter;
+mod repair;
+#[cfg(feature = "resp")]
+pub mod resp;
+#[cfg(feature = "server")]
+pub mod server;
+mod slow_log;
 mod sst;
+pub mod stats;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub mod uring;
 mod utils;
+mod value_log;
 mod wal;
+pub mod write_batch;
+mod write_controller;
 
 #[cfg(test)]
 mod tests {
     use crate::lsm::LsmDb;
-    use std::env;
+    use crate::utils::temp_dir;
 
     #[test]
     fn open_lsmdb() {
-        let cur_dir = env::current_dir().unwrap();
-        let _lsm = LsmDb::new(cur_dir);
+        let _lsm = LsmDb::new(temp_dir("open_lsmdb")).unwrap();
     }
 }
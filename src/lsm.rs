@@ -1,23 +1,122 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, RwLock, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc};
 use std::ffi::OsStr;
-use std::fs::{create_dir_all, read_dir};
+use std::any::Any;
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::bloom;
+use crate::clock::{unix_millis, Clock, SystemClock};
+use crate::compaction_filter::CompactionFilter;
+use crate::env::{self, Env};
+use crate::error::{Error, Result};
+use crate::events::{self, DbEvent, EventBus, TableFile};
+use crate::fault;
+use crate::health::{HealthCheck, HealthCheckKind, HealthCheckOptions, HealthReport, Heartbeat, HEALTH_CHECK_KEY, HEARTBEAT_INTERVAL};
+use crate::histogram::OpHistograms;
+use crate::identity::Identity;
+use crate::key::{key_with_timestamp, verify_value_checksum, InternalKey, LookUpKey, ValueType, MAX_SEQ_NUM};
+use crate::manifest::Manifest;
 use crate::memtable::MemTable;
-use crate::sst::{Levels, Table};
-use crate::wal::{Log, LogEntry};
+use crate::options::{self, Options};
+use crate::prefix_extractor::PrefixExtractor;
+use crate::rate_limiter::RateLimiter;
+use crate::db_iter::{Cursor, DbIterator, VecCursor};
+use crate::sst::{merge_newest, ranges_overlap, until_error, BlockReads, Levels, Scan, Table, CURRENT_FORMAT};
+use crate::repair;
+use crate::slow_log::{SlowOp, SlowOpLog};
+use crate::perf_context::{self, PerfContext};
+use crate::stats::{format_level_stats, format_table_stats, CompactionSummary, DbStats, DiskUsage, IngestSummary, LatencyHistograms, LevelStats, LiveFiles,
+    LiveLog, MemoryUsage, RepairReport, SplitSummary, StatsDump, ValueLogGcSummary, ValueLogStats, WriteStallState};
+use crate::utils::{file_num, has_extension, random_u64, Rng};
+use crate::value_log::{ValueLogs, ValuePointer, ValueRecord};
+use crate::wal::{self, LogEntry};
+use crate::write_batch::WriteBatch;
+use crate::write_controller::{WriteController, SLOWDOWN_DELAY};
 
+use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
-use crossbeam_utils::sync::ShardedLock;
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionStyle {
+    Leveled,
+    Universal, //sorted runs in level 0, less write amplification at the cost of reads
+}
+
+//how LsmDb::ingest_db treats a key both databases hold
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IngestMode {
+    FailOnConflict, //nothing is ingested
+    NewestWins,     //the ingested value, it is written after the one already here
+}
+
+//what is read into memory while the database opens, instead of by the first lookups
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preload {
+    None,         //tables are opened one after another
+    Indexes,      //the index and filter blocks, read on read_parallelism threads
+    IndexesAndL0, //and the data blocks of level 0 tables into the block cache, up to preload_budget_bytes
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub block_size: usize,
     pub l0_compaction_threshold: usize,
+    pub l0_intra_compaction_threshold: usize, //level 0 may be merged into itself once it has this many tables
+    pub level0_slowdown_writes_trigger: usize, //each write is delayed a little while level 0 has this many tables
+    pub level0_stop_writes_trigger: usize, //and writes wait for compactions from this many on, see set_options
     pub l1_max_bytes: u64,
-    pub max_levels: usize,
-    write_buffer_size: usize,
+    pub level_size_multiplier: u64, //each level below 1 may hold this many times the bytes of the one above
+    pub level_max_bytes: Vec<u64>, //budgets of level 1, 2, ... overriding the multiplier, levels past its end use it
+    pub max_levels: usize, //levels are added below as the bottom one outgrows its budget, up to this many
+    pub target_file_size_base: u64, //compaction outputs of level 1 are cut at this size
+    pub target_file_size_multiplier: u64, //and each deeper level cuts at this many times the size of the one above
+    pub write_buffer_size: usize,
+    pub max_subcompactions: usize,
+    pub max_background_compactions: usize, //compaction threads, flushes have a thread of their own
+    pub read_parallelism: usize, //threads par_multi_get reads the tables with
+    pub compaction_rate_limit_bytes_per_sec: u64, //0 means unlimited
+    pub compaction_style: CompactionStyle,
+    pub universal_size_ratio: u64, //percent
+    pub tombstone_compaction_ratio: f64, //leveled tables with a larger share of tombstones are compacted first, down to where they are dropped; 0 disables it
+    pub max_background_retries: usize,
+    pub read_only_on_background_error: bool, //reject writes until resume() once background work gave up
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    pub user_timestamp_size: usize, //bytes of the timestamp every key carries, 0 disables them; keys then go through the _ts methods
+    pub user_timestamp_horizon: Option<Vec<u8>>, //compactions keep only the newest version at or before it, None keeps all history
+    pub record_write_time: bool, //log when writes were made, so seq_at_time can map wall-clock times to sequence numbers
+    pub clock: Arc<dyn Clock>,
+    pub env: Arc<dyn Env>, //every file of the database is read and written through it
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>, //tables get a filter over the prefixes of their keys for scan_prefix
+    pub bloom_bits_per_key: f64, //of the key filter tables get for lookups, 0 writes tables without one
+    pub bloom_bits_per_key_per_level: Vec<f64>, //of the tables of level 0, 1, ..., levels past its end use bloom_bits_per_key
+    pub value_log_threshold: usize, //flushes move larger values to a value log and leave a pointer in the table, 0 disables it
+    pub value_log_gc_ratio: f64, //gc_value_logs collects the value logs with at least this share of garbage
+    //each put carries a checksum of its key and value from the write on, reads verify it like block checksums
+    pub value_checksums: bool,
+    pub strict_file_names: bool, //fail to open on .sst and .LOG files not named by a number, instead of ignoring them
+    pub paranoid_checks: bool, //verify blocks on every read and level invariants on every update, fail recovery on a damaged log
+    pub readahead_size: usize, //bytes iterators and compactions read at once when they read blocks in a row, 0 disables it
+    pub evict_compaction_output_from_page_cache: bool, //advise the OS to drop the tables a compaction wrote from its cache
+    pub preallocate_sst: bool, //reserve the estimated size of a table before writing it, so its blocks stay together on disk
+    pub block_cache_size: usize, //bytes of data blocks kept for lookups, 0 disables the cache
+    pub block_cache_shard_bits: u32, //the cache is split into 2^bits shards with a lock each
+    pub pin_l0_blocks: bool, //data blocks of level 0 tables are never evicted while the table is in level 0
+    pub pin_index_and_filter_blocks: bool, //charge the index and filter blocks of every table to the cache, pinned
+    pub preload_on_open: Preload,
+    pub preload_budget_bytes: u64, //data blocks preloaded at most, index and filter blocks are read to open a table anyway
+    pub slow_op_threshold: Option<Duration>, //gets, puts, commits and flushes taking longer are logged, see set_options
+    pub stats_dump_period: Option<Duration>, //log a line of stats this often and send it as DbEvent::StatsDump, see StatsDump
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Config {
@@ -25,119 +124,750 @@ impl Config {
         Config {
             block_size: 4 * 1024, // 4KB
             l0_compaction_threshold: 4,
+            l0_intra_compaction_threshold: 8,
+            level0_slowdown_writes_trigger: 20,
+            level0_stop_writes_trigger: 36,
             l1_max_bytes: 64 * 1024 * 1024, // 64MB 
+            level_size_multiplier: 10,
+            level_max_bytes: Vec::new(),
             max_levels: 7,
+            target_file_size_base: 2 * 1024 * 1024, // 2MB
+            target_file_size_multiplier: 2,
             write_buffer_size: 4 * 1024 * 1024, // 4MB,
+            max_subcompactions: 1,
+            max_background_compactions: 1,
+            read_parallelism: 4,
+            compaction_rate_limit_bytes_per_sec: 0,
+            compaction_style: CompactionStyle::Leveled,
+            universal_size_ratio: 1,
+            tombstone_compaction_ratio: 0.0,
+            max_background_retries: 3,
+            read_only_on_background_error: true,
+            compaction_filter: None,
+            user_timestamp_size: 0,
+            user_timestamp_horizon: None,
+            record_write_time: false,
+            clock: Arc::new(SystemClock),
+            env: env::default_env(),
+            prefix_extractor: None,
+            bloom_bits_per_key: bloom::BITS_PER_KEY,
+            bloom_bits_per_key_per_level: Vec::new(),
+            value_log_threshold: 0,
+            value_log_gc_ratio: 0.5,
+            value_checksums: false,
+            strict_file_names: false,
+            paranoid_checks: false,
+            readahead_size: 256 * 1024,
+            evict_compaction_output_from_page_cache: false,
+            preallocate_sst: true,
+            block_cache_size: 8 * 1024 * 1024, // 8MB
+            block_cache_shard_bits: 4,
+            pin_l0_blocks: false,
+            pin_index_and_filter_blocks: false,
+            preload_on_open: Preload::None,
+            preload_budget_bytes: 64 * 1024 * 1024, // 64MB
+            slow_op_threshold: None,
+            stats_dump_period: None,
+        }
+    }
+
+    //checked on open, each error names the field and the values it may take
+    pub fn validate(&self) -> Result<()> {
+        let check = |ok: bool, field: &str, value: &dyn std::fmt::Display, allowed: String| match ok {
+            true => Ok(()),
+            false => Err(Error::InvalidArgument(format!("{} is {}, it must be {}", field, value, allowed))),
+        };
+        check((1..=16).contains(&self.max_levels), "max_levels", &self.max_levels, "in 1..=16".to_owned())?;
+        check(self.block_size >= 1024, "block_size", &self.block_size, "at least 1024".to_owned())?;
+        check(self.write_buffer_size >= self.block_size, "write_buffer_size", &self.write_buffer_size,
+            format!("at least the block_size of {}", self.block_size))?;
+        check(self.l0_compaction_threshold >= 1, "l0_compaction_threshold", &self.l0_compaction_threshold, "at least 1".to_owned())?;
+        check(self.level0_slowdown_writes_trigger < self.level0_stop_writes_trigger, "level0_slowdown_writes_trigger",
+            &self.level0_slowdown_writes_trigger, format!("below the level0_stop_writes_trigger of {}", self.level0_stop_writes_trigger))?;
+        check(self.l1_max_bytes >= self.write_buffer_size as u64, "l1_max_bytes", &self.l1_max_bytes,
+            format!("at least the write_buffer_size of {}", self.write_buffer_size))?;
+        check(self.level_size_multiplier >= 2, "level_size_multiplier", &self.level_size_multiplier, "at least 2".to_owned())?;
+        check(self.target_file_size_base >= self.block_size as u64, "target_file_size_base", &self.target_file_size_base,
+            format!("at least the block_size of {}", self.block_size))?;
+        check(self.target_file_size_multiplier >= 1, "target_file_size_multiplier", &self.target_file_size_multiplier, "at least 1".to_owned())?;
+        check(self.max_subcompactions >= 1, "max_subcompactions", &self.max_subcompactions, "at least 1".to_owned())?;
+        check(self.max_background_compactions >= 1, "max_background_compactions", &self.max_background_compactions, "at least 1".to_owned())?;
+        check((0.0..=1.0).contains(&self.tombstone_compaction_ratio), "tombstone_compaction_ratio", &self.tombstone_compaction_ratio,
+            "in 0.0..=1.0".to_owned())?;
+        check((0.0..=1.0).contains(&self.value_log_gc_ratio), "value_log_gc_ratio", &self.value_log_gc_ratio, "in 0.0..=1.0".to_owned())?;
+        for (field, bits) in std::iter::once(("bloom_bits_per_key", &self.bloom_bits_per_key))
+            .chain(self.bloom_bits_per_key_per_level.iter().map(|b| ("bloom_bits_per_key_per_level", b))) {
+            check((0.0..=64.0).contains(bits), field, bits, "in 0.0..=64.0".to_owned())?;
+        }
+        check(self.read_parallelism >= 1, "read_parallelism", &self.read_parallelism, "at least 1".to_owned())?;
+        check(self.block_cache_shard_bits <= 16, "block_cache_shard_bits", &self.block_cache_shard_bits, "at most 16".to_owned())
+    }
+}
+
+
+//par_multi_get reads fewer keys than this on the calling thread, the threads would cost more than they save
+const PAR_MULTI_GET_MIN_KEYS: usize = 16;
+
+//memory an entry of a transaction takes besides its key and value, in the hash map and the vectors
+const TX_ENTRY_OVERHEAD: usize = 64;
+
+//writes stop well before the encoding runs out, numbers taken by transactions still in flight fit in between
+const SEQ_NUM_LIMIT: u64 = MAX_SEQ_NUM - (1 << 20);
+//entries ingest_db writes at once
+const INGEST_BATCH_SIZE: usize = 1024;
+//bytes of keys purge_prefix deletes in one batch before it lets other writers in
+const PURGE_BATCH_BYTES: usize = 64 * 1024;
+
+//data blocks sample_keys reads at most, however many keys it is asked for
+pub const MAX_SAMPLE_BLOCKS: usize = 256;
+//keys approximate_quantiles samples for each quantile, and at least
+const SAMPLE_KEYS_PER_QUANTILE: usize = 64;
+const MIN_QUANTILE_SAMPLE: usize = 1024;
+
+const LOCK_FILE: &str = "LOCK";
+//times open_read_only starts over when a file it listed was removed by the owner of the database meanwhile
+const READ_ONLY_OPEN_RETRIES: usize = 10;
+
+//the user keys at the positions in `draws` of the mem table, in order, deletions are left out
+fn sample_mem_table(mem_table: &MemTable, draws: &[u64], keys: &mut Vec<Vec<u8>>) {
+    let mut draws = draws.iter().peekable();
+    for (pos, (key, _)) in mem_table.inner.iter().enumerate() {
+        if draws.peek().is_none() {
+            break;
+        }
+        while draws.next_if_eq(&&(pos as u64)).is_some() {
+            if !key.get_type().is_deletion() {
+                keys.push(key.user_key.to_vec());
+            }
+        }
+    }
+}
+
+//an empty user key would encode to nothing but the tail of the internal key
+//one LsmDb per directory. The lock goes with the file, so the OS releases it when the process dies.
+fn lock_dir(env: &dyn Env, dir_path: &Path) -> Result<Box<dyn Any + Send + Sync>> {
+    let lock_file = dir_path.join(LOCK_FILE);
+    let lock = match env.try_lock(&lock_file)? {
+        Some(lock) => lock,
+        None => {
+            let pid = String::from_utf8_lossy(&env.read(&lock_file)?).into_owned();
+            return Err(Error::DbLocked { pid: pid.trim().parse().ok() });
+        },
+    };
+    //for whoever finds the directory locked
+    env.create(&lock_file)?.append(std::process::id().to_string().as_bytes())?;
+    Ok(lock)
+}
+
+//the files under `dir`, one deleted meanwhile is left out
+fn dir_bytes(env: &dyn Env, dir: &Path) -> Result<u64> {
+    let mut bytes = 0;
+    for path in env.list_dir(dir)? {
+        if env.is_dir(&path) {
+            bytes += dir_bytes(env, &path)?;
+            continue;
+        }
+        match env.open(&path).and_then(|file| file.size()) {
+            Ok(size) => bytes += size,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(bytes)
+}
+
+//the smallest first and the largest last of the ranges
+fn union_range(ranges: impl Iterator<Item = Option<(Vec<u8>, Vec<u8>)>>) -> Option<(Vec<u8>, Vec<u8>)> {
+    ranges.flatten().fold(None, |union, (min, max)| match union {
+        None => Some((min, max)),
+        Some((a, b)) => Some((std::cmp::min(a, min), std::cmp::max(b, max))),
+    })
+}
+
+fn mem_table_range(mem_table: &MemTable) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut keys = mem_table.prefix_iter(b"").map(|(k, _)| k.get_user_key().to_vec());
+    let min = keys.next()?;
+    Some((min.clone(), keys.last().unwrap_or(min)))
+}
+
+fn tx_entry_charge(key: &[u8], value: &[u8]) -> usize {
+    key.len() + value.len() + TX_ENTRY_OVERHEAD
+}
+
+//a decimal integer as increment keeps it, without spaces or a leading plus
+pub fn parse_integer(value: &[u8]) -> Option<i64> {
+    let digits = value.strip_prefix(b"-").unwrap_or(value);
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+fn check_key(key: &[u8]) -> Result<()> {
+    match key.is_empty() {
+        true => Err(Error::InvalidArgument("empty key".to_owned())),
+        false => Ok(()),
+    }
+}
+
+//work for the background threads, flushes have their own so a long compaction never holds them up
+#[derive(Clone, Copy, Debug)]
+enum BackgroundWork {
+    Flush,      //minor compaction of the immutable mem table
+    Compaction, //major compaction
+}
+
+//compaction slots given back wake compact_level, which waits for all of them
+type CompactionsIdle = (Mutex<()>, Condvar);
+
+//the writes of each open transaction by tx_id, keyed by user key and seq_num
+type TxCacheTable = HashMap<u64, HashMap<(Vec<u8>, u64), Vec<u8>>>;
+
+type CloseHook = Box<dyn FnOnce() + Send>;
+
+fn release_compaction(running_compactions: &AtomicUsize, idle: &CompactionsIdle) {
+    running_compactions.fetch_sub(1, Ordering::SeqCst);
+    //taking the lock orders the notify after a waiter that saw the slot still taken went to sleep
+    let _guard = idle.0.lock();
+    idle.1.notify_all();
+}
+
+//takes one of the compaction slots and queues a compaction, false if they are all taken
+fn schedule_compaction(running_compactions: &AtomicUsize, idle: &CompactionsIdle, max_compactions: usize, do_compaction: &Sender<()>) -> bool {
+    let claimed = running_compactions
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| if n < max_compactions { Some(n + 1) } else { None })
+        .is_ok();
+    //the queue holds one entry per slot, so it is never full here
+    if claimed && do_compaction.try_send(()).is_err() {
+        release_compaction(running_compactions, idle);
+        return false;
+    }
+    claimed
+}
+
+//Per call knobs of a read, ReadOptions::default() reads like the methods without them.
+#[derive(Clone, Copy)]
+pub struct ReadOptions<'a> {
+    pub snapshot: Option<&'a Snapshot>, //None reads at the newest published sequence number
+    pub verify_checksums: Option<bool>, //blocks and value checksums, None follows Config::paranoid_checks
+    pub fill_cache: bool, //false keeps a large read from evicting the hot blocks of the block cache
+    pub readahead: Option<usize>, //for scans, None follows Config::readahead_size
+    pub deadline: Option<Duration>, //from the start of the call, past it the read fails with Error::TimedOut
+}
+
+impl<'a> Default for ReadOptions<'a> {
+    fn default() -> Self {
+        ReadOptions {
+            snapshot: None,
+            verify_checksums: None,
+            fill_cache: true,
+            readahead: None,
+            deadline: None,
+        }
+    }
+}
+
+impl<'a> ReadOptions<'a> {
+    fn block_reads(&self) -> BlockReads {
+        BlockReads {
+            verify_checksums: self.verify_checksums,
+            fill_cache: self.fill_cache,
+            readahead: self.readahead,
+            deadline: self.deadline.map(|d| Instant::now() + d),
         }
     }
 }
 
+//how an iterator steps over the entries, beyond what ReadOptions covers
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IterOptions {
+    pub keys_only: bool, //values are skipped without being copied, DbIterator::value() is empty
+}
+
+//How LsmDb::count_range counts. The approximate count is taken from the recorded entries of
+//each block, at the newest state whatever the snapshot; it is never below the exact count and
+//above it by at most the older versions and tombstones still stored in the range.
+#[derive(Clone, Copy, Default)]
+pub struct CountOptions<'a> {
+    pub read: ReadOptions<'a>,
+    pub approximate: bool,
+}
+
+//what LsmDb::key_may_exist could tell without reading a data block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyMayExist {
+    No,
+    Maybe,
+    YesWithValue(Vec<u8>), //found in a mem table
+}
+
+//Pins the database as of seq_num, compactions keep every version it reads until it is dropped.
+pub struct Snapshot {
+    seq_num: u64,
+    registry: Arc<Mutex<BTreeMap<u64, usize>>>,
+}
+
+impl Snapshot {
+    pub fn seq_num(&self) -> u64 {
+        self.seq_num
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut registry = self.registry.lock();
+        let handles = registry.get_mut(&self.seq_num).unwrap();
+        *handles -= 1;
+        if *handles == 0 {
+            registry.remove(&self.seq_num);
+        }
+    }
+}
+
+//Several reads at one sequence number, a write batch is either entirely visible or not at all.
+//It holds a snapshot, so versions it reads are not compacted away meanwhile.
+pub struct ReadView<'a> {
+    db: &'a LsmDb,
+    snapshot: Snapshot,
+}
+
+impl<'a> ReadView<'a> {
+    pub fn seq_num(&self) -> u64 {
+        self.snapshot.seq_num()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.search(key, Some(self.seq_num()))
+    }
+
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.db.multi_get_at(keys, self.seq_num(), 1, &BlockReads::default())
+    }
+}
 
 pub struct LsmDb {
     config: Config,
     db_path: PathBuf,
-    next_seq_num: AtomicU64,
+    read_only: bool, //opened without the lock, nothing is written and no background threads run
+    next_seq_num: AtomicU64, //the next one to hand out, only taken with the update lock held
+    last_published_seq: AtomicU64, //the newest one readers see, everything up to it is in the mem table
     next_log_num: AtomicU64,
-    mem_table: ShardedLock<MemTable>,
-    im_mem_table: ShardedLock<Option<MemTable>>,
+    mem_table: Arc<RwLock<MemTable>>,
+    im_mem_table: Arc<RwLock<Option<MemTable>>>,
     levels: Arc<RwLock<Levels>>,
-    do_compaction: Sender<Option<MemTable>>,
-    running_compaction: Arc<AtomicBool>,
+    rate_limiter: Arc<RateLimiter>,
+    do_flush: Sender<()>,
+    do_compaction: Sender<()>,
+    background_error: Arc<Mutex<Option<String>>>,
+    running_flush: Arc<AtomicBool>, //a flush is queued or running
+    running_compactions: Arc<AtomicUsize>, //compactions queued or running, one per compaction thread at most
+    compactions_idle: Arc<CompactionsIdle>,
     shutdown: Arc<AtomicBool>,
-    shutdown_compaction_thread: Receiver<()>,
+    closed: AtomicBool, //close() was called, writes fail from then on
+    close_hooks: Mutex<Option<Vec<CloseHook>>>, //run by close() before the last flush, None once it began
+    stop_workers: Option<Sender<()>>, //dropped to wake the idle background threads on shutdown
+    stop_stats_dump: Mutex<Option<Sender<()>>>, //dropped by close() as well, the stats of a closed database do not change
+    workers: Vec<(Arc<Heartbeat>, thread::JoinHandle<()>)>,
     update_lock: Arc<Mutex<()>>,
     tx_num: AtomicU64,
-    tx_cache_table: Arc<RwLock<HashMap<u64, HashMap<(Vec<u8>, u64), Vec<u8>> >>>, //tx_id, cache_table
+    tx_cache_table: Arc<RwLock<TxCacheTable>>,
     tx_write_lock: AtomicU64,
+    snapshots: Arc<Mutex<BTreeMap<u64, usize>>>, //shared with the levels, whose merges keep what the snapshots read
+    tables_probed: AtomicU64,
+    wal_bytes_written: Arc<AtomicU64>, //since the database was opened
+    wal_buffer_bytes: Arc<AtomicUsize>,
+    tx_buffer_bytes: AtomicUsize, //keys and values of open transactions
+    histograms: Arc<OpHistograms>,
+    events: Arc<EventBus>, //shared with the levels
+    slow_ops: Arc<SlowOpLog>,
+    write_stalls: AtomicU64, //flushes that waited for the flush thread while holding the update lock
+    write_stalled: AtomicBool, //a flush is waiting for the flush thread right now
+    write_stall_micros: Arc<AtomicU64>, //spent waiting for the flush thread, summed
+    write_controller: WriteController, //holds writes back while level 0 has too many tables
+    value_logs: Arc<ValueLogs>, //shared with the levels, scans and iterators read the values of pointer entries from them
+    last_write_time: AtomicU64, //unix millis of the newest recorded write time
+    untracked_bytes: AtomicU64, //on disk but not counted by size_on_disk, found by refresh_from_fs
+    foreign_files: Vec<PathBuf>, //.sst, .LOG and .tmp files found on open that are not named like ours
+    identity: Identity,
+    options: Options, //as written to the OPTIONS file on open
+    _lock: Box<dyn Any + Send + Sync>, //holds the lock on the directory until dropped
 }
 
 impl LsmDb {
-    pub fn new(dir_path: PathBuf) -> Self {
-        //set configuration
-        let config = Config::new();
+    pub fn new(dir_path: PathBuf) -> Result<Self> {
+        Self::with_config(dir_path, Config::new())
+    }
 
-        //open db
-        create_dir_all(dir_path.clone()).unwrap();
-        let all_file_list = read_dir(dir_path.clone()).unwrap()
-            .map(|x| {
-                x.unwrap().path()
-            }).collect::<Vec<_>>();
-        //read write-ahead-log
-        let mut log_list = all_file_list.clone().into_iter().filter(|x| x.extension() == Some(OsStr::new("LOG")))
-            .collect::<Vec<_>>();
-        log_list.sort_by(|a, b| b.cmp(a)); //at most 2 logs, one for mutable mem table, and the other for immutable mem table
-        log_list.truncate(2);
+    pub fn with_config(dir_path: PathBuf, config: Config) -> Result<Self> {
+        Self::open(dir_path, config, false)
+    }
 
-        let log_nums = log_list.into_iter().map(|x| x.file_stem()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .parse::<u64>()
-            .unwrap()
-        ).collect::<Vec<_>>();
-        println!("log_nums = {:?}", log_nums);
-        let max_log_num = match log_nums.first() {
-            Some(log_num) => *log_num, 
-            None => 0,
+    //opens the database without taking its lock or changing any of its files, so a directory still locked by
+    //a process, or owned by a stopped one, can be inspected. Writes, flushes and compactions fail with ReadOnly.
+    pub fn open_read_only(dir_path: PathBuf, config: Config) -> Result<Self> {
+        //the owner may flush or compact meanwhile, a log or table that went away is in a newer table by now
+        let mut attempt = 0;
+        loop {
+            match Self::open(dir_path.clone(), config.clone(), true) {
+                Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound && attempt < READ_ONLY_OPEN_RETRIES => attempt += 1,
+                res => return res,
+            }
+        }
+    }
+
+    fn open(dir_path: PathBuf, config: Config, read_only: bool) -> Result<Self> {
+        config.validate()?;
+        //open db
+        let env = config.env.clone();
+        let lock: Box<dyn Any + Send + Sync> = if read_only {
+            if !env.is_dir(&dir_path) {
+                return Err(Error::InvalidArgument(format!("no database at {:?}", dir_path)));
+            }
+            Box::new(())
+        } else {
+            env.create_dir_all(&dir_path)?;
+            lock_dir(&*env, &dir_path)?
         };
-        let mut max_seq_num = 0;
-        let mut trans = HashMap::<u64, Vec<LogEntry>>::new();
-        let mut mem_table = MemTable::new();
-        let mut im_mem_table = None;
-        for (i, log_num) in log_nums.into_iter().enumerate() {
-            let mut mem_table_temp = MemTable::new();
-            max_seq_num = std::cmp::max(max_seq_num, mem_table_temp.recover(&dir_path, log_num, &mut trans));
-            if i == 0 {
-                mem_table = mem_table_temp;
+        let mut all_file_list = Vec::new();
+        let mut foreign_files = Vec::new();
+        for path in env.list_dir(&dir_path)? {
+            //subdirectories, like the lost/ of a repair, are left alone
+            if env.is_dir(&path) {
+                continue;
+            }
+            let named_by_number = if has_extension(&path, "sst") || has_extension(&path, "LOG") || has_extension(&path, "vlog") {
+                file_num(&path).is_some()
+            } else if has_extension(&path, "tmp") {
+                path.file_stem().and_then(OsStr::to_str).is_some_and(|stem| {
+                    stem == "MANIFEST" || stem == "IDENTITY" || stem.starts_with("OPTIONS-") || file_num(Path::new(stem)).is_some()
+                })
+            } else {
+                true
+            };
+            if named_by_number {
+                all_file_list.push(path);
+            } else if config.strict_file_names {
+                return Err(Error::InvalidArgument(format!("unrecognized file {:?} in the database directory", path)));
             } else {
-                im_mem_table = Some(mem_table_temp);
+                foreign_files.push(path);
             }
         }
-        mem_table.set_writer(&dir_path, max_log_num);
+        if !foreign_files.is_empty() {
+            log::warn!("ignore foreign files {:?}", foreign_files);
+        }
+        //leftovers of a table or manifest write interrupted by a crash, a read-only open leaves them to the next one
+        for tmp_file in all_file_list.iter().filter(|x| !read_only && has_extension(x, "tmp")) {
+            log::info!("remove unfinished file {:?}", tmp_file);
+            fault::remove_file(&*env, tmp_file)?;
+            fault::sync_dir(&*env, &dir_path)?;
+        }
+        //databases created before the identity existed get one now
+        let identity = match Identity::load(&*env, &dir_path)? {
+            Some(identity) => identity,
+            None => {
+                let identity = Identity::new(unix_millis(config.clock.now()), CURRENT_FORMAT);
+                if !read_only {
+                    identity.save(&*env, &dir_path)?;
+                }
+                identity
+            },
+        };
+
+        //what is baked into the data has to match the last open, everything else is taken from the new config
+        let options = options::from_config(&config);
+        let options_num = match options::load_latest(&*env, &dir_path)? {
+            Some((num, persisted)) => {
+                options::check_immutable(&persisted, &options)?;
+                num + 1
+            },
+            None => 1,
+        };
+        if !read_only {
+            options::save(&*env, &dir_path, options_num, &options)?;
+        }
 
         //contruct sstable meta data
-        let sst_list = all_file_list.clone().into_iter().filter(|x| x.extension() == Some(OsStr::new("sst")))
+        let sst_list = all_file_list.clone().into_iter().filter(|x| has_extension(x, "sst"))
             .collect::<Vec<_>>();
-        let levels = Arc::new(RwLock::new(Levels::new(dir_path.clone(), sst_list, &config)));
+        let mut levels = Levels::open(dir_path.clone(), sst_list, &config, read_only)?;
+        if config.preload_on_open != Preload::None {
+            log::info!("preloaded {} bytes of tables", levels.preloaded_bytes());
+        }
+
+        //read write-ahead-logs, oldest first, a transaction may begin in one log and commit in the next
+        let mut log_nums = Vec::new();
+        let mut max_log_num = 0;
+        for log_file in all_file_list.iter().filter(|x| has_extension(x, "LOG")) {
+            let log_num = file_num(log_file).unwrap();
+            max_log_num = std::cmp::max(max_log_num, log_num);
+            //left by a crash right after the log was created, or touched by hand
+            if env.open(log_file)?.size()? == 0 && !read_only {
+                log::info!("remove empty log {:?}", log_file);
+                fault::remove_file(&*env, log_file)?;
+                fault::sync_dir(&*env, &dir_path)?;
+                continue;
+            }
+            log_nums.push(log_num);
+        }
+        log_nums.sort_unstable();
+        let mut max_seq_num = 0;
+        let mut trans = HashMap::<u64, Vec<LogEntry>>::new();
+        let mut mem_tables = Vec::new();
+        //without writes there is nothing to flush, the logs all go into one mem table that has no log of its own
+        if read_only {
+            let mut mem_table = MemTable::new();
+            for log_num in log_nums.drain(..) {
+                let log_file = wal::log_path(&dir_path, log_num);
+                let entries = wal::read_log(&*env, &log_file, config.paranoid_checks)?;
+                max_seq_num = std::cmp::max(max_seq_num, mem_table.apply(entries, &mut trans, &log_file)?);
+            }
+            mem_tables.push(mem_table);
+        }
+        for log_num in log_nums {
+            let mut mem_table = MemTable::new();
+            max_seq_num = std::cmp::max(max_seq_num, mem_table.recover(&env, &dir_path, log_num, &mut trans, config.paranoid_checks)?);
+            mem_tables.push(mem_table);
+        }
+        //one log for the mutable mem table and one for the immutable one, older logs are flushed right away
+        while mem_tables.len() > 2 {
+            let mut mem_table = mem_tables.remove(0);
+            if let Some(table) = levels.write_level0_files(&mem_table)? {
+                levels.update(Vec::new(), vec![table])?;
+            }
+            mem_table.remove_writer()?;
+        }
+        //the log of the immutable mem table goes away once it is flushed
+        let mut mem_table = mem_tables.pop().unwrap_or_else(MemTable::new);
+        let im_mem_table = mem_tables.pop();
+        if !read_only {
+            mem_table.set_writer(&env, &dir_path, max_log_num)?;
+        }
+        mem_table.set_value_checksums(config.value_checksums);
+        let wal_bytes_written = Arc::new(AtomicU64::new(0));
+        mem_table.count_log_bytes(wal_bytes_written.clone());
+        let wal_buffer_bytes = Arc::new(AtomicUsize::new(0));
+        mem_table.count_log_buffer(wal_buffer_bytes.clone());
 
-        let (do_compaction_sender, do_compaction_receiver) = crossbeam_channel::bounded(1);
-        let (shutdown_compaction_sender, shutdown_compaction_receiver) = crossbeam_channel::bounded(1);
+        //the logs of flushed mem tables are gone, their numbers are only left in the tables
+        max_seq_num = std::cmp::max(max_seq_num, levels.last_seq_num());
+        let last_write_time = levels.write_times().into_iter()
+            .chain(mem_table.write_times.iter().cloned())
+            .chain(im_mem_table.iter().flat_map(|t| t.write_times.iter().cloned()))
+            .map(|(_, millis)| millis)
+            .max()
+            .unwrap_or(0);
+        let rate_limiter = levels.rate_limiter();
+        let events = levels.events();
+        let snapshots = levels.snapshots();
+        let value_logs = levels.value_logs();
+        let write_controller = WriteController::new(levels.level0_files(), config.level0_slowdown_writes_trigger,
+            config.level0_stop_writes_trigger, events.clone());
+        let levels = Arc::new(RwLock::new(levels));
 
-        let lsm_db = LsmDb {
+        let (do_flush_sender, do_flush_receiver) = crossbeam_channel::bounded(1);
+        let (do_compaction_sender, do_compaction_receiver) = crossbeam_channel::bounded(config.max_background_compactions);
+        let (stop_workers_sender, stop_workers_receiver) = crossbeam_channel::bounded(0);
+        let max_compactions = config.max_background_compactions;
+        let slow_ops = Arc::new(SlowOpLog::new(config.slow_op_threshold));
+
+        let mut lsm_db = LsmDb {
             config,
             db_path: dir_path,
+            read_only,
             next_seq_num: AtomicU64::new(max_seq_num+1),
+            last_published_seq: AtomicU64::new(max_seq_num),
             next_log_num: AtomicU64::new(max_log_num+1),
-            mem_table: ShardedLock::new(mem_table),
-            im_mem_table: ShardedLock::new(im_mem_table),
+            mem_table: Arc::new(RwLock::new(mem_table)),
+            im_mem_table: Arc::new(RwLock::new(im_mem_table)),
             levels,
-            do_compaction: do_compaction_sender.clone(),
-            running_compaction: Arc::new(AtomicBool::new(false)),
+            rate_limiter,
+            do_flush: do_flush_sender,
+            do_compaction: do_compaction_sender,
+            background_error: Arc::new(Mutex::new(None)),
+            running_flush: Arc::new(AtomicBool::new(false)),
+            running_compactions: Arc::new(AtomicUsize::new(0)),
+            compactions_idle: Arc::new((Mutex::new(()), Condvar::new())),
             shutdown: Arc::new(AtomicBool::new(false)),
-            shutdown_compaction_thread: shutdown_compaction_receiver,
+            closed: AtomicBool::new(false),
+            close_hooks: Mutex::new(Some(Vec::new())),
+            stop_workers: Some(stop_workers_sender),
+            stop_stats_dump: Mutex::new(None),
+            workers: Vec::new(),
             update_lock: Arc::new(Mutex::new(())),
             tx_num: AtomicU64::new(1),
             tx_cache_table: Arc::new(RwLock::new(HashMap::new())),
             tx_write_lock: AtomicU64::new(0),  //0 is an invalid tx_id
+            snapshots,
+            tables_probed: AtomicU64::new(0),
+            wal_bytes_written,
+            wal_buffer_bytes,
+            tx_buffer_bytes: AtomicUsize::new(0),
+            histograms: Arc::new(OpHistograms::default()),
+            events,
+            slow_ops,
+            write_stalls: AtomicU64::new(0),
+            write_stalled: AtomicBool::new(false),
+            write_stall_micros: Arc::new(AtomicU64::new(0)),
+            write_controller,
+            value_logs,
+            last_write_time: AtomicU64::new(last_write_time),
+            untracked_bytes: AtomicU64::new(0),
+            foreign_files,
+            identity,
+            options,
+            _lock: lock,
         };
 
-        lsm_db.process_compaction(shutdown_compaction_sender, (do_compaction_sender, do_compaction_receiver));
+        if read_only {
+            return Ok(lsm_db);
+        }
+        let mut workers = vec![lsm_db.start_worker(BackgroundWork::Flush, 0, do_flush_receiver, stop_workers_receiver.clone())];
+        for i in 0..max_compactions {
+            workers.push(lsm_db.start_worker(BackgroundWork::Compaction, i, do_compaction_receiver.clone(), stop_workers_receiver.clone()));
+        }
+        if let Some(period) = lsm_db.config.stats_dump_period {
+            let (stop_sender, stop_receiver) = crossbeam_channel::bounded(0);
+            *lsm_db.stop_stats_dump.lock() = Some(stop_sender);
+            workers.push(lsm_db.start_stats_dump(period, stop_receiver));
+        }
+        lsm_db.workers = workers;
+        //a recovered immutable mem table is flushed right away, not on the next write
+        lsm_db.may_schedule_flush();
+
+        Ok(lsm_db)
+    }
+
+    //salvages a database that no longer opens, it must not be open while this runs.
+    //Unreadable files end up in the lost/ subdirectory, the report tells what was recovered.
+    pub fn repair(dir_path: PathBuf) -> Result<RepairReport> {
+        let env = Config::new().env;
+        let _lock = lock_dir(&*env, &dir_path)?;
+        repair::repair(&env, &dir_path)
+    }
+
+    //stays the same wherever the directory is moved or copied to
+    pub fn db_id(&self) -> &str {
+        &self.identity.id
+    }
+
+    pub fn persisted_options(&self) -> &Options {
+        &self.options
+    }
 
-        lsm_db
+    //the files in the directory that were left alone on open, as they are not ours
+    pub fn foreign_files(&self) -> &[PathBuf] {
+        &self.foreign_files
     }
 
     pub fn may_compact_mem_table(&self) {
-        if self.im_mem_table.read().unwrap().is_some() {
-            if let Ok(_) = self.running_compaction.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst) {
-                self.do_compaction.send(self.im_mem_table.write().unwrap().take()).unwrap();
+        self.may_schedule_flush();
+        if self.mem_table.read().size >= self.config.write_buffer_size 
+        && self.im_mem_table.read().is_none() {
+            //the write that got here is logged already; without a new log the mem table stays, the next write tries again
+            let _ = self.switch_mem_table();
+        }
+    }
+
+    //the mutable mem table becomes the immutable one, the caller makes sure there is none yet
+    fn switch_mem_table(&self) -> Result<()> {
+        let mut mem_table = MemTable::new();
+        mem_table.set_value_checksums(self.config.value_checksums);
+        let log_num = self.next_log_num.fetch_add(1, Ordering::SeqCst);
+        mem_table.set_writer(&self.config.env, &self.db_path, log_num)?;
+        mem_table.count_log_bytes(self.wal_bytes_written.clone());
+        mem_table.count_log_buffer(self.wal_buffer_bytes.clone());
+        let im_mem_table = std::mem::replace(&mut *self.mem_table.write(), mem_table);  
+        *self.im_mem_table.write() = Some(im_mem_table);
+        self.events.emit(|| DbEvent::WalRotated { at: SystemTime::now(), log_num });
+        Ok(())
+    }
+
+    //writes the mem table out to a level 0 table and waits for it, an empty mem table is left alone
+    pub fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let _lock = self.update_lock.lock();
+        //an immutable mem table still being flushed goes first
+        let mut waited = self.wait_for_flush()?;
+        if !self.mem_table.read().inner.is_empty() {
+            self.switch_mem_table()?;
+            waited |= self.wait_for_flush()?;
+        }
+        if waited {
+            self.write_stalls.fetch_add(1, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    //true if there was a flush to wait for
+    fn wait_for_flush(&self) -> Result<bool> {
+        if self.im_mem_table.read().is_none() {
+            return Ok(false);
+        }
+        let started = Instant::now();
+        self.events.emit(|| DbEvent::WriteStallStarted { at: SystemTime::now() });
+        self.write_stalled.store(true, Ordering::Release);
+        let mut res = Ok(true);
+        while self.im_mem_table.read().is_some() {
+            if let Some(e) = self.background_error() {
+                res = Err(e);
+                break;
             }
+            //a flush skipped over a background error is scheduled again once resume() cleared it
+            self.may_schedule_flush();
+            thread::sleep(Duration::from_millis(1));
         }
-        if self.mem_table.read().unwrap().size >= self.config.write_buffer_size 
-        && self.im_mem_table.read().unwrap().is_none() {
-            let mut mem_table = MemTable::new();
-            mem_table.set_writer(&self.db_path, self.next_log_num.fetch_add(1, Ordering::SeqCst));
-            let im_mem_table = std::mem::replace(&mut *self.mem_table.write().unwrap(), mem_table);  
-            *self.im_mem_table.write().unwrap() = Some(im_mem_table);
+        self.write_stalled.store(false, Ordering::Release);
+        self.write_stall_micros.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        self.events.emit(|| DbEvent::WriteStallEnded { at: SystemTime::now(), duration: started.elapsed() });
+        res
+    }
+
+    //Holds the write back while level 0 has too many tables, see WriteController. A stopped write gives up
+    //on a background error or close(), as no compaction would come to let it through.
+    fn throttle_write(&self) -> Result<()> {
+        loop {
+            match self.write_controller.check() {
+                WriteStallState::None => return Ok(()),
+                WriteStallState::Slowdown => {
+                    thread::sleep(SLOWDOWN_DELAY);
+                    return Ok(());
+                },
+                WriteStallState::Stop => {
+                    if let Some(e) = self.background_error() {
+                        return Err(e);
+                    }
+                    if self.closed.load(Ordering::Acquire) {
+                        return Err(Error::Closed);
+                    }
+                    self.may_schedule_compaction();
+                    thread::sleep(Duration::from_millis(1));
+                },
+            }
+        }
+    }
+
+    //takes the update lock, true if a flush held it while waiting for the flush thread meanwhile
+    fn lock_for_write(&self) -> (MutexGuard<'_, ()>, bool) {
+        let write_stalls = self.write_stalls.load(Ordering::Acquire);
+        let lock = self.update_lock.lock();
+        (lock, self.write_stalls.load(Ordering::Acquire) != write_stalls)
+    }
+
+    fn may_schedule_flush(&self) {
+        //background work stays paused after it gave up, until resume()
+        if self.im_mem_table.read().is_some() && self.background_error.lock().is_none()
+            && self.running_flush.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            //the immutable mem table stays in place and readable until its table is installed
+            self.do_flush.send(()).unwrap();
+        }
+    }
+
+    //compactions are normally scheduled after flushes, reads can also call for one
+    fn may_schedule_compaction(&self) {
+        if !self.read_only && self.background_error.lock().is_none() {
+            schedule_compaction(&self.running_compactions, &self.compactions_idle, self.config.max_background_compactions, &self.do_compaction);
         }
     }
 
@@ -158,157 +888,4410 @@ impl LsmDb {
             Ordering::Relaxed);
     }
 
+    //the returned sequence number is the snapshot the transaction reads at
     pub fn tx_begin(&self) -> (u64, u64) {
         let tx_id = self.tx_num.fetch_add(1, Ordering::SeqCst);
-        let seq_num = self.next_seq_num.fetch_add(1, Ordering::SeqCst);
-        self.tx_cache_table.write().unwrap().insert(tx_id, HashMap::new());
+        let seq_num = self.last_published_seq();
+        self.tx_cache_table.write().insert(tx_id, HashMap::new());
         (tx_id, seq_num)
     }
 
-    pub fn tx_insert(&self, tx_id: u64, seq_num: u64, key: &[u8], value: &[u8]) {
+    //a read-only transaction buffers nothing, it only reads at its snapshot
+    pub fn tx_begin_read_only(&self) -> Snapshot {
+        self.snapshot()
+    }
+
+    pub fn tx_insert(&self, tx_id: u64, seq_num: u64, key: &[u8], value: &[u8]) -> Result<()> {
+        check_key(key)?;
         self.get_tx_write_lock(tx_id);
-        self.tx_cache_table.write()
-            .unwrap()
-            .get_mut(&tx_id)
-            .unwrap()
-            .insert((key.to_vec(), seq_num), value.to_vec());
+        self.tx_buffer(tx_id, seq_num, key, value.to_vec());
+        Ok(())
     }
 
     pub fn tx_delete(&self, tx_id: u64, seq_num: u64, key: &[u8]) {
         self.get_tx_write_lock(tx_id);
-        self.tx_cache_table.write()
-            .unwrap()
+        self.tx_buffer(tx_id, seq_num, key, Vec::new());
+    }
+
+    //an empty value deletes the key on commit
+    fn tx_buffer(&self, tx_id: u64, seq_num: u64, key: &[u8], value: Vec<u8>) {
+        let charge = tx_entry_charge(key, &value);
+        let replaced = self.tx_cache_table.write()
             .get_mut(&tx_id)
             .unwrap()
-            .insert((key.to_vec(), seq_num), Vec::new());
+            .insert((key.to_vec(), seq_num), value);
+        self.tx_buffer_bytes.fetch_add(charge, Ordering::Relaxed);
+        if let Some(replaced) = replaced {
+            self.tx_buffer_bytes.fetch_sub(tx_entry_charge(key, &replaced), Ordering::Relaxed);
+        }
+    }
+
+    //the writes of a transaction that committed or aborted are no longer buffered
+    fn release_tx_buffer(&self, txs: &HashMap<(Vec<u8>, u64), Vec<u8>>) {
+        let charge = txs.iter().map(|((key, _), value)| tx_entry_charge(key, value)).sum::<usize>();
+        self.tx_buffer_bytes.fetch_sub(charge, Ordering::Relaxed);
     }
 
-    pub fn tx_update<F>(&self, tx_id: u64, seq_num: u64, key: &[u8], f: F)
+    pub fn tx_update<F>(&self, tx_id: u64, seq_num: u64, key: &[u8], f: F) -> Result<()>
     where
         F: Fn(Vec<u8>) -> Vec<u8>, 
     {
         self.get_tx_write_lock(tx_id);
-        let old_value = self.tx_search(tx_id, seq_num, key);
+        let old_value = self.tx_search(tx_id, seq_num, key)?;
         if let Some(v) = old_value {
-            self.tx_insert(tx_id, seq_num, key, &f(v));
+            self.tx_insert(tx_id, seq_num, key, &f(v))?;
         }
+        Ok(())
+    }
+
+    //getset within the transaction, a delete buffered before reads as no value
+    pub fn tx_getset(&self, tx_id: u64, seq_num: u64, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_tx_write_lock(tx_id);
+        let old_value = self.tx_search(tx_id, seq_num, key)?.filter(|v| !v.is_empty());
+        self.tx_insert(tx_id, seq_num, key, value)?;
+        Ok(old_value)
+    }
+
+    pub fn tx_get_and_delete(&self, tx_id: u64, seq_num: u64, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_tx_write_lock(tx_id);
+        let old_value = self.tx_search(tx_id, seq_num, key)?.filter(|v| !v.is_empty());
+        self.tx_delete(tx_id, seq_num, key);
+        Ok(old_value)
     }
 
-    pub fn tx_search(&self, tx_id: u64, seq_num: u64, key: &[u8]) -> Option<Vec<u8>> {
+    pub fn tx_search(&self, tx_id: u64, seq_num: u64, key: &[u8]) -> Result<Option<Vec<u8>>> {
         match self.tx_cache_table.read()
-            .unwrap()
             .get(&tx_id)
             .unwrap()
             .get(&(key.to_vec(), seq_num)) 
         {
-            Some(v) => Some(v.clone()),
+            Some(v) => Ok(Some(v.clone())),
             None => {
                 self.search(key, Some(seq_num))
             },
         }
     }
 
-    pub fn tx_commit(&self, tx_id: u64) {
+    pub fn tx_commit(&self, tx_id: u64) -> Result<()> {
+        let now = Instant::now();
+        self.check_writable()?;
+        self.check_no_timestamps()?;
+        //a stalled commit fails before it takes the writes, so the transaction can still be committed or aborted
+        self.throttle_write()?;
+        //the writes take their number on commit and are published together, so readers see all of them or none
+        let (_lock, write_stall) = self.lock_for_write();
+        let seq_num = self.allocate_seq_num()?;
         let txs = self.tx_cache_table.write()
-            .unwrap()
             .remove(&tx_id)
             .unwrap();
-        let seq_num = txs.keys().collect::<Vec<_>>()[0].1; 
-        self.mem_table.write().unwrap().begin_tx(seq_num);
-        for ((key, seq_num), value) in txs {
-            if value.is_empty() {
-                self.mem_table.write().unwrap().delete(&key, seq_num, true);
-            } else {
-                self.mem_table.write().unwrap().insert(&key, &value, seq_num, true);
-            }
+        if let Err(e) = self.mem_table.write().write_tx(seq_num, txs.iter().map(|((key, _), value)| (&key[..], &value[..]))) {
+            //none of the writes were applied, they stay buffered for a retry or an abort
+            self.tx_cache_table.write().insert(tx_id, txs);
+            return Err(e);
         }
-        self.mem_table.write().unwrap().commit_tx(seq_num);
+        self.publish(seq_num);
+        self.release_tx_buffer(&txs);
         self.free_tx_write_lock(tx_id);
+        let elapsed = now.elapsed();
+        self.histograms.tx_commit.record(elapsed);
+        self.slow_ops.check(SlowOp::Commit, elapsed, None, &PerfContext::default(), write_stall);
+        Ok(())
     }
 
     pub fn tx_abort(&self, tx_id: u64) {
-        self.tx_cache_table.write().unwrap().remove(&tx_id);
+        if let Some(txs) = self.tx_cache_table.write().remove(&tx_id) {
+            self.release_tx_buffer(&txs);
+        }
         self.free_tx_write_lock(tx_id);
     }
 
-    pub fn insert(&self, key: &[u8], value: &[u8]) {
-        let _lock = self.update_lock.lock().unwrap();
-        self.mem_table.write().unwrap().insert(key, value, self.next_seq_num.fetch_add(1, Ordering::SeqCst), false);
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let now = Instant::now();
+        self.check_writable()?;
+        self.check_no_timestamps()?;
+        check_key(key)?;
+        self.throttle_write()?;
+        let (_lock, write_stall) = self.lock_for_write();
+        let seq_num = self.allocate_seq_num()?;
+        self.mem_table.write().insert(key, value, seq_num, false)?;
+        self.publish(seq_num);
+        self.may_compact_mem_table();
+        let elapsed = now.elapsed();
+        self.histograms.put.record(elapsed);
+        self.slow_ops.check(SlowOp::Put, elapsed, Some(key), &PerfContext::default(), write_stall);
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        let now = Instant::now();
+        self.check_writable()?;
+        self.check_no_timestamps()?;
+        self.throttle_write()?;
+        let _lock = self.update_lock.lock();
+        let seq_num = self.allocate_seq_num()?;
+        self.mem_table.write().delete(key, seq_num, false)?;
+        self.publish(seq_num);
         self.may_compact_mem_table();
+        self.histograms.delete.record(now.elapsed());
+        Ok(())
     }
 
-    pub fn delete(&self, key: &[u8]) {
-        let _lock = self.update_lock.lock().unwrap();
-        self.mem_table.write().unwrap().delete(key,  self.next_seq_num.fetch_add(1, Ordering::SeqCst), false);
+    //applies the whole batch at once, see WriteBatch
+    pub fn write(&self, batch: &WriteBatch) -> Result<()> {
+        let now = Instant::now();
+        self.check_writable()?;
+        self.check_no_timestamps()?;
+        for (key, value) in batch.iter() {
+            if value.is_some() {
+                check_key(key)?;
+            }
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.throttle_write()?;
+        let _lock = self.update_lock.lock();
+        let seq_nums = self.reserve_seq_nums(batch.len() as u64)?;
+        self.mem_table.write().write_batch(batch, seq_nums.start)?;
+        self.publish(seq_nums.end - 1);
         self.may_compact_mem_table();
+        self.histograms.write_batch.record(now.elapsed());
+        Ok(())
+    }
+
+    //the tombstones of all `keys` as one batch, with consecutive sequence numbers
+    pub fn delete_batch(&self, keys: &[&[u8]]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        keys.iter().for_each(|key| { batch.delete(key); });
+        self.write(&batch)
+    }
+
+    //Deletes every key starting with `prefix` and returns how many there were. The keys come from
+    //an iterator opened at the start, their deletes are written a batch at a time so other writers
+    //get in between. A key with the prefix written meanwhile may or may not be deleted.
+    pub fn purge_prefix(&self, prefix: &[u8]) -> Result<u64> {
+        let mut iter = self.iter_with(&ReadOptions::default(), &IterOptions { keys_only: true })?;
+        let mut deleted = 0;
+        let mut batch = WriteBatch::new();
+        let mut batch_bytes = 0;
+        iter.seek(prefix);
+        loop {
+            let done = !(iter.valid() && iter.key().starts_with(prefix));
+            if done || batch_bytes >= PURGE_BATCH_BYTES {
+                iter.status()?;
+                self.write(&batch)?;
+                deleted += batch.len() as u64;
+                batch.clear();
+                batch_bytes = 0;
+                if done {
+                    return Ok(deleted);
+                }
+                thread::yield_now();
+            }
+            batch.delete(iter.key());
+            batch_bytes += iter.key().len();
+            iter.next();
+        }
     }
 
-    pub fn update<F>(&self, key: &[u8], f: F)
+    pub fn update<F>(&self, key: &[u8], f: F) -> Result<()>
     where
         F: Fn(Vec<u8>) -> Vec<u8>, 
     {
-        let _lock = self.update_lock.lock().unwrap();
-        let old_value = self.search(key, None);
+        self.check_writable()?;
+        self.check_no_timestamps()?;
+        self.throttle_write()?;
+        let _lock = self.update_lock.lock();
+        let old_value = self.search(key, None)?;
         if let Some(v) = old_value {
-            self.mem_table.write().unwrap().insert(key, &f(v), self.next_seq_num.fetch_add(1, Ordering::SeqCst), false);
+            let seq_num = self.allocate_seq_num()?;
+            self.mem_table.write().insert(key, &f(v), seq_num, false)?;
+            self.publish(seq_num);
             self.may_compact_mem_table();
         }
+        Ok(())
     }
 
-    pub fn search(&self, key: &[u8], version: Option<u64>) -> Option<Vec<u8>> {
-        let seq_num = match version {
-            Some(seq_num) => seq_num,
-            None => self.next_seq_num.load(Ordering::SeqCst) - 1,
+    //adds `delta` to a value held as a decimal integer and returns the sum, a missing key counts as 0
+    pub fn increment(&self, key: &[u8], delta: i64) -> Result<i64> {
+        self.check_writable()?;
+        self.check_no_timestamps()?;
+        check_key(key)?;
+        self.throttle_write()?;
+        let _lock = self.update_lock.lock();
+        let current = match self.search(key, None)? {
+            Some(value) => parse_integer(&value)
+                .ok_or_else(|| Error::InvalidArgument(format!("value of {:?} is not an integer", key.escape_ascii().to_string())))?,
+            None => 0,
         };
-        //search in mutable table
-        let mem_res = self.mem_table.read().unwrap().search(key, seq_num);
-        if mem_res.is_some() {
-            return mem_res.unwrap();
+        let sum = current.checked_add(delta).ok_or_else(|| Error::InvalidArgument("increment overflows".to_owned()))?;
+        let seq_num = self.allocate_seq_num()?;
+        self.mem_table.write().insert(key, sum.to_string().as_bytes(), seq_num, false)?;
+        self.publish(seq_num);
+        self.may_compact_mem_table();
+        Ok(sum)
+    }
+
+    //writes `value` and returns the value it replaced, no other write of the key comes in between
+    pub fn getset(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_writable()?;
+        self.check_no_timestamps()?;
+        check_key(key)?;
+        self.throttle_write()?;
+        let _lock = self.update_lock.lock();
+        let old_value = self.search(key, None)?;
+        let seq_num = self.allocate_seq_num()?;
+        self.mem_table.write().insert(key, value, seq_num, false)?;
+        self.publish(seq_num);
+        self.may_compact_mem_table();
+        Ok(old_value)
+    }
+
+    //deletes the key and returns the value it had, nothing is written for a key without one
+    pub fn get_and_delete(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_writable()?;
+        self.check_no_timestamps()?;
+        self.throttle_write()?;
+        let _lock = self.update_lock.lock();
+        let old_value = self.search(key, None)?;
+        if old_value.is_some() {
+            let seq_num = self.allocate_seq_num()?;
+            self.mem_table.write().delete(key, seq_num, false)?;
+            self.publish(seq_num);
+            self.may_compact_mem_table();
         }
-        //search in immutable mem table
-        let im_mem_res = self.im_mem_table.read().unwrap().as_ref().map(|t| t.search(key, seq_num)).flatten();
-        if im_mem_res.is_some() {
-            return im_mem_res.unwrap();
+        Ok(old_value)
+    }
+
+    //the newest version by `ts` wins regardless of the order of writes
+    pub fn insert_ts(&self, key: &[u8], ts: &[u8], value: &[u8]) -> Result<()> {
+        let now = Instant::now();
+        self.check_writable()?;
+        self.check_write_timestamp(ts)?;
+        check_key(key)?;
+        self.throttle_write()?;
+        let (_lock, write_stall) = self.lock_for_write();
+        let seq_num = self.allocate_seq_num()?;
+        self.mem_table.write().insert(&key_with_timestamp(key, ts), value, seq_num, false)?;
+        self.publish(seq_num);
+        self.may_compact_mem_table();
+        let elapsed = now.elapsed();
+        self.histograms.put.record(elapsed);
+        self.slow_ops.check(SlowOp::Put, elapsed, Some(key), &PerfContext::default(), write_stall);
+        Ok(())
+    }
+
+    //hides the versions of the key up to `ts`
+    pub fn delete_ts(&self, key: &[u8], ts: &[u8]) -> Result<()> {
+        let now = Instant::now();
+        self.check_writable()?;
+        self.check_write_timestamp(ts)?;
+        self.throttle_write()?;
+        let _lock = self.update_lock.lock();
+        let seq_num = self.allocate_seq_num()?;
+        self.mem_table.write().delete(&key_with_timestamp(key, ts), seq_num, false)?;
+        self.publish(seq_num);
+        self.may_compact_mem_table();
+        self.histograms.delete.record(now.elapsed());
+        Ok(())
+    }
+
+    //the value of the key as of `ts`, the version with the newest timestamp not after it.
+    //Versions before the history horizon may have been collapsed by compactions.
+    pub fn search_at_ts(&self, key: &[u8], ts: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_timestamp(ts)?;
+        let stored_key = key_with_timestamp(key, ts);
+        let prefix = &stored_key[..stored_key.len() - ts.len()];
+        //the versions of the key sort by timestamp and then sequence number, both newest first,
+        //so the first one at or after the seek key in any source is the answer
+        let seek_key = LookUpKey::new(InternalKey::new(&stored_key, MAX_SEQ_NUM, ValueType::Delete));
+        let mut found = Vec::new();
+        found.extend(self.mem_table.read().seek(&seek_key.internal_key));
+        found.extend(self.im_mem_table.read().as_ref().and_then(|t| t.seek(&seek_key.internal_key)));
+        let mut found = found.into_iter()
+            .map(|(k, v)| (LookUpKey::new(k), v))
+            .collect::<Vec<_>>();
+        let mut last_key = prefix.to_vec();
+        last_key.resize(stored_key.len(), 0xff);
+        let candidates = self.levels.read().range_candidates(&stored_key, &last_key);
+        self.tables_probed.fetch_add(candidates.len() as u64, Ordering::Relaxed);
+        for table in candidates.iter() {
+            found.extend(table.seek(&seek_key)?);
+        }
+        let found = found.into_iter()
+            .min_by(|a, b| a.0.cmp(&b.0))
+            .filter(|(k, _)| k.get_user_key().len() == stored_key.len() && k.get_user_key().starts_with(prefix));
+        match found {
+            Some((k, v)) if !k.is_deletion() => Ok(Some(k.internal_key.split_value(v)?.0.to_vec())),
+            _ => Ok(None),
         }
-        //search in sst, both None and deleted item will return None 
-        self.levels.read().unwrap().search(key, seq_num)
     }
 
-    fn process_compaction(&self, shutdown_compaction_sender: Sender<()>, do_compaction: (Sender<Option<MemTable>>, Receiver<Option<MemTable>>)) {
-        let levels = self.levels.clone();
-        let running_compaction = self.running_compaction.clone();
-        let shutdown = self.shutdown.clone();
-        thread::Builder::new()
-            .name("compaction".to_owned())
-            .spawn(move || {
-                let (do_compaction_sender, do_compaction_receiver) = do_compaction;
-                let mut done_compaction = false;
-                let mut input_start = Vec::new();
-                //For im_mem_table, Some: minor compaction; None: major compaction
-                while let Ok(im_mem_table) = do_compaction_receiver.recv() {
-                    if shutdown.load(Ordering::Acquire) {
-                        break;
-                    } else {
-                        input_start = levels.read()
-                            .unwrap()
-                            .get_input_start(input_start);
-                        //read lock to prevent blocking other services
-                        let (deleted_tables, new_tables) = levels.read().unwrap().background_compaction(im_mem_table, &input_start);
-                        done_compaction = !(deleted_tables.is_empty() && new_tables.is_empty());
-                        levels.write().unwrap().update(deleted_tables, new_tables); 
-                    }
-                    running_compaction.store(false, Ordering::Release);
+    fn allocate_seq_num(&self) -> Result<u64> {
+        self.reserve_seq_nums(1).map(|seq_nums| seq_nums.start)
+    }
 
-                    if done_compaction && !running_compaction.load(Ordering::Acquire) && !shutdown.load(Ordering::Acquire) {
-                        // Previous compaction may have produced too many files in a level,
-                        // so reschedule another compaction if needed
-                        let _ = do_compaction_sender.try_send(None);
-                        done_compaction = false;
-                    }
-                }
-                shutdown_compaction_sender.send(()).unwrap();
-            })
-            .unwrap();
+    //`n` consecutive numbers in one step. The counter keeps going past the limit, but nothing is written with those numbers.
+    //Called with the update lock held, so write times are recorded in sequence number order.
+    fn reserve_seq_nums(&self, n: u64) -> Result<Range<u64>> {
+        //readers go by the published number, the lock orders the writers
+        let seq_num = self.next_seq_num.fetch_add(n, Ordering::Relaxed);
+        if seq_num + n - 1 > SEQ_NUM_LIMIT {
+            return Err(Error::SequenceExhausted);
+        }
+        if self.config.record_write_time {
+            //only the first write of each millisecond is recorded, a clock going back is ignored
+            let millis = unix_millis(self.config.clock.now());
+            if millis > self.last_write_time.load(Ordering::SeqCst) {
+                self.last_write_time.store(millis, Ordering::SeqCst);
+                self.mem_table.write().record_write_time(seq_num, millis)?;
+            }
+        }
+        Ok(seq_num..seq_num + n)
+    }
+
+    //makes the writes up to `seq_num` visible once they are logged and in the mem table, with the update lock held
+    fn publish(&self, seq_num: u64) {
+        self.last_published_seq.store(seq_num, Ordering::Release);
+    }
+
+    //Reads with Some(snapshot.seq_num()) see the database as it is now, also after compactions.
+    //The sequence number is taken with the registry locked, a compaction that read the registry
+    //before can only have inputs published before it, which the snapshot sees as the newest versions.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut registry = self.snapshots.lock();
+        let seq_num = self.last_published_seq();
+        *registry.entry(seq_num).or_insert(0) += 1;
+        Snapshot { seq_num, registry: self.snapshots.clone() }
+    }
+
+    //pins the newest published sequence number for the reads of the view
+    pub fn read_view(&self) -> ReadView<'_> {
+        ReadView { db: self, snapshot: self.snapshot() }
+    }
+
+    //the oldest sequence number a live snapshot pins, compactions keep the versions it reads
+    pub fn oldest_snapshot_seq(&self) -> Option<u64> {
+        self.snapshots.lock().keys().next().cloned()
+    }
+
+    //the default snapshot of reads, no write after it is visible and none before it is missing
+    pub fn last_published_seq(&self) -> u64 {
+        self.last_published_seq.load(Ordering::Acquire)
+    }
+
+    //the newest sequence number written at or before `time`, for search(key, Some(seq)).
+    //None if nothing was written by then or write times are not recorded.
+    //Writes made before record_write_time was turned on all count as made before the first recorded one.
+    pub fn seq_at_time(&self, time: SystemTime) -> Option<u64> {
+        let millis = unix_millis(time);
+        let mut write_times = self.levels.read().write_times();
+        write_times.extend(self.mem_table.read().write_times.iter().cloned());
+        write_times.extend(self.im_mem_table.read().iter().flat_map(|t| t.write_times.clone()));
+        if write_times.is_empty() {
+            return None;
+        }
+        write_times.sort();
+        //every write up to the first one recorded after `time`
+        let seq_num = match write_times.iter().find(|(_, t)| *t > millis) {
+            Some((seq_num, _)) => seq_num - 1,
+            None => self.last_published_seq(),
+        };
+        match seq_num {
+            0 => None,
+            seq_num => Some(std::cmp::min(seq_num, SEQ_NUM_LIMIT)),
+        }
     }
 
-}
\ No newline at end of file
+    fn check_timestamp(&self, ts: &[u8]) -> Result<()> {
+        match self.config.user_timestamp_size {
+            0 => Err(Error::InvalidArgument("user timestamps are not enabled".to_owned())),
+            size if size != ts.len() => Err(Error::InvalidArgument(format!("timestamp of {} bytes, expected {}", ts.len(), size))),
+            _ => Ok(()),
+        }
+    }
+
+    //older versions may already be collapsed, a write before the horizon could end up hidden by them
+    fn check_write_timestamp(&self, ts: &[u8]) -> Result<()> {
+        self.check_timestamp(ts)?;
+        match &self.config.user_timestamp_horizon {
+            Some(horizon) if ts < &horizon[..] => Err(Error::InvalidArgument("timestamp before the history horizon".to_owned())),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_no_timestamps(&self) -> Result<()> {
+        match self.config.user_timestamp_size {
+            0 => Ok(()),
+            _ => Err(Error::InvalidArgument("keys need a timestamp, use the _ts methods".to_owned())),
+        }
+    }
+
+    //the error background work gave up on after exhausting its retries
+    pub fn background_error(&self) -> Option<Error> {
+        self.background_error.lock().clone().map(Error::Background)
+    }
+
+    //flushes, compactions, write stalls, log rotations, deleted tables and background errors from now on.
+    //A receiver that falls behind by more than DEFAULT_EVENT_CAPACITY events loses the oldest ones.
+    pub fn events(&self) -> events::Receiver {
+        self.events_with_capacity(events::DEFAULT_EVENT_CAPACITY)
+    }
+
+    pub fn events_with_capacity(&self, capacity: usize) -> events::Receiver {
+        self.events.subscribe(capacity)
+    }
+
+    //clear the background error and retry the pending flush
+    pub fn resume(&self) {
+        self.background_error.lock().take();
+        let _lock = self.update_lock.lock();
+        self.may_compact_mem_table();
+    }
+
+    //checks for a readiness probe, each with how long it took, see HealthCheckOptions. A check that has not
+    //started by the deadline fails without running, and the write probe gives up waiting for writers by then.
+    pub fn health_check(&self, opts: &HealthCheckOptions) -> HealthReport {
+        let started = Instant::now();
+        let deadline = started + opts.deadline;
+        let mut checks = Vec::new();
+        let mut check = |kind: HealthCheckKind, f: &mut dyn FnMut() -> std::result::Result<(), String>| {
+            let now = Instant::now();
+            let res = match now < deadline {
+                true => f(),
+                false => Err("deadline exceeded before the check started".to_owned()),
+            };
+            checks.push(HealthCheck { kind, passed: res.is_ok(), latency: now.elapsed(), error: res.err() });
+        };
+        //a database with timestamps only takes keys that carry one
+        if opts.write_probe && !self.read_only && self.check_no_timestamps().is_ok() {
+            check(HealthCheckKind::WriteProbe, &mut || self.probe_write(deadline));
+        }
+        if opts.read_probe {
+            let version = self.levels.read().clone();
+            for level in (0..version.num_levels()).filter(|&l| version.num_files_at_level(l) > 0) {
+                check(HealthCheckKind::ReadProbe { level }, &mut || match version.probe_level(level) {
+                    Some((table, Err(e))) => Err(format!("{:?}: {}", table, e)),
+                    _ => Ok(()),
+                });
+            }
+        }
+        if opts.background_threads {
+            for (heartbeat, handle) in self.workers.iter() {
+                check(HealthCheckKind::BackgroundThread { name: heartbeat.name.clone() }, &mut || match heartbeat.age() {
+                    _ if handle.is_finished() => Err("the thread has exited".to_owned()),
+                    age if age > opts.max_heartbeat_age => Err(format!("no heartbeat for {:?}", age)),
+                    _ => Ok(()),
+                });
+            }
+        }
+        if opts.background_error {
+            check(HealthCheckKind::BackgroundError, &mut || self.background_error().map_or(Ok(()), |e| Err(e.to_string())));
+        }
+        if let Some(min_free_bytes) = opts.min_free_bytes {
+            check(HealthCheckKind::FreeSpace, &mut || match self.config.env.available_space(&self.db_path) {
+                Ok(Some(available)) if available < min_free_bytes => Err(format!("{} bytes free, less than {}", available, min_free_bytes)),
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.to_string()),
+            });
+        }
+        HealthReport { checks, elapsed: started.elapsed() }
+    }
+
+    //puts a random value under HEALTH_CHECK_KEY through the log and the mem table, reads it back and deletes it
+    fn probe_write(&self, deadline: Instant) -> std::result::Result<(), String> {
+        self.check_writable().map_err(|e| e.to_string())?;
+        let timed_out = || "timed out waiting for other writers".to_owned();
+        let value = random_u64().to_le_bytes();
+        {
+            let _lock = self.update_lock.try_lock_until(deadline).ok_or_else(timed_out)?;
+            let seq_num = self.allocate_seq_num().map_err(|e| e.to_string())?;
+            self.mem_table.write().insert(HEALTH_CHECK_KEY, &value, seq_num, false).map_err(|e| e.to_string())?;
+            self.publish(seq_num);
+        }
+        if self.search(HEALTH_CHECK_KEY, None).map_err(|e| e.to_string())?.as_deref() != Some(&value[..]) {
+            return Err("the value written was not read back".to_owned());
+        }
+        let _lock = self.update_lock.try_lock_until(deadline).ok_or_else(timed_out)?;
+        let seq_num = self.allocate_seq_num().map_err(|e| e.to_string())?;
+        self.mem_table.write().delete(HEALTH_CHECK_KEY, seq_num, false).map_err(|e| e.to_string())?;
+        self.publish(seq_num);
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.closed.load(Ordering::Acquire) {
+            return Err(Error::Closed);
+        }
+        match self.background_error() {
+            Some(e) if self.config.read_only_on_background_error => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn search(&self, key: &[u8], version: Option<u64>) -> Result<Option<Vec<u8>>> {
+        self.search_with(key, version, &BlockReads::default())
+    }
+
+    //search at the snapshot of `opts`, or the newest published sequence number without one
+    pub fn search_opt(&self, key: &[u8], opts: &ReadOptions) -> Result<Option<Vec<u8>>> {
+        self.search_with(key, opts.snapshot.map(|s| s.seq_num()), &opts.block_reads())
+    }
+
+    fn search_with(&self, key: &[u8], version: Option<u64>, reads: &BlockReads) -> Result<Option<Vec<u8>>> {
+        let now = Instant::now();
+        //what the lookup read is only collected for the slow operation log
+        let (res, perf) = match self.slow_ops.enabled() {
+            true => perf_context::measure(|| self.search_at(key, version, reads)),
+            false => (self.search_at(key, version, reads), PerfContext::default()),
+        };
+        let elapsed = now.elapsed();
+        self.histograms.get.record(elapsed);
+        self.slow_ops.check(SlowOp::Get, elapsed, Some(key), &perf, false);
+        res
+    }
+
+    fn search_at(&self, key: &[u8], version: Option<u64>, reads: &BlockReads) -> Result<Option<Vec<u8>>> {
+        let seq_num = match version {
+            Some(seq_num) => seq_num,
+            None => self.last_published_seq(),
+        };
+        //the time spent is only taken for the perf context
+        let timer = perf_context::enabled().then(Instant::now);
+        let verify = reads.verify_checksums.unwrap_or(self.config.paranoid_checks);
+        //search in mutable table
+        //values are shared with the tables internally, the caller gets its own copy
+        let mem_res = self.mem_table.read().search_with(key, seq_num, verify)?;
+        perf_context::record(|c| c.mem_table_probes += 1);
+        if let Some(res) = mem_res {
+            perf_context::record(|c| c.mem_table_time += timer.unwrap().elapsed());
+            return Ok(res.map(|v| v.to_vec()));
+        }
+        //search in immutable mem table
+        let im_mem_res = match self.im_mem_table.read().as_ref() {
+            Some(t) => {
+                perf_context::record(|c| c.mem_table_probes += 1);
+                t.search_with(key, seq_num, verify)?
+            },
+            None => None,
+        };
+        perf_context::record(|c| c.mem_table_time += timer.unwrap().elapsed());
+        if let Some(res) = im_mem_res {
+            return Ok(res.map(|v| v.to_vec()));
+        }
+        //search in sst, both None and deleted item will return None
+        //the lock is only held to pick the tables, a compaction may delete them while they are read
+        let timer = perf_context::enabled().then(Instant::now);
+        let candidates = self.levels.read().candidates(key);
+        self.tables_probed.fetch_add(candidates.len() as u64, Ordering::Relaxed);
+        let (res, seeks_exhausted) = Levels::search_candidates_with(&candidates, key, seq_num, reads)?;
+        perf_context::record(|c| c.table_time += timer.unwrap().elapsed());
+        if seeks_exhausted {
+            self.may_schedule_compaction();
+        }
+        Ok(res.map(|v| v.to_vec()))
+    }
+
+    //A cheap check before an expensive fallback. Only the mem tables and the key ranges and prefix filters
+    //of the tables are looked at, No means none of them can hold the key or a mem table has it deleted.
+    pub fn key_may_exist(&self, key: &[u8]) -> KeyMayExist {
+        let seq_num = self.last_published_seq();
+        let mem_res = self.mem_table.read().search(key, seq_num)
+            .or_else(|| self.im_mem_table.read().as_ref().and_then(|t| t.search(key, seq_num)));
+        match mem_res {
+            Some(Some(value)) => KeyMayExist::YesWithValue(value.to_vec()),
+            Some(None) => KeyMayExist::No,
+            None if self.levels.read().key_may_exist(key) => KeyMayExist::Maybe,
+            None => KeyMayExist::No,
+        }
+    }
+
+    //the values of `keys` in their order, all read at the same sequence number
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.multi_get_with(keys, 1)
+    }
+
+    //multi_get with the table reads of different keys spread over up to read_parallelism threads
+    pub fn par_multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.multi_get_with(keys, self.config.read_parallelism)
+    }
+
+    //multi_get with the sequence number pinned, a compaction running meanwhile can not drop the versions it reads
+    pub fn multi_get_consistent(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.read_view().multi_get(keys)
+    }
+
+    //multi_get at the snapshot of `opts`, the deadline covers all of the keys
+    pub fn multi_get_opt(&self, keys: &[&[u8]], opts: &ReadOptions) -> Result<Vec<Option<Vec<u8>>>> {
+        let seq_num = opts.snapshot.map_or_else(|| self.last_published_seq(), |s| s.seq_num());
+        self.multi_get_at(keys, seq_num, 1, &opts.block_reads())
+    }
+
+    fn multi_get_with(&self, keys: &[&[u8]], parallelism: usize) -> Result<Vec<Option<Vec<u8>>>> {
+        self.multi_get_at(keys, self.last_published_seq(), parallelism, &BlockReads::default())
+    }
+
+    fn multi_get_at(&self, keys: &[&[u8]], seq_num: u64, parallelism: usize, reads: &BlockReads) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut results = vec![None; keys.len()];
+        //the keys not found in the mem tables, by their index in `keys`
+        let mut pending = Vec::new();
+        {
+            let verify = reads.verify_checksums.unwrap_or(self.config.paranoid_checks);
+            let mem_table = self.mem_table.read();
+            let im_mem_table = self.im_mem_table.read();
+            for (i, key) in keys.iter().enumerate() {
+                let res = match mem_table.search_with(key, seq_num, verify)? {
+                    None => im_mem_table.as_ref().map(|t| t.search_with(key, seq_num, verify)).transpose()?.flatten(),
+                    res => res,
+                };
+                match res {
+                    Some(value) => results[i] = value.map(|v| v.to_vec()),
+                    None => pending.push(i),
+                }
+            }
+        }
+        //the tables are picked once for all keys, a compaction may delete them while they are read
+        let candidates = {
+            let levels = self.levels.read();
+            pending.iter().map(|&i| levels.candidates(keys[i])).collect::<Vec<_>>()
+        };
+        self.tables_probed.fetch_add(candidates.iter().map(|c| c.len() as u64).sum(), Ordering::Relaxed);
+        let pending_keys = pending.iter().map(|&i| keys[i]).collect::<Vec<_>>();
+        //the keys needing the same table have their blocks read in one batch
+        let lookup = |start: usize, end: usize| {
+            Levels::multi_search_candidates_with(&candidates[start..end], &pending_keys[start..end], seq_num, reads)
+        };
+        let found = if parallelism <= 1 || pending.len() < PAR_MULTI_GET_MIN_KEYS {
+            lookup(0, pending.len())?
+        } else {
+            //contiguous chunks, so joining the threads in order keeps the results in order
+            let chunk_len = pending.len().div_ceil(parallelism);
+            crossbeam_utils::thread::scope(|s| {
+                let lookup = &lookup;
+                let handles = (0..pending.len()).step_by(chunk_len)
+                    .map(|start| {
+                        let end = std::cmp::min(start + chunk_len, pending.len());
+                        s.spawn(move |_| lookup(start, end))
+                    })
+                    .collect::<Vec<_>>();
+                handles.into_iter()
+                    .map(|h| h.join().unwrap())
+                    .collect::<Result<Vec<_>>>()
+            }).unwrap()?
+                .into_iter()
+                .flatten()
+                .collect()
+        };
+        let mut seeks_exhausted = false;
+        for (&i, (value, exhausted)) in pending.iter().zip(found) {
+            results[i] = value.map(|v| v.to_vec());
+            seeks_exhausted |= exhausted;
+        }
+        if seeks_exhausted {
+            self.may_schedule_compaction();
+        }
+        Ok(results)
+    }
+
+    //the newest value of every key starting with `prefix`, in key order.
+    //With a prefix extractor, scanning one of its whole prefixes skips the tables whose filter rules it out.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_prefix_from(prefix, prefix, usize::MAX)
+    }
+
+    //scan_prefix at the snapshot of `opts`, the deadline is checked between the entries
+    pub fn scan_prefix_opt(&self, prefix: &[u8], opts: &ReadOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let seq_num = opts.snapshot.map_or_else(|| self.last_published_seq(), |s| s.seq_num());
+        self.scan_prefix_with(prefix, prefix, usize::MAX, seq_num, opts.block_reads())
+    }
+
+    //a cursor over the database as it is now, see DbIterator
+    pub fn iter(&self) -> Result<DbIterator> {
+        self.iter_opt(&ReadOptions::default())
+    }
+
+    //the deadline counts from now, a block read after it stops the iterator with status() Err(TimedOut)
+    pub fn iter_opt(&self, opts: &ReadOptions) -> Result<DbIterator> {
+        self.iter_with(opts, &IterOptions::default())
+    }
+
+    pub fn iter_with(&self, opts: &ReadOptions, iter_opts: &IterOptions) -> Result<DbIterator> {
+        self.check_no_timestamps()?;
+        let seq_num = opts.snapshot.map_or_else(|| self.last_published_seq(), |s| s.seq_num());
+        let keys_only = iter_opts.keys_only;
+        let mem_entries = |t: &MemTable| t.inner.iter()
+            .map(|(k, v)| (LookUpKey::new(k.clone()), if keys_only { Bytes::new() } else { v.clone() }))
+            .collect::<Vec<_>>();
+        //newer sources first
+        let mut children: Vec<Box<dyn Cursor>> = vec![Box::new(VecCursor::new(mem_entries(&self.mem_table.read())))];
+        children.extend(self.im_mem_table.read().as_ref().map(|t| Box::new(VecCursor::new(mem_entries(t))) as Box<dyn Cursor>));
+        //the cursors hold the tables, so one read of the levels is the version the iterator sees till it is dropped
+        let levels = self.levels.read();
+        children.extend(levels.cursors(opts.block_reads(), keys_only).into_iter().map(|c| Box::new(c) as Box<dyn Cursor>));
+        let pinned_files = levels.live_tables();
+        drop(levels);
+        //keys only iterators never look at the values, pointers included
+        let value_logs = (!keys_only).then(|| self.value_logs.clone());
+        let verify = opts.verify_checksums.unwrap_or(self.config.paranoid_checks);
+        Ok(DbIterator::new(children, seq_num, pinned_files, value_logs, verify))
+    }
+
+    //the keys starting with `prefix` in key order, without reading their values
+    pub fn scan_prefix_keys(&self, prefix: &[u8], opts: &ReadOptions) -> Result<Vec<Vec<u8>>> {
+        let mut iter = self.iter_with(opts, &IterOptions { keys_only: true })?;
+        let mut keys = Vec::new();
+        iter.seek(prefix);
+        while iter.valid() && iter.key().starts_with(prefix) {
+            keys.push(iter.key().to_vec());
+            iter.next();
+        }
+        iter.status()?;
+        Ok(keys)
+    }
+
+    //live keys in [start, end), without copying a value
+    pub fn count_range(&self, start: &[u8], end: &[u8], opts: &CountOptions) -> Result<u64> {
+        if start >= end {
+            return Ok(0);
+        }
+        if opts.approximate {
+            let in_range = |k: &InternalKey| start <= &k.user_key[..] && &k.user_key[..] < end;
+            let mem_entries = |t: &MemTable| t.inner.iter().filter(|(k, _)| in_range(k)).count() as u64;
+            let mut count = mem_entries(&self.mem_table.read());
+            count += self.im_mem_table.read().as_ref().map_or(0, mem_entries);
+            return Ok(count + self.levels.read().count_entries_in(start, end, &opts.read.block_reads())?);
+        }
+        let mut iter = self.iter_with(&opts.read, &IterOptions { keys_only: true })?;
+        let mut count = 0;
+        iter.seek(start);
+        while iter.valid() && iter.key() < end {
+            count += 1;
+            iter.next();
+        }
+        iter.status()?;
+        Ok(count)
+    }
+
+    pub(crate) fn env(&self) -> &Arc<dyn Env> {
+        &self.config.env
+    }
+
+    //up to `limit` live entries under `prefix` from key `start` on, so a scan can go on where it stopped
+    pub(crate) fn scan_prefix_from(&self, prefix: &[u8], start: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_prefix_with(prefix, start, limit, self.last_published_seq(), BlockReads::default())
+    }
+
+    fn scan_prefix_with(&self, prefix: &[u8], start: &[u8], limit: usize, seq_num: u64, reads: BlockReads) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.check_no_timestamps()?;
+        let start = std::cmp::max(prefix, start);
+        //newer sources first, merge_newest resolves equal sequence numbers by input order
+        let mem_entries = |t: &MemTable| t.prefix_iter(prefix).filter(|(k, _)| k.get_user_key() >= start).collect::<Vec<_>>();
+        let mut sources = vec![mem_entries(&self.mem_table.read())];
+        sources.extend(self.im_mem_table.read().as_ref().map(mem_entries));
+        let candidates = self.levels.read().prefix_candidates(prefix);
+        let table_error = Mutex::new(None);
+        let mut iters = sources.into_iter()
+            .map(|entries| Box::new(entries.into_iter()) as Box<dyn Iterator<Item = (LookUpKey, Bytes)>>)
+            .collect::<Vec<_>>();
+        iters.extend(candidates.iter().map(|t| {
+            let entries = until_error(t.iter_from_with(Some(start), None, Scan::Long, reads), &table_error)
+                .take_while(|(k, _)| k.get_user_key().starts_with(prefix));
+            Box::new(entries) as Box<dyn Iterator<Item = (LookUpKey, Bytes)>>
+        }));
+        let visible = iters.into_iter()
+            .map(|iter| iter.filter(|(k, _)| k.get_seq_num() <= seq_num))
+            .collect();
+        let verify = reads.verify_checksums.unwrap_or(self.config.paranoid_checks);
+        let res = merge_newest(visible)
+            .filter(|(k, _)| !k.is_deletion())
+            .take(limit)
+            .map(|(k, v)| {
+                reads.check_deadline()?;
+                let (value, checksum) = k.internal_key.split_value(v)?;
+                let value = self.value_logs.resolve(k.is_value_pointer(), value)?;
+                if verify {
+                    verify_value_checksum(k.get_user_key(), &value, checksum)?;
+                }
+                Ok((k.get_user_key().to_vec(), value.to_vec()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        match table_error.into_inner() {
+            Some(e) => Err(e),
+            None => Ok(res),
+        }
+    }
+
+    pub fn stats(&self) -> DbStats {
+        //a single read lock, a second one would wait behind a queued writer holding up the first
+        let levels = self.levels.read();
+        DbStats {
+            levels: levels.level_stats(),
+            compaction: levels.compaction_stats(),
+            tables_probed: self.tables_probed.load(Ordering::Relaxed),
+            slow_ops: self.slow_ops.count(),
+            blocks_read: levels.blocks_read(),
+            block_cache: levels.block_cache_stats(),
+            preloaded_bytes: levels.preloaded_bytes(),
+            wal_bytes_written: self.wal_bytes_written.load(Ordering::Relaxed),
+            last_seq_num: self.last_published_seq(),
+            seq_num_limit: SEQ_NUM_LIMIT,
+            write_stall: self.write_controller.stats(),
+        }
+    }
+
+    //a property by name as a string, for dashboards, None for a name it does not know:
+    //  draftkv.num-files-at-level<N>    tables of level N
+    //  draftkv.size-at-level<N>         bytes of the tables of level N
+    //  draftkv.cur-size-active-mem-table  memory of the mem table taking writes
+    //  draftkv.num-immutable-mem-table  0 or 1, the mem table waiting to be flushed
+    //  draftkv.estimate-num-keys        entries less twice the deletions, which also hide an older entry each
+    //  draftkv.background-errors        1 while background work stopped on an error, until resume()
+    //  draftkv.is-write-stalled         1 while a write waits for the flush thread
+    //  draftkv.levelstats               the levels, a line each
+    //  draftkv.sstables                 the tables with their key ranges, a line each
+    pub fn get_property(&self, name: &str) -> Option<String> {
+        let name = name.strip_prefix("draftkv.")?;
+        let level_stat = |suffix: &str, f: fn(&LevelStats) -> u64| {
+            let level = suffix.parse::<usize>().ok()?;
+            self.stats().levels.get(level).map(|l| f(l).to_string())
+        };
+        if let Some(level) = name.strip_prefix("num-files-at-level") {
+            return level_stat(level, |l| l.num_files as u64);
+        }
+        if let Some(level) = name.strip_prefix("size-at-level") {
+            return level_stat(level, |l| l.size_bytes);
+        }
+        let flag = |b: bool| (b as u8).to_string();
+        match name {
+            "cur-size-active-mem-table" => Some(self.memory_usage().mem_table.to_string()),
+            "num-immutable-mem-table" => Some(flag(self.im_mem_table.read().is_some())),
+            "estimate-num-keys" => Some(self.estimate_num_keys().to_string()),
+            "background-errors" => Some(flag(self.background_error.lock().is_some())),
+            "is-write-stalled" => Some(flag(self.write_stalled.load(Ordering::Acquire))),
+            "levelstats" => Some(format_level_stats(&self.stats().levels)),
+            "sstables" => Some(format_table_stats(&self.levels.read().table_stats())),
+            _ => None,
+        }
+    }
+
+    //a deletion usually hides an older entry, so it takes two off. Keys overwritten but not compacted yet count twice.
+    fn estimate_num_keys(&self) -> u64 {
+        let mut entries = 0;
+        let mut deletions = 0;
+        let mut add_mem_table = |t: &MemTable| {
+            entries += t.inner.len() as u64;
+            deletions += t.num_deletions as u64;
+        };
+        add_mem_table(&self.mem_table.read());
+        if let Some(t) = self.im_mem_table.read().as_ref() {
+            add_mem_table(t);
+        }
+        for level in self.stats().levels {
+            entries += level.num_entries;
+            deletions += level.num_deletions;
+        }
+        entries.saturating_sub(2 * deletions)
+    }
+
+    //a roughly uniform sample of up to `n` live user keys, sorted and without duplicates. Each entry of the mem
+    //tables and the tables is as likely to be drawn, so a key with several versions not compacted yet is more
+    //likely than others. The draws are spread evenly over the entries from a random start, and at most
+    //MAX_SAMPLE_BLOCKS data blocks are read: past that, every few draws in a table are taken from one block.
+    pub fn sample_keys(&self, n: usize) -> Result<Vec<Vec<u8>>> {
+        let mut rng = Rng(random_u64() | 1);
+        let tables = self.levels.read().table_weights();
+        let mem_entries = [
+            self.mem_table.read().inner.len() as u64,
+            self.im_mem_table.read().as_ref().map_or(0, |t| t.inner.len() as u64),
+        ];
+        //the draws are positions in the entries of the mem table, the immutable one and then each table
+        let ends = mem_entries.iter().cloned().chain(tables.iter().map(|(_, entries)| *entries))
+            .scan(0, |end, entries| { *end += entries; Some(*end) })
+            .collect::<Vec<_>>();
+        let total = ends.last().cloned().unwrap_or(0);
+        if total == 0 {
+            return Ok(Vec::new());
+        }
+        let mut mem_draws = [Vec::new(), Vec::new()];
+        let mut block_draws = Vec::new();
+        let start = rng.next() % total;
+        for i in 0..n {
+            let pos = ((i as u128 * total as u128 + start as u128) / n as u128) as u64;
+            let source = ends.partition_point(|&end| end <= pos);
+            let offset = pos - source.checked_sub(1).map_or(0, |s| ends[s]);
+            match source {
+                0 | 1 => mem_draws[source].push(offset),
+                _ => {
+                    let (table, entries) = &tables[source - 2];
+                    let block_idx = (offset as u128 * table.num_blocks() as u128 / *entries as u128) as usize;
+                    block_draws.push((source - 2, block_idx));
+                },
+            }
+        }
+
+        let mut keys = Vec::new();
+        sample_mem_table(&self.mem_table.read(), &mem_draws[0], &mut keys);
+        if let Some(t) = self.im_mem_table.read().as_ref() {
+            sample_mem_table(t, &mem_draws[1], &mut keys);
+        }
+        //the draws are in order, those next to each other are taken from the block of the first of them
+        let draws_per_block = block_draws.len().div_ceil(MAX_SAMPLE_BLOCKS);
+        let mut picks: HashMap<(usize, usize), usize> = HashMap::new();
+        for draws in block_draws.chunks(std::cmp::max(draws_per_block, 1)) {
+            *picks.entry(draws[0]).or_default() += draws.len();
+        }
+        for ((table_idx, block_idx), picks) in picks {
+            keys.extend(tables[table_idx].0.sample_block(block_idx, picks, &mut rng)?);
+        }
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    //the q - 1 keys that split the live keys into `q` parts with about as many keys each, from sample_keys
+    pub fn approximate_quantiles(&self, q: usize) -> Result<Vec<Vec<u8>>> {
+        if q == 0 {
+            return Err(Error::InvalidArgument("no quantiles".to_owned()));
+        }
+        let sample = self.sample_keys(std::cmp::max(q * SAMPLE_KEYS_PER_QUANTILE, MIN_QUANTILE_SAMPLE))?;
+        if sample.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok((1..q).map(|i| sample[i * sample.len() / q].clone()).collect())
+    }
+
+    //the level layout with every table, see Levels::dump
+    pub fn dump_levels(&self) -> String {
+        self.levels.read().dump()
+    }
+
+    pub fn dump_levels_normalized(&self) -> String {
+        self.levels.read().dump_normalized()
+    }
+
+    pub fn latency_histograms(&self) -> LatencyHistograms {
+        self.histograms.summary()
+    }
+
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn op_histograms(&self) -> &OpHistograms {
+        &self.histograms
+    }
+
+    //each part is kept up to date where it is allocated and freed, nothing is walked here
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let (cache, index_and_filter_blocks) = {
+            let levels = self.levels.read();
+            (levels.block_cache_stats(), levels.meta_bytes())
+        };
+        let mut usage = MemoryUsage {
+            mem_table: self.mem_table.read().memory_usage(),
+            im_mem_table: self.im_mem_table.read().as_ref().map_or(0, |t| t.memory_usage()),
+            block_cache: cache.usage - cache.pinned_usage,
+            //the index and filter blocks charged to the cache are counted with the tables holding them
+            pinned_blocks: cache.pinned_usage - cache.pinned_meta_usage,
+            index_and_filter_blocks,
+            tx_buffers: self.tx_buffer_bytes.load(Ordering::Relaxed),
+            wal_buffers: self.wal_buffer_bytes.load(Ordering::Relaxed),
+            total: 0,
+        };
+        usage.total = usage.mem_table + usage.im_mem_table + usage.block_cache + usage.pinned_blocks
+            + usage.index_and_filter_blocks + usage.tx_buffers + usage.wal_buffers;
+        usage
+    }
+
+    //compact the whole level into the next one regardless of scores, the bottom level is rewritten in place
+    //and a level the database has not grown yet has nothing to compact
+    pub fn compact_level(&self, level: usize) -> Result<CompactionSummary> {
+        if level >= self.config.max_levels {
+            return Err(Error::InvalidArgument(format!("level {} out of {} levels", level, self.config.max_levels)));
+        }
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        //wait for the compaction threads to go idle and keep them out until the result is installed, flushes go on
+        let max_compactions = self.config.max_background_compactions;
+        let mut guard = self.compactions_idle.0.lock();
+        while self.running_compactions.compare_exchange(0, max_compactions, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            self.compactions_idle.1.wait(&mut guard);
+        }
+        drop(guard);
+        //merge on a copy so readers and flushes are not held up by the lock
+        let levels = self.levels.read().clone();
+        let res = levels.compact_level(level);
+        let started = Instant::now();
+        let res = res.and_then(|(summary, (deleted_tables, new_tables))| {
+            let inputs = levels.table_files(&deleted_tables);
+            let outputs = new_tables.iter().map(Table::file).collect::<Vec<_>>();
+            self.levels.write().update(deleted_tables, new_tables)?;
+            if !(inputs.is_empty() && outputs.is_empty()) {
+                self.events.emit(|| DbEvent::CompactionFinished { at: SystemTime::now(), inputs, outputs, duration: started.elapsed() });
+            }
+            Ok(summary)
+        });
+        self.running_compactions.store(0, Ordering::Release);
+        //another compact_level may be waiting for the slots as well
+        let _guard = self.compactions_idle.0.lock();
+        self.compactions_idle.1.notify_all();
+        res
+    }
+
+    //the value logs by file number, with how much of each the live tables still point to
+    pub fn value_log_stats(&self) -> Vec<ValueLogStats> {
+        let live_bytes = self.levels.read().value_log_live_bytes();
+        self.value_logs.sizes().into_iter()
+            .map(|(file_num, bytes)| ValueLogStats {
+                file_num,
+                bytes,
+                live_bytes: live_bytes.get(&file_num).copied().unwrap_or(0),
+                retired: self.value_logs.is_retired(file_num),
+            })
+            .collect()
+    }
+
+    //Writes the live values of every value log with a garbage_ratio of at least value_log_gc_ratio again,
+    //through the write path, and retires the log. A value is live while the newest version of its key
+    //points to it. The new versions go to a new value log with a flush; once compactions dropped the
+    //pointers they hide, and no snapshot or iterator needs them any more, the retired log is deleted.
+    pub fn gc_value_logs(&self) -> Result<ValueLogGcSummary> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let mut summary = ValueLogGcSummary::default();
+        let victims = self.value_log_stats().into_iter()
+            .filter(|log| !log.retired && log.garbage_ratio() >= self.config.value_log_gc_ratio);
+        for log in victims {
+            let records = self.value_logs.records(log.file_num)?;
+            for records in records.chunks(INGEST_BATCH_SIZE) {
+                self.rewrite_live_values(records, &mut summary)?;
+            }
+            self.value_logs.retire(log.file_num);
+            summary.collected_files += 1;
+        }
+        Ok(summary)
+    }
+
+    //writes the values of `records` that are still live again, no other write of their keys comes in between
+    fn rewrite_live_values(&self, records: &[ValueRecord], summary: &mut ValueLogGcSummary) -> Result<()> {
+        self.check_writable()?;
+        self.throttle_write()?;
+        let _lock = self.update_lock.lock();
+        let mut batch = WriteBatch::new();
+        for record in records.iter() {
+            if !self.value_is_live(&record.key, &record.pointer)? {
+                continue;
+            }
+            batch.put(&record.key, &record.value);
+            summary.rewritten_values += 1;
+            summary.rewritten_bytes += record.value.len() as u64;
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let seq_nums = self.reserve_seq_nums(batch.len() as u64)?;
+        self.mem_table.write().write_batch(&batch, seq_nums.start)?;
+        self.publish(seq_nums.end - 1);
+        self.may_compact_mem_table();
+        Ok(())
+    }
+
+    //whether the newest version of `key` is the value at `pointer`, with the update lock held
+    fn value_is_live(&self, key: &[u8], pointer: &ValuePointer) -> Result<bool> {
+        match self.key_may_exist(key) {
+            //a mem table has a newer version, or no table can have the key
+            KeyMayExist::No | KeyMayExist::YesWithValue(_) => Ok(false),
+            KeyMayExist::Maybe => {
+                let candidates = self.levels.read().candidates(key);
+                let newest = Levels::newest_entry(&candidates, key)?
+                    .filter(|(k, _)| k.is_value_pointer())
+                    .and_then(|(k, v)| k.internal_key.split_value(v).ok())
+                    .and_then(|(v, _)| ValuePointer::decode(&v).ok());
+                Ok(newest.as_ref() == Some(pointer))
+            },
+        }
+    }
+
+    //`hook` runs once on close(), right away if that already happened. Front ends like a KvServer stop serving
+    //the database here, so their last writes make it into the flush.
+    pub fn on_close(&self, hook: Box<dyn FnOnce() + Send>) {
+        let mut hooks = self.close_hooks.lock();
+        match hooks.as_mut() {
+            Some(hooks) => hooks.push(hook),
+            None => {
+                drop(hooks);
+                hook();
+            },
+        }
+    }
+
+    //runs the close hooks, then writes the mem table out. Writes fail with Closed afterwards, reads go on
+    //until the handle is dropped, which stops the background threads.
+    pub fn close(&self) -> Result<()> {
+        let hooks = match self.close_hooks.lock().take() {
+            Some(hooks) => hooks,
+            None => return Ok(()),
+        };
+        //the hooks may still write while they wind down
+        for hook in hooks {
+            hook();
+        }
+        self.closed.store(true, Ordering::Release);
+        self.stop_stats_dump.lock().take();
+        match self.read_only {
+            true => Ok(()),
+            false => self.flush(),
+        }
+    }
+
+    //reads every table back and checks it, the number of tables verified or the first damage found.
+    //Also works on a read-only database.
+    pub fn verify(&self) -> Result<usize> {
+        //on a copy, so the files stay around for the reads and the lock is not held for them
+        let levels = self.levels.read().clone();
+        levels.verify()
+    }
+
+    //adds what the database in `other_dir` holds, which is opened read-only and left as it is. If its keys
+    //are all outside the ones here, its tables are copied over as they are and only its unflushed writes go
+    //through the write path; otherwise its live entries are written here one batch after another.
+    pub fn ingest_db(&self, other_dir: &Path, mode: IngestMode) -> Result<IngestSummary> {
+        self.check_writable()?;
+        self.check_no_timestamps()?;
+        let other = self.open_to_ingest(other_dir)?;
+        let other_levels = other.levels.read().clone();
+        let other_range = union_range(vec![
+            other_levels.key_range(),
+            mem_table_range(&other.mem_table.read()),
+            other.im_mem_table.read().as_ref().and_then(mem_table_range),
+        ].into_iter());
+        let other_range = match other_range {
+            Some(range) => range,
+            None => return Ok(IngestSummary::default()),
+        };
+        let mut summary = IngestSummary::default();
+        //the pointers of linked tables would lead into the value logs of this database
+        let can_link = other_levels.compaction_style() == self.config.compaction_style
+            && other.value_logs.file_nums().is_empty()
+            && other_levels.live_tables().iter().all(|t| t.level < self.config.max_levels);
+        let linked = can_link && self.link_tables(&other, &other_levels, &other_range, &mut summary)?;
+        //the unflushed writes of the other database are newer than its tables
+        let mut batch = WriteBatch::new();
+        let keys = match linked {
+            true => {
+                let mut keys = other.mem_table.read().prefix_iter(b"").map(|(k, _)| k.get_user_key().to_vec()).collect::<Vec<_>>();
+                keys.extend(other.im_mem_table.read().iter().flat_map(|t| t.prefix_iter(b"").map(|(k, _)| k.get_user_key().to_vec())));
+                keys.sort();
+                keys.dedup();
+                keys
+            },
+            false => {
+                if mode == IngestMode::FailOnConflict {
+                    self.check_no_conflicts(&other)?;
+                }
+                Vec::new()
+            },
+        };
+        for key in keys {
+            match other.search(&key, None)? {
+                Some(value) => batch.put(&key, &value),
+                None => batch.delete(&key),
+            };
+            self.write_full_batch(&mut batch, &mut summary, INGEST_BATCH_SIZE)?;
+        }
+        if !linked {
+            let mut start = Vec::new();
+            loop {
+                let entries = other.scan_prefix_from(b"", &start, INGEST_BATCH_SIZE)?;
+                let last = match entries.last() {
+                    Some((key, _)) => key.clone(),
+                    None => break,
+                };
+                for (key, value) in entries.iter() {
+                    batch.put(key, value);
+                }
+                self.write_full_batch(&mut batch, &mut summary, 1)?;
+                start = last;
+                start.push(0);
+            }
+        }
+        self.write_full_batch(&mut batch, &mut summary, 1)?;
+        Ok(summary)
+    }
+
+    //writes two databases, one with the keys before `split_key` to `left_dir` and one with the rest to `right_dir`.
+    //Both directories must be empty or not exist yet. The mem tables are flushed first, unless the database is
+    //read-only; writes made while the tables are copied are left out.
+    pub fn split(&self, split_key: &[u8], left_dir: &Path, right_dir: &Path) -> Result<SplitSummary> {
+        self.check_no_timestamps()?;
+        let env = &self.config.env;
+        for dir in [left_dir, right_dir].iter() {
+            env.create_dir_all(dir)?;
+            if !env.list_dir(dir)?.is_empty() {
+                return Err(Error::InvalidArgument(format!("{:?} is not empty", dir)));
+            }
+        }
+        if !self.read_only {
+            self.flush()?;
+        }
+        //the mem tables first, a flush meanwhile moves entries into the levels and never out of them
+        let seq_num = self.last_published_seq();
+        let mut mem_tables = Vec::new();
+        let entries = |t: &MemTable| (t.prefix_iter(b"").filter(|(k, _)| k.get_seq_num() <= seq_num).collect::<Vec<_>>(), t.write_times.clone());
+        mem_tables.extend(self.im_mem_table.read().as_ref().map(entries));
+        mem_tables.push(entries(&self.mem_table.read()));
+        let levels = self.levels.read().clone();
+
+        let left = Levels::open(left_dir.to_path_buf(), Vec::new(), &self.config, false)?;
+        let right = Levels::open(right_dir.to_path_buf(), Vec::new(), &self.config, false)?;
+        let (mut left_tables, mut right_tables, summary) = levels.split_into(split_key, &left, &right)?;
+        //what was not flushed goes to level 0, after the tables that are older
+        for (entries, write_times) in mem_tables {
+            let (left_entries, right_entries): (Vec<_>, Vec<_>) = entries.into_iter().partition(|(k, _)| k.get_user_key() < split_key);
+            left_tables.extend(left.write_files(left_entries.into_iter(), 0, write_times.clone(), 0)?);
+            right_tables.extend(right.write_files(right_entries.into_iter(), 0, write_times, 0)?);
+        }
+        let created_millis = unix_millis(self.config.clock.now());
+        for (dir, mut levels, tables) in [(left_dir, left, left_tables), (right_dir, right, right_tables)] {
+            levels.update(Vec::new(), tables)?;
+            Identity::new(created_millis, CURRENT_FORMAT).save(&**env, dir)?;
+        }
+        Ok(summary)
+    }
+
+    //the other database as ingest_db reads it, with the settings its data was written with
+    fn open_to_ingest(&self, other_dir: &Path) -> Result<LsmDb> {
+        let env = self.config.env.clone();
+        let mut config = Config::new();
+        if let Some(manifest) = Manifest::load(&*env, other_dir)? {
+            config.compaction_style = manifest.compaction_style;
+            config.user_timestamp_size = manifest.user_timestamp_size;
+        }
+        let max_levels = options::load_latest(&*env, other_dir)?
+            .and_then(|(_, options)| options.get("max_levels").and_then(|n| n.parse().ok()));
+        config.max_levels = std::cmp::max(config.max_levels, max_levels.unwrap_or(0));
+        config.env = env;
+        let other = LsmDb::open_read_only(other_dir.to_path_buf(), config)?;
+        other.check_no_timestamps()?;
+        Ok(other)
+    }
+
+    //copies the tables of `other` in if nothing here falls in `other_range`, then the sequence numbers here go
+    //past the ones in the tables so newer writes win over them. False if there is an overlap.
+    fn link_tables(&self, other: &LsmDb, other_levels: &Levels, other_range: &(Vec<u8>, Vec<u8>), summary: &mut IngestSummary) -> Result<bool> {
+        //no writes meanwhile, a flush only moves keys from the mem tables to the levels
+        let _lock = self.update_lock.lock();
+        let range = union_range(vec![
+            mem_table_range(&self.mem_table.read()),
+            self.im_mem_table.read().as_ref().and_then(mem_table_range),
+            self.levels.read().key_range(),
+        ].into_iter());
+        if let Some((min, max)) = range {
+            if ranges_overlap(&min, &max, &other_range.0, &other_range.1) {
+                return Ok(false);
+            }
+        }
+        let other_seq = other.last_published_seq();
+        if other_seq > SEQ_NUM_LIMIT {
+            return Err(Error::SequenceExhausted);
+        }
+        let levels = self.levels.read().clone();
+        let mut new_tables = Vec::new();
+        for table in other_levels.live_tables() {
+            match levels.link_table(&**other.env(), &table.path, table.level) {
+                Ok(table) => new_tables.push(table),
+                Err(e) => {
+                    for table in new_tables {
+                        table.mark_obsolete();
+                    }
+                    return Err(e);
+                },
+            }
+        }
+        summary.linked_files = new_tables.len();
+        summary.linked_bytes = new_tables.iter().map(|t| t.get_size()).sum();
+        self.levels.write().update(Vec::new(), new_tables)?;
+        if self.next_seq_num.fetch_max(other_seq + 1, Ordering::SeqCst) <= other_seq {
+            self.publish(other_seq);
+        }
+        Ok(true)
+    }
+
+    //fails on the first live key of `other` that is live here as well
+    fn check_no_conflicts(&self, other: &LsmDb) -> Result<()> {
+        let mut start = Vec::new();
+        loop {
+            let entries = other.scan_prefix_from(b"", &start, INGEST_BATCH_SIZE)?;
+            for (key, _) in entries.iter() {
+                if self.search(key, None)?.is_some() {
+                    return Err(Error::InvalidArgument(format!("key {:?} is in both databases", String::from_utf8_lossy(key))));
+                }
+            }
+            match entries.last() {
+                Some((key, _)) => {
+                    start = key.clone();
+                    start.push(0);
+                },
+                None => return Ok(()),
+            }
+        }
+    }
+
+    //writes `batch` once it holds `len` entries
+    fn write_full_batch(&self, batch: &mut WriteBatch, summary: &mut IngestSummary, len: usize) -> Result<()> {
+        if batch.len() >= len {
+            self.write(batch)?;
+            summary.written_entries += batch.len() as u64;
+            batch.clear();
+        }
+        Ok(())
+    }
+
+    //cheap enough to poll, nothing is read from disk
+    pub fn size_on_disk(&self) -> DiskUsage {
+        let (levels, obsolete) = {
+            let levels = self.levels.read();
+            (levels.level_bytes().to_vec(), levels.obsolete_bytes())
+        };
+        let wal = self.im_mem_table.read().iter().chain(Some(&*self.mem_table.read()))
+            .filter_map(|mem_table| mem_table.log_file())
+            .map(|(_, bytes)| bytes)
+            .sum();
+        let untracked = self.untracked_bytes.load(Ordering::Relaxed);
+        let value_logs = self.value_logs.total_bytes();
+        DiskUsage {
+            total: levels.iter().sum::<u64>() + wal + value_logs + obsolete + untracked,
+            levels,
+            wal,
+            value_logs,
+            obsolete,
+            untracked,
+        }
+    }
+
+    //walks the directory and counts whatever the counters of size_on_disk miss as untracked
+    pub fn refresh_from_fs(&self) -> Result<DiskUsage> {
+        let on_disk = dir_bytes(&*self.config.env, &self.db_path)?;
+        self.untracked_bytes.store(0, Ordering::Relaxed);
+        let counted = self.size_on_disk().total;
+        self.untracked_bytes.store(on_disk.saturating_sub(counted), Ordering::Relaxed);
+        Ok(self.size_on_disk())
+    }
+
+    //the files a copy of the database needs, taken while writes are held off for a moment. Call
+    //disable_file_deletions first, or flushes and compactions may delete some of them before they are copied.
+    pub fn live_files(&self) -> Result<LiveFiles> {
+        let _lock = self.update_lock.lock();
+        let seq_num = self.last_published_seq.load(Ordering::Acquire);
+        let mut logs = Vec::new();
+        //a flush installs its table before it lets go of the mem table, so looking at the levels afterwards
+        //finds the data in one or the other
+        if let Some(im_mem_table) = self.im_mem_table.read().as_ref() {
+            logs.extend(im_mem_table.log_file());
+        }
+        logs.extend(self.mem_table.read().log_file());
+        let levels = self.levels.read().clone();
+        let env = &*self.config.env;
+        let identity = Identity::file_name(&self.db_path);
+        Ok(LiveFiles {
+            tables: levels.live_tables(),
+            value_logs: self.value_logs.paths(),
+            logs: logs.into_iter().map(|(path, valid_bytes)| LiveLog { path, valid_bytes }).collect(),
+            manifest: Manifest::file_name(&self.db_path),
+            manifest_contents: levels.manifest().encode_to(),
+            identity: Some(identity).filter(|path| env.exists(path)),
+            seq_num,
+        })
+    }
+
+    //until the matching enable_file_deletions, obsolete tables and logs stay on disk. Calls nest.
+    pub fn disable_file_deletions(&self) {
+        self.levels.read().disable_file_deletions();
+    }
+
+    //the files kept while deletions were disabled are deleted once every disable is matched
+    pub fn enable_file_deletions(&self) -> Result<()> {
+        self.levels.read().enable_file_deletions()
+    }
+
+    //throttles background flushes and compactions, 0 means unlimited
+    pub fn set_rate_limit(&self, bytes_per_sec: u64) {
+        self.rate_limiter.set_bytes_per_sec(bytes_per_sec);
+    }
+
+    //changes options of the open database, named as in the OPTIONS file. Nothing changes unless all of them
+    //are valid, and the file keeps the values the database was opened with.
+    pub fn set_options(&self, options: &[(&str, &str)]) -> Result<()> {
+        let mut slow_op_threshold = None;
+        let mut rate_limit = None;
+        let (mut slowdown_trigger, mut stop_trigger) = self.write_controller.triggers();
+        for &(name, value) in options {
+            let invalid = || Error::InvalidArgument(format!("{} can not be set to {:?}", name, value));
+            match name {
+                //empty turns the slow operation log off
+                "slow_op_threshold_micros" if value.is_empty() => slow_op_threshold = Some(None),
+                "slow_op_threshold_micros" => {
+                    let micros = value.parse().map_err(|_| invalid())?;
+                    slow_op_threshold = Some(Some(Duration::from_micros(micros)));
+                },
+                "compaction_rate_limit_bytes_per_sec" => rate_limit = Some(value.parse().map_err(|_| invalid())?),
+                "level0_slowdown_writes_trigger" => slowdown_trigger = value.parse().map_err(|_| invalid())?,
+                "level0_stop_writes_trigger" => stop_trigger = value.parse().map_err(|_| invalid())?,
+                _ => return Err(Error::InvalidArgument(format!("{} can not be changed while the database is open", name))),
+            }
+        }
+        if slowdown_trigger >= stop_trigger {
+            return Err(Error::InvalidArgument(format!("level0_slowdown_writes_trigger is {}, it must be below the level0_stop_writes_trigger of {}",
+                slowdown_trigger, stop_trigger)));
+        }
+        if let Some(threshold) = slow_op_threshold {
+            self.slow_ops.set_threshold(threshold);
+        }
+        self.write_controller.set_triggers(slowdown_trigger, stop_trigger);
+        if let Some(bytes_per_sec) = rate_limit {
+            self.set_rate_limit(bytes_per_sec);
+        }
+        Ok(())
+    }
+
+    //one thread doing either flushes or compactions, it runs until the stop channel is dropped
+    fn start_worker(&self, work: BackgroundWork, idx: usize, do_work: Receiver<()>, stop: Receiver<()>) -> (Arc<Heartbeat>, thread::JoinHandle<()>) {
+        let levels = self.levels.clone();
+        let im_mem_table = self.im_mem_table.clone();
+        let running_flush = self.running_flush.clone();
+        let running_compactions = self.running_compactions.clone();
+        let compactions_idle = self.compactions_idle.clone();
+        let do_compaction = self.do_compaction.clone();
+        let shutdown = self.shutdown.clone();
+        let background_error = self.background_error.clone();
+        let max_retries = self.config.max_background_retries;
+        let max_compactions = self.config.max_background_compactions;
+        let histograms = self.histograms.clone();
+        let slow_ops = self.slow_ops.clone();
+        let events = self.events.clone();
+        let name = match work {
+            BackgroundWork::Flush => "flush",
+            BackgroundWork::Compaction => "compaction",
+        };
+        let heartbeat = Arc::new(Heartbeat::new(format!("{} {}", name, idx)));
+        let beating = heartbeat.clone();
+        let handle = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || loop {
+                beating.beat();
+                #[cfg(test)]
+                {
+                    if beating.exit.load(Ordering::Acquire) {
+                        break;
+                    }
+                }
+                crossbeam_channel::select! {
+                    recv(do_work) -> msg => if msg.is_err() { break },
+                    recv(stop) -> _ => break,
+                    default(HEARTBEAT_INTERVAL) => continue,
+                }
+                if shutdown.load(Ordering::Acquire) {
+                    break;
+                }
+                let mut done = false;
+                //work requested just before the error was recorded waits for resume() like the rest
+                if background_error.lock().is_none() {
+                    //a failed attempt leaves everything as it was, so it can simply be retried
+                    let mut attempt = 0;
+                    loop {
+                        let now = Instant::now();
+                        match Self::do_background_work(&levels, &im_mem_table, &events, work) {
+                            Ok(res) => {
+                                done = res;
+                                let histogram = match work {
+                                    BackgroundWork::Flush => &histograms.flush,
+                                    BackgroundWork::Compaction => &histograms.compaction,
+                                };
+                                if done {
+                                    let elapsed = now.elapsed();
+                                    histogram.record(elapsed);
+                                    if let BackgroundWork::Flush = work {
+                                        slow_ops.check(SlowOp::Flush, elapsed, None, &PerfContext::default(), false);
+                                    }
+                                }
+                                break;
+                            },
+                            Err(e) if attempt < max_retries => {
+                                attempt += 1;
+                                log::warn!("background {:?} failed, retry {} of {}: {}", work, attempt, max_retries, e);
+                                thread::sleep(Duration::from_millis(10 << attempt));
+                            },
+                            Err(e) => {
+                                log::error!("background {:?} failed, giving up: {}", work, e);
+                                *background_error.lock() = Some(e.to_string());
+                                events.emit(|| DbEvent::BackgroundError { at: SystemTime::now(), error: e.to_string() });
+                                break;
+                            },
+                        }
+                    }
+                }
+                match work {
+                    BackgroundWork::Flush => running_flush.store(false, Ordering::Release),
+                    BackgroundWork::Compaction => release_compaction(&running_compactions, &compactions_idle),
+                }
+                //a flush adds to level 0 and a compaction may overfill the next level, either can make more work
+                if done && !shutdown.load(Ordering::Acquire) {
+                    schedule_compaction(&running_compactions, &compactions_idle, max_compactions, &do_compaction);
+                }
+            })
+            .unwrap();
+        (heartbeat, handle)
+    }
+
+    //Logs and sends a StatsDump every `period` until `stop` is dropped. Only the counters are read,
+    //the levels lock just long enough to count their files.
+    fn start_stats_dump(&self, period: Duration, stop: Receiver<()>) -> (Arc<Heartbeat>, thread::JoinHandle<()>) {
+        let levels = self.levels.clone();
+        let mem_table = self.mem_table.clone();
+        let im_mem_table = self.im_mem_table.clone();
+        let histograms = self.histograms.clone();
+        let write_stall_micros = self.write_stall_micros.clone();
+        let background_error = self.background_error.clone();
+        let shutdown = self.shutdown.clone();
+        let events = self.events.clone();
+        let heartbeat = Arc::new(Heartbeat::new("stats dump 0".to_owned()));
+        let beating = heartbeat.clone();
+        //cache hits and misses, reads, writes and stall micros
+        let counters = move || {
+            let cache = levels.read().block_cache_stats();
+            let writes = histograms.put.count() + histograms.delete.count() + histograms.write_batch.count() + histograms.tx_commit.count();
+            [cache.hits, cache.misses, histograms.get.count(), writes, write_stall_micros.load(Ordering::Relaxed)]
+        };
+        let levels = self.levels.clone();
+        //taken here, the writes right after the open count towards the first dump
+        let mut last = (Instant::now(), counters());
+        let handle = thread::Builder::new()
+            .name("stats dump".to_owned())
+            .spawn(move || {
+                loop {
+                    beating.beat();
+                    let wait = period.saturating_sub(last.0.elapsed());
+                    if !wait.is_zero() {
+                        crossbeam_channel::select! {
+                            recv(stop) -> _ => break,
+                            default(std::cmp::min(wait, HEARTBEAT_INTERVAL)) => continue,
+                        }
+                    }
+                    if shutdown.load(Ordering::Acquire) {
+                        break;
+                    }
+                    let now = (Instant::now(), counters());
+                    let delta = |i: usize| now.1[i].saturating_sub(last.1[i]);
+                    let stats = StatsDump {
+                        period: now.0 - last.0,
+                        levels: levels.read().level_sizes(),
+                        mem_table_bytes: mem_table.read().memory_usage() + im_mem_table.read().as_ref().map_or(0, |t| t.memory_usage()),
+                        cache_hits: delta(0),
+                        cache_misses: delta(1),
+                        reads: delta(2),
+                        writes: delta(3),
+                        stall_time: Duration::from_micros(delta(4)),
+                        background_error: background_error.lock().clone(),
+                    };
+                    log::info!(target: "draft_kv::stats", "{}", stats);
+                    events.emit(|| DbEvent::StatsDump { at: SystemTime::now(), stats });
+                    last = now;
+                }
+            })
+            .unwrap();
+        (heartbeat, handle)
+    }
+
+    //returns whether anything changed, a panic is turned into an error as a last resort
+    fn do_background_work(levels: &RwLock<Levels>, im_mem_table: &RwLock<Option<MemTable>>, events: &EventBus, work: BackgroundWork) -> Result<bool> {
+        let started = Instant::now();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| -> Result<bool> {
+            match work {
+                BackgroundWork::Flush => {
+                    //write the table from a copy of the levels, the lock is only taken to install it
+                    let (deleted_tables, new_tables) = match im_mem_table.read().as_ref() {
+                        Some(im_mem_table) => {
+                            events.emit(|| DbEvent::FlushStarted { at: SystemTime::now(), mem_table_bytes: im_mem_table.size });
+                            let version = levels.read().clone();
+                            version.background_compaction(Some(im_mem_table))?
+                        },
+                        None => return Ok(false),
+                    };
+                    let table = new_tables.first().map(Table::file);
+                    levels.write().update(deleted_tables, new_tables)?;
+                    events.emit(|| DbEvent::FlushFinished { at: SystemTime::now(), table, duration: started.elapsed() });
+                    //the data is in the new table now, the log is no longer needed
+                    let log = im_mem_table.write().take().unwrap().take_writer();
+                    if let Err(e) = log.map_or(Ok(()), |log| levels.read().remove_log(log)) {
+                        log::error!("failed to remove the log of a flushed mem table: {}", e);
+                    }
+                    Ok(true)
+                },
+                BackgroundWork::Compaction => {
+                    //the merge may take seconds, run it on a copy instead of holding the lock
+                    let version = levels.read().clone();
+                    let (deleted_tables, new_tables) = version.background_compaction(None)?;
+                    let done = !(deleted_tables.is_empty() && new_tables.is_empty());
+                    let inputs = version.table_files(&deleted_tables);
+                    let outputs = new_tables.iter().map(Table::file).collect::<Vec<TableFile>>();
+                    //losing to another compaction still counts as done, so the inputs are picked again
+                    if levels.write().install(deleted_tables, new_tables)? && done {
+                        events.emit(|| DbEvent::CompactionFinished { at: SystemTime::now(), inputs, outputs, duration: started.elapsed() });
+                    }
+                    Ok(done)
+                },
+            }
+        }));
+        res.unwrap_or_else(|e| {
+            let msg = e.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| e.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_owned());
+            Err(Error::Background(format!("panicked: {}", msg)))
+        })
+    }
+
+}
+
+impl Drop for LsmDb {
+    //background work is finished before the lock is released, so it never races with the next instance
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Release);
+        //wakes the idle threads up, a busy one sees the flag once its work is done
+        self.stop_workers.take();
+        self.stop_stats_dump.lock().take();
+        for (_, worker) in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction_filter::FilterDecision;
+    use crate::prefix_extractor::FixedPrefix;
+    use crate::env::{MemEnv, StdEnv};
+    use crate::utils::{count_allocated_bytes, test_env, temp_dir, Rng};
+    use std::fs::{create_dir_all, read_dir};
+    use std::time::Instant;
+
+    fn small_config() -> Config {
+        let mut config = Config::new();
+        config.block_size = 1024;
+        config.l0_compaction_threshold = 2;
+        config.write_buffer_size = 1024;
+        config
+    }
+
+    fn wait_until<F: Fn() -> bool>(f: F) {
+        let now = Instant::now();
+        while !f() {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn background_idle(lsm: &LsmDb) -> bool {
+        !lsm.running_flush.load(Ordering::Acquire) && lsm.running_compactions.load(Ordering::Acquire) == 0
+    }
+
+    #[test]
+    fn major_compaction_keeps_keys_readable() {
+        let lsm = LsmDb::with_config(temp_dir("major_compaction"), small_config()).unwrap();
+        let mut keys = Vec::new();
+        let now = Instant::now();
+        //flushes are triggered by writes, so keep writing until compaction has reached L1
+        while lsm.levels.read().num_files_at_level(1) == 0 {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            let key = format!("key{:05}", keys.len());
+            lsm.insert(key.as_bytes(), format!("value-of-{}", key).as_bytes()).unwrap();
+            keys.push(key);
+        }
+        wait_until(|| {
+            background_idle(&lsm)
+                && lsm.levels.read().num_files_at_level(0) <= lsm.config.l0_compaction_threshold
+        });
+        for key in keys.iter() {
+            assert_eq!(lsm.search(key.as_bytes(), None).unwrap(), Some(format!("value-of-{}", key).into_bytes()));
+        }
+    }
+
+    #[test]
+    fn levels_are_added_as_the_bottom_one_outgrows_its_budget() {
+        let dir = temp_dir("grow_levels");
+        let mut config = small_config();
+        config.l1_max_bytes = 4096;
+        config.level_size_multiplier = 2;
+        config.max_levels = 4;
+        let lsm = LsmDb::with_config(dir.clone(), config).unwrap();
+        assert_eq!(lsm.levels.read().num_levels(), 2);
+        let mut keys = Vec::new();
+        let now = Instant::now();
+        //level 3 is the last one allowed, it takes whatever does not fit above
+        while lsm.levels.read().num_files_at_level(3) == 0 {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            let key = format!("key{:05}", keys.len());
+            lsm.insert(key.as_bytes(), format!("value-of-{}", key).as_bytes()).unwrap();
+            keys.push(key);
+            assert!(lsm.levels.read().num_levels() <= 4);
+        }
+        wait_until(|| background_idle(&lsm));
+        assert_eq!(lsm.levels.read().num_levels(), 4);
+        for key in keys.iter() {
+            assert_eq!(lsm.search(key.as_bytes(), None).unwrap(), Some(format!("value-of-{}", key).into_bytes()));
+        }
+        lsm.compact_level(2).unwrap();
+        assert_eq!(lsm.levels.read().num_files_at_level(2), 0);
+        assert_eq!(lsm.search(keys[0].as_bytes(), None).unwrap(), Some(b"value-of-key00000".to_vec()));
+        drop(lsm);
+
+        //the manifest keeps the levels, even the empty ones
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        assert_eq!(lsm.levels.read().num_levels(), 4);
+        assert_eq!(lsm.levels.read().num_files_at_level(2), 0);
+        drop(lsm);
+        let mut config = small_config();
+        config.max_levels = 3;
+        assert!(matches!(LsmDb::with_config(dir, config), Err(Error::InvalidArgument(_))));
+    }
+
+    //the same database in memory, the directory never reaches the disk
+    fn mem_env_config(env: &MemEnv) -> Config {
+        let mut config = small_config();
+        config.env = Arc::new(env.clone());
+        config
+    }
+
+    //what any test logged, the logger of the process can only be set once
+    static LOGGED: Mutex<Vec<String>> = parking_lot::const_mutex(Vec::new());
+
+    struct TestLogger;
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGGED.lock().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_log() {
+        static LOGGER: TestLogger = TestLogger;
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Warn);
+    }
+
+    #[test]
+    fn major_compaction_in_a_mem_env() {
+        let env = MemEnv::new();
+        let dir = PathBuf::from("/mem/major_compaction");
+        let lsm = LsmDb::with_config(dir.clone(), mem_env_config(&env)).unwrap();
+        let mut keys = Vec::new();
+        let now = Instant::now();
+        while lsm.levels.read().num_files_at_level(1) == 0 {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            let key = format!("key{:05}", keys.len());
+            lsm.insert(key.as_bytes(), format!("value-of-{}", key).as_bytes()).unwrap();
+            keys.push(key);
+        }
+        wait_until(|| {
+            background_idle(&lsm)
+                && lsm.levels.read().num_files_at_level(0) <= lsm.config.l0_compaction_threshold
+        });
+        for key in keys.iter() {
+            assert_eq!(lsm.search(key.as_bytes(), None).unwrap(), Some(format!("value-of-{}", key).into_bytes()));
+        }
+        assert!(env.list_dir(&dir).unwrap().iter().any(|path| path.extension() == Some(OsStr::new("sst"))));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn recovery_in_a_mem_env() {
+        let env = MemEnv::new();
+        let dir = PathBuf::from("/mem/recovery");
+        let lsm = LsmDb::with_config(dir.clone(), mem_env_config(&env)).unwrap();
+        assert!(matches!(LsmDb::with_config(dir.clone(), mem_env_config(&env)), Err(Error::DbLocked { .. })));
+        lsm.insert(b"flushed", b"1").unwrap();
+        lsm.flush().unwrap();
+        lsm.insert(b"logged", b"2").unwrap();
+        lsm.delete(b"flushed").unwrap();
+        let id = lsm.db_id().to_owned();
+        drop(lsm);
+
+        let lsm = LsmDb::with_config(dir.clone(), mem_env_config(&env)).unwrap();
+        assert_eq!(lsm.db_id(), id);
+        assert_eq!(lsm.search(b"logged", None).unwrap(), Some(b"2".to_vec()));
+        assert_eq!(lsm.search(b"flushed", None).unwrap(), None);
+        assert_eq!(options::load_latest(&env, &dir).unwrap().unwrap().0, 2);
+        //another env is another file system
+        drop(lsm);
+        let lsm = LsmDb::with_config(dir.clone(), mem_env_config(&MemEnv::new())).unwrap();
+        assert_eq!(lsm.search(b"logged", None).unwrap(), None);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn slow_lookups_are_logged_with_what_they_read() {
+        capture_log();
+        let env = MemEnv::new();
+        let mut config = mem_env_config(&env);
+        config.block_cache_size = 0;
+        let lsm = LsmDb::with_config(PathBuf::from("/mem/slow_ops"), config).unwrap();
+        lsm.insert(b"slow-key", b"value").unwrap();
+        lsm.flush().unwrap();
+        assert_eq!(lsm.stats().slow_ops, 0);
+
+        lsm.set_options(&[("slow_op_threshold_micros", "5000")]).unwrap();
+        env.set_read_delay(Duration::from_millis(10));
+        assert_eq!(lsm.search(b"slow-key", None).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(lsm.stats().slow_ops, 1);
+        let logged = LOGGED.lock().iter().filter(|r| r.contains("key=\"slow-key\"")).cloned().collect::<Vec<_>>();
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].starts_with("slow get") && logged[0].contains("tables_probed=1 blocks_read=1"), "{}", logged[0]);
+
+        lsm.set_options(&[("slow_op_threshold_micros", "")]).unwrap();
+        assert_eq!(lsm.search(b"slow-key", None).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(lsm.stats().slow_ops, 1);
+        assert!(matches!(lsm.set_options(&[("slow_op_threshold_micros", "soon")]), Err(Error::InvalidArgument(_))));
+        assert!(matches!(lsm.set_options(&[("block_size", "4096")]), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn commit_failed_in_a_write_stall_can_be_retried() {
+        let mut config = small_config();
+        config.l0_compaction_threshold = 100;
+        config.level0_slowdown_writes_trigger = 2;
+        config.level0_stop_writes_trigger = 4;
+        //the error reaches the stalled commit instead of failing it right away
+        config.read_only_on_background_error = false;
+        let lsm = LsmDb::with_config(temp_dir("commit_in_write_stall"), config).unwrap();
+        for i in 0..4 {
+            lsm.insert(format!("key{}", i).as_bytes(), b"value").unwrap();
+            lsm.flush().unwrap();
+        }
+        let (tx_id, seq_num) = lsm.tx_begin();
+        lsm.tx_insert(tx_id, seq_num, b"first", b"value").unwrap();
+        //a stopped write gives up on a background error
+        *lsm.background_error.lock() = Some("injected".to_owned());
+        assert!(matches!(lsm.tx_commit(tx_id), Err(Error::Background(_))));
+        lsm.resume();
+        lsm.set_options(&[("level0_stop_writes_trigger", "10")]).unwrap();
+        lsm.tx_commit(tx_id).unwrap();
+        //the write lock of the transaction was released
+        let (tx_id, seq_num) = lsm.tx_begin();
+        lsm.tx_insert(tx_id, seq_num, b"second", b"value").unwrap();
+        lsm.tx_commit(tx_id).unwrap();
+        assert_eq!(lsm.search(b"first", None).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(lsm.search(b"second", None).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn level0_triggers_slow_down_and_then_stop_writes() {
+        let mut config = small_config();
+        //level 0 is never compacted, only raising the triggers lets writes through
+        config.l0_compaction_threshold = 100;
+        config.level0_slowdown_writes_trigger = 2;
+        config.level0_stop_writes_trigger = 4;
+        let lsm = Arc::new(LsmDb::with_config(temp_dir("level0_triggers"), config).unwrap());
+        let events = lsm.events();
+        for i in 0..4 {
+            lsm.insert(format!("key{}", i).as_bytes(), b"value").unwrap();
+            lsm.flush().unwrap();
+        }
+        let stall = lsm.stats().write_stall;
+        assert_eq!((stall.state, stall.level0_files, stall.episodes), (WriteStallState::Stop, 4, 1));
+
+        let writer = {
+            let lsm = lsm.clone();
+            thread::spawn(move || lsm.insert(b"stopped", b"value"))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!writer.is_finished());
+        assert_eq!(lsm.search(b"stopped", None).unwrap(), None);
+        lsm.set_options(&[("level0_stop_writes_trigger", "10")]).unwrap();
+        writer.join().unwrap().unwrap();
+        assert_eq!(lsm.search(b"stopped", None).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(lsm.stats().write_stall.state, WriteStallState::Slowdown);
+
+        assert!(matches!(lsm.set_options(&[("level0_slowdown_writes_trigger", "10")]), Err(Error::InvalidArgument(_))));
+        lsm.set_options(&[("level0_slowdown_writes_trigger", "8")]).unwrap();
+        lsm.insert(b"key4", b"value").unwrap();
+        let stall = lsm.stats().write_stall;
+        assert_eq!((stall.state, stall.level0_files, stall.episodes), (WriteStallState::None, 4, 1));
+        assert!(stall.duration >= Duration::from_millis(50));
+        let changes = std::iter::from_fn(|| events.try_recv())
+            .filter_map(|e| match e {
+                DbEvent::WriteStallStateChanged { from, to, level0_files, .. } => Some((from, to, level0_files)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(changes, vec![
+            (WriteStallState::None, WriteStallState::Slowdown, 2),
+            (WriteStallState::Slowdown, WriteStallState::Stop, 4),
+            (WriteStallState::Stop, WriteStallState::Slowdown, 4),
+            (WriteStallState::Slowdown, WriteStallState::None, 4),
+        ]);
+    }
+
+    #[test]
+    fn par_multi_get_reads_tables_concurrently() {
+        let env = MemEnv::new();
+        //the lookups below would find their blocks cached instead of reading them
+        let mut config = mem_env_config(&env);
+        config.block_cache_size = 0;
+        let lsm = LsmDb::with_config(PathBuf::from("/mem/par_multi_get"), config).unwrap();
+        let mut i = 0;
+        let now = Instant::now();
+        while lsm.levels.read().num_files_at_level(1) == 0 {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            lsm.insert(format!("key{:05}", i).as_bytes(), b"old").unwrap();
+            i += 1;
+        }
+        wait_until(|| background_idle(&lsm));
+        //newer versions in level 0 and in the mem table
+        for j in (0..i).step_by(5) {
+            lsm.insert(format!("key{:05}", j).as_bytes(), b"new").unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.delete(b"key00003").unwrap();
+        lsm.insert(b"key00004", b"newest").unwrap();
+
+        let keys = (0..i + 20).step_by(3).map(|j| format!("key{:05}", j)).collect::<Vec<_>>();
+        let keys = keys.iter().map(|k| k.as_bytes()).collect::<Vec<_>>();
+        let serial = lsm.multi_get(&keys).unwrap();
+        assert_eq!(serial[1], None);
+        assert_eq!(serial[0], Some(b"new".to_vec()));
+        for (key, value) in keys.iter().zip(serial.iter()) {
+            assert_eq!(&lsm.search(key, None).unwrap(), value);
+        }
+        env.set_read_delay(Duration::from_micros(200));
+        env.take_max_concurrent_reads();
+        assert_eq!(lsm.par_multi_get(&keys).unwrap(), serial);
+        assert!(env.take_max_concurrent_reads() > 1);
+        //a few keys are read on the calling thread
+        assert_eq!(lsm.par_multi_get(&keys[..4]).unwrap(), &serial[..4]);
+        assert_eq!(env.take_max_concurrent_reads(), 1);
+    }
+
+    #[test]
+    fn lookups_are_served_from_the_block_cache_with_any_number_of_shards() {
+        for shard_bits in [0, 6] {
+            let env = MemEnv::new();
+            let mut config = mem_env_config(&env);
+            config.block_cache_shard_bits = shard_bits;
+            config.block_cache_size = 1024 * 1024;
+            let lsm = LsmDb::with_config(PathBuf::from(format!("/mem/block_cache_{}", shard_bits)), config).unwrap();
+            for i in 0..500 {
+                lsm.insert(format!("key{:05}", i).as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+            }
+            lsm.flush().unwrap();
+            wait_until(|| background_idle(&lsm));
+            //one table per key, so no lookup misses often enough to set off a seek compaction
+            for level in 0..lsm.config.max_levels - 1 {
+                lsm.compact_level(level).unwrap();
+            }
+            let keys = (0..520).map(|i| format!("key{:05}", i)).collect::<Vec<_>>();
+            let keys = keys.iter().map(|k| k.as_bytes()).collect::<Vec<_>>();
+            let expected = (0..520)
+                .map(|i| if i < 500 { Some(format!("value{}", i).into_bytes()) } else { None })
+                .collect::<Vec<_>>();
+            let searched = keys.iter().map(|k| lsm.search(k, None).unwrap()).collect::<Vec<_>>();
+            assert_eq!(searched, expected);
+
+            let blocks_read = lsm.stats().blocks_read;
+            assert_eq!(keys.iter().map(|k| lsm.search(k, None).unwrap()).collect::<Vec<_>>(), expected);
+            assert_eq!(lsm.multi_get(&keys).unwrap(), expected);
+            assert_eq!(lsm.stats().blocks_read, blocks_read);
+            let stats = lsm.stats().block_cache;
+            assert_eq!((stats.shards, stats.evictions), (1 << shard_bits, 0));
+            assert!(stats.hits >= 1000 && stats.misses > 0 && stats.usage > 0);
+        }
+        let mut config = Config::new();
+        config.block_cache_shard_bits = 17;
+        assert!(matches!(LsmDb::with_config(temp_dir("block_cache_shards"), config), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn memory_usage_follows_what_the_database_holds() {
+        let env = MemEnv::new();
+        let mut config = mem_env_config(&env);
+        config.write_buffer_size = 4 * 1024 * 1024;
+        let lsm = LsmDb::with_config(PathBuf::from("/mem/memory_usage"), config).unwrap();
+        let usage = lsm.memory_usage();
+        assert_eq!((usage.mem_table, usage.tx_buffers, usage.index_and_filter_blocks), (0, 0, 0));
+
+        let value = vec![b'v'; 1000];
+        for i in 0..1000 {
+            lsm.insert(format!("key{:05}", i).as_bytes(), &value).unwrap();
+        }
+        //internal keys and values
+        let written = 1000 * (8 + 8 + 1000);
+        let usage = lsm.memory_usage();
+        assert!(usage.mem_table >= written && usage.mem_table <= written * 5 / 4, "{} for {}", usage.mem_table, written);
+        //a single buffer as large as a record
+        assert!(usage.wal_buffers >= 1000 && usage.wal_buffers < 4096, "{}", usage.wal_buffers);
+        assert_eq!(usage.total, usage.mem_table + usage.im_mem_table + usage.block_cache + usage.pinned_blocks
+            + usage.index_and_filter_blocks + usage.tx_buffers + usage.wal_buffers);
+
+        let (tx_id, seq_num) = lsm.tx_begin();
+        for i in 0..100 {
+            lsm.tx_insert(tx_id, seq_num, format!("tx{:05}", i).as_bytes(), &value).unwrap();
+        }
+        lsm.tx_delete(tx_id, seq_num, b"tx00000");
+        let buffered = lsm.memory_usage().tx_buffers;
+        assert!((99 * 1007..=100 * 1007 * 5 / 4).contains(&buffered), "{}", buffered);
+        lsm.tx_commit(tx_id).unwrap();
+        let (tx_id, seq_num) = lsm.tx_begin();
+        lsm.tx_insert(tx_id, seq_num, b"aborted", &value).unwrap();
+        assert!(lsm.memory_usage().tx_buffers > 1000);
+        lsm.tx_abort(tx_id);
+        assert_eq!(lsm.memory_usage().tx_buffers, 0);
+
+        lsm.flush().unwrap();
+        let usage = lsm.memory_usage();
+        assert_eq!((usage.mem_table, usage.im_mem_table), (0, 0));
+        assert!(usage.index_and_filter_blocks > 0 && usage.index_and_filter_blocks < written / 10, "{}", usage.index_and_filter_blocks);
+        //the log of the flushed mem table is gone with its buffer
+        assert_eq!(usage.wal_buffers, 0);
+    }
+
+    #[test]
+    fn pinned_level0_blocks_outlast_a_cache_full_of_other_blocks() {
+        let env = MemEnv::new();
+        let mut config = mem_env_config(&env);
+        config.l0_compaction_threshold = 8;
+        config.block_cache_size = 16 * 1024;
+        config.block_cache_shard_bits = 0;
+        config.pin_l0_blocks = true;
+        config.pin_index_and_filter_blocks = true;
+        let lsm = LsmDb::with_config(PathBuf::from("/mem/pinned_l0"), config).unwrap();
+        let entries = |prefix: &str, n: usize| (0..n)
+            .map(|i| (LookUpKey::new(InternalKey::new(format!("{}{:05}", prefix, i).as_bytes(), 1, ValueType::Put)), vec![b'v'; 100]))
+            .collect::<Vec<_>>();
+        lsm.next_seq_num.store(2, Ordering::SeqCst);
+        lsm.last_published_seq.store(1, Ordering::SeqCst);
+        {
+            let mut levels = lsm.levels.write();
+            let l0 = levels.write_file(entries("hot", 20).into_iter(), 0).unwrap();
+            let l1 = levels.write_file(entries("cold", 1000).into_iter(), 1).unwrap();
+            levels.update(Vec::new(), vec![l0, l1]).unwrap();
+        }
+        let pinned_meta = lsm.stats().block_cache.pinned_usage;
+        assert!(pinned_meta > 0);
+        assert_eq!(lsm.memory_usage().index_and_filter_blocks, pinned_meta);
+        assert_eq!(lsm.memory_usage().pinned_blocks, 0);
+
+        let hot = (0..20).map(|i| format!("hot{:05}", i)).collect::<Vec<_>>();
+        for key in hot.iter() {
+            assert_eq!(lsm.search(key.as_bytes(), None).unwrap(), Some(vec![b'v'; 100]));
+        }
+        assert!(lsm.memory_usage().pinned_blocks > 0);
+        //lookups over every block of level 1 are many times the cache
+        for i in 0..1000 {
+            assert!(lsm.search(format!("cold{:05}", i).as_bytes(), None).unwrap().is_some());
+        }
+        let stats = lsm.stats();
+        assert!(stats.block_cache.evictions > 0);
+        for key in hot.iter() {
+            assert_eq!(lsm.search(key.as_bytes(), None).unwrap(), Some(vec![b'v'; 100]));
+        }
+        assert_eq!(lsm.stats().blocks_read, stats.blocks_read);
+        let usage = lsm.memory_usage();
+        assert!(usage.block_cache + usage.pinned_blocks + pinned_meta <= 16 * 1024);
+
+        //the table leaves level 0 by a trivial move, only its index and filter blocks stay pinned
+        lsm.compact_level(0).unwrap();
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 0);
+        assert_eq!(lsm.memory_usage().pinned_blocks, 0);
+        assert_eq!(lsm.stats().block_cache.pinned_usage, pinned_meta);
+    }
+
+    #[test]
+    fn preloaded_level0_blocks_need_no_reads_on_the_first_lookup() {
+        let env = MemEnv::new();
+        let dir = PathBuf::from("/mem/preload");
+        let config = |preload: Preload| {
+            let mut config = mem_env_config(&env);
+            config.l0_compaction_threshold = 8;
+            config.preload_on_open = preload;
+            config
+        };
+        {
+            let lsm = LsmDb::with_config(dir.clone(), config(Preload::None)).unwrap();
+            for i in 0..200 {
+                lsm.insert(format!("a{:05}", i).as_bytes(), &[b'v'; 50]).unwrap();
+            }
+            lsm.flush().unwrap();
+            wait_until(|| background_idle(&lsm));
+            lsm.compact_level(0).unwrap();
+            for i in 0..5 {
+                lsm.insert(format!("b{:05}", i).as_bytes(), &[b'w'; 50]).unwrap();
+            }
+            lsm.flush().unwrap();
+            wait_until(|| background_idle(&lsm));
+            assert_eq!(lsm.levels.read().num_files_at_level(0), 1);
+            assert!(lsm.levels.read().num_files_at_level(1) >= 1);
+        }
+
+        let lsm = LsmDb::with_config(dir.clone(), config(Preload::None)).unwrap();
+        assert_eq!(lsm.stats().preloaded_bytes, 0);
+        env.take_read_count();
+        assert_eq!(lsm.search(b"b00003", None).unwrap(), Some(vec![b'w'; 50]));
+        assert_eq!(env.take_read_count(), 1);
+        drop(lsm);
+
+        let lsm = LsmDb::with_config(dir.clone(), config(Preload::IndexesAndL0)).unwrap();
+        let preloaded = lsm.stats().preloaded_bytes;
+        env.take_read_count();
+        assert_eq!(lsm.search(b"b00003", None).unwrap(), Some(vec![b'w'; 50]));
+        assert_eq!(env.take_read_count(), 0);
+        //the index was read on open, only the data block is left to read
+        assert_eq!(lsm.search(b"a00100", None).unwrap(), Some(vec![b'v'; 50]));
+        assert_eq!(env.take_read_count(), 1);
+        drop(lsm);
+
+        //with no budget only the index and filter blocks are read
+        let mut no_budget = config(Preload::IndexesAndL0);
+        no_budget.preload_budget_bytes = 0;
+        let lsm = LsmDb::with_config(dir, no_budget).unwrap();
+        assert!(lsm.stats().preloaded_bytes > 0 && lsm.stats().preloaded_bytes < preloaded);
+        env.take_read_count();
+        assert_eq!(lsm.search(b"b00003", None).unwrap(), Some(vec![b'w'; 50]));
+        assert_eq!(env.take_read_count(), 1);
+    }
+
+    #[test]
+    fn latency_histograms_count_every_operation() {
+        let lsm = LsmDb::with_config(temp_dir("latency_histograms"), small_config()).unwrap();
+        for i in 0..50 {
+            lsm.insert(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        for i in 0..10 {
+            lsm.delete(format!("key{:03}", i).as_bytes()).unwrap();
+        }
+        lsm.write(WriteBatch::new().put(b"a", b"1").delete(b"b")).unwrap();
+        let (tx_id, seq_num) = lsm.tx_begin();
+        lsm.tx_insert(tx_id, seq_num, b"c", b"3").unwrap();
+        lsm.tx_commit(tx_id).unwrap();
+        for i in 0..30 {
+            lsm.search(format!("key{:03}", i).as_bytes(), None).unwrap();
+        }
+        assert!(lsm.insert(b"", b"empty key").is_err());
+        lsm.flush().unwrap();
+        wait_until(|| background_idle(&lsm));
+
+        let histograms = lsm.latency_histograms();
+        let counts = [&histograms.put, &histograms.delete, &histograms.write_batch, &histograms.tx_commit, &histograms.get]
+            .iter()
+            .map(|h| h.count)
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![50, 10, 1, 1, 30]);
+        assert!(histograms.flush.count >= 1);
+        for h in [&histograms.put, &histograms.get, &histograms.flush].iter() {
+            assert!(h.p50 <= h.p90 && h.p90 <= h.p99 && h.p99 <= h.max && h.max > Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn perf_context_follows_a_lookup_through_the_levels() {
+        let mut config = small_config();
+        //the key filter would spare the read of the level 0 block
+        config.bloom_bits_per_key = 0.0;
+        let lsm = LsmDb::with_config(temp_dir("perf_context"), config).unwrap();
+        let entry = |key: &str| (LookUpKey::new(InternalKey::new(key.as_bytes(), 1, ValueType::Put)), key.as_bytes().to_vec());
+        lsm.next_seq_num.store(2, Ordering::SeqCst);
+        lsm.last_published_seq.store(1, Ordering::SeqCst);
+        let (l0, l1) = {
+            let mut levels = lsm.levels.write();
+            let l0 = levels.write_file(vec![entry("a"), entry("c")].into_iter(), 0).unwrap();
+            let l1 = levels.write_file(vec![entry("b")].into_iter(), 1).unwrap();
+            let nums = (l0.get_file_num(), l1.get_file_num());
+            levels.update(Vec::new(), vec![l0, l1]).unwrap();
+            nums
+        };
+
+        perf_context::enable();
+        assert_eq!(lsm.search(b"b", None).unwrap(), Some(b"b".to_vec()));
+        let context = perf_context::get();
+        assert_eq!(context.mem_table_probes, 1);
+        assert_eq!(context.table_probes, vec![(0, l0), (1, l1)]);
+        assert_eq!((context.blocks_read, context.block_cache_hits), (2, 0));
+
+        perf_context::reset();
+        assert_eq!(lsm.search(b"b", None).unwrap(), Some(b"b".to_vec()));
+        let context = perf_context::get();
+        assert_eq!(context.table_probes, vec![(0, l0), (1, l1)]);
+        assert_eq!((context.blocks_read, context.block_cache_hits), (0, 2));
+        //a key before every table is only looked for in the mem table
+        perf_context::reset();
+        assert_eq!(lsm.search(b"0", None).unwrap(), None);
+        assert!(perf_context::get().table_probes.is_empty());
+        perf_context::disable();
+        assert_eq!(lsm.search(b"b", None).unwrap(), Some(b"b".to_vec()));
+        assert_eq!(perf_context::get().table_probes.len(), 0);
+    }
+
+    #[test]
+    fn readers_never_see_half_a_batch() {
+        let lsm = LsmDb::with_config(temp_dir("atomic_batches"), small_config()).unwrap();
+        let done = AtomicBool::new(false);
+        crossbeam_utils::thread::scope(|s| {
+            s.spawn(|_| {
+                for i in 0..500u64 {
+                    let mut batch = WriteBatch::new();
+                    batch.put(b"left", &i.to_be_bytes()).put(b"right", &i.to_be_bytes());
+                    lsm.write(&batch).unwrap();
+                    let (tx_id, seq_num) = lsm.tx_begin();
+                    lsm.tx_insert(tx_id, seq_num, b"tx-left", &i.to_be_bytes()).unwrap();
+                    lsm.tx_insert(tx_id, seq_num, b"tx-right", &i.to_be_bytes()).unwrap();
+                    lsm.tx_commit(tx_id).unwrap();
+                }
+                done.store(true, Ordering::Release);
+            });
+            while !done.load(Ordering::Acquire) {
+                let values = lsm.multi_get(&[b"left", b"right", b"tx-left", b"tx-right"]).unwrap();
+                assert_eq!(values[0], values[1]);
+                assert_eq!(values[2], values[3]);
+            }
+        }).unwrap();
+        assert_eq!(lsm.search(b"right", None).unwrap(), Some(499u64.to_be_bytes().to_vec()));
+    }
+
+    #[test]
+    fn batches_take_consecutive_sequence_numbers_and_survive_a_reopen() {
+        let dir = temp_dir("batch_recovery");
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        lsm.insert(b"a", b"old").unwrap();
+        let before = lsm.last_published_seq();
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1").put(b"b", b"2").delete(b"a").put(b"a", b"3");
+        lsm.write(&batch).unwrap();
+        assert_eq!(lsm.last_published_seq(), before + 4);
+        //every step of the batch is a version of its own
+        assert_eq!(lsm.search(b"a", Some(before + 1)).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(lsm.search(b"a", Some(before + 3)).unwrap(), None);
+        assert_eq!(lsm.search(b"a", None).unwrap(), Some(b"3".to_vec()));
+        lsm.write(&WriteBatch::new()).unwrap();
+        assert_eq!(lsm.last_published_seq(), before + 4);
+        drop(lsm);
+
+        let lsm = LsmDb::with_config(dir, small_config()).unwrap();
+        assert_eq!(lsm.last_published_seq(), before + 4);
+        assert_eq!(lsm.search(b"a", None).unwrap(), Some(b"3".to_vec()));
+        assert_eq!(lsm.search(b"b", None).unwrap(), Some(b"2".to_vec()));
+        let mut batch = WriteBatch::new();
+        batch.put(b"", b"empty key");
+        assert!(matches!(lsm.write(&batch), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn flush_writes_the_mem_table_out() {
+        let dir = temp_dir("flush");
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        lsm.flush().unwrap();
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 0);
+        lsm.insert(b"k", b"v").unwrap();
+        lsm.delete(b"gone").unwrap();
+        lsm.flush().unwrap();
+        assert!(lsm.mem_table.read().inner.is_empty());
+        assert!(lsm.im_mem_table.read().is_none());
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 1);
+        assert_eq!(lsm.search(b"k", None).unwrap(), Some(b"v".to_vec()));
+        //only the log of the new mem table is left
+        let logs = read_dir(&dir).unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension() == Some(OsStr::new("LOG")))
+            .count();
+        assert_eq!(logs, 1);
+    }
+
+    #[test]
+    fn compact_level_of_empty_level_is_noop() {
+        let lsm = LsmDb::with_config(temp_dir("compact_empty_level"), small_config()).unwrap();
+        assert_eq!(lsm.compact_level(1).unwrap(), CompactionSummary::default());
+        assert!(matches!(lsm.compact_level(lsm.config.max_levels), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn compact_level_moves_single_table_down() {
+        let mut config = small_config();
+        config.l0_compaction_threshold = 100;
+        let lsm = LsmDb::with_config(temp_dir("compact_single_table"), config).unwrap();
+        let mut keys = Vec::new();
+        let now = Instant::now();
+        while lsm.levels.read().num_files_at_level(0) == 0 {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            let key = format!("key{:05}", keys.len());
+            lsm.insert(key.as_bytes(), key.as_bytes()).unwrap();
+            keys.push(key);
+        }
+        wait_until(|| background_idle(&lsm));
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 1);
+
+        let summary = lsm.compact_level(0).unwrap();
+        assert_eq!(summary.input_files, 1);
+        assert_eq!(summary.output_files, 1);
+        assert!(summary.bytes_read > 0 && summary.bytes_written > 0);
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 0);
+        assert_eq!(lsm.levels.read().num_files_at_level(1), 1);
+        for key in keys.iter() {
+            assert_eq!(lsm.search(key.as_bytes(), None).unwrap(), Some(key.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn events_follow_a_flush_and_a_compaction() {
+        let mut config = small_config();
+        config.write_buffer_size = 1 << 20;
+        config.l0_compaction_threshold = 100;
+        let lsm = LsmDb::with_config(temp_dir("events"), config).unwrap();
+        let first = lsm.events();
+        let second = lsm.events_with_capacity(2);
+        for i in 0..100 {
+            lsm.insert(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.compact_level(0).unwrap();
+
+        let events = std::iter::from_fn(|| first.try_recv())
+            .filter(|e| !matches!(e, DbEvent::WriteStallStarted { .. } | DbEvent::WriteStallEnded { .. }))
+            .collect::<Vec<_>>();
+        let flushed = match &events[..] {
+            [DbEvent::WalRotated { .. }, DbEvent::FlushStarted { mem_table_bytes, .. }, DbEvent::FlushFinished { table: Some(flushed), .. },
+                DbEvent::CompactionStarted { inputs, output_level: 1, .. }, DbEvent::CompactionFinished { outputs, .. }, DbEvent::SstDeleted { table, .. }] => {
+                assert!(*mem_table_bytes > 0);
+                assert_eq!(inputs, &vec![flushed.clone()]);
+                assert_eq!(outputs.len(), 1);
+                assert_eq!(outputs[0].level, 1);
+                assert_eq!(table, flushed);
+                flushed.clone()
+            },
+            _ => panic!("unexpected events {:?}", events),
+        };
+        assert_eq!(flushed.level, 0);
+        assert!(!flushed.path.exists());
+        //the second receiver only kept the newest two, after the count of those it lost
+        assert!(matches!(second.try_recv(), Some(DbEvent::Dropped { .. })));
+        assert!(matches!(second.try_recv(), Some(DbEvent::CompactionFinished { .. })));
+        assert!(matches!(second.try_recv(), Some(DbEvent::SstDeleted { .. })));
+        assert_eq!(second.try_recv(), None);
+    }
+
+    #[test]
+    fn a_copy_of_the_live_files_opens_at_their_seq_num() {
+        let lsm = Arc::new(LsmDb::with_config(temp_dir("live_files"), small_config()).unwrap());
+        for i in 0..200 {
+            lsm.insert(format!("key{:05}", i).as_bytes(), b"value").unwrap();
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (lsm, stop) = (lsm.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut i = 200;
+                while !stop.load(Ordering::Acquire) {
+                    lsm.insert(format!("key{:05}", i).as_bytes(), b"value").unwrap();
+                    i += 1;
+                }
+                i
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        lsm.disable_file_deletions();
+        let live = lsm.live_files().unwrap();
+        //flushes and compactions go on while the files are copied
+        thread::sleep(Duration::from_millis(50));
+        let copy = temp_dir("live_files_copy");
+        for table in live.tables.iter() {
+            std::fs::copy(&table.path, copy.join(table.path.file_name().unwrap())).unwrap();
+        }
+        for log in live.logs.iter() {
+            let bytes = std::fs::read(&log.path).unwrap();
+            std::fs::write(copy.join(log.path.file_name().unwrap()), &bytes[..log.valid_bytes as usize]).unwrap();
+        }
+        std::fs::write(copy.join(live.manifest.file_name().unwrap()), &live.manifest_contents).unwrap();
+        let identity = live.identity.clone().unwrap();
+        std::fs::copy(&identity, copy.join(identity.file_name().unwrap())).unwrap();
+        stop.store(true, Ordering::Release);
+        let written = writer.join().unwrap();
+        lsm.enable_file_deletions().unwrap();
+        assert!(lsm.enable_file_deletions().is_err());
+
+        let copied = LsmDb::with_config(copy, small_config()).unwrap();
+        assert_eq!(copied.db_id(), lsm.db_id());
+        assert_eq!(copied.stats().last_seq_num, live.seq_num);
+        //a single writer, so the keys in the copy are the ones written first
+        let present = (0..written).take_while(|i| copied.search(format!("key{:05}", i).as_bytes(), None).unwrap().is_some()).count();
+        assert!(present >= 200 && present < written, "{} of {}", present, written);
+        assert!((present..written).all(|i| copied.search(format!("key{:05}", i).as_bytes(), None).unwrap().is_none()));
+    }
+
+    #[test]
+    fn disabled_file_deletions_keep_obsolete_files_until_enabled_as_often() {
+        let mut config = small_config();
+        config.write_buffer_size = 1 << 20;
+        config.l0_compaction_threshold = 100;
+        let lsm = LsmDb::with_config(temp_dir("file_deletions"), config).unwrap();
+        lsm.insert(b"a", b"1").unwrap();
+        lsm.disable_file_deletions();
+        lsm.disable_file_deletions();
+        let log = lsm.live_files().unwrap().logs[0].path.clone();
+        lsm.flush().unwrap();
+        let table = lsm.live_files().unwrap().tables[0].path.clone();
+        lsm.compact_level(0).unwrap();
+        assert!(lsm.live_files().unwrap().tables.iter().all(|t| t.path != table));
+        lsm.enable_file_deletions().unwrap();
+        assert!(log.exists() && table.exists());
+        lsm.enable_file_deletions().unwrap();
+        assert!(!log.exists() && !table.exists());
+    }
+
+    #[test]
+    fn size_on_disk_follows_flushes_compactions_and_deletions() {
+        let mut config = small_config();
+        config.write_buffer_size = 1 << 20;
+        config.l0_compaction_threshold = 100;
+        let dir = temp_dir("size_on_disk");
+        let lsm = LsmDb::with_config(dir.clone(), config).unwrap();
+        let file_bytes = |path: &Path| std::fs::metadata(path).unwrap().len();
+        for i in 0..100 {
+            lsm.insert(format!("key{:03}", i).as_bytes(), &[b'v'; 100]).unwrap();
+        }
+        let usage = lsm.size_on_disk();
+        assert_eq!(usage.wal, file_bytes(&lsm.live_files().unwrap().logs[0].path));
+        assert!(usage.levels.iter().all(|&bytes| bytes == 0));
+
+        lsm.flush().unwrap();
+        let live = lsm.live_files().unwrap();
+        let usage = lsm.size_on_disk();
+        assert_eq!(usage.levels[0], file_bytes(&live.tables[0].path));
+        //the new log only has its header
+        assert!(usage.wal < 100);
+        assert_eq!(usage.obsolete, 0);
+
+        //the flushed table waits for deletion while deletions are disabled
+        lsm.disable_file_deletions();
+        lsm.compact_level(0).unwrap();
+        let usage = lsm.size_on_disk();
+        assert_eq!(usage.levels[0], 0);
+        assert_eq!(usage.levels[1], file_bytes(&lsm.live_files().unwrap().tables[0].path));
+        assert_eq!(usage.obsolete, live.tables[0].bytes);
+        //and so does the log of the next flushed mem table
+        lsm.insert(b"key999", b"v").unwrap();
+        let log = lsm.size_on_disk().wal;
+        lsm.flush().unwrap();
+        assert_eq!(lsm.size_on_disk().obsolete, live.tables[0].bytes + log);
+        lsm.enable_file_deletions().unwrap();
+        assert_eq!(lsm.size_on_disk().obsolete, 0);
+
+        //the directory also holds the MANIFEST and friends, which only a refresh finds
+        let usage = lsm.refresh_from_fs().unwrap();
+        assert!(usage.untracked > 0);
+        assert_eq!(usage.total, dir_bytes(&StdEnv, &dir).unwrap());
+        assert_eq!(usage.total, usage.levels.iter().sum::<u64>() + usage.wal + usage.untracked);
+    }
+
+    fn dir_contents(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files = read_dir(dir).unwrap().map(|e| e.unwrap().path()).map(|p| {
+            let bytes = std::fs::read(&p).unwrap();
+            (p, bytes)
+        }).collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn ingesting_a_disjoint_database_copies_its_tables() {
+        let other_dir = temp_dir("ingest_disjoint_other");
+        {
+            let mut config = small_config();
+            config.write_buffer_size = 1 << 20;
+            let other = LsmDb::with_config(other_dir.clone(), config).unwrap();
+            for run in 0..3 {
+                for i in run * 100..run * 100 + 100 {
+                    other.insert(format!("b{:04}", i).as_bytes(), format!("other{}", i).as_bytes()).unwrap();
+                }
+                other.flush().unwrap();
+            }
+            wait_until(|| background_idle(&other));
+            //left in the log
+            other.insert(b"b9999", b"unflushed").unwrap();
+            other.delete(b"b0000").unwrap();
+        }
+        let before = dir_contents(&other_dir);
+        let tables = before.iter().filter(|(path, _)| path.extension() == Some(OsStr::new("sst"))).map(|(_, b)| b.len() as u64).collect::<Vec<_>>();
+
+        let dir = temp_dir("ingest_disjoint");
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        for i in 0..300 {
+            lsm.insert(format!("a{:04}", i).as_bytes(), b"local").unwrap();
+        }
+        let summary = lsm.ingest_db(&other_dir, IngestMode::FailOnConflict).unwrap();
+        assert_eq!((summary.linked_files, summary.linked_bytes), (tables.len(), tables.iter().sum()));
+        assert_eq!(summary.written_entries, 2);
+        assert_eq!(dir_contents(&other_dir), before);
+
+        let check = |lsm: &LsmDb| {
+            assert!((0..300).all(|i| lsm.search(format!("a{:04}", i).as_bytes(), None).unwrap() == Some(b"local".to_vec())));
+            assert!((2..300).all(|i| lsm.search(format!("b{:04}", i).as_bytes(), None).unwrap() == Some(format!("other{}", i).into_bytes())));
+            assert_eq!(lsm.search(b"b0000", None).unwrap(), None);
+            assert_eq!(lsm.search(b"b9999", None).unwrap(), Some(b"unflushed".to_vec()));
+        };
+        check(&lsm);
+        //writes after the ingestion are newer than the ingested tables, also once they are compacted together
+        lsm.insert(b"b0001", b"newer").unwrap();
+        lsm.flush().unwrap();
+        for level in 0..lsm.config.max_levels - 1 {
+            lsm.compact_level(level).unwrap();
+        }
+        assert_eq!(lsm.search(b"b0001", None).unwrap(), Some(b"newer".to_vec()));
+        drop(lsm);
+        let lsm = LsmDb::with_config(dir, small_config()).unwrap();
+        check(&lsm);
+        assert_eq!(lsm.search(b"b0001", None).unwrap(), Some(b"newer".to_vec()));
+    }
+
+    #[test]
+    fn ingesting_an_overlapping_database_writes_its_entries() {
+        let other_dir = temp_dir("ingest_overlap_other");
+        {
+            let other = LsmDb::with_config(other_dir.clone(), small_config()).unwrap();
+            for i in 5..15 {
+                other.insert(format!("k{:02}", i).as_bytes(), b"other").unwrap();
+            }
+            other.flush().unwrap();
+        }
+        let lsm = LsmDb::with_config(temp_dir("ingest_overlap"), small_config()).unwrap();
+        for i in 0..10 {
+            lsm.insert(format!("k{:02}", i).as_bytes(), b"local").unwrap();
+        }
+        let value = |i: usize| lsm.search(format!("k{:02}", i).as_bytes(), None).unwrap();
+
+        assert!(matches!(lsm.ingest_db(&other_dir, IngestMode::FailOnConflict), Err(Error::InvalidArgument(_))));
+        assert_eq!((value(9), value(10)), (Some(b"local".to_vec()), None));
+
+        let summary = lsm.ingest_db(&other_dir, IngestMode::NewestWins).unwrap();
+        assert_eq!(summary, IngestSummary { linked_files: 0, linked_bytes: 0, written_entries: 10 });
+        assert!((0..5).all(|i| value(i) == Some(b"local".to_vec())));
+        assert!((5..15).all(|i| value(i) == Some(b"other".to_vec())));
+    }
+
+    #[test]
+    fn split_divides_the_keys_between_two_databases() {
+        let lsm = LsmDb::with_config(temp_dir("split_source"), small_config()).unwrap();
+        let key = |i: usize| format!("k{:04}", i).into_bytes();
+        for i in 0..600 {
+            lsm.insert(&key(i), format!("v{}", i).as_bytes()).unwrap();
+        }
+        lsm.flush().unwrap();
+        wait_until(|| background_idle(&lsm));
+        lsm.compact_level(0).unwrap();
+        for i in (0..600).step_by(7) {
+            lsm.delete(&key(i)).unwrap();
+        }
+        lsm.insert(&key(1), b"newer").unwrap();
+        lsm.flush().unwrap();
+        wait_until(|| background_idle(&lsm));
+        assert!(lsm.levels.read().num_files_at_level(1) > 0);
+        let expected = |i: usize| match i {
+            1 => Some(b"newer".to_vec()),
+            i if i % 7 == 0 => None,
+            i => Some(format!("v{}", i).into_bytes()),
+        };
+        //a key from the middle of a table of level 1
+        let table = lsm.levels.read().live_tables().into_iter().find(|t| t.level == 1).unwrap();
+        let keys = Table::open(&lsm.config.env, table.path).unwrap().content().unwrap().iter().map(|(k, _)| k.get_user_key().to_vec()).collect::<Vec<_>>();
+        let split_key = keys[keys.len() / 2].clone();
+        //left in the mem table
+        lsm.insert(&key(2), b"unflushed").unwrap();
+
+        let (left_dir, right_dir) = (temp_dir("split_left"), temp_dir("split_right"));
+        let summary = lsm.split(&split_key, &left_dir, &right_dir).unwrap();
+        assert!(summary.split_files >= 1 && summary.copied_files >= 1);
+        let left = LsmDb::with_config(left_dir.clone(), small_config()).unwrap();
+        let right = LsmDb::with_config(right_dir.clone(), small_config()).unwrap();
+        for i in 0..600 {
+            let (left_value, right_value) = (left.search(&key(i), None).unwrap(), right.search(&key(i), None).unwrap());
+            let expected = if i == 2 { Some(b"unflushed".to_vec()) } else { expected(i) };
+            if key(i) < split_key {
+                assert_eq!((left_value, right_value), (expected, None), "k{:04}", i);
+            } else {
+                assert_eq!((left_value, right_value), (None, expected), "k{:04}", i);
+            }
+        }
+        let id = |dir: &Path| Identity::load(&StdEnv, dir).unwrap().unwrap().id;
+        assert_ne!(id(&left_dir), id(&right_dir));
+        assert!(matches!(lsm.split(&split_key, &left_dir, &temp_dir("split_other")), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn health_check_probes_a_working_database() {
+        let env = MemEnv::new();
+        let mut config = mem_env_config(&env);
+        config.write_buffer_size = 1024;
+        let lsm = LsmDb::with_config(PathBuf::from("/mem/health_check"), config).unwrap();
+        for i in 0..200 {
+            lsm.insert(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.compact_level(0).unwrap();
+        let opts = HealthCheckOptions { min_free_bytes: Some(1 << 20), ..HealthCheckOptions::default() };
+
+        let report = lsm.health_check(&opts);
+        assert!(report.is_healthy(), "{:?}", report);
+        let kinds = report.checks.iter().map(|c| c.kind.clone()).collect::<Vec<_>>();
+        assert!(kinds.contains(&HealthCheckKind::WriteProbe));
+        assert!(kinds.contains(&HealthCheckKind::ReadProbe { level: 1 }));
+        assert!(kinds.contains(&HealthCheckKind::BackgroundThread { name: "compaction 0".to_owned() }));
+        assert_eq!(lsm.search(HEALTH_CHECK_KEY, None).unwrap(), None);
+
+        env.set_available_space(Some(1 << 10));
+        let report = lsm.health_check(&opts);
+        assert_eq!(report.failed().map(|c| c.kind.clone()).collect::<Vec<_>>(), vec![HealthCheckKind::FreeSpace]);
+
+        let report = lsm.health_check(&HealthCheckOptions { deadline: Duration::from_secs(0), ..opts });
+        assert!(report.checks.iter().all(|c| !c.passed && c.error.as_deref().unwrap().contains("deadline")));
+    }
+
+    #[test]
+    fn health_check_flags_a_dead_thread_and_a_background_error() {
+        let lsm = LsmDb::with_config(temp_dir("health_check_failures"), small_config()).unwrap();
+        let opts = HealthCheckOptions { min_free_bytes: None, ..HealthCheckOptions::default() };
+        assert!(lsm.health_check(&opts).is_healthy());
+
+        let (heartbeat, handle) = &lsm.workers[1];
+        heartbeat.exit.store(true, Ordering::Release);
+        wait_until(|| handle.is_finished());
+        *lsm.background_error.lock() = Some("injected".to_owned());
+        let report = lsm.health_check(&opts);
+        let failed = report.failed().map(|c| c.kind.clone()).collect::<Vec<_>>();
+        assert_eq!(failed, vec![
+            HealthCheckKind::WriteProbe,
+            HealthCheckKind::BackgroundThread { name: "compaction 0".to_owned() },
+            HealthCheckKind::BackgroundError,
+        ]);
+        //a thread that is still there but quiet for too long is reported too
+        let report = lsm.health_check(&HealthCheckOptions { max_heartbeat_age: Duration::from_secs(0), ..opts });
+        assert!(report.failed().any(|c| c.kind == HealthCheckKind::BackgroundThread { name: "flush 0".to_owned() }));
+    }
+
+    #[test]
+    fn properties_reflect_a_known_workload() {
+        let lsm = LsmDb::with_config(temp_dir("get_property"), Config::new()).unwrap();
+        let property = |name: &str| lsm.get_property(&format!("draftkv.{}", name));
+        let number = |name: &str| property(name).unwrap().parse::<u64>().unwrap();
+        for i in 0..100 {
+            lsm.insert(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+        lsm.flush().unwrap();
+        for i in 0..10 {
+            lsm.delete(format!("key{:03}", i).as_bytes()).unwrap();
+        }
+        for i in 100..105 {
+            lsm.insert(format!("key{:03}", i).as_bytes(), b"value").unwrap();
+        }
+
+        assert_eq!(number("num-files-at-level0"), 1);
+        assert_eq!(number("num-files-at-level1"), 0);
+        assert_eq!(number("size-at-level0"), lsm.stats().levels[0].size_bytes);
+        assert!(number("cur-size-active-mem-table") > 0);
+        assert_eq!(number("num-immutable-mem-table"), 0);
+        assert_eq!(number("estimate-num-keys"), 95);
+        assert_eq!(number("background-errors"), 0);
+        assert_eq!(number("is-write-stalled"), 0);
+
+        let levelstats = property("levelstats").unwrap();
+        let lines = levelstats.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), lsm.levels.read().num_levels() + 1);
+        let level0 = lines[1].split_whitespace().collect::<Vec<_>>();
+        assert_eq!((level0[0], level0[1], level0[5], level0[6]), ("0", "1", "100", "0"));
+        let sstables = property("sstables").unwrap();
+        assert_eq!(sstables.lines().count(), 1);
+        assert!(sstables.starts_with("level 0 #") && sstables.contains(" 100 entries 0 deletions ") && sstables.ends_with("[key000 .. key099]\n"), "{}", sstables);
+
+        *lsm.background_error.lock() = Some("injected".to_owned());
+        assert_eq!(number("background-errors"), 1);
+        for unknown in ["draftkv.num-files-at-levelx", "draftkv.num-files-at-level99", "draftkv.unknown", "num-files-at-level0"].iter() {
+            assert_eq!(lsm.get_property(unknown), None);
+        }
+    }
+
+    #[test]
+    fn quantiles_of_a_skewed_key_set_are_close() {
+        let env = MemEnv::new();
+        let mut config = mem_env_config(&env);
+        config.write_buffer_size = 32 << 10;
+        let lsm = LsmDb::with_config(PathBuf::from("/mem/quantiles"), config).unwrap();
+        //key i is i squared, so the keys crowd together at the start of the key space
+        let key = |i: u64| format!("{:012}", i * i).into_bytes();
+        let n = 20_000;
+        for i in 0..n {
+            lsm.insert(&key(i), b"value").unwrap();
+        }
+        for i in (0..n / 2).step_by(4) {
+            lsm.delete(&key(i)).unwrap();
+        }
+        //the deleted keys are gone once their tombstones reach the bottom, until then they skew the sample
+        lsm.flush().unwrap();
+        wait_until(|| background_idle(&lsm));
+        for level in 0..lsm.config.max_levels - 1 {
+            lsm.compact_level(level).unwrap();
+        }
+        let live = (0..n).filter(|i| i >= &(n / 2) || i % 4 != 0).map(key).collect::<Vec<_>>();
+
+        env.take_read_count();
+        let sample = lsm.sample_keys(5000).unwrap();
+        assert!(env.take_read_count() <= MAX_SAMPLE_BLOCKS as u64);
+        assert!(sample.len() > 1000 && sample.windows(2).all(|w| w[0] < w[1]), "{} keys", sample.len());
+        assert!(sample.iter().all(|k| live.binary_search(k).is_ok()));
+
+        let q = 10;
+        let quantiles = lsm.approximate_quantiles(q).unwrap();
+        assert_eq!(quantiles.len(), q - 1);
+        for (i, k) in quantiles.iter().enumerate() {
+            let rank = live.partition_point(|l| l < k);
+            let expected = (i + 1) * live.len() / q;
+            assert!((rank as i64 - expected as i64).abs() < live.len() as i64 / 20, "quantile {} at rank {}, not {}", i + 1, rank, expected);
+        }
+        assert!(LsmDb::with_config(temp_dir("quantiles_empty"), small_config()).unwrap().approximate_quantiles(4).unwrap().is_empty());
+    }
+
+    #[test]
+    fn slow_compaction_does_not_block_readers() {
+        let mut config = small_config();
+        config.l0_compaction_threshold = 100;
+        let lsm = LsmDb::with_config(temp_dir("slow_compaction"), config).unwrap();
+        let mut keys = Vec::new();
+        let now = Instant::now();
+        while lsm.levels.read().num_files_at_level(0) == 0 {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            let key = format!("key{:05}", keys.len());
+            lsm.insert(key.as_bytes(), key.as_bytes()).unwrap();
+            keys.push(key);
+        }
+        wait_until(|| background_idle(&lsm));
+        //reading and rewriting the table takes about two seconds
+        let table_size = lsm.stats().levels[0].size_bytes;
+        lsm.set_rate_limit(table_size);
+
+        crossbeam_utils::thread::scope(|s| {
+            let compaction = s.spawn(|_| {
+                let now = Instant::now();
+                lsm.compact_level(0).unwrap();
+                now.elapsed()
+            });
+            wait_until(|| lsm.running_compactions.load(Ordering::Acquire) > 0);
+            let mut max_latency = Duration::default();
+            while lsm.running_compactions.load(Ordering::Acquire) > 0 {
+                for key in keys.iter().step_by(10) {
+                    let now = Instant::now();
+                    assert_eq!(lsm.search(key.as_bytes(), None).unwrap(), Some(key.as_bytes().to_vec()));
+                    max_latency = max_latency.max(now.elapsed());
+                }
+                //installing a flush takes the write lock
+                let now = Instant::now();
+                drop(lsm.levels.write());
+                max_latency = max_latency.max(now.elapsed());
+            }
+            let compaction_time = compaction.join().unwrap();
+            assert!(compaction_time > Duration::from_secs(1));
+            assert!(max_latency < Duration::from_millis(200), "blocked for {:?}", max_latency);
+        }).unwrap();
+        assert_eq!(lsm.levels.read().num_files_at_level(1), 1);
+    }
+
+    #[test]
+    fn flush_is_not_held_up_by_a_compaction() {
+        let env = MemEnv::new();
+        let mut config = mem_env_config(&env);
+        config.l0_compaction_threshold = 100;
+        config.write_buffer_size = 128 * 1024;
+        config.readahead_size = 0;
+        let lsm = LsmDb::with_config(PathBuf::from("/mem/slow_compaction"), config).unwrap();
+        for i in 0..2000 {
+            let key = format!("key{:05}", i);
+            lsm.insert(key.as_bytes(), &[b'v'; 50]).unwrap();
+        }
+        lsm.flush().unwrap();
+        //every block of the table costs the compaction 10ms
+        env.set_read_delay(Duration::from_millis(10));
+
+        crossbeam_utils::thread::scope(|s| {
+            let compaction = s.spawn(|_| lsm.compact_level(0).unwrap());
+            wait_until(|| lsm.running_compactions.load(Ordering::Acquire) > 0);
+            lsm.insert(b"during", b"compaction").unwrap();
+            let now = Instant::now();
+            lsm.flush().unwrap();
+            let flush_time = now.elapsed();
+            assert!(lsm.running_compactions.load(Ordering::Acquire) > 0, "flush waited {:?} for the compaction", flush_time);
+            compaction.join().unwrap();
+        }).unwrap();
+        env.set_read_delay(Duration::default());
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 1);
+        assert_eq!(lsm.search(b"during", None).unwrap(), Some(b"compaction".to_vec()));
+        assert_eq!(lsm.search(b"key01999", None).unwrap(), Some(vec![b'v'; 50]));
+    }
+
+    #[test]
+    fn intra_level0_compaction_reduces_tables_probed() {
+        let mut config = small_config();
+        config.l0_intra_compaction_threshold = 4;
+        let lsm = LsmDb::with_config(temp_dir("intra_level0"), config).unwrap();
+        let keys = (0..200).map(|i| format!("key{:03}", i)).collect::<Vec<_>>();
+        let version = |k: &str, seq_num: u64| (
+            LookUpKey::new(InternalKey::new(k.as_bytes(), seq_num, ValueType::Put)),
+            format!("{}-{}", k, seq_num).into_bytes(),
+        );
+        //a big level 1 table under four small level 0 tables overwriting a few keys each
+        {
+            let mut levels = lsm.levels.write();
+            let mut tables = vec![levels.write_file(keys.iter().map(|k| version(k, 1)), 1).unwrap()];
+            for seq_num in 2..6 {
+                let overwrites = keys.iter().step_by(50).map(|k| version(k, seq_num)).collect::<Vec<_>>();
+                tables.push(levels.write_file(overwrites.into_iter(), 0).unwrap());
+            }
+            levels.update(Vec::new(), tables).unwrap();
+        }
+        let probes_per_get = || {
+            let before = lsm.stats().tables_probed;
+            for (i, key) in keys.iter().enumerate() {
+                let seq_num = if i % 50 == 0 { 5 } else { 1 };
+                assert_eq!(lsm.search(key.as_bytes(), Some(10)).unwrap(), Some(format!("{}-{}", key, seq_num).into_bytes()));
+            }
+            (lsm.stats().tables_probed - before) as f64 / keys.len() as f64
+        };
+        let before = probes_per_get();
+
+        let l1_tables = lsm.stats().levels[1].num_files;
+        let (deleted_tables, new_tables) = lsm.levels.read().background_compaction(None).unwrap();
+        assert!(deleted_tables.iter().all(|(level, _)| *level == 0));
+        lsm.levels.write().update(deleted_tables, new_tables).unwrap();
+        assert_eq!(lsm.stats().levels[0].num_files, 1);
+        assert_eq!(lsm.stats().levels[1].num_files, l1_tables);
+        assert!(probes_per_get() < before);
+    }
+
+    #[test]
+    fn table_missed_by_many_reads_is_compacted() {
+        let lsm = LsmDb::with_config(temp_dir("seek_compaction"), small_config()).unwrap();
+        let version = |k: &str| (LookUpKey::new(InternalKey::new(k.as_bytes(), 1, ValueType::Put)), k.as_bytes().to_vec());
+        let keys = (0..50).map(|i| format!("key{:03}", i)).collect::<Vec<_>>();
+        //a small level 1 table spanning the keys of a level 2 table, every read goes through both
+        {
+            let mut levels = lsm.levels.write();
+            let l1 = levels.write_file(vec![version("a"), version("z")].into_iter(), 1).unwrap();
+            let l2 = levels.write_file(keys.iter().map(|k| version(k)), 2).unwrap();
+            levels.update(Vec::new(), vec![l1, l2]).unwrap();
+        }
+        assert_eq!(lsm.levels.read().pick_compaction(), None);
+
+        let now = Instant::now();
+        while lsm.levels.read().num_files_at_level(1) > 0 {
+            assert!(now.elapsed() < Duration::from_secs(10), "the table was never compacted");
+            for key in keys.iter() {
+                assert_eq!(lsm.search(key.as_bytes(), Some(10)).unwrap(), Some(key.as_bytes().to_vec()));
+            }
+        }
+        wait_until(|| background_idle(&lsm));
+        assert_eq!(lsm.search(b"a", Some(10)).unwrap(), Some(b"a".to_vec()));
+        for key in keys.iter() {
+            assert_eq!(lsm.search(key.as_bytes(), Some(10)).unwrap(), Some(key.as_bytes().to_vec()));
+        }
+    }
+
+    struct ExpireFilter;
+
+    impl CompactionFilter for ExpireFilter {
+        fn filter(&self, _level: usize, key: &[u8], value: &[u8]) -> FilterDecision {
+            if key == b"boom" {
+                panic!("filter bug");
+            }
+            if value.starts_with(b"expired:") {
+                FilterDecision::Remove
+            } else if value.starts_with(b"old:") {
+                FilterDecision::Change(value[4..].to_vec())
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn compaction_filter_drops_and_rewrites_entries() {
+        let mut config = small_config();
+        config.l0_compaction_threshold = 100;
+        config.compaction_filter = Some(Arc::new(ExpireFilter));
+        let lsm = LsmDb::with_config(temp_dir("compaction_filter"), config).unwrap();
+        lsm.insert(b"boom", b"expired:but the filter panics").unwrap();
+        let mut keys = Vec::new();
+        let now = Instant::now();
+        while lsm.levels.read().num_files_at_level(0) == 0 {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            let key = format!("key{:05}", keys.len());
+            let value = match keys.len() % 3 {
+                0 => format!("expired:{}", key),
+                1 => format!("old:{}", key),
+                _ => key.clone(),
+            };
+            lsm.insert(key.as_bytes(), value.as_bytes()).unwrap();
+            keys.push(key);
+        }
+        //every key goes to level 0, and no flush is left running alongside the compaction
+        lsm.flush().unwrap();
+        wait_until(|| background_idle(&lsm));
+        lsm.insert(b"recent", b"expired:still in the mem table").unwrap();
+        assert_eq!(lsm.search(keys[0].as_bytes(), None).unwrap(), Some(format!("expired:{}", keys[0]).into_bytes()));
+
+        assert_eq!(lsm.compact_level(0).unwrap().output_files, 1);
+        for (i, key) in keys.iter().enumerate() {
+            let res = lsm.search(key.as_bytes(), None).unwrap();
+            match i % 3 {
+                0 => assert_eq!(res, None),
+                _ => assert_eq!(res, Some(key.as_bytes().to_vec())),
+            }
+        }
+        assert_eq!(lsm.search(b"boom", None).unwrap(), Some(b"expired:but the filter panics".to_vec()));
+        assert_eq!(lsm.search(b"recent", None).unwrap(), Some(b"expired:still in the mem table".to_vec()));
+    }
+
+    #[test]
+    fn unfinished_files_are_removed_on_open() {
+        let dir = temp_dir("unfinished_files");
+        let tmp_table = dir.join("7.sst.tmp");
+        std::fs::write(&tmp_table, b"half a table").unwrap();
+        let lsm = LsmDb::with_config(dir, small_config()).unwrap();
+        assert!(!tmp_table.exists());
+        assert_eq!(lsm.stats().levels[0].num_files, 0);
+    }
+
+    #[test]
+    fn table_build_interrupted_midway_leaves_only_a_tmp_file() {
+        let dir = temp_dir("interrupted_build");
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        lsm.insert(b"kept", b"value").unwrap();
+        //the entries run out halfway through the table, after several blocks were written
+        let entries = (0..1000).map(|i| {
+            assert!(i < 500, "crash");
+            let key = format!("key{:05}", i);
+            (LookUpKey::new(InternalKey::new(key.as_bytes(), 1, ValueType::Put)), key.into_bytes())
+        });
+        let res = panic::catch_unwind(AssertUnwindSafe(|| lsm.levels.read().write_file(entries, 1)));
+        assert!(res.is_err());
+        let files = read_dir(&dir).unwrap().map(|x| x.unwrap().path()).collect::<Vec<_>>();
+        let tmp_files = files.iter().filter(|x| x.to_string_lossy().ends_with(".sst.tmp")).collect::<Vec<_>>();
+        assert_eq!(tmp_files.len(), 1);
+        assert!(std::fs::metadata(tmp_files[0]).unwrap().len() > 0);
+        assert!(files.iter().all(|x| x.extension() != Some(OsStr::new("sst"))));
+
+        drop(lsm);
+        let lsm = LsmDb::with_config(dir, small_config()).unwrap();
+        assert!(!tmp_files[0].exists());
+        assert_eq!(lsm.stats().levels[1].num_files, 0);
+        assert_eq!(lsm.search(b"kept", None).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn repair_salvages_a_damaged_database() {
+        let dir = temp_dir("repair");
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        let keys = |prefix: &str| (0..50).map(|i| format!("{}{:03}", prefix, i)).collect::<Vec<_>>();
+        let versions = |prefix: &str, seq_num| keys(prefix).into_iter()
+            .map(move |k| (LookUpKey::new(InternalKey::new(k.as_bytes(), seq_num, ValueType::Put)), format!("{}@{}", k, seq_num)))
+            .collect::<Vec<_>>();
+        {
+            let mut levels = lsm.levels.write();
+            let newer = levels.write_file(versions("a", 3).into_iter(), 1).unwrap();
+            let older = levels.write_file(versions("a", 1).into_iter(), 2).unwrap();
+            let damaged = levels.write_file(versions("b", 2).into_iter(), 2).unwrap();
+            damaged.corrupt_index();
+            levels.update(Vec::new(), vec![newer, older, damaged]).unwrap();
+        }
+        lsm.next_seq_num.store(10, Ordering::SeqCst);
+        lsm.insert(b"logged", b"value").unwrap();
+        drop(lsm);
+        std::fs::write(dir.join("MANIFEST"), b"garbage").unwrap();
+        assert!(LsmDb::with_config(dir.clone(), small_config()).is_err());
+
+        let report = LsmDb::repair(dir.clone()).unwrap();
+        assert_eq!(report.recovered_files.len(), 4);
+        assert_eq!(report.salvaged_entries, 50 + 1);
+        assert_eq!(report.quarantined_files.len(), 1);
+        let lost = dir.join(repair::LOST_DIR).join(report.quarantined_files[0].file_name().unwrap());
+        assert!(lost.exists());
+        assert!(!report.quarantined_files[0].exists());
+
+        let lsm = LsmDb::with_config(dir, small_config()).unwrap();
+        //the two versions of the a keys overlap, the newer one wins in level 0
+        for (prefix, seq_num) in [("a", 3), ("b", 2)].iter() {
+            for key in keys(prefix) {
+                assert_eq!(lsm.search(key.as_bytes(), None).unwrap(), Some(format!("{}@{}", key, seq_num).into_bytes()));
+            }
+        }
+        assert_eq!(lsm.search(b"logged", None).unwrap(), Some(b"value".to_vec()));
+        lsm.insert(b"after", b"repair").unwrap();
+        assert_eq!(lsm.search(b"after", None).unwrap(), Some(b"repair".to_vec()));
+    }
+
+    #[test]
+    fn every_log_is_replayed_on_open() {
+        let dir = temp_dir("every_log");
+        let mut logs = (1..=3).map(|log_num| {
+            let mut mem_table = MemTable::new();
+            mem_table.set_writer(&test_env(), &dir, log_num).unwrap();
+            mem_table
+        }).collect::<Vec<_>>();
+        logs[0].insert(b"first", b"1", 1, false).unwrap();
+        //a transaction that commits in a later log
+        logs[0].begin_tx(2).unwrap();
+        logs[0].insert(b"tx", b"2", 2, true).unwrap();
+        logs[1].commit_tx(2).unwrap();
+        logs[1].insert(b"second", b"3", 3, false).unwrap();
+        logs[2].insert(b"third", b"4", 4, false).unwrap();
+        drop(logs);
+        //newer than the others, but empty
+        std::fs::write(dir.join("4.LOG"), b"").unwrap();
+
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        assert!(!dir.join("4.LOG").exists());
+        //the oldest log did not fit in the mem tables and went straight into a table
+        assert!(!dir.join("1.LOG").exists());
+        wait_until(|| lsm.im_mem_table.read().is_none());
+        assert!(!dir.join("2.LOG").exists());
+        for (key, value) in [("first", "1"), ("tx", "2"), ("second", "3"), ("third", "4")].iter() {
+            assert_eq!(lsm.search(key.as_bytes(), None).unwrap(), Some(value.as_bytes().to_vec()), "{}", key);
+        }
+        lsm.insert(b"fifth", b"5").unwrap();
+        drop(lsm);
+        let lsm = LsmDb::with_config(dir, small_config()).unwrap();
+        assert_eq!(lsm.search(b"fifth", None).unwrap(), Some(b"5".to_vec()));
+        assert_eq!(lsm.search(b"first", None).unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn foreign_files_are_ignored_or_rejected() {
+        let dir = temp_dir("foreign_files");
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        lsm.insert(b"key", b"value").unwrap();
+        drop(lsm);
+        let foreign = ["backup.sst", "draft.sst.tmp", "empty.sst", "notes.LOG"];
+        for name in foreign.iter() {
+            let content: &[u8] = if name.starts_with("empty") { b"" } else { b"junk" };
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        std::fs::write(dir.join("README"), b"not a table").unwrap();
+        create_dir_all(dir.join("12.sst")).unwrap();
+        create_dir_all(dir.join("lost")).unwrap();
+
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        assert_eq!(lsm.search(b"key", None).unwrap(), Some(b"value".to_vec()));
+        let mut found = lsm.foreign_files().to_vec();
+        found.sort();
+        assert_eq!(found, foreign.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+        drop(lsm);
+        //nothing was touched
+        assert!(foreign.iter().all(|name| dir.join(name).exists()));
+        assert!(dir.join("12.sst").is_dir());
+
+        let mut config = small_config();
+        config.strict_file_names = true;
+        match LsmDb::with_config(dir.clone(), config) {
+            Err(Error::InvalidArgument(msg)) => assert!(foreign.iter().any(|name| msg.contains(name)), "{}", msg),
+            res => panic!("expected an error, got {:?}", res.map(|_| ())),
+        }
+        for name in foreign.iter() {
+            std::fs::remove_file(dir.join(name)).unwrap();
+        }
+        let mut config = small_config();
+        config.strict_file_names = true;
+        let lsm = LsmDb::with_config(dir, config).unwrap();
+        assert_eq!(lsm.search(b"key", None).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn directory_is_locked_while_open() {
+        let dir = temp_dir("lock");
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        lsm.insert(b"key", b"value").unwrap();
+        match LsmDb::with_config(dir.clone(), small_config()) {
+            Err(Error::DbLocked { pid }) => assert_eq!(pid, Some(std::process::id())),
+            res => panic!("expected the lock to be held, got {:?}", res.map(|_| ())),
+        }
+        assert!(matches!(LsmDb::repair(dir.clone()), Err(Error::DbLocked { .. })));
+        drop(lsm);
+        let lsm = LsmDb::with_config(dir, small_config()).unwrap();
+        assert_eq!(lsm.search(b"key", None).unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn directory_that_can_not_be_created_is_reported() {
+        let dir = temp_dir("uncreatable");
+        std::fs::write(dir.join("file"), b"").unwrap();
+        assert!(matches!(LsmDb::with_config(dir.join("file").join("db"), small_config()), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn increment_keeps_decimal_integers() {
+        let env = MemEnv::new();
+        let lsm = LsmDb::with_config(PathBuf::from("/mem/increment"), mem_env_config(&env)).unwrap();
+        assert_eq!(lsm.increment(b"n", 5).unwrap(), 5);
+        assert_eq!(lsm.increment(b"n", -7).unwrap(), -2);
+        assert_eq!(lsm.search(b"n", None).unwrap(), Some(b"-2".to_vec()));
+        lsm.insert(b"max", i64::MAX.to_string().as_bytes()).unwrap();
+        assert!(matches!(lsm.increment(b"max", 1), Err(Error::InvalidArgument(_))));
+        for bad in [&b"abc"[..], b"", b"+1", b" 1", b"1.5", b"-"] {
+            lsm.insert(b"bad", bad).unwrap();
+            assert!(matches!(lsm.increment(b"bad", 1), Err(Error::InvalidArgument(_))), "{:?} incremented", bad);
+        }
+        assert_eq!(lsm.search(b"max", None).unwrap(), Some(i64::MAX.to_string().into_bytes()));
+    }
+
+    #[test]
+    fn read_only_open_changes_no_files() {
+        let env = MemEnv::new();
+        let dir = PathBuf::from("/mem/read_only");
+        assert!(matches!(LsmDb::open_read_only(dir.clone(), mem_env_config(&env)), Err(Error::InvalidArgument(_))));
+        let lsm = LsmDb::with_config(dir.clone(), mem_env_config(&env)).unwrap();
+        for i in 0..100u32 {
+            lsm.insert(format!("key{:03}", i).as_bytes(), &[b'v'; 20]).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.insert(b"logged", b"1").unwrap();
+        lsm.delete(b"key000").unwrap();
+        //a leftover the next writable open removes
+        env.create(&dir.join("99.sst.tmp")).unwrap();
+        let mut files = env.list_dir(&dir).unwrap();
+        files.sort();
+
+        let reader = LsmDb::open_read_only(dir.clone(), mem_env_config(&env)).unwrap();
+        assert_eq!(reader.search(b"logged", None).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(reader.search(b"key000", None).unwrap(), None);
+        assert_eq!(reader.scan_prefix(b"key").unwrap().len(), 99);
+        assert_eq!(reader.verify().unwrap(), lsm.stats().levels.iter().map(|l| l.num_files).sum::<usize>());
+        assert!(matches!(reader.insert(b"k", b"v"), Err(Error::ReadOnly)));
+        assert!(matches!(reader.delete(b"k"), Err(Error::ReadOnly)));
+        assert!(matches!(reader.flush(), Err(Error::ReadOnly)));
+        assert!(matches!(reader.compact_level(0), Err(Error::ReadOnly)));
+        let (tx_id, seq_num) = reader.tx_begin();
+        reader.tx_insert(tx_id, seq_num, b"k", b"v").unwrap();
+        assert!(matches!(reader.tx_commit(tx_id), Err(Error::ReadOnly)));
+        drop(reader);
+        let mut after = env.list_dir(&dir).unwrap();
+        after.sort();
+        assert_eq!(after, files);
+    }
+
+    #[test]
+    fn read_only_open_while_the_owner_flushes_and_compacts() {
+        let dir = temp_dir("read_only_while_writing");
+        let lsm = Arc::new(LsmDb::with_config(dir.clone(), small_config()).unwrap());
+        lsm.insert(b"first", b"1").unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (lsm, stop) = (lsm.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut i = 0;
+                while !stop.load(Ordering::Acquire) {
+                    lsm.insert(format!("key{:06}", i).as_bytes(), &[b'v'; 100]).unwrap();
+                    if i % 50 == 0 {
+                        lsm.flush().unwrap();
+                    }
+                    i += 1;
+                }
+            })
+        };
+        //logs and tables the owner removes between the listing and reading them are not an error
+        for _ in 0..20 {
+            let reader = LsmDb::open_read_only(dir.clone(), small_config()).unwrap();
+            assert_eq!(reader.search(b"first", None).unwrap(), Some(b"1".to_vec()));
+        }
+        stop.store(true, Ordering::Release);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn database_identity_persists() {
+        let dir = temp_dir("db_identity");
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        let id = lsm.db_id().to_owned();
+        assert_eq!(id.len(), 36);
+        drop(lsm);
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        assert_eq!(lsm.db_id(), id);
+        drop(lsm);
+        //a database from before identities gets one, a damaged one is reported
+        std::fs::remove_file(Identity::file_name(&dir)).unwrap();
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        assert_ne!(lsm.db_id(), id);
+        drop(lsm);
+        std::fs::write(Identity::file_name(&dir), b"garbage").unwrap();
+        assert!(matches!(LsmDb::with_config(dir, small_config()), Err(Error::Corruption(_))));
+        assert_ne!(LsmDb::with_config(temp_dir("db_identity"), small_config()).unwrap().db_id(), id);
+    }
+
+    #[test]
+    fn options_are_persisted_and_checked_on_reopen() {
+        let dir = temp_dir("persisted_options");
+        let mut config = small_config();
+        config.user_timestamp_size = 8;
+        let lsm = LsmDb::with_config(dir.clone(), config).unwrap();
+        assert_eq!(lsm.persisted_options().get("user_timestamp_size").map(|s| s.as_str()), Some("8"));
+        drop(lsm);
+        //a tunable may change
+        let mut config = small_config();
+        config.user_timestamp_size = 8;
+        config.write_buffer_size = 4096;
+        let lsm = LsmDb::with_config(dir.clone(), config).unwrap();
+        assert_eq!(lsm.persisted_options().get("write_buffer_size").map(|s| s.as_str()), Some("4096"));
+        drop(lsm);
+        assert_eq!(options::load_latest(&StdEnv, &dir).unwrap().unwrap().0, 2);
+        assert!(!dir.join("OPTIONS-1").exists());
+        match LsmDb::with_config(dir, small_config()) {
+            Err(Error::InvalidArgument(msg)) => assert!(msg.contains("user_timestamp_size 8, but 0"), "{}", msg),
+            res => panic!("expected an error, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        Config::new().validate().unwrap();
+        small_config().validate().unwrap();
+        type Rule = (&'static str, fn(&mut Config));
+        let rules: Vec<Rule> = vec![
+            ("max_levels is 0, it must be in 1..=16", |c| c.max_levels = 0),
+            ("max_levels is 17", |c| c.max_levels = 17),
+            ("block_size is 512, it must be at least 1024", |c| c.block_size = 512),
+            ("write_buffer_size is 2048, it must be at least the block_size of 4096", |c| c.write_buffer_size = 2048),
+            ("l0_compaction_threshold is 0", |c| c.l0_compaction_threshold = 0),
+            ("level0_slowdown_writes_trigger is 36, it must be below the level0_stop_writes_trigger of 36",
+                |c| c.level0_slowdown_writes_trigger = 36),
+            ("l1_max_bytes is 1024, it must be at least the write_buffer_size", |c| c.l1_max_bytes = 1024),
+            ("level_size_multiplier is 1", |c| c.level_size_multiplier = 1),
+            ("target_file_size_base is 100", |c| c.target_file_size_base = 100),
+            ("target_file_size_multiplier is 0", |c| c.target_file_size_multiplier = 0),
+            ("max_subcompactions is 0", |c| c.max_subcompactions = 0),
+            ("tombstone_compaction_ratio is 1.5, it must be in 0.0..=1.0", |c| c.tombstone_compaction_ratio = 1.5),
+            ("bloom_bits_per_key_per_level is -1, it must be in 0.0..=64.0", |c| c.bloom_bits_per_key_per_level = vec![10.0, -1.0]),
+            ("value_log_gc_ratio is 2, it must be in 0.0..=1.0", |c| c.value_log_gc_ratio = 2.0),
+        ];
+        for (expected, break_rule) in rules {
+            let mut config = Config::new();
+            break_rule(&mut config);
+            match config.validate() {
+                Err(Error::InvalidArgument(msg)) => assert!(msg.starts_with(expected), "{}", msg),
+                res => panic!("{}: expected an error, got {:?}", expected, res),
+            }
+        }
+        let mut config = Config::new();
+        config.max_levels = 0;
+        let dir = temp_dir("invalid_config");
+        assert!(matches!(LsmDb::with_config(dir.clone(), config), Err(Error::InvalidArgument(_))));
+        //nothing was created
+        assert_eq!(read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn failed_flush_is_reported_and_retried() {
+        let mut config = small_config();
+        config.l0_compaction_threshold = 100;
+        config.max_background_retries = 1;
+        let lsm = LsmDb::with_config(temp_dir("failed_flush"), config).unwrap();
+        //the first attempt and its only retry both fail
+        lsm.levels.read().failed_writes.store(2, Ordering::SeqCst);
+        let mut keys = Vec::new();
+        let now = Instant::now();
+        while lsm.background_error().is_none() {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            let key = format!("key{:05}", keys.len());
+            if lsm.insert(key.as_bytes(), key.as_bytes()).is_ok() {
+                keys.push(key);
+            }
+        }
+        wait_until(|| background_idle(&lsm));
+        assert!(matches!(lsm.insert(b"rejected", b"value"), Err(Error::Background(_))));
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 0);
+        //nothing was lost, the immutable mem table is still there
+        assert!(lsm.im_mem_table.read().is_some());
+        for key in keys.iter() {
+            assert_eq!(lsm.search(key.as_bytes(), None).unwrap(), Some(key.as_bytes().to_vec()));
+        }
+
+        lsm.resume();
+        wait_until(|| lsm.im_mem_table.read().is_none());
+        assert!(lsm.background_error().is_none());
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 1);
+        lsm.insert(b"accepted", b"value").unwrap();
+        for key in keys.iter() {
+            assert_eq!(lsm.search(key.as_bytes(), None).unwrap(), Some(key.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn panic_while_holding_a_lock_does_not_brick_the_database() {
+        let lsm = LsmDb::with_config(temp_dir("lock_panic"), small_config()).unwrap();
+        lsm.insert(b"before", b"1").unwrap();
+        let panics: Vec<Box<dyn Fn()>> = vec![
+            Box::new(|| { let _guard = lsm.mem_table.write(); panic!("injected"); }),
+            Box::new(|| { let _guard = lsm.im_mem_table.write(); panic!("injected"); }),
+            Box::new(|| { let _guard = lsm.levels.write(); panic!("injected"); }),
+            Box::new(|| { let _guard = lsm.update_lock.lock(); panic!("injected"); }),
+            Box::new(|| { let _guard = lsm.tx_cache_table.write(); panic!("injected"); }),
+            Box::new(|| { let _guard = lsm.background_error.lock(); panic!("injected"); }),
+        ];
+        for f in panics {
+            assert!(panic::catch_unwind(AssertUnwindSafe(f)).is_err());
+        }
+        //the guards were released while unwinding, everything else goes on as before
+        assert_eq!(lsm.search(b"before", None).unwrap(), Some(b"1".to_vec()));
+        lsm.insert(b"after", b"2").unwrap();
+        lsm.flush().unwrap();
+        assert_eq!(lsm.search(b"after", None).unwrap(), Some(b"2".to_vec()));
+        assert!(lsm.background_error().is_none());
+    }
+
+    #[test]
+    fn user_timestamps_across_mem_table_and_tables() {
+        let mut config = small_config();
+        config.l0_compaction_threshold = 100;
+        config.user_timestamp_size = 8;
+        let lsm = LsmDb::with_config(temp_dir("user_timestamps"), config).unwrap();
+        let ts = |t: u64| t.to_be_bytes();
+        lsm.insert_ts(b"k", &ts(10), b"v10").unwrap();
+        lsm.insert_ts(b"k", &ts(30), b"v30").unwrap();
+        let now = Instant::now();
+        let mut i = 0;
+        while lsm.levels.read().num_files_at_level(0) == 0 {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            lsm.insert_ts(format!("filler{:05}", i).as_bytes(), &ts(1), b"filler").unwrap();
+            i += 1;
+        }
+        wait_until(|| background_idle(&lsm));
+        //written last, but older by timestamp than the version already in a table
+        lsm.insert_ts(b"k", &ts(20), b"v20").unwrap();
+        lsm.delete_ts(b"k", &ts(40)).unwrap();
+        lsm.insert_ts(b"k\x00", &ts(50), b"other key").unwrap();
+
+        let check = |lsm: &LsmDb| {
+            let expected: &[(u64, Option<&[u8]>)] = &[
+                (5, None), (10, Some(b"v10")), (15, Some(b"v10")), (20, Some(b"v20")),
+                (29, Some(b"v20")), (30, Some(b"v30")), (39, Some(b"v30")), (40, None), (u64::MAX, None),
+            ];
+            for (t, value) in expected {
+                assert_eq!(lsm.search_at_ts(b"k", &ts(*t)).unwrap(), value.map(|v| v.to_vec()), "at {}", t);
+            }
+            assert_eq!(lsm.search_at_ts(b"filler00000", &ts(1)).unwrap(), Some(b"filler".to_vec()));
+            assert_eq!(lsm.search_at_ts(b"filler00000", &ts(0)).unwrap(), None);
+        };
+        check(&lsm);
+        lsm.compact_level(0).unwrap();
+        check(&lsm);
+
+        assert!(matches!(lsm.insert(b"k", b"v"), Err(Error::InvalidArgument(_))));
+        assert!(matches!(lsm.insert_ts(b"k", &[0; 4], b"v"), Err(Error::InvalidArgument(_))));
+        assert!(matches!(lsm.search_at_ts(b"k", &[0; 9]), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn empty_keys_are_rejected() {
+        let lsm = LsmDb::with_config(temp_dir("empty_keys"), small_config()).unwrap();
+        assert!(matches!(lsm.insert(b"", b"v"), Err(Error::InvalidArgument(_))));
+        let (tx_id, seq_num) = lsm.tx_begin();
+        assert!(matches!(lsm.tx_insert(tx_id, seq_num, b"", b"v"), Err(Error::InvalidArgument(_))));
+        lsm.tx_abort(tx_id);
+        assert_eq!(lsm.search(b"", None).unwrap(), None);
+    }
+
+    #[test]
+    fn sequence_numbers_continue_after_the_tables_on_reopen() {
+        let dir = temp_dir("seq_from_tables");
+        let mut config = small_config();
+        config.l0_compaction_threshold = 100;
+        let lsm = LsmDb::with_config(dir.clone(), config).unwrap();
+        let now = Instant::now();
+        let mut i = 0;
+        while lsm.levels.read().num_files_at_level(0) == 0 {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            lsm.insert(format!("key{:05}", i).as_bytes(), b"old").unwrap();
+            i += 1;
+        }
+        wait_until(|| background_idle(&lsm));
+        let last_seq_num = lsm.levels.read().last_seq_num();
+        assert!(last_seq_num > 1);
+        drop(lsm);
+        //only the tables are left
+        for file in std::fs::read_dir(&dir).unwrap() {
+            let path = file.unwrap().path();
+            if path.extension() == Some(OsStr::new("LOG")) {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+
+        let mut config = small_config();
+        config.l0_compaction_threshold = 100;
+        let lsm = LsmDb::with_config(dir, config).unwrap();
+        assert_eq!(lsm.stats().last_seq_num, last_seq_num);
+        lsm.insert(b"key00000", b"new").unwrap();
+        assert_eq!(lsm.search(b"key00000", None).unwrap(), Some(b"new".to_vec()));
+        //the overwrite also wins once it is flushed next to the old version, and when they are merged
+        let mut i = 0;
+        while lsm.levels.read().num_files_at_level(0) < 2 {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for background work");
+            lsm.insert(format!("filler{:05}", i).as_bytes(), b"filler").unwrap();
+            i += 1;
+        }
+        wait_until(|| background_idle(&lsm));
+        assert_eq!(lsm.search(b"key00000", None).unwrap(), Some(b"new".to_vec()));
+        lsm.compact_level(0).unwrap();
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 0);
+        assert_eq!(lsm.search(b"key00000", None).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(lsm.search(b"key00001", None).unwrap(), Some(b"old".to_vec()));
+    }
+
+    #[test]
+    fn writes_fail_once_sequence_numbers_run_out() {
+        let lsm = LsmDb::with_config(temp_dir("seq_exhausted"), small_config()).unwrap();
+        lsm.insert(b"a", b"1").unwrap();
+        lsm.next_seq_num.store(SEQ_NUM_LIMIT, Ordering::SeqCst);
+        lsm.insert(b"b", b"2").unwrap();
+        assert!(matches!(lsm.insert(b"c", b"3"), Err(Error::SequenceExhausted)));
+        assert!(matches!(lsm.delete(b"a"), Err(Error::SequenceExhausted)));
+        let (tx_id, seq_num) = lsm.tx_begin();
+        lsm.tx_insert(tx_id, seq_num, b"d", b"4").unwrap();
+        assert!(matches!(lsm.tx_commit(tx_id), Err(Error::SequenceExhausted)));
+        //still readable, and the limit is reported
+        assert_eq!(lsm.search(b"a", None).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(lsm.search(b"b", None).unwrap(), Some(b"2".to_vec()));
+        assert_eq!(lsm.search(b"c", None).unwrap(), None);
+        let stats = lsm.stats();
+        assert_eq!((stats.last_seq_num, stats.seq_num_limit), (SEQ_NUM_LIMIT, SEQ_NUM_LIMIT));
+    }
+
+    #[test]
+    fn failed_commit_can_be_retried_or_aborted() {
+        let lsm = LsmDb::with_config(temp_dir("commit_retry"), small_config()).unwrap();
+        let (tx_id, seq_num) = lsm.tx_begin();
+        lsm.tx_insert(tx_id, seq_num, b"a", b"1").unwrap();
+        lsm.tx_insert(tx_id, seq_num, b"b", b"2").unwrap();
+        let buffered = lsm.tx_buffer_bytes.load(Ordering::Relaxed);
+        lsm.next_seq_num.store(SEQ_NUM_LIMIT + 1, Ordering::SeqCst);
+        assert!(matches!(lsm.tx_commit(tx_id), Err(Error::SequenceExhausted)));
+        assert_eq!(lsm.tx_buffer_bytes.load(Ordering::Relaxed), buffered);
+        assert_eq!(lsm.search(b"a", None).unwrap(), None);
+        assert_eq!(lsm.tx_search(tx_id, seq_num, b"a").unwrap(), Some(b"1".to_vec()));
+
+        //the writes are still there for the retry
+        lsm.next_seq_num.store(seq_num + 1, Ordering::SeqCst);
+        lsm.tx_commit(tx_id).unwrap();
+        assert_eq!(lsm.search(b"a", None).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(lsm.search(b"b", None).unwrap(), Some(b"2".to_vec()));
+        assert_eq!(lsm.tx_buffer_bytes.load(Ordering::Relaxed), 0);
+
+        //or for an abort, which frees the write lock for the next transaction
+        let (tx_id, seq_num) = lsm.tx_begin();
+        lsm.tx_insert(tx_id, seq_num, b"c", b"3").unwrap();
+        lsm.next_seq_num.store(SEQ_NUM_LIMIT + 1, Ordering::SeqCst);
+        assert!(matches!(lsm.tx_commit(tx_id), Err(Error::SequenceExhausted)));
+        lsm.tx_abort(tx_id);
+        assert_eq!(lsm.tx_buffer_bytes.load(Ordering::Relaxed), 0);
+        lsm.next_seq_num.store(seq_num + 1, Ordering::SeqCst);
+        let (tx_id, seq_num) = lsm.tx_begin();
+        lsm.tx_insert(tx_id, seq_num, b"d", b"4").unwrap();
+        lsm.tx_commit(tx_id).unwrap();
+        assert_eq!(lsm.search(b"c", None).unwrap(), None);
+        assert_eq!(lsm.search(b"d", None).unwrap(), Some(b"4".to_vec()));
+    }
+
+    struct FakeClock(AtomicU64);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> SystemTime {
+            SystemTime::UNIX_EPOCH + Duration::from_millis(self.0.load(Ordering::SeqCst))
+        }
+    }
+
+    #[test]
+    fn time_travel_reads_by_write_time() {
+        let dir = temp_dir("write_time");
+        let clock = Arc::new(FakeClock(AtomicU64::new(1000)));
+        let config = || {
+            let mut config = small_config();
+            config.l0_compaction_threshold = 100;
+            config.record_write_time = true;
+            config.clock = clock.clone();
+            config
+        };
+        let at = |millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis);
+        let lsm = LsmDb::with_config(dir.clone(), config()).unwrap();
+        assert_eq!(lsm.seq_at_time(at(5000)), None);
+        for (millis, value) in [(1000, "v1"), (2000, "v2"), (3000, "v3")].iter() {
+            clock.0.store(*millis, Ordering::SeqCst);
+            lsm.insert(b"k", value.as_bytes()).unwrap();
+            lsm.insert(b"other", value.as_bytes()).unwrap();
+        }
+        let check = |lsm: &LsmDb| {
+            assert_eq!(lsm.seq_at_time(at(999)), None);
+            for (millis, value) in [(1000, "v1"), (1500, "v1"), (2999, "v2"), (3000, "v3"), (9000, "v3")].iter() {
+                let seq_num = lsm.seq_at_time(at(*millis));
+                assert_eq!(lsm.search(b"k", seq_num).unwrap(), Some(value.as_bytes().to_vec()), "at {}", millis);
+            }
+        };
+        check(&lsm);
+        //recovered from the log
+        drop(lsm);
+        let lsm = LsmDb::with_config(dir.clone(), config()).unwrap();
+        check(&lsm);
+        //carried into the tables by flushes and compactions
+        clock.0.store(4000, Ordering::SeqCst);
+        lsm.insert(b"filler", b"later").unwrap();
+        lsm.flush().unwrap();
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 1);
+        assert!(lsm.mem_table.read().write_times.is_empty());
+        check(&lsm);
+        //compactions keep only the newest version of a key, but the times stay
+        let seq_nums = [999, 1000, 2000, 3000].iter().map(|t| lsm.seq_at_time(at(*t))).collect::<Vec<_>>();
+        lsm.compact_level(0).unwrap();
+        assert_eq!([999, 1000, 2000, 3000].iter().map(|t| lsm.seq_at_time(at(*t))).collect::<Vec<_>>(), seq_nums);
+        //and are read back from the tables
+        drop(lsm);
+        let lsm = LsmDb::with_config(dir, config()).unwrap();
+        assert_eq!([999, 1000, 2000, 3000].iter().map(|t| lsm.seq_at_time(at(*t))).collect::<Vec<_>>(), seq_nums);
+        assert_eq!(lsm.search(b"filler", lsm.seq_at_time(at(3999))).unwrap(), None);
+        assert_eq!(lsm.search(b"filler", lsm.seq_at_time(at(4000))).unwrap(), Some(b"later".to_vec()));
+    }
+
+    struct RenamedPrefix(FixedPrefix);
+
+    impl PrefixExtractor for RenamedPrefix {
+        fn name(&self) -> &str {
+            "renamed"
+        }
+
+        fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+            self.0.prefix(key)
+        }
+    }
+
+    #[test]
+    fn prefix_scans_skip_tables_without_the_prefix() {
+        let dir = temp_dir("prefix_scan");
+        let config = |extractor: Arc<dyn PrefixExtractor>| {
+            let mut config = small_config();
+            config.prefix_extractor = Some(extractor);
+            config
+        };
+        let key = |tenant: u64, suffix: &str| [&tenant.to_be_bytes()[..], suffix.as_bytes()].concat();
+        let version = |tenant: u64, suffix: &str| {
+            let key = key(tenant, suffix);
+            (LookUpKey::new(InternalKey::new(&key, 1, ValueType::Put)), key)
+        };
+        let lsm = LsmDb::with_config(dir.clone(), config(Arc::new(FixedPrefix::new(8)))).unwrap();
+        lsm.next_seq_num.store(10, Ordering::SeqCst);
+        //tenant 5 falls within the range of both tables, but is in neither
+        {
+            let mut levels = lsm.levels.write();
+            let l1 = vec![version(1, "a"), version(1, "b"), version(9, "a")];
+            let l1 = levels.write_file(l1.into_iter(), 1).unwrap();
+            let l2 = (0..20).map(|i| version(2, &format!("{:02}", i))).chain(vec![version(8, "a")]);
+            let l2 = levels.write_file(l2, 2).unwrap();
+            levels.update(Vec::new(), vec![l1, l2]).unwrap();
+        }
+        lsm.insert(&key(2, "03"), b"new").unwrap();
+        lsm.delete(&key(2, "04")).unwrap();
+        lsm.insert(&key(3, "a"), b"other tenant").unwrap();
+
+        let blocks_read = |lsm: &LsmDb| lsm.stats().blocks_read;
+        let before = blocks_read(&lsm);
+        assert!(lsm.scan_prefix(&key(5, "")).unwrap().is_empty());
+        assert_eq!(blocks_read(&lsm), before);
+
+        let mut expected = (0..20)
+            .filter(|i| *i != 4)
+            .map(|i| (key(2, &format!("{:02}", i)), key(2, &format!("{:02}", i))))
+            .collect::<Vec<_>>();
+        expected[3].1 = b"new".to_vec();
+        assert_eq!(lsm.scan_prefix(&key(2, "")).unwrap(), expected);
+        assert!(blocks_read(&lsm) > before);
+        //a shorter prefix is not one the filters know, so it reads every table it overlaps
+        assert_eq!(lsm.scan_prefix(&key(2, "")[..7]).unwrap().len(), expected.len() + 5);
+
+        //the filters were built by another extractor, so they are not used
+        drop(lsm);
+        let lsm = LsmDb::with_config(dir, config(Arc::new(RenamedPrefix(FixedPrefix::new(8))))).unwrap();
+        assert_eq!(lsm.scan_prefix(&key(2, "")).unwrap(), expected);
+        let before = blocks_read(&lsm);
+        assert!(lsm.scan_prefix(&key(5, "")).unwrap().is_empty());
+        assert!(blocks_read(&lsm) > before);
+    }
+
+    #[test]
+    fn recovered_immutable_mem_table_is_flushed_on_open() {
+        let dir = temp_dir("recovered_flush");
+        let mut older = MemTable::new();
+        older.set_writer(&test_env(), &dir, 1).unwrap();
+        older.insert(b"old", b"flushed", 1, false).unwrap();
+        let mut newer = MemTable::new();
+        newer.set_writer(&test_env(), &dir, 2).unwrap();
+        newer.insert(b"new", b"logged", 2, false).unwrap();
+        drop((older, newer));
+
+        //no writes after the open
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        wait_until(|| lsm.im_mem_table.read().is_none());
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 1);
+        assert!(!dir.join("1.LOG").exists());
+        assert!(dir.join("2.LOG").exists());
+        assert_eq!(lsm.search(b"old", None).unwrap(), Some(b"flushed".to_vec()));
+        assert_eq!(lsm.search(b"new", None).unwrap(), Some(b"logged".to_vec()));
+    }
+
+    #[test]
+    fn flushing_an_empty_mem_table_only_removes_its_log() {
+        let dir = temp_dir("empty_flush");
+        let lsm = LsmDb::with_config(dir.clone(), small_config()).unwrap();
+        lsm.insert(b"k", b"v").unwrap();
+        let mut empty = MemTable::new();
+        empty.set_writer(&test_env(), &dir, 100).unwrap();
+        assert!(dir.join("100.LOG").exists());
+        *lsm.im_mem_table.write() = Some(empty);
+        assert!(LsmDb::do_background_work(&lsm.levels, &lsm.im_mem_table, &lsm.events, BackgroundWork::Flush).unwrap());
+        assert!(lsm.im_mem_table.read().is_none());
+        assert!(!dir.join("100.LOG").exists());
+        assert_eq!(lsm.levels.read().num_files_at_level(0), 0);
+        assert_eq!(lsm.search(b"k", None).unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn snapshot_reads_the_old_versions_after_compaction() {
+        let lsm = LsmDb::with_config(temp_dir("snapshot_compaction"), small_config()).unwrap();
+        lsm.insert(b"key", b"old").unwrap();
+        lsm.insert(b"gone", b"old").unwrap();
+        lsm.flush().unwrap();
+        let snap = lsm.snapshot();
+        let reader = lsm.tx_begin_read_only();
+        assert_eq!(lsm.oldest_snapshot_seq(), Some(snap.seq_num()));
+        lsm.insert(b"key", b"new").unwrap();
+        lsm.delete(b"gone").unwrap();
+        lsm.flush().unwrap();
+        for level in 0..lsm.config.max_levels {
+            lsm.compact_level(level).unwrap();
+        }
+        assert_eq!(lsm.search(b"key", Some(snap.seq_num())).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(lsm.search(b"gone", Some(snap.seq_num())).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(lsm.search(b"key", None).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(lsm.search(b"gone", None).unwrap(), None);
+
+        //both handles pin the same sequence number
+        drop(snap);
+        assert_eq!(lsm.oldest_snapshot_seq(), Some(reader.seq_num()));
+        drop(reader);
+        assert_eq!(lsm.oldest_snapshot_seq(), None);
+        lsm.compact_level(lsm.config.max_levels - 1).unwrap();
+        assert_eq!(lsm.levels.read().dump_normalized().matches(" entries").count(), 1);
+        assert_eq!(lsm.search(b"key", None).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(lsm.search(b"gone", None).unwrap(), None);
+    }
+
+    #[test]
+    fn read_view_sees_a_write_batch_entirely_or_not_at_all() {
+        let lsm = LsmDb::with_config(temp_dir("read_view_batches"), small_config()).unwrap();
+        let keys: [&[u8]; 3] = [b"a", b"b", b"c"];
+        let done = AtomicBool::new(false);
+        crossbeam_utils::thread::scope(|s| {
+            s.spawn(|_| {
+                for i in 0..2000u32 {
+                    let value = i.to_be_bytes();
+                    let mut batch = WriteBatch::new();
+                    keys.iter().for_each(|key| { batch.put(key, &value); });
+                    lsm.write(&batch).unwrap();
+                }
+                done.store(true, Ordering::Release);
+            });
+            while !done.load(Ordering::Acquire) {
+                let view = lsm.read_view();
+                let values = keys.iter().map(|key| view.get(key).unwrap()).collect::<Vec<_>>();
+                assert!(values.iter().all(|v| *v == values[0]), "torn batch {:?}", values);
+                assert_eq!(view.multi_get(&keys).unwrap(), values);
+                let values = lsm.multi_get_consistent(&keys).unwrap();
+                assert!(values.iter().all(|v| *v == values[0]), "torn batch {:?}", values);
+            }
+        }).unwrap();
+        assert_eq!(lsm.oldest_snapshot_seq(), None);
+        assert_eq!(lsm.multi_get_consistent(&keys).unwrap(), vec![Some(1999u32.to_be_bytes().to_vec()); 3]);
+    }
+
+    #[test]
+    fn key_may_exist_never_denies_a_present_key_and_reads_no_blocks() {
+        let env = MemEnv::new();
+        let mut config = mem_env_config(&env);
+        config.prefix_extractor = Some(Arc::new(FixedPrefix::new(6)));
+        let lsm = LsmDb::with_config(PathBuf::from("/db"), config).unwrap();
+        let mut reference = BTreeMap::new();
+        let mut rng = Rng(7);
+        for _ in 0..2000 {
+            let key = format!("k{:05}", rng.next() % 1000 * 2);
+            if rng.next() % 4 == 3 {
+                lsm.delete(key.as_bytes()).unwrap();
+                reference.remove(&key);
+            } else {
+                let value = format!("v{}", rng.next());
+                lsm.insert(key.as_bytes(), value.as_bytes()).unwrap();
+                reference.insert(key, value);
+            }
+        }
+        wait_until(|| background_idle(&lsm));
+        env.take_read_count();
+        let mut denied = 0;
+        for i in 0..2000 {
+            let key = format!("k{:05}", i);
+            match (lsm.key_may_exist(key.as_bytes()), reference.get(&key)) {
+                (KeyMayExist::No, Some(_)) => panic!("{} is present", key),
+                (KeyMayExist::No, None) => denied += 1,
+                (KeyMayExist::YesWithValue(value), expected) => assert_eq!(Some(&value[..]), expected.map(|v| v.as_bytes())),
+                (KeyMayExist::Maybe, _) => {},
+            }
+        }
+        assert_eq!(env.take_read_count(), 0);
+        //the odd keys were never written, the prefix filters rule out most of them
+        assert!(denied > 900, "only {} keys denied", denied);
+    }
+
+    #[test]
+    fn reads_without_fill_cache_leave_the_block_cache_alone() {
+        let env = MemEnv::new();
+        let mut config = mem_env_config(&env);
+        config.l0_compaction_threshold = 8;
+        config.block_cache_size = 16 * 1024;
+        config.block_cache_shard_bits = 0;
+        let lsm = LsmDb::with_config(PathBuf::from("/mem/fill_cache"), config).unwrap();
+        let entries = |prefix: &str, n: usize| (0..n)
+            .map(|i| (LookUpKey::new(InternalKey::new(format!("{}{:05}", prefix, i).as_bytes(), 1, ValueType::Put)), vec![b'v'; 100]))
+            .collect::<Vec<_>>();
+        lsm.next_seq_num.store(2, Ordering::SeqCst);
+        lsm.last_published_seq.store(1, Ordering::SeqCst);
+        {
+            let mut levels = lsm.levels.write();
+            let l0 = levels.write_file(entries("hot", 20).into_iter(), 0).unwrap();
+            let l1 = levels.write_file(entries("cold", 1000).into_iter(), 1).unwrap();
+            levels.update(Vec::new(), vec![l0, l1]).unwrap();
+        }
+        let hot = (0..20).map(|i| format!("hot{:05}", i)).collect::<Vec<_>>();
+        hot.iter().for_each(|key| assert!(lsm.search(key.as_bytes(), None).unwrap().is_some()));
+        let before = lsm.stats();
+        assert!(before.block_cache.usage > 0);
+
+        let opts = ReadOptions { fill_cache: false, ..ReadOptions::default() };
+        for i in 0..1000 {
+            assert!(lsm.search_opt(format!("cold{:05}", i).as_bytes(), &opts).unwrap().is_some());
+        }
+        assert_eq!(lsm.scan_prefix_opt(b"cold", &opts).unwrap().len(), 1000);
+        let after = lsm.stats();
+        assert_eq!(after.block_cache.usage, before.block_cache.usage);
+        assert_eq!(after.block_cache.evictions, before.block_cache.evictions);
+        //the hot blocks are all still cached
+        hot.iter().for_each(|key| assert!(lsm.search(key.as_bytes(), None).unwrap().is_some()));
+        assert_eq!(lsm.stats().blocks_read, after.blocks_read);
+    }
+
+    #[test]
+    fn keys_only_scans_skip_the_values() {
+        let env = MemEnv::new();
+        let mut config = mem_env_config(&env);
+        config.l0_compaction_threshold = 8;
+        config.block_cache_size = 8 << 20;
+        let lsm = LsmDb::with_config(PathBuf::from("/mem/keys_only"), config).unwrap();
+        let entries = |from: usize, n: usize| (from..from + n)
+            .map(|i| (LookUpKey::new(InternalKey::new(format!("key{:05}", i).as_bytes(), 1, ValueType::Put)), vec![b'v'; 4096]))
+            .collect::<Vec<_>>();
+        lsm.next_seq_num.store(2, Ordering::SeqCst);
+        lsm.last_published_seq.store(1, Ordering::SeqCst);
+        {
+            let mut levels = lsm.levels.write();
+            let l0 = levels.write_file(entries(0, 100).into_iter(), 0).unwrap();
+            let l1 = levels.write_file(entries(50, 200).into_iter(), 1).unwrap();
+            levels.update(Vec::new(), vec![l0, l1]).unwrap();
+        }
+        lsm.insert(b"key00300", &[b'w'; 4096]).unwrap();
+        lsm.delete(b"key00010").unwrap();
+        let opts = ReadOptions::default();
+        //a first pass brings the blocks into the cache, so only the scans themselves are counted
+        let full = lsm.scan_prefix_opt(b"key", &opts).unwrap();
+        lsm.scan_prefix_keys(b"key", &opts).unwrap();
+        let (full_len, full_bytes) = count_allocated_bytes(|| lsm.scan_prefix_opt(b"key", &opts).unwrap().len());
+        let (keys, keys_bytes) = count_allocated_bytes(|| lsm.scan_prefix_keys(b"key", &opts).unwrap());
+        let expected = (0..250).filter(|&i| i != 10).chain(std::iter::once(300))
+            .map(|i| format!("key{:05}", i).into_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+        assert_eq!(full_len, keys.len());
+        assert_eq!(full.into_iter().map(|(k, _)| k).collect::<Vec<_>>(), keys);
+        assert!(keys_bytes * 10 < full_bytes, "{} bytes keys only, {} with the values", keys_bytes, full_bytes);
+
+        let mut iter = lsm.iter_with(&opts, &IterOptions { keys_only: true }).unwrap();
+        iter.seek(b"key00300");
+        assert!(iter.valid() && iter.value().is_empty());
+    }
+
+    #[test]
+    fn count_range_matches_the_scan_and_bounds_the_estimate() {
+        let lsm = LsmDb::with_config(temp_dir("count_range"), small_config()).unwrap();
+        let mut reference = BTreeMap::new();
+        let mut written = Vec::new();
+        let mut rng = Rng(23);
+        for i in 0..3000 {
+            let key = format!("k{:04}", rng.next() % 600).into_bytes();
+            if rng.next() % 4 == 3 {
+                lsm.delete(&key).unwrap();
+                reference.remove(&key);
+            } else {
+                lsm.insert(&key, format!("v{}", i).as_bytes()).unwrap();
+                reference.insert(key.clone(), ());
+            }
+            written.push(key);
+        }
+        wait_until(|| background_idle(&lsm));
+        let snap = lsm.snapshot();
+        let before = reference.clone();
+        for i in 0..100 {
+            let key = format!("k{:04}", i * 6).into_bytes();
+            lsm.insert(&key, b"later").unwrap();
+            reference.insert(key.clone(), ());
+            written.push(key);
+        }
+
+        let exact = CountOptions::default();
+        let approximate = CountOptions { approximate: true, ..CountOptions::default() };
+        for _ in 0..40 {
+            let (a, b) = (format!("k{:04}", rng.next() % 650), format!("k{:04}", rng.next() % 650));
+            let (start, end) = (a.as_bytes().min(b.as_bytes()), a.as_bytes().max(b.as_bytes()));
+            let count = lsm.count_range(start, end, &exact).unwrap();
+            let mut iter = lsm.iter().unwrap();
+            let mut scanned = 0;
+            iter.seek(start);
+            while iter.valid() && iter.key() < end {
+                scanned += 1;
+                iter.next();
+            }
+            assert_eq!(count, scanned);
+            assert_eq!(count, reference.range(start.to_vec()..end.to_vec()).count() as u64);
+            let at_snapshot = CountOptions { read: ReadOptions { snapshot: Some(&snap), ..ReadOptions::default() }, approximate: false };
+            assert_eq!(lsm.count_range(start, end, &at_snapshot).unwrap(), before.range(start.to_vec()..end.to_vec()).count() as u64);
+
+            //never below the exact count, and never above what was ever written to the range
+            let estimate = lsm.count_range(start, end, &approximate).unwrap();
+            let stored = written.iter().filter(|k| start <= &k[..] && &k[..] < end).count() as u64;
+            assert!(count <= estimate && estimate <= stored, "{} live, estimated {}, {} written", count, estimate, stored);
+        }
+        assert_eq!(lsm.count_range(b"k0500", b"k0100", &exact).unwrap(), 0);
+    }
+
+    #[test]
+    fn delete_batch_writes_the_tombstones_at_once() {
+        let lsm = LsmDb::with_config(temp_dir("delete_batch"), small_config()).unwrap();
+        for key in ["a", "b", "c"].iter() {
+            lsm.insert(key.as_bytes(), b"v").unwrap();
+        }
+        let before = lsm.last_published_seq();
+        lsm.delete_batch(&[b"a", b"c", b"missing"]).unwrap();
+        assert_eq!(lsm.last_published_seq(), before + 3);
+        assert_eq!(lsm.search(b"a", Some(before + 1)).unwrap(), None);
+        assert_eq!(lsm.search(b"c", Some(before + 1)).unwrap(), Some(b"v".to_vec()));
+        assert_eq!(lsm.scan_prefix(b"").unwrap(), vec![(b"b".to_vec(), b"v".to_vec())]);
+        lsm.delete_batch(&[]).unwrap();
+        assert_eq!(lsm.last_published_seq(), before + 3);
+    }
+
+    #[test]
+    fn purge_prefix_deletes_from_the_mem_table_and_the_levels() {
+        let lsm = LsmDb::with_config(temp_dir("purge_prefix"), small_config()).unwrap();
+        let entries = |prefix: &str, from: usize, n: usize, seq_num: u64| (from..from + n)
+            .map(|i| (LookUpKey::new(InternalKey::new(format!("{}{:05}", prefix, i).as_bytes(), seq_num, ValueType::Put)), vec![b'v'; 40]))
+            .collect::<Vec<_>>();
+        lsm.next_seq_num.store(3, Ordering::SeqCst);
+        lsm.last_published_seq.store(2, Ordering::SeqCst);
+        {
+            let mut levels = lsm.levels.write();
+            let mut l2 = entries("purge/", 0, 3000, 1);
+            l2.extend(entries("queue/", 0, 100, 1));
+            let l2 = levels.write_file(l2.into_iter(), 2).unwrap();
+            let mut l1 = entries("keep/", 0, 100, 2);
+            l1.extend(entries("purge/", 2000, 2000, 2));
+            let l1 = levels.write_file(l1.into_iter(), 1).unwrap();
+            levels.update(Vec::new(), vec![l1, l2]).unwrap();
+        }
+        lsm.insert(b"purge/99999", b"in the mem table").unwrap();
+        lsm.insert(b"purgatory", b"another prefix").unwrap();
+        lsm.delete(b"purge/00007").unwrap();
+
+        assert_eq!(lsm.purge_prefix(b"purge/").unwrap(), 4000);
+        assert!(lsm.scan_prefix(b"purge/").unwrap().is_empty());
+        assert_eq!(lsm.scan_prefix(b"keep/").unwrap().len(), 100);
+        assert_eq!(lsm.scan_prefix(b"queue/").unwrap().len(), 100);
+        assert_eq!(lsm.search(b"purgatory", None).unwrap(), Some(b"another prefix".to_vec()));
+        assert_eq!(lsm.purge_prefix(b"purge/").unwrap(), 0);
+    }
+
+    #[test]
+    fn purge_prefix_runs_alongside_writers() {
+        let lsm = Arc::new(LsmDb::with_config(temp_dir("purge_concurrent"), small_config()).unwrap());
+        for i in 0..2000 {
+            lsm.insert(format!("p{:05}", i).as_bytes(), b"v").unwrap();
+        }
+        let writer = {
+            let lsm = lsm.clone();
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    lsm.insert(format!("p{:05}", (i * 7) % 4000).as_bytes(), b"w").unwrap();
+                }
+            })
+        };
+        let deleted = lsm.purge_prefix(b"p").unwrap();
+        writer.join().unwrap();
+        assert!(deleted >= 2000);
+        //whatever is left was written by the other thread
+        assert!(lsm.scan_prefix(b"p").unwrap().iter().all(|(_, v)| v == b"w"));
+    }
+
+    #[test]
+    fn getset_returns_every_value_written_exactly_once() {
+        let lsm = Arc::new(LsmDb::with_config(temp_dir("getset"), small_config()).unwrap());
+        assert_eq!(lsm.getset(b"key", b"init").unwrap(), None);
+        let threads = (0..4).map(|t| {
+            let lsm = lsm.clone();
+            thread::spawn(move || (0..500)
+                .map(|i| lsm.getset(b"key", format!("{}-{}", t, i).as_bytes()).unwrap().unwrap())
+                .collect::<Vec<_>>())
+        }).collect::<Vec<_>>();
+        let mut returned = threads.into_iter().flat_map(|t| t.join().unwrap()).collect::<Vec<_>>();
+        returned.push(lsm.get_and_delete(b"key").unwrap().unwrap());
+        returned.sort();
+        let mut written = (0..4).flat_map(|t| (0..500).map(move |i| format!("{}-{}", t, i).into_bytes())).collect::<Vec<_>>();
+        written.push(b"init".to_vec());
+        written.sort();
+        assert_eq!(returned, written);
+        assert_eq!(lsm.search(b"key", None).unwrap(), None);
+        let seq_num = lsm.last_published_seq();
+        assert_eq!(lsm.get_and_delete(b"key").unwrap(), None);
+        assert_eq!(lsm.last_published_seq(), seq_num);
+    }
+
+    #[test]
+    fn tx_getset_sees_the_writes_of_the_transaction() {
+        let lsm = LsmDb::with_config(temp_dir("tx_getset"), small_config()).unwrap();
+        lsm.insert(b"a", b"1").unwrap();
+        let (tx_id, seq_num) = lsm.tx_begin();
+        assert_eq!(lsm.tx_getset(tx_id, seq_num, b"a", b"2").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(lsm.tx_getset(tx_id, seq_num, b"a", b"3").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(lsm.tx_get_and_delete(tx_id, seq_num, b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(lsm.tx_getset(tx_id, seq_num, b"a", b"4").unwrap(), None);
+        assert_eq!(lsm.tx_get_and_delete(tx_id, seq_num, b"b").unwrap(), None);
+        assert_eq!(lsm.search(b"a", None).unwrap(), Some(b"1".to_vec()));
+        lsm.tx_commit(tx_id).unwrap();
+        assert_eq!(lsm.search(b"a", None).unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn stats_are_dumped_every_period_until_close() {
+        let mut config = small_config();
+        config.stats_dump_period = Some(Duration::from_millis(20));
+        let lsm = LsmDb::with_config(temp_dir("stats_dump"), config).unwrap();
+        let events = lsm.events();
+        let dumps = || std::iter::from_fn(|| events.recv_timeout(Duration::from_secs(5)))
+            .filter_map(|e| match e {
+                DbEvent::StatsDump { at, stats } => Some((at, stats)),
+                _ => None,
+            });
+        for i in 0..100 {
+            lsm.insert(format!("key{}", i).as_bytes(), b"value").unwrap();
+        }
+        lsm.delete(b"key0").unwrap();
+        for i in 0..50 {
+            lsm.search(format!("key{}", i).as_bytes(), None).unwrap();
+        }
+        //the operations are spread over the dumps up to the first one after they are all done
+        let done = SystemTime::now();
+        let (mut reads, mut writes) = (0, 0);
+        for (at, stats) in dumps() {
+            assert_eq!(stats.levels.len(), lsm.levels.read().num_levels());
+            assert!(stats.period >= Duration::from_millis(20));
+            assert_eq!(stats.background_error, None);
+            reads += stats.reads;
+            writes += stats.writes;
+            if at > done {
+                break;
+            }
+        }
+        assert_eq!((reads, writes), (50, 101));
+        let line = dumps().next().unwrap().1.to_string();
+        assert!(line.contains("0 reads, 0 writes"), "{}", line);
+
+        lsm.close().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        while events.try_recv().is_some() {}
+        thread::sleep(Duration::from_millis(100));
+        assert!(std::iter::from_fn(|| events.try_recv()).all(|e| !matches!(e, DbEvent::StatsDump { .. })));
+    }
+
+    #[test]
+    fn read_past_its_deadline_times_out() {
+        let env = MemEnv::new();
+        let mut config = mem_env_config(&env);
+        config.l0_compaction_threshold = 8;
+        config.block_cache_size = 0;
+        config.bloom_bits_per_key = 0.0;
+        let lsm = LsmDb::with_config(PathBuf::from("/mem/deadline"), config).unwrap();
+        let entry = |key: &[u8], seq_num| (LookUpKey::new(InternalKey::new(key, seq_num, ValueType::Put)), b"v".to_vec());
+        lsm.next_seq_num.store(3, Ordering::SeqCst);
+        lsm.last_published_seq.store(2, Ordering::SeqCst);
+        {
+            //both level 0 tables cover "m", only the older one holds it
+            let mut levels = lsm.levels.write();
+            let older = levels.write_file(std::iter::once(entry(b"m", 1)), 0).unwrap();
+            let newer = levels.write_file(vec![entry(b"a", 2), entry(b"z", 2)].into_iter(), 0).unwrap();
+            levels.update(Vec::new(), vec![older, newer]).unwrap();
+        }
+        env.set_read_delay(Duration::from_millis(20));
+        let opts = ReadOptions { deadline: Some(Duration::from_millis(5)), ..ReadOptions::default() };
+        assert!(matches!(lsm.search_opt(b"m", &opts), Err(Error::TimedOut)));
+        assert!(matches!(lsm.multi_get_opt(&[b"m"], &opts), Err(Error::TimedOut)));
+        let opts = ReadOptions { deadline: Some(Duration::from_secs(10)), ..ReadOptions::default() };
+        assert_eq!(lsm.search_opt(b"m", &opts).unwrap(), Some(b"v".to_vec()));
+        assert_eq!(lsm.search(b"m", None).unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn iterator_walks_the_tables_and_mem_tables_both_ways() {
+        let lsm = LsmDb::with_config(temp_dir("db_iterator"), small_config()).unwrap();
+        let mut reference = BTreeMap::new();
+        let mut rng = Rng(11);
+        for i in 0..3000 {
+            let key = format!("k{:04}", rng.next() % 500).into_bytes();
+            if rng.next() % 3 == 2 {
+                lsm.delete(&key).unwrap();
+                reference.remove(&key);
+            } else {
+                let value = format!("v{}", i).into_bytes();
+                lsm.insert(&key, &value).unwrap();
+                reference.insert(key, value);
+            }
+        }
+        wait_until(|| background_idle(&lsm));
+        assert!(lsm.levels.read().level_stats().iter().map(|l| l.num_files).sum::<usize>() > 0);
+        let snap = lsm.snapshot();
+        let before = reference.clone();
+        lsm.delete(reference.keys().next().unwrap()).unwrap();
+        lsm.insert(b"k9999", b"after").unwrap();
+
+        let mut iter = lsm.iter_opt(&ReadOptions { snapshot: Some(&snap), ..ReadOptions::default() }).unwrap();
+        let mut forward = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            forward.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        assert_eq!(forward, before.clone().into_iter().collect::<Vec<_>>());
+        let mut backward = Vec::new();
+        iter.seek_to_last();
+        while iter.valid() {
+            backward.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.prev();
+        }
+        backward.reverse();
+        assert_eq!(backward, forward);
+        assert!(iter.status().is_ok());
+
+        //seeks to keys that are not there land on the next one, then step back
+        for i in 0..50 {
+            let target = format!("k{:04}~", rng.next() % 520).into_bytes();
+            iter.seek(&target);
+            let next = before.range(target.clone()..).next();
+            assert_eq!(iter.valid().then(|| iter.key().to_vec()).as_ref(), next.map(|(k, _)| k));
+            if iter.valid() {
+                iter.prev();
+                let prev = before.range(..target).next_back();
+                assert_eq!(iter.valid().then(|| iter.key().to_vec()).as_ref(), prev.map(|(k, _)| k), "seek {}", i);
+            }
+        }
+        let mut latest = lsm.iter().unwrap();
+        latest.seek_to_last();
+        assert_eq!(latest.key(), b"k9999");
+    }
+
+    #[test]
+    fn iterator_keeps_reading_the_tables_a_compaction_replaced() {
+        let mut config = small_config();
+        config.l0_compaction_threshold = 100;
+        let lsm = LsmDb::with_config(temp_dir("pinned_iterator"), config).unwrap();
+        for i in 0..200 {
+            lsm.insert(format!("k{:03}", i).as_bytes(), format!("v{}", i).as_bytes()).unwrap();
+        }
+        lsm.flush().unwrap();
+        for i in (0..200).step_by(2) {
+            lsm.insert(format!("k{:03}", i).as_bytes(), format!("w{}", i).as_bytes()).unwrap();
+        }
+        lsm.flush().unwrap();
+        let at_creation = (0..200)
+            .map(|i| (format!("k{:03}", i).into_bytes(), format!("{}{}", if i % 2 == 0 { "w" } else { "v" }, i).into_bytes()))
+            .collect::<Vec<_>>();
+
+        let mut iter = lsm.iter().unwrap();
+        let pinned = iter.pinned_files().to_vec();
+        assert!(pinned.len() >= 2 && pinned.iter().all(|f| f.level == 0));
+        let mut seen = Vec::new();
+        iter.seek_to_first();
+        for _ in 0..50 {
+            seen.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+
+        //replace every table under the iterator
+        for i in (0..200).step_by(3) {
+            lsm.delete(format!("k{:03}", i).as_bytes()).unwrap();
+        }
+        lsm.insert(b"k999", b"after").unwrap();
+        lsm.flush().unwrap();
+        lsm.compact_level(0).unwrap();
+        let live = lsm.levels.read().live_tables();
+        assert!(pinned.iter().all(|f| !live.iter().any(|l| l.path == f.path)));
+        assert!(pinned.iter().all(|f| f.path.exists()));
+
+        while iter.valid() {
+            seen.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        assert!(iter.status().is_ok());
+        assert_eq!(seen, at_creation);
+        assert!(pinned.iter().all(|f| f.path.exists()));
+        drop(iter);
+        assert!(pinned.iter().all(|f| !f.path.exists()));
+        assert_eq!(lsm.scan_prefix_keys(b"k", &ReadOptions::default()).unwrap().len(), 200 - 67 + 1);
+    }
+
+    #[test]
+    fn large_values_are_read_through_the_value_logs() {
+        let config = || Config { value_log_threshold: 100, write_buffer_size: 1 << 20, ..small_config() };
+        let file_bytes = |path: &Path| std::fs::metadata(path).unwrap().len();
+        let dir = temp_dir("value_logs");
+        let value = |i: usize| match i % 3 {
+            0 => format!("large{}", i).repeat(50).into_bytes(),
+            _ => format!("v{}", i).into_bytes(),
+        };
+        let lsm = LsmDb::with_config(dir.clone(), config()).unwrap();
+        for i in 0..300 {
+            lsm.insert(format!("k{:03}", i).as_bytes(), &value(i)).unwrap();
+        }
+        lsm.flush().unwrap();
+        let vlogs = lsm.value_logs.paths();
+        assert_eq!(vlogs.len(), 1);
+        assert!(file_bytes(&vlogs[0]) > 100 * 250);
+        let table_bytes = lsm.levels.read().live_tables().iter().map(|t| t.bytes).sum::<u64>();
+        assert!(table_bytes < file_bytes(&vlogs[0]) / 4);
+        assert_eq!(lsm.live_files().unwrap().value_logs, vlogs);
+
+        let check = |lsm: &LsmDb| {
+            for i in (0..300).step_by(7) {
+                assert_eq!(lsm.search(format!("k{:03}", i).as_bytes(), None).unwrap(), Some(value(i)));
+            }
+            let keys = (0..3).map(|i| format!("k{:03}", i).into_bytes()).collect::<Vec<_>>();
+            let keys = keys.iter().map(|k| &k[..]).collect::<Vec<_>>();
+            assert_eq!(lsm.multi_get(&keys).unwrap(), (0..3).map(|i| Some(value(i))).collect::<Vec<_>>());
+            let scanned = lsm.scan_prefix(b"k").unwrap();
+            assert_eq!(scanned, (0..300).map(|i| (format!("k{:03}", i).into_bytes(), value(i))).collect::<Vec<_>>());
+            let mut iter = lsm.iter().unwrap();
+            iter.seek_to_last();
+            for i in (0..300).rev() {
+                assert_eq!((iter.key(), iter.value()), (format!("k{:03}", i).as_bytes(), &value(i)[..]));
+                iter.prev();
+            }
+            assert!(iter.status().is_ok());
+        };
+        check(&lsm);
+        //compactions move the pointers, never the values
+        lsm.compact_level(0).unwrap();
+        assert_eq!(lsm.value_logs.paths(), vlogs);
+        check(&lsm);
+        drop(lsm);
+
+        let lsm = LsmDb::with_config(dir.clone(), config()).unwrap();
+        check(&lsm);
+        assert_eq!(lsm.value_logs.paths(), vlogs);
+        drop(lsm);
+
+        //a lost value log fails the reads of its values, the others are still there
+        std::fs::remove_file(&vlogs[0]).unwrap();
+        let lsm = LsmDb::with_config(dir, config()).unwrap();
+        assert!(matches!(lsm.search(b"k000", None), Err(Error::Corruption(_))));
+        assert!(matches!(lsm.multi_get(&[b"k001", b"k003"]), Err(Error::Corruption(_))));
+        assert_eq!(lsm.search(b"k001", None).unwrap(), Some(value(1)));
+    }
+
+    #[test]
+    fn value_log_gc_writes_live_values_again_and_deletes_the_log_after_its_last_reader() {
+        let config = Config { value_log_threshold: 100, write_buffer_size: 1 << 20, ..small_config() };
+        let lsm = LsmDb::with_config(temp_dir("value_log_gc"), config).unwrap();
+        let key = |i: usize| format!("k{:03}", i).into_bytes();
+        let value = |i: usize, round: usize| format!("{}-{:03}", round, i).repeat(30).into_bytes();
+        for i in 0..200 {
+            lsm.insert(&key(i), &value(i, 0)).unwrap();
+        }
+        lsm.flush().unwrap();
+        //overwrite or delete most of the first log, the compaction drops the pointers into it
+        for i in 0..180 {
+            lsm.insert(&key(i), &value(i, 1)).unwrap();
+        }
+        for i in 180..190 {
+            lsm.delete(&key(i)).unwrap();
+        }
+        lsm.flush().unwrap();
+        lsm.compact_level(0).unwrap();
+        let stats = lsm.value_log_stats();
+        assert_eq!(stats.len(), 2);
+        let old = stats[0].clone();
+        assert!(old.garbage_ratio() > 0.9, "{:?}", old);
+        assert_eq!(stats[1].garbage_ratio(), 0.0);
+        let old_path = lsm.value_logs.path(old.file_num);
+        let usage_before = lsm.size_on_disk().value_logs;
+        assert_eq!(usage_before, stats.iter().map(|s| s.bytes).sum::<u64>());
+
+        let snapshot = lsm.snapshot();
+        let mut iter = lsm.iter().unwrap();
+        iter.seek_to_first();
+        let summary = lsm.gc_value_logs().unwrap();
+        assert_eq!(summary, ValueLogGcSummary { collected_files: 1, rewritten_values: 10, rewritten_bytes: 10 * 150 });
+        assert!(lsm.value_log_stats()[0].retired);
+        assert!(!lsm.live_files().unwrap().value_logs.contains(&old_path));
+        assert_eq!(lsm.gc_value_logs().unwrap(), ValueLogGcSummary::default());
+
+        //the snapshot keeps the old versions in the tables, the iterator keeps the tables
+        lsm.flush().unwrap();
+        lsm.compact_level(0).unwrap();
+        assert!(old_path.exists());
+        assert_eq!(lsm.search(&key(195), Some(snapshot.seq_num())).unwrap(), Some(value(195, 0)));
+        drop(snapshot);
+        lsm.compact_level(1).unwrap();
+        assert!(old_path.exists());
+        let mut seen = Vec::new();
+        while iter.valid() {
+            seen.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        assert!(iter.status().is_ok());
+        let expected = (0..180).map(|i| (key(i), value(i, 1))).chain((190..200).map(|i| (key(i), value(i, 0)))).collect::<Vec<_>>();
+        assert_eq!(seen, expected);
+        drop(iter);
+        assert!(!old_path.exists());
+        assert!(lsm.size_on_disk().value_logs < usage_before - old.bytes * 9 / 10);
+
+        for i in 0..200 {
+            let expected = match i {
+                0..=179 => Some(value(i, 1)),
+                180..=189 => None,
+                _ => Some(value(i, 0)),
+            };
+            assert_eq!(lsm.search(&key(i), None).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn value_checksums_catch_a_value_corrupted_after_its_write() {
+        let config = |paranoid_checks| Config { value_checksums: true, paranoid_checks, write_buffer_size: 1 << 20, ..small_config() };
+        let dir = temp_dir("value_checksums");
+        let key = |i: usize| format!("k{:03}", i).into_bytes();
+        let lsm = LsmDb::with_config(dir.clone(), config(false)).unwrap();
+        for i in 0..100 {
+            lsm.insert(&key(i), format!("v{}", i).as_bytes()).unwrap();
+        }
+        let mut batch = WriteBatch::new();
+        batch.put(&key(100), b"v100").delete(&key(0));
+        lsm.write(&batch).unwrap();
+        //the checksums are logged with the values
+        drop(lsm);
+        let lsm = LsmDb::with_config(dir.clone(), config(false)).unwrap();
+        let verified = ReadOptions { verify_checksums: Some(true), ..ReadOptions::default() };
+        let expected = (1..=100).map(|i| (key(i), format!("v{}", i).into_bytes())).collect::<Vec<_>>();
+        assert_eq!(lsm.scan_prefix_opt(b"k", &verified).unwrap(), expected);
+
+        let assert_caught = |lsm: &LsmDb, opts: &ReadOptions| {
+            let assert_names_key = |res: Result<()>| match res {
+                Err(Error::Corruption(msg)) => assert!(msg.contains("k042"), "{}", msg),
+                res => panic!("expected corruption, got {:?}", res),
+            };
+            assert_names_key(lsm.search_opt(&key(42), opts).map(|_| ()));
+            assert_names_key(lsm.multi_get_opt(&[&key(41), &key(42)], opts).map(|_| ()));
+            assert_names_key(lsm.scan_prefix_opt(b"k04", opts).map(|_| ()));
+            let mut iter = lsm.iter_opt(opts).unwrap();
+            iter.seek(&key(41));
+            assert_eq!(iter.value(), b"v41");
+            iter.next();
+            assert!(!iter.valid());
+            assert_names_key(iter.status());
+            assert_eq!(lsm.search_opt(&key(43), opts).unwrap(), Some(b"v43".to_vec()));
+            //the keys alone are still there
+            assert_eq!(lsm.scan_prefix_keys(b"k04", opts).unwrap().len(), 10);
+        };
+        lsm.mem_table.write().corrupt_value(&key(42));
+        assert_caught(&lsm, &verified);
+        //unverified reads do not notice
+        assert_eq!(lsm.search(&key(42), None).unwrap(), Some(b"w42".to_vec()));
+
+        //the checksum of the value as it was written goes along into the tables
+        lsm.flush().unwrap();
+        lsm.compact_level(0).unwrap();
+        assert_eq!(lsm.mem_table.read().inner.len(), 0);
+        drop(lsm);
+        let lsm = LsmDb::with_config(dir.clone(), config(false)).unwrap();
+        assert_caught(&lsm, &verified);
+        drop(lsm);
+        //paranoid checks verify every read
+        let lsm = LsmDb::with_config(dir, config(true)).unwrap();
+        assert_caught(&lsm, &ReadOptions::default());
+        //and so do the reads without options
+        assert!(matches!(lsm.search(&key(42), None), Err(Error::Corruption(_))));
+        assert!(matches!(lsm.multi_get(&[&key(42)]), Err(Error::Corruption(_))));
+        assert!(matches!(lsm.read_view().get(&key(42)), Err(Error::Corruption(_))));
+        assert!(matches!(lsm.read_view().multi_get(&[&key(42)]), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn disjoint_level0_table_sinks_to_level1_without_a_merge() {
+        use crate::key::{InternalKey, LookUpKey, ValueType};
+        use crate::utils::temp_dir;
+        use std::time::{Duration, Instant};
+
+        let dir = temp_dir("lsm_sink");
+        let lsm = LsmDb::new(dir.clone()).unwrap();
+        let write_table = |prefix: &str, seq_num: u64, level: usize| {
+            let data = (1..4)
+                .map(|i| format!("{}{}", prefix, i).into_bytes())
+                .map(|k| (LookUpKey::new(InternalKey::new(&k, seq_num, ValueType::Put)), k))
+                .collect::<Vec<_>>();
+            lsm.levels.read().write_file(Box::new(data.into_iter()), level).unwrap()
+        };
+        //1.sst and 2.sst in level 1, one more table in level 0 than the threshold allows
+        let mut tables = vec![write_table("a", 1, 1), write_table("z", 2, 1)];
+        for (i, prefix) in ["c", "d", "e", "f", "g"].iter().enumerate() {
+            tables.push(write_table(prefix, i as u64 + 3, 0));
+        }
+        lsm.levels.write().update(Vec::new(), tables).unwrap();
+        lsm.next_seq_num.store(8, Ordering::SeqCst);
+        lsm.publish(7);
+
+        //the oldest level 0 table is compacted first and overlaps no level 1 table
+        lsm.may_schedule_compaction();
+        let now = Instant::now();
+        while lsm.levels.read().num_files_at_level(0) > 4 {
+            assert!(now.elapsed() < Duration::from_secs(10), "timed out waiting for the compaction");
+            thread::sleep(Duration::from_millis(10));
+        }
+        //it is moved down as it is, and the level 1 tables are left alone
+        assert_eq!(lsm.stats().compaction.moved_files, 1);
+        assert_eq!(lsm.levels.read().num_files_at_level(1), 3);
+        assert!(["1.sst", "2.sst", "3.sst"].iter().all(|file| dir.join(file).exists()));
+        for prefix in ["a", "c", "d", "e", "f", "g", "z"].iter() {
+            let key = format!("{}2", prefix).into_bytes();
+            assert_eq!(lsm.search(&key, None).unwrap(), Some(key.clone()));
+        }
+    }
+}
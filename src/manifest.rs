@@ -0,0 +1,143 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::env::Env;
+use crate::error::{Error, Result};
+use crate::fault;
+use crate::lsm::CompactionStyle;
+use crate::utils::*;
+
+//The MANIFEST is a snapshot of the live tables and the level each one belongs to.
+//It is rewritten as a whole after every change and replaced atomically by renaming,
+//so a level recorded here takes precedence over the one in the sst footer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub compaction_style: CompactionStyle, //fixed when the database is created
+    pub tables: Vec<(u64, usize)>, //file num, level
+    pub user_timestamp_size: usize, //fixed when the database is created as well, 0 in older manifests
+    pub num_levels: usize, //levels grow on demand, 0 in older manifests which had max_levels of them
+}
+
+impl Manifest {
+    pub fn file_name(db_path: &Path) -> PathBuf {
+        db_path.join("MANIFEST")
+    }
+
+    //None if there is no manifest yet
+    pub fn load(env: &dyn Env, db_path: &Path) -> Result<Option<Self>> {
+        let bytes = match env.read(&Self::file_name(db_path)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Self::decode_from(&bytes).map(Some)
+    }
+
+    pub fn save(&self, env: &dyn Env, db_path: &Path) -> io::Result<()> {
+        let manifest_file = Self::file_name(db_path);
+        let tmp_file = manifest_file.with_extension("tmp");
+        let mut file = env.create(&tmp_file)?;
+        fault::append(&mut *file, &self.encode_to(), &tmp_file)?;
+        fault::sync(&mut *file, &tmp_file)?;
+        drop(file);
+        fault::rename(env, &tmp_file, &manifest_file)?;
+        fault::sync_dir(env, db_path)
+    }
+
+    pub fn level_of(&self, file_num: u64) -> Option<usize> {
+        self.tables.iter()
+            .find(|(num, _)| *num == file_num)
+            .map(|(_, level)| *level)
+    }
+
+    pub fn encode_to(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        let compaction_style = match self.compaction_style {
+            CompactionStyle::Leveled => 0u64,
+            CompactionStyle::Universal => 1,
+        };
+        res.extend_from_slice(&compaction_style.to_le_bytes());
+        res.extend_from_slice(&(self.tables.len() as u64).to_le_bytes());
+        for (file_num, level) in self.tables.iter() {
+            res.extend_from_slice(&file_num.to_le_bytes());
+            res.extend_from_slice(&(*level as u64).to_le_bytes());
+        }
+        res.extend_from_slice(&(self.user_timestamp_size as u64).to_le_bytes());
+        res.extend_from_slice(&(self.num_levels as u64).to_le_bytes());
+        res
+    }
+
+    pub fn decode_from(bytes: &[u8]) -> Result<Self> {
+        let mut offset = 0;
+        let compaction_style = match get_fixed64(bytes, &mut offset)? {
+            0 => CompactionStyle::Leveled,
+            1 => CompactionStyle::Universal,
+            style => return Err(Error::Corruption(format!("invalid compaction style {} in the manifest", style))),
+        };
+        let num_tables = get_fixed64(bytes, &mut offset)?;
+        let mut tables = Vec::new();
+        for _ in 0..num_tables {
+            let file_num = get_fixed64(bytes, &mut offset)?;
+            let level = to_len(get_fixed64(bytes, &mut offset)?)?;
+            tables.push((file_num, level));
+        }
+        let user_timestamp_size = match offset == bytes.len() {
+            true => 0,
+            false => to_len(get_fixed64(bytes, &mut offset)?)?,
+        };
+        let num_levels = match offset == bytes.len() {
+            true => 0,
+            false => to_len(get_fixed64(bytes, &mut offset)?)?,
+        };
+        Ok(Manifest {
+            compaction_style,
+            tables,
+            user_timestamp_size,
+            num_levels,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::StdEnv;
+    use crate::utils::temp_dir;
+
+    #[test]
+    fn manifest_roundtrip() {
+        let dir = temp_dir("manifest");
+        assert_eq!(Manifest::load(&StdEnv, &dir).unwrap(), None);
+        let manifest = Manifest {
+            compaction_style: CompactionStyle::Universal,
+            tables: vec![(3, 0), (7, 2), (12, 1)],
+            user_timestamp_size: 8,
+            num_levels: 4,
+        };
+        manifest.save(&StdEnv, &dir).unwrap();
+        assert_eq!(Manifest::load(&StdEnv, &dir).unwrap(), Some(manifest.clone()));
+        assert_eq!(manifest.level_of(7), Some(2));
+        assert_eq!(manifest.level_of(8), None);
+        //written before levels grew on demand, and before timestamps existed
+        let bytes = manifest.encode_to();
+        assert_eq!(Manifest::decode_from(&bytes[..bytes.len() - 8]).unwrap().num_levels, 0);
+        assert_eq!(Manifest::decode_from(&bytes[..bytes.len() - 16]).unwrap().user_timestamp_size, 0);
+    }
+
+    #[test]
+    fn truncated_manifest_is_reported() {
+        let manifest = Manifest {
+            compaction_style: CompactionStyle::Leveled,
+            tables: vec![(3, 0), (7, 2)],
+            user_timestamp_size: 0,
+            num_levels: 3,
+        };
+        let bytes = manifest.encode_to();
+        for len in 0..bytes.len() - 16 {
+            assert!(matches!(Manifest::decode_from(&bytes[..len]), Err(Error::Corruption(_))), "{} bytes", len);
+        }
+        let mut bytes = bytes;
+        bytes[0] = 9;
+        assert!(matches!(Manifest::decode_from(&bytes), Err(Error::Corruption(_))));
+    }
+}
@@ -1,17 +1,30 @@
 
 use std::collections::HashMap;
-use std::fs::remove_file;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::Arc;
 
-use crate::key::InternalKey;
-use crate::wal::{Log, LogEntry};
+use crate::env::Env;
+use crate::error::{Error, Result};
+use crate::key::{checked_value, verify_value_checksum, InternalKey, LookUpKey, ValueType, MAX_SEQ_NUM};
+use crate::utils::read_u64_exact;
+use crate::wal::{Log, LogEntry, WalRecordType};
+use crate::write_batch::WriteBatch;
 
+use bytes::Bytes;
 use skiplist::skipmap::SkipMap;
 
+//memory a skip list node takes besides the key and value bytes: the key and value handles and the links
+const ENTRY_OVERHEAD: usize = 128;
+
 pub struct MemTable {
-    pub inner: SkipMap<InternalKey, Vec<u8>>,
+    pub inner: SkipMap<InternalKey, Bytes>,
     writer: Option<Log>,
     pub size: usize,
+    pub num_deletions: usize,
+    pub write_times: Vec<(u64, u64)>, //seq num, unix millis; the first write of each millisecond
+    value_checksums: bool, //puts get a checksum of their key and value, logged and kept with the value
 }
 
 impl MemTable {
@@ -20,151 +33,319 @@ impl MemTable {
             inner: SkipMap::new(),
             writer: None,
             size: 0,
+            num_deletions: 0,
+            write_times: Vec::new(),
+            value_checksums: false,
         }
     }
 
-    pub fn take(&mut self) -> SkipMap<InternalKey, Vec<u8>> {
-        std::mem::take(&mut self.inner)
+    //the puts from now on carry value checksums, the entries already in it keep what they have
+    pub fn set_value_checksums(&mut self, value_checksums: bool) {
+        self.value_checksums = value_checksums;
     }
 
-    pub fn set_writer(&mut self, dir_path: &PathBuf, log_num: u64) {
+    pub fn set_writer(&mut self, env: &Arc<dyn Env>, dir_path: &Path, log_num: u64) -> Result<()> {
         if self.writer.is_none() {
-            let log = Log::open(dir_path, log_num);
+            let log = Log::open(env, dir_path, log_num)?;
             self.writer = Some(log);
         }
+        Ok(())
+    }
+
+    //the bytes logged from now on are added to `counter`
+    pub fn count_log_bytes(&mut self, counter: Arc<AtomicU64>) {
+        if let Some(log) = self.writer.as_mut() {
+            log.count_bytes_written(counter);
+        }
+    }
+
+    //the buffer the log encodes its records in is counted in `counter` until the log is removed
+    pub fn count_log_buffer(&mut self, counter: Arc<AtomicUsize>) {
+        if let Some(log) = self.writer.as_mut() {
+            log.count_buffer_bytes(counter);
+        }
+    }
+
+    //`size` is what the entries would take in a table, this adds the nodes holding them
+    pub fn memory_usage(&self) -> usize {
+        self.size + self.inner.len() * ENTRY_OVERHEAD
     }
 
-    pub fn remove_writer(&mut self) {
-        let log = self.writer.take().unwrap();
-        let path = log.get_path();
-        println!("remove writer {:?}", path);
-        drop(log);
-        remove_file(path).unwrap();
+    //the log and the length of it that holds this mem table
+    pub fn log_file(&self) -> Option<(PathBuf, u64)> {
+        self.writer.as_ref().map(|log| (log.get_path(), log.valid_len()))
     }
 
-    pub fn recover(&mut self, dir_path: &PathBuf, log_num: u64, trans: &mut HashMap<u64, Vec<LogEntry>>) -> u64 {
-        println!("begin to recover mem_table");
+    pub fn take_writer(&mut self) -> Option<Log> {
+        self.writer.take()
+    }
+
+    pub fn remove_writer(&mut self) -> io::Result<()> {
+        let log = self.writer.take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the mem table has no log"))?;
+        log.remove()
+    }
+
+    pub fn recover(&mut self, env: &Arc<dyn Env>, dir_path: &Path, log_num: u64, trans: &mut HashMap<u64, Vec<LogEntry>>, paranoid: bool) -> Result<u64> {
+        let mut log = Log::open(env, dir_path, log_num)?;
+        let log_entries = log.recover(paranoid)?;
+        let max_seq_num = self.apply(log_entries, trans, &log.get_path())?;
+        self.writer = Some(log);
+        Ok(max_seq_num)
+    }
+
+    //applies the records of a log, the entries of a transaction once it commits
+    pub fn apply(&mut self, log_entries: Vec<LogEntry>, trans: &mut HashMap<u64, Vec<LogEntry>>, log_path: &Path) -> Result<u64> {
         let mut max_seq_num = 0;
-        let mut log = Log::open(dir_path, log_num);
-        let log_entries = log.read();
-        println!("log entries = {:?}", log_entries);
-        //apply these entries to mem_table
         for entry in log_entries {
+            max_seq_num = std::cmp::max(max_seq_num, entry.seq_num);
+            let not_begun = || Error::Corruption(format!("{:?} of transaction {} before it began in {:?}", entry.entry_type, entry.seq_num, log_path));
             match entry.entry_type {
-                0 => {
-                    self.insert_inner(&entry.key, &entry.value, entry.seq_num, false);
-                    max_seq_num = std::cmp::max(max_seq_num, entry.seq_num);
+                WalRecordType::Put => {
+                    self.insert_inner(entry.key, entry.value, entry.seq_num, false);
+                },
+                WalRecordType::PutChecked => {
+                    self.insert_entry(entry.key, entry.value, entry.seq_num, ValueType::PutChecked);
                 },
-                1 => {
-                    self.delete_inner(&entry.key, entry.seq_num, false);
-                    max_seq_num = std::cmp::max(max_seq_num, entry.seq_num);
+                WalRecordType::Delete => {
+                    self.delete_inner(entry.key, entry.seq_num, false);
                 },
-                2 | 3 => {
-                    trans.get_mut(&entry.seq_num).unwrap().push(entry);
+                WalRecordType::TxPut | WalRecordType::TxPutChecked | WalRecordType::TxDelete => {
+                    trans.get_mut(&entry.seq_num).ok_or_else(not_begun)?.push(entry);
                 },
-                4 => {
+                WalRecordType::TxBegin => {
                     trans.insert(entry.seq_num, Vec::new());
                 }
-                5 => {
-                    for entry in trans.remove(&entry.seq_num).unwrap() {
-                        if entry.entry_type == 2 {
-                            self.insert_inner(&entry.key, &entry.value, entry.seq_num, true);
-                        } else {
-                            self.delete_inner(&entry.key, entry.seq_num, true);
+                WalRecordType::TxCommit => {
+                    for entry in trans.remove(&entry.seq_num).ok_or_else(not_begun)? {
+                        match entry.entry_type {
+                            WalRecordType::TxPut => self.insert_inner(entry.key, entry.value, entry.seq_num, true),
+                            WalRecordType::TxPutChecked => self.insert_entry(entry.key, entry.value, entry.seq_num, ValueType::TxPutChecked),
+                            _ => self.delete_inner(entry.key, entry.seq_num, true),
                         }
                     }
                 }, 
-                6 => {
+                WalRecordType::TxAbort => {
                     trans.remove(&entry.seq_num);
                 },
-                _ => panic!("invalid entry type"),
+                WalRecordType::WriteTime => {
+                    self.write_times.push((entry.seq_num, read_u64_exact(&entry.value)?));
+                },
+                WalRecordType::Batch => {
+                    let batch = WriteBatch::decode_from(&entry.value)?;
+                    max_seq_num = std::cmp::max(max_seq_num, entry.seq_num + batch.len().saturating_sub(1) as u64);
+                    self.apply_batch(&batch, entry.seq_num);
+                },
             };
         }
-        self.writer = Some(log);
-        max_seq_num
+        Ok(max_seq_num)
     }
 
-    pub fn begin_tx(&mut self, seq_num: u64) {
-        let log_entry = LogEntry {
-            entry_type: 4, 
-            key: Vec::new(),
-            value: Vec::new(),
+    //the entry goes to the log before the mem table takes it
+    fn log(&mut self, log_entry: LogEntry) -> Result<()> {
+        let writer = self.writer.as_mut()
+            .ok_or_else(|| Error::Io(io::Error::other("the mem table has no log")))?;
+        Ok(writer.write(log_entry)?)
+    }
+
+    pub fn begin_tx(&mut self, seq_num: u64) -> Result<()> {
+        self.log(LogEntry {
+            entry_type: WalRecordType::TxBegin,
+            key: Bytes::new(),
+            value: Bytes::new(),
             seq_num,
-        };
-        self.writer.as_mut().unwrap().write(log_entry).unwrap();
+        })
     }
 
-    pub fn commit_tx(&mut self, seq_num: u64) {
+    pub fn commit_tx(&mut self, seq_num: u64) -> Result<()> {
+        self.log(LogEntry {
+            entry_type: WalRecordType::TxCommit,
+            key: Bytes::new(),
+            value: Bytes::new(),
+            seq_num,
+        })
+    }
+
+    //`writes` are logged between the begin and commit records, the mem table takes them only once
+    //all of them are logged, so a failed write leaves nothing of the transaction to read. An empty value deletes.
+    pub fn write_tx<'a>(&mut self, seq_num: u64, writes: impl Iterator<Item = (&'a [u8], &'a [u8])>) -> Result<()> {
+        let writes = writes
+            .map(|(key, value)| match value.is_empty() {
+                true => self.delete_entry(key, seq_num, true),
+                false => self.put_entry(key, value, seq_num, true),
+            })
+            .collect::<Vec<_>>();
+        self.begin_tx(seq_num)?;
+        for (log_entry, _) in writes.iter() {
+            self.log(log_entry.clone())?;
+        }
+        self.commit_tx(seq_num)?;
+        for (log_entry, value_type) in writes {
+            match value_type {
+                ValueType::TxDelete => self.delete_inner(log_entry.key, seq_num, true),
+                value_type => self.insert_entry(log_entry.key, log_entry.value, seq_num, value_type),
+            }
+        }
+        Ok(())
+    }
+
+    //the write with `seq_num` is the first one made at `millis`
+    pub fn record_write_time(&mut self, seq_num: u64, millis: u64) -> Result<()> {
         let log_entry = LogEntry {
-            entry_type: 5, 
-            key: Vec::new(),
-            value: Vec::new(),
+            entry_type: WalRecordType::WriteTime,
+            key: Bytes::new(),
+            value: Bytes::copy_from_slice(&millis.to_le_bytes()),
             seq_num,
         };
-        self.writer.as_mut().unwrap().write(log_entry).unwrap();
+        self.log(log_entry)?;
+        self.write_times.push((seq_num, millis));
+        Ok(())
+    }
+
+    //the key and value are copied once, the log entry and the mem table share them
+    pub fn insert(&mut self, key: &[u8], value: &[u8], seq_num: u64, is_tx: bool) -> Result<()> {
+        let (log_entry, value_type) = self.put_entry(key, value, seq_num, is_tx);
+        self.log(log_entry.clone())?;
+        self.insert_entry(log_entry.key, log_entry.value, seq_num, value_type);
+        Ok(())
     }
 
-    pub fn insert(&mut self, key: &[u8], value: &[u8], seq_num: u64, is_tx: bool) {
+    fn put_entry(&self, key: &[u8], value: &[u8], seq_num: u64, is_tx: bool) -> (LogEntry, ValueType) {
+        let (value, entry_type, value_type) = match (self.value_checksums, is_tx) {
+            (true, true) => (Bytes::from(checked_value(key, value)), WalRecordType::TxPutChecked, ValueType::TxPutChecked),
+            (true, false) => (Bytes::from(checked_value(key, value)), WalRecordType::PutChecked, ValueType::PutChecked),
+            (false, true) => (Bytes::copy_from_slice(value), WalRecordType::TxPut, ValueType::TxPut),
+            (false, false) => (Bytes::copy_from_slice(value), WalRecordType::Put, ValueType::Put),
+        };
         let log_entry = LogEntry {
-            entry_type: match is_tx {
-                true => 2,
-                false => 0,
-            }, 
-            key: key.to_vec(),
-            value: value.to_vec(),
+            entry_type,
+            key: Bytes::copy_from_slice(key),
+            value,
             seq_num,
         };
-        self.writer.as_mut()
-            .unwrap()
-            .write(log_entry)
-            .unwrap();
-        self.insert_inner(key, value, seq_num, is_tx);
+        (log_entry, value_type)
     }
 
-    pub fn insert_inner(&mut self, key: &[u8], value: &[u8], seq_num: u64, is_tx: bool) {
-        let internal_key = if is_tx {
-            InternalKey::new(key, seq_num,2)
-        } else {
-            InternalKey::new(key, seq_num,0)
-        };
-        self.inner.insert(internal_key, value.to_vec());
+    pub fn insert_inner(&mut self, key: Bytes, value: Bytes, seq_num: u64, is_tx: bool) {
+        let value_type = if is_tx { ValueType::TxPut } else { ValueType::Put };
+        self.insert_entry(key, value, seq_num, value_type);
+    }
+
+    //the value of a checked put ends with its checksum
+    fn insert_entry(&mut self, key: Bytes, value: Bytes, seq_num: u64, value_type: ValueType) {
         self.size += 8 + key.len() + value.len();   //size of internal key + size of value
+        self.inner.insert(InternalKey::from_bytes(key, seq_num, value_type), value);
+    }
+
+    pub fn delete(&mut self, key: &[u8], seq_num: u64, is_tx: bool) -> Result<()> {
+        let (log_entry, _) = self.delete_entry(key, seq_num, is_tx);
+        self.log(log_entry.clone())?;
+        self.delete_inner(log_entry.key, seq_num, is_tx);
+        Ok(())
     }
 
-    pub fn delete(&mut self, key: &[u8], seq_num: u64, is_tx: bool) {
+    fn delete_entry(&self, key: &[u8], seq_num: u64, is_tx: bool) -> (LogEntry, ValueType) {
+        let (entry_type, value_type) = match is_tx {
+            true => (WalRecordType::TxDelete, ValueType::TxDelete),
+            false => (WalRecordType::Delete, ValueType::Delete),
+        };
         let log_entry = LogEntry {
-            entry_type: match is_tx {
-                true => 3,
-                false => 1,
-            }, 
-            key: key.to_vec(),
-            value: Vec::new(),
+            entry_type,
+            key: Bytes::copy_from_slice(key),
+            value: Bytes::new(),
             seq_num,
         };
-        self.writer.as_mut().unwrap().write(log_entry).unwrap();
-        self.delete_inner(key, seq_num, is_tx);
+        (log_entry, value_type)
+    }
+
+    //the batch is logged as one record, so recovery applies all of it or nothing
+    pub fn write_batch(&mut self, batch: &WriteBatch, first_seq_num: u64) -> Result<()> {
+        let checked;
+        let batch = match self.value_checksums {
+            true => {
+                checked = batch.with_value_checksums();
+                &checked
+            },
+            false => batch,
+        };
+        let log_entry = LogEntry {
+            entry_type: WalRecordType::Batch,
+            key: Bytes::new(),
+            value: Bytes::from(batch.encode_to()),
+            seq_num: first_seq_num,
+        };
+        self.log(log_entry)?;
+        self.apply_batch(batch, first_seq_num);
+        Ok(())
+    }
+
+    fn apply_batch(&mut self, batch: &WriteBatch, first_seq_num: u64) {
+        let put_type = if batch.has_value_checksums() { ValueType::PutChecked } else { ValueType::Put };
+        for ((key, value), seq_num) in batch.iter().zip(first_seq_num..) {
+            match value {
+                Some(value) => self.insert_entry(key.clone(), value.clone(), seq_num, put_type),
+                None => self.delete_inner(key.clone(), seq_num, false),
+            }
+        }
     }
 
-    pub fn delete_inner(&mut self, key: &[u8], seq_num: u64, is_tx: bool) {
+    pub fn delete_inner(&mut self, key: Bytes, seq_num: u64, is_tx: bool) {
+        self.size += 8 + key.len();
+        self.num_deletions += 1;
         let internal_key = if is_tx {
-            InternalKey::new(key, seq_num,3)
+            InternalKey::from_bytes(key, seq_num, ValueType::TxDelete)
         } else {
-            InternalKey::new(key, seq_num,1)
+            InternalKey::from_bytes(key, seq_num, ValueType::Delete)
         };
-        self.inner.insert(internal_key, Vec::new());
-        self.size += 8 + key.len();
+        self.inner.insert(internal_key, Bytes::new());
+    }
+
+    pub fn search(&self, key: &[u8], seq_num: u64) -> Option<Option<Bytes>> {
+        self.search_with(key, seq_num, false).expect("a mem table holds whole checked values")
+    }
+
+    //the value without its checksum, with `verify` a value that does not match the checksum is an error
+    pub fn search_with(&self, key: &[u8], seq_num: u64, verify: bool) -> Result<Option<Option<Bytes>>> {
+        let internal_key = InternalKey::new(key, std::cmp::min(seq_num, MAX_SEQ_NUM), ValueType::Delete);
+        let (found, value) = match self.inner.iter().find(|kv| kv.0 >= &internal_key && &kv.0.user_key[..] == key) {
+            Some(kv) => kv,
+            None => return Ok(None),
+        };
+        if found.get_type().is_deletion() {
+            return Ok(Some(None));
+        }
+        let (value, checksum) = found.split_value(value.clone())?;
+        if verify {
+            verify_value_checksum(key, &value, checksum)?;
+        }
+        Ok(Some(Some(value)))
     }
 
-    pub fn search(&self, key: &[u8], seq_num: u64) -> Option<Option<Vec<u8>>> {
-        let internal_key = InternalKey::new(key, seq_num, 1);
+    //the entries with a user key starting with `prefix`, in key order
+    pub fn prefix_iter<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = (LookUpKey, Bytes)> + 'a {
         self.inner.iter()
-            .find(|kv| kv.0 >= &internal_key && &kv.0.user_key[..] == key)
-            .map(|kv| {
-                match kv.0.get_type() == 0 || kv.0.get_type() == 2 {   
-                    true => Some(kv.1.clone()),  //insert
-                    false => None                //delete
-                }
-            })
+            .skip_while(move |(k, _)| &k.user_key[..] < prefix)
+            .take_while(move |(k, _)| k.user_key.starts_with(prefix))
+            .map(|(k, v)| (LookUpKey::new(k.clone()), v.clone()))
+    }
+
+    //the first entry not less than `key`
+    pub fn seek(&self, key: &InternalKey) -> Option<(InternalKey, Bytes)> {
+        self.inner.iter()
+            .find(|kv| kv.0 >= key)
+            .map(|(k, v)| (k.clone(), v.clone()))
+    }
+
+    //flips a bit in the newest value of `key`, as a bug copying it around would
+    #[cfg(test)]
+    pub fn corrupt_value(&mut self, key: &[u8]) {
+        let (found, value) = self.inner.iter()
+            .find(|(k, _)| &k.user_key[..] == key)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .expect("no value to corrupt");
+        let mut value = value.to_vec();
+        value[0] ^= 1;
+        self.inner.insert(found, Bytes::from(value));
     }
 
 }
\ No newline at end of file
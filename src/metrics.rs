@@ -0,0 +1,3 @@
+//! Exporters of the statistics of a database to monitoring systems.
+
+pub mod prometheus;
@@ -0,0 +1,328 @@
+//! The statistics of a database in the Prometheus text exposition format, rendered by
+//! `LsmDb::prometheus_metrics` and served on `/metrics` by `serve_metrics`.
+//!
+//! Every metric is named `draftkv_...` and keeps its name; the ones per level carry a `level` label,
+//! the latencies an `op` label. Counters count since the database was opened.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::histogram::Histogram;
+use crate::lsm::LsmDb;
+
+use parking_lot::Mutex;
+
+//the upper bounds of the latency buckets, in seconds
+const LATENCY_BOUNDS: [f64; 12] = [0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+//a scraper that stops sending its request is given up on after this long
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+//the families in the order they are written
+struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP draftkv_{} {}\n# TYPE draftkv_{} {}", name, help, name, kind);
+    }
+
+    fn sample<V: std::fmt::Display>(&mut self, name: &str, labels: &[(&str, &str)], value: V) {
+        let _ = write!(self.out, "draftkv_{}", name);
+        if !labels.is_empty() {
+            let labels = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, v)).collect::<Vec<_>>();
+            let _ = write!(self.out, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    //a family of a single sample without labels
+    fn single<V: std::fmt::Display>(&mut self, name: &str, kind: &str, help: &str, value: V) {
+        self.family(name, kind, help);
+        self.sample(name, &[], value);
+    }
+
+    fn histogram(&mut self, name: &str, op: &str, histogram: &Histogram) {
+        let bounds = LATENCY_BOUNDS.iter().map(|s| Duration::from_secs_f64(*s)).collect::<Vec<_>>();
+        let (cumulative, count, sum) = histogram.cumulative_counts(&bounds);
+        for (bound, n) in LATENCY_BOUNDS.iter().zip(cumulative) {
+            self.sample(&format!("{}_bucket", name), &[("op", op), ("le", &bound.to_string())], n);
+        }
+        self.sample(&format!("{}_bucket", name), &[("op", op), ("le", "+Inf")], count);
+        self.sample(&format!("{}_sum", name), &[("op", op)], sum.as_secs_f64());
+        self.sample(&format!("{}_count", name), &[("op", op)], count);
+    }
+}
+
+impl LsmDb {
+    pub fn prometheus_metrics(&self) -> String {
+        let stats = self.stats();
+        let memory = self.memory_usage();
+        let mut e = Exposition { out: String::new() };
+
+        e.family("level_files", "gauge", "Tables in the level.");
+        for (level, stats) in stats.levels.iter().enumerate() {
+            e.sample("level_files", &[("level", &level.to_string())], stats.num_files);
+        }
+        e.family("level_bytes", "gauge", "Bytes of the tables in the level.");
+        for (level, stats) in stats.levels.iter().enumerate() {
+            e.sample("level_bytes", &[("level", &level.to_string())], stats.size_bytes);
+        }
+        e.family("level_score", "gauge", "Compaction score of the level, it is compacted past 1.");
+        for (level, stats) in stats.levels.iter().enumerate() {
+            e.sample("level_score", &[("level", &level.to_string())], stats.score);
+        }
+        //counted by the live tables, they go down when a compaction replaces the tables
+        e.family("level_filter_negatives", "gauge", "Lookups the key filters of the level ruled out.");
+        for (level, stats) in stats.levels.iter().enumerate() {
+            e.sample("level_filter_negatives", &[("level", &level.to_string())], stats.filter_negatives);
+        }
+        e.family("level_filter_false_positives", "gauge", "Lookups the key filters of the level let through that found nothing.");
+        for (level, stats) in stats.levels.iter().enumerate() {
+            e.sample("level_filter_false_positives", &[("level", &level.to_string())], stats.filter_false_positives);
+        }
+
+        let compaction = &stats.compaction;
+        e.single("compactions_total", "counter", "Compactions that changed the levels.", compaction.compactions);
+        e.single("compaction_moved_files_total", "counter", "Tables moved to the next level without a rewrite.", compaction.moved_files);
+        e.single("compaction_read_bytes_total", "counter", "Bytes read by compactions.", compaction.bytes_read);
+        e.single("compaction_written_bytes_total", "counter", "Bytes written by compactions.", compaction.bytes_written);
+        e.single("flushes_total", "counter", "Mem tables flushed to level 0.", compaction.flushes);
+        e.single("flush_written_bytes_total", "counter", "Bytes of the level 0 tables written by flushes.", compaction.flush_bytes_written);
+        e.single("tables_probed_total", "counter", "Tables whose key range covered the key of a search.", stats.tables_probed);
+        e.single("slow_ops_total", "counter", "Operations slower than the slow op threshold.", stats.slow_ops);
+        e.single("blocks_read_total", "counter", "Data blocks read from tables.", stats.blocks_read);
+        e.single("wal_written_bytes_total", "counter", "Bytes of log records appended.", stats.wal_bytes_written);
+        e.single("preloaded_bytes", "gauge", "Bytes of blocks read while opening.", stats.preloaded_bytes);
+        e.single("last_sequence_number", "gauge", "Sequence number of the last published write.", stats.last_seq_num);
+
+        let cache = &stats.block_cache;
+        e.single("block_cache_capacity_bytes", "gauge", "Capacity of the block cache.", cache.capacity);
+        e.single("block_cache_usage_bytes", "gauge", "Bytes of blocks in the block cache.", cache.usage);
+        e.single("block_cache_pinned_bytes", "gauge", "Bytes of blocks the block cache never evicts.", cache.pinned_usage);
+        e.single("block_cache_hits_total", "counter", "Lookups the block cache answered.", cache.hits);
+        e.single("block_cache_misses_total", "counter", "Lookups the block cache missed.", cache.misses);
+        e.single("block_cache_evictions_total", "counter", "Blocks evicted from the block cache.", cache.evictions);
+
+        e.family("memory_bytes", "gauge", "Bytes of memory held, by what holds them.");
+        for &(part, bytes) in [
+            ("mem_table", memory.mem_table),
+            ("im_mem_table", memory.im_mem_table),
+            ("block_cache", memory.block_cache),
+            ("pinned_blocks", memory.pinned_blocks),
+            ("index_and_filter_blocks", memory.index_and_filter_blocks),
+            ("tx_buffers", memory.tx_buffers),
+            ("wal_buffers", memory.wal_buffers),
+        ].iter() {
+            e.sample("memory_bytes", &[("part", part)], bytes);
+        }
+
+        let histograms = self.op_histograms();
+        e.family("op_duration_seconds", "histogram", "Latency of the operations that succeeded.");
+        for &(op, histogram) in [
+            ("get", &histograms.get),
+            ("put", &histograms.put),
+            ("delete", &histograms.delete),
+            ("write_batch", &histograms.write_batch),
+            ("tx_commit", &histograms.tx_commit),
+            ("flush", &histograms.flush),
+            ("compaction", &histograms.compaction),
+        ].iter() {
+            e.histogram("op_duration_seconds", op, histogram);
+        }
+        e.out
+    }
+}
+
+/// Answers `GET /metrics` with `LsmDb::prometheus_metrics`, until dropped or the database is closed.
+/// Requests are answered one at a time on a thread of its own.
+pub struct MetricsServer {
+    state: Arc<State>,
+}
+
+struct State {
+    addr: SocketAddr,
+    stopping: AtomicBool,
+    acceptor: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl State {
+    fn stop(&self) {
+        if self.stopping.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        //accept() only returns for a connection, so one is made to wake it
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(addr);
+        if let Some(acceptor) = self.acceptor.lock().take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+impl MetricsServer {
+    //the address it listens on, with the port picked if it was bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.state.addr
+    }
+
+    pub fn shutdown(&self) {
+        self.state.stop();
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.state.stop();
+    }
+}
+
+pub fn serve_metrics<A: ToSocketAddrs>(addr: A, db: Arc<LsmDb>) -> Result<MetricsServer> {
+    let listener = TcpListener::bind(addr)?;
+    let state = Arc::new(State {
+        addr: listener.local_addr()?,
+        stopping: AtomicBool::new(false),
+        acceptor: Mutex::new(None),
+    });
+    let acceptor = {
+        let (state, db) = (state.clone(), db.clone());
+        thread::Builder::new()
+            .name("kv-metrics".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if state.stopping.load(Ordering::Acquire) {
+                        break;
+                    }
+                    if let Err(e) = stream.and_then(|stream| answer(stream, &db)) {
+                        log::warn!("failed to answer a metrics request: {}", e);
+                    }
+                }
+            })?
+    };
+    *state.acceptor.lock() = Some(acceptor);
+    let hook_state = state.clone();
+    db.on_close(Box::new(move || hook_state.stop()));
+    Ok(MetricsServer { state })
+}
+
+//reads the request line and headers, the body of a GET is empty
+fn answer(stream: TcpStream, db: &LsmDb) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header != "\r\n" && header != "\n" {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next().map(|p| p.split('?').next().unwrap_or(p))) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4; charset=utf-8", db.prometheus_metrics()),
+        (Some("GET"), _) => ("404 Not Found", "text/plain; charset=utf-8", "metrics are on /metrics\n".to_owned()),
+        _ => ("405 Method Not Allowed", "text/plain; charset=utf-8", "only GET is answered\n".to_owned()),
+    };
+    let mut stream = stream;
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::Config;
+    use crate::utils::temp_dir;
+
+    use std::collections::HashMap;
+    use std::io::Read;
+
+    //the samples by name with their labels, and the type of each family
+    fn parse(text: &str) -> (HashMap<String, f64>, HashMap<String, String>) {
+        let (mut samples, mut types) = (HashMap::new(), HashMap::new());
+        for line in text.lines() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let mut parts = rest.split(' ');
+                types.insert(parts.next().unwrap().to_owned(), parts.next().unwrap().to_owned());
+            } else if !line.starts_with('#') {
+                let (name, value) = line.rsplit_once(' ').unwrap();
+                assert!(name.starts_with("draftkv_"), "{}", line);
+                samples.insert(name.to_owned(), value.parse::<f64>().unwrap_or_else(|_| panic!("{}", line)));
+            }
+        }
+        (samples, types)
+    }
+
+    #[test]
+    fn metrics_are_typed_and_move_with_a_workload() {
+        let db = LsmDb::with_config(temp_dir("prometheus_workload"), Config::new()).unwrap();
+        let (before, types) = parse(&db.prometheus_metrics());
+        for (family, kind) in [("draftkv_level_files", "gauge"), ("draftkv_flushes_total", "counter"),
+            ("draftkv_memory_bytes", "gauge"), ("draftkv_op_duration_seconds", "histogram")].iter() {
+            assert_eq!(types.get(*family).map(String::as_str), Some(*kind), "{}", family);
+        }
+        assert_eq!(before["draftkv_level_files{level=\"0\"}"], 0.0);
+        assert_eq!(before["draftkv_op_duration_seconds_count{op=\"put\"}"], 0.0);
+
+        for i in 0..100 {
+            db.insert(format!("key{:03}", i).as_bytes(), &[b'v'; 100]).unwrap();
+        }
+        let (samples, _) = parse(&db.prometheus_metrics());
+        assert!(samples["draftkv_memory_bytes{part=\"mem_table\"}"] > before["draftkv_memory_bytes{part=\"mem_table\"}"]);
+        assert!(samples["draftkv_wal_written_bytes_total"] > 10_000.0);
+        db.flush().unwrap();
+        for i in 0..10 {
+            db.search(format!("key{:03}", i).as_bytes(), None).unwrap();
+        }
+
+        let (after, _) = parse(&db.prometheus_metrics());
+        assert_eq!(after["draftkv_level_files{level=\"0\"}"], 1.0);
+        assert!(after["draftkv_level_bytes{level=\"0\"}"] > 10_000.0);
+        assert_eq!(after["draftkv_flushes_total"], 1.0);
+        assert_eq!(after["draftkv_last_sequence_number"], 100.0);
+        assert_eq!(after["draftkv_op_duration_seconds_count{op=\"put\"}"], 100.0);
+        assert_eq!(after["draftkv_op_duration_seconds_count{op=\"get\"}"], 10.0);
+        assert!(after["draftkv_op_duration_seconds_sum{op=\"put\"}"] > 0.0);
+        //the buckets are cumulative and end with every operation
+        let buckets = LATENCY_BOUNDS.iter().map(|b| b.to_string()).chain(Some("+Inf".to_owned()))
+            .map(|le| after[&format!("draftkv_op_duration_seconds_bucket{{op=\"put\",le=\"{}\"}}", le)])
+            .collect::<Vec<_>>();
+        assert!(buckets.windows(2).all(|w| w[0] <= w[1]), "{:?}", buckets);
+        assert_eq!(buckets.last(), Some(&100.0));
+    }
+
+    fn http_get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn the_listener_answers_metrics_until_the_database_closes() {
+        let db = Arc::new(LsmDb::with_config(temp_dir("prometheus_listener"), Config::new()).unwrap());
+        let server = serve_metrics("127.0.0.1:0", db.clone()).unwrap();
+        db.insert(b"k", b"v").unwrap();
+        let response = http_get(server.local_addr(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        assert_eq!(parse(body).0["draftkv_op_duration_seconds_count{op=\"put\"}"], 1.0);
+        assert!(http_get(server.local_addr(), "/").starts_with("HTTP/1.1 404"));
+
+        db.close().unwrap();
+        assert!(TcpStream::connect(server.local_addr()).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::env::Env;
+use crate::error::{Error, Result};
+use crate::fault;
+use crate::lsm::Config;
+
+//The OPTIONS-<n> file records the configuration the database was last opened with, one name=value per line.
+//A new one is written on every open and replaces the older ones.
+pub type Options = BTreeMap<String, String>;
+
+//baked into the data on disk, a database can not be opened with other values
+const IMMUTABLE: [&str; 2] = ["compaction_style", "user_timestamp_size"];
+
+pub fn from_config(config: &Config) -> Options {
+    let options = vec![
+        ("block_size", config.block_size.to_string()),
+        ("l0_compaction_threshold", config.l0_compaction_threshold.to_string()),
+        ("l0_intra_compaction_threshold", config.l0_intra_compaction_threshold.to_string()),
+        ("level0_slowdown_writes_trigger", config.level0_slowdown_writes_trigger.to_string()),
+        ("level0_stop_writes_trigger", config.level0_stop_writes_trigger.to_string()),
+        ("l1_max_bytes", config.l1_max_bytes.to_string()),
+        ("level_size_multiplier", config.level_size_multiplier.to_string()),
+        ("level_max_bytes", config.level_max_bytes.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(",")),
+        ("max_levels", config.max_levels.to_string()),
+        ("target_file_size_base", config.target_file_size_base.to_string()),
+        ("target_file_size_multiplier", config.target_file_size_multiplier.to_string()),
+        ("write_buffer_size", config.write_buffer_size.to_string()),
+        ("max_subcompactions", config.max_subcompactions.to_string()),
+        ("max_background_compactions", config.max_background_compactions.to_string()),
+        ("read_parallelism", config.read_parallelism.to_string()),
+        ("compaction_rate_limit_bytes_per_sec", config.compaction_rate_limit_bytes_per_sec.to_string()),
+        ("compaction_style", format!("{:?}", config.compaction_style)),
+        ("universal_size_ratio", config.universal_size_ratio.to_string()),
+        ("tombstone_compaction_ratio", config.tombstone_compaction_ratio.to_string()),
+        ("max_background_retries", config.max_background_retries.to_string()),
+        ("read_only_on_background_error", config.read_only_on_background_error.to_string()),
+        ("user_timestamp_size", config.user_timestamp_size.to_string()),
+        ("record_write_time", config.record_write_time.to_string()),
+        ("prefix_extractor", config.prefix_extractor.as_ref().map_or(String::new(), |e| e.name().to_owned())),
+        ("bloom_bits_per_key", config.bloom_bits_per_key.to_string()),
+        ("bloom_bits_per_key_per_level", config.bloom_bits_per_key_per_level.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(",")),
+        ("value_log_threshold", config.value_log_threshold.to_string()),
+        ("value_log_gc_ratio", config.value_log_gc_ratio.to_string()),
+        ("value_checksums", config.value_checksums.to_string()),
+        ("strict_file_names", config.strict_file_names.to_string()),
+        ("paranoid_checks", config.paranoid_checks.to_string()),
+        ("readahead_size", config.readahead_size.to_string()),
+        ("evict_compaction_output_from_page_cache", config.evict_compaction_output_from_page_cache.to_string()),
+        ("preallocate_sst", config.preallocate_sst.to_string()),
+        ("block_cache_size", config.block_cache_size.to_string()),
+        ("block_cache_shard_bits", config.block_cache_shard_bits.to_string()),
+        ("pin_l0_blocks", config.pin_l0_blocks.to_string()),
+        ("pin_index_and_filter_blocks", config.pin_index_and_filter_blocks.to_string()),
+        ("preload_on_open", format!("{:?}", config.preload_on_open)),
+        ("preload_budget_bytes", config.preload_budget_bytes.to_string()),
+        ("slow_op_threshold_micros", config.slow_op_threshold.map_or(String::new(), |t| t.as_micros().to_string())),
+        ("stats_dump_period_millis", config.stats_dump_period.map_or(String::new(), |p| p.as_millis().to_string())),
+    ];
+    options.into_iter().map(|(name, value)| (name.to_owned(), value)).collect()
+}
+
+//the options of the last open, the settings that can change are taken from `new`
+pub fn check_immutable(persisted: &Options, new: &Options) -> Result<()> {
+    for name in IMMUTABLE.iter() {
+        match (persisted.get(*name), new.get(*name)) {
+            (Some(old), Some(new)) if old != new => return Err(Error::InvalidArgument(format!(
+                "database was created with {} {}, but {} is configured", name, old, new))),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+//the numbered options files in the directory, oldest first
+fn options_files(env: &dyn Env, db_path: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for path in env.list_dir(db_path)? {
+        let num = path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("OPTIONS-"))
+            .and_then(|num| num.parse::<u64>().ok());
+        if let Some(num) = num {
+            files.push((num, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+//the number and options of the newest file, None if there is none yet
+pub fn load_latest(env: &dyn Env, db_path: &Path) -> Result<Option<(u64, Options)>> {
+    let (num, path) = match options_files(env, db_path)?.pop() {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    let text = env.read(&path)?;
+    Ok(Some((num, decode_from(&text)?)))
+}
+
+//written as OPTIONS-<num>, then the older files are removed
+pub fn save(env: &dyn Env, db_path: &Path, num: u64, options: &Options) -> io::Result<()> {
+    let options_file = db_path.join(format!("OPTIONS-{}", num));
+    let tmp_file = options_file.with_extension("tmp");
+    let mut file = env.create(&tmp_file)?;
+    fault::append(&mut *file, &encode_to(options), &tmp_file)?;
+    fault::sync(&mut *file, &tmp_file)?;
+    drop(file);
+    fault::rename(env, &tmp_file, &options_file)?;
+    for (_, path) in options_files(env, db_path)?.into_iter().filter(|(n, _)| *n < num) {
+        fault::remove_file(env, &path)?;
+    }
+    fault::sync_dir(env, db_path)
+}
+
+pub fn encode_to(options: &Options) -> Vec<u8> {
+    options.iter()
+        .map(|(name, value)| format!("{}={}\n", name, value))
+        .collect::<String>()
+        .into_bytes()
+}
+
+pub fn decode_from(bytes: &[u8]) -> Result<Options> {
+    let text = std::str::from_utf8(bytes)
+        .map_err(|_| Error::Corruption("options file is not valid utf-8".to_owned()))?;
+    text.lines()
+        .map(|line| match line.split_once('=') {
+            Some((name, value)) => Ok((name.to_owned(), value.to_owned())),
+            None => Err(Error::Corruption(format!("invalid line {:?} in the options file", line))),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::StdEnv;
+    use crate::lsm::CompactionStyle;
+    use crate::utils::temp_dir;
+
+    #[test]
+    fn only_the_latest_options_are_kept() {
+        let dir = temp_dir("options");
+        assert_eq!(load_latest(&StdEnv, &dir).unwrap(), None);
+        let mut config = Config::new();
+        save(&StdEnv, &dir, 1, &from_config(&config)).unwrap();
+        config.block_size = 1024;
+        save(&StdEnv, &dir, 2, &from_config(&config)).unwrap();
+        let (num, options) = load_latest(&StdEnv, &dir).unwrap().unwrap();
+        assert_eq!((num, options.get("block_size").map(|s| s.as_str())), (2, Some("1024")));
+        assert!(!dir.join("OPTIONS-1").exists());
+        assert_eq!(decode_from(&encode_to(&options)).unwrap(), options);
+        assert!(matches!(decode_from(b"no separator\n"), Err(Error::Corruption(_))));
+
+        //tunables may change, what is baked into the data may not
+        check_immutable(&options, &from_config(&Config::new())).unwrap();
+        config.compaction_style = CompactionStyle::Universal;
+        assert!(matches!(check_immutable(&options, &from_config(&config)), Err(Error::InvalidArgument(_))));
+    }
+}
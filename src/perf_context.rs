@@ -0,0 +1,127 @@
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+//Where the lookups of one thread spent their time, counted only on threads that enabled it.
+//While no thread has it enabled, recording is a single relaxed load and branch.
+static ENABLED_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    static CONTEXT: RefCell<PerfContext> = RefCell::new(PerfContext::default());
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PerfContext {
+    pub mem_table_probes: u64,  //the mutable and the immutable mem table count one each
+    pub mem_table_time: Duration,
+    pub table_probes: Vec<(usize, u64)>,  //level and file number of each table searched, in order
+    pub table_time: Duration,
+    pub blocks_read: u64,  //data blocks read from the files
+    pub block_cache_hits: u64,
+}
+
+//starts counting on the calling thread from zero
+pub fn enable() {
+    if !ENABLED.with(|e| e.replace(true)) {
+        ENABLED_THREADS.fetch_add(1, Ordering::Relaxed);
+    }
+    reset();
+}
+
+pub fn disable() {
+    if ENABLED.with(|e| e.replace(false)) {
+        ENABLED_THREADS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn reset() {
+    CONTEXT.with(|c| *c.borrow_mut() = PerfContext::default());
+}
+
+//what the calling thread counted since it was enabled or reset
+pub fn get() -> PerfContext {
+    CONTEXT.with(|c| c.borrow().clone())
+}
+
+#[inline]
+pub(crate) fn enabled() -> bool {
+    ENABLED_THREADS.load(Ordering::Relaxed) != 0 && ENABLED.with(|e| e.get())
+}
+
+#[inline]
+pub(crate) fn record<F: FnOnce(&mut PerfContext)>(f: F) {
+    if enabled() {
+        CONTEXT.with(|c| f(&mut c.borrow_mut()));
+    }
+}
+
+//runs `f` counting on the calling thread and returns what it counted, a thread that enabled
+//counting itself goes on with its own context as if nothing happened
+pub(crate) fn measure<T, F: FnOnce() -> T>(f: F) -> (T, PerfContext) {
+    let was_enabled = ENABLED.with(|e| e.get());
+    let before = match was_enabled {
+        true => get(),
+        false => {
+            enable();
+            PerfContext::default()
+        },
+    };
+    let res = f();
+    let counted = get().since(&before);
+    if !was_enabled {
+        disable();
+        reset();
+    }
+    (res, counted)
+}
+
+impl PerfContext {
+    //what was counted after `earlier`, taken from the same thread
+    fn since(mut self, earlier: &PerfContext) -> PerfContext {
+        self.mem_table_probes -= earlier.mem_table_probes;
+        self.mem_table_time -= earlier.mem_table_time;
+        self.table_probes.drain(..earlier.table_probes.len());
+        self.table_time -= earlier.table_time;
+        self.blocks_read -= earlier.blocks_read;
+        self.block_cache_hits -= earlier.block_cache_hits;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_enabled_threads_count() {
+        record(|c| c.blocks_read += 1);
+        enable();
+        record(|c| c.blocks_read += 1);
+        std::thread::spawn(|| {
+            record(|c| c.blocks_read += 1);
+            assert_eq!(get(), PerfContext::default());
+        }).join().unwrap();
+        assert_eq!(get().blocks_read, 1);
+        disable();
+        record(|c| c.blocks_read += 1);
+        assert_eq!(get().blocks_read, 1);
+        enable();
+        assert_eq!(get(), PerfContext::default());
+        disable();
+    }
+
+    #[test]
+    fn measuring_leaves_the_context_of_the_thread_alone() {
+        let ((), counted) = measure(|| record(|c| c.blocks_read += 2));
+        assert_eq!(counted.blocks_read, 2);
+        assert!(!enabled());
+        enable();
+        record(|c| c.table_probes.push((0, 1)));
+        let ((), counted) = measure(|| record(|c| c.table_probes.push((1, 2))));
+        assert_eq!(counted.table_probes, vec![(1, 2)]);
+        assert!(enabled());
+        assert_eq!(get().table_probes, vec![(0, 1), (1, 2)]);
+        disable();
+    }
+}
@@ -0,0 +1,32 @@
+/// Maps a key to the prefix that table filters and prefix scans work on, such as the tenant id
+/// at the start of every key. Keys without a prefix are left out of the filters.
+pub trait PrefixExtractor: Send + Sync {
+    //recorded in every table, the filter of a table built by another extractor is not used
+    fn name(&self) -> &str;
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]>;
+}
+
+//the first `len` bytes, shorter keys have no prefix
+pub struct FixedPrefix {
+    len: usize,
+    name: String,
+}
+
+impl FixedPrefix {
+    pub fn new(len: usize) -> Self {
+        FixedPrefix {
+            len,
+            name: format!("fixed:{}", len),
+        }
+    }
+}
+
+impl PrefixExtractor for FixedPrefix {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        key.get(..self.len)
+    }
+}
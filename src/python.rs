@@ -0,0 +1,281 @@
+//! The `draft_kv` Python module, built with `maturin develop` (see pyproject.toml).
+//!
+//! Keys and values are `bytes` both ways. Every call that may block on the disk or on another writer
+//! releases the GIL, so Python threads keep running through a stall or a transaction lock held elsewhere.
+//! `InvalidArgument` is raised as ValueError, `Io` as OSError and `TimedOut` as TimeoutError, the other errors
+//! as subclasses of `draft_kv.DraftKvError`.
+
+//the macros of pyo3 0.22 check a feature of their own crate in this one and convert PyErr into itself
+#![allow(unexpected_cfgs, clippy::useless_conversion)]
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyList, PyTuple};
+
+use crate::error::Error;
+use crate::lsm::{Config, LsmDb};
+use crate::write_batch::WriteBatch;
+
+create_exception!(draft_kv, DraftKvError, PyException);
+create_exception!(draft_kv, CorruptionError, DraftKvError);
+create_exception!(draft_kv, BackgroundError, DraftKvError);
+create_exception!(draft_kv, SequenceExhaustedError, DraftKvError);
+create_exception!(draft_kv, DbLockedError, DraftKvError);
+create_exception!(draft_kv, ReadOnlyError, DraftKvError);
+create_exception!(draft_kv, ClosedError, DraftKvError);
+
+impl From<Error> for PyErr {
+    fn from(e: Error) -> Self {
+        let msg = e.to_string();
+        match e {
+            //the errno picks the subclass, FileNotFoundError and the like
+            Error::Io(e) => e.into(),
+            Error::InvalidArgument(_) => PyValueError::new_err(msg),
+            Error::Corruption(_) => CorruptionError::new_err(msg),
+            Error::Background(_) => BackgroundError::new_err(msg),
+            Error::SequenceExhausted => SequenceExhaustedError::new_err(msg),
+            Error::DbLocked { .. } => DbLockedError::new_err(msg),
+            Error::ReadOnly => ReadOnlyError::new_err(msg),
+            Error::Closed => ClosedError::new_err(msg),
+            Error::TimedOut => PyTimeoutError::new_err(msg),
+        }
+    }
+}
+
+/// Db(path, read_only=False) opens or creates the database in `path`.
+#[pyclass(module = "draft_kv")]
+struct Db {
+    db: Option<Arc<LsmDb>>, //None once closed
+}
+
+#[pymethods]
+impl Db {
+    #[new]
+    #[pyo3(signature = (path, read_only = false))]
+    fn open(py: Python<'_>, path: PathBuf, read_only: bool) -> PyResult<Self> {
+        let db = py.allow_threads(|| match read_only {
+            true => LsmDb::open_read_only(path, Config::new()),
+            false => LsmDb::with_config(path, Config::new()),
+        })?;
+        Ok(Db { db: Some(Arc::new(db)) })
+    }
+
+    fn get<'py>(&self, py: Python<'py>, key: &[u8]) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let db = self.db()?;
+        let value = py.allow_threads(|| db.search(key, None))?;
+        Ok(value.map(|value| PyBytes::new_bound(py, &value)))
+    }
+
+    fn put(&self, py: Python<'_>, key: &[u8], value: &[u8]) -> PyResult<()> {
+        let db = self.db()?;
+        Ok(py.allow_threads(|| db.insert(key, value))?)
+    }
+
+    fn delete(&self, py: Python<'_>, key: &[u8]) -> PyResult<()> {
+        let db = self.db()?;
+        Ok(py.allow_threads(|| db.delete(key))?)
+    }
+
+    //a list of (key, value) tuples in key order
+    #[pyo3(signature = (prefix = None, limit = None))]
+    fn scan<'py>(&self, py: Python<'py>, prefix: Option<&[u8]>, limit: Option<usize>) -> PyResult<Bound<'py, PyList>> {
+        let db = self.db()?;
+        let prefix = prefix.unwrap_or_default();
+        let entries = py.allow_threads(|| db.scan_prefix_from(prefix, prefix, limit.unwrap_or(usize::MAX)))?;
+        Ok(entries_to_list(py, &entries))
+    }
+
+    //`ops` is a list of ("put", key, value) and ("delete", key) tuples, applied together
+    fn write_batch(&self, py: Python<'_>, ops: &Bound<'_, PyAny>) -> PyResult<()> {
+        let db = self.db()?;
+        let batch = batch_from_ops(ops)?;
+        Ok(py.allow_threads(|| db.write(&batch))?)
+    }
+
+    fn transaction(&self) -> PyResult<Transaction> {
+        let db = self.db()?;
+        let tx = db.tx_begin();
+        Ok(Transaction { db, tx: Some(tx) })
+    }
+
+    //flushes and closes, later calls raise ClosedError
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        match self.db.take() {
+            Some(db) => Ok(py.allow_threads(|| db.close())?),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Db {
+    fn db(&self) -> PyResult<Arc<LsmDb>> {
+        self.db.clone().ok_or_else(|| Error::Closed.into())
+    }
+}
+
+/// Returned by `Db.transaction()`. Reads see the database as it was when the transaction began, and
+/// writes are applied together on commit. As a context manager it commits unless the block raised.
+#[pyclass(module = "draft_kv")]
+struct Transaction {
+    db: Arc<LsmDb>,
+    tx: Option<(u64, u64)>, //id and snapshot, None once committed or aborted
+}
+
+#[pymethods]
+impl Transaction {
+    fn get<'py>(&self, py: Python<'py>, key: &[u8]) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let (tx_id, seq_num) = self.tx()?;
+        let value = py.allow_threads(|| self.db.tx_search(tx_id, seq_num, key))?;
+        Ok(value.map(|value| PyBytes::new_bound(py, &value)))
+    }
+
+    //the first write waits for any other transaction that is writing to finish
+    fn put(&self, py: Python<'_>, key: &[u8], value: &[u8]) -> PyResult<()> {
+        let (tx_id, seq_num) = self.tx()?;
+        //a transaction takes an empty value for a delete
+        if value.is_empty() {
+            return Err(PyValueError::new_err("a transaction can not write an empty value"));
+        }
+        Ok(py.allow_threads(|| self.db.tx_insert(tx_id, seq_num, key, value))?)
+    }
+
+    fn delete(&self, py: Python<'_>, key: &[u8]) -> PyResult<()> {
+        let (tx_id, seq_num) = self.tx()?;
+        py.allow_threads(|| self.db.tx_delete(tx_id, seq_num, key));
+        Ok(())
+    }
+
+    fn commit(&mut self, py: Python<'_>) -> PyResult<()> {
+        let (tx_id, _) = self.tx()?;
+        self.tx = None;
+        let db = &self.db;
+        let res = py.allow_threads(|| {
+            let res = db.tx_commit(tx_id);
+            //a commit that failed still holds the write lock of the transaction
+            if res.is_err() {
+                db.tx_abort(tx_id);
+            }
+            res
+        });
+        Ok(res?)
+    }
+
+    fn abort(&mut self) {
+        if let Some((tx_id, _)) = self.tx.take() {
+            self.db.tx_abort(tx_id);
+        }
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature = (exc_type, _exc_value, _traceback))]
+    fn __exit__(&mut self, py: Python<'_>, exc_type: Option<&Bound<'_, PyAny>>, _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>) -> PyResult<bool> {
+        match (exc_type, self.tx) {
+            (None, Some(_)) => self.commit(py)?,
+            _ => self.abort(),
+        }
+        //an exception from the block goes on
+        Ok(false)
+    }
+}
+
+impl Transaction {
+    fn tx(&self) -> PyResult<(u64, u64)> {
+        self.tx.ok_or_else(|| PyValueError::new_err("the transaction is over"))
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+fn batch_from_ops(ops: &Bound<'_, PyAny>) -> PyResult<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    for op in ops.iter()? {
+        let op = op?;
+        let op = op.downcast::<PyTuple>()
+            .map_err(|_| PyValueError::new_err(format!("a batch op is a tuple, got {}", op)))?;
+        let kind = op.get_item(0)?;
+        match (kind.extract::<&str>()?, op.len()) {
+            ("put", 3) => batch.put(op.get_item(1)?.extract()?, op.get_item(2)?.extract()?),
+            ("delete", 2) => batch.delete(op.get_item(1)?.extract()?),
+            _ => return Err(PyValueError::new_err(format!("{} is neither (\"put\", key, value) nor (\"delete\", key)", op))),
+        };
+    }
+    Ok(batch)
+}
+
+fn entries_to_list<'py>(py: Python<'py>, entries: &[(Vec<u8>, Vec<u8>)]) -> Bound<'py, PyList> {
+    PyList::new_bound(py, entries.iter().map(|(key, value)| (PyBytes::new_bound(py, key), PyBytes::new_bound(py, value))))
+}
+
+#[pymodule]
+fn draft_kv(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_class::<Db>()?;
+    m.add_class::<Transaction>()?;
+    m.add("DraftKvError", py.get_type_bound::<DraftKvError>())?;
+    m.add("CorruptionError", py.get_type_bound::<CorruptionError>())?;
+    m.add("BackgroundError", py.get_type_bound::<BackgroundError>())?;
+    m.add("SequenceExhaustedError", py.get_type_bound::<SequenceExhaustedError>())?;
+    m.add("DbLockedError", py.get_type_bound::<DbLockedError>())?;
+    m.add("ReadOnlyError", py.get_type_bound::<ReadOnlyError>())?;
+    m.add("ClosedError", py.get_type_bound::<ClosedError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pyo3::exceptions::{PyFileNotFoundError, PyTypeError};
+
+    fn with_python<F: for<'py> FnOnce(Python<'py>)>(f: F) {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(f);
+    }
+
+    #[test]
+    fn batch_ops_convert_from_tuples() {
+        with_python(|py| {
+            let ops = py.eval_bound(r#"[("put", b"a", b"1"), ("delete", b"b"), ("put", b"c", b"")]"#, None, None).unwrap();
+            let mut expected = WriteBatch::new();
+            expected.put(b"a", b"1").delete(b"b").put(b"c", b"");
+            assert_eq!(batch_from_ops(&ops).unwrap(), expected);
+            assert!(batch_from_ops(&py.eval_bound("[]", None, None).unwrap()).unwrap().is_empty());
+
+            for bad in [r#"[("put", b"a")]"#, r#"[("get", b"a")]"#, r#"[("put", "a", b"1")]"#, r#"[[ "delete", b"a" ]]"#, "[1]", "1"] {
+                let ops = py.eval_bound(bad, None, None).unwrap();
+                let err = batch_from_ops(&ops).unwrap_err();
+                assert!(err.is_instance_of::<PyValueError>(py) || err.is_instance_of::<PyTypeError>(py), "{}: {}", bad, err);
+            }
+        });
+    }
+
+    #[test]
+    fn entries_and_errors_convert_to_python() {
+        with_python(|py| {
+            let list = entries_to_list(py, &[(b"k\x00".to_vec(), b"v".to_vec())]);
+            let entries: Vec<(Vec<u8>, Vec<u8>)> = list.extract().unwrap();
+            assert_eq!(entries, vec![(b"k\x00".to_vec(), b"v".to_vec())]);
+            assert!(list.get_item(0).unwrap().get_item(0).unwrap().is_instance_of::<PyBytes>());
+
+            let err = PyErr::from(Error::ReadOnly);
+            assert!(err.is_instance_of::<ReadOnlyError>(py) && err.is_instance_of::<DraftKvError>(py));
+            assert_eq!(err.value_bound(py).to_string(), "database is open read-only");
+            assert!(PyErr::from(Error::InvalidArgument("empty key".to_owned())).is_instance_of::<PyValueError>(py));
+            assert!(PyErr::from(Error::Corruption("bad block".to_owned())).is_instance_of::<CorruptionError>(py));
+            let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+            assert!(PyErr::from(Error::Io(missing)).is_instance_of::<PyFileNotFoundError>(py));
+        });
+    }
+}
@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Token bucket throttling background I/O, a rate of 0 means unlimited.
+/// Requests larger than the bucket are let through and paid back by sleeping.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: AtomicU64,
+    bucket: Mutex<(f64, Instant)>, //available bytes, last refill
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec: AtomicU64::new(bytes_per_sec),
+            bucket: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    pub fn set_bytes_per_sec(&self, bytes_per_sec: u64) {
+        self.bytes_per_sec.store(bytes_per_sec, Ordering::Release);
+    }
+
+    pub fn get_bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec.load(Ordering::Acquire)
+    }
+
+    //blocks the caller until `bytes` fit in the budget
+    pub fn request(&self, bytes: u64) {
+        let rate = self.get_bytes_per_sec();
+        if rate == 0 {
+            return;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock();
+            let (available, last_refill) = &mut *bucket;
+            let now = Instant::now();
+            //at most 100ms worth of bytes can be saved up while idle
+            let refilled = *available + now.duration_since(*last_refill).as_secs_f64() * rate as f64;
+            *available = refilled.min(rate as f64 / 10.0);
+            *last_refill = now;
+            *available -= bytes as f64;
+            if *available < 0.0 {
+                Some(Duration::from_secs_f64(-*available / rate as f64))
+            } else {
+                None
+            }
+        };
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::env::Env;
+use crate::error::Result;
+use crate::fault;
+use crate::key::LookUpKey;
+use crate::lsm::Config;
+use crate::manifest::Manifest;
+use crate::memtable::MemTable;
+use crate::rate_limiter::RateLimiter;
+use crate::sst::{salvage_file, Table};
+use crate::stats::RepairReport;
+use crate::utils::{file_num, has_extension};
+use crate::wal::Log;
+
+//damaged files are moved here as they were, nothing is deleted
+pub const LOST_DIR: &str = "lost";
+
+//Rebuilds the MANIFEST from the files themselves. Tables that read back whole are kept,
+//what can be read of the others and of the logs is written into new level 0 tables.
+pub fn repair(env: &Arc<dyn Env>, dir_path: &Path) -> Result<RepairReport> {
+    let mut report = RepairReport::default();
+    let files = env.list_dir(dir_path)?;
+    for tmp_file in files.iter().filter(|x| has_extension(x, "tmp")) {
+        fault::remove_file(&**env, tmp_file)?;
+    }
+    let numbered = |extension: &str| {
+        let mut files = files.iter()
+            .filter(|x| has_extension(x, extension))
+            .filter_map(|x| file_num(x).map(|num| (num, x.clone())))
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+    let sst_files = numbered("sst");
+    let log_files = numbered("LOG");
+
+    //an old manifest that still reads knows how the database was configured, and where tables were moved
+    let old_manifest = Manifest::load(&**env, dir_path).ok().flatten();
+    let config = Config::new();
+    let rate_limiter = RateLimiter::new(0);
+    let mut next_file_num = sst_files.last().map_or(0, |(num, _)| *num) + 1;
+    let mut new_table_file = || {
+        let path = dir_path.join(format!("{}.sst", next_file_num));
+        next_file_num += 1;
+        path
+    };
+    let mut tables = Vec::new();
+    let mut damaged = Vec::new();
+
+    for (num, sst_file) in sst_files {
+        let (entries, write_times) = match Table::open(env, sst_file.clone()) {
+            Ok(table) => {
+                let (entries, intact) = table.salvage();
+                if intact {
+                    let level = old_manifest.as_ref()
+                        .and_then(|m| m.level_of(num))
+                        .unwrap_or_else(|| table.get_level());
+                    tables.push(table.moved_to(level));
+                    report.recovered_files.push(sst_file);
+                    continue;
+                }
+                (entries, table.write_times())
+            },
+            Err(e) => {
+                log::warn!("table {:?} does not open: {}", sst_file, e);
+                (salvage_file(&**env, &sst_file)?, Vec::new())
+            },
+        };
+        if !entries.is_empty() {
+            report.salvaged_entries += entries.len() as u64;
+            report.recovered_files.push(sst_file.clone());
+            tables.push(Table::with_write_times(env, new_table_file(), entries.into_iter(), 0, config.block_size, &rate_limiter, write_times)?);
+        }
+        damaged.push(sst_file);
+    }
+
+    //the logs are replayed oldest first, as far as they parse
+    let mut mem_table = MemTable::new();
+    let mut trans = HashMap::new();
+    let mut replayed_logs = Vec::new();
+    for (num, log_file) in log_files {
+        let (log_entries, error) = Log::open(env, dir_path, num)?.read_valid()?;
+        let applied = mem_table.apply(log_entries, &mut trans, &log_file);
+        match (error, applied) {
+            (None, Ok(_)) => replayed_logs.push(log_file.clone()),
+            (Some(e), _) | (None, Err(e)) => {
+                log::warn!("log {:?} is damaged: {}", log_file, e);
+                damaged.push(log_file.clone());
+            },
+        }
+        report.recovered_files.push(log_file);
+    }
+    if !mem_table.inner.is_empty() {
+        report.salvaged_entries += mem_table.inner.len() as u64;
+        let iter = mem_table.inner.iter().map(|(k, v)| (LookUpKey::new(k.clone()), v.clone()));
+        tables.push(Table::with_write_times(env, new_table_file(), iter, 0, config.block_size, &rate_limiter, mem_table.write_times.clone())?);
+    }
+
+    //a table only keeps a level past 0 if no other table overlaps it, only level 0 tolerates overlaps
+    let levels = tables.iter()
+        .map(|table| match tables.iter().any(|other| !std::ptr::eq(table, other) && table.overlaps(other)) {
+            true => 0,
+            false => table.get_level(),
+        })
+        .collect::<Vec<_>>();
+    let manifest = Manifest {
+        compaction_style: old_manifest.as_ref().map_or(config.compaction_style, |m| m.compaction_style),
+        tables: tables.iter().zip(levels).map(|(t, level)| (t.get_file_num(), level)).collect(),
+        user_timestamp_size: old_manifest.as_ref().map_or(config.user_timestamp_size, |m| m.user_timestamp_size),
+        num_levels: old_manifest.as_ref().map_or(0, |m| m.num_levels),
+    };
+    manifest.save(&**env, dir_path)?;
+    drop(tables);
+
+    //only now that the manifest no longer needs them
+    for log_file in replayed_logs {
+        fault::remove_file(&**env, &log_file)?;
+    }
+    if !damaged.is_empty() {
+        let lost_dir = dir_path.join(LOST_DIR);
+        env.create_dir_all(&lost_dir)?;
+        for file in damaged {
+            fault::rename(&**env, &file, &lost_dir.join(file.file_name().unwrap()))?;
+            report.quarantined_files.push(file);
+        }
+        fault::sync_dir(&**env, &lost_dir)?;
+    }
+    fault::sync_dir(&**env, dir_path)?;
+    Ok(report)
+}
@@ -0,0 +1,553 @@
+//! The Redis protocol (RESP2) on the server of `server`, so redis-cli and the Redis client libraries can
+//! talk to a database. Keys and values are bytes as they are.
+//!
+//! | command                          | reply                                                         |
+//! |----------------------------------|---------------------------------------------------------------|
+//! | GET key                          | the value, null if there is none                              |
+//! | SET key value                    | OK, options such as EX or NX are refused                      |
+//! | DEL key [key ...]                | how many of the keys existed                                  |
+//! | EXISTS key [key ...]             | how many of the keys exist                                    |
+//! | MGET key [key ...]               | the values, null for each missing one                         |
+//! | INCR key, DECR key               | the value after adding 1 or -1, see `LsmDb::increment`        |
+//! | SCAN cursor [MATCH p*] [COUNT n] | the next cursor and up to n keys, 0 once the scan is through  |
+//! | MULTI, EXEC, DISCARD             | a transaction of the commands in between                      |
+//! | PING, QUIT                       |                                                               |
+//!
+//! MATCH only takes a prefix followed by `*`. A SCAN cursor names the key the scan goes on from and only
+//! means something to the connection it was returned on. MULTI begins a transaction whose reads see the
+//! database as it was then; the commands after it are answered QUEUED and their replies come with EXEC,
+//! which commits. DISCARD aborts, and so does EXEC after a command that was refused while queueing.
+//! Any other command is answered with an error.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+use crate::lsm::{parse_integer, LsmDb};
+use crate::server::{is_timeout, KvServer, ServerConfig, State, POLL_INTERVAL};
+
+//the oldest cursor of a connection is forgotten past this many
+const MAX_CURSORS: usize = 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+pub fn serve<A: ToSocketAddrs>(addr: A, db: Arc<LsmDb>) -> Result<KvServer> {
+    serve_with_config(addr, db, ServerConfig::new())
+}
+
+//max_request_bytes bounds a command, max_scan_entries the COUNT of a SCAN
+pub fn serve_with_config<A: ToSocketAddrs>(addr: A, db: Arc<LsmDb>, config: ServerConfig) -> Result<KvServer> {
+    let conn_db = db.clone();
+    KvServer::start(addr, db, Arc::new(move |stream, state: &State| {
+        let mut connection = Connection::new(conn_db.clone());
+        if let Err(e) = connection.serve(stream, state, &config) {
+            log::warn!("connection failed: {}", e);
+        }
+        connection.discard();
+    }))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn failed(e: Error) -> Self {
+        match e {
+            Error::ReadOnly => Reply::Error(format!("READONLY {}", e)),
+            e => Reply::Error(format!("ERR {}", e)),
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            //a line break would end the error early
+            Reply::Error(msg) => out.extend_from_slice(format!("-{}\r\n", msg.replace(&['\r', '\n'][..], " ")).as_bytes()),
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(bytes) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            },
+            Reply::Null => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            },
+        }
+    }
+}
+
+//the name and arguments of a command
+type Command = Vec<Vec<u8>>;
+
+//the next command in `buf` and how many bytes it took, None until all of it has arrived. Clients send
+//arrays of bulk strings, a line of words separated by spaces is taken too as redis-cli over telnet would
+fn parse_command(buf: &[u8], max_bytes: usize) -> std::result::Result<Option<(Command, usize)>, String> {
+    if buf.first() != Some(&b'*') {
+        return Ok(buf.iter().position(|&b| b == b'\n').map(|end| {
+            let args = buf[..end].split(u8::is_ascii_whitespace).filter(|w| !w.is_empty()).map(<[u8]>::to_vec).collect();
+            (args, end + 1)
+        }));
+    }
+    let (count, mut pos) = match parse_line_int(buf, 1)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    //every argument takes at least four bytes
+    if count > (max_bytes / 4) as i64 {
+        return Err(format!("{} arguments are too many", count));
+    }
+    let mut args = Vec::with_capacity(std::cmp::max(count, 0) as usize);
+    for _ in 0..count {
+        match buf.get(pos) {
+            None => return Ok(None),
+            Some(b'$') => {},
+            Some(&b) => return Err(format!("expected '$', got {:?}", b as char)),
+        }
+        let (len, start) = match parse_line_int(buf, pos + 1)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if len < 0 || len as usize > max_bytes {
+            return Err(format!("invalid bulk length {}", len));
+        }
+        let end = start + len as usize;
+        match buf.get(end..end + 2) {
+            None => return Ok(None),
+            Some(b"\r\n") => {},
+            Some(_) => return Err("a bulk string is not followed by CRLF".to_owned()),
+        }
+        args.push(buf[start..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+//the number on the line from `start` and where the line after it begins
+fn parse_line_int(buf: &[u8], start: usize) -> std::result::Result<Option<(i64, usize)>, String> {
+    let rest = buf.get(start..).unwrap_or_default();
+    let end = match rest.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if rest.len() > 20 => return Err("a length line is too long".to_owned()),
+        None => return Ok(None),
+    };
+    let n = parse_integer(&rest[..end]).ok_or_else(|| format!("invalid length {:?}", rest[..end].escape_ascii().to_string()))?;
+    Ok(Some((n, start + end + 2)))
+}
+
+//an open MULTI
+struct Multi {
+    tx: (u64, u64), //id and snapshot of its transaction
+    replies: Vec<Reply>,
+    refused: bool, //a command was refused while queueing, EXEC aborts
+}
+
+struct Connection {
+    db: Arc<LsmDb>,
+    multi: Option<Multi>,
+    cursors: BTreeMap<u64, Vec<u8>>, //the key each open scan goes on from
+    next_cursor: u64,
+}
+
+impl Connection {
+    fn new(db: Arc<LsmDb>) -> Self {
+        Connection { db, multi: None, cursors: BTreeMap::new(), next_cursor: 1 }
+    }
+
+    fn serve(&mut self, mut stream: TcpStream, state: &State, config: &ServerConfig) -> io::Result<()> {
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_nodelay(true)?;
+        let mut buf = Vec::new();
+        let mut chunk = vec![0; 16 * 1024];
+        loop {
+            //every command that has arrived is answered in one write, which is what pipelining clients expect
+            let mut out = Vec::new();
+            let mut consumed = 0;
+            loop {
+                match parse_command(&buf[consumed..], config.max_request_bytes) {
+                    Ok(Some((args, len))) => {
+                        consumed += len;
+                        if args.is_empty() {
+                            continue;
+                        }
+                        if args[0].eq_ignore_ascii_case(b"QUIT") {
+                            Reply::Simple("OK").encode(&mut out);
+                            return stream.write_all(&out);
+                        }
+                        self.execute(&args, config).encode(&mut out);
+                    },
+                    Ok(None) => break,
+                    Err(msg) => {
+                        Reply::Error(format!("ERR Protocol error: {}", msg)).encode(&mut out);
+                        return stream.write_all(&out);
+                    },
+                }
+            }
+            buf.drain(..consumed);
+            if !out.is_empty() {
+                stream.write_all(&out)?;
+            }
+            if buf.len() > config.max_request_bytes {
+                let msg = format!("ERR Protocol error: a command of more than {} bytes", config.max_request_bytes);
+                out.clear();
+                Reply::Error(msg).encode(&mut out);
+                return stream.write_all(&out);
+            }
+            match stream.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                Err(e) if is_timeout(&e) => {
+                    if buf.is_empty() && state.stopping() {
+                        return Ok(());
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn execute(&mut self, args: &[Vec<u8>], config: &ServerConfig) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let args = &args[1..];
+        let arity = |ok: bool| match ok {
+            true => Ok(()),
+            false => Err(Reply::Error(format!("ERR wrong number of arguments for '{}' command", name.to_ascii_lowercase()))),
+        };
+        //the commands a transaction can queue are checked before anything runs
+        let checked = match name.as_str() {
+            "GET" | "INCR" | "DECR" => arity(args.len() == 1),
+            "SET" if args.len() > 2 => Err(Reply::Error("ERR SET options are not supported".to_owned())),
+            "SET" => arity(args.len() == 2),
+            "DEL" | "EXISTS" | "MGET" => arity(!args.is_empty()),
+            "MULTI" | "EXEC" | "DISCARD" => arity(args.is_empty()),
+            "PING" => arity(args.len() <= 1),
+            "SCAN" => Ok(()),
+            _ => Err(Reply::Error(format!("ERR unknown command '{}'", name.to_ascii_lowercase()))),
+        };
+        let in_multi = self.multi.is_some();
+        match (name.as_str(), checked) {
+            (_, Err(reply)) => {
+                if let Some(multi) = self.multi.as_mut() {
+                    multi.refused = true;
+                }
+                reply
+            },
+            ("MULTI", _) if in_multi => Reply::Error("ERR MULTI calls can not be nested".to_owned()),
+            ("MULTI", _) => {
+                self.multi = Some(Multi { tx: self.db.tx_begin(), replies: Vec::new(), refused: false });
+                Reply::Simple("OK")
+            },
+            ("EXEC", _) | ("DISCARD", _) if !in_multi => Reply::Error(format!("ERR {} without MULTI", name)),
+            ("EXEC", _) => self.exec(),
+            ("DISCARD", _) => {
+                self.discard();
+                Reply::Simple("OK")
+            },
+            ("PING", _) => args.first().map_or(Reply::Simple("PONG"), |msg| Reply::Bulk(msg.clone())),
+            ("SCAN", _) if in_multi => {
+                self.multi.as_mut().unwrap().refused = true;
+                Reply::Error("ERR SCAN is not supported inside MULTI".to_owned())
+            },
+            ("SCAN", _) => self.scan(args, config).unwrap_or_else(|reply| reply),
+            _ => {
+                let reply = self.run(&name, args);
+                match self.multi.as_mut() {
+                    Some(multi) => {
+                        multi.replies.push(reply);
+                        Reply::Simple("QUEUED")
+                    },
+                    None => reply,
+                }
+            },
+        }
+    }
+
+    //the commands that read and write through the transaction while a MULTI is open
+    fn run(&self, name: &str, args: &[Vec<u8>]) -> Reply {
+        let reply = match name {
+            "GET" => self.get(&args[0]).map(|value| value.map_or(Reply::Null, Reply::Bulk)),
+            "SET" => self.set(&args[0], &args[1]).map(|_| Reply::Simple("OK")),
+            "DEL" => args.iter()
+                .try_fold(0, |deleted, key| match self.get(key)? {
+                    Some(_) => self.del(key).map(|_| deleted + 1),
+                    None => Ok(deleted),
+                })
+                .map(Reply::Integer),
+            "EXISTS" => args.iter()
+                .try_fold(0, |found, key| self.get(key).map(|value| found + value.is_some() as i64))
+                .map(Reply::Integer),
+            "MGET" => args.iter()
+                .map(|key| self.get(key).map(|value| value.map_or(Reply::Null, Reply::Bulk)))
+                .collect::<Result<Vec<_>>>()
+                .map(Reply::Array),
+            "INCR" => self.increment(&args[0], 1).map(Reply::Integer),
+            "DECR" => self.increment(&args[0], -1).map(Reply::Integer),
+            _ => unreachable!("{} is not run by run()", name),
+        };
+        reply.unwrap_or_else(Reply::failed)
+    }
+
+    fn tx(&self) -> Option<(u64, u64)> {
+        self.multi.as_ref().map(|multi| multi.tx)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.tx() {
+            Some((tx_id, seq_num)) => self.db.tx_search(tx_id, seq_num, key),
+            None => self.db.search(key, None),
+        }
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        match self.tx() {
+            //a transaction takes an empty value for a delete
+            Some(_) if value.is_empty() => Err(Error::InvalidArgument("a transaction can not write an empty value".to_owned())),
+            Some((tx_id, seq_num)) => self.db.tx_insert(tx_id, seq_num, key, value),
+            None => self.db.insert(key, value),
+        }
+    }
+
+    fn del(&self, key: &[u8]) -> Result<()> {
+        match self.tx() {
+            Some((tx_id, seq_num)) => {
+                self.db.tx_delete(tx_id, seq_num, key);
+                Ok(())
+            },
+            None => self.db.delete(key),
+        }
+    }
+
+    fn increment(&self, key: &[u8], delta: i64) -> Result<i64> {
+        let (tx_id, seq_num) = match self.tx() {
+            Some(tx) => tx,
+            None => return self.db.increment(key, delta),
+        };
+        let current = match self.db.tx_search(tx_id, seq_num, key)? {
+            Some(value) => parse_integer(&value).ok_or_else(|| Error::InvalidArgument("value is not an integer".to_owned()))?,
+            None => 0,
+        };
+        let sum = current.checked_add(delta).ok_or_else(|| Error::InvalidArgument("increment overflows".to_owned()))?;
+        self.db.tx_insert(tx_id, seq_num, key, sum.to_string().as_bytes())?;
+        Ok(sum)
+    }
+
+    fn exec(&mut self) -> Reply {
+        let multi = self.multi.take().unwrap();
+        let (tx_id, _) = multi.tx;
+        if multi.refused {
+            self.db.tx_abort(tx_id);
+            return Reply::Error("EXECABORT Transaction discarded because of previous errors.".to_owned());
+        }
+        match self.db.tx_commit(tx_id) {
+            Ok(()) => Reply::Array(multi.replies),
+            Err(e) => {
+                //a commit that failed still holds the write lock of the transaction
+                self.db.tx_abort(tx_id);
+                Reply::failed(e)
+            },
+        }
+    }
+
+    fn discard(&mut self) {
+        if let Some(multi) = self.multi.take() {
+            self.db.tx_abort(multi.tx.0);
+        }
+    }
+
+    fn scan(&mut self, args: &[Vec<u8>], config: &ServerConfig) -> std::result::Result<Reply, Reply> {
+        let syntax = || Reply::Error("ERR syntax error".to_owned());
+        let cursor = args.first().and_then(|c| parse_integer(c)).filter(|c| *c >= 0).ok_or_else(|| Reply::Error("ERR invalid cursor".to_owned()))? as u64;
+        let mut prefix = Vec::new();
+        let mut count = DEFAULT_SCAN_COUNT;
+        for option in args[1..].chunks(2) {
+            match option {
+                [flag, pattern] if flag.eq_ignore_ascii_case(b"MATCH") => {
+                    prefix = match pattern.split_last() {
+                        Some((b'*', p)) if !p.iter().any(|b| b"*?[]\\".contains(b)) => p.to_vec(),
+                        _ => return Err(Reply::Error("ERR MATCH only takes a prefix followed by *".to_owned())),
+                    };
+                },
+                [flag, n] if flag.eq_ignore_ascii_case(b"COUNT") => {
+                    count = parse_integer(n).filter(|n| *n > 0).ok_or_else(syntax)? as usize;
+                },
+                _ => return Err(syntax()),
+            }
+        }
+        let count = std::cmp::min(count, config.max_scan_entries);
+        let start = match cursor {
+            0 => Vec::new(),
+            cursor => self.cursors.remove(&cursor).ok_or_else(|| Reply::Error("ERR invalid cursor".to_owned()))?,
+        };
+        //one more than asked for tells whether there is a next page and where it begins
+        let mut entries = self.db.scan_prefix_from(&prefix, &start, count + 1).map_err(Reply::failed)?;
+        let next = match entries.len() > count {
+            true => {
+                let (next_key, _) = entries.pop().unwrap();
+                let id = self.next_cursor;
+                self.next_cursor += 1;
+                self.cursors.insert(id, next_key);
+                if self.cursors.len() > MAX_CURSORS {
+                    let oldest = *self.cursors.keys().next().unwrap();
+                    self.cursors.remove(&oldest);
+                }
+                id
+            },
+            false => 0,
+        };
+        let keys = entries.into_iter().map(|(key, _)| Reply::Bulk(key)).collect();
+        Ok(Reply::Array(vec![Reply::Bulk(next.to_string().into_bytes()), Reply::Array(keys)]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::Config;
+    use crate::utils::temp_dir;
+
+    use std::time::Duration;
+
+    fn serve_db(name: &str) -> (Arc<LsmDb>, KvServer) {
+        let db = Arc::new(LsmDb::with_config(temp_dir(name), Config::new()).unwrap());
+        let server = serve("127.0.0.1:0", db.clone()).unwrap();
+        (db, server)
+    }
+
+    fn command(args: &[&[u8]]) -> Vec<u8> {
+        let mut frame = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            frame.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            frame.extend_from_slice(arg);
+            frame.extend_from_slice(b"\r\n");
+        }
+        frame
+    }
+
+    //sends `request` in one write and reads until `expected` bytes came back
+    fn round_trip(stream: &mut TcpStream, request: &[u8], expected: &[u8]) {
+        stream.write_all(request).unwrap();
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(reply.escape_ascii().to_string(), expected.escape_ascii().to_string());
+    }
+
+    fn connect(server: &KvServer) -> TcpStream {
+        let stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream
+    }
+
+    #[test]
+    fn pipelined_commands_are_answered_in_order() {
+        let (db, server) = serve_db("resp_pipeline");
+        let mut stream = connect(&server);
+        let request = [
+            command(&[b"SET", b"k\x00ey", b"v\r\nal"]),
+            command(&[b"GET", b"k\x00ey"]),
+            command(&[b"get", b"missing"]),
+            command(&[b"INCR", b"counter"]),
+            command(&[b"DECR", b"counter"]),
+            command(&[b"DECR", b"counter"]),
+            command(&[b"INCR", b"k\x00ey"]),
+            command(&[b"MGET", b"counter", b"missing"]),
+            command(&[b"EXISTS", b"counter", b"missing", b"counter"]),
+            command(&[b"DEL", b"counter", b"missing"]),
+            command(&[b"SET", b"a", b"1", b"NX"]),
+            command(&[b"HSET", b"h", b"f", b"v"]),
+            b"PING\r\n".to_vec(),
+        ].concat();
+        let expected = concat!(
+            "+OK\r\n", "$5\r\nv\r\nal\r\n", "$-1\r\n", ":1\r\n", ":0\r\n", ":-1\r\n",
+            "-ERR invalid argument: value of \"k\\\\x00ey\" is not an integer\r\n",
+            "*2\r\n$2\r\n-1\r\n$-1\r\n", ":2\r\n", ":1\r\n",
+            "-ERR SET options are not supported\r\n", "-ERR unknown command 'hset'\r\n", "+PONG\r\n");
+        round_trip(&mut stream, &request, expected.as_bytes());
+        assert_eq!(db.search(b"k\x00ey", None).unwrap(), Some(b"v\r\nal".to_vec()));
+        assert_eq!(db.search(b"counter", None).unwrap(), None);
+
+        //a command split over writes waits for the rest
+        let set = command(&[b"SET", b"split", b"value"]);
+        stream.write_all(&set[..7]).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        round_trip(&mut stream, &set[7..], b"+OK\r\n");
+        round_trip(&mut stream, b"*1\r\n+GET\r\n", b"-ERR Protocol error: expected '$', got '+'\r\n");
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+    }
+
+    #[test]
+    fn multi_commits_with_exec_and_aborts_otherwise() {
+        let (db, server) = serve_db("resp_multi");
+        let mut stream = connect(&server);
+        db.insert(b"balance", b"10").unwrap();
+        let mut request = command(&[b"MULTI"]);
+        request.extend(command(&[b"DECR", b"balance"]));
+        request.extend(command(&[b"SET", b"log", b"spent 1"]));
+        request.extend(command(&[b"GET", b"log"]));
+        request.extend(command(&[b"EXEC"]));
+        round_trip(&mut stream, &request, b"+OK\r\n+QUEUED\r\n+QUEUED\r\n+QUEUED\r\n*3\r\n:9\r\n+OK\r\n$7\r\nspent 1\r\n");
+        assert_eq!(db.search(b"balance", None).unwrap(), Some(b"9".to_vec()));
+
+        let mut request = command(&[b"MULTI"]);
+        request.extend(command(&[b"SET", b"balance", b"0"]));
+        request.extend(command(&[b"DISCARD"]));
+        request.extend(command(&[b"MULTI"]));
+        request.extend(command(&[b"DEL", b"log"]));
+        request.extend(command(&[b"GET"]));
+        request.extend(command(&[b"EXEC"]));
+        request.extend(command(&[b"EXEC"]));
+        round_trip(&mut stream, &request, concat!(
+            "+OK\r\n+QUEUED\r\n+OK\r\n", "+OK\r\n+QUEUED\r\n-ERR wrong number of arguments for 'get' command\r\n",
+            "-EXECABORT Transaction discarded because of previous errors.\r\n", "-ERR EXEC without MULTI\r\n").as_bytes());
+        assert_eq!(db.search(b"balance", None).unwrap(), Some(b"9".to_vec()));
+        assert_eq!(db.search(b"log", None).unwrap(), Some(b"spent 1".to_vec()));
+
+        //a MULTI left open is aborted with its connection, so other transactions can write
+        round_trip(&mut stream, &[command(&[b"MULTI"]), command(&[b"SET", b"balance", b"0"])].concat(), b"+OK\r\n+QUEUED\r\n");
+        drop(stream);
+        let mut stream = connect(&server);
+        round_trip(&mut stream, &[command(&[b"MULTI"]), command(&[b"INCR", b"balance"]), command(&[b"EXEC"])].concat(),
+            b"+OK\r\n+QUEUED\r\n*1\r\n:10\r\n");
+    }
+
+    #[test]
+    fn scan_goes_on_from_its_cursor() {
+        let (db, server) = serve_db("resp_scan");
+        let mut stream = connect(&server);
+        for i in 0..5 {
+            db.insert(format!("user:{}", i).as_bytes(), b"x").unwrap();
+        }
+        db.insert(b"other", b"x").unwrap();
+        db.flush().unwrap();
+        db.delete(b"user:3").unwrap();
+        let page = |keys: &[&str], cursor: &str| {
+            let mut reply = format!("*2\r\n${}\r\n{}\r\n*{}\r\n", cursor.len(), cursor, keys.len());
+            for key in keys {
+                reply += &format!("${}\r\n{}\r\n", key.len(), key);
+            }
+            reply.into_bytes()
+        };
+        round_trip(&mut stream, &command(&[b"SCAN", b"0", b"MATCH", b"user:*", b"COUNT", b"2"]), &page(&["user:0", "user:1"], "1"));
+        //written after the scan began and after where it is, so it is seen
+        db.insert(b"user:5", b"x").unwrap();
+        round_trip(&mut stream, &command(&[b"SCAN", b"1", b"MATCH", b"user:*", b"COUNT", b"2"]), &page(&["user:2", "user:4"], "2"));
+        round_trip(&mut stream, &command(&[b"SCAN", b"2", b"MATCH", b"user:*", b"COUNT", b"2"]), &page(&["user:5"], "0"));
+        round_trip(&mut stream, &command(&[b"SCAN", b"2"]), b"-ERR invalid cursor\r\n");
+        round_trip(&mut stream, &command(&[b"SCAN", b"0", b"COUNT", b"10"]),
+            &page(&["other", "user:0", "user:1", "user:2", "user:4", "user:5"], "0"));
+        round_trip(&mut stream, &command(&[b"SCAN", b"0", b"MATCH", b"u?er*"]), b"-ERR MATCH only takes a prefix followed by *\r\n");
+        round_trip(&mut stream, &command(&[b"SCAN", b"0", b"COUNT"]), b"-ERR syntax error\r\n");
+    }
+}
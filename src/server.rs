@@ -0,0 +1,594 @@
+//! A minimal network front end for services on the same host: one thread per connection and a
+//! length-prefixed binary protocol.
+//!
+//! Every frame is a big-endian u32 length followed by that many bytes. A request is an opcode byte and
+//! its fields, each a u32 length and that many bytes; a response is a status byte and its payload.
+//!
+//! | opcode   | fields              | payload with OK                                      |
+//! |----------|---------------------|------------------------------------------------------|
+//! | GET 1    | key                 | the value, NOT_FOUND if there is none                |
+//! | PUT 2    | key, value          |                                                      |
+//! | DEL 3    | key                 |                                                      |
+//! | SCAN 4   | prefix, limit (u32) | key and value fields of up to limit entries in order |
+//! | BEGIN 5  |                     |                                                      |
+//! | COMMIT 6 |                     |                                                      |
+//! | ABORT 7  |                     |                                                      |
+//!
+//! Between BEGIN and COMMIT or ABORT the GET, PUT and DEL of a connection go through its transaction,
+//! which is aborted if the connection ends first. SCAN always reads what is committed. Any status other
+//! than OK and NOT_FOUND carries a utf-8 message.
+
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::lsm::LsmDb;
+
+use parking_lot::Mutex;
+
+//how often a connection waiting for its next request checks whether the server is stopping
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    Get = 1,
+    Put = 2,
+    Del = 3,
+    Scan = 4,
+    Begin = 5,
+    Commit = 6,
+    Abort = 7,
+}
+
+impl Opcode {
+    fn from_byte(byte: u8) -> Option<Self> {
+        [Opcode::Get, Opcode::Put, Opcode::Del, Opcode::Scan, Opcode::Begin, Opcode::Commit, Opcode::Abort]
+            .iter()
+            .copied()
+            .find(|op| *op as u8 == byte)
+    }
+}
+
+//one per variant of Error, and the ones of the protocol itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    NotFound = 1,
+    InvalidArgument = 2,
+    Corruption = 3,
+    IoError = 4,
+    BackgroundError = 5,
+    SequenceExhausted = 6,
+    DbLocked = 7,
+    ReadOnly = 8,
+    Closed = 9,
+    TooLarge = 10, //the request was longer than the server takes, the connection is closed after it
+    BadRequest = 11, //an unknown opcode, missing fields, or a transaction command out of order
+    TimedOut = 12,
+}
+
+impl Status {
+    fn from_byte(byte: u8) -> Option<Self> {
+        use Status::*;
+        [Ok, NotFound, InvalidArgument, Corruption, IoError, BackgroundError, SequenceExhausted, DbLocked, ReadOnly,
+            Closed, TooLarge, BadRequest, TimedOut]
+            .iter()
+            .copied()
+            .find(|status| *status as u8 == byte)
+    }
+
+    fn of(e: &Error) -> Self {
+        match e {
+            Error::Io(_) => Status::IoError,
+            Error::InvalidArgument(_) => Status::InvalidArgument,
+            Error::Corruption(_) => Status::Corruption,
+            Error::Background(_) => Status::BackgroundError,
+            Error::SequenceExhausted => Status::SequenceExhausted,
+            Error::DbLocked { .. } => Status::DbLocked,
+            Error::ReadOnly => Status::ReadOnly,
+            Error::Closed => Status::Closed,
+            Error::TimedOut => Status::TimedOut,
+        }
+    }
+
+    //back to the error the server answered with, the protocol errors become InvalidArgument
+    fn into_error(self, msg: String) -> Error {
+        match self {
+            Status::IoError => Error::Io(io::Error::other(msg)),
+            Status::Corruption => Error::Corruption(msg),
+            Status::BackgroundError => Error::Background(msg),
+            Status::SequenceExhausted => Error::SequenceExhausted,
+            Status::DbLocked => Error::DbLocked { pid: None },
+            Status::ReadOnly => Error::ReadOnly,
+            Status::Closed => Error::Closed,
+            Status::TimedOut => Error::TimedOut,
+            _ => Error::InvalidArgument(msg),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub max_request_bytes: usize, //a longer request is answered with TOO_LARGE and the connection closed
+    pub max_scan_entries: usize, //a SCAN returns at most this many entries whatever its limit
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        ServerConfig {
+            max_request_bytes: 16 * 1024 * 1024,
+            max_scan_entries: 10_000,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//what the accept thread, the connections and the close hook of the database share
+pub(crate) struct State {
+    addr: SocketAddr,
+    stopping: AtomicBool,
+    acceptor: Mutex<Option<JoinHandle<()>>>,
+    connections: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl State {
+    pub(crate) fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::Acquire)
+    }
+
+    //no new connections are accepted, the open ones finish the request they are on and end
+    fn stop(&self) {
+        if self.stopping.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        //accept() only returns for a connection, so one is made to wake it
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(addr);
+        if let Some(acceptor) = self.acceptor.lock().take() {
+            let _ = acceptor.join();
+        }
+        for connection in std::mem::take(&mut *self.connections.lock()) {
+            let _ = connection.join();
+        }
+    }
+}
+
+/// Serves a database over TCP until `shutdown`, drop, or `LsmDb::close`.
+pub struct KvServer {
+    state: Arc<State>,
+}
+
+impl KvServer {
+    pub fn serve<A: ToSocketAddrs>(addr: A, db: Arc<LsmDb>) -> Result<Self> {
+        Self::serve_with_config(addr, db, ServerConfig::new())
+    }
+
+    pub fn serve_with_config<A: ToSocketAddrs>(addr: A, db: Arc<LsmDb>, config: ServerConfig) -> Result<Self> {
+        let conn_db = db.clone();
+        Self::start(addr, db, Arc::new(move |stream, state: &State| {
+            let mut connection = Connection { db: conn_db.clone(), tx: None };
+            if let Err(e) = connection.serve(stream, state, &config) {
+                log::warn!("connection failed: {}", e);
+            }
+            connection.abort();
+        }))
+    }
+
+    //listens on `addr` and runs `handler` on a thread of its own for each connection
+    pub(crate) fn start<A: ToSocketAddrs>(addr: A, db: Arc<LsmDb>, handler: Arc<Handler>) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let state = Arc::new(State {
+            addr: listener.local_addr()?,
+            stopping: AtomicBool::new(false),
+            acceptor: Mutex::new(None),
+            connections: Mutex::new(Vec::new()),
+        });
+        let acceptor = {
+            let state = state.clone();
+            thread::Builder::new()
+                .name("kv-server".to_owned())
+                .spawn(move || accept_loop(listener, state, handler))?
+        };
+        *state.acceptor.lock() = Some(acceptor);
+        let hook_state = state.clone();
+        db.on_close(Box::new(move || hook_state.stop()));
+        Ok(KvServer { state })
+    }
+
+    //the address it listens on, with the port picked if it was bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.state.addr
+    }
+
+    pub fn shutdown(&self) {
+        self.state.stop();
+    }
+}
+
+impl Drop for KvServer {
+    fn drop(&mut self) {
+        self.state.stop();
+    }
+}
+
+pub(crate) type Handler = dyn Fn(TcpStream, &State) + Send + Sync;
+
+fn accept_loop(listener: TcpListener, state: Arc<State>, handler: Arc<Handler>) {
+    for stream in listener.incoming() {
+        if state.stopping() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("failed to accept a connection: {}", e);
+                continue;
+            },
+        };
+        let (conn_state, handler) = (state.clone(), handler.clone());
+        let handle = thread::Builder::new()
+            .name("kv-connection".to_owned())
+            .spawn(move || handler(stream, &conn_state));
+        match handle {
+            Ok(handle) => {
+                let mut connections = state.connections.lock();
+                connections.retain(|c| !c.is_finished());
+                connections.push(handle);
+            },
+            Err(e) => log::warn!("failed to start a connection thread: {}", e),
+        }
+    }
+}
+
+struct Connection {
+    db: Arc<LsmDb>,
+    tx: Option<(u64, u64)>, //id and snapshot of the open transaction
+}
+
+impl Connection {
+    fn serve(&mut self, mut stream: TcpStream, state: &State, config: &ServerConfig) -> io::Result<()> {
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_nodelay(true)?;
+        loop {
+            let len = match read_len(&mut stream, state)? {
+                Some(len) => len,
+                None => return Ok(()),
+            };
+            if len > config.max_request_bytes {
+                let msg = format!("request of {} bytes, at most {} are taken", len, config.max_request_bytes);
+                return write_frame(&mut stream, Status::TooLarge as u8, &[msg.as_bytes()]);
+            }
+            let mut request = vec![0; len];
+            read_full(&mut stream, &mut request)?;
+            let (status, payload) = match self.execute(&request, config) {
+                Ok(Some(payload)) => (Status::Ok, payload),
+                Ok(None) => (Status::NotFound, Vec::new()),
+                Err((status, msg)) => (status, vec![msg.into_bytes()]),
+            };
+            let payload = payload.iter().map(Vec::as_slice).collect::<Vec<_>>();
+            write_frame(&mut stream, status as u8, &payload)?;
+        }
+    }
+
+    //the parts of the payload, None for NOT_FOUND
+    fn execute(&mut self, request: &[u8], config: &ServerConfig) -> std::result::Result<Option<Vec<Vec<u8>>>, (Status, String)> {
+        let bad_request = |msg: &str| (Status::BadRequest, msg.to_owned());
+        let failed = |e: Error| (Status::of(&e), e.to_string());
+        let (&opcode, mut fields) = request.split_first().ok_or_else(|| bad_request("empty request"))?;
+        let opcode = Opcode::from_byte(opcode).ok_or_else(|| bad_request(&format!("unknown opcode {}", opcode)))?;
+        let mut field = || take_field(&mut fields).ok_or_else(|| bad_request(&format!("{:?} is missing a field", opcode)));
+        let res = match (opcode, self.tx) {
+            (Opcode::Get, None) => return self.db.search(field()?, None).map(|value| value.map(|value| vec![value])).map_err(failed),
+            (Opcode::Get, Some((tx_id, seq_num))) => {
+                return self.db.tx_search(tx_id, seq_num, field()?).map(|value| value.map(|value| vec![value])).map_err(failed);
+            },
+            (Opcode::Put, None) => {
+                let (key, value) = (field()?, field()?);
+                self.db.insert(key, value)
+            },
+            //a transaction takes an empty value for a delete
+            (Opcode::Put, Some(_)) => {
+                let (key, value) = (field()?, field()?);
+                if value.is_empty() {
+                    return Err((Status::InvalidArgument, "a transaction can not write an empty value".to_owned()));
+                }
+                let (tx_id, seq_num) = self.tx.unwrap();
+                self.db.tx_insert(tx_id, seq_num, key, value)
+            },
+            (Opcode::Del, None) => self.db.delete(field()?),
+            (Opcode::Del, Some((tx_id, seq_num))) => {
+                self.db.tx_delete(tx_id, seq_num, field()?);
+                Ok(())
+            },
+            (Opcode::Scan, _) => {
+                let prefix = field()?;
+                let limit = field()?;
+                let limit = <[u8; 4]>::try_from(limit).map_err(|_| bad_request("the limit of SCAN is not a u32"))?;
+                let limit = std::cmp::min(u32::from_be_bytes(limit) as usize, config.max_scan_entries);
+                let entries = self.db.scan_prefix(prefix).map_err(failed)?;
+                let payload = entries.into_iter().take(limit).flat_map(|(key, value)| [key, value]).collect();
+                return Ok(Some(payload));
+            },
+            (Opcode::Begin, Some(_)) => return Err(bad_request("a transaction is already open")),
+            (Opcode::Begin, None) => {
+                self.tx = Some(self.db.tx_begin());
+                Ok(())
+            },
+            (Opcode::Commit, None) | (Opcode::Abort, None) => return Err(bad_request("no transaction is open")),
+            (Opcode::Commit, Some((tx_id, _))) => {
+                self.tx = None;
+                let res = self.db.tx_commit(tx_id);
+                //a commit that failed still holds the write lock of the transaction
+                if res.is_err() {
+                    self.db.tx_abort(tx_id);
+                }
+                res
+            },
+            (Opcode::Abort, Some(_)) => {
+                self.abort();
+                Ok(())
+            },
+        };
+        res.map(|_| Some(Vec::new())).map_err(failed)
+    }
+
+    fn abort(&mut self) {
+        if let Some((tx_id, _)) = self.tx.take() {
+            self.db.tx_abort(tx_id);
+        }
+    }
+}
+
+fn take_field<'a>(fields: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u32::from_be_bytes(fields.get(..4)?.try_into().ok()?) as usize;
+    let field = fields.get(4..4 + len)?;
+    *fields = &fields[4 + len..];
+    Some(field)
+}
+
+fn put_field(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    buf.extend_from_slice(field);
+}
+
+//the length of the next frame, None if the peer closed the connection or the server is stopping between requests
+fn read_len(stream: &mut TcpStream, state: &State) -> io::Result<Option<usize>> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match stream.read(&mut len[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if is_timeout(&e) => {
+                if read == 0 && state.stopping() {
+                    return Ok(None);
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(u32::from_be_bytes(len) as usize))
+}
+
+//the rest of a frame that has begun, the peer is expected to send it without pausing for long
+fn read_full(stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        match stream.read(&mut buf[read..]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+//a frame of the first byte and the fields after it
+fn write_frame(stream: &mut TcpStream, first: u8, fields: &[&[u8]]) -> io::Result<()> {
+    let mut body = vec![first];
+    for field in fields {
+        put_field(&mut body, field);
+    }
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    stream.write_all(&frame)
+}
+
+/// Talks to a KvServer, one request at a time.
+pub struct KvClient {
+    stream: TcpStream,
+}
+
+impl KvClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(KvClient { stream })
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.call(Opcode::Get, &[key])? {
+            Some(mut fields) if fields.len() == 1 => Ok(fields.pop()),
+            Some(_) => Err(Error::InvalidArgument("GET answered with other than one value".to_owned())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.call(Opcode::Put, &[key, value]).map(|_| ())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.call(Opcode::Del, &[key]).map(|_| ())
+    }
+
+    //the server may return fewer than `limit` entries, up to its max_scan_entries
+    pub fn scan(&mut self, prefix: &[u8], limit: u32) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let fields = self.call(Opcode::Scan, &[prefix, &limit.to_be_bytes()])?.unwrap_or_default();
+        if fields.len() % 2 != 0 {
+            return Err(Error::InvalidArgument("SCAN answered with a key without a value".to_owned()));
+        }
+        let mut fields = fields.into_iter();
+        let mut entries = Vec::new();
+        while let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+
+    pub fn begin(&mut self) -> Result<()> {
+        self.call(Opcode::Begin, &[]).map(|_| ())
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        self.call(Opcode::Commit, &[]).map(|_| ())
+    }
+
+    pub fn abort(&mut self) -> Result<()> {
+        self.call(Opcode::Abort, &[]).map(|_| ())
+    }
+
+    //the fields of an OK response, None for NOT_FOUND
+    fn call(&mut self, opcode: Opcode, fields: &[&[u8]]) -> Result<Option<Vec<Vec<u8>>>> {
+        write_frame(&mut self.stream, opcode as u8, fields)?;
+        let mut len = [0; 4];
+        self.stream.read_exact(&mut len)?;
+        let mut response = vec![0; u32::from_be_bytes(len) as usize];
+        self.stream.read_exact(&mut response)?;
+        let malformed = || Error::InvalidArgument("malformed response".to_owned());
+        let (&status, mut rest) = response.split_first().ok_or_else(malformed)?;
+        let mut fields = Vec::new();
+        while !rest.is_empty() {
+            fields.push(take_field(&mut rest).ok_or_else(malformed)?.to_vec());
+        }
+        match Status::from_byte(status).ok_or_else(malformed)? {
+            Status::Ok => Ok(Some(fields)),
+            Status::NotFound => Ok(None),
+            status => {
+                let msg = fields.first().map(|msg| String::from_utf8_lossy(msg).into_owned()).unwrap_or_default();
+                Err(status.into_error(msg))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsm::Config;
+    use crate::utils::temp_dir;
+
+    fn serve(name: &str) -> (Arc<LsmDb>, KvServer) {
+        let db = Arc::new(LsmDb::with_config(temp_dir(name), Config::new()).unwrap());
+        let server = KvServer::serve("127.0.0.1:0", db.clone()).unwrap();
+        (db, server)
+    }
+
+    #[test]
+    fn every_opcode_round_trips() {
+        let (db, server) = serve("server_opcodes");
+        let mut client = KvClient::connect(server.local_addr()).unwrap();
+        client.put(b"a", b"1").unwrap();
+        client.put(b"b", b"2").unwrap();
+        client.put(b"c", b"").unwrap();
+        assert_eq!(client.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(client.get(b"c").unwrap(), Some(Vec::new()));
+        assert_eq!(client.get(b"z").unwrap(), None);
+        client.delete(b"b").unwrap();
+        assert_eq!(client.scan(b"", 10).unwrap(), vec![(b"a".to_vec(), b"1".to_vec()), (b"c".to_vec(), Vec::new())]);
+        assert_eq!(client.scan(b"", 1).unwrap().len(), 1);
+        assert!(matches!(client.put(b"", b"v"), Err(Error::InvalidArgument(_))));
+
+        client.begin().unwrap();
+        assert!(matches!(client.begin(), Err(Error::InvalidArgument(_))));
+        client.put(b"a", b"tx").unwrap();
+        client.delete(b"c").unwrap();
+        assert_eq!(client.get(b"a").unwrap(), Some(b"tx".to_vec()));
+        assert_eq!(db.search(b"a", None).unwrap(), Some(b"1".to_vec()));
+        client.commit().unwrap();
+        assert_eq!((db.search(b"a", None).unwrap(), db.search(b"c", None).unwrap()), (Some(b"tx".to_vec()), None));
+        client.begin().unwrap();
+        client.put(b"a", b"aborted").unwrap();
+        client.abort().unwrap();
+        assert!(matches!(client.commit(), Err(Error::InvalidArgument(_))));
+        assert_eq!(client.get(b"a").unwrap(), Some(b"tx".to_vec()));
+    }
+
+    #[test]
+    fn a_transaction_left_open_is_aborted_with_its_connection() {
+        let (db, server) = serve("server_disconnect");
+        let mut client = KvClient::connect(server.local_addr()).unwrap();
+        client.begin().unwrap();
+        client.put(b"k", b"lost").unwrap();
+        drop(client);
+        //the write lock of the transaction is free again, or this would wait forever
+        let mut client = KvClient::connect(server.local_addr()).unwrap();
+        client.begin().unwrap();
+        client.put(b"k", b"kept").unwrap();
+        client.commit().unwrap();
+        assert_eq!(db.search(b"k", None).unwrap(), Some(b"kept".to_vec()));
+    }
+
+    #[test]
+    fn oversized_and_malformed_requests_are_refused() {
+        let db = Arc::new(LsmDb::with_config(temp_dir("server_limits"), Config::new()).unwrap());
+        let mut config = ServerConfig::new();
+        config.max_request_bytes = 100;
+        config.max_scan_entries = 2;
+        let server = KvServer::serve_with_config("127.0.0.1:0", db, config).unwrap();
+        let mut client = KvClient::connect(server.local_addr()).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            client.put(key, b"v").unwrap();
+        }
+        assert_eq!(client.scan(b"", 10).unwrap().len(), 2);
+        assert!(matches!(client.call(Opcode::Put, &[b"k"]), Err(Error::InvalidArgument(msg)) if msg.contains("missing a field")));
+        assert!(matches!(client.put(b"k", &[0; 100]), Err(Error::InvalidArgument(msg)) if msg.contains("at most 100")));
+        //the server hung up after the oversized request
+        assert!(matches!(client.get(b"a"), Err(Error::Io(_))));
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(&[0, 0, 0, 1, 99]).unwrap();
+        let mut response = [0; 5];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(&response[..], &[0, 0, 0, 1 + 4 + 17, Status::BadRequest as u8][..5]);
+    }
+
+    #[test]
+    fn close_stops_the_server_before_the_last_flush() {
+        let (db, server) = serve("server_close");
+        let addr = server.local_addr();
+        let mut client = KvClient::connect(addr).unwrap();
+        client.put(b"k", b"v").unwrap();
+        db.close().unwrap();
+        assert!(client.get(b"k").is_err());
+        assert!(KvClient::connect(addr).and_then(|mut c| c.get(b"k")).is_err());
+        assert!(matches!(db.insert(b"k", b"w"), Err(Error::Closed)));
+        assert_eq!(db.search(b"k", None).unwrap(), Some(b"v".to_vec()));
+        assert_eq!(db.stats().levels[0].num_files, 1);
+        drop(server);
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::perf_context::PerfContext;
+
+//keys are cut to this many bytes in the log
+const MAX_LOGGED_KEY_LEN: usize = 64;
+
+//the threshold while there is none, nothing takes that long
+const DISABLED: u64 = u64::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowOp {
+    Get,
+    Put,
+    Commit, //of a transaction
+    Flush,  //of the immutable mem table, on the flush thread
+}
+
+impl SlowOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            SlowOp::Get => "get",
+            SlowOp::Put => "put",
+            SlowOp::Commit => "commit",
+            SlowOp::Flush => "flush",
+        }
+    }
+}
+
+/// Warns through the `log` crate about every operation that took longer than the threshold,
+/// with what it did on the way. The threshold can change while the database is open.
+#[derive(Debug)]
+pub struct SlowOpLog {
+    threshold_nanos: AtomicU64,
+    count: AtomicU64, //operations logged so far
+}
+
+impl SlowOpLog {
+    pub fn new(threshold: Option<Duration>) -> Self {
+        let log = SlowOpLog {
+            threshold_nanos: AtomicU64::new(DISABLED),
+            count: AtomicU64::new(0),
+        };
+        log.set_threshold(threshold);
+        log
+    }
+
+    pub fn set_threshold(&self, threshold: Option<Duration>) {
+        let nanos = threshold.map_or(DISABLED, |t| std::cmp::min(t.as_nanos(), (DISABLED - 1) as u128) as u64);
+        self.threshold_nanos.store(nanos, Ordering::Relaxed);
+    }
+
+    //operations only collect what they did on the way while there is a threshold
+    pub fn enabled(&self) -> bool {
+        self.threshold_nanos.load(Ordering::Relaxed) != DISABLED
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self, op: SlowOp, elapsed: Duration, key: Option<&[u8]>, perf: &PerfContext, write_stall: bool) {
+        let threshold = self.threshold_nanos.load(Ordering::Relaxed);
+        if threshold == DISABLED || elapsed.as_nanos() <= threshold as u128 {
+            return;
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        log::warn!(target: "draft_kv::slow_op",
+            "slow {}: elapsed={:?} key={} tables_probed={} blocks_read={} block_cache_hits={} write_stall={}",
+            op.name(), elapsed, key.map_or(String::from("-"), escape_key), perf.table_probes.len(),
+            perf.blocks_read, perf.block_cache_hits, write_stall);
+    }
+}
+
+//printable ascii stays as it is, the rest is escaped and long keys are cut with their length noted
+fn escape_key(key: &[u8]) -> String {
+    let shown = &key[..std::cmp::min(key.len(), MAX_LOGGED_KEY_LEN)];
+    let mut escaped = format!("\"{}\"", shown.escape_ascii());
+    if shown.len() < key.len() {
+        escaped.push_str(&format!("...({} bytes)", key.len()));
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_escaped_and_cut() {
+        assert_eq!(escape_key(b"user:42"), "\"user:42\"");
+        assert_eq!(escape_key(b"a\x00\xff\"b"), "\"a\\x00\\xff\\\"b\"");
+        let long = vec![b'k'; 100];
+        assert_eq!(escape_key(&long), format!("\"{}\"...(100 bytes)", "k".repeat(MAX_LOGGED_KEY_LEN)));
+    }
+
+    #[test]
+    fn only_operations_past_the_threshold_count() {
+        let log = SlowOpLog::new(None);
+        let perf = PerfContext::default();
+        log.check(SlowOp::Get, Duration::from_secs(60), Some(b"key"), &perf, false);
+        assert!(!log.enabled());
+        log.set_threshold(Some(Duration::from_millis(10)));
+        log.check(SlowOp::Get, Duration::from_millis(10), Some(b"key"), &perf, false);
+        log.check(SlowOp::Put, Duration::from_millis(11), Some(b"key"), &perf, true);
+        log.check(SlowOp::Flush, Duration::from_secs(1), None, &perf, false);
+        assert_eq!(log.count(), 2);
+        log.set_threshold(None);
+        log.check(SlowOp::Commit, Duration::from_secs(1), None, &perf, false);
+        assert_eq!(log.count(), 2);
+    }
+}
@@ -1,21 +1,54 @@
+use std::borrow::{Borrow, Cow};
 use std::cmp::Ordering;
-use std::collections::BTreeSet;
-use std::collections::HashMap;
-use std::fs::{remove_file, File, OpenOptions};
-use std::io::{self, BufWriter, Read, Write};
-use std::os::unix::fs::FileExt;
-use std::sync::atomic::{self, AtomicU64};
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::Arc;
 use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
 
-use crate::key::{InternalKey, LookUpKey};
-use crate::lsm::Config;
+use crate::bloom;
+use crate::cache::BlockCache;
+use crate::compaction_filter::{self, CompactionFilter, FilterDecision};
+use crate::db_iter::Cursor;
+use crate::env::{Advice, Env, RandomAccessFile};
+use crate::key::{checked_value, split_timestamp, strip_timestamp, verify_value_checksum, InternalKey, LookUpKey, ValueType, MAX_SEQ_NUM};
+use crate::error::{Error, Result};
+use crate::events::{DbEvent, EventBus, TableFile};
+use crate::fault;
+use crate::lsm::{CompactionStyle, Config, Preload};
+use crate::manifest::Manifest;
 use crate::memtable::MemTable;
+use crate::perf_context;
+use crate::prefix_extractor::PrefixExtractor;
+use crate::rate_limiter::RateLimiter;
+use crate::stats::{CacheStats, CompactionStats, CompactionSummary, LevelStats, SplitSummary, TableStats};
 use crate::utils::*;
+use crate::value_log::{self, ValueLogRef, ValuePointer, ValueLogWriter, ValueLogs};
+use crate::wal::Log;
 
+use bytes::Bytes;
 use itertools::Itertools;
+use parking_lot::Mutex;
+
+//table formats, a table is read back in the format recorded in its footer
+pub const LEGACY_FORMAT: u32 = 0; //8 byte lengths, the footer has no version
+pub const VARINT_FORMAT: u32 = 1; //varint lengths
+pub const PROPERTIES_FORMAT: u32 = 2; //named properties in the meta index block
+pub const VALUE_CHECKSUM_FORMAT: u32 = 3; //puts may carry a checksum of their key and value
+//only tables with checked puts are written in VALUE_CHECKSUM_FORMAT, the others stay readable by older builds
+pub const CURRENT_FORMAT: u32 = PROPERTIES_FORMAT;
+const NEWEST_FORMAT: u32 = VALUE_CHECKSUM_FORMAT;
+
+//versioned footers end with the magic in the high half and the version in the low half of a u64,
+//a legacy footer ends with the index block address, which never gets that big
+const FOOTER_MAGIC: u64 = 0x6472_6166_0000_0000;
+const LEGACY_FOOTER_LEN: u64 = 48;
 
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Footer {
+    format_version: u32,
     level: usize,
     min_key_addr: u64,  //For look up key
     max_key_addr: u64,  //For look up key
@@ -26,79 +59,247 @@ pub struct Footer {
 }
 
 impl Footer {
-    pub fn decode_from(sst_file: &File) -> Self {
-        let file_len = sst_file.metadata().unwrap().len();
-        let mut footer = vec![0; 48];
-        sst_file.read_exact_at(
+    pub fn decode_from(sst_file: &dyn RandomAccessFile) -> Result<Self> {
+        let file_len = sst_file.size()?;
+        if file_len < LEGACY_FOOTER_LEN {
+            return Err(Error::Corruption(format!("table of {} bytes is too short for a footer", file_len)));
+        }
+        let mut trailer = [0; 8];
+        sst_file.read_at(&mut trailer, file_len - 8)?;
+        let trailer = u64::from_le_bytes(trailer);
+        let (format_version, footer_len) = if trailer & !0xffff_ffff == FOOTER_MAGIC {
+            (trailer as u32, LEGACY_FOOTER_LEN + 8)
+        } else {
+            (LEGACY_FORMAT, LEGACY_FOOTER_LEN)
+        };
+        if format_version > NEWEST_FORMAT || file_len < footer_len {
+            return Err(Error::Corruption(format!("unsupported table format {}", format_version)));
+        }
+        let mut footer = vec![0; LEGACY_FOOTER_LEN as usize];
+        sst_file.read_at(
             footer.as_mut_slice(),
-            file_len - 48,
-        ).unwrap();
+            file_len - footer_len,
+        )?;
 
-        let level = to_usize(&footer[0..8]);
-        let min_key_addr = to_u64(&footer[8..16]);
-        let max_key_addr = to_u64(&footer[16..24]);
-        let last_seq_num = to_u64(&footer[24..32]);
-        let meta_index_block_addr = to_u64(&footer[32..40]);
-        let index_block_addr = to_u64(&footer[40..48]);
-        Footer {
+        let level = to_len(read_u64_exact(&footer[0..8])?)?;
+        let min_key_addr = read_u64_exact(&footer[8..16])?;
+        let max_key_addr = read_u64_exact(&footer[16..24])?;
+        let last_seq_num = read_u64_exact(&footer[24..32])?;
+        let meta_index_block_addr = read_u64_exact(&footer[32..40])?;
+        let index_block_addr = read_u64_exact(&footer[40..48])?;
+        Ok(Footer {
+            format_version,
             level,
             min_key_addr,
             max_key_addr,
             last_seq_num,
             meta_index_block_addr,
             index_block_addr,
-            foot_addr: file_len - 48,
-        }
+            foot_addr: file_len - footer_len,
+        })
     }
 
     pub fn encode_to(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(48);
-        buf.extend_from_slice(&self.level.to_le_bytes());
+        let mut buf = Vec::with_capacity(LEGACY_FOOTER_LEN as usize + 8);
+        buf.extend_from_slice(&(self.level as u64).to_le_bytes());
         buf.extend_from_slice(&self.min_key_addr.to_le_bytes());
         buf.extend_from_slice(&self.max_key_addr.to_le_bytes());
         buf.extend_from_slice(&self.last_seq_num.to_le_bytes());
         buf.extend_from_slice(&self.meta_index_block_addr.to_le_bytes());
         buf.extend_from_slice(&self.index_block_addr.to_le_bytes());
+        if self.format_version != LEGACY_FORMAT {
+            buf.extend_from_slice(&(FOOTER_MAGIC | self.format_version as u64).to_le_bytes());
+        }
         buf
     }
 
 }
 
+fn put_length(buf: &mut Vec<u8>, length: u64, format_version: u32) {
+    if format_version >= VARINT_FORMAT {
+        put_varint64(buf, length);
+    } else {
+        buf.extend_from_slice(&length.to_le_bytes());
+    }
+}
+
+fn get_length(bytes: &[u8], offset: &mut usize, format_version: u32) -> Result<u64> {
+    if format_version >= VARINT_FORMAT {
+        get_varint64(bytes, offset)
+    } else {
+        get_fixed64(bytes, offset)
+    }
+}
+
+//What a table records about itself besides its entries, kept in the meta index block.
+//Older formats only kept the write times there, as bare pairs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    write_times: Vec<(u64, u64)>, //seq num, unix millis; carried along by compactions
+    prefix_extractor: Option<String>, //name of the extractor the prefix filter was built by
+    prefix_filter: Vec<u8>,
+    key_filter: Vec<u8>, //over the user keys for lookups, empty in tables written without one
+    block_checksums: Vec<u32>, //crc32c of each data block, checked by paranoid reads
+    block_entries: Vec<u32>, //entries in each data block, empty in tables written before they were counted
+    num_entries: Option<u64>, //None in tables written before they were counted
+    num_deletions: Option<u64>,
+    value_log_bytes: BTreeMap<u64, u64>, //the record bytes the pointers lead to, by value log
+}
+
+impl TableProperties {
+    pub fn encode_to(&self, buf: &mut Vec<u8>, format_version: u32) {
+        let mut write_times = Vec::with_capacity(self.write_times.len() * 16);
+        for (seq_num, millis) in self.write_times.iter() {
+            write_times.extend_from_slice(&seq_num.to_le_bytes());
+            write_times.extend_from_slice(&millis.to_le_bytes());
+        }
+        if format_version < PROPERTIES_FORMAT {
+            buf.extend_from_slice(&write_times);
+            return;
+        }
+        //each property is its name and value, both prefixed with their length
+        let mut put_property = |name: &str, value: &[u8]| {
+            put_varint64(buf, name.len() as u64);
+            buf.extend_from_slice(name.as_bytes());
+            put_varint64(buf, value.len() as u64);
+            buf.extend_from_slice(value);
+        };
+        if !write_times.is_empty() {
+            put_property("write_times", &write_times);
+        }
+        if let Some(name) = &self.prefix_extractor {
+            put_property("prefix_extractor", name.as_bytes());
+            put_property("prefix_filter", &self.prefix_filter);
+        }
+        if !self.key_filter.is_empty() {
+            put_property("key_filter", &self.key_filter);
+        }
+        if !self.block_checksums.is_empty() {
+            let checksums = self.block_checksums.iter().flat_map(|c| c.to_le_bytes()).collect::<Vec<_>>();
+            put_property("block_checksums", &checksums);
+        }
+        if !self.block_entries.is_empty() {
+            let entries = self.block_entries.iter().flat_map(|n| n.to_le_bytes()).collect::<Vec<_>>();
+            put_property("block_entries", &entries);
+        }
+        if let Some(num_entries) = self.num_entries {
+            put_property("num_entries", &num_entries.to_le_bytes());
+        }
+        if let Some(num_deletions) = self.num_deletions {
+            put_property("num_deletions", &num_deletions.to_le_bytes());
+        }
+        if !self.value_log_bytes.is_empty() {
+            let bytes = self.value_log_bytes.iter().flat_map(|(num, bytes)| [num.to_le_bytes(), bytes.to_le_bytes()].concat()).collect::<Vec<_>>();
+            put_property("value_log_bytes", &bytes);
+        }
+    }
+
+    pub fn decode_from(bytes: &[u8], format_version: u32) -> Result<Self> {
+        let mut properties = TableProperties::default();
+        if format_version < PROPERTIES_FORMAT {
+            properties.write_times = Self::decode_write_times(bytes)?;
+            return Ok(properties);
+        }
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let name = get_property(bytes, &mut offset)?;
+            let value = get_property(bytes, &mut offset)?;
+            match name {
+                b"write_times" => properties.write_times = Self::decode_write_times(value)?,
+                b"prefix_extractor" => properties.prefix_extractor = Some(String::from_utf8_lossy(value).into_owned()),
+                b"prefix_filter" => properties.prefix_filter = value.to_vec(),
+                b"key_filter" => properties.key_filter = value.to_vec(),
+                b"block_checksums" if value.len() % 4 == 0 => {
+                    properties.block_checksums = value.chunks(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+                },
+                b"block_checksums" => return Err(Error::Corruption(format!("block checksums of {} bytes", value.len()))),
+                b"block_entries" if value.len() % 4 == 0 => {
+                    properties.block_entries = value.chunks(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+                },
+                b"block_entries" => return Err(Error::Corruption(format!("block entry counts of {} bytes", value.len()))),
+                b"num_entries" => properties.num_entries = Some(read_u64_exact(value)?),
+                b"num_deletions" => properties.num_deletions = Some(read_u64_exact(value)?),
+                b"value_log_bytes" if value.len() % 16 == 0 => {
+                    properties.value_log_bytes = value.chunks(16)
+                        .map(|pair| Ok((read_u64_exact(&pair[..8])?, read_u64_exact(&pair[8..])?)))
+                        .collect::<Result<_>>()?;
+                },
+                b"value_log_bytes" => return Err(Error::Corruption(format!("value log bytes of {} bytes", value.len()))),
+                //written by a newer version, tables stay readable without it
+                _ => {},
+            }
+        }
+        Ok(properties)
+    }
+
+    fn decode_write_times(bytes: &[u8]) -> Result<Vec<(u64, u64)>> {
+        if !bytes.len().is_multiple_of(16) {
+            return Err(Error::Corruption(format!("write times of {} bytes", bytes.len())));
+        }
+        bytes.chunks(16)
+            .map(|pair| Ok((read_u64_exact(&pair[..8])?, read_u64_exact(&pair[8..])?)))
+            .collect()
+    }
+}
+
+fn get_property<'a>(bytes: &'a [u8], offset: &mut usize) -> Result<&'a [u8]> {
+    let len = get_varint64(bytes, offset)?;
+    let end = checked_end(*offset, len, bytes.len())
+        .ok_or_else(|| Error::Corruption(format!("table property truncated at offset {}", offset)))?;
+    let res = &bytes[*offset..end];
+    *offset = end;
+    Ok(res)
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct DataBlockEntry {
     look_up_key: LookUpKey,
-    value: Vec<u8>,
+    value: Bytes,
 }
 
 impl DataBlockEntry {
-    pub fn new(look_up_key: LookUpKey, value: Vec<u8>) -> Self {
-        DataBlockEntry {
+    //the key and value are views into the block, nothing is copied.
+    //The value of a checked put ends with its checksum.
+    pub fn decode_from(bytes: &Bytes, offset: &mut u64, format_version: u32) -> Result<Self> {
+        let look_up_key = Self::decode_look_up_key(bytes, offset, format_version)?;
+        let mut cur = *offset as usize;
+        let value_len = get_length(bytes, &mut cur, format_version)?;
+        let end = checked_end(cur, value_len, bytes.len())
+            .ok_or_else(|| Error::Corruption(format!("value truncated at offset {}", cur)))?;
+        let value = bytes.slice(cur..end);
+        *offset = end as u64;
+        Ok(DataBlockEntry {
             look_up_key,
             value,
-        }
+        })
     }
 
-    pub fn decode_from(bytes: &[u8], offset: &mut u64) -> Self {
-        let look_up_key = LookUpKey::decode_from_bytes(bytes, offset);
+    //only the key, the value is skipped over
+    pub fn decode_key_from(bytes: &Bytes, offset: &mut u64, format_version: u32) -> Result<LookUpKey> {
+        let look_up_key = Self::decode_look_up_key(bytes, offset, format_version)?;
         let mut cur = *offset as usize;
-        let mut next = (*offset + 8) as usize;
-        let value_len = to_u64(&bytes[cur..next]);
-        *offset += 8;
-        cur = *offset as usize;
-        next = (*offset + value_len) as usize;
-        DataBlockEntry {
-            look_up_key,
-            value: bytes[cur..next].to_vec(),
-        }
+        let value_len = get_length(bytes, &mut cur, format_version)?;
+        let end = checked_end(cur, value_len, bytes.len())
+            .ok_or_else(|| Error::Corruption(format!("value truncated at offset {}", cur)))?;
+        *offset = end as u64;
+        Ok(look_up_key)
     }
 
-    pub fn encode_to(&self) -> Vec<u8> {
-        let mut buf = self.look_up_key.encode_to();
-        for b in &u64::to_le_bytes(self.value.len() as u64) {
-            buf.push(*b);
+    //value checksums came with VALUE_CHECKSUM_FORMAT, an older table can not have them
+    fn decode_look_up_key(bytes: &Bytes, offset: &mut u64, format_version: u32) -> Result<LookUpKey> {
+        let start = *offset;
+        let look_up_key = LookUpKey::decode_from_bytes(bytes, offset, format_version >= VARINT_FORMAT)?;
+        if look_up_key.has_value_checksum() && format_version < VALUE_CHECKSUM_FORMAT {
+            return Err(Error::Corruption(format!("value checksum at offset {} of a table in format {}", start, format_version)));
         }
-        buf.extend_from_slice(&self.value);
-        buf
+        Ok(look_up_key)
+    }
+
+    //writers append their entries directly, without building a DataBlockEntry first
+    pub fn encode_entry(buf: &mut Vec<u8>, look_up_key: &LookUpKey, value: &[u8], format_version: u32) {
+        look_up_key.encode_to(buf, format_version >= VARINT_FORMAT);
+        put_length(buf, value.len() as u64, format_version);
+        buf.extend_from_slice(value);
     }
     
 }
@@ -119,462 +320,4475 @@ impl IndexBlockEntry {
         }
     }
 
-    pub fn decode_from(sst_file: &File, addr: &mut u64) -> Self {
-        let max_key = LookUpKey::decode_from_file(sst_file, addr);
-        //read offset
-        let mut offset = vec![0; 8];
-        sst_file.read_exact_at(
-            offset.as_mut_slice(),
-            *addr,
-        ).unwrap();
-        *addr += 8;
-        let offset = to_u64(&offset);
-        //read length
-        let mut length = vec![0; 8];
-        sst_file.read_exact_at(
-            length.as_mut_slice(),
-            *addr,
-        ).unwrap();
-        *addr += 8;
-        let length = to_u64(&length);
-        IndexBlockEntry {
+    pub fn decode_from(bytes: &Bytes, addr: &mut u64, format_version: u32) -> Result<Self> {
+        let max_key = LookUpKey::decode_from_bytes(bytes, addr, format_version >= VARINT_FORMAT)?;
+        let mut cur = *addr as usize;
+        let offset = get_length(bytes, &mut cur, format_version)?;
+        let length = get_length(bytes, &mut cur, format_version)?;
+        *addr = cur as u64;
+        Ok(IndexBlockEntry {
             max_key,
             offset,
             length,
-        }
+        })
     }
 
-    pub fn encode_to(&self) -> Vec<u8> {
-        let mut buf = self.max_key.encode_to();
-        for b in &u64::to_le_bytes(self.offset) {
-            buf.push(*b);
+    pub fn encode_to(&self, buf: &mut Vec<u8>, format_version: u32) {
+        self.max_key.encode_to(buf, format_version >= VARINT_FORMAT);
+        put_length(buf, self.offset, format_version);
+        put_length(buf, self.length, format_version);
+    }
+}
+
+/// The entries of a table iterator up to the first one that can not be read. The error is left in `error`,
+/// for whoever merges the entries to check once it is done with them.
+pub fn until_error<'a, I>(iter: I, error: &'a Mutex<Option<Error>>) -> impl Iterator<Item = (LookUpKey, Bytes)> + 'a
+where
+    I: Iterator<Item = Result<(LookUpKey, Bytes)>> + 'a,
+{
+    iter.map_while(move |res| match res {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+            error.lock().get_or_insert(e);
+            None
+        },
+    })
+}
+
+/// Two key ranges overlap only when each one starts before the other ends.
+pub fn ranges_overlap<T: PartialOrd>(a_min: &T, a_max: &T, b_min: &T, b_max: &T) -> bool {
+    a_min <= b_max && b_min <= a_max
+}
+
+/// Lazily merges sorted runs, keeping only the newest version of each user key.
+/// The version with the highest sequence number wins; when two inputs carry the same
+/// sequence number for a key, the one from the earlier input wins, so callers pass newer runs first.
+pub fn merge_newest<I, V>(iters: Vec<I>) -> impl Iterator<Item = (LookUpKey, V)>
+where
+    I: Iterator<Item = (LookUpKey, V)>,
+{
+    merge_visible(iters, Vec::new())
+}
+
+/// Like `merge_newest`, but also keeps the newest version of each user key at or below every
+/// sequence number in `snapshots`, which is sorted. Versions are merged only within a stripe,
+/// the sequence numbers between two snapshots.
+#[allow(clippy::result_large_err)] //coalesce takes its pairs back through the Err side
+pub fn merge_visible<I, V>(iters: Vec<I>, snapshots: Vec<u64>) -> impl Iterator<Item = (LookUpKey, V)>
+where
+    I: Iterator<Item = (LookUpKey, V)>,
+{
+    let stripe = move |seq_num: u64| snapshots.partition_point(|&snapshot| snapshot < seq_num);
+    iters.into_iter()
+        .enumerate()
+        .map(|(input_idx, iter)| {
+            let mut last: Option<LookUpKey> = None;
+            iter.map(move |(k, v)| {
+                if cfg!(debug_assertions) {
+                    debug_assert!(last.as_ref().is_none_or(|last| *last <= k), "merge input is not sorted");
+                    last = Some(k.clone());
+                }
+                (k, input_idx, v)
+            })
+        })
+        .kmerge_by(|a, b| (&a.0, a.1) < (&b.0, b.1))
+        .coalesce(move |a, b| {
+            if a.0.get_user_key() != b.0.get_user_key() || stripe(a.0.get_seq_num()) != stripe(b.0.get_seq_num()) {
+                return Err((a, b));
+            }
+            let b_is_newer = b.0.get_seq_num() > a.0.get_seq_num()
+                || (b.0.get_seq_num() == a.0.get_seq_num() && b.1 < a.1);
+            Ok(if b_is_newer { b } else { a })
+        })
+        .map(|(k, _, v)| (k, v))
+}
+
+//Drop the tombstones `can_drop` allows. A tombstone followed by an older version of its key,
+//kept for a snapshot, has to stay or the older version would show through again.
+fn drop_tombstones<I, F>(iter: I, mut can_drop: F) -> impl Iterator<Item = (LookUpKey, Bytes)>
+where
+    I: Iterator<Item = (LookUpKey, Bytes)>,
+    F: FnMut(&LookUpKey) -> bool,
+{
+    let mut iter = iter.peekable();
+    std::iter::from_fn(move || loop {
+        let (k, v) = iter.next()?;
+        let oldest = !matches!(iter.peek(), Some((next, _)) if next.get_user_key() == k.get_user_key());
+        if !(k.is_deletion() && oldest && can_drop(&k)) {
+            return Some((k, v));
         }
-        for b in &u64::to_le_bytes(self.length) {
-            buf.push(*b);
+    })
+}
+
+//number of adjacent tables rewritten together by a bottom level compaction
+const BOTTOM_COMPACTION_BATCH: usize = 4;
+
+//what a table found for a key: the sequence number of the version and its value, None if it is a tombstone
+type Found = Option<(u64, Option<Bytes>)>;
+
+//a table with what it found for each key index looked up in it
+type TableLookups<'a> = (&'a Arc<Table>, Vec<(usize, Found)>);
+
+//the tables a compaction deleted, by level and file name, and the ones it wrote
+type CompactionOutput = (Vec<(usize, PathBuf)>, Vec<Table>);
+
+//[start, end) by user key, None is unbounded
+type KeyRange = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+fn merge_write_times(tables: &[&Table]) -> Vec<(u64, u64)> {
+    let mut write_times = tables.iter()
+        .flat_map(|t| t.properties.write_times.iter().cloned())
+        .collect::<Vec<_>>();
+    write_times.sort();
+    write_times.dedup();
+    write_times
+}
+
+pub fn parse_file_num(sst_file: &Path) -> u64 {
+    sst_file.file_stem()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse::<u64>()
+        .unwrap()
+}
+
+//`f` over the items on up to `threads` threads, the results keep the order of the items
+fn parallel_map<T: Sync, R: Send>(items: &[T], threads: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    if threads <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let chunk_len = items.len().div_ceil(threads);
+    crossbeam_utils::thread::scope(|s| {
+        let f = &f;
+        let handles = items.chunks(chunk_len)
+            .map(|chunk| s.spawn(move |_| chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        handles.into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    }).unwrap()
+}
+
+//Obsolete files that stay on disk while file deletions are disabled, so an external copy of the live files
+//can finish. They go once the last disable_file_deletions is matched by an enable_file_deletions.
+#[derive(Default)]
+struct FileDeletions {
+    disabled: usize,
+    tables: Vec<Arc<Table>>,
+    logs: Vec<Log>,
+}
+
+//marks the inputs of a compaction while it runs, they are unmarked when it is dropped
+struct Compacting {
+    file_nums: Arc<Mutex<HashSet<u64>>>,
+    inputs: Vec<u64>,
+}
+
+impl Drop for Compacting {
+    fn drop(&mut self) {
+        let mut file_nums = self.file_nums.lock();
+        for file_num in self.inputs.iter() {
+            file_nums.remove(file_num);
         }
-        buf
     }
 }
 
+//bytes of a key Levels::dump shows, the rest is cut off
+const DUMP_KEY_LEN: usize = 24;
+
+fn dump_key(key: &[u8]) -> String {
+    match key.len() > DUMP_KEY_LEN {
+        true => format!("{}...", key[..DUMP_KEY_LEN].escape_ascii()),
+        false => key.escape_ascii().to_string(),
+    }
+}
+
+//A clone shares the tables and the bookkeeping below but has its own level lists,
+//so a long compaction works on a copy while `update` installs other results meanwhile.
+#[derive(Clone)]
 pub struct Levels {
     db_path: PathBuf,
-    inner: Vec<BTreeSet<Table>>,
-    next_file_num: AtomicU64,
+    env: Arc<dyn Env>,
+    inner: Vec<BTreeSet<Arc<Table>>>, //shared with in-flight readers, a file is deleted once its last reader is gone
+    level_bytes: Vec<u64>, //the size of the tables of each level, kept up to date with `inner`
+    max_levels: usize, //`inner` starts with levels 0 and 1 and grows a new bottom level up to this many
+    level0_files: Arc<AtomicUsize>, //the tables of level 0, for writers to check without the lock on the levels
+    next_file_num: Arc<AtomicU64>,
     block_size: usize,
     l0_compaction_threshold: usize,
+    l0_intra_compaction_threshold: usize,
     l1_max_bytes: u64,
+    level_size_multiplier: u64,
+    level_max_bytes: Vec<u64>,
+    target_file_size_base: u64,
+    target_file_size_multiplier: u64,
+    max_subcompactions: usize,
+    compaction_style: CompactionStyle,
+    universal_size_ratio: u64,
+    tombstone_compaction_ratio: f64,
+    rate_limiter: Arc<RateLimiter>, //only background flushes and compactions are throttled
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    user_timestamp_size: usize,
+    user_timestamp_horizon: Option<Vec<u8>>,
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    bloom_bits_per_key: f64,
+    bloom_bits_per_key_per_level: Vec<f64>,
+    value_log_threshold: usize,
+    value_logs: Arc<ValueLogs>, //shared with the tables, which read the values their pointers lead to
+    paranoid_checks: bool,
+    readahead_size: usize,
+    evict_compaction_output: bool,
+    preallocate_sst: bool,
+    blocks_read: Arc<AtomicU64>, //data blocks read by lookups, scans and compactions
+    block_cache: Option<Arc<BlockCache>>,
+    pin_l0_blocks: bool,
+    pin_index_and_filter_blocks: bool,
+    preloaded_bytes: u64,
+    meta_bytes: Arc<AtomicUsize>, //index and filter blocks held by the open tables
+    compact_pointers: Arc<Mutex<Vec<Option<LookUpKey>>>>, //max key of the last table compacted out of each level
+    compacting: Arc<Mutex<HashSet<u64>>>, //file numbers of the inputs of the compactions running now
+    snapshots: Arc<Mutex<BTreeMap<u64, usize>>>, //sequence numbers pinned by live snapshots, with their number of handles
+    compaction_stats: Arc<Mutex<CompactionStats>>,
+    events: Arc<EventBus>, //shared with the database and the tables
+    file_deletions: Arc<Mutex<FileDeletions>>,
+    obsolete_bytes: Arc<AtomicU64>, //of the tables that are no longer live, until their files are deleted
+    #[cfg(test)]
+    pub failed_writes: Arc<std::sync::atomic::AtomicUsize>, //number of upcoming table writes to fail
 }
 
 impl Levels {
-    pub fn new(db_path: PathBuf, sst_list: Vec<PathBuf>, config: &Config) -> Self {
-        let mut levels = Vec::with_capacity(config.max_levels);
-        for _ in 0..config.max_levels {
-            levels.push(BTreeSet::new());
-        }
+    #[cfg(test)]
+    pub fn new(db_path: PathBuf, sst_list: Vec<PathBuf>, config: &Config) -> Result<Self> {
+        Self::open(db_path, sst_list, config, false)
+    }
+
+    //read-only levels leave the files they do not use where they are
+    pub fn open(db_path: PathBuf, sst_list: Vec<PathBuf>, config: &Config, read_only: bool) -> Result<Self> {
+        let mut levels = vec![BTreeSet::new(); std::cmp::min(config.max_levels, 2)];
         let mut max_file_num = 0;
+        let blocks_read = Arc::new(AtomicU64::new(0));
+        let events = Arc::new(EventBus::default());
+        let obsolete_bytes = Arc::new(AtomicU64::new(0));
+        let meta_bytes = Arc::new(AtomicUsize::new(0));
+        let block_cache = match config.block_cache_size {
+            0 => None,
+            size => Some(Arc::new(BlockCache::new(size, config.block_cache_shard_bits))),
+        };
+        let manifest = Manifest::load(&*config.env, &db_path)?;
+        //tables written before the manifest existed all come from leveled compaction
+        let recorded_style = match &manifest {
+            Some(manifest) => Some(manifest.compaction_style),
+            None if !sst_list.is_empty() => Some(CompactionStyle::Leveled),
+            None => None,
+        };
+        if let Some(style) = recorded_style {
+            if style != config.compaction_style {
+                return Err(Error::InvalidArgument(format!(
+                    "database uses {:?} compaction, but {:?} is configured", style, config.compaction_style)));
+            }
+        }
+        //stored keys are read back by their timestamp size, so it can not change either
+        let recorded_timestamp_size = match &manifest {
+            Some(manifest) => Some(manifest.user_timestamp_size),
+            None if !sst_list.is_empty() => Some(0),
+            None => None,
+        };
+        if let Some(size) = recorded_timestamp_size {
+            if size != config.user_timestamp_size {
+                return Err(Error::InvalidArgument(format!(
+                    "database uses {} byte timestamps, but {} is configured", size, config.user_timestamp_size)));
+            }
+        }
         
+        //file numbers are shared by tables and value logs
+        let value_logs = Arc::new(ValueLogs::open(&config.env, &db_path)?);
+        max_file_num = value_logs.file_nums().into_iter().fold(max_file_num, std::cmp::max);
+        let mut sst_files = Vec::new();
         for sst_file in sst_list {
-            let num = sst_file.file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .parse::<u64>()
-                .unwrap();
+            let num = parse_file_num(&sst_file);
             max_file_num = std::cmp::max(num, max_file_num);
-            let table = Table::open(sst_file);
-            levels[table.get_level()].insert(table);
+            //written by a flush or compaction that crashed before installing it, its inputs are still live
+            if manifest.as_ref().is_some_and(|m| m.level_of(num).is_none()) {
+                if !read_only {
+                    log::info!("remove orphan table {:?}", sst_file);
+                    fault::remove_file(&*config.env, &sst_file)?;
+                }
+                continue;
+            }
+            sst_files.push(sst_file);
+        }
+        //a read-only open may list the directory before the owner wrote a table the manifest it loaded has
+        if let Some(manifest) = manifest.as_ref().filter(|_| read_only) {
+            let listed = sst_files.iter().map(|f| parse_file_num(f)).collect::<HashSet<_>>();
+            if let Some((num, _)) = manifest.tables.iter().find(|(num, _)| !listed.contains(num)) {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, format!("table {} of the manifest is not listed", num))));
+            }
+        }
+        //opening a table reads its index and filter blocks
+        let threads = match config.preload_on_open {
+            Preload::None => 1,
+            _ => config.read_parallelism,
+        };
+        let mut preloaded_bytes = 0;
+        //checked before the levels are grown, a damaged manifest may record any level
+        let too_deep = |num_levels: usize| Error::InvalidArgument(format!(
+            "database has {} levels, but max_levels is {}", num_levels, config.max_levels));
+        for table in parallel_map(&sst_files, threads, |sst_file| Table::open(&config.env, sst_file.clone())) {
+            let mut table = table?;
+            let num = table.get_file_num();
+            //a trivial move only updates the level in the manifest
+            if let Some(level) = manifest.as_ref().and_then(|m| m.level_of(num)) {
+                table.level = level;
+            }
+            table.blocks_read = blocks_read.clone();
+            table.set_block_cache(block_cache.clone(), config.pin_l0_blocks, config.pin_index_and_filter_blocks);
+            table.count_meta_bytes(meta_bytes.clone());
+            table.obsolete_bytes = Some(obsolete_bytes.clone());
+            table.events = Some(events.clone());
+            table.paranoid_checks = config.paranoid_checks;
+            table.readahead_size = config.readahead_size;
+            table.set_value_logs(&value_logs);
+            if config.preload_on_open != Preload::None {
+                preloaded_bytes += table.meta_size();
+            }
+            if table.get_level() >= config.max_levels {
+                return Err(too_deep(table.get_level() + 1));
+            }
+            if levels.len() <= table.get_level() {
+                levels.resize(table.get_level() + 1, BTreeSet::new());
+            }
+            levels[table.get_level()].insert(Arc::new(table));
+        }
+        //the levels the database grew, some of them may have no tables right now
+        let num_levels = manifest.as_ref().map_or(0, |m| m.num_levels);
+        if num_levels > config.max_levels {
+            return Err(too_deep(num_levels));
+        }
+        if levels.len() < num_levels {
+            levels.resize(num_levels, BTreeSet::new());
+        }
+        if config.preload_on_open == Preload::IndexesAndL0 && block_cache.is_some() {
+            let budget = AtomicU64::new(config.preload_budget_bytes);
+            let level0 = levels[0].iter().cloned().collect::<Vec<_>>();
+            preloaded_bytes += parallel_map(&level0, threads, |table| table.preload_blocks(&budget))
+                .into_iter().sum::<Result<u64>>()?;
         }
 
-        Self {
+        let level_bytes = levels.iter().map(|tables| tables.iter().map(|t| t.get_size()).sum()).collect();
+        let mut levels = Self {
             db_path,
+            env: config.env.clone(),
             inner: levels,
-            next_file_num: AtomicU64::new(max_file_num + 1),
+            level_bytes,
+            max_levels: config.max_levels,
+            level0_files: Arc::new(AtomicUsize::new(0)),
+            next_file_num: Arc::new(AtomicU64::new(max_file_num + 1)),
             block_size: config.block_size,
             l0_compaction_threshold: config.l0_compaction_threshold,
+            l0_intra_compaction_threshold: config.l0_intra_compaction_threshold,
             l1_max_bytes: config.l1_max_bytes,
+            level_size_multiplier: config.level_size_multiplier,
+            level_max_bytes: config.level_max_bytes.clone(),
+            target_file_size_base: config.target_file_size_base,
+            target_file_size_multiplier: config.target_file_size_multiplier,
+            max_subcompactions: config.max_subcompactions,
+            compaction_style: config.compaction_style,
+            universal_size_ratio: config.universal_size_ratio,
+            tombstone_compaction_ratio: config.tombstone_compaction_ratio,
+            rate_limiter: Arc::new(RateLimiter::new(config.compaction_rate_limit_bytes_per_sec)),
+            compaction_filter: config.compaction_filter.clone(),
+            user_timestamp_size: config.user_timestamp_size,
+            user_timestamp_horizon: config.user_timestamp_horizon.clone(),
+            prefix_extractor: config.prefix_extractor.clone(),
+            bloom_bits_per_key: config.bloom_bits_per_key,
+            bloom_bits_per_key_per_level: config.bloom_bits_per_key_per_level.clone(),
+            value_log_threshold: config.value_log_threshold,
+            value_logs,
+            paranoid_checks: config.paranoid_checks,
+            readahead_size: config.readahead_size,
+            evict_compaction_output: config.evict_compaction_output_from_page_cache,
+            preallocate_sst: config.preallocate_sst,
+            blocks_read,
+            block_cache,
+            pin_l0_blocks: config.pin_l0_blocks,
+            pin_index_and_filter_blocks: config.pin_index_and_filter_blocks,
+            preloaded_bytes,
+            meta_bytes,
+            compact_pointers: Arc::new(Mutex::new(vec![None; config.max_levels])),
+            compacting: Arc::new(Mutex::new(HashSet::new())),
+            snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+            compaction_stats: Arc::new(Mutex::new(CompactionStats::default())),
+            events,
+            file_deletions: Arc::new(Mutex::new(FileDeletions::default())),
+            obsolete_bytes,
+            #[cfg(test)]
+            failed_writes: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
+        };
+        levels.add_level();
+        levels.level0_files.store(levels.inner[0].len(), atomic::Ordering::Release);
+        //record the compaction style right away, a read-only open would overwrite what the owner saved meanwhile
+        if !read_only {
+            levels.manifest().save(&*levels.env, &levels.db_path)?;
         }
+        Ok(levels)
     }
 
-    pub fn background_compaction(&self, im_mem_table: Option<MemTable>, input_start: &Vec<Option<(LookUpKey, LookUpKey)>>) -> (Vec<(usize, PathBuf)>, Vec<Table>) {
+    pub fn background_compaction(&self, im_mem_table: Option<&MemTable>) -> Result<CompactionOutput> {
         match im_mem_table {
             Some(im_mem_table) => {
-                (Vec::new(), vec![self.write_level0_files(im_mem_table)])
+                Ok((Vec::new(), self.write_level0_files(im_mem_table)?.into_iter().collect()))
+            },
+            None if self.compaction_style == CompactionStyle::Universal => {
+                match self.pick_universal_compaction() {
+                    Some((start, end)) => self.compact_runs(start, end),
+                    None => Ok((Vec::new(), Vec::new())),
+                }
             },
             None => {
-                let max_levels = self.inner.len();
-                let mut deleted_tables = Vec::new();
-                let mut new_tables = Vec::new();
-                let mut src_table_idx = 0;
-                for (level_idx, (level, input_start)) in self.inner.iter().zip(input_start.iter()).enumerate() {
-                    let table_refs = level.iter().collect::<Vec<_>>();
-                    let table_sizes = level.iter()
-                        .map(|t| t.get_size())
-                        .collect::<Vec<_>>();
-                    let size_sum = table_sizes.iter().sum::<u64>();
-                    if (level_idx == 0 && level.len() > self.l0_compaction_threshold) || 
-                        (level_idx > 0 && size_sum > self.l1_max_bytes << (4*(level_idx-1)))
-                    {
-                        for (table_idx, &table) in table_refs.iter().enumerate() {
-                            if input_start.as_ref().filter(|(min_key, max_key)| 
-                                *min_key == table.min_key && *max_key == table.max_key
-                            ).is_some() {
-                                deleted_tables.push(table);
-                                src_table_idx = table_idx;
-                                break;
-                            }
-                        }
-                    }
-                    if !deleted_tables.is_empty() && level_idx < max_levels - 1 {
-                        let dst_level_idx = level_idx + 1;
-                        let mut dst_table_idx = usize::MAX;
-                        let dst_table_refs = self.inner[dst_level_idx].iter().collect::<Vec<_>>();
-                        let mut key_range = (&deleted_tables[0].min_key, &deleted_tables[0].max_key);
-                        for (table_idx, &table) in dst_table_refs.iter().enumerate() {
-                            //overlap
-                            if table.min_key <= *key_range.1 || *key_range.0 <= table.max_key {
-                                key_range.0 = std::cmp::min(&table.min_key, key_range.0);
-                                key_range.1 = std::cmp::max(&table.max_key, key_range.1);
-                                deleted_tables.push(table);
-                                dst_table_idx = table_idx;
-                            }
+                match self.pick_compaction() {
+                    Some((0, _)) if self.prefers_intra_level0() => self.compact_runs(0, self.inner[0].len()),
+                    Some((level_idx, table_idx)) => {
+                        if level_idx > 0 {
+                            let table = self.level_tables(level_idx).nth(table_idx).unwrap();
+                            self.compact_pointers.lock()[level_idx] = Some(table.max_key.clone());
                         }
-                        //sink directly without compaction
-                        if dst_table_idx == usize::MAX {
-                            assert!(deleted_tables.len() == 1);
-                            let iter = Box::new(deleted_tables[0].content().into_iter());
-                            let table = self.write_file(iter, dst_level_idx);
-                            new_tables.push(table);
+                        if level_idx == self.inner.len() - 1 {
+                            self.compact_bottom(table_idx)
                         } else {
-                            //src and dst take turn
-                            let mut last_len = 0;
-                            while deleted_tables.len() != last_len {
-                                last_len = deleted_tables.len();
-                                
-                                while src_table_idx + 1 < table_refs.len() {
-                                    src_table_idx += 1;
-                                    let min_key = &table_refs[src_table_idx].min_key;
-                                    let max_key = &table_refs[src_table_idx].max_key;
-                                    if min_key <= key_range.1 || key_range.0 <= max_key {
-                                        key_range.0 = std::cmp::min(min_key, key_range.0);
-                                        key_range.1 = std::cmp::max(max_key, key_range.1);
-                                        deleted_tables.push(table_refs[src_table_idx]);
-                                    } else {
-                                        src_table_idx -= 1;
-                                        break;
-                                    }
-                                }
-                                while dst_table_idx + 1 < dst_table_refs.len() {
-                                    dst_table_idx += 1;
-                                    let min_key = &dst_table_refs[dst_table_idx].min_key;
-                                    let max_key = &dst_table_refs[dst_table_idx].max_key;
-                                    if min_key <= key_range.1 || key_range.0 <= max_key {
-                                        key_range.0 = std::cmp::min(min_key, key_range.0);
-                                        key_range.1 = std::cmp::max(max_key, key_range.1);
-                                        deleted_tables.push(dst_table_refs[dst_table_idx]);
-                                    } else {
-                                        dst_table_idx -= 1;
-                                        break;
-                                    }
-                                }
-    
-                            }
+                            self.compact(level_idx, table_idx)
                         }
-                        //begin to compact
-                        let mut merged = deleted_tables.iter()
-                            .map(|x| x.content().into_iter())
-                            .kmerge()
-                            .collect::<Vec<_>>();
-                        //only keep the newest version for the same key
-                        merged.dedup_by_key(|(k, _)| k.get_user_key().to_vec());
-                        self.write_file(Box::new(merged.into_iter()), dst_level_idx);
-                        break;
-                    }
+                    },
+                    None => Ok((Vec::new(), Vec::new())),
                 }
-                (   
-                    deleted_tables.into_iter()
-                        .map(|x| (x.get_level(), x.file_name.clone()))
-                        .collect::<Vec<_>>(), 
-                    new_tables
-                )
             },
         }
     }
 
-    pub fn get_input_start(&self, mut last_input_start: Vec<Option<(LookUpKey, LookUpKey)>>) -> Vec<Option<(LookUpKey, LookUpKey)>> {
-        if last_input_start.is_empty() {
-            last_input_start = vec![None; self.inner.len()];
+    //a level the database has not grown yet has no tables
+    fn level_tables(&self, level_idx: usize) -> impl Iterator<Item = &Table> {
+        self.inner.get(level_idx).into_iter().flatten().map(|t| t.as_ref())
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.inner.len()
+    }
+
+    //Once the bottom level is over its budget a new bottom level is added below it, which the old one then
+    //compacts into like any other level. At max_levels the bottom level stays over budget and is only
+    //rewritten in place.
+    fn add_level(&mut self) {
+        let bottom = self.inner.len() - 1;
+        if bottom > 0 && self.inner.len() < self.max_levels && self.level_bytes[bottom] > self.max_bytes_for_level(bottom) {
+            self.inner.push(BTreeSet::new());
+            self.level_bytes.push(0);
         }
+    }
+
+    //the tables with the number of entries each holds, to weigh them by when sampling. A table written before
+    //entries were counted is taken to have as many per block as the others on average.
+    pub fn table_weights(&self) -> Vec<(Arc<Table>, u64)> {
+        let (counted_entries, counted_blocks) = self.inner.iter()
+            .flatten()
+            .filter_map(|t| Some((t.num_entries()?, t.index_block.len() as u64)))
+            .fold((0, 0), |(entries, blocks), (e, b)| (entries + e, blocks + b));
+        let per_block = std::cmp::max(counted_entries / std::cmp::max(counted_blocks, 1), 1);
+        self.inner.iter()
+            .flatten()
+            .map(|t| (t.clone(), t.num_entries().unwrap_or(t.index_block.len() as u64 * per_block)))
+            .collect()
+    }
+
+    //reads a data block of a table of the level, both picked at random, from the file and not the block cache.
+    //None if the level has no tables.
+    pub fn probe_level(&self, level: usize) -> Option<(PathBuf, Result<()>)> {
+        let random = random_u64() as usize;
+        let table = self.level_tables(level).nth(random % self.num_files_at_level(level).max(1))?;
+        let res = match table.index_block.len() {
+            0 => Ok(()),
+            blocks => table.read_checked_block((random >> 16) % blocks).map(|_| ()),
+        };
+        Some((table.file_name.clone(), res))
+    }
+
+    pub fn rate_limiter(&self) -> Arc<RateLimiter> {
+        self.rate_limiter.clone()
+    }
+
+    //LevelDB style scores: file count against the threshold for level 0, bytes against the budget for the others
+    pub fn compaction_scores(&self) -> Vec<f64> {
         self.inner.iter()
             .enumerate()
             .map(|(level_idx, level)| if level_idx == 0 {
-                level.last().map(|t| (t.min_key.clone(), t.max_key.clone()))
-            } else {
-                let mut input_start = None;
-                for table in level.iter() {
-                    if last_input_start[level_idx].as_ref().filter(|x| table.min_key >= x.1).is_some() {
-                        input_start = Some((table.min_key.clone(), table.max_key.clone()));
-                        break;
-                    }
-                }
-                if input_start.is_none() {
-                    input_start = level.first().map(|t| (t.min_key.clone(), t.max_key.clone()));
+                if level.is_empty() {
+                    0.0
+                } else {
+                    level.len() as f64 / self.l0_compaction_threshold as f64
                 }
-                input_start
+            } else {
+                let size_sum = level.iter().map(|t| t.get_size()).sum::<u64>();
+                size_sum as f64 / self.max_bytes_for_level(level_idx) as f64
             }).collect::<Vec<_>>()
     }
 
-    pub fn search(&self, key: &[u8], seq_num: u64) -> Option<Vec<u8>> {
-        let internal_key = InternalKey::new(key, seq_num, 1);
-        let look_up_key = LookUpKey::new(internal_key.clone());
-        for (level, tables) in self.inner.iter().enumerate() {
-            if tables.is_empty() {
-                continue; 
-            }
-            if level == 0 {
-                for table in tables {
-                    if table.min_key <= look_up_key && table.max_key >= look_up_key {
-                        let res = table.search(key, seq_num);
-                        if res.is_some() {
-                            return res.unwrap();
-                        }
-                    }
-                }
-            } else {
-                let table = tables.iter()
-                    .find(|table| table.min_key <= look_up_key && table.max_key >= look_up_key);
-                let res = table.map(|t| t.search(key, seq_num)).flatten();
-                if res.is_some() {
-                    return res.unwrap();
-                }
-            }
+    //the budget of a level below 0, saturating at u64::MAX for deep levels
+    pub fn max_bytes_for_level(&self, level_idx: usize) -> u64 {
+        if let Some(&max_bytes) = self.level_max_bytes.get(level_idx - 1) {
+            return max_bytes;
         }
-        None
+        (1..level_idx)
+            .try_fold(self.l1_max_bytes, |max_bytes, _| max_bytes.checked_mul(self.level_size_multiplier))
+            .unwrap_or(u64::MAX)
     }
 
-    pub fn update(&mut self, deleted_tables: Vec<(usize, PathBuf)>, new_tables: Vec<Table>) {
-        let mut deleted_table_map = HashMap::new();            
-        for (level, file_name) in deleted_tables {
-            let files = deleted_table_map.entry(level).or_insert(Vec::new());
-            files.push(file_name);
+    //level 0 tables are whole sorted runs and are never cut
+    pub fn target_file_size(&self, level_idx: usize) -> u64 {
+        if level_idx == 0 {
+            return u64::MAX;
         }
-        for (level, files) in deleted_table_map {
-            //remove table from levels
-            let deleted_tables = self.inner[level]
-                .drain_filter(|t| files.contains(&t.file_name))
-                .collect::<Vec<_>>();
-            drop(deleted_tables);
-            //detele corresponding sst files
-            for file_name in files {
-                remove_file(file_name).unwrap();
+        (1..level_idx)
+            .try_fold(self.target_file_size_base, |size, _| size.checked_mul(self.target_file_size_multiplier))
+            .unwrap_or(u64::MAX)
+    }
+
+    //the highest scoring level over its quota (score > 1) and the index of the table to compact in it
+    pub fn pick_compaction(&self) -> Option<(usize, usize)> {
+        let scores = self.compaction_scores();
+        let mut picked: Option<(usize, f64)> = None;
+        let last_level_idx = self.inner.len() - 1;
+        //tables that are mostly tombstones go ahead of the size based picks
+        if let Some(picked) = self.pick_tombstone_heavy() {
+            return Some(picked);
+        }
+        for (level_idx, score) in scores.into_iter().enumerate() {
+            //the last level has nowhere to sink to, rewriting it only pays off if it has something to reclaim
+            if level_idx == last_level_idx && !self.level_tables(level_idx).any(|t| t.is_moved()) {
+                continue;
+            }
+            if score > 1.0 && picked.is_none_or(|(_, best)| score > best) {
+                picked = Some((level_idx, score));
             }
         }
-
-        for table in new_tables {
-            self.inner[table.get_level()].insert(table);
+        match picked {
+            Some((level_idx, _)) => Some((level_idx, self.pick_table(level_idx))),
+            //with no level over its quota, go after a table that keeps making reads look further down
+            None => (1..last_level_idx)
+                .find_map(|level_idx| self.level_tables(level_idx)
+                    .position(|t| t.seeks_exhausted())
+                    .map(|table_idx| (level_idx, table_idx))),
         }
     }
 
-    pub fn write_level0_files(&self, mut im_mem_table: MemTable) -> Table {
-        let iter = Box::new(im_mem_table.take()
-            .into_iter()
-            .map(|(k, v)| (LookUpKey::new(k), v)));
-        let table = self.write_file(iter, 0);
-        im_mem_table.remove_writer();
-        table
+    //The first table over tombstone_compaction_ratio, with its level. Each compaction takes it a level further down
+    //until it is merged into the last level and its tombstones are dropped. A table that got to the last level
+    //by being moved is rewritten there, but one written there still holds tombstones a snapshot needs.
+    fn pick_tombstone_heavy(&self) -> Option<(usize, usize)> {
+        let last_level_idx = self.inner.len() - 1;
+        (0..=last_level_idx).find_map(|level_idx| self.level_tables(level_idx)
+            .position(|t| self.is_tombstone_heavy(t) && (level_idx < last_level_idx || t.is_moved()))
+            .map(|table_idx| (level_idx, table_idx)))
     }
 
-    pub fn write_file(&self, iter: Box<dyn Iterator<Item = (LookUpKey, Vec<u8>)>>, level: usize) -> Table {
-        let mut sst_file = self.db_path.clone();
-        let next_file_num = self.next_file_num.fetch_add(1, atomic::Ordering::SeqCst);
-        sst_file.push(next_file_num.to_string());
-        sst_file.set_extension("sst");
-        let table = Table::new(sst_file, iter, level, self.block_size);
-        table
+    fn is_tombstone_heavy(&self, table: &Table) -> bool {
+        self.tombstone_compaction_ratio > 0.0
+            && matches!(table.tombstone_ratio(), Some(ratio) if ratio > self.tombstone_compaction_ratio)
     }
 
-}
-
-#[derive(Debug)]
-pub struct Table {
-    file_name: PathBuf,
-    file: File,
-    footer: Footer,
-    index_block: Vec<IndexBlockEntry>,
-    min_key: LookUpKey,
-    max_key: LookUpKey,
-}
-
-impl Table {
-    pub fn new(sst_file: PathBuf, iter: Box<dyn Iterator<Item = (LookUpKey, Vec<u8>)>>, level: usize, block_size: usize) -> Self {
-        let mut file = OpenOptions::new().create(true).append(true).read(true).open(&sst_file).unwrap();
-        let mut buf = Vec::new();
-        let mut index_block = Vec::new();
-        let mut data_block = Vec::new();
-        let data = iter.collect::<Vec<_>>();
-        let min_key = data.first().unwrap().0.clone();
-        let max_key = data.last().unwrap().0.clone();
-        let mut last_seq_num = 0;
-
-        for (key, value) in data {
-            last_seq_num = std::cmp::max(key.get_seq_num(), last_seq_num);
-            let data_block_entry = DataBlockEntry::new(key.clone(), value);
-            data_block.append(&mut data_block_entry.encode_to());
-            if data_block.len() > block_size {
-                let offset = buf.len() as u64;
-                let length = data_block.len() as u64; 
-                let index_block_entry = IndexBlockEntry::new(key, offset, length);
-                buf.append(&mut data_block);
-                index_block.push(index_block_entry);
-            }
-        }
-        let index_block_addr = buf.len() as u64;
-        //Currently, there is no meta index block, so the addr is equal to index_block_addr
-        let meta_index_block_addr = index_block_addr;
-        buf.append(&mut index_block.iter().map(|e| e.encode_to()).flatten().collect::<Vec<_>>());
-        let min_key_addr = buf.len() as u64;
-        buf.append(&mut min_key.encode_to());
-        let max_key_addr = buf.len() as u64;
-        buf.append(&mut max_key.encode_to());
-        let foot_addr = buf.len() as u64;
-
-        let footer = Footer {
-            level,
-            min_key_addr,
-            max_key_addr,
-            last_seq_num,
-            meta_index_block_addr,
-            index_block_addr,
-            foot_addr,
+    //level 0 always starts from its oldest table, other levels continue round-robin after the compaction pointer
+    //and prefer the table with the least overlap in the next level relative to its own size
+    pub fn pick_table(&self, level_idx: usize) -> usize {
+        let tables = self.level_tables(level_idx).collect::<Vec<_>>();
+        if level_idx == 0 {
+            return tables.len() - 1;
+        }
+        let start = match &self.compact_pointers.lock()[level_idx] {
+            Some(pointer) => tables.iter().position(|t| t.min_key > *pointer).unwrap_or(0),
+            None => 0,
         };
-        buf.append(&mut footer.encode_to());
-        //Write to file
-        file.write_all(&buf).unwrap();
-        file.flush().unwrap();
-
-        Table {
-            file_name: sst_file,
-            file,
-            footer,
-            index_block,
-            min_key,
-            max_key,
+        if level_idx == self.inner.len() - 1 {
+            return (start..tables.len()).chain(0..start)
+                .find(|&table_idx| tables[table_idx].is_moved())
+                .unwrap_or(start);
+        }
+        let mut picked = start;
+        let mut min_ratio = f64::MAX;
+        for table_idx in (start..tables.len()).chain(0..start) {
+            let table = tables[table_idx];
+            let overlap = self.inner.get(level_idx + 1)
+                .map(|next| next.iter()
+                    .filter(|t| ranges_overlap(&t.min_key, &t.max_key, &table.min_key, &table.max_key))
+                    .map(|t| t.get_size())
+                    .sum::<u64>())
+                .unwrap_or(0);
+            let ratio = overlap as f64 / table.get_size() as f64;
+            if ratio < min_ratio {
+                min_ratio = ratio;
+                picked = table_idx;
+            }
         }
+        picked
     }
 
-    pub fn open(sst_file: PathBuf) -> Self {
-        let file = OpenOptions::new().read(true).open(&sst_file).unwrap();
-        let footer = Footer::decode_from(&file);
-        let mut index_block = Vec::new();
-        let mut addr = footer.index_block_addr;
-        while addr < footer.foot_addr {
-            index_block.push(IndexBlockEntry::decode_from(&file, &mut addr));
-        }
-        let mut key_addr = footer.min_key_addr;
-        let min_key = LookUpKey::decode_from_file(&file, &mut key_addr);
-        assert!(key_addr == footer.max_key_addr);
-        let max_key = LookUpKey::decode_from_file(&file, &mut key_addr);
-        Table {
-            file_name: sst_file,
-            file,
-            footer,
-            index_block,
-            min_key,
-            max_key,
-        }
+    //the inputs count as being compacted until the returned guard is dropped
+    #[must_use]
+    fn compaction_started(&self, inputs: &[&Table], output_level: usize) -> Compacting {
+        self.events.emit(|| DbEvent::CompactionStarted {
+            at: SystemTime::now(),
+            inputs: inputs.iter().map(|t| t.file()).collect(),
+            output_level,
+        });
+        let inputs = inputs.iter().map(|t| t.file_num).collect::<Vec<_>>();
+        self.compacting.lock().extend(inputs.iter().cloned());
+        Compacting { file_nums: self.compacting.clone(), inputs }
     }
 
-    pub fn get_level(&self) -> usize {
-        self.footer.level
+    //the levels as text, a line for each level and each of its tables, to see what compactions did
+    pub fn dump(&self) -> String {
+        self.dump_with(false)
     }
 
-    pub fn get_size(&self) -> u64 {
-        self.file.metadata().unwrap().len()
+    //dump with the file numbers masked, they depend on every file written before and would make tests brittle
+    pub fn dump_normalized(&self) -> String {
+        self.dump_with(true)
     }
 
-    pub fn search(&self, key: &[u8], seq_num: u64) -> Option<Option<Vec<u8>>> {
-        let internal_key = InternalKey::new(key, seq_num, 1);
-        let look_up_key = LookUpKey::new(internal_key.clone());
-        let idx = match self.index_block.binary_search_by_key(&&look_up_key, |e| &e.max_key) {
-            Ok(idx) => idx,
-            Err(idx) => idx,
-        };
-        if idx < self.index_block.len() {
-            let index_entry = self.index_block[idx].clone();
-            let mut block = vec![0 as u8; index_entry.length as usize];
-            self.file.read_exact_at(
-                block.as_mut_slice(),
-                index_entry.offset,
-            ).unwrap();
-            
-            let mut offset = 0;
-            while offset < index_entry.length {
-                let block_entry = DataBlockEntry::decode_from(&block, &mut offset);
-                if block_entry.look_up_key >= look_up_key && block_entry.look_up_key.get_user_key() == key {
-                    match block_entry.look_up_key.get_type() {
-                        0 => return Some(Some(block_entry.value.to_vec())), 
-                        1 => return Some(None),
-                        _ => panic!("invalid look_up_key"),
-                    };
+    fn dump_with(&self, mask_file_nums: bool) -> String {
+        let compacting = self.compacting.lock().clone();
+        let compact_pointers = self.compact_pointers.lock().clone();
+        let mut out = String::new();
+        for (level, tables) in self.inner.iter().enumerate() {
+            let budget = match level {
+                0 => format!("{} files", self.l0_compaction_threshold),
+                _ => format!("{} bytes", self.max_bytes_for_level(level)),
+            };
+            out += &format!("level {}: {} files, {} bytes, budget {}", level, tables.len(), self.level_bytes[level], budget);
+            if let Some(key) = &compact_pointers[level] {
+                out += &format!(", compact pointer {}", dump_key(key.get_user_key()));
+            }
+            out.push('\n');
+            for table in tables {
+                let file_num = match mask_file_nums {
+                    true => "*".to_owned(),
+                    false => table.file_num.to_string(),
+                };
+                let entries = table.num_entries().map_or("?".to_owned(), |n| n.to_string());
+                out += &format!("  #{} {} bytes {} entries seq {} [{} .. {}]", file_num, table.get_size(), entries,
+                    table.footer.last_seq_num, dump_key(table.min_key.get_user_key()), dump_key(table.max_key.get_user_key()));
+                if compacting.contains(&table.file_num) {
+                    out += " being-compacted";
                 }
+                if table.obsolete.load(atomic::Ordering::Acquire) {
+                    out += " pending-deletion";
+                }
+                out.push('\n');
             }
-            return None;
-        } else {
-            return None;
         }
+        out
     }
 
-    pub fn content(&self) -> Vec<(LookUpKey, Vec<u8>)> {
-        let mut res = Vec::new();
-        for index_entry in self.index_block.iter() {
-            let mut block = vec![0 as u8; index_entry.length as usize];
-            self.file.read_exact_at(
-                block.as_mut_slice(),
-                index_entry.offset,
-            ).unwrap();
-            let mut offset = 0;
-            while offset < index_entry.length {
-                let block_entry = DataBlockEntry::decode_from(&block, &mut offset);
-                let DataBlockEntry {
-                    look_up_key,
-                    value,
-                } = block_entry;
-                res.push((look_up_key, value));
-            }
-        }
-        res
+    pub fn events(&self) -> Arc<EventBus> {
+        self.events.clone()
     }
-}
 
-impl PartialEq for Table {
-    fn eq(&self, other: &Self) -> bool {
-        if self.footer.level == 0 {
-            self.footer.last_seq_num == self.footer.last_seq_num
-        } else {
-            self.min_key == other.min_key
-        }
+    //the registry of live snapshots, shared with the database that hands them out
+    pub fn snapshots(&self) -> Arc<Mutex<BTreeMap<u64, usize>>> {
+        self.snapshots.clone()
     }
-}
 
-impl Eq for Table {}
+    pub fn level0_files(&self) -> Arc<AtomicUsize> {
+        self.level0_files.clone()
+    }
 
-impl PartialOrd for Table {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        if self.footer.level == 0 {
-            other.footer.last_seq_num.partial_cmp(&self.footer.last_seq_num)
-        } else {
-            self.min_key.partial_cmp(&other.min_key)
-        }
+    //the sequence numbers a merge has to keep a version at or below, oldest first
+    fn live_snapshots(&self) -> Vec<u64> {
+        self.snapshots.lock().keys().cloned().collect()
     }
-}
 
-impl Ord for Table {
-    fn cmp(&self, other: &Self) -> Ordering {
-        if self.footer.level == 0 {
-            other.footer.last_seq_num.cmp(&self.footer.last_seq_num)
-        } else {
-            self.min_key.cmp(&other.min_key)
-        }
+    //the tables of the levels named in `files`, as the events report them
+    pub fn table_files(&self, files: &[(usize, PathBuf)]) -> Vec<TableFile> {
+        files.iter()
+            .filter_map(|(level, path)| self.level_tables(*level).find(|t| t.file_name == *path))
+            .map(Table::file)
+            .collect()
+    }
+
+    //the smallest and the largest user key of the tables, None without tables
+    pub fn key_range(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let min = self.inner.iter().flatten().map(|t| t.min_key.get_user_key()).min()?;
+        let max = self.inner.iter().flatten().map(|t| t.max_key.get_user_key()).max()?;
+        Some((min.to_vec(), max.to_vec()))
     }
-}
 
+    pub fn value_logs(&self) -> Arc<ValueLogs> {
+        self.value_logs.clone()
+    }
 
+    //the record bytes of each value log the pointers of the tables lead to, the rest of a log is garbage
+    pub fn value_log_live_bytes(&self) -> BTreeMap<u64, u64> {
+        let mut live = BTreeMap::new();
+        for table in self.inner.iter().flatten() {
+            for (&file_num, &bytes) in table.properties.value_log_bytes.iter() {
+                *live.entry(file_num).or_insert(0) += bytes;
+            }
+        }
+        live
+    }
+
+    pub fn compaction_style(&self) -> CompactionStyle {
+        self.compaction_style
+    }
+
+    //every table of the levels, level by level
+    pub fn live_tables(&self) -> Vec<TableFile> {
+        self.inner.iter().flatten().map(|t| t.file()).collect()
+    }
+
+    pub fn level_bytes(&self) -> &[u64] {
+        &self.level_bytes
+    }
+
+    //tables no longer live and logs of flushed mem tables, while their files wait for deletion
+    pub fn obsolete_bytes(&self) -> u64 {
+        let logs = self.file_deletions.lock().logs.iter().map(|log| log.valid_len()).sum::<u64>();
+        self.obsolete_bytes.load(atomic::Ordering::Relaxed) + logs
+    }
+
+    pub fn disable_file_deletions(&self) {
+        self.file_deletions.lock().disabled += 1;
+        self.value_logs.disable_deletions();
+    }
+
+    //the files kept meanwhile are deleted once every disable is matched
+    pub fn enable_file_deletions(&self) -> Result<()> {
+        let (tables, logs) = {
+            let mut deletions = self.file_deletions.lock();
+            if deletions.disabled == 0 {
+                return Err(Error::InvalidArgument("file deletions are not disabled".to_owned()));
+            }
+            self.value_logs.enable_deletions();
+            deletions.disabled -= 1;
+            if deletions.disabled > 0 {
+                return Ok(());
+            }
+            (std::mem::take(&mut deletions.tables), std::mem::take(&mut deletions.logs))
+        };
+        drop(tables);
+        for log in logs {
+            let path = log.get_path();
+            if let Err(e) = log.remove() {
+                log::error!("failed to remove the log {:?}: {}", path, e);
+            }
+        }
+        Ok(())
+    }
+
+    //the log of a flushed mem table, kept until file deletions are enabled again
+    pub fn remove_log(&self, log: Log) -> io::Result<()> {
+        let mut deletions = self.file_deletions.lock();
+        match deletions.disabled {
+            0 => {
+                drop(deletions);
+                log.remove()
+            },
+            _ => {
+                deletions.logs.push(log);
+                Ok(())
+            },
+        }
+    }
+
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.compaction_stats.lock().clone()
+    }
+
+    pub fn level_stats(&self) -> Vec<LevelStats> {
+        self.inner.iter()
+            .zip(self.compaction_scores())
+            .enumerate()
+            .map(|(level_idx, (level, score))| LevelStats {
+                num_files: level.len(),
+                size_bytes: level.iter().map(|t| t.get_size()).sum(),
+                max_bytes: if level_idx == 0 { None } else { Some(self.max_bytes_for_level(level_idx)) },
+                avg_file_size: level.iter().map(|t| t.get_size()).sum::<u64>() / std::cmp::max(level.len(), 1) as u64,
+                score,
+                num_entries: level.iter().filter_map(|t| t.num_entries()).sum(),
+                num_deletions: level.iter().filter_map(|t| t.num_deletions()).sum(),
+                tombstone_ratio: {
+                    let (entries, deletions) = level.iter()
+                        .filter_map(|t| Some((t.num_entries()?, t.num_deletions()?)))
+                        .fold((0, 0), |(entries, deletions), (e, d)| (entries + e, deletions + d));
+                    deletions as f64 / std::cmp::max(entries, 1) as f64
+                },
+                tombstone_heavy_files: level.iter().filter(|t| self.is_tombstone_heavy(t)).count(),
+                filter_negatives: level.iter().map(|t| t.filter_counts().0).sum(),
+                filter_false_positives: level.iter().map(|t| t.filter_counts().1).sum(),
+            }).collect()
+    }
+
+    //every table, level by level in the order they are searched
+    pub fn table_stats(&self) -> Vec<TableStats> {
+        self.inner.iter()
+            .flatten()
+            .map(|t| TableStats {
+                level: t.level,
+                file_num: t.file_num,
+                size_bytes: t.get_size(),
+                num_entries: t.num_entries(),
+                num_deletions: t.num_deletions(),
+                min_key: t.min_key.get_user_key().to_vec(),
+                max_key: t.max_key.get_user_key().to_vec(),
+                last_seq_num: t.footer.last_seq_num,
+                filter_negatives: t.filter_counts().0,
+                filter_false_positives: t.filter_counts().1,
+            }).collect()
+    }
+
+    //compact one table of `level_idx` together with everything it overlaps into the next level
+    fn compact(&self, level_idx: usize, mut src_table_idx: usize) -> Result<CompactionOutput> {
+        if level_idx == 0 {
+            return self.compact_level0(src_table_idx);
+        }
+        let table_refs = self.level_tables(level_idx).collect::<Vec<_>>();
+        let mut deleted_tables = vec![table_refs[src_table_idx]];
+        let mut new_tables = Vec::new();
+        let dst_level_idx = level_idx + 1;
+        let mut dst_table_idx = usize::MAX;
+        let dst_table_refs = self.level_tables(dst_level_idx).collect::<Vec<_>>();
+        let mut key_range = (&deleted_tables[0].min_key, &deleted_tables[0].max_key);
+        for (table_idx, &table) in dst_table_refs.iter().enumerate() {
+            if ranges_overlap(&table.min_key, &table.max_key, key_range.0, key_range.1) {
+                key_range.0 = std::cmp::min(&table.min_key, key_range.0);
+                key_range.1 = std::cmp::max(&table.max_key, key_range.1);
+                deleted_tables.push(table);
+                dst_table_idx = table_idx;
+            }
+        }
+        //nothing to merge with, hand the file over to the next level without rewriting it
+        if dst_table_idx == usize::MAX {
+            let _compacting = self.compaction_started(&deleted_tables, dst_level_idx);
+            new_tables.push(deleted_tables[0].moved_to(dst_level_idx));
+            let mut stats = self.compaction_stats.lock();
+            stats.compactions += 1;
+            stats.moved_files += 1;
+        } else {
+            //src and dst take turn
+            let mut last_len = 0;
+            while deleted_tables.len() != last_len {
+                last_len = deleted_tables.len();
+
+                while src_table_idx + 1 < table_refs.len() {
+                    src_table_idx += 1;
+                    let min_key = &table_refs[src_table_idx].min_key;
+                    let max_key = &table_refs[src_table_idx].max_key;
+                    if ranges_overlap(min_key, max_key, key_range.0, key_range.1) {
+                        key_range.0 = std::cmp::min(min_key, key_range.0);
+                        key_range.1 = std::cmp::max(max_key, key_range.1);
+                        deleted_tables.push(table_refs[src_table_idx]);
+                    } else {
+                        src_table_idx -= 1;
+                        break;
+                    }
+                }
+                while dst_table_idx + 1 < dst_table_refs.len() {
+                    dst_table_idx += 1;
+                    let min_key = &dst_table_refs[dst_table_idx].min_key;
+                    let max_key = &dst_table_refs[dst_table_idx].max_key;
+                    if ranges_overlap(min_key, max_key, key_range.0, key_range.1) {
+                        key_range.0 = std::cmp::min(min_key, key_range.0);
+                        key_range.1 = std::cmp::max(max_key, key_range.1);
+                        deleted_tables.push(dst_table_refs[dst_table_idx]);
+                    } else {
+                        dst_table_idx -= 1;
+                        break;
+                    }
+                }
+            }
+            new_tables = self.merge_into(&deleted_tables, dst_level_idx)?;
+        }
+        Ok((
+            deleted_tables.into_iter()
+                .map(|x| (x.get_level(), x.file_name.clone()))
+                .collect::<Vec<_>>(),
+            new_tables
+        ))
+    }
+
+    //level 0 tables overlap each other, so every level 0 table sharing user keys with the picked one
+    //(transitively, also through the level 1 tables pulled in) has to go down with it,
+    //otherwise an older version left behind could later land above a newer one
+    fn compact_level0(&self, src_table_idx: usize) -> Result<CompactionOutput> {
+        let picked = self.level_tables(0).nth(src_table_idx).unwrap();
+        let mut candidates = self.level_tables(0)
+            .chain(self.level_tables(1))
+            .filter(|t| !std::ptr::eq(*t, picked))
+            .collect::<Vec<_>>();
+        let mut inputs = vec![picked];
+        let mut key_range = (picked.min_key.get_user_key(), picked.max_key.get_user_key());
+        loop {
+            let (overlapping, rest): (Vec<_>, Vec<_>) = candidates.into_iter()
+                .partition(|t| ranges_overlap(&t.min_key.get_user_key(), &t.max_key.get_user_key(), &key_range.0, &key_range.1));
+            candidates = rest;
+            if overlapping.is_empty() {
+                break;
+            }
+            for table in overlapping {
+                key_range.0 = std::cmp::min(table.min_key.get_user_key(), key_range.0);
+                key_range.1 = std::cmp::max(table.max_key.get_user_key(), key_range.1);
+                inputs.push(table);
+            }
+        }
+        let new_tables = if inputs.len() == 1 {
+            let _compacting = self.compaction_started(&inputs, 1);
+            let mut stats = self.compaction_stats.lock();
+            stats.compactions += 1;
+            stats.moved_files += 1;
+            vec![picked.moved_to(1)]
+        } else {
+            self.merge_into(&inputs, 1)?
+        };
+        Ok((
+            inputs.into_iter()
+                .map(|x| (x.get_level(), x.file_name.clone()))
+                .collect::<Vec<_>>(),
+            new_tables
+        ))
+    }
+
+    //Tables merged into the last level lose their tombstones and stale versions on the way,
+    //but tables moved there keep them. Rewrite such a table together with its neighbours in place.
+    fn compact_bottom(&self, src_table_idx: usize) -> Result<CompactionOutput> {
+        let level_idx = self.inner.len() - 1;
+        let inputs = self.level_tables(level_idx)
+            .skip(src_table_idx)
+            .take(BOTTOM_COMPACTION_BATCH)
+            .collect::<Vec<_>>();
+        let new_tables = self.merge_into(&inputs, level_idx)?;
+        Ok((
+            inputs.into_iter()
+                .map(|x| (level_idx, x.file_name.clone()))
+                .collect::<Vec<_>>(),
+            new_tables
+        ))
+    }
+
+    //compact every table of `level_idx` with everything it overlaps in the next level,
+    //the bottom level has no next level and is rewritten in place
+    pub fn compact_level(&self, level_idx: usize) -> Result<(CompactionSummary, CompactionOutput)> {
+        let mut inputs = self.level_tables(level_idx).collect::<Vec<_>>();
+        if inputs.is_empty() {
+            return Ok((CompactionSummary::default(), (Vec::new(), Vec::new())));
+        }
+        let dst_level_idx = std::cmp::min(level_idx + 1, self.inner.len() - 1);
+        if dst_level_idx != level_idx {
+            let min_key = inputs.iter().map(|t| &t.min_key).min().unwrap();
+            let max_key = inputs.iter().map(|t| &t.max_key).max().unwrap();
+            inputs.extend(self.level_tables(dst_level_idx)
+                .filter(|t| ranges_overlap(&t.min_key, &t.max_key, min_key, max_key)));
+        }
+        let outputs = self.merge_into(&inputs, dst_level_idx)?;
+        let summary = CompactionSummary {
+            input_files: inputs.len(),
+            output_files: outputs.len(),
+            bytes_read: inputs.iter().map(|t| t.get_size()).sum(),
+            bytes_written: outputs.iter().map(|t| t.get_size()).sum(),
+        };
+        Ok((
+            summary,
+            (
+                inputs.into_iter()
+                    .map(|x| (x.get_level(), x.file_name.clone()))
+                    .collect::<Vec<_>>(),
+                outputs
+            )
+        ))
+    }
+
+    //merge the inputs into new tables of `dst_level_idx`, one subcompaction per key range
+    fn merge_into(&self, inputs: &[&Table], dst_level_idx: usize) -> Result<Vec<Table>> {
+        let _compacting = self.compaction_started(inputs, dst_level_idx);
+        //newer tables first, merge_newest resolves equal sequence numbers by input order
+        let mut inputs = inputs.to_vec();
+        inputs.sort_by(|a, b| a.get_level().cmp(&b.get_level()).then_with(|| a.cmp(b)));
+        let inputs = &inputs[..];
+        let ranges = self.subcompaction_ranges(inputs);
+        //the write times outlive the entries they came with, the first subcompaction keeps them
+        let write_times = merge_write_times(inputs);
+        let new_tables = if ranges.len() == 1 {
+            self.merge_range(inputs, &ranges[0], dst_level_idx, write_times)?
+        } else {
+            let outputs = crossbeam_utils::thread::scope(|s| {
+                let mut write_times = Some(write_times);
+                let handles = ranges.iter()
+                    .map(|range| {
+                        let write_times = write_times.take().unwrap_or_default();
+                        s.spawn(move |_| self.merge_range(inputs, range, dst_level_idx, write_times))
+                    })
+                    .collect::<Vec<_>>();
+                handles.into_iter()
+                    .map(|h| h.join().unwrap())
+                    .collect::<Vec<_>>()
+            }).unwrap();
+            let mut new_tables = Vec::new();
+            let mut res = Ok(());
+            for output in outputs {
+                match output {
+                    Ok(tables) => new_tables.extend(tables),
+                    Err(e) => res = res.and(Err(e)),
+                }
+            }
+            if let Err(e) = res {
+                //nothing refers to the tables the other subcompactions wrote
+                for table in new_tables {
+                    table.mark_obsolete();
+                }
+                return Err(e);
+            }
+            new_tables
+        };
+        let mut stats = self.compaction_stats.lock();
+        stats.compactions += 1;
+        stats.bytes_read += inputs.iter().map(|t| t.get_size()).sum::<u64>();
+        stats.bytes_written += new_tables.iter().map(|t| t.get_size()).sum::<u64>();
+        Ok(new_tables)
+    }
+
+    //Universal compaction keeps every sorted run as a level 0 table, newest first.
+    //Once there are more runs than the level 0 threshold, merge the first window of adjacent runs
+    //where each next run is at most size_ratio percent bigger than the runs before it combined;
+    //without such a window the adjacent pair with the smallest combined size is merged.
+    //Returns the range [start, end) of runs to merge.
+    pub fn pick_universal_compaction(&self) -> Option<(usize, usize)> {
+        let sizes = self.level_tables(0).map(|t| t.get_size()).collect::<Vec<_>>();
+        if sizes.len() <= self.l0_compaction_threshold || sizes.len() < 2 {
+            return None;
+        }
+        for start in 0..sizes.len() - 1 {
+            let mut combined = sizes[start];
+            let mut end = start + 1;
+            while end < sizes.len() && sizes[end] * 100 <= combined * (100 + self.universal_size_ratio) {
+                combined += sizes[end];
+                end += 1;
+            }
+            if end - start >= 2 {
+                return Some((start, end));
+            }
+        }
+        (0..sizes.len() - 1)
+            .min_by_key(|&i| sizes[i] + sizes[i + 1])
+            .map(|i| (i, i + 2))
+    }
+
+    //Merging level 0 into itself only costs rewriting level 0, while pushing it down also rewrites
+    //every overlapping level 1 table. Worth it once level 0 has many tables and level 1 outweighs it,
+    //the merged table grows with every round until it is the cheaper side and goes down after all.
+    fn prefers_intra_level0(&self) -> bool {
+        let tables = self.level_tables(0).collect::<Vec<_>>();
+        if tables.len() < std::cmp::max(self.l0_intra_compaction_threshold, 2) {
+            return false;
+        }
+        let min_key = tables.iter().map(|t| &t.min_key).min().unwrap();
+        let max_key = tables.iter().map(|t| &t.max_key).max().unwrap();
+        let level0_bytes = tables.iter().map(|t| t.get_size()).sum::<u64>();
+        let overlap_bytes = self.level_tables(1)
+            .filter(|t| ranges_overlap(&t.min_key, &t.max_key, min_key, max_key))
+            .map(|t| t.get_size())
+            .sum::<u64>();
+        overlap_bytes > level0_bytes
+    }
+
+    //merge the runs [start, end) of level 0 into a single new run,
+    //its last_seq_num is the max of the inputs so it keeps their place in the recency order
+    fn compact_runs(&self, start: usize, end: usize) -> Result<CompactionOutput> {
+        let runs = self.level_tables(0).collect::<Vec<_>>();
+        let inputs = &runs[start..end];
+        let _compacting = self.compaction_started(inputs, 0);
+        //older runs may still hold a version a tombstone hides
+        let includes_oldest = end == runs.len();
+        let input_error = Mutex::new(None);
+        let iters = inputs.iter()
+            .map(|t| until_error(t.iter_from(None, Some(&self.rate_limiter), Scan::Compaction), &input_error))
+            .collect();
+        let merged = merge_visible(iters, self.live_snapshots());
+        let mut merged = drop_tombstones(self.collapse_history(self.filter_entries(merged, 0)),
+            |k| includes_oldest && self.can_drop_tombstone(k, 0, inputs))
+            .peekable();
+        let mut new_tables = Vec::new();
+        if merged.peek().is_some() {
+            let input_size = inputs.iter().map(|t| t.get_size()).sum();
+            new_tables.push(self.write_file_with_times(merged, 0, merge_write_times(inputs), input_size)?);
+        }
+        //the run written from what could be read is missing the rest of the inputs
+        if let Some(e) = input_error.lock().take() {
+            for table in new_tables {
+                table.mark_obsolete();
+            }
+            return Err(e);
+        }
+        self.evict_outputs(&new_tables);
+        let mut stats = self.compaction_stats.lock();
+        stats.compactions += 1;
+        stats.bytes_read += inputs.iter().map(|t| t.get_size()).sum::<u64>();
+        stats.bytes_written += new_tables.iter().map(|t| t.get_size()).sum::<u64>();
+        Ok((
+            inputs.iter()
+                .map(|x| (0, x.file_name.clone()))
+                .collect::<Vec<_>>(),
+            new_tables
+        ))
+    }
+
+    //split the inputs at index block boundaries into at most max_subcompactions user key ranges,
+    //each range is [start, end) by user key so all versions of a key land in the same subcompaction
+    fn subcompaction_ranges(&self, tables: &[&Table]) -> Vec<KeyRange> {
+        //a boundary is the first stored key of its version group, all timestamps of a key stay on one side
+        let mut boundaries = tables.iter()
+            .flat_map(|t| t.index_block.iter().map(|e| {
+                let mut boundary = self.version_group(e.max_key.get_user_key()).to_vec();
+                boundary.resize(e.max_key.get_user_key().len(), 0);
+                boundary
+            }))
+            .collect::<Vec<_>>();
+        boundaries.sort();
+        boundaries.dedup();
+        //the largest boundary would leave the last range empty
+        boundaries.pop();
+        let num_ranges = std::cmp::min(self.max_subcompactions, boundaries.len() + 1);
+        let mut ranges = Vec::with_capacity(num_ranges);
+        let mut start = None;
+        for i in 1..num_ranges {
+            let end = boundaries[i * boundaries.len() / num_ranges].clone();
+            ranges.push((start, Some(end.clone())));
+            start = Some(end);
+        }
+        ranges.push((start, None));
+        ranges
+    }
+
+    fn merge_range(&self, tables: &[&Table], range: &KeyRange, dst_level_idx: usize, write_times: Vec<(u64, u64)>) -> Result<Vec<Table>> {
+        let (start, end) = range;
+        let input_error = Mutex::new(None);
+        let iters = tables.iter()
+            .map(|t| until_error(t.iter_from(start.as_deref(), Some(&self.rate_limiter), Scan::Compaction), &input_error)
+                .take_while(move |(k, _)| end.as_ref().is_none_or(|end| k.get_user_key() < &end[..])))
+            .collect();
+        let merged = self.collapse_history(self.filter_entries(merge_visible(iters, self.live_snapshots()), dst_level_idx));
+        let merged = drop_tombstones(merged, |k| self.can_drop_tombstone(k, dst_level_idx, tables));
+        //a subcompaction reads only part of the inputs, the estimate of each output is capped by the target size anyway
+        let input_size = tables.iter().map(|t| t.get_size()).sum();
+        let outputs = self.write_files(merged, dst_level_idx, write_times, input_size)?;
+        if let Some(e) = input_error.lock().take() {
+            for table in outputs {
+                table.mark_obsolete();
+            }
+            return Err(e);
+        }
+        self.evict_outputs(&outputs);
+        Ok(outputs)
+    }
+
+    //the outputs are synced, so their pages are clean and can be dropped right away
+    fn evict_outputs(&self, outputs: &[Table]) {
+        if self.evict_compaction_output {
+            for table in outputs.iter() {
+                let _ = table.file.advise(Advice::DontNeed, 0, 0);
+            }
+        }
+    }
+
+    //the stored keys that have to stay together, with timestamps that is every version of a user key
+    fn version_group<'a>(&self, user_key: &'a [u8]) -> &'a [u8] {
+        &user_key[..user_key.len() - self.user_timestamp_size]
+    }
+
+    fn before_horizon(&self, k: &LookUpKey) -> bool {
+        match &self.user_timestamp_horizon {
+            Some(horizon) if self.user_timestamp_size > 0 => split_timestamp(k.get_user_key(), self.user_timestamp_size).1 <= *horizon,
+            _ => false,
+        }
+    }
+
+    //Reads at or after the horizon only ever see the newest version of a key at or before it,
+    //the older ones are dropped. Versions after the horizon are all kept.
+    fn collapse_history<'a, I>(&'a self, iter: I) -> impl Iterator<Item = (LookUpKey, Bytes)> + 'a
+    where
+        I: Iterator<Item = (LookUpKey, Bytes)> + 'a,
+    {
+        let mut covered: Option<Bytes> = None; //group of the last version kept at or before the horizon
+        iter.filter(move |(k, _)| {
+            if !self.before_horizon(k) {
+                return true;
+            }
+            let group = self.version_group(k.get_user_key());
+            if covered.as_deref() == Some(group) {
+                return false;
+            }
+            covered = Some(k.internal_key.user_key.slice(..group.len()));
+            true
+        })
+    }
+
+    //A tombstone can only be dropped when no deeper level may still hold an older version of the key.
+    //With timestamps it also hides the older timestamps of the key, which may be in any table outside the
+    //compaction, even in a level above; and reads after it but before the horizon still need it.
+    fn can_drop_tombstone(&self, k: &LookUpKey, level: usize, inputs: &[&Table]) -> bool {
+        if self.user_timestamp_size == 0 {
+            return !self.may_exist_below(k.get_user_key(), level);
+        }
+        if !self.before_horizon(k) {
+            return false;
+        }
+        let first = self.version_group(k.get_user_key()).to_vec();
+        let mut last = first.clone();
+        last.resize(k.get_user_key().len(), 0xff);
+        !self.inner.iter()
+            .flatten()
+            .filter(|t| inputs.iter().all(|input| input.file_num != t.file_num))
+            .any(|t| ranges_overlap(&t.min_key.get_user_key(), &t.max_key.get_user_key(), &&first[..], &&last[..]))
+    }
+
+    //Run the compaction filter over merged entries written to `level`. A removed entry becomes a tombstone,
+    //it is dropped like any other one once nothing older can be left below.
+    //Older versions of a key only survive a merge for a snapshot, the filter sees each of them.
+    //It sees the values in value logs as well, an entry whose value can not be read is kept as it is.
+    fn filter_entries<'a, I>(&'a self, iter: I, level: usize) -> impl Iterator<Item = (LookUpKey, Bytes)> + 'a
+    where
+        I: Iterator<Item = (LookUpKey, Bytes)> + 'a,
+    {
+        iter.map(move |(k, v)| {
+            let filter = match &self.compaction_filter {
+                Some(filter) if !k.is_deletion() => filter,
+                _ => return (k, v),
+            };
+            let user_key = match self.user_timestamp_size {
+                0 => Cow::Borrowed(k.get_user_key()),
+                size => Cow::Owned(strip_timestamp(k.get_user_key(), size)),
+            };
+            let (value, checksum) = match k.internal_key.split_value(v.clone()) {
+                Ok(split) => split,
+                Err(_) => return (k, v),
+            };
+            let value = match self.value_logs.resolve(k.is_value_pointer(), value) {
+                Ok(value) => value,
+                Err(_) => return (k, v),
+            };
+            match compaction_filter::filter_or_keep(filter.as_ref(), level, &user_key, &value) {
+                FilterDecision::Keep => (k, v),
+                FilterDecision::Remove => {
+                    let user_key = k.internal_key.user_key.clone();
+                    (LookUpKey::new(InternalKey::from_bytes(user_key, k.get_seq_num(), ValueType::Delete)), Bytes::new())
+                },
+                FilterDecision::Change(value) => {
+                    let user_key = k.internal_key.user_key.clone();
+                    let value_type = k.get_type().to_inline_value();
+                    //a new value, the checksum of the old one does not carry over
+                    let value = match checksum {
+                        Some(_) => checked_value(&user_key, &value),
+                        None => value,
+                    };
+                    (LookUpKey::new(InternalKey::from_bytes(user_key, k.get_seq_num(), value_type)), value.into())
+                },
+            }
+        })
+    }
+
+    //whether any level below `level` has a table whose range covers the key
+    pub fn may_exist_below(&self, key: &[u8], level: usize) -> bool {
+        self.inner.iter()
+            .skip(level + 1)
+            .any(|tables| tables.iter().any(|t| t.contains_user_key(key)))
+    }
+
+    //the newest sequence number in any table
+    pub fn last_seq_num(&self) -> u64 {
+        self.inner.iter()
+            .flatten()
+            .map(|t| t.footer.last_seq_num)
+            .max()
+            .unwrap_or(0)
+    }
+
+    //the write times recorded in every table, by sequence number
+    pub fn write_times(&self) -> Vec<(u64, u64)> {
+        merge_write_times(&self.inner.iter().flatten().map(|t| &**t).collect::<Vec<_>>())
+    }
+
+    //reads back every table, the number verified or the first one that is damaged
+    pub fn verify(&self) -> Result<usize> {
+        let tables = self.inner.iter().flatten().collect::<Vec<_>>();
+        for table in tables.iter() {
+            table.verify()?;
+        }
+        Ok(tables.len())
+    }
+
+    pub fn num_files_at_level(&self, level: usize) -> usize {
+        self.inner.get(level).map_or(0, |tables| tables.len())
+    }
+
+    //files and bytes of each level, without looking at the tables
+    pub fn level_sizes(&self) -> Vec<(usize, u64)> {
+        self.inner.iter().map(|level| level.len()).zip(self.level_bytes.iter().cloned()).collect()
+    }
+
+    #[cfg(test)]
+    pub fn search(&self, key: &[u8], seq_num: u64) -> Option<Vec<u8>> {
+        Self::search_candidates(&self.candidates(key), key, seq_num).0.map(|v| v.to_vec())
+    }
+
+    //tables that may hold `key` in search order, every level 0 table containing it and at most one table of
+    //each other level; they are cloned out so the caller can read them without holding the lock on the levels
+    pub fn candidates(&self, key: &[u8]) -> Vec<Arc<Table>> {
+        let mut candidates = Vec::new();
+        for (level, tables) in self.inner.iter().enumerate() {
+            let mut tables = tables.iter().filter(|table| table.contains_user_key(key));
+            if level == 0 {
+                candidates.extend(tables.cloned());
+            } else {
+                candidates.extend(tables.next().cloned());
+            }
+        }
+        candidates
+    }
+
+    //whether any table may hold the key, judged by the key ranges and prefix filters alone
+    pub fn key_may_exist(&self, key: &[u8]) -> bool {
+        let prefix = self.prefix_extractor.as_deref()
+            .filter(|_| self.user_timestamp_size == 0)
+            .and_then(|e| e.prefix(key).map(|p| (e, p)));
+        self.inner.iter()
+            .flatten()
+            .filter(|t| t.contains_user_key(key))
+            .any(|t| !matches!(prefix, Some((e, p)) if !t.may_contain_prefix(e, p)))
+    }
+
+    //the newest entry of the key in the candidates as it is stored, a pointer stays a pointer
+    pub fn newest_entry(candidates: &[Arc<Table>], key: &[u8]) -> Result<Option<(LookUpKey, Bytes)>> {
+        let mut newest: Option<(LookUpKey, Bytes)> = None;
+        for table in candidates.iter() {
+            match table.iter_from(Some(key), None, Scan::Short).next().transpose()? {
+                Some((k, v)) if k.get_user_key() == key && newest.as_ref().is_none_or(|(n, _)| n.get_seq_num() < k.get_seq_num()) => {
+                    newest = Some((k, v));
+                },
+                _ => {},
+            }
+        }
+        Ok(newest)
+    }
+
+    //a cursor per level 0 table and one for each other level that has tables
+    pub fn cursors(&self, reads: BlockReads, keys_only: bool) -> Vec<LevelCursor> {
+        let level0 = self.inner[0].iter().map(|t| LevelCursor::new(vec![t.clone()], reads, keys_only));
+        let others = self.inner[1..].iter()
+            .filter(|tables| !tables.is_empty())
+            .map(|tables| LevelCursor::new(tables.iter().cloned().collect(), reads, keys_only));
+        level0.chain(others).collect()
+    }
+
+    //Table::count_entries_in summed over every table, the deadline is checked before each one
+    pub fn count_entries_in(&self, start: &[u8], end: &[u8], reads: &BlockReads) -> Result<u64> {
+        let mut count = 0;
+        for table in self.inner.iter().flatten() {
+            if table.max_key.get_user_key() >= start && table.min_key.get_user_key() < end {
+                reads.check_deadline()?;
+                count += table.count_entries_in(start, end, reads)?;
+            }
+        }
+        Ok(count)
+    }
+
+    //tables that may hold a stored user key in [first, last], every such level 0 table
+    //and the first one of each other level, which holds the smallest of them in the level
+    pub fn range_candidates(&self, first: &[u8], last: &[u8]) -> Vec<Arc<Table>> {
+        let mut candidates = Vec::new();
+        for (level, tables) in self.inner.iter().enumerate() {
+            let mut tables = tables.iter()
+                .filter(|t| ranges_overlap(&t.min_key.get_user_key(), &t.max_key.get_user_key(), &first, &last));
+            if level == 0 {
+                candidates.extend(tables.cloned());
+            } else {
+                candidates.extend(tables.next().cloned());
+            }
+        }
+        candidates
+    }
+
+    //every table that may hold a key starting with `prefix`. When `prefix` is a whole prefix
+    //of the extractor, tables whose prefix filter rules it out are skipped as well.
+    pub fn prefix_candidates(&self, prefix: &[u8]) -> Vec<Arc<Table>> {
+        let extractor = self.prefix_extractor.as_deref()
+            .filter(|e| self.user_timestamp_size == 0 && e.prefix(prefix) == Some(prefix));
+        self.inner.iter()
+            .flatten()
+            .filter(|t| t.max_key.get_user_key() >= prefix
+                && (t.min_key.get_user_key() < prefix || t.min_key.get_user_key().starts_with(prefix)))
+            .filter(|t| extractor.is_none_or(|e| t.may_contain_prefix(e, prefix)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn blocks_read(&self) -> u64 {
+        self.blocks_read.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn preloaded_bytes(&self) -> u64 {
+        self.preloaded_bytes
+    }
+
+    pub fn meta_bytes(&self) -> usize {
+        self.meta_bytes.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn block_cache_stats(&self) -> CacheStats {
+        self.block_cache.as_ref().map_or_else(CacheStats::default, |cache| cache.stats())
+    }
+
+    #[cfg(test)]
+    pub fn search_candidates(candidates: &[Arc<Table>], key: &[u8], seq_num: u64) -> (Option<Bytes>, bool) {
+        Self::search_candidates_with(candidates, key, seq_num, &BlockReads::default()).unwrap()
+    }
+
+    //also returns whether a table ran out of allowed seeks and should be compacted, the deadline is checked before each table
+    pub fn search_candidates_with(candidates: &[Arc<Table>], key: &[u8], seq_num: u64, reads: &BlockReads) -> Result<(Option<Bytes>, bool)> {
+        let num_level0 = candidates.iter().take_while(|t| t.get_level() == 0).count();
+        //tables in level 0 overlap, so the newest visible version may live in any of them
+        let mut res: Option<(u64, Option<Bytes>)> = None;
+        for table in candidates[..num_level0].iter() {
+            reads.check_deadline()?;
+            if let Some(found) = table.search_with(key, seq_num, reads)? {
+                if !matches!(&res, Some((newest, _)) if found.0 < *newest) {
+                    res = Some(found);
+                }
+            }
+        }
+        if let Some((_, value)) = res {
+            return Ok((value, false));
+        }
+        //a table that missed is charged once the lookup has to go on to the next one
+        let mut missed: Option<&Table> = None;
+        let mut exhausted = false;
+        for table in candidates[num_level0..].iter() {
+            reads.check_deadline()?;
+            if let Some(missed) = missed.take() {
+                exhausted |= missed.charge_seek();
+            }
+            match table.search_with(key, seq_num, reads)? {
+                Some((_, value)) => return Ok((value, exhausted)),
+                None => missed = Some(table),
+            }
+        }
+        Ok((None, exhausted))
+    }
+
+    #[cfg(test)]
+    pub fn multi_search_candidates(candidates: &[Vec<Arc<Table>>], keys: &[&[u8]], seq_num: u64) -> Vec<(Option<Bytes>, bool)> {
+        Self::multi_search_candidates_with(candidates, keys, seq_num, &BlockReads::default()).unwrap()
+    }
+
+    //search_candidates_with for many keys, level by level so the keys that need the same table are looked up in one batch
+    pub fn multi_search_candidates_with(candidates: &[Vec<Arc<Table>>], keys: &[&[u8]], seq_num: u64, reads: &BlockReads)
+        -> Result<Vec<(Option<Bytes>, bool)>> {
+        let num_level0 = candidates.iter()
+            .map(|c| c.iter().take_while(|t| t.get_level() == 0).count())
+            .collect::<Vec<_>>();
+        let mut results = vec![(None, false); keys.len()];
+        let mut newest: Vec<Option<(u64, Option<Bytes>)>> = vec![None; keys.len()];
+        let level0 = (0..keys.len()).flat_map(|i| candidates[i][..num_level0[i]].iter().map(move |t| (t, i)));
+        for (_, found) in Self::search_by_table(level0, keys, seq_num, reads)? {
+            for (i, res) in found.into_iter().filter_map(|(i, res)| res.map(|res| (i, res))) {
+                if newest[i].as_ref().is_none_or(|(newest_seq_num, _)| res.0 >= *newest_seq_num) {
+                    newest[i] = Some(res);
+                }
+            }
+        }
+        //the keys still to look up with the position of their next candidate
+        let mut pending = Vec::new();
+        for (i, res) in newest.into_iter().enumerate() {
+            match res {
+                Some((_, value)) => results[i].0 = value,
+                None if num_level0[i] < candidates[i].len() => pending.push((i, num_level0[i])),
+                None => {},
+            }
+        }
+        while !pending.is_empty() {
+            let positions = std::mem::take(&mut pending).into_iter().collect::<HashMap<_, _>>();
+            let next = positions.iter().map(|(&i, &pos)| (&candidates[i][pos], i));
+            for (table, found) in Self::search_by_table(next, keys, seq_num, reads)? {
+                for (i, res) in found {
+                    let pos = positions[&i];
+                    match res {
+                        Some((_, value)) => results[i].0 = value,
+                        //a table that missed is charged once the lookup has to go on to the next one
+                        None if pos + 1 < candidates[i].len() => {
+                            results[i].1 |= table.charge_seek();
+                            pending.push((i, pos + 1));
+                        },
+                        None => {},
+                    }
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    //groups the (table, key index) pairs by table and searches each table for its keys at once,
+    //the deadline is checked before each table
+    fn search_by_table<'a>(lookups: impl Iterator<Item = (&'a Arc<Table>, usize)>, keys: &[&[u8]], seq_num: u64, reads: &BlockReads)
+        -> Result<Vec<TableLookups<'a>>> {
+        let mut by_table: HashMap<*const Table, (&Arc<Table>, Vec<usize>)> = HashMap::new();
+        for (table, i) in lookups {
+            by_table.entry(Arc::as_ptr(table)).or_insert_with(|| (table, Vec::new())).1.push(i);
+        }
+        by_table.into_iter()
+            .map(|(_, (table, idxs))| {
+                reads.check_deadline()?;
+                let found = table.multi_search_with(&idxs.iter().map(|&i| keys[i]).collect::<Vec<_>>(), seq_num, reads)?;
+                Ok((table, idxs.into_iter().zip(found).collect()))
+            })
+            .collect()
+    }
+
+    pub fn update(&mut self, deleted_tables: Vec<(usize, PathBuf)>, new_tables: Vec<Table>) -> Result<()> {
+        let mut deleted_table_map = HashMap::new();            
+        for (level, file_name) in deleted_tables {
+            let files = deleted_table_map.entry(level).or_insert(Vec::new());
+            files.push(file_name);
+        }
+        if self.paranoid_checks {
+            self.check_no_overlaps(&deleted_table_map, &new_tables)?;
+        }
+        let mut obsolete_tables = Vec::new();
+        for (level, files) in deleted_table_map {
+            //remove table from levels
+            let removed = self.inner[level].iter()
+                .filter(|t| files.contains(&t.file_name))
+                .cloned()
+                .collect::<Vec<_>>();
+            for table in removed {
+                self.inner[level].remove(&table);
+                self.level_bytes[level] -= table.get_size();
+                obsolete_tables.push(table);
+            }
+        }
+        //a moved table comes back as a new table of the next level and keeps its file
+        obsolete_tables.retain(|t| new_tables.iter().all(|new| new.file_name != t.file_name));
+
+        for table in new_tables {
+            //tables linked in by ingestion keep the level they had
+            if self.inner.len() <= table.get_level() {
+                self.inner.resize(table.get_level() + 1, BTreeSet::new());
+                self.level_bytes.resize(table.get_level() + 1, 0);
+            }
+            self.level_bytes[table.get_level()] += table.get_size();
+            self.inner[table.get_level()].insert(Arc::new(table));
+        }
+        self.add_level();
+        self.level0_files.store(self.inner[0].len(), atomic::Ordering::Release);
+        //the manifest has to stop referring to the files before they are gone
+        self.manifest().save(&*self.env, &self.db_path)?;
+        //the files are deleted once the last reader drops its reference
+        for table in obsolete_tables.iter() {
+            table.mark_obsolete();
+        }
+        let mut deletions = self.file_deletions.lock();
+        if deletions.disabled > 0 {
+            deletions.tables.extend(obsolete_tables);
+        }
+        Ok(())
+    }
+
+    //installs the result of a compaction that ran next to others. If one of them installed first and took any
+    //of the same tables or put a table in the way, the result is thrown away with its new files and false returned,
+    //the inputs get picked again.
+    pub fn install(&mut self, deleted_tables: Vec<(usize, PathBuf)>, new_tables: Vec<Table>) -> Result<bool> {
+        let taken = deleted_tables.iter()
+            .any(|(level, file_name)| self.level_tables(*level).all(|t| t.file_name != *file_name));
+        let mut deleted_table_map: HashMap<usize, Vec<PathBuf>> = HashMap::new();
+        for (level, file_name) in deleted_tables.iter() {
+            deleted_table_map.entry(*level).or_default().push(file_name.clone());
+        }
+        if taken || self.check_no_overlaps(&deleted_table_map, &new_tables).is_err() {
+            //a moved table keeps the file of the one it replaces
+            for table in new_tables.iter().filter(|t| deleted_tables.iter().all(|(_, f)| *f != t.file_name)) {
+                table.mark_obsolete();
+            }
+            return Ok(false);
+        }
+        self.update(deleted_tables, new_tables)?;
+        Ok(true)
+    }
+
+    //the tables of a level past 0 must stay disjoint once the deleted ones are gone and the new ones are in,
+    //a compaction that breaks this would make lookups miss
+    fn check_no_overlaps(&self, deleted_tables: &HashMap<usize, Vec<PathBuf>>, new_tables: &[Table]) -> Result<()> {
+        for (i, table) in new_tables.iter().enumerate().filter(|(_, t)| t.get_level() > 0) {
+            let level = table.get_level();
+            let deleted = deleted_tables.get(&level).map_or(&[][..], |files| files.as_slice());
+            let remaining = self.level_tables(level).filter(|t| !deleted.contains(&t.file_name));
+            let other_new = new_tables[i + 1..].iter().filter(|t| t.get_level() == level);
+            if let Some(other) = remaining.chain(other_new).find(|other| table.overlaps(other)) {
+                return Err(Error::Corruption(format!("table {:?} would overlap {:?} in level {}",
+                    table.file_name, other.file_name, level)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        Manifest {
+            compaction_style: self.compaction_style,
+            tables: self.inner.iter()
+                .flatten()
+                .map(|t| (t.file_num, t.get_level()))
+                .collect(),
+            user_timestamp_size: self.user_timestamp_size,
+            num_levels: self.inner.len(),
+        }
+    }
+
+    //the immutable mem table stays readable until its table is installed, the caller drops it afterwards.
+    //An empty mem table has nothing to write, a mem table of tombstones still needs its table.
+    pub fn write_level0_files(&self, im_mem_table: &MemTable) -> Result<Option<Table>> {
+        if im_mem_table.inner.is_empty() {
+            return Ok(None);
+        }
+        let iter = im_mem_table.inner.iter()
+            .map(|(k, v)| (LookUpKey::new(k.clone()), v.clone()));
+        let table = match self.separates_values(im_mem_table) {
+            true => {
+                let entries = self.write_value_log(iter)?;
+                self.write_file_with_times(entries.into_iter(), 0, im_mem_table.write_times.clone(), im_mem_table.size as u64)?
+            },
+            false => self.write_file_with_times(iter, 0, im_mem_table.write_times.clone(), im_mem_table.size as u64)?,
+        };
+        let mut stats = self.compaction_stats.lock();
+        stats.flushes += 1;
+        stats.flush_bytes_written += table.get_size();
+        Ok(Some(table))
+    }
+
+    //whether the mem table has a value for the value log, keys with timestamps keep theirs in the tables
+    fn separates_values(&self, mem_table: &MemTable) -> bool {
+        self.value_log_threshold > 0 && self.user_timestamp_size == 0
+            && mem_table.inner.iter().any(|(k, v)| !k.get_type().is_deletion() && v.len() > self.value_log_threshold)
+    }
+
+    //moves the values over the threshold to a new value log, the entries that come back point to them.
+    //The log is synced before this returns, so it is durable before any table pointing into it.
+    fn write_value_log<I>(&self, iter: I) -> Result<Vec<(LookUpKey, Bytes)>>
+    where
+        I: Iterator<Item = (LookUpKey, Bytes)>,
+    {
+        let file_num = self.next_file_num.fetch_add(1, atomic::Ordering::SeqCst);
+        let mut writer = ValueLogWriter::create(&*self.env, &self.db_path, file_num)?;
+        let mut entries = Vec::new();
+        for (k, v) in iter {
+            if k.is_deletion() || v.len() <= self.value_log_threshold {
+                entries.push((k, v));
+                continue;
+            }
+            let added = k.internal_key.split_value(v)
+                .and_then(|(value, checksum)| Ok((writer.add(k.get_user_key(), &value)?, checksum)));
+            match added {
+                Ok((pointer, checksum)) => {
+                    let value_type = k.internal_key.get_type().to_value_pointer();
+                    let key = InternalKey::from_bytes(k.internal_key.user_key.clone(), k.get_seq_num(), value_type);
+                    //the checksum written with the value stays with the pointer
+                    let mut pointer = pointer.encode();
+                    if let Some(checksum) = checksum {
+                        pointer.extend_from_slice(&checksum.to_le_bytes());
+                    }
+                    entries.push((LookUpKey::new(key), Bytes::from(pointer)));
+                },
+                Err(e) => {
+                    writer.abandon(&*self.env);
+                    return Err(e);
+                },
+            }
+        }
+        writer.finish(&*self.env)?;
+        self.value_logs.add(file_num)?;
+        Ok(entries)
+    }
+
+    //cut the entries into tables of the target size of `level`, the versions of a user key are never split
+    //so sibling tables do not overlap. The write times all go to the first table. Without entries no table
+    //is written, a compaction that kept nothing only deletes its inputs.
+    //`input_size` is about how many bytes the entries take, each table is preallocated to its share of it.
+    pub fn write_files<I, V>(&self, iter: I, level: usize, mut write_times: Vec<(u64, u64)>, input_size: u64) -> Result<Vec<Table>>
+    where
+        I: Iterator<Item = (LookUpKey, V)>,
+        V: AsRef<[u8]>,
+    {
+        let target_file_size = self.target_file_size(level);
+        let mut iter = iter.peekable();
+        let mut tables: Vec<Table> = Vec::new();
+        while iter.peek().is_some() {
+            let remaining = input_size.saturating_sub(tables.iter().map(|t| t.get_size()).sum());
+            let mut size = 0;
+            let mut cut_after: Option<Vec<u8>> = None;
+            let entries = iter.peeking_take_while(|(k, v)| {
+                if let Some(last_key) = &cut_after {
+                    return self.version_group(k.get_user_key()) == &last_key[..];
+                }
+                //about the encoded size of the data block entry: user key, tail, value and their varint lengths
+                size += (k.get_user_key().len() + 8 + v.as_ref().len() + 4) as u64;
+                if size >= target_file_size {
+                    cut_after = Some(self.version_group(k.get_user_key()).to_vec());
+                }
+                true
+            });
+            match self.write_file_with_times(entries, level, std::mem::take(&mut write_times), std::cmp::min(remaining, target_file_size)) {
+                Ok(table) => tables.push(table),
+                Err(e) => {
+                    //nothing refers to the tables written so far
+                    for table in tables {
+                        table.mark_obsolete();
+                    }
+                    return Err(e);
+                },
+            }
+        }
+        Ok(tables)
+    }
+
+    //bits per key of the key filters of the tables written to `level`
+    fn bloom_bits_per_key(&self, level: usize) -> f64 {
+        self.bloom_bits_per_key_per_level.get(level).copied().unwrap_or(self.bloom_bits_per_key)
+    }
+
+    #[cfg(test)]
+    pub fn write_file<I, V>(&self, iter: I, level: usize) -> Result<Table>
+    where
+        I: Iterator<Item = (LookUpKey, V)>,
+        V: AsRef<[u8]>,
+    {
+        self.write_file_with_times(iter, level, Vec::new(), 0)
+    }
+
+    //`size_estimate` is preallocated unless that is turned off, 0 preallocates nothing
+    fn write_file_with_times<I, V>(&self, iter: I, level: usize, write_times: Vec<(u64, u64)>, size_estimate: u64) -> Result<Table>
+    where
+        I: Iterator<Item = (LookUpKey, V)>,
+        V: AsRef<[u8]>,
+    {
+        #[cfg(test)]
+        {
+            if self.failed_writes.fetch_update(atomic::Ordering::SeqCst, atomic::Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err(io::Error::other("injected write failure").into());
+            }
+        }
+        let mut sst_file = self.db_path.clone();
+        let next_file_num = self.next_file_num.fetch_add(1, atomic::Ordering::SeqCst);
+        sst_file.push(next_file_num.to_string());
+        sst_file.set_extension("sst");
+        //stored keys with timestamps are encoded, their prefixes are not the ones of the user keys
+        let prefix_extractor = self.prefix_extractor.as_deref().filter(|_| self.user_timestamp_size == 0);
+        //lookups of keys with timestamps do not look for the stored keys, a filter over those could not rule them out
+        let key_filter_bits = if self.user_timestamp_size == 0 { self.bloom_bits_per_key(level) } else { 0.0 };
+        let preallocate = if self.preallocate_sst { size_estimate } else { 0 };
+        let mut table = Table::build(&self.env, sst_file, iter, level, self.block_size, &self.rate_limiter, CURRENT_FORMAT, write_times, prefix_extractor,
+            key_filter_bits, preallocate)?;
+        self.adopt(&mut table);
+        Ok(table)
+    }
+
+    //a copy of a table of another database under a number of ours, the caller installs it in `level` with update
+    pub fn link_table(&self, src_env: &dyn Env, src: &Path, level: usize) -> Result<Table> {
+        let bytes = src_env.read(src)?;
+        let next_file_num = self.next_file_num.fetch_add(1, atomic::Ordering::SeqCst);
+        let sst_file = self.db_path.join(next_file_num.to_string()).with_extension("sst");
+        let mut file = self.env.create(&sst_file)?;
+        fault::append(&mut *file, &bytes, &sst_file)?;
+        fault::sync(&mut *file, &sst_file)?;
+        drop(file);
+        fault::sync_dir(&*self.env, &self.db_path)?;
+        let mut table = Table::open(&self.env, sst_file)?;
+        table.level = level;
+        self.adopt(&mut table);
+        Ok(table)
+    }
+
+    //copies the value logs under their numbers, before these levels number any file of their own
+    fn copy_value_logs(&self, src: &ValueLogs) -> Result<()> {
+        for file_num in src.file_nums() {
+            let path = value_log::file_name(&self.db_path, file_num);
+            let mut file = self.env.create(&path)?;
+            fault::append(&mut *file, &src.env().read(&src.path(file_num))?, &path)?;
+            fault::sync(&mut *file, &path)?;
+            drop(file);
+            self.value_logs.add(file_num)?;
+            self.next_file_num.fetch_max(file_num + 1, atomic::Ordering::SeqCst);
+        }
+        fault::sync_dir(&*self.env, &self.db_path)?;
+        Ok(())
+    }
+
+    //the tables of these levels divided at `split_key` between `left` and `right`, the levels of other directories.
+    //A table with keys on one side only is copied as it is, one with keys on both sides is rewritten into two.
+    pub fn split_into(&self, split_key: &[u8], left: &Levels, right: &Levels) -> Result<(Vec<Table>, Vec<Table>, SplitSummary)> {
+        let mut outputs = (Vec::new(), Vec::new());
+        let mut summary = SplitSummary::default();
+        let res = (|| -> Result<()> {
+            //the pointers in the tables keep the file numbers of the value logs, both sides get all of them
+            left.copy_value_logs(&self.value_logs)?;
+            right.copy_value_logs(&self.value_logs)?;
+            for table in self.inner.iter().flatten() {
+                let level = table.get_level();
+                if table.max_key.get_user_key() < split_key {
+                    outputs.0.push(left.link_table(&*self.env, &table.file_name, level)?);
+                    summary.copied_files += 1;
+                } else if table.min_key.get_user_key() >= split_key {
+                    outputs.1.push(right.link_table(&*self.env, &table.file_name, level)?);
+                    summary.copied_files += 1;
+                } else {
+                    let (left_entries, right_entries): (Vec<_>, Vec<_>) = table.iter().collect::<Result<Vec<_>>>()?
+                        .into_iter()
+                        .partition(|(k, _)| k.get_user_key() < split_key);
+                    let size = table.get_size();
+                    outputs.0.extend(left.write_files(left_entries.into_iter(), level, table.write_times(), size / 2)?);
+                    outputs.1.extend(right.write_files(right_entries.into_iter(), level, table.write_times(), size / 2)?);
+                    summary.split_files += 1;
+                }
+            }
+            Ok(())
+        })();
+        match res {
+            Ok(()) => Ok((outputs.0, outputs.1, summary)),
+            Err(e) => {
+                //nothing refers to the tables written so far
+                for table in outputs.0.iter().chain(outputs.1.iter()) {
+                    table.mark_obsolete();
+                }
+                Err(e)
+            },
+        }
+    }
+
+    //shares the counters, the cache and the settings of the levels with a new table
+    fn adopt(&self, table: &mut Table) {
+        table.blocks_read = self.blocks_read.clone();
+        table.set_block_cache(self.block_cache.clone(), self.pin_l0_blocks, self.pin_index_and_filter_blocks);
+        table.count_meta_bytes(self.meta_bytes.clone());
+        table.obsolete_bytes = Some(self.obsolete_bytes.clone());
+        table.events = Some(self.events.clone());
+        table.paranoid_checks = self.paranoid_checks;
+        table.readahead_size = self.readahead_size;
+        table.set_value_logs(&self.value_logs);
+    }
+
+}
+
+#[derive(Debug)]
+pub struct Table {
+    file_name: PathBuf,
+    file_num: u64,
+    file: Arc<dyn RandomAccessFile>,
+    env: Arc<dyn Env>,
+    footer: Footer,
+    level: usize, //differs from the footer once the table has been moved
+    obsolete: AtomicBool, //delete the file on drop
+    allowed_seeks: AtomicU64, //lookups that may still miss here before the table is compacted, kept in memory only
+    filter_negatives: AtomicU64, //lookups the key filter ruled out
+    filter_false_positives: AtomicU64, //lookups the key filter let through that found nothing
+    index_block: Vec<IndexBlockEntry>,
+    min_key: LookUpKey,
+    max_key: LookUpKey,
+    properties: TableProperties,
+    blocks_read: Arc<AtomicU64>, //shared by all tables of the levels
+    block_cache: Option<Arc<BlockCache>>, //shared as well, only lookups go through it
+    pin_l0_blocks: bool, //the blocks of a level 0 table stay cached until it leaves the level
+    meta_bytes: Option<Arc<AtomicUsize>>, //shared by all tables of the levels, this one's part is taken back on drop
+    events: Option<Arc<EventBus>>, //told when the file is deleted
+    obsolete_bytes: Option<Arc<AtomicU64>>, //shared by all tables of the levels, this one's size is added while it waits for deletion
+    paranoid_checks: bool, //verify every block read against its checksum, and lookups against the key range
+    readahead_size: usize, //bytes an iterator reads at once after two blocks in a row, 0 reads block by block
+    value_logs: Option<Arc<ValueLogs>>, //lookups read the values of pointer entries from them
+    value_log_refs: Vec<Arc<ValueLogRef>>, //keep the logs the pointers lead to, also once they are retired
+}
+
+impl Table {
+    //data blocks are written out as soon as they fill up, so only one block is buffered at a time
+    #[cfg(test)]
+    pub fn new<I, V>(env: &Arc<dyn Env>, sst_file: PathBuf, iter: I, level: usize, block_size: usize, rate_limiter: &RateLimiter) -> Result<Self>
+    where
+        I: Iterator<Item = (LookUpKey, V)>,
+        V: AsRef<[u8]>,
+    {
+        Self::with_format(env, sst_file, iter, level, block_size, rate_limiter, CURRENT_FORMAT)
+    }
+
+    #[cfg(test)]
+    pub fn with_format<I, V>(env: &Arc<dyn Env>, sst_file: PathBuf, iter: I, level: usize, block_size: usize, rate_limiter: &RateLimiter, format_version: u32) -> Result<Self>
+    where
+        I: Iterator<Item = (LookUpKey, V)>,
+        V: AsRef<[u8]>,
+    {
+        Self::build(env, sst_file, iter, level, block_size, rate_limiter, format_version, Vec::new(), None, 0.0, 0)
+    }
+
+    pub fn with_write_times<I, V>(env: &Arc<dyn Env>, sst_file: PathBuf, iter: I, level: usize, block_size: usize, rate_limiter: &RateLimiter,
+        write_times: Vec<(u64, u64)>) -> Result<Self>
+    where
+        I: Iterator<Item = (LookUpKey, V)>,
+        V: AsRef<[u8]>,
+    {
+        Self::build(env, sst_file, iter, level, block_size, rate_limiter, CURRENT_FORMAT, write_times, None, 0.0, 0)
+    }
+
+    //`key_filter_bits` bits per key of the key filter, 0 writes none
+    #[allow(clippy::too_many_arguments)]
+    fn build<I, V>(env: &Arc<dyn Env>, sst_file: PathBuf, iter: I, level: usize, block_size: usize, rate_limiter: &RateLimiter, format_version: u32,
+        write_times: Vec<(u64, u64)>, prefix_extractor: Option<&dyn PrefixExtractor>, key_filter_bits: f64, preallocate: u64) -> Result<Self>
+    where
+        I: Iterator<Item = (LookUpKey, V)>,
+        V: AsRef<[u8]>,
+    {
+        let mut iter = iter.peekable();
+        let min_key = match iter.peek() {
+            Some((key, _)) => key.clone(),
+            None => return Err(Error::InvalidArgument(format!("table {:?} would have no entries", sst_file))),
+        };
+        //only a complete table gets the .sst name, a crash midway leaves a .tmp file behind
+        let tmp_file = sst_file.with_extension("sst.tmp");
+        let mut file = env.create(&tmp_file)?;
+        //only a hint, a file system that can not reserve space still gets the table
+        let allocated = preallocate > 0 && file.allocate(preallocate).is_ok();
+        let mut index_block = Vec::new();
+        let mut data_block = Vec::new();
+        let mut max_key = min_key.clone();
+        let mut last_seq_num = 0;
+        let mut written = 0;
+        let mut prefix_hashes = Vec::new();
+        let mut last_prefix: Option<Vec<u8>> = None;
+        let mut key_hashes = Vec::new();
+        let mut block_checksums = Vec::new();
+        let mut block_entries = Vec::new();
+        let (mut num_entries, mut num_deletions) = (0, 0);
+        let mut entries_in_block = 0;
+        let mut value_log_bytes = BTreeMap::new();
+        let mut value_checksums = false;
+
+        while let Some((key, value)) = iter.next() {
+            last_seq_num = std::cmp::max(key.get_seq_num(), last_seq_num);
+            num_entries += 1;
+            entries_in_block += 1;
+            if key.is_deletion() {
+                num_deletions += 1;
+            }
+            value_checksums |= key.has_value_checksum();
+            if key.is_value_pointer() {
+                let (len, _) = key.internal_key.value_len(value.as_ref())?;
+                let pointer = ValuePointer::decode(&value.as_ref()[..len])?;
+                *value_log_bytes.entry(pointer.file_num).or_insert(0) += value_log::record_size(key.get_user_key().len(), pointer.len);
+            }
+            //the versions of a key are next to each other, it is hashed once
+            if key_filter_bits > 0.0 && (key_hashes.is_empty() || key.get_user_key() != max_key.get_user_key()) {
+                key_hashes.push(bloom::hash(key.get_user_key()));
+            }
+            //keys are sorted, so the keys sharing a prefix are next to each other
+            if let Some(prefix) = prefix_extractor.and_then(|e| e.prefix(key.get_user_key())) {
+                if last_prefix.as_deref() != Some(prefix) {
+                    prefix_hashes.push(bloom::hash(prefix));
+                    last_prefix = Some(prefix.to_vec());
+                }
+            }
+            DataBlockEntry::encode_entry(&mut data_block, &key, value.as_ref(), format_version);
+            //the last block may be smaller than block_size, but it still has to be written
+            if data_block.len() > block_size || iter.peek().is_none() {
+                let offset = written;
+                let length = data_block.len() as u64;
+                rate_limiter.request(length);
+                block_checksums.push(crc32c(&data_block));
+                block_entries.push(entries_in_block);
+                entries_in_block = 0;
+                fault::append(&mut *file, &data_block, &tmp_file)?;
+                written += length;
+                data_block.clear();
+                index_block.push(IndexBlockEntry::new(key.clone(), offset, length));
+            }
+            max_key = key;
+        }
+        let mut buf = Vec::new();
+        let properties = TableProperties {
+            write_times,
+            prefix_extractor: prefix_extractor.map(|e| e.name().to_owned()),
+            prefix_filter: match prefix_extractor {
+                Some(_) => bloom::build(&prefix_hashes, bloom::BITS_PER_KEY),
+                None => Vec::new(),
+            },
+            key_filter: match key_filter_bits > 0.0 {
+                true => bloom::build(&key_hashes, key_filter_bits),
+                false => Vec::new(),
+            },
+            block_checksums,
+            block_entries,
+            num_entries: Some(num_entries),
+            num_deletions: Some(num_deletions),
+            value_log_bytes,
+        };
+        //without properties the meta index block is empty, its addr is equal to index_block_addr
+        let meta_index_block_addr = written;
+        properties.encode_to(&mut buf, format_version);
+        let index_block_addr = written + buf.len() as u64;
+        for entry in index_block.iter() {
+            entry.encode_to(&mut buf, format_version);
+        }
+        let min_key_addr = written + buf.len() as u64;
+        min_key.encode_to(&mut buf, format_version >= VARINT_FORMAT);
+        let max_key_addr = written + buf.len() as u64;
+        max_key.encode_to(&mut buf, format_version >= VARINT_FORMAT);
+        let foot_addr = written + buf.len() as u64;
+
+        //the entries are laid out the same from PROPERTIES_FORMAT on, only the footer tells about the checksums
+        debug_assert!(!value_checksums || format_version >= PROPERTIES_FORMAT, "value checksums in a table of format {}", format_version);
+        let format_version = match value_checksums {
+            true => std::cmp::max(format_version, VALUE_CHECKSUM_FORMAT),
+            false => format_version,
+        };
+        let footer = Footer {
+            format_version,
+            level,
+            min_key_addr,
+            max_key_addr,
+            last_seq_num,
+            meta_index_block_addr,
+            index_block_addr,
+            foot_addr,
+        };
+        buf.append(&mut footer.encode_to());
+        //Write to file
+        rate_limiter.request(buf.len() as u64);
+        fault::append(&mut *file, &buf, &tmp_file)?;
+        //give back what the estimate reserved past the end of the table
+        if allocated {
+            file.truncate(written + buf.len() as u64)?;
+        }
+        fault::sync(&mut *file, &tmp_file)?;
+        drop(file);
+        fault::rename(&**env, &tmp_file, &sst_file)?;
+        if let Some(dir) = sst_file.parent() {
+            fault::sync_dir(&**env, dir)?;
+        }
+
+        let file = env.open(&sst_file)?;
+        let allowed_seeks = AtomicU64::new(Self::initial_allowed_seeks(&*file));
+        Ok(Table {
+            file_num: parse_file_num(&sst_file),
+            file_name: sst_file,
+            file,
+            env: env.clone(),
+            footer,
+            level,
+            obsolete: AtomicBool::new(false),
+            allowed_seeks,
+            filter_negatives: AtomicU64::new(0),
+            filter_false_positives: AtomicU64::new(0),
+            index_block,
+            min_key,
+            max_key,
+            properties,
+            blocks_read: Arc::new(AtomicU64::new(0)),
+            block_cache: None,
+            pin_l0_blocks: false,
+            meta_bytes: None,
+            events: None,
+            obsolete_bytes: None,
+            paranoid_checks: false,
+            readahead_size: 0,
+            value_logs: None,
+            value_log_refs: Vec::new(),
+        })
+    }
+
+    pub fn open(env: &Arc<dyn Env>, sst_file: PathBuf) -> Result<Self> {
+        let file = env.open(&sst_file)?;
+        let footer = Footer::decode_from(&*file)?;
+        let varint = footer.format_version >= VARINT_FORMAT;
+        //the index block and then the min and max keys sit between the data blocks and the footer
+        let corrupted_addrs = || Error::Corruption(format!("invalid addresses in the footer of {:?}", sst_file));
+        if !(footer.meta_index_block_addr <= footer.index_block_addr
+            && footer.index_block_addr <= footer.min_key_addr
+            && footer.min_key_addr <= footer.max_key_addr
+            && footer.max_key_addr <= footer.foot_addr) {
+            return Err(corrupted_addrs());
+        }
+        let mut trailer = vec![0; to_len(footer.foot_addr - footer.meta_index_block_addr)?];
+        file.read_at(&mut trailer, footer.meta_index_block_addr)?;
+        let index_block_offset = to_len(footer.index_block_addr - footer.meta_index_block_addr)?;
+        let properties = TableProperties::decode_from(&trailer[..index_block_offset], footer.format_version)?;
+        //the index keys and the min and max keys stay views into the trailer
+        let trailer = Bytes::from(trailer);
+        let mut index_block = Vec::new();
+        let mut addr = index_block_offset as u64;
+        let min_key_offset = footer.min_key_addr - footer.meta_index_block_addr;
+        while addr < min_key_offset {
+            let entry = IndexBlockEntry::decode_from(&trailer, &mut addr, footer.format_version)?;
+            //blocks are read into memory whole, so their lengths are checked once here
+            if entry.offset.checked_add(entry.length).is_none_or(|end| end > footer.meta_index_block_addr) {
+                return Err(Error::Corruption(format!("index entry past the data blocks of {:?}", sst_file)));
+            }
+            to_len(entry.length)?;
+            index_block.push(entry);
+        }
+        let mut key_addr = min_key_offset;
+        let min_key = LookUpKey::decode_from_bytes(&trailer, &mut key_addr, varint)?;
+        if key_addr != footer.max_key_addr - footer.meta_index_block_addr {
+            return Err(corrupted_addrs());
+        }
+        let max_key = LookUpKey::decode_from_bytes(&trailer, &mut key_addr, varint)?;
+        if !properties.block_checksums.is_empty() && properties.block_checksums.len() != index_block.len() {
+            return Err(Error::Corruption(format!("{} block checksums for {} blocks in {:?}",
+                properties.block_checksums.len(), index_block.len(), sst_file)));
+        }
+        if !properties.block_entries.is_empty() && properties.block_entries.len() != index_block.len() {
+            return Err(Error::Corruption(format!("{} block entry counts for {} blocks in {:?}",
+                properties.block_entries.len(), index_block.len(), sst_file)));
+        }
+        let allowed_seeks = AtomicU64::new(Self::initial_allowed_seeks(&*file));
+        Ok(Table {
+            file_num: parse_file_num(&sst_file),
+            file_name: sst_file,
+            file,
+            env: env.clone(),
+            level: footer.level,
+            obsolete: AtomicBool::new(false),
+            allowed_seeks,
+            filter_negatives: AtomicU64::new(0),
+            filter_false_positives: AtomicU64::new(0),
+            footer,
+            index_block,
+            min_key,
+            max_key,
+            properties,
+            blocks_read: Arc::new(AtomicU64::new(0)),
+            block_cache: None,
+            pin_l0_blocks: false,
+            meta_bytes: None,
+            events: None,
+            obsolete_bytes: None,
+            paranoid_checks: false,
+            readahead_size: 0,
+            value_logs: None,
+            value_log_refs: Vec::new(),
+        })
+    }
+
+    //shares the block cache of the levels, the index and filter blocks are charged to it when they are pinned
+    fn set_block_cache(&mut self, block_cache: Option<Arc<BlockCache>>, pin_l0_blocks: bool, pin_index_and_filter_blocks: bool) {
+        if let Some(cache) = block_cache.as_ref().filter(|_| pin_index_and_filter_blocks) {
+            cache.pin_meta(self.file_num, self.meta_size() as usize);
+        }
+        self.block_cache = block_cache;
+        self.pin_l0_blocks = pin_l0_blocks;
+    }
+
+    //the file is deleted once the last reference is dropped
+    pub fn mark_obsolete(&self) {
+        if !self.obsolete.swap(true, atomic::Ordering::AcqRel) {
+            if let Some(counter) = &self.obsolete_bytes {
+                counter.fetch_add(self.get_size(), atomic::Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn count_meta_bytes(&mut self, counter: Arc<AtomicUsize>) {
+        counter.fetch_add(self.meta_size() as usize, atomic::Ordering::Relaxed);
+        self.meta_bytes = Some(counter);
+    }
+
+    //bytes of the index and filter blocks, they are read on open and kept in memory
+    fn meta_size(&self) -> u64 {
+        self.footer.foot_addr - self.footer.meta_index_block_addr
+    }
+
+    //reads the data blocks into the block cache in one batch, as many from the start as `budget` still allows.
+    //Returns the bytes read.
+    fn preload_blocks(&self, budget: &AtomicU64) -> Result<u64> {
+        let cache = match &self.block_cache {
+            Some(cache) => cache,
+            None => return Ok(0),
+        };
+        let block_idxs = (0..self.index_block.len())
+            .take_while(|&idx| {
+                let length = self.index_block[idx].length;
+                budget.fetch_update(atomic::Ordering::Relaxed, atomic::Ordering::Relaxed, |b| b.checked_sub(length)).is_ok()
+            })
+            .collect::<Vec<_>>();
+        let mut bytes = 0;
+        for (idx, block) in block_idxs.iter().copied().zip(self.read_block_batch(&block_idxs, None)?) {
+            bytes += block.len() as u64;
+            self.cache_block(cache, idx, block);
+        }
+        Ok(bytes)
+    }
+
+    //the same file seen as a table of another level, nothing is read or written
+    pub fn moved_to(&self, level: usize) -> Self {
+        if let Some(cache) = self.block_cache.as_ref().filter(|_| self.level == 0 && level != 0 && self.pin_l0_blocks) {
+            cache.unpin_file(self.file_num);
+        }
+        //holds a copy of the index until the table it was moved from is dropped
+        if let Some(counter) = &self.meta_bytes {
+            counter.fetch_add(self.meta_size() as usize, atomic::Ordering::Relaxed);
+        }
+        Table {
+            file_name: self.file_name.clone(),
+            file_num: self.file_num,
+            file: self.file.clone(),
+            env: self.env.clone(),
+            footer: self.footer.clone(),
+            level,
+            obsolete: AtomicBool::new(false),
+            allowed_seeks: AtomicU64::new(Self::initial_allowed_seeks(&*self.file)),
+            filter_negatives: AtomicU64::new(0),
+            filter_false_positives: AtomicU64::new(0),
+            index_block: self.index_block.clone(),
+            min_key: self.min_key.clone(),
+            max_key: self.max_key.clone(),
+            properties: self.properties.clone(),
+            blocks_read: self.blocks_read.clone(),
+            block_cache: self.block_cache.clone(),
+            pin_l0_blocks: self.pin_l0_blocks,
+            meta_bytes: self.meta_bytes.clone(),
+            events: self.events.clone(),
+            obsolete_bytes: self.obsolete_bytes.clone(),
+            paranoid_checks: self.paranoid_checks,
+            readahead_size: self.readahead_size,
+            value_logs: self.value_logs.clone(),
+            value_log_refs: self.value_log_refs.clone(),
+        }
+    }
+
+    pub fn get_level(&self) -> usize {
+        self.level
+    }
+
+    pub fn get_file_num(&self) -> u64 {
+        self.file_num
+    }
+
+    pub fn overlaps(&self, other: &Table) -> bool {
+        ranges_overlap(&self.min_key.get_user_key(), &self.max_key.get_user_key(),
+            &other.min_key.get_user_key(), &other.max_key.get_user_key())
+    }
+
+    pub fn write_times(&self) -> Vec<(u64, u64)> {
+        self.properties.write_times.clone()
+    }
+
+    //written to another level and moved here without a rewrite
+    pub fn is_moved(&self) -> bool {
+        self.level != self.footer.level
+    }
+
+    //the range check is by user key, versions of a boundary key may carry any sequence number
+    pub fn contains_user_key(&self, key: &[u8]) -> bool {
+        self.min_key.get_user_key() <= key && key <= self.max_key.get_user_key()
+    }
+
+    pub fn file(&self) -> TableFile {
+        TableFile { level: self.level, path: self.file_name.clone(), bytes: self.get_size() }
+    }
+
+    pub fn num_entries(&self) -> Option<u64> {
+        self.properties.num_entries
+    }
+
+    pub fn num_deletions(&self) -> Option<u64> {
+        self.properties.num_deletions
+    }
+
+    //stored entries with a user key in [start, end), every version and tombstone. Blocks wholly
+    //inside the range are taken from their recorded count, only the others are read.
+    pub fn count_entries_in(&self, start: &[u8], end: &[u8], reads: &BlockReads) -> Result<u64> {
+        let mut count = 0;
+        let first_block = self.index_block.partition_point(|b| b.max_key.get_user_key() < start);
+        for idx in first_block..self.index_block.len() {
+            //a block holds no user key below the largest one of the block before it
+            let low = match idx {
+                0 => self.min_key.get_user_key(),
+                _ => self.index_block[idx - 1].max_key.get_user_key(),
+            };
+            if low >= end {
+                break;
+            }
+            match self.properties.block_entries.get(idx) {
+                Some(&n) if low >= start && self.index_block[idx].max_key.get_user_key() < end => count += n as u64,
+                _ => {
+                    let block = self.cached_block_with(idx, reads)?;
+                    count += decode_sorted_keys(&block, self.footer.format_version).iter()
+                        .filter(|(k, _)| start <= k.get_user_key() && k.get_user_key() < end)
+                        .count() as u64;
+                },
+            }
+        }
+        Ok(count)
+    }
+
+    //None for a table written before entries were counted
+    pub fn tombstone_ratio(&self) -> Option<f64> {
+        match (self.num_entries()?, self.num_deletions()?) {
+            (0, _) => None,
+            (entries, deletions) => Some(deletions as f64 / entries as f64),
+        }
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.index_block.len()
+    }
+
+    //the user keys of `picks` entries of data block `block_idx` drawn at random, deletions are left out.
+    //The block is read from the file, not the cache.
+    pub fn sample_block(&self, block_idx: usize, picks: usize, rng: &mut Rng) -> Result<Vec<Vec<u8>>> {
+        let block = self.read_checked_block(block_idx)?;
+        let (entries, _) = decode_sorted_run(&block, self.footer.format_version);
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok((0..picks)
+            .map(|_| &entries[(rng.next() % entries.len() as u64) as usize].0)
+            .filter(|k| !k.is_deletion())
+            .map(|k| k.get_user_key().to_vec())
+            .collect())
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.file.size().unwrap()
+    }
+
+    //as in LevelDB, a seek costs about as much as compacting 16KB
+    fn initial_allowed_seeks(file: &dyn RandomAccessFile) -> u64 {
+        std::cmp::max(100, file.size().unwrap_or(0) / (16 * 1024))
+    }
+
+    //returns true only for the miss that used up the last allowed seek
+    fn charge_seek(&self) -> bool {
+        self.allowed_seeks.fetch_update(atomic::Ordering::Relaxed, atomic::Ordering::Relaxed, |n| n.checked_sub(1)) == Ok(1)
+    }
+
+    //false only if the prefix filter rules out every key with this prefix,
+    //a table without a filter or with one built by another extractor may hold any
+    pub fn may_contain_prefix(&self, extractor: &dyn PrefixExtractor, prefix: &[u8]) -> bool {
+        match &self.properties.prefix_extractor {
+            Some(name) if name == extractor.name() => bloom::may_contain(&self.properties.prefix_filter, prefix),
+            _ => true,
+        }
+    }
+
+    //false only if the key filter rules the key out, a table without one may hold any key.
+    //The lookups it rules out are counted.
+    fn may_contain_key(&self, key: &[u8]) -> bool {
+        if self.properties.key_filter.is_empty() || bloom::may_contain(&self.properties.key_filter, key) {
+            return true;
+        }
+        self.filter_negatives.fetch_add(1, atomic::Ordering::Relaxed);
+        false
+    }
+
+    //a lookup the key filter let through found nothing, or only versions newer than it reads
+    fn count_false_positive(&self) {
+        if !self.properties.key_filter.is_empty() {
+            self.filter_false_positives.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    //lookups the key filter ruled out and lookups it let through that found nothing
+    pub fn filter_counts(&self) -> (u64, u64) {
+        (self.filter_negatives.load(atomic::Ordering::Relaxed), self.filter_false_positives.load(atomic::Ordering::Relaxed))
+    }
+
+    pub fn seeks_exhausted(&self) -> bool {
+        self.allowed_seeks.load(atomic::Ordering::Relaxed) == 0
+    }
+
+    #[cfg(test)]
+    pub fn search(&self, key: &[u8], seq_num: u64) -> Option<(u64, Option<Bytes>)> {
+        self.search_with(key, seq_num, &BlockReads::default()).unwrap()
+    }
+
+    //returns the sequence number of the found version as well, None in the inner option means deleted.
+    //A value that does not match its checksum is an error when the reads verify checksums
+    pub fn search_with(&self, key: &[u8], seq_num: u64, reads: &BlockReads) -> Result<Option<(u64, Option<Bytes>)>> {
+        perf_context::record(|c| c.table_probes.push((self.level, self.file_num)));
+        if !self.may_contain_key(key) {
+            return Ok(None);
+        }
+        let look_up_key = Self::search_key(key, seq_num);
+        let found = match self.block_for(&look_up_key) {
+            Some(idx) => {
+                let block = self.cached_block_with(idx, reads)?;
+                self.search_block(idx, &block, key, &look_up_key, reads)?
+            },
+            None => None,
+        };
+        if found.is_none() {
+            self.count_false_positive();
+        }
+        Ok(found)
+    }
+
+    //search for several keys at once, the blocks they need are read in one batch
+    pub fn multi_search_with(&self, keys: &[&[u8]], seq_num: u64, reads: &BlockReads) -> Result<Vec<Found>> {
+        let look_up_keys = keys.iter().map(|key| Self::search_key(key, seq_num)).collect::<Vec<_>>();
+        let filtered = keys.iter().map(|key| self.may_contain_key(key)).collect::<Vec<_>>();
+        let block_idxs = look_up_keys.iter().zip(&filtered)
+            .map(|(k, &maybe)| if maybe { self.block_for(k) } else { None })
+            .collect::<Vec<_>>();
+        let mut needed = block_idxs.iter().flatten().copied().collect::<Vec<_>>();
+        needed.sort_unstable();
+        needed.dedup();
+        let mut blocks = HashMap::new();
+        if let Some(cache) = &self.block_cache {
+            needed.retain(|&idx| match cache.get(self.file_num, self.index_block[idx].offset) {
+                Some(block) => {
+                    perf_context::record(|c| c.block_cache_hits += 1);
+                    blocks.insert(idx, block);
+                    false
+                },
+                None => true,
+            });
+        }
+        for (idx, block) in needed.iter().copied().zip(self.read_block_batch(&needed, reads.verify_checksums)?) {
+            if let Some(cache) = self.block_cache.as_ref().filter(|_| reads.fill_cache) {
+                self.cache_block(cache, idx, block.clone());
+            }
+            blocks.insert(idx, block);
+        }
+        keys.iter().zip(look_up_keys.iter()).zip(block_idxs).zip(filtered)
+            .map(|(((key, look_up_key), idx), maybe)| {
+                let found = match idx {
+                    Some(idx) => self.search_block(idx, &blocks[&idx], key, look_up_key, reads)?,
+                    None => None,
+                };
+                if maybe && found.is_none() {
+                    self.count_false_positive();
+                }
+                Ok(found)
+            })
+            .collect()
+    }
+
+    fn search_key(key: &[u8], seq_num: u64) -> LookUpKey {
+        LookUpKey::new(InternalKey::new(key, std::cmp::min(seq_num, MAX_SEQ_NUM), ValueType::Delete))
+    }
+
+    //the only data block that may hold the key, None if it is past the end of the table
+    fn block_for(&self, look_up_key: &LookUpKey) -> Option<usize> {
+        let idx = match self.index_block.binary_search_by_key(&look_up_key, |e| &e.max_key) {
+            Ok(idx) => idx,
+            Err(idx) => idx,
+        };
+        if idx < self.index_block.len() { Some(idx) } else { None }
+    }
+
+    //entries are decoded as views into the block, only the returned value outlives it
+    fn search_block(&self, idx: usize, block: &Bytes, key: &[u8], look_up_key: &LookUpKey, reads: &BlockReads) -> Result<Option<(u64, Option<Bytes>)>> {
+        let mut offset = 0;
+        while offset < self.index_block[idx].length {
+            let block_entry = DataBlockEntry::decode_from(block, &mut offset, self.footer.format_version)?;
+            if block_entry.look_up_key >= *look_up_key && block_entry.look_up_key.get_user_key() == key {
+                if self.paranoid_checks && (block_entry.look_up_key < self.min_key || block_entry.look_up_key > self.max_key) {
+                    return Err(Error::Corruption(format!("data block {} of {:?}: key {:?} is outside of the table",
+                        idx, self.file_name, block_entry.look_up_key)));
+                }
+                let found_seq_num = block_entry.look_up_key.get_seq_num();
+                if block_entry.look_up_key.is_deletion() {
+                    return Ok(Some((found_seq_num, None)));
+                }
+                let verify = reads.verify_checksums.unwrap_or(self.paranoid_checks);
+                let value = self.resolve_value(&block_entry.look_up_key, block_entry.value, verify)?;
+                return Ok(Some((found_seq_num, Some(value))));
+            }
+        }
+        Ok(None)
+    }
+
+    fn set_value_logs(&mut self, value_logs: &Arc<ValueLogs>) {
+        self.value_log_refs = value_logs.refs(self.properties.value_log_bytes.keys().copied());
+        self.value_logs = Some(value_logs.clone());
+    }
+
+    //the value without its checksum, or the one a pointer entry leads to.
+    //A lost or damaged value log fails the lookup like a damaged block does.
+    fn resolve_value(&self, key: &LookUpKey, value: Bytes, verify: bool) -> Result<Bytes> {
+        let (value, checksum) = key.internal_key.split_value(value)?;
+        let value = match key.is_value_pointer() {
+            true => {
+                let value_logs = self.value_logs.as_ref().ok_or_else(|| Error::Corruption(format!(
+                    "value pointer of {:?} in {:?} without value logs", key.get_user_key().escape_ascii().to_string(), self.file_name)))?;
+                value_logs.resolve(true, value)?
+            },
+            false => value,
+        };
+        if verify {
+            verify_value_checksum(key.get_user_key(), &value, checksum)?;
+        }
+        Ok(value)
+    }
+
+    //the data block at `block_idx` of the index, through any handle of the file
+    fn read_block_from(&self, file: &dyn RandomAccessFile, block_idx: usize, verify_checksums: Option<bool>) -> Result<Bytes> {
+        let index_entry = &self.index_block[block_idx];
+        let mut block = vec![0; index_entry.length as usize];
+        file.read_at(
+            block.as_mut_slice(),
+            index_entry.offset,
+        )?;
+        self.check_block_with(block_idx, Bytes::from(block), verify_checksums)
+    }
+
+    //the data block at `block_idx` through the block cache, scans and compactions read around it
+    fn cached_block_with(&self, block_idx: usize, reads: &BlockReads) -> Result<Bytes> {
+        let cache = match &self.block_cache {
+            Some(cache) => cache,
+            None => return self.read_block_from(&*self.file, block_idx, reads.verify_checksums),
+        };
+        if let Some(block) = cache.get(self.file_num, self.index_block[block_idx].offset) {
+            perf_context::record(|c| c.block_cache_hits += 1);
+            return Ok(block);
+        }
+        let block = self.read_block_from(&*self.file, block_idx, reads.verify_checksums)?;
+        if reads.fill_cache {
+            self.cache_block(cache, block_idx, block.clone());
+        }
+        Ok(block)
+    }
+
+    fn cache_block(&self, cache: &BlockCache, block_idx: usize, block: Bytes) {
+        let offset = self.index_block[block_idx].offset;
+        match self.level == 0 && self.pin_l0_blocks {
+            true => { cache.insert_pinned(self.file_num, offset, block); },
+            false => cache.insert(self.file_num, offset, block),
+        }
+    }
+
+    //the data blocks at `block_idxs` of the index, with a single request to the file
+    fn read_block_batch(&self, block_idxs: &[usize], verify_checksums: Option<bool>) -> Result<Vec<Bytes>> {
+        let reads = block_idxs.iter()
+            .map(|&idx| (self.index_block[idx].offset, self.index_block[idx].length as usize))
+            .collect::<Vec<_>>();
+        let blocks = self.file.read_many(&reads)?;
+        block_idxs.iter().zip(blocks)
+            .map(|(&idx, block)| self.check_block_with(idx, Bytes::from(block), verify_checksums))
+            .collect()
+    }
+
+    //the data blocks from `block_idx` on that fit in `len` bytes, at least that one block, with the file offset they start at
+    fn read_blocks(&self, file: &dyn RandomAccessFile, block_idx: usize, len: usize) -> Result<(u64, Bytes)> {
+        let start = self.index_block[block_idx].offset;
+        let block_end = |e: &IndexBlockEntry| e.offset + e.length;
+        let end = self.index_block[block_idx + 1..].iter()
+            .map(block_end)
+            .take_while(|end| end - start <= len as u64)
+            .last()
+            .unwrap_or_else(|| block_end(&self.index_block[block_idx]));
+        let mut blocks = vec![0; (end - start) as usize];
+        file.read_at(&mut blocks, start)?;
+        Ok((start, Bytes::from(blocks)))
+    }
+
+    //counts a block read, a paranoid table checks it before handing it out; `verify_checksums` overrides paranoid_checks
+    fn check_block_with(&self, block_idx: usize, block: Bytes, verify_checksums: Option<bool>) -> Result<Bytes> {
+        self.blocks_read.fetch_add(1, atomic::Ordering::Relaxed);
+        perf_context::record(|c| c.blocks_read += 1);
+        if verify_checksums.unwrap_or(self.paranoid_checks) {
+            //tables from before checksums have none to check
+            if let Some(&checksum) = self.properties.block_checksums.get(block_idx) {
+                if crc32c(&block) != checksum {
+                    return Err(Error::Corruption(format!("data block {} of {:?}: checksum mismatch", block_idx, self.file_name)));
+                }
+            }
+        }
+        Ok(block)
+    }
+
+    //the first entry not less than `key`
+    pub fn seek(&self, key: &LookUpKey) -> Result<Option<(LookUpKey, Bytes)>> {
+        let mut iter = self.iter();
+        iter.seek_to_block(self.index_block.partition_point(|e| e.max_key < *key));
+        iter.find(|res| res.as_ref().map_or(true, |(k, _)| k >= key)).transpose()
+    }
+
+    pub fn iter(&self) -> TableIterator<'_, &Table> {
+        TableIterator::new(self)
+    }
+
+    //keeps the table, and so its file, alive until the iterator is dropped
+    #[cfg(test)]
+    pub fn owned_iter(self: Arc<Self>) -> TableIterator<'static, Arc<Table>> {
+        TableIterator::new(self)
+    }
+
+    //entries with a user key not less than `start`, beginning at the first block that may hold one
+    pub fn iter_from<'a>(&'a self, start: Option<&'a [u8]>, rate_limiter: Option<&'a RateLimiter>, scan: Scan) -> impl Iterator<Item = Result<(LookUpKey, Bytes)>> + 'a {
+        self.iter_from_with(start, rate_limiter, scan, BlockReads::default())
+    }
+
+    pub fn iter_from_with<'a>(&'a self, start: Option<&'a [u8]>, rate_limiter: Option<&'a RateLimiter>, scan: Scan, reads: BlockReads)
+        -> impl Iterator<Item = Result<(LookUpKey, Bytes)>> + 'a {
+        let mut iter = self.iter();
+        iter.reads = reads;
+        iter.rate_limiter = rate_limiter;
+        iter.scan = scan;
+        if let Some(start) = start {
+            iter.seek_to_block(self.index_block.partition_point(|e| e.max_key.get_user_key() < start));
+        }
+        iter.skip_while(move |res| matches!((res, start), (Ok((k, _)), Some(start)) if k.get_user_key() < start))
+    }
+
+    #[cfg(test)]
+    pub fn content(&self) -> Result<Vec<(LookUpKey, Bytes)>> {
+        self.iter().collect()
+    }
+
+    //the entries of every data block that can still be read and decoded. The second value is true only
+    //if that was all of them, in order, and they span the key range recorded in the trailer.
+    //every data block has to match its checksum, decode in order and end at its index key,
+    //and the blocks together have to span the min and max keys of the footer
+    pub fn verify(&self) -> Result<()> {
+        let corrupted = |block_idx: usize, what: &str| Error::Corruption(format!("data block {} of {:?}: {}", block_idx, self.file_name, what));
+        let mut first_key = None;
+        let mut last_key: Option<LookUpKey> = None;
+        for (block_idx, index_entry) in self.index_block.iter().enumerate() {
+            let block = self.read_checked_block(block_idx)?;
+            let (entries, decoded) = decode_sorted_run(&block, self.footer.format_version);
+            if !decoded {
+                return Err(corrupted(block_idx, "entries do not decode in order"));
+            }
+            let (first, last) = match (entries.first(), entries.last()) {
+                (Some((first, _)), Some((last, _))) => (first.clone(), last.clone()),
+                _ => return Err(corrupted(block_idx, "no entries")),
+            };
+            if last_key.as_ref().is_some_and(|k| *k >= first) {
+                return Err(corrupted(block_idx, "keys out of order with the block before"));
+            }
+            if last != index_entry.max_key {
+                return Err(corrupted(block_idx, "last key differs from the index"));
+            }
+            first_key.get_or_insert(first);
+            last_key = Some(last);
+        }
+        if first_key.as_ref() != Some(&self.min_key) || last_key.as_ref() != Some(&self.max_key) {
+            return Err(Error::Corruption(format!("keys of {:?} do not span the range in its footer", self.file_name)));
+        }
+        Ok(())
+    }
+
+    //the data block at `block_idx` from the file, an error if it does not match its checksum
+    fn read_checked_block(&self, block_idx: usize) -> Result<Bytes> {
+        let index_entry = &self.index_block[block_idx];
+        let mut block = vec![0; index_entry.length as usize];
+        self.file.read_at(&mut block, index_entry.offset)?;
+        //tables from before checksums have none to check
+        if let Some(&checksum) = self.properties.block_checksums.get(block_idx) {
+            if crc32c(&block) != checksum {
+                return Err(Error::Corruption(format!("data block {} of {:?}: checksum mismatch", block_idx, self.file_name)));
+            }
+        }
+        Ok(Bytes::from(block))
+    }
+
+    pub fn salvage(&self) -> (Vec<(LookUpKey, Bytes)>, bool) {
+        let mut entries: Vec<(LookUpKey, Bytes)> = Vec::new();
+        let mut intact = true;
+        for index_entry in self.index_block.iter() {
+            let mut block = vec![0; index_entry.length as usize];
+            if self.file.read_at(block.as_mut_slice(), index_entry.offset).is_err() {
+                intact = false;
+                continue;
+            }
+            let block = Bytes::from(block);
+            let (block_entries, decoded) = decode_sorted_run(&block, self.footer.format_version);
+            let in_order = match (entries.last(), block_entries.first()) {
+                (Some((last, _)), Some((first, _))) => last < first,
+                _ => true,
+            };
+            if decoded && in_order {
+                entries.extend(block_entries);
+            } else {
+                intact = false;
+            }
+        }
+        intact &= entries.first().map(|(k, _)| k) == Some(&self.min_key)
+            && entries.last().map(|(k, _)| k) == Some(&self.max_key);
+        (entries, intact)
+    }
+
+    //overwrites the index block, so the table no longer opens
+    #[cfg(test)]
+    pub fn corrupt_index(&self) {
+        let len = to_len(self.footer.min_key_addr - self.footer.index_block_addr).unwrap();
+        let mut bytes = self.env.read(&self.file_name).unwrap();
+        let start = self.footer.index_block_addr as usize;
+        bytes[start..start + len].fill(0xff);
+        self.env.create(&self.file_name).unwrap().append(&bytes).unwrap();
+    }
+}
+
+//what can be read of a table that does not open. The data blocks come first and hold nothing but entries,
+//so they are decoded from the start until an entry does not decode or is out of order.
+pub fn salvage_file(env: &dyn Env, sst_file: &Path) -> Result<Vec<(LookUpKey, Bytes)>> {
+    let file = env.open(sst_file)?;
+    let bytes = Bytes::from(env.read(sst_file)?);
+    //with a readable footer the entries end where the properties begin, without one their format is a guess
+    let (end, formats) = match Footer::decode_from(&*file) {
+        Ok(footer) if footer.meta_index_block_addr <= bytes.len() as u64 => {
+            (footer.meta_index_block_addr as usize, vec![footer.format_version])
+        },
+        _ => (bytes.len(), vec![NEWEST_FORMAT, LEGACY_FORMAT]),
+    };
+    let data = bytes.slice(..end);
+    Ok(formats.into_iter()
+        .map(|format_version| decode_sorted_run(&data, format_version).0)
+        .max_by_key(|entries| entries.len())
+        .unwrap_or_default())
+}
+
+//decode_sorted_run with empty values, the value bytes are never looked at
+fn decode_sorted_keys(bytes: &Bytes, format_version: u32) -> Vec<(LookUpKey, Bytes)> {
+    let mut entries: Vec<(LookUpKey, Bytes)> = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() as u64 {
+        match DataBlockEntry::decode_key_from(bytes, &mut offset, format_version) {
+            Ok(k) if !matches!(entries.last(), Some((last, _)) if *last >= k) => entries.push((k, Bytes::new())),
+            _ => break,
+        }
+    }
+    entries
+}
+
+//the entries up to the first one that does not decode or is out of order, and whether that was all of them
+fn decode_sorted_run(bytes: &Bytes, format_version: u32) -> (Vec<(LookUpKey, Bytes)>, bool) {
+    let mut entries: Vec<(LookUpKey, Bytes)> = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() as u64 {
+        match DataBlockEntry::decode_from(bytes, &mut offset, format_version) {
+            Ok(entry) if entries.last().is_none_or(|(k, _)| *k < entry.look_up_key) => {
+                entries.push((entry.look_up_key, entry.value));
+            },
+            _ => return (entries, false),
+        }
+    }
+    (entries, true)
+}
+
+//How a read goes through the tables, the per call part of lsm::ReadOptions.
+#[derive(Clone, Copy, Debug)]
+pub struct BlockReads {
+    pub verify_checksums: Option<bool>, //None follows the paranoid_checks of the table
+    pub fill_cache: bool, //blocks read from the file are added to the block cache
+    pub readahead: Option<usize>, //for scans, None follows the readahead_size of the table
+    pub deadline: Option<Instant>, //no table is searched after it
+}
+
+impl Default for BlockReads {
+    fn default() -> Self {
+        BlockReads {
+            verify_checksums: None,
+            fill_cache: true,
+            readahead: None,
+            deadline: None,
+        }
+    }
+}
+
+impl BlockReads {
+    pub fn check_deadline(&self) -> Result<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Error::TimedOut),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A cursor over the tables of a level in key order, which do not overlap, or over a single level 0 table.
+/// Only the block it is in is decoded, a seek reads just the block that may hold the key.
+pub struct LevelCursor {
+    tables: Vec<Arc<Table>>,
+    reads: BlockReads,
+    keys_only: bool, //the values are skipped and come out empty
+    table_idx: usize, //tables.len() when not valid
+    block_idx: usize,
+    entries: Vec<(LookUpKey, Bytes)>, //of the current block
+    pos: usize,
+    timed_out: bool,
+    error: Option<String>, //a block that could not be read or failed its checksum
+}
+
+impl LevelCursor {
+    pub fn new(tables: Vec<Arc<Table>>, reads: BlockReads, keys_only: bool) -> Self {
+        let table_idx = tables.len();
+        LevelCursor { tables, reads, keys_only, table_idx, block_idx: 0, entries: Vec::new(), pos: 0, timed_out: false, error: None }
+    }
+
+    fn invalidate(&mut self) {
+        self.table_idx = self.tables.len();
+        self.entries.clear();
+        self.pos = 0;
+    }
+
+    fn load_block(&mut self, table_idx: usize, block_idx: usize) -> bool {
+        if self.reads.check_deadline().is_err() {
+            self.timed_out = true;
+            self.invalidate();
+            return false;
+        }
+        let table = &self.tables[table_idx];
+        let block = match table.cached_block_with(block_idx, &self.reads) {
+            Ok(block) => block,
+            Err(e) => {
+                self.error = Some(e.to_string());
+                self.invalidate();
+                return false;
+            },
+        };
+        self.entries = match self.keys_only {
+            true => decode_sorted_keys(&block, table.footer.format_version),
+            false => decode_sorted_run(&block, table.footer.format_version).0,
+        };
+        self.table_idx = table_idx;
+        self.block_idx = block_idx;
+        true
+    }
+
+    //on to the first entry of the next block that has any
+    fn skip_forward(&mut self) {
+        while self.pos >= self.entries.len() {
+            let (table_idx, block_idx) = if self.block_idx + 1 < self.tables[self.table_idx].index_block.len() {
+                (self.table_idx, self.block_idx + 1)
+            } else if self.table_idx + 1 < self.tables.len() {
+                (self.table_idx + 1, 0)
+            } else {
+                return self.invalidate();
+            };
+            if !self.load_block(table_idx, block_idx) {
+                return;
+            }
+            self.pos = 0;
+        }
+    }
+
+    //back to the last entry of the previous block that has any
+    fn skip_backward(&mut self) {
+        while self.entries.is_empty() {
+            let (table_idx, block_idx) = if self.block_idx > 0 {
+                (self.table_idx, self.block_idx - 1)
+            } else if self.table_idx > 0 {
+                (self.table_idx - 1, self.tables[self.table_idx - 1].index_block.len() - 1)
+            } else {
+                return self.invalidate();
+            };
+            if !self.load_block(table_idx, block_idx) {
+                return;
+            }
+        }
+        self.pos = self.entries.len() - 1;
+    }
+}
+
+impl Cursor for LevelCursor {
+    fn valid(&self) -> bool {
+        self.table_idx < self.tables.len() && self.pos < self.entries.len()
+    }
+
+    fn seek(&mut self, key: &LookUpKey) {
+        let table_idx = self.tables.partition_point(|t| t.max_key < *key);
+        if table_idx == self.tables.len() {
+            return self.invalidate();
+        }
+        let block_idx = self.tables[table_idx].index_block.partition_point(|e| e.max_key < *key);
+        if self.load_block(table_idx, block_idx) {
+            self.pos = self.entries.partition_point(|(k, _)| k < key);
+            self.skip_forward();
+        }
+    }
+
+    fn seek_to_first(&mut self) {
+        if self.tables.is_empty() || !self.load_block(0, 0) {
+            return;
+        }
+        self.pos = 0;
+        self.skip_forward();
+    }
+
+    fn seek_to_last(&mut self) {
+        let table_idx = match self.tables.len() {
+            0 => return,
+            len => len - 1,
+        };
+        let block_idx = self.tables[table_idx].index_block.len() - 1;
+        if self.load_block(table_idx, block_idx) {
+            self.pos = self.entries.len().saturating_sub(1);
+            if self.entries.is_empty() {
+                self.skip_backward();
+            }
+        }
+    }
+
+    fn next(&mut self) {
+        self.pos += 1;
+        self.skip_forward();
+    }
+
+    fn prev(&mut self) {
+        if self.pos > 0 {
+            self.pos -= 1;
+            return;
+        }
+        self.entries.clear();
+        self.skip_backward();
+    }
+
+    fn key(&self) -> &LookUpKey {
+        &self.entries[self.pos].0
+    }
+
+    fn value(&self) -> &Bytes {
+        &self.entries[self.pos].1
+    }
+
+    fn status(&self) -> Result<()> {
+        if let Some(e) = &self.error {
+            return Err(Error::Corruption(e.clone()));
+        }
+        match self.timed_out {
+            true => Err(Error::TimedOut),
+            false => Ok(()),
+        }
+    }
+}
+
+//what an iterator tells the OS about the reads it is going to make
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scan {
+    Short,      //a seek and a few blocks, no hints
+    Long,       //on through many blocks, once it reads two in a row the OS may read further ahead
+    Compaction, //every block once, and never again, so they are dropped from the page cache once read
+}
+
+/// Walks the entries of a table in key order, reading one data block at a time.
+/// Once it reads two blocks in a row it reads ahead, the next blocks are sliced from one larger read.
+pub struct TableIterator<'a, T: Borrow<Table>> {
+    table: T,
+    block_idx: usize,
+    block: Bytes, //shared with the keys and values handed out
+    offset: u64,
+    rate_limiter: Option<&'a RateLimiter>,
+    last_block_idx: Option<usize>, //the block read last, to tell sequential reads from a seek
+    readahead: (u64, Bytes), //file offset and bytes of the blocks read ahead
+    scan: Scan,
+    file: Option<Arc<dyn RandomAccessFile>>, //of its own for long scans and compactions, hints are per open file
+    reads: BlockReads, //the deadline is left to the caller
+    failed: bool, //a block that did not decode ended the iteration
+}
+
+impl<'a, T: Borrow<Table>> TableIterator<'a, T> {
+    fn new(table: T) -> Self {
+        TableIterator {
+            table,
+            block_idx: 0,
+            block: Bytes::new(),
+            offset: 0,
+            rate_limiter: None,
+            last_block_idx: None,
+            readahead: (0, Bytes::new()),
+            scan: Scan::Short,
+            file: None,
+            reads: BlockReads::default(),
+            failed: false,
+        }
+    }
+
+    //opens the file again on the first read, so the hint for the data blocks leaves the lookups alone.
+    //Without it the reads go through the handle of the table, without hints.
+    fn scan_file(&mut self) -> Option<Arc<dyn RandomAccessFile>> {
+        if self.scan != Scan::Short && self.file.is_none() {
+            let table = self.table.borrow();
+            let file = table.env.open(&table.file_name).ok()?;
+            let _ = file.advise(Advice::Sequential, 0, table.footer.meta_index_block_addr);
+            self.file = Some(file);
+        }
+        self.file.clone()
+    }
+
+    //continues at the start of the block, what was read ahead is dropped unless the block is in it
+    fn seek_to_block(&mut self, block_idx: usize) {
+        self.block_idx = block_idx;
+        self.block = Bytes::new();
+        self.offset = 0;
+        self.last_block_idx = None;
+        if self.buffered(block_idx).is_none() {
+            self.readahead = (0, Bytes::new());
+        }
+    }
+
+    //the block if it was read ahead
+    fn buffered(&self, block_idx: usize) -> Option<Bytes> {
+        let entry = self.table.borrow().index_block.get(block_idx)?;
+        let (start, bytes) = &self.readahead;
+        let offset = entry.offset.checked_sub(*start)? as usize;
+        let end = offset.checked_add(entry.length as usize).filter(|&end| end <= bytes.len())?;
+        Some(bytes.slice(offset..end))
+    }
+
+    fn read_block(&mut self, block_idx: usize) -> Result<Bytes> {
+        let verify_checksums = self.reads.verify_checksums;
+        if let Some(block) = self.buffered(block_idx) {
+            return self.table.borrow().check_block_with(block_idx, block, verify_checksums);
+        }
+        let sequential = block_idx > 0 && self.last_block_idx == Some(block_idx - 1);
+        //a long scan that stops within its first block never opens the file
+        let scan_file = match self.scan == Scan::Compaction || sequential {
+            true => self.scan_file(),
+            false => self.file.clone(),
+        };
+        let table = self.table.borrow();
+        let file = scan_file.as_deref().unwrap_or(&*table.file);
+        let readahead_size = self.reads.readahead.unwrap_or(table.readahead_size);
+        if !sequential || readahead_size == 0 {
+            let block = table.read_block_from(file, block_idx, verify_checksums)?;
+            if self.scan == Scan::Compaction {
+                let _ = file.advise(Advice::DontNeed, table.index_block[block_idx].offset, block.len() as u64);
+            }
+            return Ok(block);
+        }
+        self.readahead = table.read_blocks(file, block_idx, readahead_size)?;
+        if self.scan == Scan::Compaction {
+            let _ = file.advise(Advice::DontNeed, self.readahead.0, self.readahead.1.len() as u64);
+        }
+        let block = self.buffered(block_idx).unwrap();
+        self.table.borrow().check_block_with(block_idx, block, verify_checksums)
+    }
+}
+
+impl<'a, T: Borrow<Table>> Iterator for TableIterator<'a, T> {
+    type Item = Result<(LookUpKey, Bytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        while self.offset >= self.block.len() as u64 {
+            let index_entry = self.table.borrow().index_block.get(self.block_idx)?;
+            if let Some(rate_limiter) = self.rate_limiter {
+                rate_limiter.request(index_entry.length);
+            }
+            self.block = match self.read_block(self.block_idx) {
+                Ok(block) => block,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                },
+            };
+            self.last_block_idx = Some(self.block_idx);
+            self.block_idx += 1;
+            self.offset = 0;
+        }
+        let res = DataBlockEntry::decode_from(&self.block, &mut self.offset, self.table.borrow().footer.format_version)
+            .map(|entry| (entry.look_up_key, entry.value));
+        self.failed = res.is_err();
+        Some(res)
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if let Some(counter) = &self.meta_bytes {
+            counter.fetch_sub(self.meta_size() as usize, atomic::Ordering::Relaxed);
+        }
+        if *self.obsolete.get_mut() {
+            if let Some(cache) = &self.block_cache {
+                cache.erase_file(self.file_num);
+            }
+            //the size is taken while the file is still there
+            let file = TableFile { level: self.level, path: self.file_name.clone(), bytes: self.file.size().unwrap_or(0) };
+            let env = &*self.env;
+            let res = fault::remove_file(env, &self.file_name)
+                .and_then(|_| self.file_name.parent().map_or(Ok(()), |dir| fault::sync_dir(env, dir)));
+            if let Some(counter) = &self.obsolete_bytes {
+                counter.fetch_sub(file.bytes, atomic::Ordering::Relaxed);
+            }
+            match (res, &self.events) {
+                (Err(e), _) => log::error!("failed to remove obsolete table {:?}: {}", self.file_name, e),
+                (Ok(()), Some(events)) => events.emit(|| DbEvent::SstDeleted { at: SystemTime::now(), table: file }),
+                (Ok(()), None) => {},
+            }
+        }
+    }
+}
+
+impl PartialEq for Table {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Table {}
+
+impl PartialOrd for Table {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Table {
+    //the file number breaks ties, so two distinct tables never collapse into one entry of a level
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.level == 0 {
+            other.footer.last_seq_num.cmp(&self.footer.last_seq_num)
+                .then_with(|| other.file_num.cmp(&self.file_num))
+        } else {
+            self.min_key.cmp(&other.min_key)
+                .then_with(|| self.file_num.cmp(&other.file_num))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn entries(keys: &[&str], seq_num: u64) -> Box<dyn Iterator<Item = (LookUpKey, Vec<u8>)>> {
+        let data = keys.iter()
+            .map(|k| (LookUpKey::new(InternalKey::new(k.as_bytes(), seq_num, ValueType::Put)), k.as_bytes().to_vec()))
+            .collect::<Vec<_>>();
+        Box::new(data.into_iter())
+    }
+
+    fn tombstones(keys: &[&str], seq_num: u64) -> Box<dyn Iterator<Item = (LookUpKey, Vec<u8>)>> {
+        let data = keys.iter()
+            .map(|k| (LookUpKey::new(InternalKey::new(k.as_bytes(), seq_num, ValueType::Delete)), Vec::new()))
+            .collect::<Vec<_>>();
+        Box::new(data.into_iter())
+    }
+
+    fn compact(levels: &mut Levels) {
+        let (deleted_tables, new_tables) = levels.background_compaction(None).unwrap();
+        levels.update(deleted_tables, new_tables).unwrap();
+    }
+
+    #[test]
+    fn ranges_overlap_requires_both_bounds() {
+        assert!(ranges_overlap(&1, &5, &3, &8));
+        assert!(ranges_overlap(&3, &8, &1, &5));
+        assert!(ranges_overlap(&1, &8, &3, &5));
+        assert!(ranges_overlap(&1, &3, &3, &5));
+        assert!(ranges_overlap(&4, &4, &4, &4));
+        assert!(!ranges_overlap(&1, &2, &3, &5));
+        assert!(!ranges_overlap(&6, &9, &3, &5));
+    }
+
+    #[test]
+    fn disjoint_level0_table_sinks_directly() {
+        let mut config = Config::new();
+        config.l0_compaction_threshold = 0;
+        let mut levels = Levels::new(temp_dir("sink"), Vec::new(), &config).unwrap();
+        let l1_left = levels.write_file(entries(&["a", "b"], 1), 1).unwrap();
+        let l1_right = levels.write_file(entries(&["x", "y"], 2), 1).unwrap();
+        let l0 = levels.write_file(entries(&["m", "n"], 3), 0).unwrap();
+        let l0_name = l0.file_name.clone();
+        levels.update(Vec::new(), vec![l1_left, l1_right, l0]).unwrap();
+
+        let (deleted_tables, new_tables) = levels.background_compaction(None).unwrap();
+        assert_eq!(deleted_tables, vec![(0, l0_name)]);
+        assert_eq!(new_tables.len(), 1);
+        assert_eq!(new_tables[0].get_level(), 1);
+        levels.update(deleted_tables, new_tables).unwrap();
+        assert!(levels.inner[0].is_empty());
+        assert_eq!(levels.inner[1].len(), 3);
+    }
+
+    #[test]
+    fn level0_tables_with_same_seq_coexist() {
+        let config = Config::new();
+        let mut levels = Levels::new(temp_dir("l0_same_seq"), Vec::new(), &config).unwrap();
+        let tables = ["a", "b", "c"].iter()
+            .map(|k| levels.write_file(entries(&[k], 5), 0).unwrap())
+            .collect::<Vec<_>>();
+        levels.update(Vec::new(), tables).unwrap();
+        assert_eq!(levels.inner[0].len(), 3);
+        for k in ["a", "b", "c"].iter() {
+            assert_eq!(levels.search(k.as_bytes(), 5), Some(k.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn tombstone_kept_until_bottom_level() {
+        let mut config = Config::new();
+        config.l0_compaction_threshold = 0;
+        let mut levels = Levels::new(temp_dir("tombstone"), Vec::new(), &config).unwrap();
+        let l2 = levels.write_file(entries(&["k"], 1), 2).unwrap();
+        let l1 = levels.write_file(entries(&["j", "l"], 2), 1).unwrap();
+        let l0 = levels.write_file(tombstones(&["k"], 3), 0).unwrap();
+        levels.update(Vec::new(), vec![l2, l1, l0]).unwrap();
+        assert_eq!(levels.search(b"k", 10), None);
+
+        //L0 -> L1 merges with the overlapping L1 table, L2 still holds "k" so the tombstone must survive
+        compact(&mut levels);
+        assert_eq!(levels.num_files_at_level(0), 0);
+        assert_eq!(levels.num_files_at_level(1), 1);
+        assert_eq!(levels.search(b"k", 10), None);
+        assert_eq!(levels.search(b"j", 10), Some(b"j".to_vec()));
+
+        //L1 -> L2 reaches the last level holding "k", both the tombstone and the old value are dropped
+        levels.l1_max_bytes = 0;
+        compact(&mut levels);
+        assert_eq!(levels.num_files_at_level(1), 0);
+        assert_eq!(levels.num_files_at_level(2), 1);
+        assert_eq!(levels.search(b"k", 10), None);
+        assert_eq!(levels.search(b"l", 10), Some(b"l".to_vec()));
+        let content = levels.inner[2].iter().next().unwrap().content().unwrap();
+        assert!(content.iter().all(|(k, _)| k.get_user_key() != b"k"));
+        assert_eq!(levels.dump_normalized(), [
+            "level 0: 0 files, 0 bytes, budget 0 files",
+            "level 1: 0 files, 0 bytes, budget 0 bytes, compact pointer l",
+            "level 2: 1 files, 217 bytes, budget 0 bytes",
+            "  #* 217 bytes 2 entries seq 2 [j .. l]",
+            "level 3: 0 files, 0 bytes, budget 0 bytes",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn dump_flags_tables_and_cuts_long_keys() {
+        let mut levels = Levels::new(temp_dir("dump"), Vec::new(), &Config::new()).unwrap();
+        let long_key = format!("{}\x01", "k".repeat(30));
+        let table = levels.write_file(entries(&["a", &long_key], 1), 1).unwrap();
+        let file_num = table.file_num;
+        levels.update(Vec::new(), vec![table]).unwrap();
+        let line = |dump: String| dump.lines().find(|l| l.starts_with("  #")).unwrap().to_owned();
+        assert_eq!(line(levels.dump()), format!("  #{} {} bytes 2 entries seq 1 [a .. {}...]", file_num, levels.level_bytes[1], "k".repeat(24)));
+
+        let version = levels.clone();
+        let table = version.level_tables(1).next().unwrap();
+        let compacting = version.compaction_started(&[table], 2);
+        assert!(line(levels.dump()).ends_with("] being-compacted"));
+        drop(compacting);
+        levels.update(vec![(1, table.file_name.clone())], Vec::new()).unwrap();
+        assert!(line(version.dump()).ends_with("] pending-deletion"));
+    }
+
+    #[test]
+    fn level0_search_picks_newest_visible_version() {
+        let config = Config::new();
+        let mut levels = Levels::new(temp_dir("l0_search"), Vec::new(), &config).unwrap();
+        let oldest = levels.write_file(entries(&["a", "b"], 1), 0).unwrap();
+        let overwrite = levels.write_file(
+            Box::new(vec![
+                (LookUpKey::new(InternalKey::new(b"b", 2, ValueType::Put)), b"b2".to_vec()),
+                (LookUpKey::new(InternalKey::new(b"c", 2, ValueType::Put)), b"c".to_vec()),
+            ].into_iter()),
+            0,
+        ).unwrap();
+        let delete = levels.write_file(tombstones(&["a"], 3), 0).unwrap();
+        levels.update(Vec::new(), vec![oldest, overwrite, delete]).unwrap();
+
+        assert_eq!(levels.search(b"a", 10), None);
+        assert_eq!(levels.search(b"a", 2), Some(b"a".to_vec()));
+        assert_eq!(levels.search(b"b", 10), Some(b"b2".to_vec()));
+        assert_eq!(levels.search(b"b", 1), Some(b"b".to_vec()));
+        assert_eq!(levels.search(b"c", 10), Some(b"c".to_vec()));
+        assert_eq!(levels.search(b"c", 1), None);
+    }
+
+    fn reference_tables(levels: &Levels) -> Vec<Table> {
+        //three overlapping runs, every third key is overwritten and every fifth deleted by a newer run
+        let run = |seq_num: u64, step: usize, delete: bool| {
+            let data = (0..300).step_by(step)
+                .map(|i| {
+                    let key = format!("key{:04}", i);
+                    let op_type = if delete { ValueType::Delete } else { ValueType::Put };
+                    let value = if delete { Vec::new() } else { format!("{}-{}", key, seq_num).into_bytes() };
+                    (LookUpKey::new(InternalKey::new(key.as_bytes(), seq_num, op_type)), value)
+                })
+                .collect::<Vec<_>>();
+            data.into_iter()
+        };
+        vec![
+            levels.write_file(run(1, 1, false), 1).unwrap(),
+            levels.write_file(run(2, 3, false), 1).unwrap(),
+            levels.write_file(run(3, 5, true), 1).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn streaming_merge_matches_materialized_merge() {
+        let mut config = Config::new();
+        config.block_size = 256;
+        let levels = Levels::new(temp_dir("streaming_merge"), Vec::new(), &config).unwrap();
+        let tables = reference_tables(&levels);
+
+        let mut expected = tables.iter()
+            .map(|t| t.content().unwrap().into_iter())
+            .kmerge()
+            .collect::<Vec<_>>();
+        expected.dedup_by_key(|(k, _)| k.get_user_key().to_vec());
+        let merged = merge_newest(tables.iter().map(|t| t.iter().map(Result::unwrap)).collect()).collect::<Vec<_>>();
+        assert_eq!(merged.len(), expected.len());
+        for ((k, v), (ek, ev)) in merged.iter().zip(expected.iter()) {
+            assert_eq!((k.get_user_key(), k.get_seq_num(), k.get_type()), (ek.get_user_key(), ek.get_seq_num(), ek.get_type()));
+            assert_eq!(v, ev);
+        }
+
+        let table = levels.write_file(merged.into_iter(), 2).unwrap();
+        assert_eq!(table.content().unwrap().len(), expected.len());
+    }
+
+    #[test]
+    fn streaming_merge_buffers_boundedly() {
+        use std::cell::Cell;
+        use std::collections::BTreeMap;
+        use std::rc::Rc;
+
+        let mut config = Config::new();
+        config.block_size = 256;
+        let levels = Levels::new(temp_dir("bounded_merge"), Vec::new(), &config).unwrap();
+        let tables = reference_tables(&levels);
+        //number of input entries whose user key is not greater than a given key
+        let mut consumed_upto = BTreeMap::new();
+        for (k, _) in tables.iter().flat_map(|t| t.content().unwrap()) {
+            *consumed_upto.entry(k.get_user_key().to_vec()).or_insert(0) += 1;
+        }
+        let mut total = 0;
+        for count in consumed_upto.values_mut() {
+            total += *count;
+            *count = total;
+        }
+
+        let pulled = Rc::new(Cell::new(0usize));
+        let max_ahead = Rc::new(Cell::new(0usize));
+        let inputs = tables.iter()
+            .map(|t| {
+                let pulled = pulled.clone();
+                t.iter().map(Result::unwrap).inspect(move |_| pulled.set(pulled.get() + 1))
+            })
+            .collect::<Vec<_>>();
+        let (p, m) = (pulled.clone(), max_ahead.clone());
+        let merged = merge_newest(inputs).inspect(move |(k, _)| {
+            //entries read from the inputs beyond the key handed to the table builder
+            let ahead = p.get() - consumed_upto[k.get_user_key()];
+            m.set(std::cmp::max(m.get(), ahead));
+        });
+        let table = levels.write_file(merged, 2).unwrap();
+
+        assert_eq!(pulled.get(), total);
+        assert_eq!(table.content().unwrap().len(), 300);
+        //the merge holds at most one head per input plus the lookahead of the dedup
+        assert!(max_ahead.get() <= tables.len() + 1, "merge read {} entries ahead", max_ahead.get());
+    }
+
+    #[test]
+    fn update_deletes_some_tables_of_a_level_and_keeps_the_others() {
+        let mut levels = Levels::new(temp_dir("update_subset"), Vec::new(), &Config::new()).unwrap();
+        let tables = ["a", "c", "e", "g"].iter()
+            .enumerate()
+            .map(|(i, key)| levels.write_file(entries(&[key], i as u64 + 1), 1).unwrap())
+            .collect::<Vec<_>>();
+        let files = tables.iter().map(|t| t.file_name.clone()).collect::<Vec<_>>();
+        let sizes = tables.iter().map(|t| t.get_size()).collect::<Vec<_>>();
+        levels.update(Vec::new(), tables).unwrap();
+
+        levels.update(vec![(1, files[1].clone()), (1, files[3].clone())], Vec::new()).unwrap();
+        let kept = levels.level_tables(1).map(|t| t.file_name.clone()).collect::<Vec<_>>();
+        assert_eq!(kept, vec![files[0].clone(), files[2].clone()]);
+        assert_eq!(levels.level_sizes()[1], (2, sizes[0] + sizes[2]));
+        assert!(files[0].exists() && files[2].exists());
+        assert!(!files[1].exists() && !files[3].exists());
+        let manifest = levels.manifest();
+        assert_eq!(manifest.tables.len(), 2);
+        assert!(kept.iter().all(|file| manifest.level_of(parse_file_num(file)) == Some(1)));
+        assert_eq!(levels.search(b"a", 10), Some(b"a".to_vec()));
+        assert_eq!(levels.search(b"c", 10), None);
+        assert_eq!(levels.search(b"e", 10), Some(b"e".to_vec()));
+    }
+
+    #[test]
+    fn manifest_past_the_deepest_level_is_rejected_before_growing_the_levels() {
+        let dir = temp_dir("manifest_levels");
+        let mut manifest = Levels::new(dir.clone(), Vec::new(), &Config::new()).unwrap().manifest();
+        manifest.num_levels = 1 << 40;
+        manifest.save(&*Config::new().env, &dir).unwrap();
+        assert!(matches!(Levels::new(dir, Vec::new(), &Config::new()), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn more_bits_per_key_let_fewer_missing_keys_through_the_key_filters() {
+        let mut config = Config::new();
+        config.bloom_bits_per_key = 0.0;
+        config.bloom_bits_per_key_per_level = vec![2.0, 16.0];
+        let mut levels = Levels::new(temp_dir("filter_bits"), Vec::new(), &config).unwrap();
+        let keys = (0..2000).step_by(2).map(|i| format!("k{:05}", i)).collect::<Vec<_>>();
+        let keys = keys.iter().map(|k| k.as_str()).collect::<Vec<_>>();
+        let tables = (0..3).map(|level| levels.write_file(entries(&keys, level as u64 + 1), level).unwrap()).collect();
+        levels.update(Vec::new(), tables).unwrap();
+
+        //every odd key misses level 0 and then level 1, the present ones are found in level 0
+        for i in 0..1998 {
+            let key = format!("k{:05}", i);
+            let found = levels.search(key.as_bytes(), 10);
+            assert_eq!(found.is_some(), i % 2 == 0, "{}", key);
+        }
+        let (found, _) = Levels::multi_search_candidates(&[levels.candidates(b"k00001")], &[b"k00001"], 10)[0].clone();
+        assert_eq!(found, None);
+        let stats = levels.level_stats();
+        let misses = |level: usize| stats[level].filter_negatives + stats[level].filter_false_positives;
+        assert_eq!((misses(0), misses(1)), (1000, 1000));
+        let (few_bits, many_bits) = (stats[0].filter_false_positives, stats[1].filter_false_positives);
+        assert!(many_bits * 10 < few_bits, "{} false positives with 16 bits per key, {} with 2", many_bits, few_bits);
+        assert!(stats[0].filter_false_positive_rate().unwrap() > 0.2);
+        //0 bits per key writes no filter, nothing is counted
+        assert_eq!(misses(2), 0);
+        assert_eq!(stats[2].filter_false_positive_rate(), None);
+        let table_stats = levels.table_stats();
+        assert_eq!(table_stats[1].filter_false_positives, many_bits);
+    }
+
+    #[test]
+    fn highest_scoring_level_is_picked() {
+        let config = Config::new();
+        let mut levels = Levels::new(temp_dir("pick_level"), Vec::new(), &config).unwrap();
+        let l1 = levels.write_file(entries(&["a", "b"], 1), 1).unwrap();
+        let l1_size = l1.get_size();
+        let big = (0..1000)
+            .map(|i| (LookUpKey::new(InternalKey::new(format!("c{:04}", i).as_bytes(), 1, ValueType::Put)), vec![0u8; 100]))
+            .collect::<Vec<_>>();
+        let l3 = levels.write_file(big.into_iter(), 3).unwrap();
+        let l3_size = l3.get_size();
+        //level 1 is marginally over its quota, level 3 is far over and gets a level 4 to compact into
+        levels.l1_max_bytes = l1_size - 10;
+        levels.update(Vec::new(), vec![l1, l3]).unwrap();
+        assert_eq!(levels.num_levels(), 5);
+        assert!(l3_size as f64 / levels.max_bytes_for_level(3) as f64 > 3.0);
+        let scores = levels.compaction_scores();
+        assert!(scores[1] > 1.0 && scores[1] < 1.2);
+        assert_eq!(levels.pick_compaction(), Some((3, 0)));
+        let stats = levels.level_stats();
+        assert_eq!(stats[3].num_files, 1);
+        assert_eq!(stats[3].size_bytes, l3_size);
+        assert_eq!(stats[3].score, scores[3]);
+
+        //nothing is picked while every level is within its quota
+        levels.l1_max_bytes = l3_size;
+        assert_eq!(levels.pick_compaction(), None);
+    }
+
+    #[test]
+    fn level_budgets_saturate_instead_of_overflowing() {
+        let mut config = Config::new();
+        config.l1_max_bytes = u64::MAX / 4;
+        config.max_levels = 40;
+        let levels = Levels::new(temp_dir("budget_overflow"), Vec::new(), &config).unwrap();
+        assert_eq!(levels.max_bytes_for_level(1), u64::MAX / 4);
+        assert_eq!(levels.max_bytes_for_level(2), u64::MAX);
+        assert_eq!(levels.max_bytes_for_level(39), u64::MAX);
+        assert!(levels.compaction_scores().iter().all(|score| *score == 0.0));
+
+        config.level_max_bytes = vec![100, 50];
+        let levels = Levels::new(temp_dir("budget_override"), Vec::new(), &config).unwrap();
+        assert_eq!(levels.max_bytes_for_level(1), 100);
+        assert_eq!(levels.max_bytes_for_level(2), 50);
+        assert_eq!(levels.max_bytes_for_level(3), u64::MAX);
+        let stats = levels.level_stats();
+        assert_eq!(stats[0].max_bytes, None);
+        assert_eq!(stats[1].max_bytes, Some(100));
+    }
+
+    #[test]
+    fn level_size_multiplier_decides_which_level_triggers() {
+        let mut config = Config::new();
+        config.level_size_multiplier = 2;
+        let mut levels = Levels::new(temp_dir("multiplier"), Vec::new(), &config).unwrap();
+        let l1 = levels.write_file(entries(&["a", "b", "c", "d"], 1), 1).unwrap();
+        levels.l1_max_bytes = l1.get_size() * 2 / 3;
+        let l2 = ["e", "f", "g"].iter()
+            .map(|k| levels.write_file(entries(&[&format!("{}1", k), &format!("{}2", k)], 1), 2).unwrap())
+            .collect::<Vec<_>>();
+        let mut tables = l2;
+        tables.push(l1);
+        levels.update(Vec::new(), tables).unwrap();
+
+        //level 2 holds more than twice level 1's budget, but less than ten times
+        assert_eq!(levels.pick_compaction().map(|(level_idx, _)| level_idx), Some(2));
+        levels.level_size_multiplier = 10;
+        assert_eq!(levels.pick_compaction().map(|(level_idx, _)| level_idx), Some(1));
+    }
+
+    #[test]
+    fn tombstone_heavy_table_is_compacted_first() {
+        let mut config = Config::new();
+        config.max_levels = 3;
+        config.tombstone_compaction_ratio = 0.3;
+        let mut levels = Levels::new(temp_dir("tombstone_ratio"), Vec::new(), &config).unwrap();
+        let keys = (0..1000).map(|i| format!("k{:03}", i)).collect::<Vec<_>>();
+        let key_refs = keys.iter().map(|k| k.as_str()).collect::<Vec<_>>();
+        let l2 = levels.write_file(entries(&key_refs, 1), 2).unwrap();
+        let others = (0..500).map(|i| format!("a{:03}", i)).collect::<Vec<_>>();
+        let others = levels.write_file(entries(&others.iter().map(|k| k.as_str()).collect::<Vec<_>>(), 2), 1).unwrap();
+        //90% of the keys deleted, the rest written again
+        let heavy = tombstones(&key_refs[..900], 3).chain(entries(&key_refs[900..], 3));
+        let heavy = levels.write_file(Box::new(heavy), 1).unwrap();
+        levels.l1_max_bytes = 1;
+        levels.update(Vec::new(), vec![l2, others, heavy]).unwrap();
+        let stats = levels.level_stats();
+        assert_eq!(stats[1].tombstone_heavy_files, 1);
+        assert!((stats[1].tombstone_ratio - 0.6).abs() < 1e-9);
+        assert_eq!(stats[2].tombstone_heavy_files, 0);
+
+        //by size alone the table without overlap in level 2 would go first
+        assert_eq!(levels.pick_compaction(), Some((1, 1)));
+        levels.tombstone_compaction_ratio = 0.0;
+        assert_eq!(levels.pick_compaction(), Some((1, 0)));
+        levels.tombstone_compaction_ratio = 0.3;
+
+        let bytes = |levels: &Levels| levels.level_stats().iter().map(|l| l.size_bytes).sum::<u64>();
+        let before = bytes(&levels);
+        compact(&mut levels);
+        assert!(levels.level_stats().iter().all(|l| l.tombstone_heavy_files == 0));
+        assert_eq!(levels.num_files_at_level(1), 1);
+        assert!(bytes(&levels) < before / 2, "{} bytes, {} before", bytes(&levels), before);
+        assert_eq!(levels.search(b"k000", 10), None);
+        assert_eq!(levels.search(b"k950", 10), Some(b"k950".to_vec()));
+        assert_eq!(levels.search(b"a100", 10), Some(b"a100".to_vec()));
+    }
+
+    #[test]
+    fn compactions_keeping_nothing_leave_no_table() {
+        let mut config = Config::new();
+        config.max_levels = 3;
+        config.max_subcompactions = 4;
+        let mut levels = Levels::new(temp_dir("empty_outputs"), Vec::new(), &config).unwrap();
+        let keys = (0..500).map(|i| format!("k{:03}", i)).collect::<Vec<_>>();
+        let keys = keys.iter().map(|k| k.as_str()).collect::<Vec<_>>();
+        let l2 = levels.write_file(entries(&keys, 1), 2).unwrap();
+        let l1 = levels.write_file(tombstones(&keys, 2), 1).unwrap();
+        assert!(l1.num_blocks() > 1);
+        levels.l1_max_bytes = 1;
+        levels.update(Vec::new(), vec![l2, l1]).unwrap();
+        let (deleted_tables, new_tables) = levels.background_compaction(None).unwrap();
+        assert_eq!(deleted_tables.len(), 2);
+        assert!(new_tables.is_empty());
+        levels.update(deleted_tables, new_tables).unwrap();
+        assert!(levels.live_tables().is_empty());
+        assert_eq!(levels.search(b"k100", 10), None);
+
+        //and the same when a compaction filter takes every entry out
+        struct RemoveAll;
+        impl CompactionFilter for RemoveAll {
+            fn filter(&self, _: usize, _: &[u8], _: &[u8]) -> FilterDecision {
+                FilterDecision::Remove
+            }
+        }
+        config.compaction_filter = Some(Arc::new(RemoveAll));
+        let mut levels = Levels::new(temp_dir("filtered_outputs"), Vec::new(), &config).unwrap();
+        let l2 = levels.write_file(entries(&keys[..250], 1), 2).unwrap();
+        let l1 = levels.write_file(entries(&keys[200..], 2), 1).unwrap();
+        levels.l1_max_bytes = 1;
+        levels.update(Vec::new(), vec![l2, l1]).unwrap();
+        compact(&mut levels);
+        assert!(levels.live_tables().is_empty());
+        assert!(levels.dump_normalized().lines().all(|line| line.starts_with("level ")));
+    }
+
+    #[test]
+    fn deeper_levels_get_bigger_files() {
+        let mut config = Config::new();
+        config.target_file_size_base = 2 * 1024;
+        config.target_file_size_multiplier = 4;
+        //two versions of every key, the newer one written first so they straddle every possible cut
+        let data = (0..200)
+            .flat_map(|i| {
+                let key = format!("key{:03}", i);
+                vec![
+                    (LookUpKey::new(InternalKey::new(key.as_bytes(), 2, ValueType::Put)), vec![2u8; 100]),
+                    (LookUpKey::new(InternalKey::new(key.as_bytes(), 1, ValueType::Put)), vec![1u8; 100]),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let outputs = [1, 3].iter()
+            .map(|&level_idx| {
+                let levels = Levels::new(temp_dir(&format!("target_size_{}", level_idx)), Vec::new(), &config).unwrap();
+                let tables = levels.write_files(data.clone().into_iter(), level_idx, Vec::new(), 0).unwrap();
+                (levels.target_file_size(level_idx), tables)
+            })
+            .collect::<Vec<_>>();
+
+        let (l1_target, l1_tables) = &outputs[0];
+        let (l3_target, l3_tables) = &outputs[1];
+        assert_eq!(*l3_target, 16 * *l1_target);
+        assert!(l1_tables.len() > 4 * l3_tables.len());
+        for (target, tables) in outputs.iter() {
+            for pair in tables.windows(2) {
+                assert!(pair[0].max_key.get_user_key() < pair[1].min_key.get_user_key());
+            }
+            //a table only goes past its target to finish the versions of its last key, plus its index and footer
+            for table in tables.iter() {
+                assert!(table.get_size() < target + 1024);
+            }
+            assert_eq!(tables.iter().map(|t| t.content().unwrap().len()).sum::<usize>(), data.len());
+        }
+    }
+
+    #[test]
+    fn average_file_size_is_reported_per_level() {
+        let config = Config::new();
+        let mut levels = Levels::new(temp_dir("avg_file_size"), Vec::new(), &config).unwrap();
+        let tables = vec![
+            levels.write_file(entries(&["a", "b"], 1), 1).unwrap(),
+            levels.write_file(entries(&["c", "d", "e", "f"], 1), 1).unwrap(),
+        ];
+        let total = tables.iter().map(|t| t.get_size()).sum::<u64>();
+        levels.update(Vec::new(), tables).unwrap();
+        let stats = levels.level_stats();
+        assert_eq!(stats[0].avg_file_size, 0);
+        assert_eq!(stats[1].avg_file_size, total / 2);
+    }
+
+    #[test]
+    fn varint_tables_are_smaller_and_legacy_tables_stay_readable() {
+        let dir = temp_dir("table_formats");
+        let rate_limiter = RateLimiter::new(0);
+        //10 byte keys with 20 byte values, where fixed width lengths outweigh the payload
+        let data = (0..1000)
+            .map(|i| (LookUpKey::new(InternalKey::new(format!("key{:07}", i).as_bytes(), i, ValueType::Put)), vec![b'v'; 20]))
+            .collect::<Vec<_>>();
+        let tables = [(1, LEGACY_FORMAT), (2, VARINT_FORMAT)].iter()
+            .map(|&(file_num, format_version)| {
+                let file_name = dir.join(format!("{}.sst", file_num));
+                Table::with_format(&test_env(), file_name.clone(), data.clone().into_iter(), 1, 4096, &rate_limiter, format_version).unwrap();
+                Table::open(&test_env(), file_name).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(tables[0].footer.format_version, LEGACY_FORMAT);
+        assert_eq!(tables[1].footer.format_version, VARINT_FORMAT);
+        //54 bytes per entry shrink to 40
+        assert!(tables[1].get_size() * 5 < tables[0].get_size() * 4,
+            "{} bytes with varints, {} without", tables[1].get_size(), tables[0].get_size());
+        for table in tables.iter() {
+            assert_eq!(table.content().unwrap().len(), data.len());
+            assert_eq!(table.search(b"key0000500", 1000), Some((500, Some(vec![b'v'; 20].into()))));
+            assert_eq!(table.min_key, data[0].0);
+            assert_eq!(table.max_key, data[data.len() - 1].0);
+        }
+    }
+
+    #[test]
+    fn malformed_table_is_reported() {
+        let dir = temp_dir("malformed_table");
+        let file_name = dir.join("1.sst");
+        Table::new(&test_env(), file_name.clone(), entries(&["a", "b"], 1), 0, 4096, &RateLimiter::new(0)).unwrap();
+        let len = std::fs::metadata(&file_name).unwrap().len();
+        //cut into the index block, the footer now points past the end of the trailer
+        let bytes = std::fs::read(&file_name).unwrap();
+        let footer = bytes[bytes.len() - 56..].to_vec();
+        let mut truncated = bytes[..(len as usize - 56) / 2].to_vec();
+        truncated.extend_from_slice(&footer);
+        std::fs::write(&file_name, &truncated).unwrap();
+        assert!(matches!(Table::open(&test_env(), file_name.clone()), Err(Error::Corruption(_))));
+        std::fs::write(&file_name, b"short").unwrap();
+        assert!(matches!(Table::open(&test_env(), file_name), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn table_with_least_overlap_after_pointer_is_picked() {
+        let config = Config::new();
+        let mut levels = Levels::new(temp_dir("pick_table"), Vec::new(), &config).unwrap();
+        let tables = vec![
+            levels.write_file(entries(&["a", "b"], 1), 1).unwrap(),
+            levels.write_file(entries(&["c", "d"], 1), 1).unwrap(),
+            levels.write_file(entries(&["e", "f"], 1), 1).unwrap(),
+            levels.write_file(entries(&["c", "cc", "d"], 1), 2).unwrap(),
+        ];
+        let max_keys = tables.iter().take(3).map(|t| t.max_key.clone()).collect::<Vec<_>>();
+        levels.update(Vec::new(), tables).unwrap();
+
+        //no pointer yet: "a-b" overlaps nothing below and comes first
+        assert_eq!(levels.pick_table(1), 0);
+        //after "a-b", "c-d" would drag in the level 2 table, so "e-f" is preferred
+        levels.compact_pointers.lock()[1] = Some(max_keys[0].clone());
+        assert_eq!(levels.pick_table(1), 2);
+        //after "e-f" the pointer wraps around
+        levels.compact_pointers.lock()[1] = Some(max_keys[2].clone());
+        assert_eq!(levels.pick_table(1), 0);
+    }
+
+    //the level 0 table overlaps all the reference tables, so compacting it rewrites everything
+    fn overlapping_levels(name: &str, config: &mut Config) -> Levels {
+        config.block_size = 256;
+        config.l0_compaction_threshold = 0;
+        let mut levels = Levels::new(temp_dir(name), Vec::new(), config).unwrap();
+        let mut tables = reference_tables(&levels);
+        tables.push(levels.write_file(entries(&["key0000", "key0150", "key0299"], 4), 0).unwrap());
+        levels.update(Vec::new(), tables).unwrap();
+        levels
+    }
+
+    #[test]
+    fn subcompactions_match_serial_compaction() {
+        let compacted = |name: &str, max_subcompactions: usize| {
+            let mut config = Config::new();
+            config.max_subcompactions = max_subcompactions;
+            let mut levels = overlapping_levels(name, &mut config);
+            compact(&mut levels);
+            assert_eq!(levels.num_files_at_level(0), 0);
+            levels
+        };
+        let serial = compacted("serial_compaction", 1);
+        let parallel = compacted("parallel_compaction", 4);
+        assert_eq!(serial.num_files_at_level(1), 1);
+        assert_eq!(parallel.num_files_at_level(1), 4);
+
+        //outputs are sorted and disjoint by user key
+        let outputs = parallel.inner[1].iter().collect::<Vec<_>>();
+        for pair in outputs.windows(2) {
+            assert!(pair[0].max_key.get_user_key() < pair[1].min_key.get_user_key());
+        }
+        let expected = serial.inner[1].iter().flat_map(|t| t.content().unwrap()).collect::<Vec<_>>();
+        let actual = outputs.iter().flat_map(|t| t.content().unwrap()).collect::<Vec<_>>();
+        assert_eq!(actual.len(), expected.len());
+        for ((k, v), (ek, ev)) in actual.iter().zip(expected.iter()) {
+            assert_eq!((k.get_user_key(), k.get_seq_num(), k.get_type()), (ek.get_user_key(), ek.get_seq_num(), ek.get_type()));
+            assert_eq!(v, ev);
+        }
+    }
+
+    #[test]
+    fn rate_limiter_throttles_compaction() {
+        use std::time::Instant;
+
+        let mut levels = overlapping_levels("rate_limited", &mut Config::new());
+        let input_bytes = levels.inner.iter().flatten().map(|t| t.get_size()).sum::<u64>();
+        //reading the inputs alone should take about a second, writing the output about as long again
+        levels.rate_limiter.set_bytes_per_sec(input_bytes);
+        let now = Instant::now();
+        compact(&mut levels);
+        assert!(now.elapsed().as_secs_f64() > 0.8, "took {:?}", now.elapsed());
+
+        let mut levels = overlapping_levels("rate_unlimited", &mut Config::new());
+        let rate_limiter = levels.rate_limiter();
+        rate_limiter.set_bytes_per_sec(input_bytes);
+        rate_limiter.set_bytes_per_sec(0);
+        let now = Instant::now();
+        compact(&mut levels);
+        assert!(now.elapsed().as_secs_f64() < 0.5, "took {:?}", now.elapsed());
+    }
+
+    #[test]
+    fn disjoint_tables_are_moved_without_rewriting() {
+        let mut config = Config::new();
+        config.l0_compaction_threshold = 0;
+        let dir = temp_dir("trivial_move");
+        let mut levels = Levels::new(dir.clone(), Vec::new(), &config).unwrap();
+        let tables = vec![
+            levels.write_file(entries(&["a", "b"], 1), 0).unwrap(),
+            levels.write_file(entries(&["c", "d"], 2), 0).unwrap(),
+        ];
+        let file_names = tables.iter().map(|t| t.file_name.clone()).collect::<Vec<_>>();
+        levels.update(Vec::new(), tables).unwrap();
+        compact(&mut levels);
+        compact(&mut levels);
+        assert_eq!(levels.num_files_at_level(0), 0);
+        assert_eq!(levels.num_files_at_level(1), 2);
+        let stats = levels.compaction_stats();
+        assert_eq!(stats.moved_files, 2);
+        assert_eq!(stats.bytes_written, 0);
+        assert!(file_names.iter().all(|f| f.exists()));
+        assert_eq!(levels.search(b"c", 2), Some(b"c".to_vec()));
+
+        //the footers still say level 0, the manifest wins on restart
+        drop(levels);
+        let levels = Levels::new(dir.clone(), file_names, &config).unwrap();
+        assert_eq!(levels.num_files_at_level(0), 0);
+        assert_eq!(levels.num_files_at_level(1), 2);
+        for k in ["a", "b", "c", "d"].iter() {
+            assert_eq!(levels.search(k.as_bytes(), 2), Some(k.as_bytes().to_vec()));
+        }
+    }
+
+    //flush `batches` interleaved batches of fresh keys, compacting until nothing is picked after each one
+    fn insert_workload(levels: &mut Levels, batches: u64) {
+        for batch in 0..batches {
+            let data = (0..50)
+                .map(|i| {
+                    let key = format!("key{:05}", i * batches + batch);
+                    (LookUpKey::new(InternalKey::new(key.as_bytes(), batch + 1, ValueType::Put)), vec![b'v'; 20])
+                })
+                .collect::<Vec<_>>();
+            let table = levels.write_file(data.into_iter(), 0).unwrap();
+            levels.update(Vec::new(), vec![table]).unwrap();
+            loop {
+                let (deleted_tables, new_tables) = levels.background_compaction(None).unwrap();
+                if deleted_tables.is_empty() && new_tables.is_empty() {
+                    break;
+                }
+                levels.update(deleted_tables, new_tables).unwrap();
+            }
+        }
+        for i in 0..50 * batches {
+            let key = format!("key{:05}", i);
+            assert_eq!(levels.search(key.as_bytes(), batches), Some(vec![b'v'; 20]));
+        }
+    }
+
+    #[test]
+    fn universal_compaction_rewrites_less_than_leveled() {
+        let mut config = Config::new();
+        config.block_size = 256;
+        config.l0_compaction_threshold = 4;
+        config.l1_max_bytes = 4 * 1024;
+        let mut leveled = Levels::new(temp_dir("leveled_workload"), Vec::new(), &config).unwrap();
+        insert_workload(&mut leveled, 30);
+        config.compaction_style = CompactionStyle::Universal;
+        let mut universal = Levels::new(temp_dir("universal_workload"), Vec::new(), &config).unwrap();
+        insert_workload(&mut universal, 30);
+
+        assert!(universal.inner.iter().skip(1).all(|level| level.is_empty()));
+        assert!(universal.num_files_at_level(0) <= config.l0_compaction_threshold);
+        let leveled_written = leveled.compaction_stats().bytes_written;
+        let universal_written = universal.compaction_stats().bytes_written;
+        assert!(universal_written < leveled_written, "universal {} leveled {}", universal_written, leveled_written);
+    }
+
+    #[test]
+    fn reopening_with_another_compaction_style_fails() {
+        let dir = temp_dir("compaction_style");
+        let mut config = Config::new();
+        config.compaction_style = CompactionStyle::Universal;
+        let levels = Levels::new(dir.clone(), Vec::new(), &config).unwrap();
+        drop(levels);
+        config.compaction_style = CompactionStyle::Leveled;
+        assert!(matches!(Levels::new(dir.clone(), Vec::new(), &config), Err(Error::InvalidArgument(_))));
+        config.compaction_style = CompactionStyle::Universal;
+        assert!(Levels::new(dir, Vec::new(), &config).is_ok());
+    }
+
+    #[test]
+    fn compacting_bottom_level_rewrites_it_in_place() {
+        let mut config = Config::new();
+        config.max_levels = 3;
+        let mut levels = Levels::new(temp_dir("compact_bottom"), Vec::new(), &config).unwrap();
+        let data = vec![
+            (LookUpKey::new(InternalKey::new(b"a", 3, ValueType::Put)), b"a3".to_vec()),
+            (LookUpKey::new(InternalKey::new(b"a", 1, ValueType::Put)), b"a1".to_vec()),
+            (LookUpKey::new(InternalKey::new(b"b", 4, ValueType::Delete)), Vec::new()),
+            (LookUpKey::new(InternalKey::new(b"b", 2, ValueType::Put)), b"b2".to_vec()),
+            (LookUpKey::new(InternalKey::new(b"c", 5, ValueType::Put)), b"c5".to_vec()),
+        ];
+        let table = levels.write_file(data.into_iter(), 2).unwrap();
+        levels.update(Vec::new(), vec![table]).unwrap();
+
+        let (summary, (deleted_tables, new_tables)) = levels.compact_level(2).unwrap();
+        assert_eq!(summary.input_files, 1);
+        assert_eq!(summary.output_files, 1);
+        levels.update(deleted_tables, new_tables).unwrap();
+        let content = levels.inner[2].iter().next().unwrap().content().unwrap();
+        let content = content.iter()
+            .map(|(k, v)| (k.get_user_key().to_vec(), k.get_seq_num(), v.to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(content, vec![(b"a".to_vec(), 3, b"a3".to_vec()), (b"c".to_vec(), 5, b"c5".to_vec())]);
+    }
+
+    #[test]
+    fn obsolete_table_is_deleted_after_its_last_reader() {
+        let mut config = Config::new();
+        config.max_levels = 3;
+        config.block_size = 64;
+        let mut levels = Levels::new(temp_dir("obsolete_table"), Vec::new(), &config).unwrap();
+        let keys = (0..100).map(|i| format!("key{:03}", i)).collect::<Vec<_>>();
+        let keys = keys.iter().map(|k| k.as_str()).collect::<Vec<_>>();
+        let table = levels.write_file(entries(&keys, 1), 2).unwrap();
+        levels.update(Vec::new(), vec![table]).unwrap();
+
+        //a reader that has only fetched the first block when the table is compacted away
+        let reader = levels.candidates(b"key000").pop().unwrap();
+        let file_name = reader.file_name.clone();
+        let mut iter = reader.owned_iter();
+        let mut read = vec![iter.next().unwrap()];
+        let (_, (deleted_tables, new_tables)) = levels.compact_level(2).unwrap();
+        levels.update(deleted_tables, new_tables).unwrap();
+        assert!(levels.inner[2].iter().all(|t| t.file_name != file_name));
+        assert!(file_name.exists());
+
+        read.extend(iter.by_ref());
+        assert_eq!(read.len(), keys.len());
+        assert!(file_name.exists());
+        drop(iter);
+        assert!(!file_name.exists());
+        assert_eq!(levels.search(b"key042", 1), Some(b"key042".to_vec()));
+    }
+
+    #[test]
+    fn created_and_removed_tables_sync_the_directory() {
+        let dir = temp_dir("dir_sync");
+        let mut config = Config::new();
+        config.max_levels = 2;
+        let mut levels = Levels::new(dir.clone(), Vec::new(), &config).unwrap();
+        take_dir_syncs();
+        let table = levels.write_file(entries(&["a", "b"], 1), 1).unwrap();
+        assert_eq!(take_dir_syncs(), vec![dir.clone()]);
+        //the manifest is replaced by a rename
+        levels.update(Vec::new(), vec![table]).unwrap();
+        assert_eq!(take_dir_syncs(), vec![dir.clone()]);
+        //the bottom level is rewritten in place: the new table, the manifest and the removal of the old table
+        let (_, (deleted_tables, new_tables)) = levels.compact_level(1).unwrap();
+        levels.update(deleted_tables, new_tables).unwrap();
+        assert_eq!(take_dir_syncs(), vec![dir; 3]);
+    }
+
+    fn sst_files(dir: &Path) -> Vec<PathBuf> {
+        std::fs::read_dir(dir).unwrap()
+            .map(|x| x.unwrap().path())
+            .filter(|x| x.extension() == Some(std::ffi::OsStr::new("sst")))
+            .collect()
+    }
+
+    #[test]
+    fn verify_reports_the_damaged_table() {
+        let dir = temp_dir("verify");
+        let mut levels = Levels::new(dir, Vec::new(), &Config::new()).unwrap();
+        let l1 = levels.write_file(entries(&["a", "b", "c"], 1), 1).unwrap();
+        let path = l1.file_name.clone();
+        levels.update(Vec::new(), vec![l1, levels.write_file(entries(&["x"], 2), 0).unwrap()]).unwrap();
+        assert_eq!(levels.verify().unwrap(), 2);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[1] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        match levels.verify() {
+            Err(Error::Corruption(msg)) => assert!(msg.contains("checksum mismatch") && msg.contains(path.to_str().unwrap()), "{}", msg),
+            res => panic!("expected corruption, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn data_block_that_does_not_decode_is_an_error() {
+        let dir = temp_dir("undecodable_block");
+        let mut levels = Levels::new(dir.clone(), Vec::new(), &Config::new()).unwrap();
+        let table = levels.write_file(entries(&["a", "b", "c"], 1), 1).unwrap();
+        let path = table.file_name.clone();
+        levels.update(Vec::new(), vec![table]).unwrap();
+        drop(levels);
+        //the length of the first key runs past the end of the block, the checksums are not verified
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[..5].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x0f]);
+        std::fs::write(&path, &bytes).unwrap();
+        let levels = Levels::new(dir.clone(), sst_files(&dir), &Config::new()).unwrap();
+        let table = levels.level_tables(1).next().unwrap();
+        assert!(matches!(table.search_with(b"b", 10, &BlockReads::default()), Err(Error::Corruption(_))));
+        assert!(matches!(table.content(), Err(Error::Corruption(_))));
+        //a compaction fails instead of writing out the entries it could read
+        assert!(matches!(levels.compact_level(1), Err(Error::Corruption(_))));
+        assert_eq!(sst_files(&dir), vec![path]);
+    }
+
+    #[test]
+    fn paranoid_checks_catch_corruption() {
+        let dir = temp_dir("paranoid");
+        let mut config = Config::new();
+        config.paranoid_checks = true;
+        let mut levels = Levels::new(dir.clone(), Vec::new(), &config).unwrap();
+        let data = vec![(LookUpKey::new(InternalKey::new(b"k", 1, ValueType::Put)), b"original".to_vec())];
+        let table = levels.write_file(data.into_iter(), 1).unwrap();
+        let path = table.file_name.clone();
+        levels.update(Vec::new(), vec![table]).unwrap();
+        assert_eq!(levels.search(b"k", 10), Some(b"original".to_vec()));
+
+        //a bit flip that still decodes, only the checksum tells
+        let mut bytes = std::fs::read(&path).unwrap();
+        let pos = bytes.windows(8).position(|w| w == b"original").unwrap();
+        bytes[pos] = b'O';
+        std::fs::write(&path, &bytes).unwrap();
+        config.paranoid_checks = false;
+        let trusting = Levels::new(dir.clone(), sst_files(&dir), &config).unwrap();
+        assert_eq!(trusting.search(b"k", 10), Some(b"Original".to_vec()));
+        drop(trusting);
+        config.paranoid_checks = true;
+        let paranoid = Levels::new(dir.clone(), sst_files(&dir), &config).unwrap();
+        match Levels::search_candidates_with(&paranoid.candidates(b"k"), b"k", 10, &BlockReads::default()) {
+            Err(Error::Corruption(msg)) => assert!(msg.contains("checksum mismatch"), "{}", msg),
+            res => panic!("expected corruption, got {:?}", res),
+        }
+        let table = paranoid.level_tables(1).next().unwrap();
+        assert!(matches!(table.content(), Err(Error::Corruption(_))));
+        let mut cursor = LevelCursor::new(paranoid.candidates(b"k"), BlockReads::default(), false);
+        cursor.seek_to_first();
+        assert!(!cursor.valid());
+        assert!(matches!(cursor.status(), Err(Error::Corruption(_))));
+
+        //a new table overlapping one of its level is refused before anything changes
+        let overlapping = levels.write_file(entries(&["a", "z"], 2), 1).unwrap();
+        let overlapping_file = overlapping.file_name.clone();
+        match levels.update(Vec::new(), vec![overlapping]) {
+            Err(Error::Corruption(msg)) => assert!(msg.contains("overlap"), "{}", msg),
+            res => panic!("expected corruption, got {:?}", res),
+        }
+        assert_eq!(levels.num_files_at_level(1), 1);
+        //replacing the table it overlaps is fine
+        let replacement = levels.write_file(entries(&["a", "z"], 3), 1).unwrap();
+        levels.update(vec![(1, path)], vec![replacement]).unwrap();
+        assert!(levels.level_tables(1).all(|t| t.file_name != overlapping_file));
+    }
+
+    #[test]
+    fn uninstalled_flush_output_is_removed_on_reopen() {
+        let dir = temp_dir("crashed_flush");
+        let config = Config::new();
+        let levels = Levels::new(dir.clone(), Vec::new(), &config).unwrap();
+        let mut mem_table = MemTable::new();
+        mem_table.insert_inner(Bytes::from_static(b"a"), Bytes::from_static(b"a"), 1, false);
+        //crash after the table is written but before it is installed, the log still holds the data
+        let (_, new_tables) = levels.background_compaction(Some(&mem_table)).unwrap();
+        assert_eq!(sst_files(&dir).len(), 1);
+        drop(new_tables);
+        drop(levels);
+
+        let levels = Levels::new(dir.clone(), sst_files(&dir), &config).unwrap();
+        assert_eq!(levels.num_files_at_level(0), 0);
+        assert!(sst_files(&dir).is_empty());
+    }
+
+    #[test]
+    fn empty_and_tombstone_only_flushes() {
+        let dir = temp_dir("empty_flush");
+        let mut levels = Levels::new(dir.clone(), Vec::new(), &Config::new()).unwrap();
+        let table = levels.write_file(entries(&["a", "b", "c"], 1), 1).unwrap();
+        levels.update(Vec::new(), vec![table]).unwrap();
+        let (_, new_tables) = levels.background_compaction(Some(&MemTable::new())).unwrap();
+        assert!(new_tables.is_empty());
+        assert_eq!(sst_files(&dir).len(), 1);
+        let empty = Table::new(&test_env(), dir.join("100.sst"), entries(&[], 1), 0, 4096, &RateLimiter::new(0));
+        assert!(matches!(empty, Err(Error::InvalidArgument(_))));
+        assert!(!dir.join("100.sst.tmp").exists());
+
+        //the tombstones shadow the older table, so they are written out as well
+        let mut mem_table = MemTable::new();
+        mem_table.delete_inner(Bytes::from_static(b"a"), 2, false);
+        mem_table.delete_inner(Bytes::from_static(b"c"), 3, false);
+        let (_, new_tables) = levels.background_compaction(Some(&mem_table)).unwrap();
+        assert_eq!(new_tables.len(), 1);
+        assert_eq!(new_tables[0].min_key.get_user_key(), b"a");
+        assert_eq!(new_tables[0].max_key.get_user_key(), b"c");
+        levels.update(Vec::new(), new_tables).unwrap();
+        assert_eq!(levels.search(b"a", 10), None);
+        assert_eq!(levels.search(b"b", 10), Some(b"b".to_vec()));
+        assert_eq!(levels.search(b"c", 10), None);
+    }
+
+    #[test]
+    fn uninstalled_compaction_output_is_removed_on_reopen() {
+        let dir = temp_dir("crashed_compaction");
+        let mut config = Config::new();
+        config.l0_compaction_threshold = 0;
+        let mut levels = Levels::new(dir.clone(), Vec::new(), &config).unwrap();
+        let old = levels.write_file(entries(&["a", "b"], 1), 0).unwrap();
+        let new = levels.write_file(
+            vec![(LookUpKey::new(InternalKey::new(b"b", 2, ValueType::Put)), b"b2".to_vec())].into_iter(),
+            0,
+        ).unwrap();
+        levels.update(Vec::new(), vec![old, new]).unwrap();
+        //crash before the merged table replaces its inputs
+        let (_, new_tables) = levels.background_compaction(None).unwrap();
+        assert_eq!(new_tables.len(), 1);
+        assert_eq!(sst_files(&dir).len(), 3);
+        drop(new_tables);
+        drop(levels);
+
+        let levels = Levels::new(dir.clone(), sst_files(&dir), &config).unwrap();
+        assert_eq!(sst_files(&dir).len(), 2);
+        assert_eq!(levels.num_files_at_level(0), 2);
+        assert_eq!(levels.num_files_at_level(1), 0);
+        assert_eq!(levels.search(b"a", 10), Some(b"a".to_vec()));
+        assert_eq!(levels.search(b"b", 10), Some(b"b2".to_vec()));
+        assert_eq!(levels.search(b"b", 1), Some(b"b".to_vec()));
+    }
+
+    fn versions(versions: &[(&str, u64, u8)]) -> std::vec::IntoIter<(LookUpKey, Vec<u8>)> {
+        versions.iter()
+            .map(|(k, seq_num, op_type)| {
+                let value = format!("{}-{}-{}", k, seq_num, op_type).into_bytes();
+                (LookUpKey::new(InternalKey::new(k.as_bytes(), *seq_num, ValueType::try_from(*op_type).unwrap())), value)
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn merged(iters: Vec<std::vec::IntoIter<(LookUpKey, Vec<u8>)>>) -> Vec<(String, u64, u8)> {
+        merge_newest(iters)
+            .map(|(k, _)| (String::from_utf8(k.get_user_key().to_vec()).unwrap(), k.get_seq_num(), k.get_type() as u8))
+            .collect()
+    }
+
+    #[test]
+    fn merge_keeps_max_seq_across_inputs() {
+        let res = merged(vec![
+            versions(&[("a", 2, 0), ("k", 1, 0)]),
+            versions(&[("k", 3, 1), ("z", 1, 0)]),
+            versions(&[("k", 2, 0)]),
+        ]);
+        assert_eq!(res, vec![("a".to_owned(), 2, 0), ("k".to_owned(), 3, 1), ("z".to_owned(), 1, 0)]);
+    }
+
+    #[test]
+    fn merge_resolves_equal_seq_by_input_order() {
+        //a transactional delete and a plain put of the same key with the same sequence number
+        let put = || versions(&[("k", 5, 0)]);
+        let tx_delete = || versions(&[("k", 5, 3)]);
+        assert_eq!(merged(vec![put(), tx_delete()]), vec![("k".to_owned(), 5, 0)]);
+        assert_eq!(merged(vec![tx_delete(), put()]), vec![("k".to_owned(), 5, 3)]);
+        assert_eq!(merged(vec![versions(&[("k", 4, 0)]), tx_delete(), put()]), vec![("k".to_owned(), 5, 3)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "merge input is not sorted")]
+    fn merge_rejects_unsorted_input() {
+        merged(vec![versions(&[("b", 1, 0), ("a", 1, 0)])]);
+    }
+
+    #[test]
+    fn level0_compaction_takes_all_overlapping_tables() {
+        let mut config = Config::new();
+        config.l0_compaction_threshold = 0;
+        let mut levels = Levels::new(temp_dir("l0_overlapping"), Vec::new(), &config).unwrap();
+        //successive versions of "k", chained through overlapping ranges, plus an unrelated table
+        let mut tables = vec![
+            levels.write_file(entries(&["a", "k"], 1), 0).unwrap(),
+            levels.write_file(entries(&["k", "m"], 2), 0).unwrap(),
+            levels.write_file(entries(&["l", "n"], 3), 0).unwrap(),
+            levels.write_file(entries(&["x", "z"], 4), 0).unwrap(),
+        ];
+        let newest_k = (LookUpKey::new(InternalKey::new(b"k", 5, ValueType::Put)), b"k-5".to_vec());
+        tables.push(levels.write_file(vec![newest_k].into_iter(), 0).unwrap());
+        levels.update(Vec::new(), tables).unwrap();
+
+        compact(&mut levels);
+        //only "x-z" shares no key with the oldest table
+        assert_eq!(levels.num_files_at_level(0), 1);
+        assert_eq!(levels.num_files_at_level(1), 1);
+        let content = levels.inner[1].iter().next().unwrap().content().unwrap();
+        let k_versions = content.iter()
+            .filter(|(k, _)| k.get_user_key() == b"k")
+            .collect::<Vec<_>>();
+        assert_eq!(k_versions.len(), 1);
+        assert_eq!(k_versions[0].1, b"k-5".to_vec());
+        assert_eq!(levels.search(b"k", 10), Some(b"k-5".to_vec()));
+        assert_eq!(levels.search(b"n", 10), Some(b"n".to_vec()));
+    }
+
+    #[test]
+    fn bottom_level_reclaims_tombstones_of_moved_tables() {
+        let mut config = Config::new();
+        config.max_levels = 3;
+        config.l1_max_bytes = 64;
+        let mut levels = Levels::new(temp_dir("bottom_compaction"), Vec::new(), &config).unwrap();
+        //a level 1 table holding both the values and the tombstones deleting most of them
+        let data = (0..100)
+            .flat_map(|i| {
+                let key = format!("key{:03}", i);
+                let mut versions = Vec::new();
+                if i % 10 != 0 {
+                    versions.push((LookUpKey::new(InternalKey::new(key.as_bytes(), 2, ValueType::Delete)), Vec::new()));
+                }
+                versions.push((LookUpKey::new(InternalKey::new(key.as_bytes(), 1, ValueType::Put)), vec![b'v'; 100]));
+                versions
+            })
+            .collect::<Vec<_>>();
+        let table = levels.write_file(data.into_iter(), 1).unwrap();
+        levels.update(Vec::new(), vec![table]).unwrap();
+
+        //nothing below, so the table is moved to the bottom untouched
+        compact(&mut levels);
+        assert_eq!(levels.compaction_stats().moved_files, 1);
+        let moved_size = levels.level_stats()[2].size_bytes;
+        assert!(levels.inner[2].iter().next().unwrap().is_moved());
+
+        //the bottom is over budget and holds a moved table, so it is rewritten in place
+        assert_eq!(levels.pick_compaction(), Some((2, 0)));
+        compact(&mut levels);
+        assert_eq!(levels.num_files_at_level(2), 1);
+        let table = levels.inner[2].iter().next().unwrap();
+        assert!(!table.is_moved());
+        assert!(table.get_size() * 5 < moved_size);
+        assert_eq!(table.content().unwrap().len(), 10);
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            let expected = if i % 10 == 0 { Some(vec![b'v'; 100]) } else { None };
+            assert_eq!(levels.search(key.as_bytes(), 10), expected);
+        }
+        //nothing left to reclaim, even though the level is still over budget
+        assert_eq!(levels.pick_compaction(), None);
+    }
+
+    fn numbered(prefix: &str, range: std::ops::Range<u64>, seq_num: u64) -> Vec<(LookUpKey, Vec<u8>)> {
+        range.map(|i| (LookUpKey::new(InternalKey::new(format!("{}{:06}", prefix, i).as_bytes(), seq_num, ValueType::Put)), vec![b'v'; 32]))
+            .collect()
+    }
+
+    #[test]
+    fn table_lookup_does_not_copy_the_entries_it_skips() {
+        let mut config = Config::new();
+        config.block_size = 64 * 1024;
+        let levels = Levels::new(temp_dir("lookup_allocs"), Vec::new(), &config).unwrap();
+        let table = levels.write_file(numbered("key", 0..1000, 1).into_iter(), 1).unwrap();
+        assert_eq!(table.index_block.len(), 1);
+        //the last key makes the lookup decode all 1000 entries of the block
+        let (res, allocs) = count_allocations(|| {
+            (0..100).map(|_| table.search(b"key000999", 10)).last().unwrap()
+        });
+        assert_eq!(res, Some((1, Some(vec![b'v'; 32].into()))));
+        assert!(allocs <= 100 * 5, "{} allocations for 100 lookups", allocs);
+    }
+
+    #[test]
+    fn compaction_shares_keys_and_values_with_the_input_blocks() {
+        let config = Config::new();
+        let levels = Levels::new(temp_dir("compaction_allocs"), Vec::new(), &config).unwrap();
+        let newer = levels.write_file(numbered("key", 0..5000, 2).into_iter(), 1).unwrap();
+        let older = levels.write_file(numbered("key", 2500..7500, 1).into_iter(), 2).unwrap();
+        let (outputs, allocs) = count_allocations(|| levels.merge_into(&[&newer, &older], 2).unwrap());
+        assert_eq!(outputs.iter().map(|t| t.content().unwrap().len()).sum::<usize>(), 7500);
+        //the input entries are 10000, blocks hold about 80 of them
+        assert!(allocs < 1000, "{} allocations to merge 10000 entries", allocs);
+    }
+
+    #[test]
+    fn compaction_collapses_history_before_the_horizon() {
+        let ts = |t: u64| t.to_be_bytes();
+        let mut config = Config::new();
+        config.user_timestamp_size = 8;
+        config.user_timestamp_horizon = Some(ts(20).to_vec());
+        let mut levels = Levels::new(temp_dir("timestamp_horizon"), Vec::new(), &config).unwrap();
+        let version = |key: &str, t: u64, op_type: u8| {
+            let key = crate::key::key_with_timestamp(key.as_bytes(), &ts(t));
+            (LookUpKey::new(InternalKey::new(&key, t, ValueType::try_from(op_type).unwrap())), format!("{}", t).into_bytes())
+        };
+        let mut data = vec![
+            version("k", 30, 0), version("k", 20, 0), version("k", 10, 0),
+            version("t", 15, 1), version("t", 5, 0),
+            version("u", 25, 1), version("u", 22, 0),
+        ];
+        data.sort_by(|a, b| a.0.cmp(&b.0));
+        let table = levels.write_file(data.into_iter(), 1).unwrap();
+        levels.update(Vec::new(), vec![table]).unwrap();
+        let (_, (deleted_tables, new_tables)) = levels.compact_level(1).unwrap();
+        levels.update(deleted_tables, new_tables).unwrap();
+
+        let content = levels.inner[1].iter().next().unwrap().content().unwrap().into_iter()
+            .map(|(k, _)| {
+                let key = String::from_utf8(strip_timestamp(k.get_user_key(), 8)).unwrap();
+                (key, split_timestamp(k.get_user_key(), 8).1, k.get_type() as u8)
+            })
+            .collect::<Vec<_>>();
+        //k keeps its newest version at the horizon, t is gone entirely, u is after the horizon
+        assert_eq!(content, vec![
+            ("k".to_owned(), ts(30).to_vec(), 0), ("k".to_owned(), ts(20).to_vec(), 0),
+            ("u".to_owned(), ts(25).to_vec(), 1), ("u".to_owned(), ts(22).to_vec(), 0),
+        ]);
+    }
+
+    #[test]
+    fn random_blocks_never_panic_the_decoders() {
+        let mut rng = Rng(0x5eed_1234_abcd_0003);
+        for _ in 0..10000 {
+            let block = Bytes::from(rng.bytes(60));
+            for format_version in [LEGACY_FORMAT, VARINT_FORMAT].iter() {
+                let mut offset = 0;
+                while (offset as usize) < block.len() && DataBlockEntry::decode_from(&block, &mut offset, *format_version).is_ok() {}
+                let mut offset = 0;
+                while (offset as usize) < block.len() && IndexBlockEntry::decode_from(&block, &mut offset, *format_version).is_ok() {}
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_value_types_are_reported() {
+        let key = LookUpKey::new(InternalKey::new(b"key", 7, ValueType::Put));
+        let mut block = Vec::new();
+        DataBlockEntry::encode_entry(&mut block, &key, b"value", CURRENT_FORMAT);
+        //the type is the low byte of the tail after the user key
+        block[1 + 3] = 5;
+        let block = Bytes::from(block);
+        assert!(matches!(DataBlockEntry::decode_from(&block, &mut 0, CURRENT_FORMAT), Err(Error::Corruption(_))));
+
+        //the min and max keys are decoded when the table is opened
+        let dir = temp_dir("invalid_value_type");
+        let file_name = dir.join("1.sst");
+        let table = Table::new(&test_env(), file_name.clone(), entries(&["k"], 1), 0, 4096, &RateLimiter::new(0)).unwrap();
+        let mut bytes = std::fs::read(&file_name).unwrap();
+        bytes[table.footer.min_key_addr as usize + 1 + 1] = 0xff;
+        drop(table);
+        std::fs::write(&file_name, &bytes).unwrap();
+        match Table::open(&test_env(), file_name) {
+            Err(Error::Corruption(msg)) => assert!(msg.contains("invalid value type"), "{}", msg),
+            res => panic!("expected corruption, got {:?}", res.map(|t| t.file_num)),
+        }
+    }
+
+    #[test]
+    fn a_value_changed_on_its_way_into_a_table_fails_its_checksum() {
+        let dir = temp_dir("value_checksum_table");
+        let checked = |key: &str, value: &[u8]| {
+            (LookUpKey::new(InternalKey::new(key.as_bytes(), 1, ValueType::PutChecked)), checked_value(key.as_bytes(), value))
+        };
+        let mut damaged = checked("b", b"b-value");
+        damaged.1[2] ^= 1;
+        let data = vec![checked("a", b"a-value"), damaged, checked("c", b"c-value")];
+        let file_name = dir.join("1.sst");
+        drop(Table::new(&test_env(), file_name.clone(), data.into_iter(), 0, 4096, &RateLimiter::new(0)).unwrap());
+        let plain = Table::new(&test_env(), dir.join("2.sst"), entries(&["a"], 1), 0, 4096, &RateLimiter::new(0)).unwrap();
+        assert_eq!(plain.footer.format_version, CURRENT_FORMAT);
+
+        //the block checksums match, the damage was done before the block was written
+        let mut table = Table::open(&test_env(), file_name).unwrap();
+        table.paranoid_checks = true;
+        assert_eq!(table.footer.format_version, VALUE_CHECKSUM_FORMAT);
+        assert!(table.verify().is_ok());
+        assert_eq!(table.search(b"a", 1), Some((1, Some(Bytes::from_static(b"a-value")))));
+        match table.search_with(b"b", 1, &BlockReads::default()) {
+            Err(Error::Corruption(msg)) => assert!(msg.contains("\"b\""), "{}", msg),
+            res => panic!("expected corruption, got {:?}", res),
+        }
+        let trusting = BlockReads { verify_checksums: Some(false), ..BlockReads::default() };
+        assert_eq!(table.search_with(b"b", 1, &trusting).unwrap(), Some((1, Some(Bytes::from_static(b"b-walue")))));
+        let found = table.multi_search_with(&[b"a", b"c"], 1, &BlockReads::default()).unwrap();
+        assert_eq!(found, vec![Some((1, Some(Bytes::from_static(b"a-value")))), Some((1, Some(Bytes::from_static(b"c-value"))))]);
+        assert!(table.multi_search_with(&[b"a", b"b"], 1, &BlockReads::default()).is_err());
+
+        //a table of an older format can not hold checked puts
+        let key = LookUpKey::new(InternalKey::new(b"a", 1, ValueType::PutChecked));
+        let mut block = Vec::new();
+        DataBlockEntry::encode_entry(&mut block, &key, &checked_value(b"a", b"v"), PROPERTIES_FORMAT);
+        let block = Bytes::from(block);
+        assert!(matches!(DataBlockEntry::decode_from(&block, &mut 0, PROPERTIES_FORMAT), Err(Error::Corruption(_))));
+        assert!(DataBlockEntry::decode_from(&block, &mut 0, VALUE_CHECKSUM_FORMAT).is_ok());
+    }
+
+    #[test]
+    fn sequential_reads_read_ahead() {
+        let mem_env = crate::env::MemEnv::new();
+        mem_env.create_dir_all(Path::new("/mem")).unwrap();
+        let env: Arc<dyn Env> = Arc::new(mem_env.clone());
+        let data = (0..2000)
+            .map(|i| (LookUpKey::new(InternalKey::new(format!("key{:05}", i).as_bytes(), 1, ValueType::Put)), vec![b'v'; 100]))
+            .collect::<Vec<_>>();
+        let mut table = Table::new(&env, PathBuf::from("/mem/1.sst"), data.into_iter(), 0, 1024, &RateLimiter::new(0)).unwrap();
+        mem_env.take_read_count();
+        let expected = table.content().unwrap();
+        assert_eq!(mem_env.take_read_count(), table.index_block.len() as u64);
+
+        //the first block alone, then the rest in reads of up to 16KB
+        table.readahead_size = 16 * 1024;
+        assert_eq!(table.content().unwrap(), expected);
+        let data_len = table.footer.meta_index_block_addr;
+        let reads = mem_env.take_read_count();
+        assert!(reads > data_len / (16 * 1024) && reads <= 2 + data_len / (16 * 1024), "{} reads", reads);
+
+        //a seek within what was read ahead reads nothing, one far away drops it
+        let first_of_block = |block_idx: usize| expected.iter().find(|(k, _)| *k > table.index_block[block_idx - 1].max_key).cloned();
+        let mut iter = table.iter();
+        iter.by_ref().take(50).count();
+        mem_env.take_read_count();
+        let next_block = iter.block_idx + 1;
+        iter.seek_to_block(next_block);
+        assert_eq!(iter.next().transpose().unwrap(), first_of_block(next_block));
+        assert_eq!(mem_env.take_read_count(), 0);
+        let last_block = table.index_block.len() - 1;
+        iter.seek_to_block(last_block);
+        assert!(iter.readahead.1.is_empty());
+        assert_eq!(iter.next().transpose().unwrap(), first_of_block(last_block));
+        assert_eq!(mem_env.take_read_count(), 1);
+    }
+
+    #[test]
+    fn compaction_advises_sequential_reads_and_drops_what_it_read() {
+        let mem_env = crate::env::MemEnv::new();
+        let mut config = Config::new();
+        config.env = Arc::new(mem_env.clone());
+        config.readahead_size = 16 * 1024;
+        config.evict_compaction_output_from_page_cache = true;
+        let dir = PathBuf::from("/mem/fadvise");
+        config.env.create_dir_all(&dir).unwrap();
+        let mut levels = Levels::new(dir, Vec::new(), &config).unwrap();
+        let keys = |first: usize| (0..2000).map(|i| format!("key{:05}", 2 * i + first)).collect::<Vec<_>>();
+        let (even, odd) = (keys(0), keys(1));
+        let l0 = levels.write_file(entries(&even.iter().map(String::as_str).collect::<Vec<_>>(), 2), 0).unwrap();
+        let l1 = levels.write_file(entries(&odd.iter().map(String::as_str).collect::<Vec<_>>(), 1), 1).unwrap();
+        let inputs = [(l0.file_name.clone(), l0.footer.meta_index_block_addr), (l1.file_name.clone(), l1.footer.meta_index_block_addr)];
+        levels.update(Vec::new(), vec![l0, l1]).unwrap();
+        mem_env.take_advice();
+
+        let (_, (deleted_tables, new_tables)) = levels.compact_level(0).unwrap();
+        assert_eq!(deleted_tables.len(), 2);
+        let advice = mem_env.take_advice();
+        for (path, data_len) in inputs.iter() {
+            let calls = advice.iter().filter(|(p, ..)| p == path).map(|(_, a, offset, len)| (*a, *offset, *len)).collect::<Vec<_>>();
+            assert_eq!(calls[0], (Advice::Sequential, 0, *data_len));
+            //every range is dropped once read, in order and without gaps
+            let mut end = 0;
+            for &(advice, offset, len) in calls[1..].iter() {
+                assert_eq!((advice, offset), (Advice::DontNeed, end));
+                end += len;
+            }
+            assert_eq!(end, *data_len);
+            assert!(calls.len() < 2 + (*data_len as usize) / 4096, "{} calls for {} bytes", calls.len(), data_len);
+        }
+        assert!(!new_tables.is_empty());
+        for table in new_tables.iter() {
+            assert!(advice.contains(&(table.file_name.clone(), Advice::DontNeed, 0, 0)));
+        }
+        assert_eq!(advice.len(), new_tables.len() + advice.iter().filter(|(p, ..)| inputs.iter().any(|(i, _)| i == p)).count());
+    }
+
+    #[test]
+    fn preallocated_tables_are_trimmed_to_what_was_written() {
+        let mut config = Config::new();
+        config.env = Arc::new(crate::env::StdEnv);
+        let levels = Levels::new(temp_dir("preallocate"), Vec::new(), &config).unwrap();
+        let mut mem_table = MemTable::new();
+        for i in 0..500u64 {
+            mem_table.insert_inner(Bytes::from(format!("key{:04}", i)), Bytes::from_static(&[b'v'; 100]), i + 1, false);
+        }
+        let table = levels.write_level0_files(&mem_table).unwrap().unwrap();
+        let len = std::fs::metadata(&table.file_name).unwrap().len();
+        assert_eq!(len, table.footer.foot_addr + table.footer.encode_to().len() as u64);
+        let reopened = Table::open(&config.env, table.file_name.clone()).unwrap();
+        assert_eq!(reopened.iter().count(), 500);
+    }
+
+    #[test]
+    fn tables_are_preallocated_to_their_estimate() {
+        let mem_env = crate::env::MemEnv::new();
+        let mut config = Config::new();
+        config.env = Arc::new(mem_env.clone());
+        let dir = PathBuf::from("/mem/preallocate");
+        config.env.create_dir_all(&dir).unwrap();
+        let mut levels = Levels::new(dir, Vec::new(), &config).unwrap();
+        let mut mem_table = MemTable::new();
+        for i in 0..500u64 {
+            mem_table.insert_inner(Bytes::from(format!("key{:04}", i)), Bytes::from_static(&[b'v'; 100]), i + 1, false);
+        }
+        let table = levels.write_level0_files(&mem_table).unwrap().unwrap();
+        let allocations = mem_env.take_allocations();
+        assert_eq!(allocations, vec![(table.file_name.with_extension("sst.tmp"), mem_table.size as u64)]);
+        assert!(table.get_size() <= allocations[0].1 * 2 && allocations[0].1 <= table.get_size() * 2,
+            "{} bytes estimated for {}", allocations[0].1, table.get_size());
+
+        //no output of a compaction is estimated past the target size
+        let l1 = levels.write_file(entries(&["key0000", "key9999"], 1000), 1).unwrap();
+        assert!(mem_env.take_allocations().is_empty());
+        levels.update(Vec::new(), vec![table, l1]).unwrap();
+        let (_, (_, new_tables)) = levels.compact_level(0).unwrap();
+        let allocations = mem_env.take_allocations();
+        assert_eq!(allocations.len(), new_tables.len());
+        for (_, len) in allocations.iter() {
+            assert!(*len > 0 && *len <= levels.target_file_size(1));
+        }
+
+        config.preallocate_sst = false;
+        let dir = PathBuf::from("/mem/no_preallocate");
+        config.env.create_dir_all(&dir).unwrap();
+        let levels = Levels::new(dir, Vec::new(), &config).unwrap();
+        levels.write_level0_files(&mem_table).unwrap().unwrap();
+        assert!(mem_env.take_allocations().is_empty());
+    }
+}
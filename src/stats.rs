@@ -0,0 +1,284 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::events::TableFile;
+
+#[derive(Clone, Debug, Default)]
+pub struct LevelStats {
+    pub num_files: usize,
+    pub size_bytes: u64,
+    pub max_bytes: Option<u64>,  //budget of the level, level 0 is limited by its number of tables instead
+    pub avg_file_size: u64,
+    pub score: f64,  //the level is picked for compaction once its score exceeds 1
+    pub num_entries: u64,  //tables written before entries were counted add nothing
+    pub num_deletions: u64,
+    pub tombstone_ratio: f64,  //deletions of the counted entries
+    pub tombstone_heavy_files: usize,  //over Config::tombstone_compaction_ratio, waiting to be compacted
+    pub filter_negatives: u64,  //lookups the key filters of the live tables ruled out
+    pub filter_false_positives: u64,  //lookups they let through that found nothing
+}
+
+impl LevelStats {
+    //of the lookups for keys a table did not hold, None before there were any
+    pub fn filter_false_positive_rate(&self) -> Option<f64> {
+        match self.filter_negatives + self.filter_false_positives {
+            0 => None,
+            misses => Some(self.filter_false_positives as f64 / misses as f64),
+        }
+    }
+}
+
+//the levels one per line under a header, for LsmDb::get_property
+pub fn format_level_stats(levels: &[LevelStats]) -> String {
+    let mut out = format!("{:>5} {:>6} {:>12} {:>12} {:>6} {:>10} {:>10}\n", "level", "files", "bytes", "max_bytes", "score", "entries", "deletions");
+    for (level, stats) in levels.iter().enumerate() {
+        let max_bytes = stats.max_bytes.map_or("-".to_owned(), |b| b.to_string());
+        out += &format!("{:>5} {:>6} {:>12} {:>12} {:>6.2} {:>10} {:>10}\n",
+            level, stats.num_files, stats.size_bytes, max_bytes, stats.score, stats.num_entries, stats.num_deletions);
+    }
+    out
+}
+
+//one table per line with its key range, keys escaped. Counts a table does not have are written as ?.
+pub fn format_table_stats(tables: &[TableStats]) -> String {
+    let count = |n: Option<u64>| n.map_or("?".to_owned(), |n| n.to_string());
+    tables.iter()
+        .map(|t| format!("level {} #{} {} bytes {} entries {} deletions seq {} [{} .. {}]\n",
+            t.level, t.file_num, t.size_bytes, count(t.num_entries), count(t.num_deletions), t.last_seq_num,
+            t.min_key.escape_ascii(), t.max_key.escape_ascii()))
+        .collect()
+}
+
+//What Config::stats_dump_period logs and sends as DbEvent::StatsDump, the counts are since the dump before
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatsDump {
+    pub period: Duration,  //since the dump before, or since the database was opened
+    pub levels: Vec<(usize, u64)>,  //files and bytes of each level
+    pub mem_table_bytes: usize,  //of the mem table and the immutable one
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub reads: u64,  //gets
+    pub writes: u64,  //puts, deletes, batches and commits
+    pub stall_time: Duration,  //writers spent waiting for the flush thread
+    pub background_error: Option<String>,
+}
+
+impl StatsDump {
+    //None without lookups in the block cache
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        match self.cache_hits + self.cache_misses {
+            0 => None,
+            lookups => Some(self.cache_hits as f64 / lookups as f64),
+        }
+    }
+}
+
+impl fmt::Display for StatsDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let levels = self.levels.iter()
+            .enumerate()
+            .filter(|(_, (files, _))| *files > 0)
+            .map(|(level, (files, bytes))| format!("L{} {}/{}B", level, files, bytes))
+            .collect::<Vec<_>>();
+        let hit_rate = self.cache_hit_rate().map_or("-".to_owned(), |r| format!("{:.1}%", r * 100.0));
+        write!(f, "in {:?}: levels [{}], mem tables {}B, cache hits {}, {} reads, {} writes, stalled {:?}, background error {}",
+            self.period, levels.join(" "), self.mem_table_bytes, hit_rate, self.reads, self.writes, self.stall_time,
+            self.background_error.as_deref().unwrap_or("none"))
+    }
+}
+
+//one table of a level
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableStats {
+    pub level: usize,
+    pub file_num: u64,
+    pub size_bytes: u64,
+    pub num_entries: Option<u64>,  //None for a table written before entries were counted
+    pub num_deletions: Option<u64>,
+    pub min_key: Vec<u8>,  //user keys
+    pub max_key: Vec<u8>,
+    pub last_seq_num: u64,
+    pub filter_negatives: u64,  //lookups its key filter ruled out, since the table was opened
+    pub filter_false_positives: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CompactionStats {
+    pub compactions: u64,
+    pub moved_files: u64,  //tables handed to the next level without being rewritten
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub flushes: u64,
+    pub flush_bytes_written: u64,  //level 0 tables written from mem tables
+}
+
+//the outcome of a single manual compaction
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionSummary {
+    pub input_files: usize,
+    pub output_files: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+//what LsmDb::ingest_db did
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IngestSummary {
+    pub linked_files: usize,  //tables copied over as they are, without reading their entries
+    pub linked_bytes: u64,
+    pub written_entries: u64,  //puts and deletes that went through the write path
+}
+
+//a value log and how much of it the live tables point to
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValueLogStats {
+    pub file_num: u64,
+    pub bytes: u64,
+    pub live_bytes: u64,
+    pub retired: bool,  //written again by a gc, the log goes with the last table pointing into it
+}
+
+impl ValueLogStats {
+    //the share of the log no live table points to
+    pub fn garbage_ratio(&self) -> f64 {
+        match self.bytes {
+            0 => 0.0,
+            bytes => bytes.saturating_sub(self.live_bytes) as f64 / bytes as f64,
+        }
+    }
+}
+
+//what LsmDb::gc_value_logs did
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValueLogGcSummary {
+    pub collected_files: usize,  //value logs whose live values were written again, they are retired
+    pub rewritten_values: u64,
+    pub rewritten_bytes: u64,
+}
+
+//what LsmDb::split did with the tables
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SplitSummary {
+    pub copied_files: usize,  //with keys on one side of the split key only
+    pub split_files: usize,  //rewritten into a table for each side
+}
+
+//what LsmDb::repair found, files are named by where they were before the repair
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    pub recovered_files: Vec<PathBuf>,  //tables and logs whose data is in the repaired database, all of it or in part
+    pub salvaged_entries: u64,  //entries rewritten out of damaged tables and logs
+    pub quarantined_files: Vec<PathBuf>,  //damaged files, moved into lost/ as they were
+}
+
+//summed over the shards of the block cache
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub capacity: usize,  //bytes of blocks
+    pub usage: usize,
+    pub pinned_usage: usize,  //part of the usage that is never evicted
+    pub pinned_meta_usage: usize,  //part of the pinned usage charged for the index and filter blocks tables hold
+    pub shards: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub lock_waits: u64,  //lookups and inserts that found their shard locked by another thread
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+//since the database was opened, failed operations are not counted
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistograms {
+    pub get: LatencySummary,
+    pub put: LatencySummary,
+    pub delete: LatencySummary,
+    pub write_batch: LatencySummary,
+    pub tx_commit: LatencySummary,
+    pub flush: LatencySummary,  //background flushes that wrote a table
+    pub compaction: LatencySummary,  //background compactions that changed the levels
+}
+
+//bytes of memory held by the database
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub mem_table: usize,  //entries of the mutable mem table with their skip list nodes
+    pub im_mem_table: usize,  //of the immutable one while it is flushed
+    pub block_cache: usize,  //data blocks that may be evicted
+    pub pinned_blocks: usize,  //level 0 data blocks, when they are pinned
+    pub index_and_filter_blocks: usize,  //read by every open table and kept, whether charged to the cache or not
+    pub tx_buffers: usize,  //writes of open transactions, until they commit
+    pub wal_buffers: usize,  //the buffers log records are encoded in
+    pub total: usize,
+}
+
+//bytes the database takes on disk, from counters kept up to date by flushes, compactions and deletions
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub levels: Vec<u64>,  //of the live tables of each level
+    pub wal: u64,  //the logs of the mem tables
+    pub value_logs: u64,  //retired ones included
+    pub obsolete: u64,  //tables and logs that wait for their last reader or for enable_file_deletions to go
+    pub untracked: u64,  //MANIFEST, IDENTITY, OPTIONS and anything else, as of the last refresh_from_fs
+    pub total: u64,
+}
+
+//a log and the length of it to copy, the records past it were appended after the list was taken
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LiveLog {
+    pub path: PathBuf,
+    pub valid_bytes: u64,
+}
+
+//what LsmDb::live_files lists, a copy of these files opens as the database at `seq_num`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LiveFiles {
+    pub tables: Vec<TableFile>,
+    pub value_logs: Vec<PathBuf>,  //the large values the tables point to
+    pub logs: Vec<LiveLog>,  //the immutable mem table's first, if there is one
+    pub manifest: PathBuf,
+    //the MANIFEST is rewritten by every flush and compaction, the copy gets these bytes instead of the file
+    pub manifest_contents: Vec<u8>,
+    pub identity: Option<PathBuf>,  //databases written before IDENTITY files existed get one on their next open
+    pub seq_num: u64,
+}
+
+//how writes are held back by the number of level 0 tables
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WriteStallState {
+    #[default]
+    None = 0,
+    Slowdown = 1,  //from Config::level0_slowdown_writes_trigger on, each write is delayed a little
+    Stop = 2,  //from Config::level0_stop_writes_trigger on, writes wait for compactions
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteStallStats {
+    pub state: WriteStallState,
+    pub level0_files: usize,
+    pub episodes: u64,  //times the state left None
+    pub duration: Duration,  //spent outside None, the current episode up to now included
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DbStats {
+    pub levels: Vec<LevelStats>,
+    pub compaction: CompactionStats,
+    pub tables_probed: u64,  //sst tables whose range covered the key of a search
+    pub slow_ops: u64,  //operations that took longer than the slow_op_threshold and were logged
+    pub blocks_read: u64,  //data blocks read from sst tables, by searches, scans and compactions
+    pub block_cache: CacheStats,  //all zero when the cache is disabled
+    pub preloaded_bytes: u64,  //of index, filter and data blocks read while opening
+    pub wal_bytes_written: u64,  //log records appended since the database was opened
+    pub last_seq_num: u64,
+    pub seq_num_limit: u64,  //writes fail with SequenceExhausted past this sequence number
+    pub write_stall: WriteStallStats,
+}
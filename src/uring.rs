@@ -0,0 +1,418 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::env::{self, Advice, Env, RandomAccessFile, StdEnv, WritableFile};
+
+//Reads through io_uring, everything else is left to StdEnv. Each thread gets a ring of its own on its first read,
+//so concurrent readers never wait for each other. Without kernel support the reads fall back to pread.
+#[derive(Clone, Copy, Debug)]
+pub struct UringEnv {
+    supported: bool,
+}
+
+impl UringEnv {
+    pub fn new() -> Self {
+        UringEnv { supported: Self::kernel_support() }
+    }
+
+    //probed once per process, old kernels and sandboxes may refuse the setup or the read opcode
+    pub fn kernel_support() -> bool {
+        static SUPPORTED: OnceLock<bool> = OnceLock::new();
+        *SUPPORTED.get_or_init(|| {
+            let probe = || -> io::Result<bool> {
+                let file = File::open("/dev/zero")?;
+                let mut buf = [1; 8];
+                let res = Ring::new(ENTRIES)?.read(file.as_raw_fd(), &mut [(0, &mut buf[..])])?;
+                Ok(matches!(res[0], Ok(8)) && buf == [0; 8])
+            };
+            probe().unwrap_or(false)
+        })
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.supported
+    }
+}
+
+impl Default for UringEnv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Env for UringEnv {
+    fn open(&self, path: &Path) -> io::Result<Arc<dyn RandomAccessFile>> {
+        if !self.supported {
+            return StdEnv.open(path);
+        }
+        Ok(Arc::new(UringFile(File::open(path)?)))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        StdEnv.create(path)
+    }
+
+    fn open_appendable(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        StdEnv.open_appendable(path)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        StdEnv.delete(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        StdEnv.rename(from, to)
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        StdEnv.list_dir(dir)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        StdEnv.is_dir(path)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        StdEnv.create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        StdEnv.sync_dir(dir)
+    }
+
+    fn try_lock(&self, path: &Path) -> io::Result<Option<Box<dyn Any + Send + Sync>>> {
+        StdEnv.try_lock(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        StdEnv.exists(path)
+    }
+
+    fn available_space(&self, dir: &Path) -> io::Result<Option<u64>> {
+        StdEnv.available_space(dir)
+    }
+}
+
+#[derive(Debug)]
+struct UringFile(File);
+
+impl UringFile {
+    //fills every buffer from its offset, failing if the file ends before
+    fn read_into(&self, reads: &mut [(u64, &mut [u8])]) -> io::Result<()> {
+        let res = RING.with(|ring| -> io::Result<Vec<io::Result<usize>>> {
+            let mut ring = ring.borrow_mut();
+            if ring.is_none() {
+                *ring = Ring::new(ENTRIES).ok();
+            }
+            let ring = match ring.as_mut() {
+                Some(ring) => ring,
+                None => return Ok(reads.iter().map(|_| Ok(0)).collect()),
+            };
+            let mut res = Vec::with_capacity(reads.len());
+            for chunk in reads.chunks_mut(ENTRIES as usize) {
+                res.extend(ring.read(self.0.as_raw_fd(), chunk)?);
+            }
+            Ok(res)
+        })?;
+        //a short read is finished with pread, as is everything when this thread got no ring
+        for ((offset, buf), res) in reads.iter_mut().zip(res) {
+            let n = res?;
+            if n < buf.len() {
+                env::read_exact_at(&self.0, &mut buf[n..], *offset + n as u64)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RandomAccessFile for UringFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.read_into(&mut [(offset, buf)])
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn advise(&self, advice: Advice, offset: u64, len: u64) -> io::Result<()> {
+        env::fadvise(&self.0, advice, offset, len)
+    }
+
+    fn read_many(&self, reads: &[(u64, usize)]) -> io::Result<Vec<Vec<u8>>> {
+        let mut bufs = reads.iter().map(|&(_, len)| vec![0; len]).collect::<Vec<_>>();
+        let mut reads = reads.iter().zip(bufs.iter_mut())
+            .map(|(&(offset, _), buf)| (offset, buf.as_mut_slice()))
+            .collect::<Vec<_>>();
+        self.read_into(&mut reads)?;
+        Ok(bufs)
+    }
+}
+
+thread_local! {
+    static RING: RefCell<Option<Ring>> = const { RefCell::new(None) };
+}
+
+//reads in flight at once per thread, larger batches are split
+const ENTRIES: u32 = 64;
+
+//from linux/io_uring.h
+const IORING_OP_READ: u8 = 22;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x8000000;
+const IORING_OFF_SQES: i64 = 0x10000000;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+//submission queue entry
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+//completion queue entry
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: i64) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr: ptr as *mut u8, len })
+    }
+
+    //the field at `offset` of the mapped ring
+    fn at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!(offset as usize + mem::size_of::<T>() <= self.len);
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+//the mappings go before the ring is closed
+struct Ring {
+    sq: Mmap,
+    cq: Mmap,
+    sqes: Mmap,
+    params: Params,
+    fd: OwnedFd,
+}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>();
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * mem::size_of::<Sqe>();
+        Ok(Ring {
+            sq: Mmap::new(fd.as_raw_fd(), sq_len, IORING_OFF_SQ_RING)?,
+            cq: Mmap::new(fd.as_raw_fd(), cq_len, IORING_OFF_CQ_RING)?,
+            sqes: Mmap::new(fd.as_raw_fd(), sqes_len, IORING_OFF_SQES)?,
+            params,
+            fd,
+        })
+    }
+
+    //submits the reads and waits for all of them, each result is the number of bytes read.
+    //The buffers must stay put until the kernel is done with them, so once any read is submitted this does not return
+    //before its completion, not even when entering the ring fails.
+    fn read(&mut self, fd: RawFd, reads: &mut [(u64, &mut [u8])]) -> io::Result<Vec<io::Result<usize>>> {
+        assert!(reads.len() <= self.params.sq_entries as usize);
+        let sq_off = &self.params.sq_off;
+        let cq_off = &self.params.cq_off;
+        //this thread is the only producer of submissions and the only consumer of completions
+        let (sq_head, sq_tail, cq_head, cq_tail) = unsafe {(
+            &*self.sq.at::<AtomicU32>(sq_off.head),
+            &*self.sq.at::<AtomicU32>(sq_off.tail),
+            &*self.cq.at::<AtomicU32>(cq_off.head),
+            &*self.cq.at::<AtomicU32>(cq_off.tail),
+        )};
+        let (sq_mask, cq_mask) = unsafe { (*self.sq.at::<u32>(sq_off.ring_mask), *self.cq.at::<u32>(cq_off.ring_mask)) };
+
+        let first_tail = sq_tail.load(Ordering::Relaxed);
+        let mut tail = first_tail;
+        for (i, (offset, buf)) in reads.iter_mut().enumerate() {
+            let idx = tail & sq_mask;
+            let sqe = Sqe {
+                opcode: IORING_OP_READ,
+                fd,
+                off: *offset,
+                addr: buf.as_mut_ptr() as u64,
+                len: buf.len() as u32,
+                user_data: i as u64,
+                ..Sqe::default()
+            };
+            unsafe {
+                ptr::write(self.sqes.at::<Sqe>(0).add(idx as usize), sqe);
+                *self.sq.at::<u32>(sq_off.array).add(idx as usize) = idx;
+            }
+            tail = tail.wrapping_add(1);
+        }
+        sq_tail.store(tail, Ordering::Release);
+
+        let mut results = reads.iter().map(|_| None).collect::<Vec<Option<io::Result<usize>>>>();
+        let mut done = 0;
+        let mut in_flight = reads.len();
+        let mut failed = None;
+        while done < in_flight {
+            let to_submit = match failed {
+                Some(_) => 0,
+                None => tail.wrapping_sub(sq_head.load(Ordering::Acquire)),
+            };
+            let ret = unsafe {
+                libc::syscall(libc::SYS_io_uring_enter, self.fd.as_raw_fd(), to_submit, 1, IORING_ENTER_GETEVENTS, ptr::null::<u8>(), 0)
+            };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY) => continue,
+                    //still failing, the completions are looked for without it
+                    _ if failed.is_some() => thread::sleep(Duration::from_millis(1)),
+                    //the entries not taken yet are withdrawn, only this thread enters the ring.
+                    //The reads taken still write into the buffers, they are drained before the error is returned.
+                    _ => {
+                        let head = sq_head.load(Ordering::Acquire);
+                        sq_tail.store(head, Ordering::Release);
+                        in_flight = head.wrapping_sub(first_tail) as usize;
+                        failed = Some(e);
+                    },
+                }
+            }
+            let mut head = cq_head.load(Ordering::Relaxed);
+            while head != cq_tail.load(Ordering::Acquire) {
+                let cqe = unsafe { &*self.cq.at::<Cqe>(cq_off.cqes).add((head & cq_mask) as usize) };
+                results[cqe.user_data as usize] = Some(match cqe.res {
+                    res if res < 0 => Err(io::Error::from_raw_os_error(-res)),
+                    res => Ok(res as usize),
+                });
+                head = head.wrapping_add(1);
+                done += 1;
+            }
+            cq_head.store(head, Ordering::Release);
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(results.into_iter().map(Option::unwrap).collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::temp_dir;
+
+    #[test]
+    fn batched_reads_match_the_file() {
+        let dir = temp_dir("uring");
+        let path = dir.join("data");
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &data).unwrap();
+        let env = UringEnv::new();
+        let file = env.open(&path).unwrap();
+        assert_eq!(file.size().unwrap(), data.len() as u64);
+
+        //more reads than fit in a ring at once
+        let reads = (0..200).map(|i| ((i * 499) as u64, 37 + i * 3)).collect::<Vec<_>>();
+        let bufs = file.read_many(&reads).unwrap();
+        for ((offset, len), buf) in reads.iter().zip(bufs) {
+            assert_eq!(&buf[..], &data[*offset as usize..*offset as usize + len]);
+        }
+        let mut buf = vec![0; 1000];
+        file.read_at(&mut buf, 5000).unwrap();
+        assert_eq!(&buf[..], &data[5000..6000]);
+
+        //the file ends before the buffer is full
+        assert_eq!(file.read_at(&mut buf, 99_500).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(file.read_many(&[(0, 10), (99_999, 2)]).is_err());
+        assert_eq!(file.read_many(&[]).unwrap(), Vec::<Vec<u8>>::new());
+    }
+}
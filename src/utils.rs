@@ -1,17 +1,363 @@
+use std::convert::{TryFrom, TryInto};
+use std::io;
+use std::path::Path;
 
+use crate::error::{Error, Result};
 
-pub fn to_usize(bytes: &[u8]) -> usize {
-    let mut buf = [0 as u8; 8];
-    for (p, i) in bytes.iter().enumerate() {
-        buf[p] = *i;
+//Everything on disk is sized in u64, whatever the width of usize on the machine that wrote it.
+
+//exactly 8 little-endian bytes, a shorter or longer slice means the data is corrupted
+pub fn read_u64_exact(bytes: &[u8]) -> Result<u64> {
+    let buf: [u8; 8] = bytes.try_into()
+        .map_err(|_| Error::Corruption(format!("expected 8 bytes for a u64, got {}", bytes.len())))?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+//a length or offset read back from a file, it may not fit in memory on 32-bit targets
+pub fn to_len(value: u64) -> Result<usize> {
+    usize::try_from(value).map_err(|_| Error::Corruption(format!("length {} does not fit in memory", value)))
+}
+
+pub fn get_fixed64(bytes: &[u8], offset: &mut usize) -> Result<u64> {
+    let end = offset.checked_add(8)
+        .filter(|&end| end <= bytes.len())
+        .ok_or_else(|| Error::Corruption(format!("u64 truncated at offset {}", offset)))?;
+    let value = read_u64_exact(&bytes[*offset..end])?;
+    *offset = end;
+    Ok(value)
+}
+
+//the end of `len` bytes starting at `pos`, if they are all there
+pub fn checked_end(pos: usize, len: u64, available: usize) -> Option<usize> {
+    to_len(len).ok()
+        .and_then(|len| pos.checked_add(len))
+        .filter(|&end| end <= available)
+}
+
+//a new, renamed or removed file only survives a power failure once its directory is synced.
+//Directories can not be opened as files everywhere, elsewhere this does nothing.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(test)]
+    DIR_SYNCS.with(|syncs| syncs.borrow_mut().push(dir.to_path_buf()));
+    #[cfg(unix)]
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+thread_local! {
+    static DIR_SYNCS: std::cell::RefCell<Vec<std::path::PathBuf>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+//the directories synced by the current thread since the last call
+#[cfg(test)]
+pub fn take_dir_syncs() -> Vec<std::path::PathBuf> {
+    DIR_SYNCS.with(|syncs| syncs.take())
+}
+
+//the number naming a table or log, None for files that are not named like ours
+pub fn file_num(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+//whether the file name ends in .`extension`. Windows and macOS file systems ignore case, so a 5.log
+//there is the same file as the 5.LOG the database wrote and is matched as well.
+pub fn has_extension(path: &Path, extension: &str) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(e) if cfg!(any(windows, target_os = "macos")) => e.eq_ignore_ascii_case(extension),
+        Some(e) => e == extension,
+        None => false,
+    }
+}
+
+//LEB128: 7 bits per byte, least significant group first, the high bit marks that more bytes follow
+pub fn put_varint64(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn put_varint32(buf: &mut Vec<u8>, value: u32) {
+    put_varint64(buf, value as u64);
+}
+
+pub fn get_varint64(bytes: &[u8], offset: &mut usize) -> Result<u64> {
+    get_varint(bytes, offset, 64)
+}
+
+pub fn get_varint32(bytes: &[u8], offset: &mut usize) -> Result<u32> {
+    get_varint(bytes, offset, 32).map(|value| value as u32)
+}
+
+fn get_varint(bytes: &[u8], offset: &mut usize, bits: u32) -> Result<u64> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(*offset)
+            .ok_or_else(|| Error::Corruption(format!("varint truncated at offset {}", offset)))?;
+        let group = (byte & 0x7f) as u64;
+        //the last group may only use the bits left in the value
+        if shift >= bits || (bits - shift < 7 && group >> (bits - shift) != 0) {
+            return Err(Error::Corruption(format!("varint{} overflows at offset {}", bits, offset)));
+        }
+        value |= group << shift;
+        *offset += 1;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+//CRC-32C (Castagnoli), the checksum of data blocks and log records
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &b| CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+const XXH_PRIME32_1: u32 = 0x9e37_79b1;
+const XXH_PRIME32_2: u32 = 0x85eb_ca77;
+const XXH_PRIME32_3: u32 = 0xc2b2_ae3d;
+const XXH_PRIME32_4: u32 = 0x27d4_eb2f;
+const XXH_PRIME32_5: u32 = 0x1656_67b1;
+
+fn xxh32_round(acc: u32, lane: u32) -> u32 {
+    acc.wrapping_add(lane.wrapping_mul(XXH_PRIME32_2)).rotate_left(13).wrapping_mul(XXH_PRIME32_1)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+//XXH32, cheaper than crc32c for the checksums computed on every write
+pub fn xxhash32(bytes: &[u8], seed: u32) -> u32 {
+    let mut rest = bytes;
+    let mut hash = if bytes.len() >= 16 {
+        let mut acc = [
+            seed.wrapping_add(XXH_PRIME32_1).wrapping_add(XXH_PRIME32_2),
+            seed.wrapping_add(XXH_PRIME32_2),
+            seed,
+            seed.wrapping_sub(XXH_PRIME32_1),
+        ];
+        while rest.len() >= 16 {
+            for (i, acc) in acc.iter_mut().enumerate() {
+                *acc = xxh32_round(*acc, le_u32(&rest[i * 4..]));
+            }
+            rest = &rest[16..];
+        }
+        acc[0].rotate_left(1).wrapping_add(acc[1].rotate_left(7)).wrapping_add(acc[2].rotate_left(12)).wrapping_add(acc[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(XXH_PRIME32_5)
+    };
+    hash = hash.wrapping_add(bytes.len() as u32);
+    while rest.len() >= 4 {
+        hash = hash.wrapping_add(le_u32(rest).wrapping_mul(XXH_PRIME32_3)).rotate_left(17).wrapping_mul(XXH_PRIME32_4);
+        rest = &rest[4..];
+    }
+    for &b in rest {
+        hash = hash.wrapping_add((b as u32).wrapping_mul(XXH_PRIME32_5)).rotate_left(11).wrapping_mul(XXH_PRIME32_1);
     }
-    usize::from_le_bytes(buf)
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(XXH_PRIME32_2);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(XXH_PRIME32_3);
+    hash ^ (hash >> 16)
 }
 
-pub fn to_u64(bytes: &[u8]) -> u64 {
-    let mut buf = [0 as u8; 8];
-    for (p, i) in bytes.iter().enumerate() {
-        buf[p] = *i;
+//each RandomState is seeded with fresh randomness from the OS
+pub fn random_u64() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+pub fn temp_dir(name: &str) -> std::path::PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut path = std::env::temp_dir();
+    path.push("draft_kv_test");
+    path.push(format!("{}-{}-{}", name, std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+//the file system Env the database uses by default, so the tests cover io_uring reads when they are built in
+#[cfg(test)]
+pub fn test_env() -> std::sync::Arc<dyn crate::env::Env> {
+    crate::env::default_env()
+}
+
+//xorshift, the same values on every run from the same nonzero seed
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
     }
-    u64::from_le_bytes(buf)
-}
\ No newline at end of file
+
+    #[cfg(test)]
+    pub fn bytes(&mut self, max_len: u64) -> Vec<u8> {
+        let len = self.next() % (max_len + 1);
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+//counts the allocations made by each thread, so tests can check a code path does not copy per entry
+#[cfg(test)]
+pub struct CountingAlloc;
+
+#[cfg(test)]
+thread_local! {
+    static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    static ALLOCATED_BYTES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        //the thread local may already be gone while the thread shuts down
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        let _ = ALLOCATED_BYTES.try_with(|n| n.set(n.get() + layout.size()));
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+//allocations made by the current thread while running `f`
+#[cfg(test)]
+pub fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(|n| n.get());
+    let res = f();
+    (res, ALLOCATIONS.with(|n| n.get()) - before)
+}
+
+//bytes allocated by the current thread while running `f`, freed or not
+#[cfg(test)]
+pub fn count_allocated_bytes<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATED_BYTES.with(|n| n.get());
+    let res = f();
+    (res, ALLOCATED_BYTES.with(|n| n.get()) - before)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions_match_with_the_case_of_the_file_system() {
+        assert!(has_extension(Path::new("/db/000005.LOG"), "LOG"));
+        assert!(has_extension(Path::new("/db/7.sst.tmp"), "tmp"));
+        assert!(!has_extension(Path::new("/db/7.sst.tmp"), "sst"));
+        assert!(!has_extension(Path::new("/db/LOCK"), "LOG"));
+        assert_eq!(has_extension(Path::new("/db/000005.log"), "LOG"), cfg!(any(windows, target_os = "macos")));
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn xxhash32_reference_values() {
+        assert_eq!(xxhash32(b"", 0), 0x02cc_5d05);
+        assert_eq!(xxhash32(b"a", 0), 0x550d_7456);
+        assert_eq!(xxhash32(b"abc", 0), 0x32d1_53ff);
+        assert_eq!(xxhash32(b"Nobody inspects the spammish repetition", 0), 0xe229_3b2f);
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        let values = [0, 1, 127, 128, 300, 16383, 16384, u32::MAX as u64, u32::MAX as u64 + 1, u64::MAX];
+        let mut buf = Vec::new();
+        for value in values.iter() {
+            put_varint64(&mut buf, *value);
+        }
+        put_varint32(&mut buf, u32::MAX);
+        assert_eq!(buf[0], 0);
+        let mut offset = 0;
+        for value in values.iter() {
+            assert_eq!(get_varint64(&buf, &mut offset).unwrap(), *value);
+        }
+        assert_eq!(get_varint32(&buf, &mut offset).unwrap(), u32::MAX);
+        assert_eq!(offset, buf.len());
+
+        let mut small = Vec::new();
+        put_varint64(&mut small, 127);
+        assert_eq!(small.len(), 1);
+        put_varint64(&mut small, 128);
+        assert_eq!(small.len(), 3);
+    }
+
+    #[test]
+    fn malformed_varints_are_rejected() {
+        //the continuation bit is set on the last byte
+        let mut offset = 0;
+        assert!(matches!(get_varint64(&[0x80, 0x80], &mut offset), Err(Error::Corruption(_))));
+        let mut offset = 0;
+        assert!(matches!(get_varint64(&[], &mut offset), Err(Error::Corruption(_))));
+        //too many bytes, or too many bits in the last one
+        let mut offset = 0;
+        assert!(matches!(get_varint64(&[0xff; 11], &mut offset), Err(Error::Corruption(_))));
+        let mut offset = 0;
+        assert!(matches!(get_varint64(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02], &mut offset), Err(Error::Corruption(_))));
+        let mut offset = 0;
+        assert!(matches!(get_varint32(&[0xff, 0xff, 0xff, 0xff, 0x1f], &mut offset), Err(Error::Corruption(_))));
+        let mut buf = Vec::new();
+        put_varint64(&mut buf, u32::MAX as u64 + 1);
+        let mut offset = 0;
+        assert!(matches!(get_varint32(&buf, &mut offset), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn fixed_width_reads_demand_exact_lengths() {
+        assert_eq!(read_u64_exact(&u64::MAX.to_le_bytes()).unwrap(), u64::MAX);
+        assert!(matches!(read_u64_exact(&[1, 2, 3]), Err(Error::Corruption(_))));
+        assert!(matches!(read_u64_exact(&[0; 9]), Err(Error::Corruption(_))));
+        let mut offset = 1;
+        assert!(matches!(get_fixed64(&[0; 8], &mut offset), Err(Error::Corruption(_))));
+        let mut offset = usize::MAX;
+        assert!(matches!(get_fixed64(&[0; 8], &mut offset), Err(Error::Corruption(_))));
+        //a length past u32 is kept whole, and then has to be there
+        let len = u32::MAX as u64 + 10;
+        assert_eq!(checked_end(0, len, 100), None);
+        assert_eq!(checked_end(usize::MAX, 1, usize::MAX), None);
+        assert_eq!(checked_end(10, 5, 15), Some(15));
+        if usize::BITS == 32 {
+            assert!(matches!(to_len(len), Err(Error::Corruption(_))));
+        } else {
+            assert_eq!(to_len(len).unwrap() as u64, len);
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+
+use bytes::Bytes;
+use parking_lot::{Mutex, RwLock};
+
+use crate::env::{Env, RandomAccessFile, WritableFile};
+use crate::error::{Error, Result};
+use crate::fault;
+use crate::utils::*;
+
+//Values larger than Config::value_log_threshold are moved out of the tables by the flush that writes them.
+//The flush appends them to a value log of its own, each record
+//  varint key length, user key, varint value length, value, crc32c of the value (4 bytes)
+//and the table keeps a ValuePointer to the value under a key type with the pointer bit set.
+//Compactions copy the pointers, so a value is written once however often its key is compacted.
+//The log is complete and synced before the table pointing into it is written.
+//
+//Every table counts the record bytes its pointers lead to in each log. What the live tables do not
+//count is garbage, a compaction that drops an overwritten or deleted pointer adds its record to it.
+//LsmDb::gc_value_logs writes the live values of a log with enough garbage again and retires the log,
+//it is deleted with the last table pointing into it. Until then snapshots and iterators still read it.
+
+pub const POINTER_SIZE: usize = 24;
+
+//where a value is in its value log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValuePointer {
+    pub file_num: u64,
+    pub offset: u64, //of the value itself, past the key of the record
+    pub len: u32,
+    pub checksum: u32, //crc32c of the value
+}
+
+impl ValuePointer {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(POINTER_SIZE);
+        buf.extend_from_slice(&self.file_num.to_le_bytes());
+        buf.extend_from_slice(&self.offset.to_le_bytes());
+        buf.extend_from_slice(&self.len.to_le_bytes());
+        buf.extend_from_slice(&self.checksum.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != POINTER_SIZE {
+            return Err(Error::Corruption(format!("value pointer of {} bytes", bytes.len())));
+        }
+        let u32_at = |pos: usize| u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]);
+        Ok(ValuePointer {
+            file_num: read_u64_exact(&bytes[0..8])?,
+            offset: read_u64_exact(&bytes[8..16])?,
+            len: u32_at(16),
+            checksum: u32_at(20),
+        })
+    }
+}
+
+pub fn file_name(db_path: &Path, file_num: u64) -> PathBuf {
+    db_path.join(file_num.to_string()).with_extension("vlog")
+}
+
+//the bytes of the record of a value in its log
+pub fn record_size(key_len: usize, value_len: u32) -> u64 {
+    let varint_len = |n: u64| (64 - n.max(1).leading_zeros() as u64).div_ceil(7);
+    varint_len(key_len as u64) + key_len as u64 + varint_len(value_len as u64) + value_len as u64 + 4
+}
+
+//a record of a value log as a gc reads it
+#[derive(Debug)]
+pub struct ValueRecord {
+    pub key: Vec<u8>,
+    pub pointer: ValuePointer,
+    pub value: Bytes,
+}
+
+//appends the values of one flush, only a finished log gets the .vlog name
+#[derive(Debug)]
+pub struct ValueLogWriter {
+    file_num: u64,
+    path: PathBuf,
+    tmp_file: PathBuf,
+    file: Box<dyn WritableFile>,
+    written: u64,
+    buf: Vec<u8>,
+}
+
+impl ValueLogWriter {
+    pub fn create(env: &dyn Env, db_path: &Path, file_num: u64) -> Result<Self> {
+        let path = file_name(db_path, file_num);
+        let tmp_file = path.with_extension("vlog.tmp");
+        let file = env.create(&tmp_file)?;
+        Ok(ValueLogWriter { file_num, path, tmp_file, file, written: 0, buf: Vec::new() })
+    }
+
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<ValuePointer> {
+        if value.len() > u32::MAX as usize {
+            return Err(Error::InvalidArgument(format!("value of {} bytes is too large for a value log", value.len())));
+        }
+        self.buf.clear();
+        put_varint64(&mut self.buf, key.len() as u64);
+        self.buf.extend_from_slice(key);
+        put_varint64(&mut self.buf, value.len() as u64);
+        let offset = self.written + self.buf.len() as u64;
+        let checksum = crc32c(value);
+        fault::append(&mut *self.file, &self.buf, &self.tmp_file)?;
+        fault::append(&mut *self.file, value, &self.tmp_file)?;
+        fault::append(&mut *self.file, &checksum.to_le_bytes(), &self.tmp_file)?;
+        self.written = offset + value.len() as u64 + 4;
+        Ok(ValuePointer { file_num: self.file_num, offset, len: value.len() as u32, checksum })
+    }
+
+    //durable once this returns, the table pointing into the log is written after
+    pub fn finish(mut self, env: &dyn Env) -> Result<PathBuf> {
+        fault::sync(&mut *self.file, &self.tmp_file)?;
+        drop(self.file);
+        fault::rename(env, &self.tmp_file, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            fault::sync_dir(env, dir)?;
+        }
+        Ok(self.path)
+    }
+
+    //a flush that failed leaves nothing behind
+    pub fn abandon(self, env: &dyn Env) {
+        drop(self.file);
+        let _ = env.delete(&self.tmp_file);
+    }
+}
+
+/// The value logs of a database, shared by the levels and every table. A log stays open from the open of
+/// the database or the flush that wrote it until it is deleted.
+#[derive(Debug)]
+pub struct ValueLogs {
+    db_path: PathBuf,
+    env: Arc<dyn Env>,
+    files: RwLock<BTreeMap<u64, LogFile>>, //by file number
+    bytes: AtomicU64, //of all the logs
+    refs: Mutex<LogRefs>,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    file: Arc<dyn RandomAccessFile>,
+    size: u64,
+}
+
+#[derive(Debug, Default)]
+struct LogRefs {
+    tables: HashMap<u64, Weak<ValueLogRef>>, //the tables pointing into a log share one ref
+    retired: HashSet<u64>, //rewritten by a gc, deleted once no table points into them
+    deletions_disabled: usize,
+    deferred: Vec<u64>, //retired logs without tables while deletions were disabled
+}
+
+/// Held by every table with pointers into a value log, a retired log is deleted along with the last one.
+#[derive(Debug)]
+pub struct ValueLogRef {
+    file_num: u64,
+    logs: Arc<ValueLogs>,
+}
+
+impl Drop for ValueLogRef {
+    fn drop(&mut self) {
+        let mut refs = self.logs.refs.lock();
+        //a table may have taken a new ref meanwhile
+        if refs.tables.get(&self.file_num).is_some_and(|r| r.strong_count() > 0) {
+            return;
+        }
+        refs.tables.remove(&self.file_num);
+        if refs.retired.contains(&self.file_num) {
+            self.logs.delete(&mut refs, self.file_num);
+        }
+    }
+}
+
+impl ValueLogs {
+    //the .vlog files in `db_path`
+    pub fn open(env: &Arc<dyn Env>, db_path: &Path) -> Result<Self> {
+        let logs = ValueLogs {
+            db_path: db_path.to_path_buf(),
+            env: env.clone(),
+            files: RwLock::default(),
+            bytes: AtomicU64::new(0),
+            refs: Mutex::default(),
+        };
+        for path in env.list_dir(db_path)?.into_iter().filter(|path| has_extension(path, "vlog")) {
+            if let Some(file_num) = file_num(&path) {
+                logs.add(file_num)?;
+            }
+        }
+        Ok(logs)
+    }
+
+    pub fn env(&self) -> &Arc<dyn Env> {
+        &self.env
+    }
+
+    pub fn file_nums(&self) -> Vec<u64> {
+        self.files.read().keys().copied().collect()
+    }
+
+    pub fn path(&self, file_num: u64) -> PathBuf {
+        file_name(&self.db_path, file_num)
+    }
+
+    //the logs not retired yet, a retired one only holds values that were written again
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let refs = self.refs.lock();
+        self.files.read().keys()
+            .filter(|num| !refs.retired.contains(num))
+            .map(|&num| file_name(&self.db_path, num))
+            .collect()
+    }
+
+    pub fn is_retired(&self, file_num: u64) -> bool {
+        self.refs.lock().retired.contains(&file_num)
+    }
+
+    //the refs a table with pointers into these logs holds
+    pub fn refs(self: &Arc<Self>, file_nums: impl Iterator<Item = u64>) -> Vec<Arc<ValueLogRef>> {
+        let mut refs = self.refs.lock();
+        file_nums.map(|file_num| {
+            if let Some(r) = refs.tables.get(&file_num).and_then(Weak::upgrade) {
+                return r;
+            }
+            let r = Arc::new(ValueLogRef { file_num, logs: self.clone() });
+            refs.tables.insert(file_num, Arc::downgrade(&r));
+            r
+        }).collect()
+    }
+
+    //after a gc wrote its live values again, the log goes with the last table pointing into it
+    pub fn retire(&self, file_num: u64) {
+        let mut refs = self.refs.lock();
+        refs.retired.insert(file_num);
+        if refs.tables.get(&file_num).is_none_or(|r| r.strong_count() == 0) {
+            self.delete(&mut refs, file_num);
+        }
+    }
+
+    pub fn disable_deletions(&self) {
+        self.refs.lock().deletions_disabled += 1;
+    }
+
+    pub fn enable_deletions(&self) {
+        let mut refs = self.refs.lock();
+        refs.deletions_disabled -= 1;
+        if refs.deletions_disabled == 0 {
+            for file_num in std::mem::take(&mut refs.deferred) {
+                self.delete(&mut refs, file_num);
+            }
+        }
+    }
+
+    fn delete(&self, refs: &mut LogRefs, file_num: u64) {
+        if refs.deletions_disabled > 0 {
+            refs.deferred.push(file_num);
+            return;
+        }
+        refs.retired.remove(&file_num);
+        if let Some(log) = self.files.write().remove(&file_num) {
+            self.bytes.fetch_sub(log.size, Ordering::Relaxed);
+        }
+        let path = file_name(&self.db_path, file_num);
+        let res = fault::remove_file(&*self.env, &path).and_then(|_| fault::sync_dir(&*self.env, &self.db_path));
+        if let Err(e) = res {
+            log::error!("failed to remove retired value log {:?}: {}", path, e);
+        }
+    }
+
+    //the size of each log by file number
+    pub fn sizes(&self) -> BTreeMap<u64, u64> {
+        self.files.read().iter().map(|(&num, log)| (num, log.size)).collect()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    //every record of a log with its checksum verified, in the order they were written
+    pub fn records(&self, file_num: u64) -> Result<Vec<ValueRecord>> {
+        let path = file_name(&self.db_path, file_num);
+        let file = self.file(file_num)?;
+        let mut bytes = vec![0; file.size()? as usize];
+        file.read_at(&mut bytes, 0)?;
+        let bytes = Bytes::from(bytes);
+        let corrupt = |offset: usize| Error::Corruption(format!("truncated record at {} of {:?}", offset, path));
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let start = offset;
+            let key_len = to_len(get_varint64(&bytes, &mut offset)?)?;
+            let key_end = checked_end(offset, key_len as u64, bytes.len()).ok_or_else(|| corrupt(start))?;
+            let key = bytes[offset..key_end].to_vec();
+            offset = key_end;
+            let value_len = get_varint32(&bytes, &mut offset)?;
+            let value_end = checked_end(offset, value_len as u64 + 4, bytes.len()).ok_or_else(|| corrupt(start))? - 4;
+            let checksum = u32::from_le_bytes([bytes[value_end], bytes[value_end + 1], bytes[value_end + 2], bytes[value_end + 3]]);
+            let pointer = ValuePointer { file_num, offset: offset as u64, len: value_len, checksum };
+            let value = bytes.slice(offset..value_end);
+            if crc32c(&value) != checksum {
+                return Err(Error::Corruption(format!("value at {} of {:?} does not match its checksum", offset, path)));
+            }
+            records.push(ValueRecord { key, pointer, value });
+            offset = value_end + 4;
+        }
+        Ok(records)
+    }
+
+    //a log a flush finished
+    pub fn add(&self, file_num: u64) -> Result<()> {
+        let file = self.env.open(&file_name(&self.db_path, file_num))?;
+        let size = file.size()?;
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        if let Some(replaced) = self.files.write().insert(file_num, LogFile { file, size }) {
+            self.bytes.fetch_sub(replaced.size, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, pointer: &ValuePointer) -> Result<Bytes> {
+        let file = self.file(pointer.file_num)?;
+        let mut value = vec![0; pointer.len as usize];
+        file.read_at(&mut value, pointer.offset)?;
+        if crc32c(&value) != pointer.checksum {
+            return Err(Error::Corruption(format!("value at {} of {:?} does not match its checksum",
+                pointer.offset, file_name(&self.db_path, pointer.file_num))));
+        }
+        Ok(Bytes::from(value))
+    }
+
+    //the value of an entry, read from its log if the entry is a pointer
+    pub fn resolve(&self, is_pointer: bool, value: Bytes) -> Result<Bytes> {
+        match is_pointer {
+            true => self.read(&ValuePointer::decode(&value)?),
+            false => Ok(value),
+        }
+    }
+
+    fn file(&self, file_num: u64) -> Result<Arc<dyn RandomAccessFile>> {
+        match self.files.read().get(&file_num) {
+            Some(log) => Ok(log.file.clone()),
+            None => Err(Error::Corruption(format!("value log {:?} is missing", file_name(&self.db_path, file_num)))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_env;
+
+    #[test]
+    fn values_read_back_through_their_pointers() {
+        let env = test_env();
+        let dir = temp_dir("value_log");
+        let mut writer = ValueLogWriter::create(&*env, &dir, 7).unwrap();
+        let values = [vec![1; 100], Vec::new(), vec![2; 5000]];
+        let pointers = values.iter()
+            .enumerate()
+            .map(|(i, v)| writer.add(format!("key{}", i).as_bytes(), v).unwrap())
+            .collect::<Vec<_>>();
+        assert!(!env.exists(&file_name(&dir, 7)));
+        assert_eq!(writer.finish(&*env).unwrap(), file_name(&dir, 7));
+
+        let logs = ValueLogs::open(&env, &dir).unwrap();
+        assert_eq!(logs.file_nums(), vec![7]);
+        for (pointer, value) in pointers.iter().zip(values.iter()) {
+            assert_eq!(ValuePointer::decode(&pointer.encode()).unwrap(), *pointer);
+            assert_eq!(&logs.read(pointer).unwrap()[..], &value[..]);
+        }
+        assert_eq!(&logs.resolve(false, Bytes::from_static(b"inline")).unwrap()[..], b"inline");
+        assert_eq!(logs.resolve(true, Bytes::from(pointers[2].encode())).unwrap().len(), 5000);
+
+        let bad = ValuePointer { checksum: pointers[0].checksum ^ 1, ..pointers[0] };
+        assert!(matches!(logs.read(&bad), Err(Error::Corruption(_))));
+        assert!(matches!(ValuePointer::decode(b"short"), Err(Error::Corruption(_))));
+        assert!(matches!(logs.read(&ValuePointer { file_num: 8, ..pointers[0] }), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn a_retired_log_goes_with_the_last_ref_unless_deletions_are_disabled() {
+        let env = test_env();
+        let dir = temp_dir("value_log_refs");
+        for num in [1, 2] {
+            let mut writer = ValueLogWriter::create(&*env, &dir, num).unwrap();
+            writer.add(b"key", &[num as u8; 50]).unwrap();
+            writer.finish(&*env).unwrap();
+        }
+        let logs = Arc::new(ValueLogs::open(&env, &dir).unwrap());
+        assert_eq!(logs.total_bytes(), 2 * record_size(3, 50));
+        let table_refs = logs.refs(std::iter::once(1));
+        let other_refs = logs.refs(std::iter::once(1));
+        assert!(Arc::ptr_eq(&table_refs[0], &other_refs[0]));
+
+        logs.retire(1);
+        assert!(logs.is_retired(1) && env.exists(&file_name(&dir, 1)));
+        assert_eq!(logs.paths(), vec![file_name(&dir, 2)]);
+        drop(table_refs);
+        drop(other_refs);
+        assert!(!env.exists(&file_name(&dir, 1)));
+        assert_eq!(logs.file_nums(), vec![2]);
+        assert_eq!(logs.total_bytes(), record_size(3, 50));
+
+        logs.disable_deletions();
+        logs.retire(2);
+        assert!(env.exists(&file_name(&dir, 2)));
+        logs.enable_deletions();
+        assert!(!env.exists(&file_name(&dir, 2)));
+        assert_eq!(logs.total_bytes(), 0);
+    }
+}
@@ -1,24 +1,87 @@
-use std::fs::{remove_file, File, OpenOptions};
-use std::io::{self, BufWriter, Read, Write};
+use std::convert::TryFrom;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 
+use crate::env::{Env, WritableFile};
+use crate::error::{Error, Result};
+use crate::fault;
 use crate::utils::*;
 
+use bytes::Bytes;
+
+//log formats, a log keeps the format it was created with
+pub const LEGACY_FORMAT: u8 = 0; //8 byte lengths, no header
+pub const CHECKSUM_FORMAT: u8 = 2; //varint lengths, each record is followed by the crc32c of its bytes; 1 had no crc32c
+pub const CURRENT_FORMAT: u8 = CHECKSUM_FORMAT;
+
+//versioned logs start with the magic followed by the version byte,
+//a legacy log starts with an entry type, which is never 0xff
+const HEADER_MAGIC: &[u8] = b"\xffDKVLOG";
+const HEADER_LEN: usize = 8;
+
+//a record buffer grown past this by a large batch is given back after the write
+const MAX_RETAINED_BUFFER: usize = 1024 * 1024;
+
 #[derive(Debug)]
 pub struct Log {
     path: PathBuf,
-    file: File,
+    env: Arc<dyn Env>,
+    file: Box<dyn WritableFile>,
+    format_version: u8,
+    bytes_written: Arc<AtomicU64>, //records appended, may be shared with the logs before it
+    buf: Vec<u8>, //each record is encoded here, then appended
+    buffer_bytes: Arc<AtomicUsize>, //capacity of `buf`, may be shared with other logs
+    valid_len: u64, //the file up to the end of the last record appended
 }
 
 impl Log {
-    pub fn open(dir_path: &PathBuf, log_num: u64) -> Self {
-        let mut path = dir_path.clone();
-        path.push(log_num.to_string());
-        path.set_extension("LOG");
-        let file = OpenOptions::new().create(true).append(true).read(true).open(&path).unwrap(); 
-        Log {
+    pub fn open(env: &Arc<dyn Env>, dir_path: &Path, log_num: u64) -> Result<Self> {
+        let path = log_path(dir_path, log_num);
+        let exists = env.exists(&path);
+        let mut file = env.open_appendable(&path)?;
+        let reader = env.open(&path)?;
+        let size = reader.size()?;
+        let mut header = vec![0; std::cmp::min(HEADER_LEN as u64, size) as usize];
+        reader.read_at(&mut header, 0)?;
+        //a crash while the header was written leaves part of it, the log has no entries yet
+        if !header.is_empty() && header.len() < HEADER_LEN && HEADER_MAGIC.starts_with(&header) {
+            file.truncate(0)?;
+            header.clear();
+        }
+        let valid_len = if header.is_empty() { HEADER_LEN as u64 } else { size };
+        let format_version = if header.is_empty() {
+            fault::append(&mut *file, HEADER_MAGIC, &path)?;
+            fault::append(&mut *file, &[CURRENT_FORMAT], &path)?;
+            if !exists {
+                fault::sync_dir(&**env, dir_path)?;
+            }
+            CURRENT_FORMAT
+        } else {
+            format_of(&header)?
+        };
+        Ok(Log {
             path,
+            env: env.clone(),
             file,
+            format_version,
+            bytes_written: Arc::new(AtomicU64::new(0)),
+            buf: Vec::new(),
+            buffer_bytes: Arc::new(AtomicUsize::new(0)),
+            valid_len,
+        })
+    }
+
+    //the log is no longer needed once its mem table is in a table
+    pub fn remove(self) -> io::Result<()> {
+        let Log { path, env, file, buf, buffer_bytes, .. } = self;
+        buffer_bytes.fetch_sub(buf.capacity(), Ordering::Relaxed);
+        drop(file);
+        fault::remove_file(&*env, &path)?;
+        match path.parent() {
+            Some(dir) => fault::sync_dir(&*env, dir),
+            None => Ok(()),
         }
     }
 
@@ -26,40 +89,145 @@ impl Log {
         self.path.clone()
     }
 
-    pub fn read(&mut self) -> Vec<LogEntry> {
-        let mut buf = Vec::new();
-        // read the whole file
-        self.file.read_to_end(&mut buf).unwrap();
-        let len = buf.len();
-        let mut pos = 0;
-        let mut entries = Vec::new();
-        while pos < len {
-            entries.push(LogEntry::decode(&buf, &mut pos));
+    //a copy of the file up to here holds every record appended so far
+    pub fn valid_len(&self) -> u64 {
+        self.valid_len
+    }
+
+    #[cfg(test)]
+    pub fn read(&mut self) -> Result<Vec<LogEntry>> {
+        match self.read_valid()? {
+            (entries, None) => Ok(entries),
+            (_, Some(e)) => Err(e),
+        }
+    }
+
+    //the records before the first one that does not decode, and the error it gave
+    pub fn read_valid(&mut self) -> Result<(Vec<LogEntry>, Option<Error>)> {
+        let (entries, _, error) = self.read_records()?;
+        Ok((entries, error))
+    }
+
+    //a damaged record fails the recovery in paranoid mode, otherwise it and everything after it is cut off,
+    //so the records written next do not end up behind it
+    pub fn recover(&mut self, paranoid: bool) -> Result<Vec<LogEntry>> {
+        match self.read_records()? {
+            (entries, _, None) => Ok(entries),
+            (_, _, Some(e)) if paranoid => Err(e),
+            (entries, valid_len, Some(e)) => {
+                log::warn!("dropping the damaged end of {:?} from offset {}: {}", self.path, valid_len, e);
+                self.file.truncate(valid_len as u64)?;
+                fault::sync(&mut *self.file, &self.path)?;
+                self.valid_len = valid_len as u64;
+                Ok(entries)
+            },
         }
-        entries
+    }
+
+    //also returns the end of the last valid record
+    fn read_records(&mut self) -> Result<(Vec<LogEntry>, usize, Option<Error>)> {
+        // read the whole file
+        let buf = self.env.read(&self.path)?;
+        Ok(decode_records(Bytes::from(buf), self.format_version))
     }
 
     pub fn write(&mut self, log_entry: LogEntry) -> io::Result<()> {
-        let bytes = log_entry.encode();
-        self.file.write_all(&bytes)?;
-        self.file.flush()
+        let capacity = self.buf.capacity();
+        self.buf.clear();
+        log_entry.encode_into(self.format_version, &mut self.buf);
+        let res = fault::append(&mut *self.file, &self.buf, &self.path);
+        match res {
+            Ok(()) => {
+                self.bytes_written.fetch_add(self.buf.len() as u64, Ordering::Relaxed);
+                self.valid_len += self.buf.len() as u64;
+            },
+            //a record cut short would end the log for recovery, the writes after it would be lost
+            Err(_) => { let _ = self.file.truncate(self.valid_len); },
+        }
+        if self.buf.capacity() > MAX_RETAINED_BUFFER {
+            self.buf = Vec::new();
+        }
+        //grows by a record larger than any before it, so the counter rarely changes
+        if self.buf.capacity() >= capacity {
+            self.buffer_bytes.fetch_add(self.buf.capacity() - capacity, Ordering::Relaxed);
+        } else {
+            self.buffer_bytes.fetch_sub(capacity - self.buf.capacity(), Ordering::Relaxed);
+        }
+        res
+    }
+
+    //the records appended from now on are counted in `counter`
+    pub fn count_bytes_written(&mut self, counter: Arc<AtomicU64>) {
+        self.bytes_written = counter;
     }
 
+    //the record buffer is counted in `counter` from now on
+    pub fn count_buffer_bytes(&mut self, counter: Arc<AtomicUsize>) {
+        let capacity = self.buf.capacity();
+        self.buffer_bytes.fetch_sub(capacity, Ordering::Relaxed);
+        counter.fetch_add(capacity, Ordering::Relaxed);
+        self.buffer_bytes = counter;
+    }
+
+}
+
+//the first byte of a log record; entries in one transaction have the same number
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalRecordType {
+    Put = 0,
+    Delete = 1,
+    TxPut = 2,
+    TxDelete = 3,
+    TxBegin = 4,
+    TxCommit = 5,
+    TxAbort = 6,
+    WriteTime = 7, //the unix millis in the value at which the write with this number was made
+    Batch = 8, //a whole WriteBatch in the value, its entries numbered on from this number
+    PutChecked = 9, //the value is followed by the checksum of the key and value
+    TxPutChecked = 10,
+}
+
+impl WalRecordType {
+    //the others only carry a sequence number
+    fn has_key_value(self) -> bool {
+        !matches!(self, WalRecordType::TxBegin | WalRecordType::TxCommit | WalRecordType::TxAbort)
+    }
+}
+
+impl TryFrom<u8> for WalRecordType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(WalRecordType::Put),
+            1 => Ok(WalRecordType::Delete),
+            2 => Ok(WalRecordType::TxPut),
+            3 => Ok(WalRecordType::TxDelete),
+            4 => Ok(WalRecordType::TxBegin),
+            5 => Ok(WalRecordType::TxCommit),
+            6 => Ok(WalRecordType::TxAbort),
+            7 => Ok(WalRecordType::WriteTime),
+            8 => Ok(WalRecordType::Batch),
+            9 => Ok(WalRecordType::PutChecked),
+            10 => Ok(WalRecordType::TxPutChecked),
+            _ => Err(Error::Corruption(format!("invalid log record type {}", value))),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct LogEntry {
-    //0 insert, 1 delete, 2/3 tx-insert/tx-delete, 4 begin, 5 commit, 6 abort; entries in one transaction have the same number
-    pub entry_type: u8, 
-    pub key: Vec<u8>,
-    pub value: Vec<u8>,
+    pub entry_type: WalRecordType,
+    pub key: Bytes,
+    pub value: Bytes,
     pub seq_num: u64,
 }
 
 impl LogEntry {
-    pub fn new(entry_type: u8, key: &[u8], value: &[u8], seq_num: u64) -> Self {
-        let key = key.to_vec();
-        let value = value.to_vec();
+    #[cfg(test)]
+    pub fn new(entry_type: WalRecordType, key: &[u8], value: &[u8], seq_num: u64) -> Self {
+        let key = Bytes::copy_from_slice(key);
+        let value = Bytes::copy_from_slice(value);
         LogEntry {
             entry_type,
             key,
@@ -68,57 +236,324 @@ impl LogEntry {
         }
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let mut bytes = vec![self.entry_type];
-        if self.entry_type < 4 {
-            bytes.extend_from_slice(&self.key.len().to_le_bytes());
+    #[cfg(test)]
+    pub fn encode(&self, format_version: u8) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.encode_into(format_version, &mut bytes);
+        bytes
+    }
+
+    //appends the record to `bytes`, which may hold others before it
+    pub fn encode_into(&self, format_version: u8, bytes: &mut Vec<u8>) {
+        let start = bytes.len();
+        bytes.push(self.entry_type as u8);
+        if self.entry_type.has_key_value() {
+            put_length(bytes, self.key.len(), format_version);
             bytes.extend_from_slice(&self.key);
-            bytes.extend_from_slice(&self.value.len().to_le_bytes());
+            put_length(bytes, self.value.len(), format_version);
             bytes.extend_from_slice(&self.value);
             bytes.extend_from_slice(&self.seq_num.to_le_bytes());
         } else {
             bytes.extend_from_slice(&self.seq_num.to_le_bytes());
         }
-        bytes
+        if format_version >= CHECKSUM_FORMAT {
+            let checksum = crc32c(&bytes[start..]);
+            bytes.extend_from_slice(&checksum.to_le_bytes());
+        }
+    }
+
+    pub fn decode(bytes: &Bytes, pos: &mut usize, format_version: u8) -> Result<Self> {
+        let start = *pos;
+        let entry = Self::decode_record(bytes, pos, format_version)?;
+        if format_version >= CHECKSUM_FORMAT {
+            let end = checked_end(*pos, 4, bytes.len())
+                .ok_or_else(|| Error::Corruption(format!("log record truncated at offset {}", pos)))?;
+            let checksum = u32::from_le_bytes([bytes[*pos], bytes[*pos + 1], bytes[*pos + 2], bytes[*pos + 3]]);
+            if crc32c(&bytes[start..*pos]) != checksum {
+                return Err(Error::Corruption(format!("log record checksum mismatch at offset {}", start)));
+            }
+            *pos = end;
+        }
+        Ok(entry)
     }
 
-    pub fn decode(bytes: &[u8], pos: &mut usize) -> Self {
+    fn decode_record(bytes: &Bytes, pos: &mut usize, format_version: u8) -> Result<Self> {
         //read entry_type
-        let entry_type = bytes[*pos];
+        let entry_type = *bytes.get(*pos)
+            .ok_or_else(|| Error::Corruption(format!("log record truncated at offset {}", pos)))?;
+        let entry_type = WalRecordType::try_from(entry_type)
+            .map_err(|_| Error::Corruption(format!("invalid log record type {} at offset {}", entry_type, pos)))?;
         *pos += 1;
-        assert!(entry_type <= 6);
-        if entry_type < 4 {
-            //read key_len
-            let key_len = to_usize(&bytes[*pos..*pos+8]);
-            *pos += 8;
+        if entry_type.has_key_value() {
             //read key
-            let key = bytes[*pos..*pos+key_len].to_vec();
-            *pos += key_len;
-            //read value_len
-            let value_len = to_usize(&bytes[*pos..*pos+8]);
-            *pos += 8;
+            let key = get_bytes(bytes, pos, format_version)?;
             //read value
-            let value = bytes[*pos..*pos+value_len].to_vec();
-            *pos += value_len;
-            //read sequence num, not suitable for 32-bit machine
-            let seq_num = to_u64(&bytes[*pos..*pos+8]);
-            *pos += 8;
-            LogEntry {
+            let value = get_bytes(bytes, pos, format_version)?;
+            //read sequence num
+            let seq_num = get_fixed64(bytes, pos)?;
+            Ok(LogEntry {
                 entry_type,
                 key,
                 value,
                 seq_num,
-            }
+            })
         } else {
-            let seq_num = to_u64(&bytes[*pos..*pos+8]);
-            *pos += 8;
-            LogEntry {
+            let seq_num = get_fixed64(bytes, pos)?;
+            Ok(LogEntry {
                 entry_type,
-                key: Vec::new(),
-                value: Vec::new(),
+                key: Bytes::new(),
+                value: Bytes::new(),
                 seq_num,
-            }
+            })
+        }
+    }
+}
+
+pub fn log_path(dir_path: &Path, log_num: u64) -> PathBuf {
+    dir_path.join(log_num.to_string()).with_extension("LOG")
+}
+
+//the records of a log without opening it for writes, for a read-only open. A damaged end is left in place,
+//in paranoid mode it fails the read like a recovery would.
+pub fn read_log(env: &dyn Env, path: &Path, paranoid: bool) -> Result<Vec<LogEntry>> {
+    let buf = Bytes::from(env.read(path)?);
+    //a header cut short by a crash leaves a log with no entries
+    if buf.len() < HEADER_LEN && HEADER_MAGIC.starts_with(&buf) {
+        return Ok(Vec::new());
+    }
+    let format_version = format_of(&buf[..std::cmp::min(buf.len(), HEADER_LEN)])?;
+    match decode_records(buf, format_version) {
+        (_, _, Some(e)) if paranoid => Err(e),
+        (entries, _, _) => Ok(entries),
+    }
+}
+
+//versioned logs start with the magic and the version, anything else is a legacy log.
+//A log of a newer version is not read as one of ours.
+fn format_of(header: &[u8]) -> Result<u8> {
+    if header.len() == HEADER_LEN && header.starts_with(HEADER_MAGIC) {
+        match header[HEADER_LEN - 1] {
+            version if version > CURRENT_FORMAT => Err(Error::Corruption(format!("unsupported log format {}", version))),
+            version => Ok(version),
+        }
+    } else {
+        Ok(LEGACY_FORMAT)
+    }
+}
+
+//the keys and values of the entries are views into the buffer, also returns the end of the last valid record
+fn decode_records(buf: Bytes, format_version: u8) -> (Vec<LogEntry>, usize, Option<Error>) {
+    let len = buf.len();
+    let mut pos = if format_version == LEGACY_FORMAT { 0 } else { HEADER_LEN };
+    let mut entries = Vec::new();
+    while pos < len {
+        let start = pos;
+        match LogEntry::decode(&buf, &mut pos, format_version) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => return (entries, start, Some(e)),
         }
     }
+    (entries, pos, None)
+}
+
+fn put_length(bytes: &mut Vec<u8>, length: usize, format_version: u8) {
+    if format_version == LEGACY_FORMAT {
+        bytes.extend_from_slice(&(length as u64).to_le_bytes());
+    } else {
+        put_varint64(bytes, length as u64);
+    }
+}
+
+//a length followed by that many bytes
+fn get_bytes(bytes: &Bytes, pos: &mut usize, format_version: u8) -> Result<Bytes> {
+    let len = if format_version == LEGACY_FORMAT {
+        get_fixed64(bytes, pos)?
+    } else {
+        get_varint64(bytes, pos)?
+    };
+    let end = checked_end(*pos, len, bytes.len())
+        .ok_or_else(|| Error::Corruption(format!("log record truncated at offset {}", pos)))?;
+    let res = bytes.slice(*pos..end);
+    *pos = end;
+    Ok(res)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::checked_value;
+    use crate::memtable::MemTable;
+    use crate::utils::{test_env, take_dir_syncs, temp_dir, Rng};
+    use crate::write_batch::WriteBatch;
+
+    fn sample_entries() -> Vec<LogEntry> {
+        vec![
+            LogEntry::new(WalRecordType::Put, b"key", b"value", 1),
+            LogEntry::new(WalRecordType::Delete, b"key", b"", 2),
+            LogEntry::new(WalRecordType::TxBegin, b"", b"", 3),
+            LogEntry::new(WalRecordType::TxPut, b"tx-key", &[7; 300], 3),
+            LogEntry::new(WalRecordType::TxCommit, b"", b"", 3),
+            LogEntry::new(WalRecordType::WriteTime, b"", &1_600_000_000_000u64.to_le_bytes(), 4),
+            LogEntry::new(WalRecordType::Batch, b"", &WriteBatch::new().put(b"a", b"1").delete(b"b").encode_to(), 4),
+            LogEntry::new(WalRecordType::PutChecked, b"key", &checked_value(b"key", b"checked"), 5),
+        ]
+    }
+
+    fn assert_same(entries: &[LogEntry], expected: &[LogEntry]) {
+        assert_eq!(entries.len(), expected.len());
+        for (entry, expected) in entries.iter().zip(expected) {
+            assert_eq!((entry.entry_type, &entry.key, &entry.value, entry.seq_num),
+                (expected.entry_type, &expected.key, &expected.value, expected.seq_num));
+        }
+    }
+
+    #[test]
+    fn new_log_uses_varints_and_reads_back() {
+        let dir = temp_dir("wal_varint");
+        let mut log = Log::open(&test_env(), &dir, 1).unwrap();
+        for entry in sample_entries() {
+            log.write(entry).unwrap();
+        }
+        let mut log = Log::open(&test_env(), &dir, 1).unwrap();
+        assert_eq!(log.format_version, CURRENT_FORMAT);
+        assert_same(&log.read().unwrap(), &sample_entries());
+        //appending to a reopened log keeps its format
+        log.write(LogEntry::new(WalRecordType::Put, b"more", b"data", 5)).unwrap();
+        assert_eq!(Log::open(&test_env(), &dir, 1).unwrap().read().unwrap().len(), sample_entries().len() + 1);
+    }
+
+    #[test]
+    fn creating_and_removing_logs_syncs_the_directory() {
+        let dir = temp_dir("wal_dir_sync");
+        take_dir_syncs();
+        let mut mem_table = MemTable::new();
+        mem_table.set_writer(&test_env(), &dir, 1).unwrap();
+        assert_eq!(take_dir_syncs(), vec![dir.clone()]);
+        //reopening an existing log creates nothing
+        drop(Log::open(&test_env(), &dir, 1).unwrap());
+        assert!(take_dir_syncs().is_empty());
+        mem_table.remove_writer().unwrap();
+        assert_eq!(take_dir_syncs(), vec![dir.clone()]);
+        assert!(!dir.join("1.LOG").exists());
+    }
+
+    #[test]
+    fn incomplete_header_is_rewritten() {
+        let dir = temp_dir("wal_incomplete_header");
+        for len in 1..HEADER_LEN {
+            std::fs::write(dir.join("1.LOG"), &HEADER_MAGIC[..len]).unwrap();
+            let mut log = Log::open(&test_env(), &dir, 1).unwrap();
+            assert_eq!(log.format_version, CURRENT_FORMAT);
+            assert!(log.read().unwrap().is_empty());
+            log.write(LogEntry::new(WalRecordType::Put, b"key", b"value", 1)).unwrap();
+            assert_same(&Log::open(&test_env(), &dir, 1).unwrap().read().unwrap(), &[LogEntry::new(WalRecordType::Put, b"key", b"value", 1)]);
+        }
+    }
+
+    #[test]
+    fn log_of_a_newer_format_is_rejected() {
+        let dir = temp_dir("wal_newer_format");
+        let mut bytes = HEADER_MAGIC.to_vec();
+        bytes.push(CURRENT_FORMAT + 1);
+        bytes.extend(sample_entries().iter().flat_map(|e| e.encode(CURRENT_FORMAT)));
+        std::fs::write(dir.join("1.LOG"), &bytes).unwrap();
+        assert!(matches!(Log::open(&test_env(), &dir, 1), Err(Error::Corruption(_))));
+        assert!(matches!(read_log(&*test_env(), &dir.join("1.LOG"), false), Err(Error::Corruption(_))));
+        //the log is left as it was
+        assert_eq!(std::fs::read(dir.join("1.LOG")).unwrap(), bytes);
+    }
+
+    #[test]
+    fn legacy_log_stays_readable() {
+        let dir = temp_dir("wal_legacy");
+        let bytes = sample_entries().iter()
+            .flat_map(|e| e.encode(LEGACY_FORMAT))
+            .collect::<Vec<_>>();
+        std::fs::write(dir.join("1.LOG"), &bytes).unwrap();
+        let mut log = Log::open(&test_env(), &dir, 1).unwrap();
+        assert_eq!(log.format_version, LEGACY_FORMAT);
+        assert_same(&log.read().unwrap(), &sample_entries());
+        let current_len = sample_entries().iter().map(|e| e.encode(CURRENT_FORMAT).len()).sum::<usize>();
+        assert!(current_len < bytes.len());
+    }
+
+    #[test]
+    fn truncated_record_is_reported() {
+        let dir = temp_dir("wal_truncated");
+        let mut log = Log::open(&test_env(), &dir, 1).unwrap();
+        log.write(LogEntry::new(WalRecordType::Put, b"key", b"value", 1)).unwrap();
+        let path = log.get_path();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 10]).unwrap();
+        assert!(matches!(Log::open(&test_env(), &dir, 1).unwrap().read(), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn damaged_record_is_cut_off_unless_paranoid() {
+        let dir = temp_dir("wal_checksum");
+        let mut log = Log::open(&test_env(), &dir, 1).unwrap();
+        log.write(LogEntry::new(WalRecordType::Put, b"key", b"value", 1)).unwrap();
+        log.write(LogEntry::new(WalRecordType::Put, b"key", b"other", 2)).unwrap();
+        let path = log.get_path();
+        let mut bytes = std::fs::read(&path).unwrap();
+        //still decodes, but no longer matches its checksum
+        let last_value = bytes.len() - 4 - 8 - 5;
+        bytes[last_value] = b'O';
+        std::fs::write(&path, &bytes).unwrap();
+        match Log::open(&test_env(), &dir, 1).unwrap().recover(true) {
+            Err(Error::Corruption(msg)) => assert!(msg.contains("checksum mismatch"), "{}", msg),
+            res => panic!("expected corruption, got {:?}", res),
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+        let mut log = Log::open(&test_env(), &dir, 1).unwrap();
+        assert_same(&log.recover(false).unwrap(), &[LogEntry::new(WalRecordType::Put, b"key", b"value", 1)]);
+        //the next record follows the last valid one
+        log.write(LogEntry::new(WalRecordType::Put, b"key", b"again", 3)).unwrap();
+        assert_eq!(Log::open(&test_env(), &dir, 1).unwrap().recover(true).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn invalid_record_type_is_reported() {
+        let dir = temp_dir("wal_record_type");
+        let mut log = Log::open(&test_env(), &dir, 1).unwrap();
+        log.write(LogEntry::new(WalRecordType::Put, b"key", b"value", 1)).unwrap();
+        let path = log.get_path();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER_LEN] = 11;
+        std::fs::write(&path, &bytes).unwrap();
+        match Log::open(&test_env(), &dir, 1).unwrap().read() {
+            Err(Error::Corruption(msg)) => assert!(msg.contains("type 11 at offset 8"), "{}", msg),
+            res => panic!("expected corruption, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn random_bytes_never_panic_the_decoder() {
+        let mut rng = Rng(0x5eed_1234_abcd_0001);
+        for _ in 0..10000 {
+            let bytes = Bytes::from(rng.bytes(40));
+            for format_version in [LEGACY_FORMAT, CHECKSUM_FORMAT].iter() {
+                let mut pos = 0;
+                while pos < bytes.len() {
+                    if LogEntry::decode(&bytes, &mut pos, *format_version).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lengths_past_u32_are_encoded_whole() {
+        let len = u32::MAX as usize + 10;
+        for format_version in [LEGACY_FORMAT, CURRENT_FORMAT].iter() {
+            let mut bytes = Vec::new();
+            put_length(&mut bytes, len, *format_version);
+            assert_eq!(bytes.len(), if *format_version == LEGACY_FORMAT { 8 } else { 5 });
+            bytes.extend_from_slice(b"short");
+            let mut pos = 0;
+            assert!(matches!(get_bytes(&Bytes::from(bytes), &mut pos, *format_version), Err(Error::Corruption(_))));
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use crate::error::{Error, Result};
+use crate::key::checked_value;
+use crate::utils::{checked_end, get_varint64, put_varint64};
+
+use bytes::Bytes;
+
+/// Writes applied together by `LsmDb::write`: they take consecutive sequence numbers in their order
+/// and readers see either all of them or none. A later write of the same key wins.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    entries: Vec<(Bytes, Option<Bytes>)>, //None is a delete
+    value_checksums: bool, //the values end with their checksums, only in the batches a mem table logs
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.entries.push((Bytes::copy_from_slice(key), Some(Bytes::copy_from_slice(value))));
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.entries.push((Bytes::copy_from_slice(key), None));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    //the same writes with a checksum after each value
+    pub(crate) fn with_value_checksums(&self) -> Self {
+        let entries = self.entries.iter()
+            .map(|(key, value)| (key.clone(), value.as_ref().map(|value| Bytes::from(checked_value(key, value)))))
+            .collect();
+        WriteBatch { entries, value_checksums: true }
+    }
+
+    pub(crate) fn has_value_checksums(&self) -> bool {
+        self.value_checksums
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, Option<&Bytes>)> {
+        self.entries.iter().map(|(key, value)| (key, value.as_ref()))
+    }
+
+    //the value of the one log record holding the whole batch:
+    //per entry a tag (0 put, 1 delete, 2 put with a checksum after the value) and the varint length prefixed key,
+    //then the value for a put
+    pub(crate) fn encode_to(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (key, value) in self.entries.iter() {
+            bytes.push(match value {
+                Some(_) if self.value_checksums => 2,
+                Some(_) => 0,
+                None => 1,
+            });
+            put_varint64(&mut bytes, key.len() as u64);
+            bytes.extend_from_slice(key);
+            if let Some(value) = value {
+                put_varint64(&mut bytes, value.len() as u64);
+                bytes.extend_from_slice(value);
+            }
+        }
+        bytes
+    }
+
+    //the keys and values are views into `bytes`
+    pub(crate) fn decode_from(bytes: &Bytes) -> Result<Self> {
+        let mut entries = Vec::new();
+        let mut value_checksums = None;
+        let mut pos = 0;
+        let get_bytes = |pos: &mut usize| -> Result<Bytes> {
+            let len = get_varint64(bytes, pos)?;
+            let end = checked_end(*pos, len, bytes.len())
+                .ok_or_else(|| Error::Corruption(format!("write batch truncated at offset {}", pos)))?;
+            let res = bytes.slice(*pos..end);
+            *pos = end;
+            Ok(res)
+        };
+        while pos < bytes.len() {
+            let tag = bytes[pos];
+            pos += 1;
+            let key = get_bytes(&mut pos)?;
+            let value = match tag {
+                0 | 2 => Some(get_bytes(&mut pos)?),
+                1 => None,
+                _ => return Err(Error::Corruption(format!("invalid write batch tag {} at offset {}", tag, pos - 1))),
+            };
+            if tag != 1 && *value_checksums.get_or_insert(tag == 2) != (tag == 2) {
+                return Err(Error::Corruption(format!("write batch mixes puts with and without checksums at offset {}", pos)));
+            }
+            entries.push((key, value));
+        }
+        Ok(WriteBatch { entries, value_checksums: value_checksums.unwrap_or(false) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_roundtrip() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1").delete(b"b").put(b"", &[7; 300]);
+        assert_eq!(batch.len(), 3);
+        let encoded = Bytes::from(batch.encode_to());
+        assert_eq!(WriteBatch::decode_from(&encoded).unwrap(), batch);
+        assert_eq!(WriteBatch::decode_from(&Bytes::new()).unwrap(), WriteBatch::new());
+        assert!(matches!(WriteBatch::decode_from(&encoded.slice(..encoded.len() - 1)), Err(Error::Corruption(_))));
+        assert!(matches!(WriteBatch::decode_from(&Bytes::from_static(b"\x03\x01a")), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn checked_batch_roundtrip() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1").delete(b"b");
+        let checked = batch.with_value_checksums();
+        assert!(checked.has_value_checksums());
+        assert_eq!(checked.iter().next().unwrap().1.unwrap()[..], checked_value(b"a", b"1")[..]);
+        let encoded = Bytes::from(checked.encode_to());
+        assert_eq!(encoded[0], 2);
+        assert_eq!(WriteBatch::decode_from(&encoded).unwrap(), checked);
+        //a batch is logged with or without checksums as a whole
+        let mut mixed = checked.encode_to();
+        mixed.extend_from_slice(&batch.encode_to());
+        assert!(matches!(WriteBatch::decode_from(&Bytes::from(mixed)), Err(Error::Corruption(_))));
+    }
+}
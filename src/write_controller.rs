@@ -0,0 +1,152 @@
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use parking_lot::Mutex;
+
+use crate::events::{DbEvent, EventBus};
+use crate::stats::{WriteStallState, WriteStallStats};
+
+//how long a write waits in the slowdown state before it goes on
+pub const SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+
+/// Holds writes back by the number of level 0 tables: from the slowdown trigger on each write is delayed,
+/// from the stop trigger on writes wait until compactions bring the number down. The triggers can change
+/// while the database is open. Writes only read atomics, the lock is taken when the state changes.
+#[derive(Debug)]
+pub struct WriteController {
+    level0_files: Arc<AtomicUsize>, //kept up to date by Levels::update
+    slowdown_trigger: AtomicUsize,
+    stop_trigger: AtomicUsize,
+    state: AtomicU8, //the WriteStallState of the last check
+    stall: Mutex<Stall>,
+    events: Arc<EventBus>,
+}
+
+#[derive(Debug)]
+struct Stall {
+    since: Instant, //the current episode started
+    episodes: u64,
+    duration: Duration, //of the episodes that are over
+}
+
+impl WriteController {
+    pub fn new(level0_files: Arc<AtomicUsize>, slowdown_trigger: usize, stop_trigger: usize, events: Arc<EventBus>) -> Self {
+        WriteController {
+            level0_files,
+            slowdown_trigger: AtomicUsize::new(slowdown_trigger),
+            stop_trigger: AtomicUsize::new(stop_trigger),
+            state: AtomicU8::new(WriteStallState::None as u8),
+            stall: Mutex::new(Stall { since: Instant::now(), episodes: 0, duration: Duration::ZERO }),
+            events,
+        }
+    }
+
+    //slowdown and stop trigger
+    pub fn triggers(&self) -> (usize, usize) {
+        (self.slowdown_trigger.load(Ordering::Relaxed), self.stop_trigger.load(Ordering::Relaxed))
+    }
+
+    //the caller checked that slowdown < stop, the next check goes by the new triggers
+    pub fn set_triggers(&self, slowdown_trigger: usize, stop_trigger: usize) {
+        self.slowdown_trigger.store(slowdown_trigger, Ordering::Relaxed);
+        self.stop_trigger.store(stop_trigger, Ordering::Relaxed);
+    }
+
+    //the state the number of level 0 tables calls for, a change is recorded and sent as an event
+    pub fn check(&self) -> WriteStallState {
+        let level0_files = self.level0_files.load(Ordering::Acquire);
+        let (slowdown_trigger, stop_trigger) = self.triggers();
+        let state = if level0_files >= stop_trigger {
+            WriteStallState::Stop
+        } else if level0_files >= slowdown_trigger {
+            WriteStallState::Slowdown
+        } else {
+            WriteStallState::None
+        };
+        if state as u8 != self.state.load(Ordering::Acquire) {
+            self.change_state(state, level0_files);
+        }
+        state
+    }
+
+    fn change_state(&self, to: WriteStallState, level0_files: usize) {
+        let mut stall = self.stall.lock();
+        //another writer may have got here first
+        let from = state_from_u8(self.state.load(Ordering::Acquire));
+        if from == to {
+            return;
+        }
+        let now = Instant::now();
+        if from == WriteStallState::None {
+            stall.episodes += 1;
+            stall.since = now;
+        }
+        if to == WriteStallState::None {
+            let episode = now - stall.since;
+            stall.duration += episode;
+        }
+        self.state.store(to as u8, Ordering::Release);
+        self.events.emit(|| DbEvent::WriteStallStateChanged { at: SystemTime::now(), from, to, level0_files });
+    }
+
+    //brings the state up to date first, it would otherwise only change on the next write
+    pub fn stats(&self) -> WriteStallStats {
+        self.check();
+        let stall = self.stall.lock();
+        let state = state_from_u8(self.state.load(Ordering::Acquire));
+        let running = match state {
+            WriteStallState::None => Duration::ZERO,
+            _ => stall.since.elapsed(),
+        };
+        WriteStallStats {
+            state,
+            level0_files: self.level0_files.load(Ordering::Acquire),
+            episodes: stall.episodes,
+            duration: stall.duration + running,
+        }
+    }
+}
+
+fn state_from_u8(state: u8) -> WriteStallState {
+    match state {
+        1 => WriteStallState::Slowdown,
+        2 => WriteStallState::Stop,
+        _ => WriteStallState::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_follows_the_level0_files_across_the_triggers() {
+        let level0_files = Arc::new(AtomicUsize::new(0));
+        let events = Arc::new(EventBus::default());
+        let receiver = events.subscribe(16);
+        let controller = WriteController::new(level0_files.clone(), 2, 4, events);
+        assert_eq!(controller.check(), WriteStallState::None);
+        for (files, state) in [(2, WriteStallState::Slowdown), (5, WriteStallState::Stop), (3, WriteStallState::Slowdown),
+            (1, WriteStallState::None), (4, WriteStallState::Stop), (0, WriteStallState::None)] {
+            level0_files.store(files, Ordering::Release);
+            assert_eq!(controller.check(), state, "{} files", files);
+        }
+        let stats = controller.stats();
+        assert_eq!((stats.state, stats.level0_files, stats.episodes), (WriteStallState::None, 0, 2));
+        let changes = std::iter::from_fn(|| receiver.try_recv())
+            .map(|e| match e {
+                DbEvent::WriteStallStateChanged { from, to, level0_files, .. } => (from as u8, to as u8, level0_files),
+                e => panic!("unexpected {:?}", e),
+            })
+            .collect::<Vec<_>>();
+        //None is 0, Slowdown 1 and Stop 2
+        assert_eq!(changes, vec![(0, 1, 2), (1, 2, 5), (2, 1, 3), (1, 0, 1), (0, 2, 4), (2, 0, 0)]);
+
+        //lower triggers apply from the next check on
+        level0_files.store(3, Ordering::Release);
+        controller.set_triggers(1, 3);
+        assert_eq!(controller.stats().state, WriteStallState::Stop);
+        assert_eq!(controller.stats().episodes, 3);
+    }
+}
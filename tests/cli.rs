@@ -0,0 +1,67 @@
+use draft_kv::cli::{self, Invocation};
+use draft_kv::error::Error;
+use draft_kv::lsm::{Config, LsmDb};
+
+use std::path::{Path, PathBuf};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("draft_kv_cli_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn invocation(dir: &Path, line: &str) -> Invocation {
+    let args = vec!["--db".to_owned(), dir.to_str().unwrap().to_owned()].into_iter()
+        .chain(line.split_whitespace().map(str::to_owned));
+    cli::parse_args(args).unwrap()
+}
+
+//the output of the command and whether it found what it looked for
+fn run(dir: &Path, line: &str) -> Result<(String, bool), Error> {
+    let mut out = Vec::new();
+    let found = cli::run(&invocation(dir, line), Config::new(), &mut out)?;
+    Ok((String::from_utf8(out).unwrap(), found))
+}
+
+#[test]
+fn commands_write_and_read_a_database() {
+    let dir = temp_dir("commands");
+    assert!(matches!(run(&dir, "get a"), Err(Error::InvalidArgument(_))));
+    run(&dir, "put user:1 alice").unwrap();
+    run(&dir, "put user:2 bob").unwrap();
+    run(&dir, "put --hex 757365723a33 00ff").unwrap();
+    run(&dir, "put other x").unwrap();
+    assert_eq!(run(&dir, "get user:1").unwrap(), ("alice\n".to_owned(), true));
+    assert_eq!(run(&dir, "get user:3").unwrap(), ("\\x00\\xff\n".to_owned(), true));
+    assert_eq!(run(&dir, "get nobody").unwrap(), (String::new(), false));
+    assert_eq!(run(&dir, "scan --prefix user: --limit 2").unwrap().0, "user:1\talice\nuser:2\tbob\n");
+    assert_eq!(run(&dir, "--hex scan --prefix 6f").unwrap().0, "6f74686572\t78\n");
+
+    run(&dir, "del user:2").unwrap();
+    run(&dir, "flush").unwrap();
+    assert_eq!(run(&dir, "scan").unwrap().0, "other\tx\nuser:1\talice\nuser:3\t\\x00\\xff\n");
+    assert!(run(&dir, "compact").unwrap().0.starts_with("compacted "));
+    assert_eq!(run(&dir, "verify").unwrap().0, "verified 1 tables\n");
+    assert!(run(&dir, "stats").unwrap().0.contains("last_seq_num"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reads_work_while_another_process_holds_the_lock() {
+    let dir = temp_dir("locked");
+    let db = LsmDb::new(dir.clone()).unwrap();
+    db.insert(b"flushed", b"1").unwrap();
+    db.flush().unwrap();
+    db.insert(b"logged", b"2").unwrap();
+
+    assert_eq!(run(&dir, "get flushed").unwrap(), ("1\n".to_owned(), true));
+    assert_eq!(run(&dir, "get logged").unwrap(), ("2\n".to_owned(), true));
+    assert_eq!(run(&dir, "verify").unwrap().0, "verified 1 tables\n");
+    assert!(matches!(run(&dir, "put k v"), Err(Error::DbLocked { .. })));
+    //the read-only opens changed nothing the owner relies on
+    db.insert(b"after", b"3").unwrap();
+    assert_eq!(db.search(b"logged", None).unwrap(), Some(b"2".to_vec()));
+    drop(db);
+    assert_eq!(run(&dir, "scan").unwrap().0, "after\t3\nflushed\t1\nlogged\t2\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}